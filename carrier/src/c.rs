@@ -1,10 +1,78 @@
 //! This is the Carrier C API
 
 use ::std::mem;
-use ::std::ffi::CStr;
+use ::std::ffi::{CStr, CString};
 use ::std::ptr;
-use ::std::os::raw::c_char;
+use ::std::os::raw::{c_char, c_void};
 use ::std::slice;
+use ::std::time::Duration;
+
+/// The version of the carrier C API. This gets bumped whenever an existing
+/// function or struct changes in a way that breaks hosts built against an older
+/// version. New functions don't bump it: they get a capability bit instead
+/// (see `carrier_capabilities()`).
+pub const CARRIER_API_VERSION: u32 = 2;
+
+/// `carrier_send_sync()`
+pub const CARRIER_CAP_SEND_SYNC: u64 = 1 << 0;
+/// `carrier_send_multi()`
+pub const CARRIER_CAP_SEND_MULTI: u64 = 1 << 1;
+/// `carrier_set_gc_policy()` and `carrier_gc()`
+pub const CARRIER_CAP_GC_POLICY: u64 = 1 << 2;
+/// `carrier_on_channel_event()` and `carrier_off_channel_event()`
+pub const CARRIER_CAP_CHANNEL_EVENTS: u64 = 1 << 3;
+/// `carrier_stats()`
+pub const CARRIER_CAP_STATS: u64 = 1 << 4;
+/// `carrier_set_timestamps()` (and the latency fields in the stats)
+pub const CARRIER_CAP_TIMESTAMPS: u64 = 1 << 5;
+/// `carrier_list_channels()` and `carrier_free_channels()`
+pub const CARRIER_CAP_LIST_CHANNELS: u64 = 1 << 6;
+/// `carrier_set_compression()`
+pub const CARRIER_CAP_COMPRESSION: u64 = 1 << 7;
+/// `carrier_interrupt()`
+pub const CARRIER_CAP_INTERRUPT: u64 = 1 << 8;
+
+/// Everything this build of carrier can do
+pub const CARRIER_CAPABILITIES: u64 =
+    CARRIER_CAP_SEND_SYNC |
+    CARRIER_CAP_SEND_MULTI |
+    CARRIER_CAP_GC_POLICY |
+    CARRIER_CAP_CHANNEL_EVENTS |
+    CARRIER_CAP_STATS |
+    CARRIER_CAP_TIMESTAMPS |
+    CARRIER_CAP_LIST_CHANNELS |
+    CARRIER_CAP_COMPRESSION |
+    CARRIER_CAP_INTERRUPT;
+
+/// Returns the version of the C API we speak (see `CARRIER_API_VERSION`). A
+/// host should refuse to run against a version it wasn't built for.
+#[no_mangle]
+pub extern fn carrier_api_version() -> u32 {
+    CARRIER_API_VERSION
+}
+
+/// Returns a bitmask of the optional functions this build provides (see the
+/// `CARRIER_CAP_*` constants), so a host built against an older or newer header
+/// can check what it's allowed to call at runtime.
+#[no_mangle]
+pub extern fn carrier_capabilities() -> u64 {
+    CARRIER_CAPABILITIES
+}
+
+/// Returns 1 if we have all of the given capabilities, 0 otherwise
+#[no_mangle]
+pub extern fn carrier_has_capabilities(caps: u64) -> i32 {
+    if CARRIER_CAPABILITIES & caps == caps { 1 } else { 0 }
+}
+
+/// Returns the size of the stats struct `carrier_stats()` fills in. The struct
+/// only ever grows (new fields go on the end), so a host can compare this to
+/// its own `sizeof` to see which fields it can trust. Hosts pass their own
+/// `sizeof` to `carrier_stats()`, which never writes past it.
+#[no_mangle]
+pub extern fn carrier_stats_size() -> usize {
+    mem::size_of::<CChannelStats>()
+}
 
 #[no_mangle]
 pub extern fn carrier_send(channel_c: *const c_char, message_bytes: *const u8, message_len: usize) -> i32 {
@@ -29,6 +97,60 @@ pub extern fn carrier_send(channel_c: *const c_char, message_bytes: *const u8, m
     res
 }
 
+/// Send a copy of a message to `num_channels` channels at once (see
+/// `send_multi()`).
+#[no_mangle]
+pub extern fn carrier_send_multi(channels_c: *const *const c_char, num_channels: usize, message_bytes: *const u8, message_len: usize) -> i32 {
+    if channels_c.is_null() { return -1; }
+    if message_bytes.is_null() { return -1; }
+    let channel_ptrs = unsafe { slice::from_raw_parts(channels_c, num_channels) };
+    let mut channels = Vec::with_capacity(num_channels);
+    for channel_c in channel_ptrs {
+        if channel_c.is_null() { return -1; }
+        match unsafe { CStr::from_ptr(*channel_c).to_str() } {
+            Ok(x) => channels.push(x),
+            Err(e) => {
+                println!("carrier: send_multi: error: {}", e);
+                return -3;
+            },
+        }
+    }
+    let message = Vec::from(unsafe { slice::from_raw_parts(message_bytes, message_len) });
+    match ::send_multi(channels.as_slice(), message) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("carrier: send_multi: error: {}", e);
+            -4
+        },
+    }
+}
+
+/// Send a message and wait up to `timeout_ms` for a consumer to dequeue it.
+/// Returns 0 on success and -5 if the message timed out (in which case it will
+/// not be delivered).
+#[no_mangle]
+pub extern fn carrier_send_sync(channel_c: *const c_char, message_bytes: *const u8, message_len: usize, timeout_ms: u64) -> i32 {
+    if channel_c.is_null() { return -1; }
+    if message_bytes.is_null() { return -1; }
+    let channel_res = unsafe { CStr::from_ptr(channel_c).to_str() };
+    let channel = match channel_res {
+        Ok(x) => x,
+        Err(e) => {
+            println!("carrier: send_sync: error: {}", e);
+            return -3;
+        },
+    };
+    let message = Vec::from(unsafe { slice::from_raw_parts(message_bytes, message_len) });
+    match ::send_sync(channel, message, Duration::from_millis(timeout_ms)) {
+        Ok(_) => 0,
+        Err(::CError::Timeout) => -5,
+        Err(e) => {
+            println!("carrier: send_sync: error: {}", e);
+            -4
+        },
+    }
+}
+
 #[no_mangle]
 pub extern fn carrier_recv(channel_c: *const c_char, len_c: *mut usize) -> *const u8 {
     let null = ptr::null_mut();
@@ -105,3 +227,251 @@ pub extern fn carrier_free(msg: *const u8, len: usize) -> i32 {
     0
 }
 
+
+#[no_mangle]
+pub extern fn carrier_shutdown() -> i32 {
+    ::shutdown();
+    0
+}
+
+#[no_mangle]
+pub extern fn carrier_reset() -> i32 {
+    ::reset();
+    0
+}
+
+/// Set the channel GC policy. `policy` is 0 for immediate, 1 for delayed (using
+/// `grace_ms` as the grace period), 2 for manual.
+#[no_mangle]
+pub extern fn carrier_set_gc_policy(policy: i32, grace_ms: u64) -> i32 {
+    let policy = match policy {
+        0 => ::GcPolicy::Immediate,
+        1 => ::GcPolicy::Delayed(Duration::from_millis(grace_ms)),
+        2 => ::GcPolicy::Manual,
+        _ => return -1,
+    };
+    match ::set_gc_policy(policy) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("carrier: set_gc_policy: error: {}", e);
+            -4
+        },
+    }
+}
+
+/// Run channel GC now. Returns the number of channels removed.
+#[no_mangle]
+pub extern fn carrier_gc() -> u32 {
+    ::gc()
+}
+
+/// The C channel event callback: `(channel, event, user_data)`. `event` is 1
+/// when a channel is created and 2 when it's removed. The channel string is
+/// only valid for the duration of the call.
+pub type ChannelEventCallback = extern "C" fn(*const c_char, i32, *mut c_void);
+
+/// Lets us move the host's user_data pointer into our callback closure. We
+/// never touch the pointer, we just hand it back to the host.
+struct UserData(*mut c_void);
+unsafe impl Send for UserData {}
+unsafe impl Sync for UserData {}
+
+/// Register a callback for channel events. Returns a listener id (> 0) or 0 on
+/// error. `user_data` is passed back to the callback untouched.
+///
+/// Callbacks are removed via `carrier_off_channel_event()`, or all at once by
+/// `carrier_shutdown()`. In both cases, once the call returns the callback is
+/// guaranteed not to be running, so it's safe to free `user_data`.
+#[no_mangle]
+pub extern fn carrier_on_channel_event(callback: Option<ChannelEventCallback>, user_data: *mut c_void) -> u64 {
+    let callback = match callback {
+        Some(x) => x,
+        None => return 0,
+    };
+    let user_data = UserData(user_data);
+    let id = ::on_channel_event(move |channel, event| {
+        let channel_c = match CString::new(channel) {
+            Ok(x) => x,
+            Err(e) => {
+                println!("carrier: on_channel_event: error: {}", e);
+                return;
+            },
+        };
+        let event_c = match event {
+            ::ChannelEvent::Created => 1,
+            ::ChannelEvent::Removed => 2,
+        };
+        callback(channel_c.as_ptr(), event_c, user_data.0);
+    });
+    id as u64
+}
+
+/// Unregister a channel event callback. Returns 0 on success, -1 if the id
+/// wasn't registered.
+#[no_mangle]
+pub extern fn carrier_off_channel_event(id: u64) -> i32 {
+    if ::off_channel_event(id as usize) { 0 } else { -1 }
+}
+
+/// Turn message timestamps (and latency stats) on (1) or off (0). See
+/// `set_timestamps()`.
+#[no_mangle]
+pub extern fn carrier_set_timestamps(yesno: i32) -> i32 {
+    ::set_timestamps(yesno != 0);
+    0
+}
+
+/// Wake up everyone blocked in `carrier_recv()` on a channel. Their calls
+/// return null (with a len of 1, like any other receive error). Returns 0 on
+/// success, -2 if the channel doesn't exist. See `interrupt()`.
+#[no_mangle]
+pub extern fn carrier_interrupt(channel_c: *const c_char) -> i32 {
+    if channel_c.is_null() { return -1; }
+    let channel_res = unsafe { CStr::from_ptr(channel_c).to_str() };
+    let channel = match channel_res {
+        Ok(x) => x,
+        Err(e) => {
+            println!("carrier: interrupt: error: {}", e);
+            return -3;
+        },
+    };
+    if ::interrupt(channel) { 0 } else { -2 }
+}
+
+/// Compress messages on a channel that are bigger than `threshold` bytes. A
+/// threshold of 0 turns compression off for the channel. See
+/// `set_compression()`.
+#[no_mangle]
+pub extern fn carrier_set_compression(channel_c: *const c_char, threshold: u64) -> i32 {
+    if channel_c.is_null() { return -1; }
+    let channel_res = unsafe { CStr::from_ptr(channel_c).to_str() };
+    let channel = match channel_res {
+        Ok(x) => x,
+        Err(e) => {
+            println!("carrier: set_compression: error: {}", e);
+            return -3;
+        },
+    };
+    let threshold = if threshold == 0 { None } else { Some(threshold as usize) };
+    ::set_compression(channel, threshold);
+    0
+}
+
+/// Channel stats, as handed to C
+#[repr(C)]
+pub struct CChannelStats {
+    pub messages: i32,
+    pub users: i32,
+    pub last_sent: u64,
+    pub last_received: u64,
+    pub max_depth: i32,
+    pub total_sent: u64,
+    pub total_received: u64,
+    pub avg_latency_us: u64,
+    pub max_latency_us: u64,
+    pub total_compressed: u64,
+}
+
+/// The first version of `CChannelStats`. Hosts can't hand us anything smaller.
+#[repr(C)]
+struct CChannelStatsV1 {
+    _messages: i32,
+    _users: i32,
+    _last_sent: u64,
+    _last_received: u64,
+}
+
+/// Fill in `stats_c` with the given channel's stats (see `ChannelStats`).
+/// `stats_len` is the size of the host's struct: we only fill in the fields
+/// that fit, so hosts built against an older (smaller) struct are safe.
+/// Returns 0 on success, -2 if the channel doesn't exist, -5 if `stats_len` is
+/// smaller than the first version of the struct.
+#[no_mangle]
+pub extern fn carrier_stats(channel_c: *const c_char, stats_c: *mut CChannelStats, stats_len: usize) -> i32 {
+    if channel_c.is_null() { return -1; }
+    if stats_c.is_null() { return -1; }
+    if stats_len < mem::size_of::<CChannelStatsV1>() { return -5; }
+    let channel_res = unsafe { CStr::from_ptr(channel_c).to_str() };
+    let channel = match channel_res {
+        Ok(x) => x,
+        Err(e) => {
+            println!("carrier: stats: error: {}", e);
+            return -3;
+        },
+    };
+    match ::stats(channel) {
+        Some(stats) => {
+            let stats = CChannelStats {
+                messages: stats.messages,
+                users: stats.users,
+                last_sent: stats.last_sent,
+                last_received: stats.last_received,
+                max_depth: stats.max_depth,
+                total_sent: stats.total_sent,
+                total_received: stats.total_received,
+                avg_latency_us: stats.avg_latency_us,
+                max_latency_us: stats.max_latency_us,
+                total_compressed: stats.total_compressed,
+            };
+            let len = ::std::cmp::min(stats_len, mem::size_of::<CChannelStats>());
+            unsafe {
+                ptr::copy_nonoverlapping(&stats as *const CChannelStats as *const u8, stats_c as *mut u8, len);
+            }
+            0
+        }
+        None => -2,
+    }
+}
+
+/// A channel's name and message/user counts, as handed to C
+#[repr(C)]
+pub struct CChannel {
+    pub name: *mut c_char,
+    pub messages: i32,
+    pub users: i32,
+}
+
+/// List the active channels. Returns an array of `num_c` channels (sorted by
+/// name), or null if there are none. The array must be freed with
+/// `carrier_free_channels()`.
+#[no_mangle]
+pub extern fn carrier_list_channels(num_c: *mut usize) -> *mut CChannel {
+    if num_c.is_null() { return ptr::null_mut(); }
+    unsafe { *num_c = 0; }
+    let mut channels = Vec::new();
+    for (name, stats) in ::channels() {
+        let name_c = match CString::new(name) {
+            Ok(x) => x,
+            Err(e) => {
+                println!("carrier: list_channels: error: {}", e);
+                continue;
+            },
+        };
+        channels.push(CChannel {
+            name: name_c.into_raw(),
+            messages: stats.messages,
+            users: stats.users,
+        });
+    }
+    if channels.len() == 0 { return ptr::null_mut(); }
+    // make len == capacity
+    channels.shrink_to_fit();
+    let ptr = channels.as_mut_ptr();
+    unsafe {
+        *num_c = channels.len();
+        mem::forget(channels);
+    }
+    ptr
+}
+
+/// Free a channel list returned from `carrier_list_channels()`
+#[no_mangle]
+pub extern fn carrier_free_channels(channels_c: *mut CChannel, num: usize) -> i32 {
+    if channels_c.is_null() { return 0; }
+    let channels = unsafe { Vec::from_raw_parts(channels_c, num, num) };
+    for channel in channels {
+        if channel.name.is_null() { continue; }
+        drop(unsafe { CString::from_raw(channel.name) });
+    }
+    0
+}
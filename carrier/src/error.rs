@@ -8,6 +8,18 @@ quick_error! {
             description(str)
             display("error: {}", str)
         }
+        Shutdown {
+            description("carrier is shut down")
+            display("error: carrier is shut down")
+        }
+        Timeout {
+            description("timed out")
+            display("error: timed out")
+        }
+        Interrupted {
+            description("interrupted")
+            display("error: interrupted")
+        }
     }
 }
 
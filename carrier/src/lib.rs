@@ -21,21 +21,58 @@
 //!      `send()` or `recv()` on a channel, it is created and can start being
 //!      used. Once a channel has no messages on it and also has no listeners,
 //!      it is recycled (removed entirely). This allows you to very cheaply make
-//!      and use new channels that clean themselves up when finished.
+//!      and use new channels that clean themselves up when finished. How and
+//!      when channels are recycled is controlled via `set_gc_policy()`.
+//!
+//! If you need to know when channels come and go, register a listener via
+//! `on_channel_event()` (or `carrier_on_channel_event()` from C).
+//!
+//! Channels that carry large, compressible payloads can have messages over a
+//! given size deflated on the way in and inflated on the way out (see
+//! `set_compression()`). This is transparent to both sides.
+//!
+//! Building with the `trace` feature logs (via the `log` crate) every send and
+//! receive, along with channels being created and removed, which makes it a
+//! lot easier to follow messages between the core and its host app.
 
 extern crate crossbeam;
+extern crate flate2;
 #[macro_use]
 extern crate lazy_static;
 #[macro_use]
 extern crate quick_error;
+#[cfg(feature = "trace")]
+#[macro_use]
+extern crate log;
+
+/// Log a message (at the given level) if we were built with the `trace`
+/// feature, otherwise compile to nothing.
+#[cfg(feature = "trace")]
+macro_rules! clog {
+    ($level:ident, $($arg:tt)*) => { $level!($($arg)*) }
+}
+
+/// Log a message (at the given level) if we were built with the `trace`
+/// feature, otherwise compile to nothing.
+#[cfg(not(feature = "trace"))]
+macro_rules! clog {
+    ($level:ident, $($arg:tt)*) => { if false { let _ = format!($($arg)*); } }
+}
 
 mod error;
 pub mod c;
 
-use ::std::sync::{Arc, RwLock};
+use ::std::sync::{Arc, RwLock, Mutex, Condvar};
+use ::std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use ::std::collections::HashMap;
+use ::std::time::{Duration, Instant};
+use ::std::thread::{self, ThreadId};
+use ::std::io::{Read, Write};
 
 use ::crossbeam::sync::MsQueue;
+use ::flate2::Compression;
+use ::flate2::write::DeflateEncoder;
+use ::flate2::read::DeflateDecoder;
 
 pub use ::error::CError;
 use ::error::CResult;
@@ -44,24 +81,286 @@ lazy_static! {
     static ref CONN: Carrier = Carrier::new().expect("carrier -- global static: failed to create");
 }
 
+/// The state of a message sent via `send_sync()`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Handoff {
+    /// Nobody has picked up the message yet
+    Pending,
+    /// A consumer dequeued the message
+    Delivered,
+    /// The sender gave up waiting, so the message must not be delivered
+    Cancelled,
+}
+
+/// Lets the sender of a message wait for a consumer to dequeue it
+struct Ack {
+    state: Mutex<Handoff>,
+    signal: Condvar,
+}
+
+impl Ack {
+    /// Create a new, pending ack
+    fn new() -> Ack {
+        Ack {
+            state: Mutex::new(Handoff::Pending),
+            signal: Condvar::new(),
+        }
+    }
+
+    /// Called by the consumer. Returns false if the sender already gave up, in
+    /// which case the message should be dropped.
+    fn deliver(&self) -> bool {
+        let mut guard = self.state.lock().expect("Ack.deliver() -- failed to grab lock");
+        if *guard == Handoff::Cancelled { return false; }
+        *guard = Handoff::Delivered;
+        self.signal.notify_all();
+        true
+    }
+
+    /// Called by the sender. Waits for the message to be delivered, cancelling
+    /// it if `timeout` passes first. Returns true if the message was delivered.
+    fn wait(&self, timeout: Duration) -> bool {
+        let start = Instant::now();
+        let mut guard = self.state.lock().expect("Ack.wait() -- failed to grab lock");
+        loop {
+            match *guard {
+                Handoff::Delivered => return true,
+                Handoff::Cancelled => return false,
+                Handoff::Pending => {}
+            }
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                *guard = Handoff::Cancelled;
+                return false;
+            }
+            guard = self.signal.wait_timeout(guard, timeout - elapsed).expect("Ack.wait() -- failed to wait on condvar").0;
+        }
+    }
+}
+
+/// A message sitting in a queue, along with an optional ack for messages that
+/// were sent synchronously.
+struct Envelope<T> {
+    seq: u64,
+    msg: T,
+    ack: Option<Arc<Ack>>,
+    /// When the message was queued (only set if timestamps are on)
+    sent: Option<Instant>,
+    /// Whether `msg` was deflated on the way in
+    compressed: bool,
+}
+
+/// Lets a queue compress the messages it carries
+trait Payload: Sized {
+    /// How big this message is, in bytes
+    fn size(&self) -> usize;
+
+    /// Deflate this message
+    fn compress(&self) -> CResult<Self>;
+
+    /// Inflate a message we deflated via `compress()`
+    fn decompress(&self) -> CResult<Self>;
+}
+
+impl Payload for Vec<u8> {
+    fn size(&self) -> usize {
+        self.len()
+    }
+
+    fn compress(&self) -> CResult<Self> {
+        let mut encoder = DeflateEncoder::new(Vec::with_capacity(self.len() / 2), Compression::fast());
+        encoder.write_all(self.as_slice())
+            .map_err(|e| CError::Msg(format!("Payload.compress() -- {}", e)))?;
+        encoder.finish()
+            .map_err(|e| CError::Msg(format!("Payload.compress() -- {}", e)))
+    }
+
+    fn decompress(&self) -> CResult<Self> {
+        let mut decoder = DeflateDecoder::new(self.as_slice());
+        let mut out = Vec::with_capacity(self.len() * 2);
+        decoder.read_to_end(&mut out)
+            .map_err(|e| CError::Msg(format!("Payload.decompress() -- {}", e)))?;
+        Ok(out)
+    }
+}
+
+/// A received message, along with its sequence number.
+///
+/// Every message sent on a channel is stamped with a sequence number, starting
+/// at 1 and increasing by one per message. A gap in the numbers a consumer sees
+/// means messages were dropped (for instance, a `send_sync()` that timed out).
+/// If a channel is collected and later recreated, its numbering starts over at
+/// 1, which consumers can treat as a reset.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub seq: u64,
+    pub data: Vec<u8>,
+}
+
+/// Some stats on a channel
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChannelStats {
+    /// How many messages are waiting in the channel
+    pub messages: i32,
+    /// How many listeners are blocking on the channel
+    pub users: i32,
+    /// The sequence number of the last message sent on the channel
+    pub last_sent: u64,
+    /// The sequence number of the last message received from the channel
+    pub last_received: u64,
+    /// The most messages that have ever been waiting in the channel at once
+    pub max_depth: i32,
+    /// How many messages were compressed on their way into the channel (see
+    /// `set_compression()`)
+    pub total_compressed: u64,
+    /// How many messages have been sent on the channel, total
+    pub total_sent: u64,
+    /// How many messages have been received from the channel, total
+    pub total_received: u64,
+    /// The average time (in microseconds) messages waited in the channel
+    /// before being received. Only tracked while timestamps are on (see
+    /// `set_timestamps()`), otherwise 0.
+    pub avg_latency_us: u64,
+    /// The longest time (in microseconds) a message waited in the channel
+    /// before being received. Only tracked while timestamps are on.
+    pub max_latency_us: u64,
+}
+
+/// Running depth/throughput/latency counters for a queue
+#[derive(Debug, Default)]
+struct Metrics {
+    max_depth: i32,
+    sent: u64,
+    received: u64,
+    compressed: u64,
+    latency_samples: u64,
+    latency_total_us: u64,
+    latency_max_us: u64,
+}
+
+/// Convert a duration to microseconds
+fn micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1000000 + (duration.subsec_nanos() / 1000) as u64
+}
+
 /// The carrier Queue is a quick and simple wrapper around MsQueue that keeps
 /// track of a bit more state than MsQueue does.
+///
+/// We never block inside of MsQueue. Blocking `pop()` calls instead wait on a
+/// condvar that's signalled whenever a message is pushed, the queue is closed,
+/// or someone calls `interrupt()`, so an idle listener is just a parked
+/// thread.
 struct Queue<T> {
-    internal: MsQueue<T>,
+    internal: MsQueue<Envelope<T>>,
+    /// Held while checking for messages before waiting on `signal`, and while
+    /// signalling, so wakeups can't slip in between the two. Holds how many
+    /// times the queue has been interrupted.
+    wakeup: Mutex<u64>,
+    /// Wakes up blocked `pop()` calls
+    signal: Condvar,
     messages: RwLock<i32>,
     users: RwLock<i32>,
+    closed: RwLock<bool>,
+    last_active: RwLock<Instant>,
+    /// The last sequence number we handed out. Held while pushing so sequence
+    /// numbers match queue order.
+    seq: Mutex<u64>,
+    /// The sequence number of the last message dequeued
+    last_received: RwLock<u64>,
+    /// Whether we stamp messages with the time they were sent, which lets us
+    /// track latency
+    timestamps: AtomicBool,
+    /// Messages bigger than this many bytes get compressed. None means we
+    /// never compress.
+    compression: RwLock<Option<usize>>,
+    metrics: Mutex<Metrics>,
 }
 
-impl<T> Queue<T> {
+impl<T: Payload> Queue<T> {
     /// Create a new carrier queue.
     fn new() -> Queue<T> {
         Queue {
             internal: MsQueue::new(),
+            wakeup: Mutex::new(0),
+            signal: Condvar::new(),
             messages: RwLock::new(0),
             users: RwLock::new(0),
+            closed: RwLock::new(false),
+            last_active: RwLock::new(Instant::now()),
+            seq: Mutex::new(0),
+            last_received: RwLock::new(0),
+            timestamps: AtomicBool::new(false),
+            compression: RwLock::new(None),
+            metrics: Mutex::new(Default::default()),
+        }
+    }
+
+    /// Turn message timestamps (and therefor latency tracking) on or off
+    fn set_timestamps(&self, yesno: bool) {
+        self.timestamps.store(yesno, Ordering::SeqCst);
+    }
+
+    /// Set the size (in bytes) over which messages are compressed, or None to
+    /// turn compression off
+    fn set_compression(&self, threshold: Option<usize>) {
+        let mut guard = self.compression.write().expect("Queue.set_compression() -- failed to grab write lock");
+        *guard = threshold;
+    }
+
+    /// Compress a message we're about to send if it's over our threshold.
+    /// Returns the message along with whether or not it was compressed.
+    fn pack(&self, val: T) -> CResult<(T, bool)> {
+        let threshold = self.compression.read().expect("Queue.pack() -- failed to grab read lock").clone();
+        match threshold {
+            Some(threshold) if val.size() > threshold => {
+                let packed = val.compress()?;
+                let mut mguard = self.metrics.lock().expect("Queue.pack() -- failed to grab metrics lock");
+                mguard.compressed += 1;
+                Ok((packed, true))
+            }
+            _ => Ok((val, false)),
+        }
+    }
+
+    /// Grab a timestamp for a message we're about to send, if we're tracking
+    /// them
+    fn stamp(&self) -> Option<Instant> {
+        if self.timestamps.load(Ordering::SeqCst) { Some(Instant::now()) } else { None }
+    }
+
+    /// Update our metrics after a message is sent. Call after the message
+    /// count has been updated.
+    fn record_sent(&self) {
+        let depth = self.num_messages();
+        let mut mguard = self.metrics.lock().expect("Queue.record_sent() -- failed to grab metrics lock");
+        mguard.sent += 1;
+        if depth > mguard.max_depth { mguard.max_depth = depth; }
+    }
+
+    /// Update our metrics after a message is received
+    fn record_received(&self, sent: Option<Instant>) {
+        let mut mguard = self.metrics.lock().expect("Queue.record_received() -- failed to grab metrics lock");
+        mguard.received += 1;
+        if let Some(sent) = sent {
+            let latency = micros(sent.elapsed());
+            mguard.latency_samples += 1;
+            mguard.latency_total_us += latency;
+            if latency > mguard.latency_max_us { mguard.latency_max_us = latency; }
         }
     }
 
+    /// Mark this queue as being used
+    fn touch(&self) {
+        let mut aguard = self.last_active.write().expect("Queue.touch() -- failed to grab write lock");
+        *aguard = Instant::now();
+    }
+
+    /// How long since this queue was last used
+    fn idle(&self) -> Duration {
+        let aguard = self.last_active.read().expect("Queue.idle() -- failed to grab read lock");
+        aguard.elapsed()
+    }
+
     /// Increment the number of messages this queue has by a certain amount (1).
     fn inc_messages(&self, val: i32) {
         let mut mguard = self.messages.write().expect("Queue.inc_messages() -- failed to grab write lock");
@@ -86,32 +385,217 @@ impl<T> Queue<T> {
         (*uguard).clone()
     }
 
+    /// Whether or not this queue has been closed
+    fn is_closed(&self) -> bool {
+        let cguard = self.closed.read().expect("Queue.is_closed() -- failed to grab read lock");
+        (*cguard).clone()
+    }
+
+    /// Close this queue, waking up anyone blocking on `pop()`. Once closed, a
+    /// queue cannot be reopened.
+    fn close(&self) {
+        {
+            let mut cguard = self.closed.write().expect("Queue.close() -- failed to grab write lock");
+            *cguard = true;
+        }
+        let _wguard = self.wakeup.lock().expect("Queue.close() -- failed to grab wakeup lock");
+        self.signal.notify_all();
+    }
+
+    /// Wake up everyone blocking on `pop()` without closing the queue. They
+    /// get a `CError::Interrupted`.
+    fn interrupt(&self) {
+        let mut wguard = self.wakeup.lock().expect("Queue.interrupt() -- failed to grab wakeup lock");
+        *wguard += 1;
+        self.signal.notify_all();
+    }
+
+    /// Let a blocked `pop()` know there's a message waiting. Call after the
+    /// message is on the queue.
+    fn notify(&self) {
+        let _wguard = self.wakeup.lock().expect("Queue.notify() -- failed to grab wakeup lock");
+        self.signal.notify_one();
+    }
+
     /// MsQueue.push()
-    fn push(&self, val: T) {
-        self.internal.push(val);
-        self.inc_messages(1);
+    fn push(&self, val: T) -> CResult<()> {
+        let sent = self.stamp();
+        let (msg, compressed) = self.pack(val)?;
+        self.push_envelope(Envelope { seq: 0, msg: msg, ack: None, sent: sent, compressed: compressed })
     }
 
-    /// MsQueue.try_pop()
-    fn try_pop(&self) -> Option<T> {
-        let res = self.internal.try_pop();
-        if res.is_some() {
-            self.inc_messages(-1);
+    /// Push a message and wait (up to `timeout`) for someone to dequeue it. If
+    /// we time out, the message is retracted (it will never be delivered) and
+    /// we return `CError::Timeout`.
+    fn push_sync(&self, val: T, timeout: Duration) -> CResult<()> {
+        let ack = Arc::new(Ack::new());
+        let sent = self.stamp();
+        let (msg, compressed) = self.pack(val)?;
+        self.push_envelope(Envelope { seq: 0, msg: msg, ack: Some(ack.clone()), sent: sent, compressed: compressed })?;
+        if ack.wait(timeout) {
+            Ok(())
         } else {
-            *(self.messages.write().expect("Queue.try_pop() -- failed to grab write lock")) = 0;
+            Err(CError::Timeout)
         }
-        res
     }
 
-    /// MsQueue.pop()
-    fn pop(&self) -> T {
+    /// Push an envelope onto the queue, stamping it with the next sequence
+    /// number.
+    fn push_envelope(&self, mut envelope: Envelope<T>) -> CResult<()> {
+        if self.is_closed() {
+            return Err(CError::Shutdown);
+        }
+        {
+            let mut sguard = self.seq.lock().expect("Queue.push_envelope() -- failed to grab seq lock");
+            *sguard += 1;
+            envelope.seq = *sguard;
+            self.internal.push(envelope);
+        }
+        self.notify();
+        self.inc_messages(1);
+        self.record_sent();
+        self.touch();
+        Ok(())
+    }
+
+    /// Push a copy of a message onto several queues such that no other push
+    /// can interleave with ours: every producer sees either all or none of our
+    /// copies ahead of its own message, on every queue.
+    ///
+    /// We do this by holding the sequence locks of all the queues while we push.
+    /// The locks are always taken in the order the queues are given, so callers
+    /// must pass queues in a consistent order (ie, sorted by channel name).
+    fn push_multi(queues: &Vec<Arc<Queue<T>>>, val: T) -> CResult<()>
+        where T: Clone
+    {
+        if queues.iter().any(|q| q.is_closed()) {
+            return Err(CError::Shutdown);
+        }
+        // compress (per each queue's threshold) before taking any locks
+        let packed = queues.iter()
+            .map(|q| q.pack(val.clone()))
+            .collect::<CResult<Vec<_>>>()?;
+        {
+            let mut guards = queues.iter()
+                .map(|q| q.seq.lock().expect("Queue::push_multi() -- failed to grab seq lock"))
+                .collect::<Vec<_>>();
+            for ((queue, sguard), (msg, compressed)) in queues.iter().zip(guards.iter_mut()).zip(packed.into_iter()) {
+                **sguard += 1;
+                queue.internal.push(Envelope { seq: **sguard, msg: msg, ack: None, sent: queue.stamp(), compressed: compressed });
+            }
+        }
+        for queue in queues {
+            queue.notify();
+            queue.inc_messages(1);
+            queue.record_sent();
+            queue.touch();
+        }
+        Ok(())
+    }
+
+    /// Open an envelope we just dequeued, decompressing its message if needed.
+    /// Returns None if the envelope was sent synchronously and the sender has
+    /// since given up on it.
+    fn open(&self, envelope: Envelope<T>) -> CResult<Option<(u64, T)>> {
+        self.inc_messages(-1);
+        let Envelope { seq, msg, ack, sent, compressed } = envelope;
+        let delivered = match ack {
+            Some(ack) => ack.deliver(),
+            None => true,
+        };
+        if !delivered { return Ok(None); }
+        self.record_received(sent);
+        {
+            let mut rguard = self.last_received.write().expect("Queue.open() -- failed to grab write lock");
+            if seq > *rguard { *rguard = seq; }
+        }
+        let msg = if compressed { msg.decompress()? } else { msg };
+        Ok(Some((seq, msg)))
+    }
+
+    /// Grab this queue's stats
+    fn stats(&self) -> ChannelStats {
+        let mguard = self.metrics.lock().expect("Queue.stats() -- failed to grab metrics lock");
+        let avg_latency_us = if mguard.latency_samples > 0 { mguard.latency_total_us / mguard.latency_samples } else { 0 };
+        ChannelStats {
+            messages: self.num_messages(),
+            users: self.num_users(),
+            last_sent: self.seq.lock().expect("Queue.stats() -- failed to grab seq lock").clone(),
+            last_received: self.last_received.read().expect("Queue.stats() -- failed to grab read lock").clone(),
+            max_depth: mguard.max_depth,
+            total_compressed: mguard.compressed,
+            total_sent: mguard.sent,
+            total_received: mguard.received,
+            avg_latency_us: avg_latency_us,
+            max_latency_us: mguard.latency_max_us,
+        }
+    }
+
+    /// MsQueue.try_pop(). Returns the message's sequence number along with
+    /// the message.
+    fn try_pop(&self) -> CResult<Option<(u64, T)>> {
+        if self.is_closed() {
+            return Err(CError::Shutdown);
+        }
+        self.touch();
+        loop {
+            match self.internal.try_pop() {
+                Some(x) => {
+                    match self.open(x)? {
+                        Some(msg) => return Ok(Some(msg)),
+                        // retracted, grab the next one
+                        None => continue,
+                    }
+                }
+                None => {
+                    *(self.messages.write().expect("Queue.try_pop() -- failed to grab write lock")) = 0;
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    /// Blocking pop. Returns the message's sequence number along with the
+    /// message, or errors if the queue is closed (`CError::Shutdown`) or
+    /// interrupted (`CError::Interrupted`) while we wait.
+    fn pop(&self) -> CResult<(u64, T)> {
         self.inc_users(1);
-        let res = self.internal.pop();
+        let res = self.pop_inner();
         self.inc_users(-1);
-        self.inc_messages(-1);
+        self.touch();
         res
     }
 
+    /// Does the actual work for `pop()`
+    fn pop_inner(&self) -> CResult<(u64, T)> {
+        let mut wguard = self.wakeup.lock().expect("Queue.pop() -- failed to grab wakeup lock");
+        let interrupts = *wguard;
+        loop {
+            // checked under the wakeup lock, so a close()/interrupt()/push
+            // either happens before our checks or wakes us up after
+            if self.is_closed() {
+                return Err(CError::Shutdown);
+            }
+            if *wguard != interrupts {
+                return Err(CError::Interrupted);
+            }
+            match self.internal.try_pop() {
+                Some(x) => {
+                    match self.open(x)? {
+                        Some(msg) => return Ok(msg),
+                        // retracted, wait for the next one
+                        None => continue,
+                    }
+                }
+                None => {
+                    // wakeups can be spurious, so we just loop and check
+                    // everything again
+                    wguard = self.signal.wait(wguard).expect("Queue.pop() -- failed to wait on condvar");
+                }
+            }
+        }
+    }
+
     /// Determine if this queue has been "abandoned" ...meaning it has no
     /// messages in it and there is nobody listening to it.
     fn is_abandoned(&self) -> bool {
@@ -123,8 +607,106 @@ impl<T> Queue<T> {
     }
 }
 
+/// Determines how abandoned channels (no messages, no listeners) get cleaned
+/// up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GcPolicy {
+    /// Remove a channel as soon as it's abandoned (the default)
+    Immediate,
+    /// Remove a channel once it has been abandoned for at least the given
+    /// grace period. A background reaper thread takes care of this.
+    Delayed(Duration),
+    /// Never remove channels automatically. Call `gc()` to clean up.
+    Manual,
+}
+
+impl Default for GcPolicy {
+    fn default() -> Self { GcPolicy::Immediate }
+}
+
+/// Describes something that happened to a channel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChannelEvent {
+    /// The channel was just created (by a send or recv)
+    Created,
+    /// The channel was removed (collected, wiped, or shut down)
+    Removed,
+}
+
+/// The callback type for channel event listeners
+pub type ChannelCallback = Fn(&str, ChannelEvent) + Send + Sync + 'static;
+
+/// Tracks which threads are currently running a listener's callback
+struct ListenerState {
+    active: bool,
+    callers: Vec<ThreadId>,
+}
+
+/// Wraps a channel event callback such that it can be unregistered safely:
+/// once `deactivate()` returns, the callback is not running (on any other
+/// thread) and never will be again.
+struct Listener {
+    id: usize,
+    callback: Box<ChannelCallback>,
+    state: Mutex<ListenerState>,
+    idle: Condvar,
+}
+
+impl Listener {
+    /// Create a new listener
+    fn new(id: usize, callback: Box<ChannelCallback>) -> Listener {
+        Listener {
+            id: id,
+            callback: callback,
+            state: Mutex::new(ListenerState { active: true, callers: Vec::new() }),
+            idle: Condvar::new(),
+        }
+    }
+
+    /// Run the callback, unless we've been deactivated
+    fn call(&self, channel: &str, event: ChannelEvent) {
+        let me = thread::current().id();
+        {
+            let mut guard = self.state.lock().expect("Listener.call() -- failed to grab lock");
+            if !guard.active { return; }
+            guard.callers.push(me);
+        }
+        (self.callback)(channel, event);
+        let mut guard = self.state.lock().expect("Listener.call() -- failed to grab lock");
+        match guard.callers.iter().position(|x| x == &me) {
+            Some(idx) => { guard.callers.remove(idx); }
+            None => {}
+        }
+        self.idle.notify_all();
+    }
+
+    /// Stop this listener from being called, and wait for any in-flight calls
+    /// to finish. If the callback is deactivating itself (ie, we're being
+    /// called from inside the callback) we don't wait on our own call.
+    fn deactivate(&self) {
+        let me = thread::current().id();
+        let mut guard = self.state.lock().expect("Listener.deactivate() -- failed to grab lock");
+        guard.active = false;
+        while guard.callers.iter().any(|x| x != &me) {
+            guard = self.idle.wait(guard).expect("Listener.deactivate() -- failed to wait on condvar");
+        }
+    }
+}
+
 pub struct Carrier {
     queues: RwLock<HashMap<String, Arc<Queue<Vec<u8>>>>>,
+    listeners: RwLock<Vec<Arc<Listener>>>,
+    next_listener: AtomicUsize,
+    shutdown: RwLock<bool>,
+    gc_policy: RwLock<GcPolicy>,
+    /// Whether new queues stamp their messages with the time they were sent
+    timestamps: AtomicBool,
+    /// Per-channel compression thresholds. Kept here (instead of only on the
+    /// queues) so they survive a channel being collected and recreated.
+    compression: RwLock<HashMap<String, usize>>,
+    /// Bumped whenever the reaper thread should exit. Each reaper remembers
+    /// the generation it was started with.
+    reaper_gen: AtomicUsize,
 }
 
 //unsafe impl Send for Carrier {}
@@ -135,49 +717,338 @@ impl Carrier {
     pub fn new() -> CResult<Carrier> {
         Ok(Carrier {
             queues: RwLock::new(HashMap::new()),
+            listeners: RwLock::new(Vec::new()),
+            next_listener: AtomicUsize::new(1),
+            shutdown: RwLock::new(false),
+            gc_policy: RwLock::new(Default::default()),
+            timestamps: AtomicBool::new(false),
+            compression: RwLock::new(HashMap::new()),
+            reaper_gen: AtomicUsize::new(0),
         })
     }
 
-    /// Ensure a channel exists
-    fn ensure(&self, channel: &String) -> Arc<Queue<Vec<u8>>> {
-        let mut guard = self.queues.write().expect("Carrier.ensure() -- failed to grab write lock");
-        if (*guard).contains_key(channel) {
-            (*guard).get(channel).expect("Carrier.ensure() -- failed to grab map item").clone()
-        } else {
-            let queue = Arc::new(Queue::new());
-            (*guard).insert(channel.clone(), queue.clone());
-            queue
+    /// Whether or not this carrier has been shut down
+    fn is_shutdown(&self) -> bool {
+        let guard = self.shutdown.read().expect("Carrier.is_shutdown() -- failed to grab read lock");
+        (*guard).clone()
+    }
+
+    /// Register a channel event listener. Returns the listener's id, which can
+    /// be passed to `remove_listener()`.
+    fn add_listener(&self, callback: Box<ChannelCallback>) -> usize {
+        let id = self.next_listener.fetch_add(1, Ordering::SeqCst);
+        let mut guard = self.listeners.write().expect("Carrier.add_listener() -- failed to grab write lock");
+        guard.push(Arc::new(Listener::new(id, callback)));
+        id
+    }
+
+    /// Unregister a channel event listener. Once this returns, the listener's
+    /// callback will not be running and will never be called again. Returns
+    /// false if no listener had the given id.
+    fn remove_listener(&self, id: usize) -> bool {
+        let listener = {
+            let mut guard = self.listeners.write().expect("Carrier.remove_listener() -- failed to grab write lock");
+            match guard.iter().position(|x| x.id == id) {
+                Some(idx) => guard.remove(idx),
+                None => return false,
+            }
+        };
+        listener.deactivate();
+        true
+    }
+
+    /// Unregister all channel event listeners
+    fn remove_all_listeners(&self) {
+        let listeners = {
+            let mut guard = self.listeners.write().expect("Carrier.remove_all_listeners() -- failed to grab write lock");
+            guard.drain(..).collect::<Vec<_>>()
+        };
+        for listener in listeners {
+            listener.deactivate();
+        }
+    }
+
+    /// Let our listeners know something happened to some channels. This must
+    /// NOT be called while holding the queue lock, since callbacks are free to
+    /// call back into carrier.
+    fn notify(&self, channels: &Vec<String>, event: ChannelEvent) {
+        if channels.len() == 0 { return; }
+        for channel in channels {
+            clog!(debug, "carrier: channel {}: {:?}", channel, event);
+        }
+        let listeners = {
+            let guard = self.listeners.read().expect("Carrier.notify() -- failed to grab read lock");
+            guard.clone()
+        };
+        for channel in channels {
+            for listener in &listeners {
+                listener.call(channel, event);
+            }
         }
     }
 
+    /// Ensure a channel exists
+    fn ensure(&self, channel: &String) -> CResult<Arc<Queue<Vec<u8>>>> {
+        let mut queues = self.ensure_many(&vec![channel.clone()])?;
+        Ok(queues.remove(0))
+    }
+
+    /// Ensure a set of channels exist, all under one lock. Returns the queues
+    /// in the same order as the channels given.
+    fn ensure_many(&self, channels: &Vec<String>) -> CResult<Vec<Arc<Queue<Vec<u8>>>>> {
+        let mut created = Vec::new();
+        let queues = {
+            let mut guard = self.queues.write().expect("Carrier.ensure_many() -- failed to grab write lock");
+            // checked under the queue lock so we can't race shutdown()
+            if self.is_shutdown() {
+                return Err(CError::Shutdown);
+            }
+            let mut queues = Vec::with_capacity(channels.len());
+            for channel in channels {
+                if (*guard).contains_key(channel) {
+                    queues.push((*guard).get(channel).expect("Carrier.ensure_many() -- failed to grab map item").clone());
+                } else {
+                    let queue = Arc::new(Queue::new());
+                    queue.set_timestamps(self.timestamps());
+                    queue.set_compression(self.compression(channel));
+                    (*guard).insert(channel.clone(), queue.clone());
+                    queues.push(queue);
+                    created.push(channel.clone());
+                }
+            }
+            queues
+        };
+        self.notify(&created, ChannelEvent::Created);
+        Ok(queues)
+    }
+
     fn exists(&self, channel: &String) -> bool {
         let guard = self.queues.read().expect("Carrier.exists() -- failed to grab read lock");
         (*guard).contains_key(channel)
     }
 
+    /// Wake up everyone blocked on a channel's `recv()`. Returns false if the
+    /// channel doesn't exist.
+    fn interrupt(&self, channel: &String) -> bool {
+        let guard = self.queues.read().expect("Carrier.interrupt() -- failed to grab read lock");
+        match (*guard).get(channel) {
+            Some(queue) => {
+                queue.interrupt();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Grab a channel's stats (if it exists)
+    fn stats(&self, channel: &String) -> Option<ChannelStats> {
+        let guard = self.queues.read().expect("Carrier.stats() -- failed to grab read lock");
+        (*guard).get(channel).map(|queue| queue.stats())
+    }
+
+    /// Whether or not messages are being timestamped
+    fn timestamps(&self) -> bool {
+        self.timestamps.load(Ordering::SeqCst)
+    }
+
+    /// Turn message timestamps on or off, for both existing and future
+    /// channels
+    fn set_timestamps(&self, yesno: bool) {
+        let guard = self.queues.read().expect("Carrier.set_timestamps() -- failed to grab read lock");
+        self.timestamps.store(yesno, Ordering::SeqCst);
+        for (_, queue) in guard.iter() {
+            queue.set_timestamps(yesno);
+        }
+    }
+
+    /// Grab a channel's compression threshold, if it has one
+    fn compression(&self, channel: &String) -> Option<usize> {
+        let guard = self.compression.read().expect("Carrier.compression() -- failed to grab read lock");
+        (*guard).get(channel).map(|x| x.clone())
+    }
+
+    /// Set (or with None, remove) a channel's compression threshold. Applies
+    /// to the channel now and to any future incarnation of it.
+    fn set_compression(&self, channel: &String, threshold: Option<usize>) {
+        let queues = self.queues.read().expect("Carrier.set_compression() -- failed to grab read lock");
+        {
+            let mut guard = self.compression.write().expect("Carrier.set_compression() -- failed to grab write lock");
+            match threshold {
+                Some(x) => { (*guard).insert(channel.clone(), x); }
+                None => { (*guard).remove(channel); }
+            }
+        }
+        if let Some(queue) = (*queues).get(channel) {
+            queue.set_compression(threshold);
+        }
+    }
+
+    /// Grab the names and stats of all active channels, sorted by name
+    fn channels(&self) -> Vec<(String, ChannelStats)> {
+        let mut channels = {
+            let guard = self.queues.read().expect("Carrier.channels() -- failed to grab read lock");
+            (*guard).iter()
+                .map(|(channel, queue)| (channel.clone(), queue.stats()))
+                .collect::<Vec<_>>()
+        };
+        channels.sort_by(|a, b| a.0.cmp(&b.0));
+        channels
+    }
+
     /// Count how many active channels there are
     fn count(&self) -> u32 {
         let guard = self.queues.read().expect("Carrier.count() -- failed to grab read lock");
         (*guard).len() as u32
     }
 
-    /// Remove a channel
-    fn remove(&self, channel: &String) {
-        let mut guard = self.queues.write().expect("Carrier.remove() -- failed to grab write lock");
-        (*guard).remove(channel);
+    /// Remove a channel, but only if it's abandoned, has been idle for at
+    /// least `grace`, and nobody is holding a reference to it.
+    ///
+    /// Since `ensure()` hands out references under the same write lock, anyone
+    /// who is about to push/pop on the channel holds a reference to it, and
+    /// we leave it alone. This means we can never remove a channel out from
+    /// under someone who just grabbed it.
+    fn remove_if_abandoned(&self, channel: &String, grace: Duration) -> bool {
+        let remove = {
+            let mut guard = self.queues.write().expect("Carrier.remove_if_abandoned() -- failed to grab write lock");
+            let remove = match (*guard).get(channel) {
+                Some(queue) => Carrier::is_collectable(queue, grace),
+                None => false,
+            };
+            if remove { (*guard).remove(channel); }
+            remove
+        };
+        if remove {
+            self.notify(&vec![channel.clone()], ChannelEvent::Removed);
+        }
+        remove
+    }
+
+    /// Whether a queue can be collected. Assumes the caller holds the queue
+    /// lock and that the only reference to the queue is the one in the map.
+    fn is_collectable(queue: &Arc<Queue<Vec<u8>>>, grace: Duration) -> bool {
+        Arc::strong_count(queue) <= 1 && queue.is_abandoned() && queue.idle() >= grace
+    }
+
+    /// Let go of a queue we grabbed via `ensure()`, removing the channel if the
+    /// GC policy says so.
+    fn release(&self, channel: &String, queue: Arc<Queue<Vec<u8>>>) {
+        drop(queue);
+        if self.gc_policy() == GcPolicy::Immediate {
+            self.remove_if_abandoned(channel, Duration::from_millis(0));
+        }
+    }
+
+    /// Remove all collectable channels. Returns how many were removed.
+    fn gc(&self) -> u32 {
+        let grace = match self.gc_policy() {
+            GcPolicy::Delayed(x) => x,
+            _ => Duration::from_millis(0),
+        };
+        let removed = {
+            let mut guard = self.queues.write().expect("Carrier.gc() -- failed to grab write lock");
+            let mut removed = Vec::new();
+            (*guard).retain(|channel, queue| {
+                let collect = Carrier::is_collectable(queue, grace);
+                if collect { removed.push(channel.clone()); }
+                !collect
+            });
+            removed
+        };
+        self.notify(&removed, ChannelEvent::Removed);
+        removed.len() as u32
+    }
+
+    /// Grab the current GC policy
+    fn gc_policy(&self) -> GcPolicy {
+        let guard = self.gc_policy.read().expect("Carrier.gc_policy() -- failed to grab read lock");
+        (*guard).clone()
+    }
+
+    /// Set the GC policy, starting/stopping the background reaper as needed
+    fn set_gc_policy(&'static self, policy: GcPolicy) -> CResult<()> {
+        {
+            let mut guard = self.gc_policy.write().expect("Carrier.set_gc_policy() -- failed to grab write lock");
+            *guard = policy;
+        }
+        // stop any running reaper
+        let gen = self.reaper_gen.fetch_add(1, Ordering::SeqCst) + 1;
+        match policy {
+            GcPolicy::Delayed(grace) => self.start_reaper(gen, grace),
+            _ => Ok(()),
+        }
+    }
+
+    /// Start a reaper thread that runs `gc()` periodically until the reaper
+    /// generation changes.
+    fn start_reaper(&'static self, gen: usize, grace: Duration) -> CResult<()> {
+        // check a few times per grace period, but don't spin
+        let interval = grace / 2;
+        let interval = if interval < Duration::from_millis(10) { Duration::from_millis(10) } else { interval };
+        let res = thread::Builder::new().name(String::from("carrier:reaper")).spawn(move || {
+            while self.reaper_gen.load(Ordering::SeqCst) == gen {
+                thread::sleep(interval);
+                if self.reaper_gen.load(Ordering::SeqCst) != gen { break; }
+                self.gc();
+            }
+        });
+        match res {
+            Ok(_) => Ok(()),
+            Err(e) => Err(CError::Msg(format!("failed to start reaper: {}", e))),
+        }
     }
 
     fn wipe(&self) {
-        let mut guard = self.queues.write().expect("Carrier.wipe() -- failed to grab write lock");
-        guard.clear();
+        let removed = {
+            let mut guard = self.queues.write().expect("Carrier.wipe() -- failed to grab write lock");
+            guard.drain().map(|(channel, _)| channel).collect::<Vec<_>>()
+        };
+        self.notify(&removed, ChannelEvent::Removed);
+    }
+
+    /// Close all channels (waking any blocked listeners) and refuse to create
+    /// new ones until `reset()` is called.
+    ///
+    /// Channel event listeners get their `Removed` events and are then
+    /// unregistered, so once this returns no callbacks are running.
+    fn shutdown(&self) {
+        let removed = {
+            let mut guard = self.queues.write().expect("Carrier.shutdown() -- failed to grab write lock");
+            {
+                let mut sguard = self.shutdown.write().expect("Carrier.shutdown() -- failed to grab shutdown lock");
+                *sguard = true;
+            }
+            for (_, queue) in guard.iter() {
+                queue.close();
+            }
+            guard.drain().map(|(channel, _)| channel).collect::<Vec<_>>()
+        };
+        // stop the reaper, if any. reset() will restart it.
+        self.reaper_gen.fetch_add(1, Ordering::SeqCst);
+        self.notify(&removed, ChannelEvent::Removed);
+        self.remove_all_listeners();
+    }
+
+    /// Allow channels to be created again after a `shutdown()`
+    fn reset(&'static self) {
+        {
+            let _guard = self.queues.write().expect("Carrier.reset() -- failed to grab write lock");
+            let mut sguard = self.shutdown.write().expect("Carrier.reset() -- failed to grab shutdown lock");
+            *sguard = false;
+        }
+        let policy = self.gc_policy();
+        match self.set_gc_policy(policy) {
+            Ok(_) => {}
+            Err(e) => println!("carrier: reset: error: {}", e),
+        }
     }
 }
 
 /// Send a message on a channel
 pub fn send(channel: &str, message: Vec<u8>) -> CResult<()> {
-    let queue = (*CONN).ensure(&String::from(channel));
-    queue.push(message);
-    Ok(())
+    clog!(trace, "carrier: send: {} ({} bytes)", channel, message.len());
+    let queue = (*CONN).ensure(&String::from(channel))?;
+    queue.push(message)
 }
 
 /// Send a message on a channel
@@ -186,24 +1057,132 @@ pub fn send_string(channel: &str, message: String) -> CResult<()> {
     send(channel, vec)
 }
 
-/// Blocking receive
-pub fn recv(channel: &str) -> CResult<Vec<u8>> {
-    let queue = (*CONN).ensure(&String::from(channel));
-    let res = Ok(queue.pop());
-    if queue.is_abandoned() { (*CONN).remove(&String::from(channel)); }
+/// Send a copy of a message to several channels at once. The copies go out
+/// atomically with respect to other senders, so if two producers `send_multi()`
+/// to overlapping channels, every channel sees their messages in the same
+/// relative order.
+pub fn send_multi(channels: &[&str], message: Vec<u8>) -> CResult<()> {
+    let mut channels = channels.iter().map(|x| String::from(*x)).collect::<Vec<_>>();
+    // sort so our locks are always taken in the same order (no deadlocks) and
+    // dedupe so we don't try to lock the same queue twice
+    channels.sort();
+    channels.dedup();
+    clog!(trace, "carrier: send_multi: {} ({} bytes)", channels.join(", "), message.len());
+    let queues = (*CONN).ensure_many(&channels)?;
+    let res = Queue::push_multi(&queues, message);
+    for (channel, queue) in channels.iter().zip(queues.into_iter()) {
+        (*CONN).release(channel, queue);
+    }
     res
 }
 
+/// Send a message on a channel and block until a consumer dequeues it, giving
+/// hand-off semantics. If nobody picks the message up within `timeout`, it is
+/// retracted (never delivered) and `CError::Timeout` is returned.
+pub fn send_sync(channel: &str, message: Vec<u8>, timeout: Duration) -> CResult<()> {
+    clog!(trace, "carrier: send_sync: {} ({} bytes)", channel, message.len());
+    let channel = String::from(channel);
+    let queue = (*CONN).ensure(&channel)?;
+    let res = queue.push_sync(message, timeout);
+    (*CONN).release(&channel, queue);
+    if let Err(ref e) = res {
+        clog!(trace, "carrier: send_sync: {}: {}", channel, e);
+    }
+    res
+}
+
+/// Blocking receive. If `shutdown()` is called while we're waiting, this
+/// returns `CError::Shutdown`.
+pub fn recv(channel: &str) -> CResult<Vec<u8>> {
+    recv_message(channel).map(|x| x.data)
+}
+
 /// Non-blocking receive
 pub fn recv_nb(channel: &str) -> CResult<Option<Vec<u8>>> {
+    recv_message_nb(channel).map(|x| x.map(|x| x.data))
+}
+
+/// Blocking receive that also gives you the message's sequence number (see
+/// `Message`).
+pub fn recv_message(channel: &str) -> CResult<Message> {
+    let channel = String::from(channel);
+    let queue = (*CONN).ensure(&channel)?;
+    let res = queue.pop();
+    (*CONN).release(&channel, queue);
+    match res {
+        Ok((seq, ref data)) => clog!(trace, "carrier: recv: {} (seq {}, {} bytes)", channel, seq, data.len()),
+        Err(ref e) => clog!(trace, "carrier: recv: {}: {}", channel, e),
+    }
+    res.map(|(seq, data)| Message { seq: seq, data: data })
+}
+
+/// Non-blocking receive that also gives you the message's sequence number (see
+/// `Message`).
+pub fn recv_message_nb(channel: &str) -> CResult<Option<Message>> {
     let channel = String::from(channel);
+    if (*CONN).is_shutdown() {
+        return Err(CError::Shutdown);
+    }
     if !(*CONN).exists(&channel) {
         return Ok(None)
     }
-    let queue = (*CONN).ensure(&channel);
-    let res = Ok(queue.try_pop());
-    if queue.is_abandoned() { (*CONN).remove(&channel); }
-    res
+    let queue = (*CONN).ensure(&channel)?;
+    let res = queue.try_pop();
+    (*CONN).release(&channel, queue);
+    if let Ok(Some((seq, ref data))) = res {
+        clog!(trace, "carrier: recv_nb: {} (seq {}, {} bytes)", channel, seq, data.len());
+    }
+    res.map(|x| x.map(|(seq, data)| Message { seq: seq, data: data }))
+}
+
+/// Wake up everyone blocking on `recv()` for a channel. They get a
+/// `CError::Interrupted`, and the channel itself is left alone (unlike with
+/// `shutdown()`). Good for cancelling a listener thread. Returns false if the
+/// channel doesn't exist.
+pub fn interrupt(channel: &str) -> bool {
+    clog!(debug, "carrier: interrupt: {}", channel);
+    (*CONN).interrupt(&String::from(channel))
+}
+
+/// Grab the stats for a channel, or None if the channel doesn't exist
+pub fn stats(channel: &str) -> Option<ChannelStats> {
+    (*CONN).stats(&String::from(channel))
+}
+
+/// Turn message timestamps on or off. While on, each message is stamped with
+/// the time it was sent so `stats()` can report how long messages wait in a
+/// channel before being received. Off by default, since it costs a clock read
+/// per message.
+pub fn set_timestamps(yesno: bool) {
+    (*CONN).set_timestamps(yesno);
+}
+
+/// Whether or not message timestamps are on
+pub fn timestamps() -> bool {
+    (*CONN).timestamps()
+}
+
+/// Compress messages on a channel that are bigger than `threshold` bytes
+/// (pass None to stop compressing). Messages are deflated when sent and
+/// inflated when received, so this is invisible to both ends. Good for
+/// channels that carry big, compressible payloads (file previews, say), but
+/// it costs CPU on each side, so don't bother for small messages.
+///
+/// The setting sticks to the channel name, so it applies even if the channel
+/// is collected and recreated.
+pub fn set_compression(channel: &str, threshold: Option<usize>) {
+    (*CONN).set_compression(&String::from(channel), threshold);
+}
+
+/// Grab a channel's compression threshold (see `set_compression()`)
+pub fn compression(channel: &str) -> Option<usize> {
+    (*CONN).compression(&String::from(channel))
+}
+
+/// List all active channels (sorted by name) along with their stats. Mainly
+/// useful for debugging.
+pub fn channels() -> Vec<(String, ChannelStats)> {
+    (*CONN).channels()
 }
 
 /// Returns the number of active channels
@@ -211,11 +1190,60 @@ pub fn count() -> u32 {
     (*CONN).count()
 }
 
+/// Set the policy for cleaning up abandoned channels (see `GcPolicy`).
+pub fn set_gc_policy(policy: GcPolicy) -> CResult<()> {
+    (*CONN).set_gc_policy(policy)
+}
+
+/// Get the current channel GC policy
+pub fn gc_policy() -> GcPolicy {
+    (*CONN).gc_policy()
+}
+
+/// Remove abandoned channels now (respecting the grace period if the policy is
+/// `GcPolicy::Delayed`). Returns the number of channels removed. Mainly useful
+/// with `GcPolicy::Manual`.
+pub fn gc() -> u32 {
+    (*CONN).gc()
+}
+
+/// Register a callback that's called whenever a channel is created or removed.
+/// Returns an id that can be passed to `off_channel_event()`.
+///
+/// Callbacks are called from whatever thread caused the event, and are never
+/// called while carrier holds any internal locks, so it's safe to use carrier
+/// from inside of them.
+pub fn on_channel_event<F>(callback: F) -> usize
+    where F: Fn(&str, ChannelEvent) + Send + Sync + 'static
+{
+    (*CONN).add_listener(Box::new(callback))
+}
+
+/// Unregister a channel event callback. Once this returns, the callback is not
+/// running and won't be called again (so it's safe to free anything it uses).
+/// Returns false if the id wasn't registered.
+pub fn off_channel_event(id: usize) -> bool {
+    (*CONN).remove_listener(id)
+}
+
 /// Wipe out all queues
 pub fn wipe() {
     (*CONN).wipe();
 }
 
+/// Shut down carrier: closes every channel, wakes up all blocked `recv()` calls
+/// (which will return `CError::Shutdown`), unregisters all channel event
+/// listeners, and refuses to create any new channels until `reset()` is called.
+/// Use this for a deterministic teardown before unloading the library.
+pub fn shutdown() {
+    (*CONN).shutdown();
+}
+
+/// Undo a `shutdown()`, allowing channels to be created/used again.
+pub fn reset() {
+    (*CONN).reset();
+}
+
 #[cfg(test)]
 mod tests {
     use ::std::thread;
@@ -276,6 +1304,294 @@ mod tests {
         assert_eq!(*(counter.read().unwrap()), num_tests);
     }
 
+    #[test]
+    fn queue_close_wakes_listeners() {
+        // NOTE: we test on a local queue instead of the global carrier since
+        // shutting down the global would break the other tests.
+        let queue: Arc<Queue<Vec<u8>>> = Arc::new(Queue::new());
+        let mut handles: Vec<thread::JoinHandle<CResult<(u64, Vec<u8>)>>> = Vec::new();
+        for _ in 0..4 {
+            let queue = queue.clone();
+            handles.push(thread::spawn(move || queue.pop()));
+        }
+        while queue.num_users() < 4 {
+            thread::yield_now();
+        }
+        queue.close();
+        for handle in handles {
+            match handle.join().unwrap() {
+                Err(CError::Shutdown) => {}
+                _ => panic!("expected shutdown error"),
+            }
+        }
+        assert!(queue.push(vec![1, 2, 3]).is_err());
+        assert!(queue.try_pop().is_err());
+    }
+
+    #[test]
+    fn c_capabilities() {
+        assert_eq!(c::carrier_api_version(), c::CARRIER_API_VERSION);
+        assert_eq!(c::carrier_has_capabilities(c::CARRIER_CAP_SEND_SYNC | c::CARRIER_CAP_INTERRUPT), 1);
+        assert_eq!(c::carrier_has_capabilities(1 << 63), 0);
+        assert_eq!(c::carrier_capabilities() & c::CARRIER_CAP_COMPRESSION, c::CARRIER_CAP_COMPRESSION);
+    }
+
+    #[test]
+    fn c_stats_stay_in_bounds() {
+        use ::std::ffi::CString;
+        use ::std::mem;
+        send("c-stats", vec![1, 2, 3]).unwrap();
+        send("c-stats", vec![4, 5, 6]).unwrap();
+        let channel = CString::new("c-stats").unwrap();
+
+        // a host that only knows the first four fields gets those and nothing
+        // past them
+        let mut buf = [0xffu8; 64];
+        let res = c::carrier_stats(channel.as_ptr(), buf.as_mut_ptr() as *mut c::CChannelStats, 24);
+        assert_eq!(res, 0);
+        let messages = unsafe { ::std::ptr::read_unaligned(buf.as_ptr() as *const i32) };
+        assert_eq!(messages, 2);
+        assert!(buf[24..].iter().all(|x| *x == 0xff));
+
+        let mut stats: c::CChannelStats = unsafe { mem::zeroed() };
+        let res = c::carrier_stats(channel.as_ptr(), &mut stats, c::carrier_stats_size());
+        assert_eq!(res, 0);
+        assert_eq!(stats.messages, 2);
+        assert_eq!(stats.total_sent, 2);
+
+        assert_eq!(c::carrier_stats(channel.as_ptr(), &mut stats, 8), -5);
+        let missing = CString::new("c-stats-nope").unwrap();
+        assert_eq!(c::carrier_stats(missing.as_ptr(), &mut stats, c::carrier_stats_size()), -2);
+    }
+
+    #[test]
+    fn interrupt_wakes_listeners() {
+        let queue: Arc<Queue<Vec<u8>>> = Arc::new(Queue::new());
+        let mut handles: Vec<thread::JoinHandle<CResult<(u64, Vec<u8>)>>> = Vec::new();
+        for _ in 0..3 {
+            let queue = queue.clone();
+            handles.push(thread::spawn(move || queue.pop()));
+        }
+        while queue.num_users() < 3 {
+            thread::yield_now();
+        }
+        queue.interrupt();
+        for handle in handles {
+            match handle.join().unwrap() {
+                Err(CError::Interrupted) => {}
+                _ => panic!("expected interrupted error"),
+            }
+        }
+        // still usable afterwards
+        queue.push(vec![1]).unwrap();
+        assert_eq!(queue.pop().unwrap(), (1, vec![1]));
+
+        // a waiting listener gets woken for a new message
+        let queue2 = queue.clone();
+        let handle = thread::spawn(move || queue2.pop());
+        while queue.num_users() < 1 {
+            thread::yield_now();
+        }
+        queue.push(vec![2]).unwrap();
+        assert_eq!(handle.join().unwrap().unwrap(), (2, vec![2]));
+    }
+
+    #[test]
+    fn collectable_respects_refs_and_grace() {
+        let queue: Arc<Queue<Vec<u8>>> = Arc::new(Queue::new());
+        assert!(Carrier::is_collectable(&queue, Duration::from_millis(0)));
+        // someone else holding the queue (ie, about to push) blocks collection
+        let queue2 = queue.clone();
+        assert!(!Carrier::is_collectable(&queue, Duration::from_millis(0)));
+        drop(queue2);
+        // so does having messages in it
+        queue.push(vec![1]).unwrap();
+        assert!(!Carrier::is_collectable(&queue, Duration::from_millis(0)));
+        queue.try_pop().unwrap();
+        assert!(Carrier::is_collectable(&queue, Duration::from_millis(0)));
+        // and not being idle long enough
+        assert!(!Carrier::is_collectable(&queue, Duration::from_millis(60000)));
+    }
+
+    #[test]
+    fn sync_send() {
+        let queue: Arc<Queue<Vec<u8>>> = Arc::new(Queue::new());
+        // nobody listening: we time out and the message is retracted
+        match queue.push_sync(vec![1], Duration::from_millis(10)) {
+            Err(CError::Timeout) => {}
+            _ => panic!("expected timeout"),
+        }
+        assert_eq!(queue.try_pop().unwrap(), None);
+
+        let queue2 = queue.clone();
+        let handle = thread::spawn(move || queue2.pop().unwrap());
+        queue.push_sync(vec![2], Duration::from_millis(60000)).unwrap();
+        // the retracted message still used up a sequence number
+        assert_eq!(handle.join().unwrap(), (2, vec![2]));
+        assert!(queue.is_abandoned());
+
+        // the global version works too
+        let handle = thread::spawn(move || recv("syncsend").unwrap());
+        send_sync("syncsend", vec![3], Duration::from_millis(60000)).unwrap();
+        assert_eq!(handle.join().unwrap(), vec![3]);
+    }
+
+    #[test]
+    fn sequence_numbers() {
+        let queue: Arc<Queue<Vec<u8>>> = Arc::new(Queue::new());
+        queue.push(vec![1]).unwrap();
+        queue.push(vec![2]).unwrap();
+        queue.push(vec![3]).unwrap();
+        assert_eq!(queue.try_pop().unwrap(), Some((1, vec![1])));
+        let qstats = queue.stats();
+        assert_eq!(qstats.messages, 2);
+        assert_eq!(qstats.last_sent, 3);
+        assert_eq!(qstats.last_received, 1);
+        assert_eq!(queue.pop().unwrap(), (2, vec![2]));
+        assert_eq!(queue.pop().unwrap(), (3, vec![3]));
+        assert_eq!(queue.stats().last_received, 3);
+
+        send_string("sequenced", String::from("one")).unwrap();
+        send_string("sequenced", String::from("two")).unwrap();
+        assert_eq!(stats("sequenced").unwrap().last_sent, 2);
+        let msg = recv_message_nb("sequenced").unwrap().unwrap();
+        assert_eq!(msg, Message { seq: 1, data: Vec::from("one".as_bytes()) });
+        let msg = recv_message("sequenced").unwrap();
+        assert_eq!(msg.seq, 2);
+        assert_eq!(stats("sequenced"), None);
+    }
+
+    #[test]
+    fn depth_and_latency_stats() {
+        let queue: Arc<Queue<Vec<u8>>> = Arc::new(Queue::new());
+        queue.push(vec![1]).unwrap();
+        queue.push(vec![2]).unwrap();
+        queue.pop().unwrap();
+        let qstats = queue.stats();
+        assert_eq!(qstats.max_depth, 2);
+        assert_eq!(qstats.total_sent, 2);
+        assert_eq!(qstats.total_received, 1);
+        // no timestamps, no latency
+        assert_eq!(qstats.avg_latency_us, 0);
+        assert_eq!(qstats.max_latency_us, 0);
+
+        queue.set_timestamps(true);
+        queue.push(vec![3]).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        // sent before timestamps were on, so not measured
+        assert_eq!(queue.pop().unwrap(), (2, vec![2]));
+        assert_eq!(queue.stats().max_latency_us, 0);
+        assert_eq!(queue.pop().unwrap(), (3, vec![3]));
+        let qstats = queue.stats();
+        assert_eq!(qstats.max_depth, 2);
+        assert_eq!(qstats.total_sent, 3);
+        assert_eq!(qstats.total_received, 3);
+        assert!(qstats.max_latency_us >= 20000);
+        assert_eq!(qstats.avg_latency_us, qstats.max_latency_us);
+    }
+
+    #[test]
+    fn compresses_big_messages() {
+        let queue: Arc<Queue<Vec<u8>>> = Arc::new(Queue::new());
+        queue.set_compression(Some(64));
+        let big = "file preview ".repeat(1000).into_bytes();
+        queue.push(vec![1, 2, 3]).unwrap();
+        queue.push(big.clone()).unwrap();
+        Queue::push_multi(&vec![queue.clone()], big.clone()).unwrap();
+        assert_eq!(queue.stats().total_compressed, 2);
+        assert_eq!(queue.pop().unwrap(), (1, vec![1, 2, 3]));
+        assert_eq!(queue.pop().unwrap(), (2, big.clone()));
+        assert_eq!(queue.try_pop().unwrap(), Some((3, big.clone())));
+
+        // settings outlive the channel
+        let carrier = Carrier::new().unwrap();
+        let channel = String::from("compressed");
+        carrier.set_compression(&channel, Some(64));
+        let queue = carrier.ensure(&channel).unwrap();
+        queue.push(big.clone()).unwrap();
+        assert_eq!(queue.stats().total_compressed, 1);
+        assert_eq!(queue.pop().unwrap(), (1, big.clone()));
+        carrier.release(&channel, queue);
+        assert!(!carrier.exists(&channel));
+        assert_eq!(carrier.compression(&channel), Some(64));
+        carrier.set_compression(&channel, None);
+        let queue = carrier.ensure(&channel).unwrap();
+        queue.push(big.clone()).unwrap();
+        assert_eq!(queue.stats().total_compressed, 0);
+    }
+
+    #[test]
+    fn lists_channels() {
+        let carrier = Carrier::new().unwrap();
+        carrier.ensure(&String::from("list:b")).unwrap().push(vec![1]).unwrap();
+        let queue = carrier.ensure(&String::from("list:a")).unwrap();
+        queue.push(vec![2]).unwrap();
+        queue.push(vec![3]).unwrap();
+        let channels = carrier.channels();
+        let names = channels.iter().map(|x| x.0.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["list:a", "list:b"]);
+        assert_eq!(channels[0].1.messages, 2);
+        assert_eq!(channels[1].1.messages, 1);
+    }
+
+    #[test]
+    fn multi_send_keeps_order() {
+        let mut handles = Vec::new();
+        for i in 0..8u8 {
+            handles.push(thread::spawn(move || {
+                for j in 0..50u8 {
+                    send_multi(&["fanout:1", "fanout:2", "fanout:1"], vec![i, j]).unwrap();
+                }
+            }));
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        let mut one = Vec::new();
+        let mut two = Vec::new();
+        while let Some(msg) = recv_nb("fanout:1").unwrap() { one.push(msg); }
+        while let Some(msg) = recv_nb("fanout:2").unwrap() { two.push(msg); }
+        // deduped, so each channel gets one copy per send
+        assert_eq!(one.len(), 8 * 50);
+        assert_eq!(one, two);
+    }
+
+    #[test]
+    fn channel_events() {
+        let events: Arc<RwLock<Vec<ChannelEvent>>> = Arc::new(RwLock::new(Vec::new()));
+        let events2 = events.clone();
+        let id = on_channel_event(move |channel, event| {
+            // other tests run in parallel, so only look at our channel
+            if channel != "evented" { return; }
+            events2.write().unwrap().push(event);
+        });
+        send_string("evented", String::from("hi")).unwrap();
+        recv_nb("evented").unwrap();
+        assert_eq!(*events.read().unwrap(), vec![ChannelEvent::Created, ChannelEvent::Removed]);
+
+        assert!(off_channel_event(id));
+        assert!(!off_channel_event(id));
+        send_string("evented", String::from("hi")).unwrap();
+        recv_nb("evented").unwrap();
+        assert_eq!(events.read().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn listener_can_remove_itself() {
+        let carrier = Arc::new(Carrier::new().unwrap());
+        let carrier2 = carrier.clone();
+        let id = Arc::new(RwLock::new(0));
+        let id2 = id.clone();
+        let listener_id = carrier.add_listener(Box::new(move |_, _| {
+            let id = id2.read().unwrap().clone();
+            // would deadlock if we waited on ourselves
+            assert!(carrier2.remove_listener(id));
+        }));
+        *id.write().unwrap() = listener_id;
+        carrier.ensure(&String::from("selfremove")).unwrap();
+        assert_eq!(carrier.listeners.read().unwrap().len(), 0);
+    }
+
     // Would love to test wiping, but running in multi-thread mode screws up the
     // other tests, so for now it's disabled.
     /*
@@ -2,10 +2,50 @@
 #include <stdio.h>
 #include <string.h>
 
+struct carrier_stats {
+	int32_t messages;
+	int32_t users;
+	uint64_t last_sent;
+	uint64_t last_received;
+	int32_t max_depth;
+	uint64_t total_sent;
+	uint64_t total_received;
+	uint64_t avg_latency_us;
+	uint64_t max_latency_us;
+	uint64_t total_compressed;
+};
+
+struct carrier_channel {
+	char* name;
+	int32_t messages;
+	int32_t users;
+};
+
+#define CARRIER_API_VERSION 2
+#define CARRIER_CAP_LIST_CHANNELS (1 << 6)
+
+extern uint32_t carrier_api_version();
+extern uint64_t carrier_capabilities();
+extern int32_t carrier_has_capabilities(uint64_t);
+extern size_t carrier_stats_size();
 extern int32_t carrier_send(char*, uint8_t*, size_t);
+extern int32_t carrier_send_sync(char*, uint8_t*, size_t, uint64_t);
+extern int32_t carrier_send_multi(char**, size_t, uint8_t*, size_t);
 extern uint8_t* carrier_recv_nb(char*, size_t*);
 extern uint8_t* carrier_recv(char*, size_t*);
 extern size_t carrier_free(uint8_t*, size_t);
+extern int32_t carrier_shutdown();
+extern int32_t carrier_reset();
+extern int32_t carrier_set_gc_policy(int32_t, uint64_t);
+extern uint32_t carrier_gc();
+extern uint64_t carrier_on_channel_event(void (*)(char*, int32_t, void*), void*);
+extern int32_t carrier_off_channel_event(uint64_t);
+extern int32_t carrier_set_timestamps(int32_t);
+extern int32_t carrier_set_compression(char*, uint64_t);
+extern int32_t carrier_interrupt(char*);
+extern int32_t carrier_stats(char*, struct carrier_stats*, size_t);
+extern struct carrier_channel* carrier_list_channels(size_t*);
+extern int32_t carrier_free_channels(struct carrier_channel*, size_t);
 
 void send(int id, char* msg) {
 	int32_t send = carrier_send("core", msg, strlen(msg));
@@ -28,8 +68,26 @@ void recv(int id) {
 	}
 }
 
+void list_channels() {
+	size_t num = 0;
+	struct carrier_channel* channels = carrier_list_channels(&num);
+	for(size_t i = 0; i < num; i++) {
+		printf("channel: %s (messages: %d, users: %d)\n", channels[i].name, channels[i].messages, channels[i].users);
+	}
+	fflush(stdout);
+	carrier_free_channels(channels, num);
+}
+
 int main() {
 	int num = 9999;
+	if(carrier_api_version() != CARRIER_API_VERSION) {
+		printf("carrier api version mismatch: %d\n", carrier_api_version());
+		return 1;
+	}
+	if(carrier_stats_size() < sizeof(struct carrier_stats)) {
+		printf("carrier stats struct is smaller than ours\n");
+		return 1;
+	}
 	printf("start...\n", num);
 	fflush(stdout);
 	sleep(5);
@@ -41,6 +99,9 @@ int main() {
 	}
 	printf("send done!\n");
 	fflush(stdout);
+	if(carrier_has_capabilities(CARRIER_CAP_LIST_CHANNELS)) {
+		list_channels();
+	}
 	sleep(5);
 
 	printf("receiving %d\n", num);
@@ -0,0 +1,193 @@
+//! Helpers for typo-tolerant searching.
+//!
+//! SQLite's full-text search only matches whole tokens, so "recipies" never
+//! finds "recipes". The idea here is to keep a list of the terms that are
+//! actually in the index, find the ones within a small edit distance of each
+//! word in a query, and rewrite the query so each word matches any of them
+//! (`recipies` becomes `(recipies OR recipes)`). What counts as "close" grows
+//! with the length of the word, and short words are left alone entirely since
+//! nearly everything is one edit away from a three-letter word.
+
+/// Whether a character is part of a token (this mirrors FTS's "simple"
+/// tokenizer, which splits on any ASCII character that isn't a letter or a
+/// number and leaves everything else alone).
+pub fn is_token_char(c: char) -> bool {
+    !c.is_ascii() || c.is_ascii_alphanumeric()
+}
+
+/// Split some text into (lowercased) terms the way the full-text index does
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !is_token_char(c))
+        .filter(|x| x.len() > 0)
+        .map(|x| x.to_ascii_lowercase())
+        .collect()
+}
+
+/// How many edits we allow for a word of the given length (in characters)
+pub fn max_distance(len: usize) -> usize {
+    if len < 4 {
+        0
+    } else if len < 8 {
+        1
+    } else {
+        2
+    }
+}
+
+/// Get the edit distance between two words (counting a swap of two adjacent
+/// characters as one edit), or None if it's more than `max`.
+pub fn distance(a: &str, b: &str, max: usize) -> Option<usize> {
+    let a = a.chars().collect::<Vec<_>>();
+    let b = b.chars().collect::<Vec<_>>();
+    let diff = if a.len() > b.len() { a.len() - b.len() } else { b.len() - a.len() };
+    if diff > max { return None; }
+
+    // we keep the last two rows of the matrix (the one before the last is
+    // needed for transpositions)
+    let mut prev2: Vec<usize> = vec![0; b.len() + 1];
+    let mut prev: Vec<usize> = (0..(b.len() + 1)).collect();
+    let mut cur: Vec<usize> = vec![0; b.len() + 1];
+    for i in 1..(a.len() + 1) {
+        cur[0] = i;
+        let mut row_min = cur[0];
+        for j in 1..(b.len() + 1) {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            let mut val = (prev[j] + 1)
+                .min(cur[j - 1] + 1)
+                .min(prev[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                val = val.min(prev2[j - 2] + 1);
+            }
+            cur[j] = val;
+            if val < row_min { row_min = val; }
+        }
+        // every path through the matrix crosses this row, so if the whole
+        // row is over the limit, so is the answer
+        if row_min > max { return None; }
+        ::std::mem::swap(&mut prev2, &mut prev);
+        ::std::mem::swap(&mut prev, &mut cur);
+    }
+    let dist = prev[b.len()];
+    if dist > max { None } else { Some(dist) }
+}
+
+/// Whether a bare query word is an operator in FTS's query syntax
+fn is_operator(word: &str) -> bool {
+    word == "OR" || word == "AND" || word == "NOT" || word.starts_with("NEAR")
+}
+
+/// Pull the (lowercased) terms out of a full-text query, including the ones in
+/// its phrases, but not operators or anything the query excludes (`-word` or
+/// `NOT word`). Each term is only listed once.
+pub fn query_terms(query: &str) -> Vec<String> {
+    let mut terms: Vec<String> = Vec::new();
+    let mut excluded = false;
+    for word in query.split(|c: char| c.is_whitespace() || c == '(' || c == ')') {
+        if word.len() == 0 { continue; }
+        if word == "NOT" {
+            excluded = true;
+            continue;
+        }
+        let skip = excluded || is_operator(word) || word.starts_with('-');
+        excluded = false;
+        if skip { continue; }
+        for term in tokenize(word) {
+            if !terms.contains(&term) { terms.push(term); }
+        }
+    }
+    terms
+}
+
+/// Rewrite a full-text query so each plain word in it also matches the terms
+/// `similar` gives back for it. Phrases (in quotes), operators, prefix
+/// searches (`recip*`) and column filters are left as they are. Returns None
+/// if nothing in the query changed.
+pub fn expand_query<F>(query: &str, mut similar: F) -> Option<String>
+    where F: FnMut(&str) -> Vec<String>
+{
+    let mut out = String::with_capacity(query.len() * 2);
+    let mut word = String::new();
+    let mut changed = false;
+    let mut in_quote = false;
+    {
+        let mut flush = |word: &mut String, out: &mut String| {
+            if word.len() == 0 { return; }
+            let plain = word.chars().all(is_token_char) && !is_operator(word.as_str());
+            let terms = if plain { similar(word.to_ascii_lowercase().as_str()) } else { Vec::new() };
+            if terms.len() > 0 {
+                changed = true;
+                out.push('(');
+                out.push_str(word.as_str());
+                for term in terms {
+                    out.push_str(" OR ");
+                    out.push_str(term.as_str());
+                }
+                out.push(')');
+            } else {
+                out.push_str(word.as_str());
+            }
+            word.clear();
+        };
+        for c in query.chars() {
+            if in_quote {
+                out.push(c);
+                if c == '"' { in_quote = false; }
+                continue;
+            }
+            match c {
+                '"' | '(' | ')' => {
+                    flush(&mut word, &mut out);
+                    out.push(c);
+                    if c == '"' { in_quote = true; }
+                }
+                c if c.is_whitespace() => {
+                    flush(&mut word, &mut out);
+                    out.push(c);
+                }
+                c => word.push(c),
+            }
+        }
+        flush(&mut word, &mut out);
+    }
+    if changed { Some(out) } else { None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokenizes() {
+        assert_eq!(tokenize("Don't STOP me-now, caf\u{e9}!"), vec!["don", "t", "stop", "me", "now", "caf\u{e9}"]);
+        assert_eq!(tokenize("  ").len(), 0);
+    }
+
+    #[test]
+    fn measures_distance() {
+        assert_eq!(distance("recipes", "recipes", 1), Some(0));
+        assert_eq!(distance("recipies", "recipes", 1), Some(1));
+        assert_eq!(distance("recpies", "recipes", 1), Some(1));
+        assert_eq!(distance("kitten", "sitting", 3), Some(3));
+        assert_eq!(distance("kitten", "sitting", 2), None);
+        assert_eq!(distance("taco", "tacocat", 2), None);
+        assert_eq!(max_distance(3), 0);
+        assert_eq!(max_distance(8), 2);
+    }
+
+    #[test]
+    fn finds_query_terms() {
+        assert_eq!(query_terms(r#"(Tacos OR "fish tacos") -beef salsa* NOT onions"#), vec!["tacos", "fish", "salsa"]);
+        assert_eq!(query_terms("OR AND").len(), 0);
+    }
+
+    #[test]
+    fn expands_queries() {
+        let similar = |word: &str| {
+            if word == "recipies" { vec![String::from("recipes")] } else { Vec::new() }
+        };
+        assert_eq!(expand_query("taco recipies", &similar), Some(String::from("taco (recipies OR recipes)")));
+        assert_eq!(expand_query("(Recipies OR tacos)", &similar), Some(String::from("((Recipies OR recipes) OR tacos)")));
+        assert_eq!(expand_query(r#""taco recipies" recipies*"#, &similar), None);
+        assert_eq!(expand_query("tacos", &similar), None);
+    }
+}
@@ -0,0 +1,98 @@
+//! Finds where the words of a search show up in some text, so a UI can
+//! highlight them.
+//!
+//! We match the same way the full-text index does (see `fuzzy::tokenize()`): a
+//! word matches a whole token, ignoring case, unless it was searched as a
+//! prefix (`recip*`), in which case it matches any token starting with it.
+
+use ::fuzzy;
+
+/// A word to look for
+#[derive(Debug, Clone, PartialEq)]
+pub struct Needle {
+    /// The (lowercased) word
+    pub word: String,
+    /// Whether the word matches the start of a token (instead of all of it)
+    pub prefix: bool,
+}
+
+impl Needle {
+    pub fn new(word: String, prefix: bool) -> Needle {
+        Needle {
+            word: word,
+            prefix: prefix,
+        }
+    }
+
+    fn matches(&self, token: &str) -> bool {
+        if self.prefix {
+            token.starts_with(self.word.as_str())
+        } else {
+            token == self.word
+        }
+    }
+}
+
+/// Grab the words to look for from a full-text query (see
+/// `fuzzy::query_terms()`, which skips operators and excluded words)
+pub fn needles(query: &str) -> Vec<Needle> {
+    let prefixes = query.split(|c: char| c.is_whitespace() || c == '(' || c == ')')
+        .filter(|x| x.ends_with('*') && !x.starts_with('-'))
+        .filter_map(|x| fuzzy::tokenize(x).pop())
+        .collect::<Vec<_>>();
+    fuzzy::query_terms(query).into_iter()
+        .map(|word| {
+            let prefix = prefixes.contains(&word);
+            Needle::new(word, prefix)
+        })
+        .collect()
+}
+
+/// Find the tokens in some text that any of the given words match. Returns
+/// each match's start and end (byte offsets into `text`, end exclusive), in
+/// order.
+pub fn find(text: &str, needles: &[Needle]) -> Vec<(usize, usize)> {
+    let mut found = Vec::new();
+    if needles.len() == 0 { return found; }
+    let mut start = None;
+    // tack a separator on the end so the last token gets checked too
+    for (idx, c) in text.char_indices().chain(Some((text.len(), ' '))) {
+        match (start, fuzzy::is_token_char(c)) {
+            (None, true) => start = Some(idx),
+            (Some(from), false) => {
+                let token = text[from..idx].to_ascii_lowercase();
+                if needles.iter().any(|x| x.matches(token.as_str())) {
+                    found.push((from, idx));
+                }
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_needles() {
+        assert_eq!(needles(r#"Tacos "fish tacos" -beef salsa* NOT onions"#), vec![
+            Needle::new(String::from("tacos"), false),
+            Needle::new(String::from("fish"), false),
+            Needle::new(String::from("salsa"), true),
+        ]);
+        assert_eq!(needles("OR").len(), 0);
+    }
+
+    #[test]
+    fn finds_matches() {
+        let words = needles("tacos salsa*");
+        assert_eq!(find("Fish TACOS with salsas", &words), vec![(5, 10), (16, 22)]);
+        assert_eq!(find("tacostacos salsa", &words), vec![(11, 16)]);
+        assert_eq!(find("caf\u{e9} tacos", &words), vec![(6, 11)]);
+        assert_eq!(find("tacos", &[]).len(), 0);
+        assert_eq!(find("", &words).len(), 0);
+    }
+}
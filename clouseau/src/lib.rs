@@ -8,11 +8,18 @@
 extern crate quick_error;
 extern crate rusqlite;
 
+pub mod segment;
+pub mod fuzzy;
+pub mod prefix;
+pub mod highlight;
+
 use ::std::error::Error;
 use ::std::mem;
 
 use ::rusqlite::Connection;
 
+pub use ::segment::{SegmentedIndex, SegmentConfig, TokenSealer};
+
 //                          ....~?=:::~M8.+$??Z$DON??=Z+,+=~.....               
 //           ...           ....~?IZO==+:=$+:+:?.$8=I.$~::+:=~....               
 //           ....           ..+~$I:$$$7??MI$:N:???,$7=~I+=~:,,,.....            
@@ -85,6 +92,7 @@ macro_rules! from_err {
     )
 }
 from_err!(rusqlite::Error);
+from_err!(::std::io::Error);
 
 impl From<(rusqlite::Connection, rusqlite::Error)> for CError {
     fn from(err: (rusqlite::Connection, rusqlite::Error)) -> CError {
@@ -0,0 +1,149 @@
+//! A small in-memory index for search-as-you-type suggestions.
+//!
+//! Everything we know about is kept in a sorted map, keyed by its lowercased
+//! text, along with how many times it's been added. Finding what starts with a
+//! prefix is then just a walk over one contiguous range of the map, no matter
+//! how much is in it. Longer text (titles, say) can also be found by the start
+//! of any of its words, so "rep" finds "CNN News Report".
+
+use ::std::collections::{BTreeMap, HashMap};
+use ::fuzzy;
+
+/// Holds text we can look up by prefix, along with how many times each was
+/// added
+pub struct PrefixIndex {
+    /// (lowercased text we match the prefix against, text as it was added) ->
+    /// how many times it was added
+    entries: BTreeMap<(String, String), usize>,
+}
+
+/// Grab the (lowercased) text starting at each word in some text
+fn word_keys(text: &str) -> Vec<String> {
+    let lower = text.to_lowercase();
+    let mut keys = Vec::new();
+    let mut prev_token = false;
+    for (idx, c) in lower.char_indices() {
+        let token = fuzzy::is_token_char(c);
+        if token && !prev_token { keys.push(String::from(&lower[idx..])); }
+        prev_token = token;
+    }
+    keys
+}
+
+impl PrefixIndex {
+    pub fn new() -> PrefixIndex {
+        PrefixIndex {
+            entries: BTreeMap::new(),
+        }
+    }
+
+    fn add_key(&mut self, key: String, text: &str) {
+        *self.entries.entry((key, String::from(text))).or_insert(0) += 1;
+    }
+
+    fn remove_key(&mut self, key: String, text: &str) {
+        let key = (key, String::from(text));
+        let gone = match self.entries.get_mut(&key) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => false,
+        };
+        if gone { self.entries.remove(&key); }
+    }
+
+    /// Add some text, found by its start
+    pub fn add(&mut self, text: &str) {
+        if text.trim() == "" { return; }
+        self.add_key(text.to_lowercase(), text);
+    }
+
+    /// Remove some text added with `add()`
+    pub fn remove(&mut self, text: &str) {
+        if text.trim() == "" { return; }
+        self.remove_key(text.to_lowercase(), text);
+    }
+
+    /// Add some text, found by the start of any of its words
+    pub fn add_words(&mut self, text: &str) {
+        for key in word_keys(text) {
+            self.add_key(key, text);
+        }
+    }
+
+    /// Remove some text added with `add_words()`
+    pub fn remove_words(&mut self, text: &str) {
+        for key in word_keys(text) {
+            self.remove_key(key, text);
+        }
+    }
+
+    /// How many different things are in the index
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Find the text starting with the given prefix (ignoring case), along with
+    /// how many times each was added. The most common come first, and we give
+    /// back at most `limit` of them.
+    pub fn complete(&self, prefix: &str, limit: usize) -> Vec<(String, usize)> {
+        let prefix = prefix.to_lowercase();
+        let mut found: HashMap<&String, usize> = HashMap::new();
+        let start = (prefix.clone(), String::new());
+        for (&(ref key, ref text), count) in self.entries.range(start..) {
+            if !key.starts_with(prefix.as_str()) { break; }
+            // text found by more than one of its words only counts once
+            let entry = found.entry(text).or_insert(0);
+            if *count > *entry { *entry = *count; }
+        }
+        let mut found = found.into_iter()
+            .map(|(text, count)| (text.clone(), count))
+            .collect::<Vec<_>>();
+        found.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        found.truncate(limit);
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn completes_prefixes() {
+        let mut idx = PrefixIndex::new();
+        idx.add("receipt");
+        idx.add("receipt");
+        idx.add("Recipes");
+        idx.add("taxes");
+        idx.add("  ");
+        assert_eq!(idx.len(), 3);
+        assert_eq!(idx.complete("rec", 10), vec![(String::from("receipt"), 2), (String::from("Recipes"), 1)]);
+        assert_eq!(idx.complete("REC", 1), vec![(String::from("receipt"), 2)]);
+        assert_eq!(idx.complete("recz", 10).len(), 0);
+        assert_eq!(idx.complete("", 10).len(), 3);
+
+        idx.remove("receipt");
+        assert_eq!(idx.complete("rece", 10), vec![(String::from("receipt"), 1)]);
+        idx.remove("receipt");
+        assert_eq!(idx.complete("rece", 10).len(), 0);
+        idx.remove("nothing here");
+        assert_eq!(idx.len(), 2);
+    }
+
+    #[test]
+    fn completes_words() {
+        let mut idx = PrefixIndex::new();
+        idx.add_words("CNN News Report");
+        idx.add_words("Fox news: the report");
+        assert_eq!(idx.complete("rep", 10), vec![(String::from("CNN News Report"), 1), (String::from("Fox news: the report"), 1)]);
+        assert_eq!(idx.complete("news r", 10), vec![(String::from("CNN News Report"), 1)]);
+        assert_eq!(idx.complete("ews", 10).len(), 0);
+
+        idx.remove_words("CNN News Report");
+        assert_eq!(idx.complete("rep", 10), vec![(String::from("Fox news: the report"), 1)]);
+        idx.remove_words("Fox news: the report");
+        assert_eq!(idx.len(), 0);
+    }
+}
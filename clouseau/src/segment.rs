@@ -0,0 +1,618 @@
+//! Segments let Clouseau keep a large full-text index on disk instead of in
+//! memory.
+//!
+//! The index is split into a number of small sqlite files (segments), each of
+//! which holds at most `max_docs` objects. Segments are opened lazily (with
+//! sqlite's `mmap_size` set so reads go through a memory map) the first time
+//! they're needed, and we only keep `max_resident` of them open at once. The
+//! least-recently used segment is closed when we go over that limit, so hot
+//! segments stay mapped and cold ones just sit on disk.
+//!
+//! A small manifest db tracks which segment each object lives in, so indexing
+//! or unindexing an object only ever touches one segment.
+//!
+//! Since objects get removed over time, segments shrink. A background thread
+//! periodically merges small segments together so a search doesn't have to
+//! visit a pile of nearly-empty files.
+//!
+//! Nothing we're given to index is written to disk as-is. Every token is
+//! sealed (see `TokenSealer`) before it goes into a segment, and the words in
+//! a search get sealed the same way before we look them up. What ends up on
+//! disk is which sealed tokens each object has (and where), so the segments
+//! still give away how often tokens repeat, but not what they are.
+
+use ::std::fs;
+use ::std::fmt;
+use ::std::mem;
+use ::std::thread;
+use ::std::time::Duration;
+use ::std::path::{Path, PathBuf};
+use ::std::sync::{Arc, Mutex};
+use ::std::sync::atomic::{AtomicBool, Ordering};
+
+use ::rusqlite::{self, Connection};
+
+use ::{CError, CResult};
+use ::fuzzy::{self, is_token_char};
+
+/// Seals tokens before they're written to (or looked up in) a segment. Given
+/// the bytes of a (lowercased) token, gives back the bytes to store in its
+/// place. It has to be deterministic, since searches find objects by sealing
+/// their words the same way: a keyed hash (HMAC) is the idea.
+#[derive(Clone)]
+pub struct TokenSealer {
+    seal: Arc<Fn(&[u8]) -> Result<Vec<u8>, String> + Send + Sync>,
+}
+
+impl TokenSealer {
+    pub fn new<F>(seal: F) -> TokenSealer
+        where F: Fn(&[u8]) -> Result<Vec<u8>, String> + Send + Sync + 'static
+    {
+        TokenSealer {
+            seal: Arc::new(seal),
+        }
+    }
+
+    /// Seal a single token. The result is hex, which the full-text index
+    /// keeps as one token.
+    fn seal(&self, token: &str) -> CResult<String> {
+        let sealed = (self.seal)(token.to_ascii_lowercase().as_bytes())
+            .map_err(|e| CError::Boxed(From::from(format!("error sealing token: {}", e))))?;
+        let mut hex = String::with_capacity(sealed.len() * 2);
+        for byte in sealed {
+            hex.push_str(format!("{:02x}", byte).as_str());
+        }
+        Ok(hex)
+    }
+
+    /// Seal the text of an object, token by token
+    fn seal_text(&self, text: &str) -> CResult<String> {
+        let mut sealed = Vec::new();
+        for token in fuzzy::tokenize(text) {
+            sealed.push(self.seal(token.as_str())?);
+        }
+        Ok(sealed.join(" "))
+    }
+
+    /// Seal the words in a full-text query so they match what `seal_text()`
+    /// stored. Quotes, parentheses, operators (`OR`, `NEAR/3`), exclusions
+    /// (`-word`) and column filters are left as they are. Prefix searches
+    /// can't work against sealed tokens, so `recip*` only finds `recip`.
+    fn seal_query(&self, query: &str) -> CResult<String> {
+        let mut out = String::with_capacity(query.len() * 4);
+        let mut word = String::new();
+        let mut in_quote = false;
+        let mut chars = query.chars().peekable();
+        loop {
+            let c = chars.next();
+            match c {
+                Some(c) if is_token_char(c) => {
+                    word.push(c);
+                    continue;
+                }
+                _ => {}
+            }
+            if word.len() > 0 {
+                let operator = word == "OR" || word == "AND" || word == "NOT" || word == "NEAR";
+                // operators and column names only mean anything outside of
+                // phrases
+                if !in_quote && (operator || c == Some(':')) {
+                    out.push_str(word.as_str());
+                } else {
+                    out.push_str(self.seal(word.as_str())?.as_str());
+                }
+                if !in_quote && word == "NEAR" && c == Some('/') {
+                    out.push('/');
+                    while let Some(&d) = chars.peek() {
+                        if !d.is_ascii_digit() { break; }
+                        out.push(d);
+                        chars.next();
+                    }
+                    word.clear();
+                    continue;
+                }
+                word.clear();
+            }
+            match c {
+                Some('*') => {}
+                Some(c) => {
+                    if c == '"' { in_quote = !in_quote; }
+                    out.push(c);
+                }
+                None => break,
+            }
+        }
+        Ok(out)
+    }
+}
+
+impl fmt::Debug for TokenSealer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TokenSealer")
+    }
+}
+
+/// Configures how our segmented index behaves
+#[derive(Debug, Clone)]
+pub struct SegmentConfig {
+    /// The folder our segments (and manifest) live in
+    pub folder: PathBuf,
+    /// How many objects a segment can hold before we start a new one
+    pub max_docs: i64,
+    /// How many segments we keep open at any given time
+    pub max_resident: usize,
+    /// The value we give sqlite's `mmap_size` pragma for each segment
+    pub mmap_size: i64,
+    /// How often (in ms) the background merger runs. 0 disables it.
+    pub merge_interval: u64,
+    /// Seals every token before it's written
+    pub sealer: TokenSealer,
+}
+
+/// A single on-disk chunk of the index
+struct Segment {
+    id: i64,
+    path: PathBuf,
+    conn: Option<Connection>,
+    docs: i64,
+    last_used: u64,
+}
+
+impl Segment {
+    fn new(id: i64, folder: &Path, docs: i64) -> Segment {
+        let mut path = PathBuf::from(folder);
+        path.push(format!("segment.{}.sqlite", id));
+        Segment {
+            id: id,
+            path: path,
+            conn: None,
+            docs: docs,
+            last_used: 0,
+        }
+    }
+
+    /// Open (map) this segment if it isn't already
+    fn load(&mut self, mmap_size: i64) -> CResult<()> {
+        if self.conn.is_some() { return Ok(()); }
+        let conn = Connection::open(&self.path)?;
+        conn.execute_batch(format!("PRAGMA mmap_size = {};", mmap_size).as_str())?;
+        conn.execute("CREATE VIRTUAL TABLE IF NOT EXISTS objects USING fts4 (id VARCHAR(64) PRIMARY KEY, content TEXT)", &[])?;
+        self.conn = Some(conn);
+        Ok(())
+    }
+
+    /// Close this segment, leaving it on disk
+    fn unload(&mut self) -> CResult<()> {
+        match self.conn.take() {
+            Some(conn) => conn.close()?,
+            None => {}
+        }
+        Ok(())
+    }
+
+    fn is_resident(&self) -> bool {
+        self.conn.is_some()
+    }
+}
+
+/// Holds the state for all our segments. Lives behind a mutex so the
+/// background merger can get at it.
+struct Segments {
+    config: SegmentConfig,
+    manifest: Connection,
+    segments: Vec<Segment>,
+    clock: u64,
+}
+
+impl Segments {
+    fn open(config: SegmentConfig) -> CResult<Segments> {
+        fs::create_dir_all(&config.folder)?;
+        let mut manifest_path = config.folder.clone();
+        manifest_path.push("manifest.sqlite");
+        let manifest = Connection::open(&manifest_path)?;
+        manifest.execute("CREATE TABLE IF NOT EXISTS segments (id INTEGER PRIMARY KEY, docs INTEGER)", &[])?;
+        manifest.execute("CREATE TABLE IF NOT EXISTS objects (id VARCHAR(64) PRIMARY KEY, segment INTEGER)", &[])?;
+        let segments = {
+            let mut query = manifest.prepare("SELECT id, docs FROM segments ORDER BY id ASC")?;
+            let rows = query.query_map(&[], |row| (row.get(0), row.get(1)))?;
+            let mut segments = Vec::new();
+            for row in rows {
+                let (id, docs) = row?;
+                segments.push(Segment::new(id, &config.folder, docs));
+            }
+            segments
+        };
+        Ok(Segments {
+            config: config,
+            manifest: manifest,
+            segments: segments,
+            clock: 0,
+        })
+    }
+
+    /// Mark a segment as used, making sure it's loaded, and evicting the least
+    /// recently used segment(s) if we have too many open.
+    fn touch(&mut self, idx: usize) -> CResult<&Connection> {
+        self.clock += 1;
+        self.segments[idx].last_used = self.clock;
+        loop {
+            let resident = self.segments.iter().filter(|x| x.is_resident()).count();
+            // count the segment we're about to load
+            let resident = if self.segments[idx].is_resident() { resident } else { resident + 1 };
+            if resident <= self.config.max_resident { break; }
+            let coldest = self.segments.iter()
+                .enumerate()
+                .filter(|&(i, x)| i != idx && x.is_resident())
+                .min_by_key(|&(_, x)| x.last_used)
+                .map(|(i, _)| i);
+            match coldest {
+                Some(i) => self.segments[i].unload()?,
+                None => break,
+            }
+        }
+        let mmap_size = self.config.mmap_size;
+        let segment = &mut self.segments[idx];
+        segment.load(mmap_size)?;
+        Ok(segment.conn.as_ref().expect("clouseau::Segments.touch() -- segment conn is None after load"))
+    }
+
+    fn find_segment(&self, segment_id: i64) -> Option<usize> {
+        self.segments.iter().position(|x| x.id == segment_id)
+    }
+
+    /// Grab the index of the segment new objects should go into, creating a
+    /// new segment if the current one is full.
+    fn active_segment(&mut self) -> CResult<usize> {
+        let next_id = match self.segments.last() {
+            Some(x) => {
+                if x.docs < self.config.max_docs {
+                    return Ok(self.segments.len() - 1);
+                }
+                x.id + 1
+            }
+            None => 1,
+        };
+        self.manifest.execute("INSERT INTO segments (id, docs) VALUES (?, 0)", &[&next_id])?;
+        self.segments.push(Segment::new(next_id, &self.config.folder, 0));
+        Ok(self.segments.len() - 1)
+    }
+
+    fn set_docs(&mut self, idx: usize, docs: i64) -> CResult<()> {
+        let segment_id = self.segments[idx].id;
+        self.segments[idx].docs = docs;
+        self.manifest.execute("UPDATE segments SET docs = ? WHERE id = ?", &[&docs, &segment_id])?;
+        Ok(())
+    }
+
+    fn index(&mut self, id: &String, body: &String) -> CResult<()> {
+        let sealed = self.config.sealer.seal_text(body.as_str())?;
+        self.unindex(id)?;
+        let idx = self.active_segment()?;
+        {
+            let conn = self.touch(idx)?;
+            conn.execute("INSERT INTO objects (id, content) VALUES (?, ?)", &[id, &sealed])?;
+        }
+        let docs = self.segments[idx].docs + 1;
+        self.set_docs(idx, docs)?;
+        let segment_id = self.segments[idx].id;
+        self.manifest.execute("INSERT OR REPLACE INTO objects (id, segment) VALUES (?, ?)", &[id, &segment_id])?;
+        Ok(())
+    }
+
+    fn unindex(&mut self, id: &String) -> CResult<()> {
+        let segment_id: i64 = match self.manifest.query_row("SELECT segment FROM objects WHERE id = ?", &[id], |row| row.get(0)) {
+            Ok(x) => x,
+            Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(()),
+            Err(e) => return Err(From::from(e)),
+        };
+        match self.find_segment(segment_id) {
+            Some(idx) => {
+                {
+                    let conn = self.touch(idx)?;
+                    conn.execute("DELETE FROM objects WHERE id = ?", &[id])?;
+                }
+                let docs = self.segments[idx].docs - 1;
+                self.set_docs(idx, if docs < 0 { 0 } else { docs })?;
+            }
+            None => {}
+        }
+        self.manifest.execute("DELETE FROM objects WHERE id = ?", &[id])?;
+        Ok(())
+    }
+
+    fn find(&mut self, terms: &String) -> CResult<Vec<String>> {
+        let sealed = self.config.sealer.seal_query(terms.as_str())?;
+        let mut ids: Vec<String> = Vec::new();
+        for idx in 0..self.segments.len() {
+            if self.segments[idx].docs <= 0 { continue; }
+            let conn = self.touch(idx)?;
+            let mut query = conn.prepare("SELECT id FROM objects WHERE content match ?")?;
+            let rows = query.query_map(&[&sealed], |row| row.get("id"))?;
+            for id in rows { ids.push(id?); }
+        }
+        ids.sort();
+        ids.dedup();
+        Ok(ids)
+    }
+
+    /// Run one round of merging. We remove any empty sealed segments, then
+    /// merge the two smallest sealed segments if they fit into one. Returns
+    /// true if anything changed.
+    ///
+    /// The last (active) segment is never touched.
+    fn merge_step(&mut self) -> CResult<bool> {
+        if self.segments.len() < 2 { return Ok(false); }
+        let sealed = self.segments.len() - 1;
+
+        // empty segments can just go away
+        let empty = self.segments[0..sealed].iter().position(|x| x.docs <= 0);
+        if let Some(idx) = empty {
+            let mut segment = self.segments.remove(idx);
+            segment.unload()?;
+            self.manifest.execute("DELETE FROM segments WHERE id = ?", &[&segment.id])?;
+            fs::remove_file(&segment.path)?;
+            return Ok(true);
+        }
+
+        let mut small = self.segments[0..sealed].iter()
+            .enumerate()
+            .filter(|&(_, x)| x.docs < (self.config.max_docs / 2))
+            .map(|(i, x)| (i, x.docs))
+            .collect::<Vec<_>>();
+        if small.len() < 2 { return Ok(false); }
+        small.sort_by_key(|&(_, docs)| docs);
+        let (into_idx, from_idx) = if small[0].0 < small[1].0 {
+            (small[0].0, small[1].0)
+        } else {
+            (small[1].0, small[0].0)
+        };
+        let docs = small[0].1 + small[1].1;
+
+        // close the segment we're merging from so nobody else is holding it
+        self.segments[from_idx].unload()?;
+        let from_id = self.segments[from_idx].id;
+        let into_id = self.segments[into_idx].id;
+        let from_path = match self.segments[from_idx].path.to_str() {
+            Some(x) => String::from(x),
+            None => return Err(CError::Boxed(From::from(format!("bad segment path: {:?}", self.segments[from_idx].path)))),
+        };
+        {
+            let conn = self.touch(into_idx)?;
+            conn.execute("ATTACH DATABASE ? AS merging", &[&from_path])?;
+            let res = conn.execute("INSERT INTO objects (id, content) SELECT id, content FROM merging.objects", &[]);
+            conn.execute("DETACH DATABASE merging", &[])?;
+            res?;
+        }
+        self.manifest.execute("UPDATE objects SET segment = ? WHERE segment = ?", &[&into_id, &from_id])?;
+        self.manifest.execute("DELETE FROM segments WHERE id = ?", &[&from_id])?;
+        self.set_docs(into_idx, docs)?;
+        let segment = self.segments.remove(from_idx);
+        fs::remove_file(&segment.path)?;
+        Ok(true)
+    }
+
+    fn close(&mut self) -> CResult<()> {
+        for segment in &mut self.segments {
+            segment.unload()?;
+        }
+        let mut conn = Connection::open_in_memory()?;
+        mem::swap(&mut self.manifest, &mut conn);
+        conn.close()?;
+        Ok(())
+    }
+}
+
+/// A full-text index split into lazily-loaded, memory-mapped segments on disk.
+/// Has the same interface as Clouseau's in-memory index.
+pub struct SegmentedIndex {
+    folder: PathBuf,
+    inner: Arc<Mutex<Segments>>,
+    quit: Arc<AtomicBool>,
+    merger: Option<thread::JoinHandle<()>>,
+}
+
+impl SegmentedIndex {
+    /// Open (or create) a segmented index in the configured folder, and start
+    /// our background merger.
+    pub fn open(config: SegmentConfig) -> CResult<SegmentedIndex> {
+        let folder = config.folder.clone();
+        let merge_interval = config.merge_interval;
+        let inner = Arc::new(Mutex::new(Segments::open(config)?));
+        let quit = Arc::new(AtomicBool::new(false));
+        let merger = if merge_interval > 0 {
+            let inner = inner.clone();
+            let quit = quit.clone();
+            let handle = thread::Builder::new().name(String::from("clouseau:merge")).spawn(move || {
+                let mut waited = 0;
+                while !quit.load(Ordering::SeqCst) {
+                    // sleep in small chunks so we can quit quickly
+                    thread::sleep(Duration::from_millis(100));
+                    waited += 100;
+                    if waited < merge_interval { continue; }
+                    waited = 0;
+                    let mut guard = inner.lock().expect("clouseau::SegmentedIndex -- merger failed to grab lock");
+                    // errors here aren't fatal, we'll just try again next round
+                    match guard.merge_step() {
+                        Ok(_) => {}
+                        Err(_) => {}
+                    }
+                }
+            })?;
+            Some(handle)
+        } else {
+            None
+        };
+        Ok(SegmentedIndex {
+            folder: folder,
+            inner: inner,
+            quit: quit,
+            merger: merger,
+        })
+    }
+
+    /// Index an object
+    pub fn index(&self, id: &String, body: &String) -> CResult<()> {
+        let mut guard = self.inner.lock().expect("clouseau::SegmentedIndex.index() -- failed to grab lock");
+        guard.index(id, body)
+    }
+
+    /// Remove an object from the index
+    pub fn unindex(&self, id: &String) -> CResult<()> {
+        let mut guard = self.inner.lock().expect("clouseau::SegmentedIndex.unindex() -- failed to grab lock");
+        guard.unindex(id)
+    }
+
+    /// Find things in the index
+    pub fn find(&self, terms: &String) -> CResult<Vec<String>> {
+        let mut guard = self.inner.lock().expect("clouseau::SegmentedIndex.find() -- failed to grab lock");
+        guard.find(terms)
+    }
+
+    /// Merge segments until there's nothing left to merge. The background
+    /// merger does this a step at a time, but sometimes you just want it done.
+    pub fn merge(&self) -> CResult<()> {
+        let mut guard = self.inner.lock().expect("clouseau::SegmentedIndex.merge() -- failed to grab lock");
+        while guard.merge_step()? {}
+        Ok(())
+    }
+
+    /// Returns (number of segments, number of segments currently loaded)
+    pub fn stats(&self) -> (usize, usize) {
+        let guard = self.inner.lock().expect("clouseau::SegmentedIndex.stats() -- failed to grab lock");
+        let resident = guard.segments.iter().filter(|x| x.is_resident()).count();
+        (guard.segments.len(), resident)
+    }
+
+    /// Stop the merger and close all our segments, leaving them on disk
+    pub fn close(&mut self) -> CResult<()> {
+        self.quit.store(true, Ordering::SeqCst);
+        match self.merger.take() {
+            Some(handle) => { let _ = handle.join(); }
+            None => {}
+        }
+        let mut guard = self.inner.lock().expect("clouseau::SegmentedIndex.close() -- failed to grab lock");
+        guard.close()
+    }
+
+    /// Close the index and remove it from disk entirely
+    pub fn destroy(&mut self) -> CResult<()> {
+        self.close()?;
+        fs::remove_dir_all(&self.folder)?;
+        Ok(())
+    }
+}
+
+impl Drop for SegmentedIndex {
+    fn drop(&mut self) {
+        self.quit.store(true, Ordering::SeqCst);
+        match self.merger.take() {
+            Some(handle) => { let _ = handle.join(); }
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::std::env;
+    use ::std::io::Read;
+
+    fn config(name: &str) -> SegmentConfig {
+        let mut folder = env::temp_dir();
+        folder.push(format!("clouseau-test-{}", name));
+        let _ = fs::remove_dir_all(&folder);
+        config_in(folder)
+    }
+
+    fn config_in(folder: PathBuf) -> SegmentConfig {
+        SegmentConfig {
+            folder: folder,
+            max_docs: 2,
+            max_resident: 1,
+            mmap_size: 1048576,
+            merge_interval: 0,
+            // not a real seal, but it's deterministic and nothing comes out
+            // the way it went in
+            sealer: TokenSealer::new(|token| Ok(token.iter().rev().map(|x| x ^ 0x5a).collect())),
+        }
+    }
+
+    #[test]
+    fn seals_queries() {
+        let sealer = config("seal-queries").sealer;
+        let seal = |x: &str| sealer.seal(x).unwrap();
+        assert_eq!(sealer.seal_text("Tea, and CHEESE").unwrap(), format!("{} {} {}", seal("tea"), seal("and"), seal("cheese")));
+        assert_eq!(sealer.seal_query("tea OR cheese").unwrap(), format!("{} OR {}", seal("tea"), seal("cheese")));
+        assert_eq!(sealer.seal_query("\"tea and cheese\" -beef").unwrap(), format!("\"{} {} {}\" -{}", seal("tea"), seal("and"), seal("cheese"), seal("beef")));
+        assert_eq!(sealer.seal_query("(tea NEAR/3 cheese) NOT it's").unwrap(), format!("({} NEAR/3 {}) NOT {}'{}", seal("tea"), seal("cheese"), seal("it"), seal("s")));
+        assert_eq!(sealer.seal_query("\"OR\" chees*").unwrap(), format!("\"{}\" {}", seal("or"), seal("chees")));
+        assert_eq!(sealer.seal_query("content:tea").unwrap(), format!("content:{}", seal("tea")));
+    }
+
+    #[test]
+    fn segments_never_hold_plain_text() {
+        let cfg = config("sealed");
+        let folder = cfg.folder.clone();
+        let mut idx = SegmentedIndex::open(cfg).unwrap();
+        idx.index(&String::from("1111"), &String::from("laughing and singing")).unwrap();
+        idx.index(&String::from("2222"), &String::from("tea and cheese")).unwrap();
+        idx.index(&String::from("3333"), &String::from("finest beef shoulder")).unwrap();
+        idx.close().unwrap();
+        for entry in fs::read_dir(&folder).unwrap() {
+            let mut contents = Vec::new();
+            fs::File::open(entry.unwrap().path()).unwrap().read_to_end(&mut contents).unwrap();
+            let contents = String::from_utf8_lossy(&contents);
+            for word in &["laughing", "singing", "cheese", "beef", "shoulder"] {
+                assert!(!contents.contains(word));
+            }
+        }
+        let mut idx = SegmentedIndex::open(config_in(folder)).unwrap();
+        assert_eq!(idx.find(&String::from("\"tea and cheese\"")).unwrap(), vec!["2222"]);
+        assert_eq!(idx.find(&String::from("Beef OR singing")).unwrap(), vec!["1111", "3333"]);
+        idx.destroy().unwrap();
+    }
+
+    #[test]
+    fn segments_index_find_merge() {
+        let mut idx = SegmentedIndex::open(config("segments")).unwrap();
+        idx.index(&String::from("1111"), &String::from("some say your nose")).unwrap();
+        idx.index(&String::from("2222"), &String::from("some say your toes")).unwrap();
+        idx.index(&String::from("3333"), &String::from("i think it's your mind")).unwrap();
+        idx.index(&String::from("4444"), &String::from("what's the ugliest part of your body?")).unwrap();
+        idx.index(&String::from("5555"), &String::from("some say it's your face")).unwrap();
+        assert_eq!(idx.stats(), (3, 1));
+
+        assert_eq!(idx.find(&String::from("some say")).unwrap(), vec!["1111", "2222", "5555"]);
+        assert_eq!(idx.find(&String::from("ugliest")).unwrap(), vec!["4444"]);
+
+        // reindexing an object shouldn't duplicate it
+        idx.index(&String::from("1111"), &String::from("some say your knees")).unwrap();
+        assert_eq!(idx.find(&String::from("nose")).unwrap().len(), 0);
+        assert_eq!(idx.find(&String::from("knees")).unwrap(), vec!["1111"]);
+
+        // shrink some segments and merge them together
+        idx.unindex(&String::from("2222")).unwrap();
+        idx.unindex(&String::from("4444")).unwrap();
+        idx.merge().unwrap();
+        assert_eq!(idx.find(&String::from("some say")).unwrap(), vec!["1111", "5555"]);
+        assert_eq!(idx.find(&String::from("mind")).unwrap(), vec!["3333"]);
+        idx.destroy().unwrap();
+    }
+
+    #[test]
+    fn segments_reopen() {
+        let cfg = config("reopen");
+        {
+            let mut idx = SegmentedIndex::open(cfg.clone()).unwrap();
+            idx.index(&String::from("1111"), &String::from("laughing and singing")).unwrap();
+            idx.index(&String::from("2222"), &String::from("tea and cheese")).unwrap();
+            idx.index(&String::from("3333"), &String::from("finest beef shoulder")).unwrap();
+            idx.close().unwrap();
+        }
+        let mut idx = SegmentedIndex::open(cfg).unwrap();
+        assert_eq!(idx.stats(), (2, 0));
+        assert_eq!(idx.find(&String::from("cheese")).unwrap(), vec!["2222"]);
+        idx.destroy().unwrap();
+    }
+}
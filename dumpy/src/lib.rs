@@ -44,6 +44,7 @@ pub enum SearchVal {
     Bool(bool),
     String(String),
     Int(i32),
+    Float(f64),
 }
 impl ToSql for SearchVal {
     fn to_sql(&self) -> rusqlite::Result<ToSqlOutput> {
@@ -57,6 +58,9 @@ impl ToSql for SearchVal {
             SearchVal::Int(ref x) => {
                 ToSqlOutput::from(x.clone())
             }
+            SearchVal::Float(ref x) => {
+                ToSqlOutput::from(x.clone())
+            }
         };
         Ok(res)
     }
@@ -373,6 +377,19 @@ impl Dumpy {
         conn.execute("DELETE FROM dumpy_kv WHERE key = $1", &[&key])?;
         Ok(())
     }
+
+    /// Grab all the keys in the k/v store that start with the given prefix
+    pub fn kv_keys(&self, conn: &Connection, prefix: &str) -> DResult<Vec<String>> {
+        // escape LIKE's wildcards so the prefix is matched literally
+        let pattern = format!("{}%", prefix.replace("\\", "\\\\").replace("%", "\\%").replace("_", "\\_"));
+        let mut query = conn.prepare("SELECT key FROM dumpy_kv WHERE key LIKE $1 ESCAPE '\\' ORDER BY key ASC")?;
+        let rows = query.query_map(&[&pattern], |row| -> String { row.get("key") })?;
+        let mut keys = Vec::new();
+        for key in rows {
+            keys.push(key?);
+        }
+        Ok(keys)
+    }
 }
 
 
@@ -521,5 +538,15 @@ mod tests {
         dumpy.kv_delete(&conn, "some_setting").unwrap();
         let val = dumpy.kv_get(&conn, "some_setting").unwrap();
         assert_eq!(val, None);
+
+        dumpy.kv_set(&conn, "sync:base:1", &String::from("a")).unwrap();
+        dumpy.kv_set(&conn, "sync:base:2", &String::from("b")).unwrap();
+        dumpy.kv_set(&conn, "sync_id", &String::from("c")).unwrap();
+        dumpy.kv_set(&conn, "syncXbase", &String::from("d")).unwrap();
+        let keys = dumpy.kv_keys(&conn, "sync:base:").unwrap();
+        assert_eq!(keys, vec!["sync:base:1", "sync:base:2"]);
+        // wildcards in the prefix are taken literally
+        let keys = dumpy.kv_keys(&conn, "sync_").unwrap();
+        assert_eq!(keys, vec!["sync_id"]);
     }
 }
@@ -0,0 +1,7 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate turtl_core;
+
+fuzz_target!(|data: &[u8]| {
+    turtl_core::fuzz::sync_record(data);
+});
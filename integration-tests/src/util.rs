@@ -77,6 +77,8 @@ from_err!(::std::string::FromUtf8Error);
 pub struct Response {
     e: u32,
     d: Value,
+    #[serde(default)]
+    w: Vec<Value>,
 }
 
 lazy_static! {
@@ -138,7 +140,7 @@ pub fn dispatch_ass(args: Value) -> Value {
     if res.e != 0 {
         panic!("dispatch: {}", res.d);
     }
-    let Response {e: _e, d} = res;
+    let Response {e: _e, d, ..} = res;
     d
 }
 
@@ -58,7 +58,7 @@ mod tests {
         let note_id2: String = jedi::get(&["note_id"], &evdata).unwrap();
 
         dispatch_ass(json!(["profile:sync:model", "delete", "file", {"id": note_id}]));
-        dispatch_ass(json!(["user:delete-account"]));
+        dispatch_ass(json!(["user:delete-account", "slippyslappy@turtlapp.com", password]));
 
         assert_eq!(note_id, note_id2);
         end(handle);
@@ -125,7 +125,7 @@ mod tests {
         let import4 = dispatch_ass(json!(["profile:import", "full", export]));
         let profile4: Profile = jedi::from_val(dispatch_ass(json!(["profile:load"]))).unwrap();
         // goodbyyye, misterrrrrrr aaaandersonnnnn
-        dispatch_ass(json!(["user:delete-account"]));
+        dispatch_ass(json!(["user:delete-account", "slippyslappy@turtlapp.com", password]));
 
         assert_eq!(profile0.spaces.len(), 3);
         assert_eq!(profile0.boards.len(), 3);
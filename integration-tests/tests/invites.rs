@@ -19,6 +19,11 @@ mod tests {
         user_id
     }
 
+    fn delete_tmp() {
+        let password: String = config::get(&["integration_tests", "login", "password"]).unwrap();
+        dispatch_ass(json!(["user:delete-account", "slippyslappy@turtlapp.com", password]));
+    }
+
     fn login_testacct() -> String {
         dispatch_ass(json!(["app:wipe-user-data"]));
         let username: String = config::get(&["integration_tests", "login", "username"]).unwrap();
@@ -110,7 +115,7 @@ mod tests {
         assert_eq!(title, "Personal");
         dispatch_ass(json!(["profile:space:leave", space_id]));
         login_tmp();
-        dispatch_ass(json!(["user:delete-account"]));
+        delete_tmp();
 
         // test send invite, accept invite, set owner (and set back), edit
         // member, delete member
@@ -132,7 +137,7 @@ mod tests {
         dispatch_ass(json!(["profile:space:edit-member", member]));
         wait_on("sync:update");
         dispatch_ass(json!(["profile:space:delete-member", space_id, test_user_id]));
-        dispatch_ass(json!(["user:delete-account"]));
+        delete_tmp();
 
         // test send invite, edit invite, then delete invite (as receiver)
         let space = setup_invite();
@@ -144,7 +149,7 @@ mod tests {
         let invite_id: String = jedi::get(&["id"], &invite).unwrap();
         dispatch_ass(json!(["profile:delete-invite", invite_id]));
         login_tmp();
-        dispatch_ass(json!(["user:delete-account"]));
+        delete_tmp();
 
         // test send invite, delete invite (as sender)
         let space = setup_invite();
@@ -152,7 +157,7 @@ mod tests {
         let invite_id: String = jedi::get(&["id"], &invite).unwrap();
         let space_id: String = jedi::get(&["space_id"], &invite).unwrap();
         dispatch_ass(json!(["profile:space:delete-invite", space_id, invite_id]));
-        dispatch_ass(json!(["user:delete-account"]));
+        delete_tmp();
 
         end(handle);
     }
@@ -78,7 +78,7 @@ mod tests {
             }
         ]));
 
-        dispatch_ass(json!(["user:delete-account"]));
+        dispatch_ass(json!(["user:delete-account", "slippyslappy@turtlapp.com", new_password]));
         end(handle);
 
         let num_notes: u32 = jedi::get(&["total"], &note_search).unwrap();
@@ -98,7 +98,7 @@ mod tests {
         let profile = dispatch_ass(json!(["profile:load"]));
         let search = dispatch_ass(json!(["profile:find-notes", {"space_id": to_space_id}]));
 
-        dispatch_ass(json!(["user:delete-account"]));
+        dispatch_ass(json!(["user:delete-account", "slippyslappy@turtlapp.com", password]));
 
         let new_num_boards = jedi::get::<Vec<Value>>(&["boards"], &profile).unwrap().len();
         let new_num_notes = jedi::get::<u32>(&["total"], &search).unwrap();
@@ -45,7 +45,7 @@ mod tests {
         let migrate_space = migrate_space.unwrap();
         let notes = dispatch_ass(json!(["profile:find-notes", {"space_id": migrate_space.id, "sort": "id"}]));
         wait_on("sync:outgoing:complete");
-        dispatch_ass(json!(["user:delete-account"]));
+        dispatch_ass(json!(["user:delete-account", "slippyslappy@turtlapp.com", new_password]));
         end(handle);
 
         let notes: Vec<Value> = jedi::get(&["notes"], &notes).unwrap();
@@ -31,7 +31,7 @@ mod tests {
         let profile_res = dispatch(json!(["profile:load"]));
         let user_privkey: Option<String> = jedi::get_opt(&["user", "privkey"], &profile_res.d);
 
-        dispatch_ass(json!(["user:delete-account"]));
+        dispatch_ass(json!(["user:delete-account", "slippyslappy+losemykey@turtlapp.com", password]));
         assert!(user_privkey.is_some());
         end(handle);
     }
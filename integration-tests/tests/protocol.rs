@@ -0,0 +1,30 @@
+include!("../src/util.rs");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protocol() {
+        let handle = init();
+        let res = dispatch(json!(["app:handshake", 9999]));
+        assert_eq!(res.e, 1);
+        let shake = dispatch_ass(json!(["app:handshake", 2]));
+        let version: u32 = jedi::get(&["protocol_version"], &shake).unwrap();
+        assert_eq!(version, 2);
+
+        // deprecated shapes still work, but warn
+        let res = dispatch(json!(["sync:shutdown"]));
+        assert_eq!(res.e, 0);
+        assert_eq!(res.w.len(), 1);
+        let cmd: String = jedi::get(&["cmd"], &res.w[0]).unwrap();
+        assert_eq!(cmd, "sync:shutdown");
+        let res = dispatch(json!(["sync:shutdown", true]));
+        assert_eq!(res.w.len(), 0);
+
+        let usage = dispatch_ass(json!(["app:protocol:deprecation-usage"]));
+        let count: u64 = jedi::get(&["sync:shutdown"], &usage).unwrap();
+        assert_eq!(count, 1);
+        end(handle);
+    }
+}
@@ -8,7 +8,7 @@ extern crate syn;
 use std::collections::HashMap;
 use proc_macro::TokenStream;
 
-#[proc_macro_derive(Protected, attributes(protected_modeltype, protected_field))]
+#[proc_macro_derive(Protected, attributes(protected_modeltype, protected_field, protected_validate))]
 pub fn protected(input: TokenStream) -> TokenStream {
     let s = input.to_string();
 
@@ -92,7 +92,8 @@ fn match_rename_fields(map: &HashMap<String, String>, fields: Vec<&syn::Ident>)
 /// Finds all fields in a Struct that are marked with a
 ///   #[protected_field(...)]
 /// meta item and match the given field type (probably either "public",
-/// "private", or "submodel").
+/// "private", "submodel", or "lazy"). If `restrict` is set, submodel fields
+/// are left out.
 fn find_protected_fields<'a>(body: &'a syn::Body, field_type: &str, restrict: bool) -> Vec<&'a syn::Ident> {
     match body {
         &syn::Body::Struct(ref data) => {
@@ -105,7 +106,13 @@ fn find_protected_fields<'a>(body: &'a syn::Body, field_type: &str, restrict: bo
                         match attr.value {
                             syn::MetaItem::List(ref id, ref nested) => {
                                 if id.as_ref() == "protected_field" {
-                                    if !restrict || (restrict && nested.len() <= 1) {
+                                    let is_submodel = nested.iter().any(|meta| {
+                                        match meta {
+                                            &syn::NestedMetaItem::MetaItem(syn::MetaItem::Word(ref subident)) => subident.as_ref() == "submodel",
+                                            _ => false,
+                                        }
+                                    });
+                                    if !restrict || !is_submodel {
                                         for meta in nested {
                                             match meta {
                                                 &syn::NestedMetaItem::MetaItem(ref submeta) => {
@@ -136,6 +143,48 @@ fn find_protected_fields<'a>(body: &'a syn::Body, field_type: &str, restrict: bo
     }
 }
 
+/// Builds the checks for all fields marked with a
+///   #[protected_validate(required, max_len = 256, url)]
+/// meta item. `required` can also be given the message to use, as in
+///   #[protected_validate(required = "Please give your board a title")]
+fn find_validators(body: &syn::Body, rename_map: &HashMap<String, String>) -> Vec<quote::Tokens> {
+    let mut validators = Vec::new();
+    let fields = match body {
+        &syn::Body::Struct(ref data) => data.fields(),
+        _ => panic!("You can only use #[derive(Protected)] on Structs"),
+    };
+    for field in fields {
+        let ident = field.ident.as_ref().expect("protected_derive::find_validators() -- failed to grab field");
+        let name = match_rename_fields(rename_map, vec![ident]).remove(0);
+        for attr in &field.attrs {
+            let nested = match attr.value {
+                syn::MetaItem::List(ref id, ref nested) if id.as_ref() == "protected_validate" => nested,
+                _ => continue,
+            };
+            for meta in nested {
+                let validator = match meta {
+                    &syn::NestedMetaItem::MetaItem(syn::MetaItem::Word(ref rule)) if rule.as_ref() == "required" => {
+                        quote! { errors.extend(::models::validate::required(#name, &self.#ident, None)); }
+                    }
+                    &syn::NestedMetaItem::MetaItem(syn::MetaItem::NameValue(ref rule, syn::Lit::Str(ref message, _))) if rule.as_ref() == "required" => {
+                        quote! { errors.extend(::models::validate::required(#name, &self.#ident, Some(t!(#message)))); }
+                    }
+                    &syn::NestedMetaItem::MetaItem(syn::MetaItem::NameValue(ref rule, syn::Lit::Int(max, _))) if rule.as_ref() == "max_len" => {
+                        let max = max as usize;
+                        quote! { errors.extend(::models::validate::max_len(#name, &self.#ident, #max)); }
+                    }
+                    &syn::NestedMetaItem::MetaItem(syn::MetaItem::Word(ref rule)) if rule.as_ref() == "url" => {
+                        quote! { errors.extend(::models::validate::url(#name, &self.#ident)); }
+                    }
+                    _ => panic!("protected_derive::find_validators() -- unknown validation rule on field `{}`: {:?}", name, meta),
+                };
+                validators.push(validator);
+            }
+        }
+    }
+    validators
+}
+
 fn get_struct_modeltype(attrs: &Vec<::syn::Attribute>) -> Option<String> {
     // [Attribute {
     //      style: Outer,
@@ -197,6 +246,31 @@ fn impl_protected(ast: &syn::MacroInput) -> quote::Tokens {
     let submodel_fields9 = submodel_fields1.clone();
     let submodel_fields_rename1 = match_rename_fields(&rename_field_map, submodel_fields1.clone());
     let submodel_fields_rename2 = match_rename_fields(&rename_field_map, submodel_fields1.clone());
+    let lazy_fields: Vec<&syn::Ident> = find_protected_fields(&ast.body, "lazy", false);
+    let lazy_fields_rename = match_rename_fields(&rename_field_map, lazy_fields.clone());
+    // lazy fields are encrypted into their own `lazy_body` field, which models
+    // without any don't have
+    let lazy_body_impl = if lazy_fields.len() > 0 {
+        quote! {
+            fn get_lazy_body<'a>(&'a self) -> Option<&'a String> {
+                self.lazy_body.as_ref()
+            }
+
+            fn set_lazy_body(&mut self, body: Option<String>) {
+                self.lazy_body = body;
+            }
+        }
+    } else {
+        quote! {
+            fn get_lazy_body<'a>(&'a self) -> Option<&'a String> {
+                None
+            }
+
+            fn set_lazy_body(&mut self, _body: Option<String>) {}
+        }
+    };
+
+    let validators = find_validators(&ast.body, &rename_field_map);
 
     let des_mapper = |field: &syn::Ident| -> quote::Tokens {
         let field_name = String::from(field.as_ref());
@@ -286,6 +360,30 @@ fn impl_protected(ast: &syn::MacroInput) -> quote::Tokens {
                 ]
             }
 
+            fn lazy_fields(&self) -> Vec<&'static str> {
+                vec![
+                    #( #lazy_fields_rename, )*
+                ]
+            }
+
+            #lazy_body_impl
+
+            fn lazy_pending(&self) -> bool {
+                self._lazy_pending
+            }
+
+            fn set_lazy_pending(&mut self, pending: bool) {
+                self._lazy_pending = pending;
+            }
+
+            fn get_snapshot<'a>(&'a self) -> Option<&'a ::models::protected::Snapshot> {
+                self._snapshot.as_ref()
+            }
+
+            fn set_snapshot(&mut self, snapshot: Option<::models::protected::Snapshot>) {
+                self._snapshot = snapshot;
+            }
+
             #[allow(unused_variables)]  // required in case we have no submodels
             fn submodel_data(&self, field: &str, private: bool) -> ::error::TResult<::jedi::Value> {
                 #(
@@ -301,6 +399,13 @@ fn impl_protected(ast: &syn::MacroInput) -> quote::Tokens {
                 Err(::error::TError::MissingField(format!("The field {} wasn't found in this model", field)))
             }
 
+            fn validate_fields(&self) -> Vec<::error::FieldError> {
+                #[allow(unused_mut)]    // required in case we have no checks
+                let mut errors: Vec<::error::FieldError> = Vec::new();
+                #( #validators )*
+                errors
+            }
+
             fn _set_key_on_submodels(&mut self) {
                 if self.key().is_none() { return; }
                 #(
@@ -344,6 +449,8 @@ fn impl_protected(ast: &syn::MacroInput) -> quote::Tokens {
                 let mut model = Self::clone_from(::jedi::to_val(self).map_err(|e| toterr!(e))?)?;
                 let key = self.key().map(|x| x.clone());
                 model.set_key(key);
+                model._lazy_pending = self._lazy_pending;
+                model._snapshot = self._snapshot.clone();
                 Ok(model)
             }
 
@@ -2,8 +2,9 @@
 //! our user authentication.
 
 use ::std::sync::RwLock;
-use ::std::io::Read;
-use ::std::time::Duration;
+use ::std::io::{Read, Write};
+use ::std::time::{Duration, Instant};
+use ::std::collections::HashMap;
 
 use ::config;
 use ::hyper;
@@ -14,6 +15,9 @@ use ::hyper::header;
 pub use ::hyper::header::Headers;
 pub use ::hyper::status::StatusCode as Status;
 use ::jedi::{self, Value, DeserializeOwned};
+use ::flate2::Compression;
+use ::flate2::read::GzDecoder;
+use ::flate2::write::GzEncoder;
 
 use ::error::{TResult, TError};
 use ::crypto;
@@ -36,11 +40,63 @@ impl ApiConfig {
     }
 }
 
+/// Holds a cached GET response along with the validators (ETag/Last-Modified)
+/// the server gave us for it.
+struct CacheEntry {
+    /// The raw response body
+    body: String,
+    /// The ETag header from the response, if any
+    etag: Option<String>,
+    /// The Last-Modified header from the response, if any
+    last_modified: Option<String>,
+    /// When we last got confirmation from the server this entry is current
+    fetched: Instant,
+}
+
+impl CacheEntry {
+    /// Is this entry young enough to use without asking the server?
+    fn is_fresh(&self, ttl: &Duration) -> bool {
+        self.fetched.elapsed() < *ttl
+    }
+}
+
+/// Grab the first value of a raw header as a string
+pub fn raw_header(headers: &Headers, name: &str) -> Option<String> {
+    match headers.get_raw(name) {
+        Some(vals) if vals.len() > 0 => String::from_utf8(vals[0].clone()).ok(),
+        _ => None,
+    }
+}
+
+/// Gzip some data
+fn gzip(data: &[u8]) -> ::std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(data.len() / 4), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// Un-gzip some data
+fn gunzip(data: &[u8]) -> ::std::io::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len() * 4);
+    GzDecoder::new(data).read_to_end(&mut out)?;
+    Ok(out)
+}
+
+/// Whether or not a header lists gzip as one of its encodings
+fn lists_gzip(headers: &Headers, name: &str) -> bool {
+    match raw_header(headers, name) {
+        Some(x) => x.split(',').any(|enc| enc.trim().eq_ignore_ascii_case("gzip")),
+        None => false,
+    }
+}
+
 /// A struct used for building API requests
 pub struct ApiReq {
     headers: Headers,
     timeout: Duration,
     data: Value,
+    cache: bool,
+    gzip: bool,
 }
 
 impl ApiReq {
@@ -50,6 +106,8 @@ impl ApiReq {
             headers: Headers::new(),
             timeout: Duration::new(10, 0),
             data: Value::Null,
+            cache: false,
+            gzip: false,
         }
     }
 
@@ -70,6 +128,20 @@ impl ApiReq {
         self.data = data;
         self
     }
+
+    /// Allow this request's response to be cached (only applies to GETs). See
+    /// `Api::call()`.
+    pub fn cache<'a>(mut self) -> Self {
+        self.cache = true;
+        self
+    }
+
+    /// Ask for a gzipped response, and gzip this request's body if the server
+    /// has told us it can handle it. See `Api::send()`.
+    pub fn gzip<'a>(mut self) -> Self {
+        self.gzip = true;
+        self
+    }
 }
 
 /// Used to store some info we want when we send a response to call_end()
@@ -91,6 +163,13 @@ impl CallInfo {
 /// Our Api object. Responsible for making outbound calls to our Turtl server.
 pub struct Api {
     config: RwLock<ApiConfig>,
+    /// Cached GET responses, keyed by resource
+    cache: RwLock<HashMap<String, CacheEntry>>,
+    /// Whether the server takes gzipped request bodies. None until it tells
+    /// us one way or the other.
+    gzip_bodies: RwLock<Option<bool>>,
+    /// Whether the server takes note deltas (see `sync::delta`)
+    deltas: RwLock<bool>,
 }
 
 impl Api {
@@ -98,22 +177,93 @@ impl Api {
     pub fn new() -> Api {
         Api {
             config: RwLock::new(ApiConfig::new()),
+            cache: RwLock::new(HashMap::new()),
+            gzip_bodies: RwLock::new(None),
+            deltas: RwLock::new(false),
+        }
+    }
+
+    /// Grab our cache TTL from config. A TTL of 0 means we always revalidate
+    /// with the server (but can still get a cheap 304 back).
+    fn cache_ttl(&self) -> Duration {
+        let ttl: u64 = config::get(&["api", "cache", "ttl"]).unwrap_or(60);
+        Duration::from_secs(ttl)
+    }
+
+    /// Is response caching enabled?
+    fn cache_enabled(&self) -> bool {
+        config::get(&["api", "cache", "enabled"]).unwrap_or(true)
+    }
+
+    /// Is gzip (for requests built with `ApiReq::gzip()`) turned on?
+    fn gzip_enabled(&self) -> bool {
+        config::get(&["api", "gzip", "enabled"]).unwrap_or(true)
+    }
+
+    /// Should we gzip request bodies (when asked to)? Only if the server has
+    /// shown us it knows what to do with them.
+    fn gzip_bodies(&self) -> bool {
+        *lockr!(self.gzip_bodies) == Some(true)
+    }
+
+    /// Learn whether the server takes gzipped bodies from its response headers
+    /// (a gzipped response or an Accept-Encoding listing gzip means yes). Once
+    /// the server has rejected a gzipped body, we stop listening.
+    fn learn_gzip(&self, headers: &Headers) {
+        if lockr!(self.gzip_bodies).is_some() { return; }
+        if lists_gzip(headers, "Content-Encoding") || lists_gzip(headers, "Accept-Encoding") {
+            debug!("api::learn_gzip() -- server speaks gzip, compressing request bodies from here on");
+            *lockw!(self.gzip_bodies) = Some(true);
+        }
+    }
+
+    /// Learn whether the server takes note deltas from its response headers
+    fn learn_deltas(&self, headers: &Headers) {
+        if *lockr!(self.deltas) { return; }
+        if raw_header(headers, "X-Turtl-Delta").map(|x| x == "1").unwrap_or(false) {
+            debug!("api::learn_deltas() -- server takes deltas, sending them from here on");
+            *lockw!(self.deltas) = true;
         }
     }
 
+    /// Whether the server has told us it takes note deltas
+    pub fn deltas_supported(&self) -> bool {
+        *lockr!(self.deltas)
+    }
+
+    /// Wipe out our response cache
+    pub fn clear_cache(&self) {
+        let ref mut cache_guard = lockw!(self.cache);
+        cache_guard.clear();
+    }
+
+    /// Remove any cached responses that a write to the given resource might
+    /// have made stale (the resource itself, and anything above or below it).
+    fn invalidate_cache(&self, resource: &str) {
+        let ref mut cache_guard = lockw!(self.cache);
+        cache_guard.retain(|key, _| !key.starts_with(resource) && !resource.starts_with(key.as_str()));
+    }
+
     /// Set the API's authentication
     pub fn set_auth(&self, username: String, auth: String) -> TResult<()> {
         let auth_str = format!("{}:{}", username, auth);
         let base_auth = crypto::to_base64(&Vec::from(auth_str.as_bytes()))?;
-        let ref mut config_guard = lockw!(self.config);
-        config_guard.auth = Some(String::from("Basic ") + &base_auth);
+        {
+            let ref mut config_guard = lockw!(self.config);
+            config_guard.auth = Some(String::from("Basic ") + &base_auth);
+        }
+        // cached responses belong to whoever was logged in before
+        self.clear_cache();
         Ok(())
     }
 
     /// Clear out the API auth
     pub fn clear_auth(&self) {
-        let ref mut config_guard = lockw!(self.config);
-        config_guard.auth = None;
+        {
+            let ref mut config_guard = lockw!(self.config);
+            config_guard.auth = None;
+        }
+        self.clear_cache();
     }
 
     /// Write our auth headers into a header collection
@@ -128,6 +278,13 @@ impl Api {
         }
     }
 
+    /// Grab our Authorization header value, if we're logged in. Handy for
+    /// connections that don't go through hyper (our sync push socket).
+    pub fn auth_header(&self) -> Option<String> {
+        let ref guard = lockr!(self.config);
+        guard.auth.clone()
+    }
+
     /// Set our standard auth header into a Headers set
     fn set_standard_headers(&self, headers: &mut Headers) {
         self.set_auth_headers(headers);
@@ -156,7 +313,7 @@ impl Api {
     /// large HTTP body
     pub fn call_start(&self, method: Method, resource: &str, builder: ApiReq) -> TResult<(Request<hyper::net::Streaming>, CallInfo)> {
         debug!("api::call_start() -- req: {} {}", method, resource);
-        let ApiReq {mut headers, timeout, data: _data} = builder;
+        let ApiReq {mut headers, timeout, ..} = builder;
         let url = self.build_url(resource)?;
         let resource = String::from(resource);
         let method2 = method.clone();
@@ -174,29 +331,154 @@ impl Api {
         Ok((request.start()?, CallInfo::new(method2, resource)))
     }
 
-    /// Send out an API request
+    /// Send a request body off to the server.
+    ///
+    /// If `gzip` is set, we ask for a gzipped response, and if the server has
+    /// told us it takes gzipped bodies (and this body is big enough to bother,
+    /// see `api.gzip.min_size`) we compress it. If the server turns out not to
+    /// like that (415), we send it again uncompressed and stop compressing.
+    fn send(&self, method: Method, url: &str, mut headers: Headers, timeout: Duration, body: String, gzip: bool) -> Result<Response, hyper::Error> {
+        let mut client = hyper::Client::new();
+        client.set_read_timeout(Some(timeout));
+        let gzip = gzip && self.gzip_enabled();
+        if gzip {
+            headers.set_raw("Accept-Encoding", vec![Vec::from("gzip".as_bytes())]);
+        }
+        let min_size: usize = config::get(&["api", "gzip", "min_size"]).unwrap_or(1024);
+        if gzip && body.len() >= min_size && self.gzip_bodies() {
+            let compressed = gzip(body.as_bytes()).map_err(|e| hyper::Error::Io(e))?;
+            debug!("api::send() -- gzipped body: {} -> {} bytes", body.len(), compressed.len());
+            let mut gz_headers = headers.clone();
+            gz_headers.set_raw("Content-Encoding", vec![Vec::from("gzip".as_bytes())]);
+            let res = client
+                .request(method.clone(), url)
+                .body(&compressed[..])
+                .headers(gz_headers)
+                .send();
+            let rejected = match res {
+                Ok(ref x) => x.status == Status::UnsupportedMediaType,
+                Err(_) => false,
+            };
+            if !rejected { return res; }
+            warn!("api::send() -- server rejected a gzipped body, sending it uncompressed (and not compressing from here on)");
+            *lockw!(self.gzip_bodies) = Some(false);
+        }
+        client
+            .request(method, url)
+            .body(&body)
+            .headers(headers)
+            .send()
+    }
+
+    /// Send out an API request.
+    ///
+    /// If the request was built with `ApiReq::cache()` (and is a GET) we keep
+    /// the response around. Within the configured TTL we return it without
+    /// touching the network, and after that we revalidate it with the server
+    /// using If-None-Match/If-Modified-Since, reusing our copy on a 304.
     pub fn call<T: DeserializeOwned>(&self, method: Method, resource: &str, builder: ApiReq) -> TResult<T> {
         debug!("api::call() -- req: {} {}", method, resource);
-        let ApiReq {mut headers, timeout, data} = builder;
+        let ApiReq {mut headers, timeout, data, cache, gzip} = builder;
         let url = self.build_url(resource)?;
         let resource = String::from(resource);
         let method2 = method.clone();
+        let cacheable = cache && method == Method::Get && self.cache_enabled();
+
+        if method != Method::Get {
+            self.invalidate_cache(&resource);
+        }
+        if cacheable {
+            let ttl = self.cache_ttl();
+            let ref cache_guard = lockr!(self.cache);
+            match cache_guard.get(&resource) {
+                Some(entry) => {
+                    if entry.is_fresh(&ttl) {
+                        debug!("api::call() -- cache hit: {}", resource);
+                        return jedi::parse(&entry.body).map_err(|e| toterr!(e));
+                    }
+                    match entry.etag.as_ref() {
+                        Some(x) => headers.set_raw("If-None-Match", vec![Vec::from(x.as_bytes())]),
+                        None => {}
+                    }
+                    match entry.last_modified.as_ref() {
+                        Some(x) => headers.set_raw("If-Modified-Since", vec![Vec::from(x.as_bytes())]),
+                        None => {}
+                    }
+                }
+                None => {}
+            }
+        }
 
-        let mut client = hyper::Client::new();
         let body = jedi::stringify(&data)?;
         self.set_standard_headers(&mut headers);
-        client.set_read_timeout(Some(timeout));
-        let res = client
-            .request(method, &url[..])
-            .body(&body)
-            .headers(headers)
-            .send();
-        self.call_end(res, CallInfo::new(method2, resource))
+        let res = self.send(method, &url[..], headers, timeout, body, gzip);
+        if !cacheable {
+            return self.call_end(res, CallInfo::new(method2, resource));
+        }
+
+        let (status, res_headers, out) = self.call_end_raw(res, CallInfo::new(method2, resource.clone()))?;
+        let out = if status == Status::NotModified {
+            let ref mut cache_guard = lockw!(self.cache);
+            match cache_guard.get_mut(&resource) {
+                Some(entry) => {
+                    debug!("api::call() -- cache revalidated: {}", resource);
+                    entry.fetched = Instant::now();
+                    entry.body.clone()
+                }
+                None => return TErr!(TError::Api(status, Value::String(String::from("got 304 for a resource we have not cached")))),
+            }
+        } else {
+            let entry = CacheEntry {
+                body: out.clone(),
+                etag: raw_header(&res_headers, "ETag"),
+                last_modified: raw_header(&res_headers, "Last-Modified"),
+                fetched: Instant::now(),
+            };
+            let ref mut cache_guard = lockw!(self.cache);
+            cache_guard.insert(resource, entry);
+            out
+        };
+        jedi::parse(&out).map_err(|e| {
+            warn!("api::call() -- JSON parse error: {}", out);
+            toterr!(e)
+        })
+    }
+
+    /// Send out an API request and hand back the raw response body instead of
+    /// parsing it (never cached). Handy if the caller wants to know how big
+    /// the response was.
+    pub fn call_raw(&self, method: Method, resource: &str, builder: ApiReq) -> TResult<String> {
+        debug!("api::call_raw() -- req: {} {}", method, resource);
+        let ApiReq {mut headers, timeout, data, gzip, ..} = builder;
+        let url = self.build_url(resource)?;
+        let resource = String::from(resource);
+        let method2 = method.clone();
+        if method != Method::Get {
+            self.invalidate_cache(&resource);
+        }
+        let body = jedi::stringify(&data)?;
+        self.set_standard_headers(&mut headers);
+        let res = self.send(method, &url[..], headers, timeout, body, gzip);
+        let (_status, _headers, out) = self.call_end_raw(res, CallInfo::new(method2, resource))?;
+        Ok(out)
     }
 
     /// Finish an API request (takes a response result given back by
     /// Request.send())
     pub fn call_end<T: DeserializeOwned>(&self, response: Result<Response, hyper::error::Error>, callinfo: CallInfo) -> TResult<T> {
+        self.call_end_raw(response, callinfo)
+            .and_then(|(_status, _headers, out)| {
+                jedi::parse(&out).map_err(|e| {
+                    warn!("api::call() -- JSON parse error: {}", out);
+                    toterr!(e)
+                })
+            })
+    }
+
+    /// Finish an API request, but instead of parsing the body, return it (along
+    /// with the response's status and headers). Note that a 304 is passed
+    /// through here (with an empty body) rather than treated as an error.
+    fn call_end_raw(&self, response: Result<Response, hyper::error::Error>, callinfo: CallInfo) -> TResult<(Status, Headers, String)> {
         response
             .map_err(|e| {
                 match e {
@@ -205,11 +487,13 @@ impl Api {
                 }
             })
             .and_then(|mut res| {
-                let mut out = String::new();
-                let str_res = res.read_to_string(&mut out)
+                let mut raw = Vec::new();
+                let gzipped = lists_gzip(&res.headers, "Content-Encoding");
+                let str_res = res.read_to_end(&mut raw)
+                    .and_then(|_| if gzipped { gunzip(&raw[..]) } else { Ok(raw) })
                     .map_err(|e| toterr!(e))
-                    .and_then(move |_| Ok(out));
-                if !res.status.is_success() {
+                    .and_then(|x| Ok(String::from_utf8(x)?));
+                if !res.status.is_success() && res.status != Status::NotModified {
                     let errstr = match str_res {
                         Ok(x) => x,
                         Err(e) => {
@@ -226,20 +510,16 @@ impl Api {
                 str_res.map(move |x| (x, res))
             })
             .map(|(out, res)| {
+                self.learn_gzip(&res.headers);
+                self.learn_deltas(&res.headers);
                 info!("api::call() -- res({}): {:?} {} {}", out.len(), res.status_raw(), &callinfo.method, &callinfo.resource);
                 trace!("  api::call() -- body: {}", out);
-                out
+                (res.status, res.headers.clone(), out)
             })
             .map_err(|err| {
                 debug!("api::call() -- call error: {}", err);
                 err
             })
-            .and_then(|out| {
-                jedi::parse(&out).map_err(|e| {
-                    warn!("api::call() -- JSON parse error: {}", out);
-                    toterr!(e)
-                })
-            })
     }
 
     /// Convenience function for api.call(GET)
@@ -263,3 +543,38 @@ impl Api {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gzips() {
+        let data = String::from("{\"records\":[") + &vec!["{\"id\":\"1234\",\"type\":\"note\"}"; 100].join(",") + "]}";
+        let compressed = gzip(data.as_bytes()).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(gunzip(&compressed[..]).unwrap(), data.as_bytes());
+
+        let mut headers = Headers::new();
+        assert!(!lists_gzip(&headers, "Accept-Encoding"));
+        headers.set_raw("Accept-Encoding", vec![Vec::from("deflate, GZIP".as_bytes())]);
+        assert!(lists_gzip(&headers, "Accept-Encoding"));
+        headers.set_raw("Content-Encoding", vec![Vec::from("gzipped-lol".as_bytes())]);
+        assert!(!lists_gzip(&headers, "Content-Encoding"));
+    }
+
+    #[test]
+    fn learns_gzip() {
+        let api = Api::new();
+        assert!(!api.gzip_bodies());
+        api.learn_gzip(&Headers::new());
+        assert!(!api.gzip_bodies());
+        let mut headers = Headers::new();
+        headers.set_raw("Accept-Encoding", vec![Vec::from("gzip".as_bytes())]);
+        api.learn_gzip(&headers);
+        assert!(api.gzip_bodies());
+        // once rejected, we don't go back
+        *lockw!(api.gzip_bodies) = Some(false);
+        api.learn_gzip(&headers);
+        assert!(!api.gzip_bodies());
+    }
+}
@@ -1,5 +1,6 @@
 //! Low-level crypto primitives/modules.
 
+use ::argon2;
 use ::hex;
 use ::base64;
 use ::sodiumoxide;
@@ -17,6 +18,10 @@ pub const KEYGEN_SALT_LEN: usize = 32;
 pub const KEYGEN_OPS_DEFAULT: usize = pwhash::OPSLIMIT_INTERACTIVE.0;
 /// Abstract the mem limit for key generation (16777216)
 pub const KEYGEN_MEM_DEFAULT: usize = pwhash::MEMLIMIT_INTERACTIVE.0;
+/// Abstract the passes over memory for argon2id key generation
+pub const KEYGEN_ARGON2_OPS_DEFAULT: u32 = 3;
+/// Abstract the mem limit (in KiB) for argon2id key generation (64MiB)
+pub const KEYGEN_ARGON2_MEM_DEFAULT: u32 = 65536;
 
 /// Run a sha256 hash on some data
 #[allow(dead_code)]
@@ -52,7 +57,6 @@ pub fn from_base64(data: &String) -> CResult<Vec<u8>> {
 
 /// Given a key (password/secret) and a set of data, run an HMAC-SHA512256 and
 /// return the binary result as a u8 vec.
-#[allow(dead_code)]
 pub fn hmac(key: &[u8], data: &[u8]) -> CResult<Vec<u8>> {
     let key = match sodium_auth::Key::from_slice(key) {
         Some(x) => x,
@@ -121,6 +125,24 @@ pub fn gen_key(password: &[u8], salt: &[u8], cpu: usize, mem: usize) -> CResult<
     }
 }
 
+/// Generate a key given a password and a salt using argon2id. `ops` is how
+/// many passes to make over memory, and `mem` how much of it to use (in KiB).
+pub fn gen_key_argon2id(password: &[u8], salt: &[u8], ops: u32, mem: u32) -> CResult<Vec<u8>> {
+    let config = argon2::Config {
+        variant: argon2::Variant::Argon2id,
+        version: argon2::Version::Version13,
+        time_cost: ops,
+        mem_cost: mem,
+        lanes: 1,
+        hash_length: chacha20poly1305::keylen() as u32,
+        ..argon2::Config::default()
+    };
+    match argon2::hash_raw(password, salt, &config) {
+        Ok(x) => Ok(x),
+        Err(e) => Err(CryptoError::OperationFailed(format!("crypto::low::gen_key_argon2id() -- could not generate key: {}", e))),
+    }
+}
+
 pub mod chacha20poly1305 {
     //! Our chacha20poly1305 wrapper.
 
@@ -349,6 +371,16 @@ mod tests {
         assert_eq!(key, vec![191, 247, 89, 55, 132, 218, 68, 194, 90, 194, 233, 50, 99, 98, 25, 230, 102, 217, 215, 59, 136, 61, 249, 107, 127, 124, 62, 119, 145, 56, 216, 191]);
     }
 
+    #[test]
+    fn can_generate_argon2id_keys() {
+        let password = String::from("not at all, to some extent (always the same), very much so, don't know");
+        let salt = sha256(String::from("don't know").as_bytes()).unwrap();
+        let key = gen_key_argon2id(password.as_bytes(), &salt[0..KEYGEN_SALT_LEN], 2, 1024).unwrap();
+        assert_eq!(key, vec![222, 194, 100, 85, 133, 66, 116, 184, 40, 60, 28, 185, 12, 72, 170, 174, 78, 35, 220, 140, 222, 80, 216, 213, 204, 47, 86, 196, 52, 140, 134, 254]);
+        // not enough memory to work with
+        assert!(gen_key_argon2id(password.as_bytes(), &salt[0..KEYGEN_SALT_LEN], 2, 1).is_err());
+    }
+
     #[test]
     fn can_encrypt_chacha20poly1305() {
         let key = from_base64(&String::from("v/dZN4TaRMJawukyY2IZ5mbZ1zuIPflrf3w+d5E42L8=")).unwrap();
@@ -16,10 +16,13 @@ pub use ::crypto::low::{
     from_hex,
     to_base64,
     from_base64,
+    hmac,
     HMAC_KEYLEN,
     KEYGEN_SALT_LEN,
     KEYGEN_OPS_DEFAULT,
     KEYGEN_MEM_DEFAULT,
+    KEYGEN_ARGON2_OPS_DEFAULT,
+    KEYGEN_ARGON2_MEM_DEFAULT,
     random_salt,
 };
 pub use ::crypto::low::chacha20poly1305::{random_nonce, random_key, noncelen, keylen};
@@ -139,25 +142,31 @@ impl CryptoData {
 /// - `nonce` is the initial vector of the payload.
 /// - `ciphertext` is our actual encrypted data. DUUUuuuUUUHHH
 ///
+/// Note that this data can come from anywhere (including other members of a
+/// shared space) so we check every length before we use it rather than trust
+/// the header.
 pub fn deserialize(mut serialized: Vec<u8>) -> CResult<CryptoData> {
+    let malformed = || CryptoError::BadData(String::from("crypto::deserialize() -- malformed data passed"));
     let mut idx: usize = 0;
+    // version (2) + desc length (1)
+    if serialized.len() < 3 { return Err(malformed()); }
     let version: u16 = ((serialized[idx] as u16) << 8) + (serialized[idx + 1] as u16);
     idx += 2;
 
     let desc_struct = {
-        let desc_length = serialized[idx];
+        let desc_length = serialized[idx] as usize;
         idx += 1;
-        let desc = &serialized[idx..(idx + (desc_length as usize))];
-        idx += desc_length as usize;
+        // desc + nonce length (1)
+        if idx + desc_length >= serialized.len() { return Err(malformed()); }
+        let desc = &serialized[idx..(idx + desc_length)];
+        idx += desc_length;
         PayloadDescription::from(desc)?
     };
 
     let nonce_length = serialized[idx] as usize;
     idx += 1;
     let nonce_idx = idx + nonce_length;
-    if nonce_idx >= serialized.len() {
-        return Err(CryptoError::BadData(String::from("crypto::deserialize() -- malformed data passed")));
-    }
+    if nonce_idx >= serialized.len() { return Err(malformed()); }
     let nonce = Vec::from(&serialized[idx..nonce_idx]);
     idx += nonce_length;
 
@@ -167,6 +176,20 @@ pub fn deserialize(mut serialized: Vec<u8>) -> CResult<CryptoData> {
     Ok(CryptoData::new(version, desc_struct, nonce, ciphertext))
 }
 
+/// The crypto version we encrypt with (see `CRYPTO_VERSION`)
+pub fn current_version() -> u16 {
+    CRYPTO_VERSION
+}
+
+/// Read the version out of a serialized message without parsing the rest of
+/// it. Only the first two bytes are needed.
+pub fn payload_version(serialized: &[u8]) -> CResult<u16> {
+    if serialized.len() < 2 {
+        return Err(CryptoError::BadData(String::from("crypto::payload_version() -- malformed data passed")));
+    }
+    Ok(((serialized[0] as u16) << 8) + (serialized[1] as u16))
+}
+
 /// Serialize a CryptoData container into a raw header vector. This is useful
 /// for extracting authentication data.
 pub fn serialize_header(data: &CryptoData) -> CResult<Vec<u8>> {
@@ -208,8 +231,8 @@ pub fn decrypt(key: &Key, ciphertext: Vec<u8>) -> CResult<Vec<u8>> {
     let nonce = &deserialized.nonce;
     let ciphertext = &deserialized.ciphertext;
     let auth: Vec<u8> = serialize_header(&deserialized)?;
-    let decrypted = match SYM_ALGORITHM[desc.algorithm as usize] {
-        "chacha20poly1305" => {
+    let decrypted = match SYM_ALGORITHM.get(desc.algorithm as usize) {
+        Some(&"chacha20poly1305") => {
             low::chacha20poly1305::decrypt(key.data().as_slice(), nonce.as_slice(), auth.as_slice(), ciphertext.as_slice())?
         },
         _ => {
@@ -252,6 +275,12 @@ pub fn gen_key(password: &[u8], salt: &[u8], cpu: usize, mem: usize) -> CResult<
     Ok(Key::new(low::gen_key(password, salt, cpu, mem)?))
 }
 
+/// Generate a key given a password and a salt using argon2id (see
+/// `low::gen_key_argon2id()`)
+pub fn gen_key_argon2id(password: &[u8], salt: &[u8], ops: u32, mem: u32) -> CResult<Key> {
+    Ok(Key::new(low::gen_key_argon2id(password, salt, ops, mem)?))
+}
+
 /// Generate a random hex string (64 bytes).
 pub fn random_hash() -> CResult<String> {
     low::to_hex(&low::rand_bytes(32)?)
@@ -274,10 +303,13 @@ pub mod asym {
         ser
     }
 
-    fn deserialize(mut serialized: Vec<u8>) -> (u8, Vec<u8>) {
+    fn deserialize(mut serialized: Vec<u8>) -> CResult<(u8, Vec<u8>)> {
+        if serialized.len() < 1 {
+            return Err(CryptoError::BadData(String::from("crypto::asym::deserialize() -- empty message")));
+        }
         let version = serialized[0];
         let data = serialized.drain(1..).collect();
-        (version, data)
+        Ok((version, data))
     }
 
     /// Generate an asym keypair.
@@ -295,7 +327,7 @@ pub mod asym {
 
     /// Asymmetrically decrypt a message with our public/private keypair
     pub fn decrypt(our_pubkey: &Key, our_privkey: &Key, message: Vec<u8>) -> CResult<Vec<u8>> {
-        let (version, ciphertext) = deserialize(message);
+        let (version, ciphertext) = deserialize(message)?;
         match version {
             3 => low_asym::decrypt(our_pubkey.data().as_slice(), our_privkey.data().as_slice(), ciphertext.as_slice()),
             _ => Err(CryptoError::NotImplemented(format!("crypto::asym::decrypt() -- found version {} (which is not implemented)", version))),
@@ -406,6 +438,25 @@ mod tests {
         else { encrypted[4] = 0; }
         let res = asym::decrypt(&her_pk, &her_sk, encrypted);
         assert!(res.is_err());
+        let res = asym::decrypt(&her_pk, &her_sk, Vec::new());
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn malformed_payloads_dont_panic() {
+        let key = Key::random().unwrap();
+        let good = encrypt(&key, Vec::from("get a job".as_bytes()), CryptoOp::new("chacha20poly1305").unwrap()).unwrap();
+        // every truncation of a valid payload should error, not panic
+        for i in 0..good.len() {
+            assert!(decrypt(&key, Vec::from(&good[0..i])).is_err());
+        }
+        // desc/nonce lengths that run off the end
+        assert!(deserialize(vec![0, 6, 255, 0]).is_err());
+        assert!(deserialize(vec![0, 6, 1, 0, 255, 1, 2]).is_err());
+        // an algorithm we don't have
+        let mut bad_alg = good.clone();
+        bad_alg[3] = 200;
+        assert!(decrypt(&key, bad_alg).is_err());
     }
 }
 
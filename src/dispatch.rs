@@ -7,31 +7,106 @@
 //!
 //! where the arg\* can be any valid JSON object. The Message ID is passed in
 //! when responding so the client knows which request we are responding to.
+//!
+//! UIs should start with an `app:handshake` to make sure we speak the same
+//! protocol version (see the `protocol` module).
+//!
+//! Every command runs through our middleware chain (see the `middleware`
+//! module) on its way to its handler. Login checks, read-only mode and the
+//! like are declared there per command and don't belong in the handlers.
 
+use ::std::collections::HashMap;
 use ::jedi::{self, Value};
-use ::error::{TResult, TError};
+use ::error::{TResult, TError, PermissionDenial};
 use ::config;
 use ::util::{self, logger};
 use ::turtl::Turtl;
-use ::search::Query;
+use ::search::{self, Query};
+use ::search_reindex;
 use ::profile::{Profile, Export, ImportMode};
+use ::markdown::{self, ExportOptions, ImportOptions};
+use ::enex::{self, EnexOptions};
 use ::models::model::Model;
 use ::models::protected::Protected;
 use ::models::user::User;
 use ::models::space::Space;
 use ::models::space_member::SpaceMember;
-use ::models::note::Note;
+use ::models::note::{Note, DuplicateOptions};
+use ::models::note_history;
+use ::models::note_stats;
+use ::models::checklist;
+use ::models::note_batch::{self, BatchOp};
+use ::models::trash;
+use ::models::tag;
+use ::models::share;
+use ::models::template::{Template, TemplateOptions};
+use ::models::settings::Settings;
+use ::models::saved_search::SavedSearch;
+use ::models::board::Board;
+use ::lib_permissions::Permission;
 use ::models::invite::{Invite, InviteRequest};
+use ::models::key_bundle::SpaceKeyBundle;
+use ::models::keychain_maint;
+use ::models::rotate::{self, RotateOptions};
 use ::models::file::FileData;
+use ::models::thumbnail;
+use ::models::extract;
+use ::models::avatar;
+use ::models::upgrade;
 use ::models::sync_record::{SyncAction, SyncType, SyncRecord};
 use ::models::feedback::Feedback;
 use ::clippo::{self, CustomParser};
 use ::sync::sync_model;
 use ::sync;
+use ::sync::selective::SpaceFilter;
+use ::sync::schedule::PollPolicy;
+use ::sync::conflict;
+use ::sync::audit;
+use ::sync::reminders;
 use ::messaging::{self, Event};
+use ::protocol;
+use ::middleware::{self, Context};
 use ::migrate;
 use ::crypto::{self, Key};
 
+/// Run a note search, returning the (decrypted) notes that matched along with
+/// the tags in the results and, when searching by text, where in each note the
+/// text matched. With `meta`, the notes come back without their lazy fields.
+fn find_notes(turtl: &Turtl, qry: &Query, meta: bool) -> TResult<Value> {
+    turtl.index_space(&qry.space_id)?;
+    let search_guard = lock!(turtl.search);
+    if search_guard.is_none() {
+        return TErr!(TError::MissingField(format!("turtl is missing `search` object")));
+    }
+    let search = search_guard.as_ref().expect("turtl::dispatch::find_notes() -- search_guard is none");
+    let (results, total) = search.find_scored(qry)?;
+    let note_ids = results.iter().map(|x| x.0.clone()).collect::<Vec<_>>();
+    let notes: Vec<Note> = if meta {
+        turtl.load_notes_meta(&note_ids)?
+    } else {
+        turtl.load_notes(&note_ids)?
+    };
+    let tags: Vec<(String, i32)> = search.find_tags(qry)?;
+    // how well each note matched the search text, and where (by note id)
+    let (scores, matches) = if qry.text.is_some() {
+        let needles = search.highlight_words(qry)?;
+        let mut matches = HashMap::with_capacity(notes.len());
+        for note in &notes {
+            matches.insert(note.id_or_else()?, search::match_positions(note, &needles));
+        }
+        (results.into_iter().collect::<HashMap<_, _>>(), matches)
+    } else {
+        (HashMap::new(), HashMap::new())
+    };
+    Ok(json!({
+        "notes": notes,
+        "tags": tags,
+        "total": total,
+        "scores": scores,
+        "matches": matches,
+    }))
+}
+
 /// Does our actual message dispatching
 fn dispatch(cmd: &String, turtl: &Turtl, data: Value) -> TResult<Value> {
     match cmd.as_ref() {
@@ -116,10 +191,35 @@ fn dispatch(cmd: &String, turtl: &Turtl, data: Value) -> TResult<Value> {
             turtl.change_user_password(current_username, current_password, new_username, new_password)?;
             Ok(json!({}))
         }
+        "user:change-email" => {
+            let current_username: String = jedi::get(&["2"], &data)?;
+            let current_password: String = jedi::get(&["3"], &data)?;
+            let new_email: String = jedi::get(&["4"], &data)?;
+            turtl.change_user_email(current_username, current_password, new_email)?;
+            Ok(json!({}))
+        }
         "user:delete-account" => {
+            let username: String = jedi::get(&["2"], &data)?;
+            let password: String = jedi::get(&["3"], &data)?;
             messaging::ui_event("user:logout:clear-cookie", &Value::Null)
                 .unwrap_or_else(|e| error!("dispatch::dispatch() -- error sending ui event: {}", e));
-            turtl.delete_account()?;
+            turtl.delete_account(username, password)?;
+            Ok(json!({}))
+        }
+        "user:avatar:get" => {
+            match avatar::get(turtl)? {
+                Some(bin) => Ok(Value::String(crypto::to_base64(&bin)?)),
+                None => Ok(Value::Null),
+            }
+        }
+        "user:avatar:set" => {
+            let bin: String = jedi::get(&["2"], &data)?;
+            let ty: Option<String> = jedi::get_opt(&["3"], &data);
+            let avatar = avatar::set(turtl, crypto::from_base64(&bin)?, ty)?;
+            Ok(jedi::to_val(&avatar)?)
+        }
+        "user:avatar:clear" => {
+            avatar::clear(turtl)?;
             Ok(json!({}))
         }
         "user:resend-confirmation" => {
@@ -129,7 +229,7 @@ fn dispatch(cmd: &String, turtl: &Turtl, data: Value) -> TResult<Value> {
         "user:get-login-token" => {
             let confirmation: String = jedi::get_opt(&["2"], &data).unwrap_or(String::from("WRONG"));
             if confirmation != "I understand this token contains the user's master key and their account may be compromised if the token is misplaced." {
-                return TErr!(TError::PermissionDenied(String::from("Please send the confirmation string to get the token")));
+                return TErr!(TError::PermissionDenied(PermissionDenial::new("Please send the confirmation string to get the token")));
             }
             let token = User::get_login_token(turtl)?;
             Ok(Value::String(token))
@@ -143,6 +243,22 @@ fn dispatch(cmd: &String, turtl: &Turtl, data: Value) -> TResult<Value> {
             let user = User::find_by_email(turtl, &email)?;
             Ok(jedi::to_val(&user)?)
         }
+        "app:handshake" => {
+            let version: u32 = jedi::get(&["2"], &data)?;
+            let handshake = protocol::handshake(version)?;
+            Ok(jedi::to_val(&handshake)?)
+        }
+        "app:read-only:get" => {
+            Ok(json!(middleware::read_only()))
+        }
+        "app:read-only:set" => {
+            let yesno: bool = jedi::get(&["2"], &data)?;
+            middleware::set_read_only(yesno);
+            Ok(json!({}))
+        }
+        "app:protocol:deprecation-usage" => {
+            Ok(jedi::to_val(&protocol::usage())?)
+        }
         "app:connected" => {
             let connguard = lockr!(turtl.connected);
             let connected: bool = *connguard;
@@ -188,8 +304,7 @@ fn dispatch(cmd: &String, turtl: &Turtl, data: Value) -> TResult<Value> {
             Ok(Value::String(contents))
         }
         "app:shutdown" => {
-            turtl.sync_shutdown(false)?;
-            messaging::stop();
+            turtl.request_shutdown()?;
             Ok(json!({}))
         }
         "sync:start" => {
@@ -197,15 +312,70 @@ fn dispatch(cmd: &String, turtl: &Turtl, data: Value) -> TResult<Value> {
             Ok(json!({}))
         }
         "sync:pause" => {
-            turtl.sync_pause();
+            // optionally pause just one syncer (or `files`)
+            match jedi::get_opt::<String>(&["2"], &data) {
+                Some(target) => turtl.sync_pause_syncer(target.as_str())?,
+                None => turtl.sync_pause(),
+            }
             Ok(json!({}))
         }
         "sync:resume" => {
-            turtl.sync_resume();
+            match jedi::get_opt::<String>(&["2"], &data) {
+                Some(target) => turtl.sync_resume_syncer(target.as_str())?,
+                None => turtl.sync_resume(),
+            }
+            Ok(json!({}))
+        }
+        "sync:get-spaces" => {
+            Ok(jedi::to_val(&turtl.sync_space_filter())?)
+        }
+        "sync:set-spaces" => {
+            let filter: SpaceFilter = jedi::get(&["2"], &data)?;
+            turtl.sync_set_space_filter(filter)?;
+            Ok(json!({}))
+        }
+        "sync:get-paused" => {
+            Ok(jedi::to_val(&turtl.sync_paused())?)
+        }
+        "sync:get-dry-run" => {
+            Ok(Value::Bool(turtl.sync_dry_run()))
+        }
+        "sync:set-dry-run" => {
+            let yesno: bool = jedi::get(&["2"], &data)?;
+            turtl.sync_set_dry_run(yesno);
+            Ok(json!({}))
+        }
+        "sync:set-rate-limits" => {
+            let upload: u64 = jedi::get(&["2", "upload"], &data)?;
+            let download: u64 = jedi::get(&["2", "download"], &data)?;
+            turtl.sync_set_rate_limits(upload, download);
+            Ok(json!({}))
+        }
+        "sync:set-online" => {
+            let online: bool = jedi::get(&["2"], &data)?;
+            turtl.sync_set_online(online);
+            Ok(json!({}))
+        }
+        "sync:set-foreground" => {
+            let foreground: bool = jedi::get(&["2"], &data)?;
+            turtl.sync_set_foreground(foreground);
+            Ok(json!({}))
+        }
+        "sync:get-poll-policy" => {
+            Ok(jedi::to_val(&turtl.sync_poll_policy())?)
+        }
+        "sync:set-poll-policy" => {
+            let policy: PollPolicy = jedi::get(&["2"], &data)?;
+            turtl.sync_set_poll_policy(policy);
             Ok(json!({}))
         }
         "sync:status" => {
-            Ok(Value::Bool(turtl.sync_running()))
+            let stats = turtl.sync_stats();
+            Ok(json!({
+                "running": turtl.sync_running(),
+                "online": turtl.sync_online(),
+                "syncers": stats.syncers,
+            }))
         }
         "sync:shutdown" => {
             let wait: bool = jedi::get_opt(&["2"], &data).unwrap_or(true);
@@ -216,11 +386,35 @@ fn dispatch(cmd: &String, turtl: &Turtl, data: Value) -> TResult<Value> {
             let frozen = SyncRecord::get_all_pending(turtl)?;
             Ok(jedi::to_val(&frozen)?)
         }
+        "sync:queue:list" => {
+            let queue = SyncRecord::get_outgoing_queue(turtl)?;
+            Ok(jedi::to_val(&queue)?)
+        }
+        "sync:audit" => {
+            let report = audit::run(turtl)?;
+            Ok(jedi::to_val(&report)?)
+        }
+        "sync:conflict:list" => {
+            let conflicts = conflict::get_all(turtl)?;
+            Ok(jedi::to_val(&conflicts)?)
+        }
+        "sync:conflict:dismiss" => {
+            let item_id: String = jedi::get(&["2"], &data)?;
+            Ok(Value::Bool(conflict::dismiss(turtl, &item_id)?))
+        }
+        "sync:get-frozen" => {
+            let frozen = SyncRecord::get_all_frozen(turtl)?;
+            Ok(jedi::to_val(&frozen)?)
+        }
         "sync:unfreeze-item" => {
             let sync_id: String = jedi::get(&["2"], &data)?;
             SyncRecord::kick_frozen_sync(turtl, &sync_id)?;
             Ok(json!({}))
         }
+        "sync:unfreeze-all" => {
+            let count = SyncRecord::kick_all_frozen_syncs(turtl)?;
+            Ok(json!({"unfrozen": count}))
+        }
         "sync:delete-item" => {
             let sync_id: String = jedi::get(&["2"], &data)?;
             SyncRecord::delete_sync_item(turtl, &sync_id)?;
@@ -237,6 +431,15 @@ fn dispatch(cmd: &String, turtl: &Turtl, data: Value) -> TResult<Value> {
             });
             Ok(profile_data)
         }
+        "profile:sync:changes" => {
+            let ty: SyncType = jedi::get(&["2"], &data)?;
+            let modeldata: Value = jedi::get(&["3"], &data)?;
+            let fields = sync_model::changed_fields(turtl, &ty, modeldata)?;
+            Ok(json!({
+                "dirty": fields.len() > 0,
+                "fields": fields,
+            }))
+        }
         "profile:sync:model" => {
             let action: SyncAction = match jedi::get(&["2"], &data) {
                 Ok(action) => action,
@@ -330,14 +533,66 @@ fn dispatch(cmd: &String, turtl: &Turtl, data: Value) -> TResult<Value> {
             let space = Space::accept_invite(turtl, &mut invite, passphrase)?;
             Ok(space.data()?)
         }
-        "profile:delete-invite" => {
+        "profile:invites:list" => {
+            Invite::list(turtl)
+        }
+        // rejecting an invite sent to us is just deleting it
+        "profile:delete-invite" | "profile:reject-invite" => {
             let invite_id: String = jedi::get(&["2"], &data)?;
             Invite::delete_user_invite(turtl, &invite_id)?;
             Ok(json!({}))
         }
+        "keychain:clean" => {
+            let dry_run: bool = jedi::get_opt(&["2", "dry_run"], &data).unwrap_or(false);
+            let report = keychain_maint::clean(turtl, dry_run)?;
+            Ok(jedi::to_val(&report)?)
+        }
+        "keychain:verify" => {
+            let report = keychain_maint::verify(turtl)?;
+            Ok(jedi::to_val(&report)?)
+        }
+        "space:export-keys" => {
+            let space_id: String = jedi::get(&["2", "space_id"], &data)?;
+            let recipient_pubkey: Key = jedi::get(&["2", "recipient_pubkey"], &data)?;
+            let profile_guard = lockr!(turtl.profile);
+            let space = match profile_guard.spaces.iter().filter(|x| x.id() == Some(&space_id)).next() {
+                Some(s) => s,
+                None => return TErr!(TError::MissingData(format!("couldn't find space {}", space_id))),
+            };
+            let bundle = space.export_keys(turtl, &recipient_pubkey)?;
+            Ok(jedi::to_val(&bundle)?)
+        }
+        "space:import-keys" => {
+            let bundle: SpaceKeyBundle = jedi::get(&["2"], &data)?;
+            let capability = Space::import_keys(turtl, &bundle)?;
+            Ok(jedi::to_val(&capability)?)
+        }
+        "space:rotate-key" => {
+            let space_id: String = jedi::get(&["2"], &data)?;
+            let options: RotateOptions = jedi::get_opt(&["3"], &data).unwrap_or(Default::default());
+            let result = rotate::rotate_space(turtl, &space_id, &options)?;
+            Ok(jedi::to_val(&result)?)
+        }
+        "board:rotate-key" => {
+            let board_id: String = jedi::get(&["2"], &data)?;
+            let options: RotateOptions = jedi::get_opt(&["3"], &data).unwrap_or(Default::default());
+            let result = rotate::rotate_board(turtl, &board_id, &options)?;
+            Ok(jedi::to_val(&result)?)
+        }
+        "note:rotate-key" => {
+            let note_id: String = jedi::get(&["2"], &data)?;
+            let result = rotate::rotate_note(turtl, &note_id)?;
+            Ok(jedi::to_val(&result)?)
+        }
         "profile:get-notes" => {
             let note_ids = jedi::get(&["2"], &data)?;
-            let notes: Vec<Note> = turtl.load_notes(&note_ids)?;
+            // `{"meta": true}` leaves the heavy fields (`embed`) encrypted
+            let meta: bool = jedi::get_opt(&["3", "meta"], &data).unwrap_or(false);
+            let notes: Vec<Note> = if meta {
+                turtl.load_notes_meta(&note_ids)?
+            } else {
+                turtl.load_notes(&note_ids)?
+            };
             Ok(jedi::to_val(&notes)?)
         }
         "profile:find-notes" => {
@@ -347,19 +602,45 @@ fn dispatch(cmd: &String, turtl: &Turtl, data: Value) -> TResult<Value> {
                     return TErr!(TError::BadValue(format!("error deserializing search query: {}", e)));
                 }
             };
+            let meta: bool = jedi::get_opt(&["3", "meta"], &data).unwrap_or(false);
+            find_notes(turtl, &qry, meta)
+        }
+        "profile:suggest" => {
+            let space_id: String = jedi::get(&["2"], &data)?;
+            let prefix: String = jedi::get(&["3"], &data)?;
+            let limit: usize = jedi::get_opt(&["4", "limit"], &data).unwrap_or(10);
+            turtl.index_space(&space_id)?;
             let search_guard = lock!(turtl.search);
-            if search_guard.is_none() {
-                return TErr!(TError::MissingField(format!("turtl is missing `search` object")));
-            }
-            let search = search_guard.as_ref().expect("turtl::dispatch::dispatch() -- profile:find-notes -- search_guard is none");
-            let (note_ids, total) = search.find(&qry)?;
-            let notes: Vec<Note> = turtl.load_notes(&note_ids)?;
-            let tags: Vec<(String, i32)> = search.find_tags(&qry)?;
-            Ok(json!({
-                "notes": notes,
-                "tags": tags,
-                "total": total,
-            }))
+            let suggestions = match search_guard.as_ref() {
+                Some(search) => search.suggest(&space_id, prefix.as_str(), limit),
+                None => return TErr!(TError::MissingField(format!("turtl is missing `search` object"))),
+            };
+            Ok(jedi::to_val(&suggestions)?)
+        }
+        "profile:saved-search:list" => {
+            Ok(jedi::to_val(&SavedSearch::list(turtl)?)?)
+        }
+        "profile:saved-search:create" => {
+            let name: String = jedi::get(&["2"], &data)?;
+            let qry: Query = match jedi::get(&["3"], &data) {
+                Ok(x) => x,
+                Err(e) => {
+                    return TErr!(TError::BadValue(format!("error deserializing search query: {}", e)));
+                }
+            };
+            SavedSearch::create(turtl, name, qry)
+        }
+        "profile:saved-search:delete" => {
+            let search_id: String = jedi::get(&["2"], &data)?;
+            SavedSearch::delete(turtl, &search_id)?;
+            Ok(json!({}))
+        }
+        "profile:saved-search:run" => {
+            let search_id: String = jedi::get(&["2"], &data)?;
+            let options: Value = jedi::get_opt(&["3"], &data).unwrap_or(json!({}));
+            let qry = SavedSearch::query_for(turtl, &search_id, &options)?;
+            let meta: bool = jedi::get_opt(&["meta"], &options).unwrap_or(false);
+            find_notes(turtl, &qry, meta)
         }
         "profile:find-tags" => {
             let qry: Query = match jedi::get(&["2"], &data) {
@@ -368,6 +649,7 @@ fn dispatch(cmd: &String, turtl: &Turtl, data: Value) -> TResult<Value> {
                     return TErr!(TError::BadValue(format!("error deserializing search query: {}", e)));
                 }
             };
+            turtl.index_space(&qry.space_id)?;
             let search_guard = lock!(turtl.search);
             if search_guard.is_none() {
                 return TErr!(TError::MissingField(format!("turtl is missing `search` object")));
@@ -378,6 +660,26 @@ fn dispatch(cmd: &String, turtl: &Turtl, data: Value) -> TResult<Value> {
                 "tags": tags,
             }))
         }
+        "profile:tags:rename" => {
+            let space_id: String = jedi::get(&["2"], &data)?;
+            let from: String = jedi::get(&["3"], &data)?;
+            let to: String = jedi::get(&["4"], &data)?;
+            let count = tag::rename(turtl, &space_id, &from, &to)?;
+            Ok(json!({"notes": count}))
+        }
+        "profile:tags:merge" => {
+            let space_id: String = jedi::get(&["2"], &data)?;
+            let from: Vec<String> = jedi::get(&["3"], &data)?;
+            let to: String = jedi::get(&["4"], &data)?;
+            let count = tag::merge(turtl, &space_id, &from, &to)?;
+            Ok(json!({"notes": count}))
+        }
+        "profile:tags:delete" => {
+            let space_id: String = jedi::get(&["2"], &data)?;
+            let name: String = jedi::get(&["3"], &data)?;
+            let count = tag::delete(turtl, &space_id, &name)?;
+            Ok(json!({"notes": count}))
+        }
         "profile:note:get-file" => {
             let note_id = jedi::get(&["2"], &data)?;
             let notes: Vec<Note> = turtl.load_notes(&vec![note_id])?;
@@ -385,16 +687,195 @@ fn dispatch(cmd: &String, turtl: &Turtl, data: Value) -> TResult<Value> {
             let base64 = crypto::to_base64(&bin)?;
             Ok(Value::String(base64))
         }
+        "profile:note:get-thumbnail" => {
+            let note_id: String = jedi::get(&["2"], &data)?;
+            match thumbnail::get(turtl, &note_id)? {
+                Some(bin) => Ok(Value::String(crypto::to_base64(&bin)?)),
+                None => Ok(Value::Null),
+            }
+        }
+        "profile:note:history" => {
+            let note_id: String = jedi::get(&["2"], &data)?;
+            let revisions = note_history::list(turtl, &note_id)?;
+            Ok(jedi::to_val(&revisions)?)
+        }
+        "profile:note:restore" => {
+            let note_id: String = jedi::get(&["2"], &data)?;
+            let revision_id: String = jedi::get(&["3"], &data)?;
+            let space_id = match Note::get_space_id(turtl, &note_id) {
+                Some(x) => x,
+                None => return TErr!(TError::MissingData(format!("cannot find space id for note {}", note_id))),
+            };
+            Space::permission_check(turtl, &space_id, &Permission::EditNote)?;
+            note_history::restore(turtl, &note_id, &revision_id)
+        }
+        "profile:get-templates" => {
+            let space_id: String = jedi::get(&["2"], &data)?;
+            let templates = Template::list(turtl, &space_id)?;
+            Ok(jedi::to_val(&templates)?)
+        }
+        "note:create-from-template" => {
+            let template_id: String = jedi::get(&["2"], &data)?;
+            let options: TemplateOptions = jedi::get_opt(&["3"], &data).unwrap_or(Default::default());
+            let template = Template::load(turtl, &template_id)?;
+            let space_id = options.space_id.clone().unwrap_or_else(|| template.space_id.clone());
+            Space::permission_check(turtl, &space_id, &Permission::AddNote)?;
+            template.create_note(turtl, &options)
+        }
+        "profile:favorites:get" => {
+            let user_guard = lockr!(turtl.user);
+            Ok(jedi::to_val(&user_guard.favorites())?)
+        }
+        "profile:favorites:set" => {
+            let ty: SyncType = jedi::get(&["2"], &data)?;
+            let item_id: String = jedi::get(&["3"], &data)?;
+            let favorite: bool = jedi::get_opt(&["4"], &data).unwrap_or(true);
+            let mut user_guard = lockw!(turtl.user);
+            let favorites = user_guard.set_favorite(turtl, &ty, &item_id, favorite)?;
+            Ok(jedi::to_val(&favorites)?)
+        }
+        "settings:get" => {
+            Settings::get(turtl)
+        }
+        "settings:set" => {
+            let changes: Value = jedi::get(&["2"], &data)?;
+            Settings::set(turtl, changes)
+        }
+        "note:share:create" => {
+            let note_id: String = jedi::get(&["2"], &data)?;
+            let link = share::create(turtl, &note_id)?;
+            Ok(jedi::to_val(&link)?)
+        }
+        "note:share:list" => {
+            let note_id: Option<String> = jedi::get_opt(&["2"], &data);
+            let shares = share::list(turtl, note_id.as_ref())?;
+            Ok(jedi::to_val(&shares)?)
+        }
+        "note:share:revoke" => {
+            let share_id: String = jedi::get(&["2"], &data)?;
+            share::revoke(turtl, &share_id)?;
+            Ok(json!({}))
+        }
+        "board:reparent" => {
+            let board_id: String = jedi::get(&["2"], &data)?;
+            let parent_id: Option<String> = jedi::get_opt(&["3"], &data);
+            Board::reparent(turtl, &board_id, parent_id)
+        }
+        "board:reorder" => {
+            let board_id: String = jedi::get(&["2"], &data)?;
+            let after_id: Option<String> = jedi::get_opt(&["3"], &data);
+            let saved = Board::reorder(turtl, &board_id, after_id)?;
+            Ok(jedi::to_val(&saved)?)
+        }
+        "note:reorder" => {
+            let note_id: String = jedi::get(&["2"], &data)?;
+            let after_id: Option<String> = jedi::get_opt(&["3"], &data);
+            let saved = Note::reorder(turtl, &note_id, after_id)?;
+            Ok(jedi::to_val(&saved)?)
+        }
+        "note:duplicate" => {
+            let note_id: String = jedi::get(&["2"], &data)?;
+            let options: DuplicateOptions = jedi::get_opt(&["3"], &data).unwrap_or(Default::default());
+            Note::duplicate(turtl, &note_id, &options)
+        }
+        "note:checklist:add" => {
+            let note_id: String = jedi::get(&["2"], &data)?;
+            let text: String = jedi::get(&["3"], &data)?;
+            let position: Option<f64> = jedi::get_opt(&["4"], &data);
+            checklist::add(turtl, &note_id, text, position)
+        }
+        "note:checklist:toggle" => {
+            let note_id: String = jedi::get(&["2"], &data)?;
+            let item_id: String = jedi::get(&["3"], &data)?;
+            let checked: Option<bool> = jedi::get_opt(&["4"], &data);
+            checklist::toggle(turtl, &note_id, &item_id, checked)
+        }
+        "note:checklist:reorder" => {
+            let note_id: String = jedi::get(&["2"], &data)?;
+            let item_ids: Vec<String> = jedi::get(&["3"], &data)?;
+            checklist::reorder(turtl, &note_id, &item_ids)
+        }
+        "notes:batch" => {
+            let note_ids: Vec<String> = jedi::get(&["2"], &data)?;
+            let op: BatchOp = jedi::get(&["3"], &data)?;
+            let result = note_batch::run(turtl, &note_ids, &op)?;
+            Ok(jedi::to_val(&result)?)
+        }
+        "note:reminder:list" => {
+            let reminders = reminders::list(turtl)?;
+            Ok(jedi::to_val(&reminders)?)
+        }
+        "note:reminder:snooze" => {
+            let note_id: String = jedi::get(&["2"], &data)?;
+            let until: i64 = jedi::get(&["3"], &data)?;
+            let reminder = reminders::snooze(turtl, &note_id, until)?;
+            Ok(jedi::to_val(&reminder)?)
+        }
+        "note:reminder:dismiss" => {
+            let note_id: String = jedi::get(&["2"], &data)?;
+            let reminder = reminders::dismiss(turtl, &note_id)?;
+            Ok(jedi::to_val(&reminder)?)
+        }
+        "trash:list" => {
+            let trash = trash::list(turtl)?;
+            Ok(jedi::to_val(&trash)?)
+        }
+        "trash:restore" => {
+            let ty: SyncType = jedi::get(&["2"], &data)?;
+            let item_id: String = jedi::get(&["3"], &data)?;
+            match ty {
+                SyncType::Note => {
+                    let space_id = match Note::get_space_id(turtl, &item_id) {
+                        Some(x) => x,
+                        None => return TErr!(TError::MissingData(format!("cannot find space id for note {}", item_id))),
+                    };
+                    Space::permission_check(turtl, &space_id, &Permission::EditNote)?;
+                    trash::restore::<Note>(turtl, &item_id)
+                }
+                SyncType::Board => {
+                    let space_id = match Board::get_space_id(turtl, &item_id) {
+                        Some(x) => x,
+                        None => return TErr!(TError::MissingData(format!("cannot find space id for board {}", item_id))),
+                    };
+                    Space::permission_check(turtl, &space_id, &Permission::EditBoard)?;
+                    trash::restore::<Board>(turtl, &item_id)
+                }
+                _ => TErr!(TError::BadValue(format!("items of type {:?} don't go in the trash", ty))),
+            }
+        }
+        "trash:empty" => {
+            let count = trash::empty(turtl)?;
+            Ok(json!({"deleted": count}))
+        }
+        "profile:stats" => {
+            let stats = note_stats::stats(turtl)?;
+            Ok(jedi::to_val(&stats)?)
+        }
         "profile:export" => {
             let export = Profile::export(turtl)?;
             Ok(jedi::to_val(&export)?)
         }
+        "profile:export:markdown" => {
+            let options: ExportOptions = jedi::get(&["2"], &data)?;
+            let result = markdown::export(turtl, &options)?;
+            Ok(jedi::to_val(&result)?)
+        }
         "profile:import" => {
             let mode: ImportMode = jedi::get(&["2"], &data)?;
             let export: Export = jedi::get(&["3"], &data)?;
             let result = Profile::import(turtl, mode, export)?;
             Ok(jedi::to_val(&result)?)
         }
+        "profile:import:markdown" => {
+            let options: ImportOptions = jedi::get(&["2"], &data)?;
+            let result = markdown::import(turtl, &options)?;
+            Ok(jedi::to_val(&result)?)
+        }
+        "profile:import:enex" => {
+            let options: EnexOptions = jedi::get(&["2"], &data)?;
+            let result = enex::import(turtl, &options)?;
+            Ok(jedi::to_val(&result)?)
+        }
         "feedback:send" => {
             let feedback: Feedback = jedi::get(&["2"], &data)?;
             feedback.send(turtl)?;
@@ -436,6 +917,27 @@ fn dispatch_event(cmd: &String, turtl: &Turtl, data: Value) -> TResult<()> {
         "sync:incoming" => {
             sync::incoming::process_incoming_sync(turtl)?;
         }
+        "sync:file:downloaded" => {
+            let note_id: String = jedi::get(&["note_id"], &data)?;
+            if let Err(e) = thumbnail::generate(turtl, &note_id) {
+                warn!("dispatch::dispatch_event() -- couldn't make thumbnail for note {}: {}", note_id, e);
+            }
+            if let Err(e) = extract::run(turtl, &note_id) {
+                warn!("dispatch::dispatch_event() -- couldn't extract text for note {}: {}", note_id, e);
+            }
+        }
+        "sync:file:uploaded" => {
+            let note_id: String = jedi::get(&["note_id"], &data)?;
+            if let Err(e) = extract::run(turtl, &note_id) {
+                warn!("dispatch::dispatch_event() -- couldn't extract text for note {}: {}", note_id, e);
+            }
+        }
+        "profile:upgrade" => {
+            upgrade::trickle(turtl)?;
+        }
+        "search:reindex" => {
+            search_reindex::run(turtl)?;
+        }
         "user:edit" => {
             let mut user_guard = lockw!(turtl.user);
             user_guard.merge_fields(&data)?;
@@ -460,13 +962,20 @@ fn dispatch_event(cmd: &String, turtl: &Turtl, data: Value) -> TResult<()> {
     Ok(())
 }
 
-/// process a message from the messaging system. this is the main communication
-/// heart of turtl core.
-pub fn process(turtl: &Turtl, msg: &String) -> TResult<()> {
-    if &msg[0..4] == "::ev" {
+/// A message from the UI, parsed
+pub enum Incoming {
+    /// An event (`::ev{"e":..., "d":...}`)
+    Event(Event),
+    /// A command to run, as (mid, cmd, data)
+    Command(String, String, Value),
+}
+
+/// Parse a raw message from the UI into an event or a command. This input
+/// comes from outside the core, so it errors on anything it doesn't like.
+pub fn parse(msg: &String) -> TResult<Incoming> {
+    if msg.starts_with("::ev") {
         let event: Event = jedi::parse(&String::from(&msg[4..]))?;
-        let Event {e, d} = event;
-        return dispatch_event(&e, turtl, d);
+        return Ok(Incoming::Event(event));
     }
 
     let data: Value = jedi::parse(msg)?;
@@ -481,18 +990,35 @@ pub fn process(turtl: &Turtl, msg: &String) -> TResult<()> {
         Ok(x) => x,
         Err(_) => return TErr!(TError::MissingField(String::from("missing cmd (1)"))),
     };
+    Ok(Incoming::Command(mid, cmd, data))
+}
+
+/// process a message from the messaging system. this is the main communication
+/// heart of turtl core.
+pub fn process(turtl: &Turtl, msg: &String) -> TResult<()> {
+    let (mid, cmd, data) = match parse(msg)? {
+        Incoming::Event(Event {e, d}) => return dispatch_event(&e, turtl, d),
+        Incoming::Command(mid, cmd, data) => (mid, cmd, data),
+    };
 
     info!("dispatch({}): {}", mid, cmd);
 
-    match dispatch(&cmd, turtl.clone(), data) {
+    let mut ctx = Context::new(turtl, mid.clone(), cmd, data);
+    let res = middleware::run(&mut ctx, &|ctx: &mut Context| {
+        let cmd = ctx.cmd.clone();
+        let data = ctx.take_data();
+        dispatch(&cmd, ctx.turtl, data)
+    });
+    let warnings = ctx.warnings;
+    match res {
         Ok(val) => {
-            match turtl.msg_success(&mid, val) {
+            match turtl.msg_success(&mid, val, warnings) {
                 Err(e) => error!("dispatch::process() -- problem sending response (mid {}): {}", mid, e),
                 _ => {},
             }
         },
         Err(e) => {
-            match turtl.msg_error(&mid, &e) {
+            match turtl.msg_error(&mid, &e, warnings) {
                 Err(e) => error!("dispatch:process() -- problem sending (error) response (mod {}): {}", mid, e),
                 _ => {},
             }
@@ -0,0 +1,457 @@
+//! Imports Evernote exports (ENEX files). An ENEX file holds the notes of one
+//! notebook, which becomes a board (in a space the UI picks, or a new one).
+//! Each note's tags, source url, and modified date come along, its content is
+//! boiled down from Evernote's HTML to plain text, and its first attachment
+//! becomes the note's file. Any other attachments get a note of their own.
+//!
+//! ENEX is simple enough (and our needs narrow enough) that we pick it apart by
+//! hand instead of pulling in a full XML parser.
+//!
+//! Notes are saved (encrypted, and queued for sync) one at a time. A note that
+//! fails doesn't stop the import, it just shows up in the result's `errors`.
+
+use ::std::fs;
+use ::std::io::Read;
+use ::std::path::PathBuf;
+use ::jedi::{self, Value};
+use ::crypto;
+use ::time;
+use ::error::{TResult, TError};
+use ::messaging;
+use ::models::model::Model;
+use ::models::protected::Protected;
+use ::models::space::Space;
+use ::models::board::Board;
+use ::models::note::Note;
+use ::models::file::{File, FileData};
+use ::models::sync_record::SyncAction;
+use ::lib_permissions::Permission;
+use ::sync::sync_model;
+use ::turtl::Turtl;
+
+/// Where the import comes from and where it goes
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct EnexOptions {
+    /// The ENEX file to import
+    pub path: String,
+    /// The space to import into. If not given, we make a new one.
+    pub space_id: Option<String>,
+    /// The board the notebook becomes. Defaults to the file's name.
+    pub board_title: Option<String>,
+}
+
+/// A note we couldn't import
+#[derive(Serialize, Debug)]
+pub struct EnexError {
+    /// Where the note is in the file (starting at 0)
+    pub index: usize,
+    pub title: Option<String>,
+    pub error: String,
+}
+
+/// How an import went
+#[derive(Serialize, Debug, Default)]
+pub struct EnexResult {
+    pub space_id: String,
+    pub board_id: String,
+    /// How many notes we created
+    pub notes: u64,
+    pub errors: Vec<EnexError>,
+}
+
+/// Progress, sent to the UI as `profile:import:enex:progress` events
+#[derive(Serialize, Debug)]
+struct EnexProgress {
+    total: u64,
+    done: u64,
+    failed: u64,
+}
+
+/// An attachment pulled out of an ENEX note
+#[derive(Debug, PartialEq)]
+pub struct Resource {
+    pub name: Option<String>,
+    pub mime: Option<String>,
+    pub data: Vec<u8>,
+}
+
+/// A note pulled out of an ENEX file
+#[derive(Debug, Default, PartialEq)]
+pub struct EnexNote {
+    pub title: Option<String>,
+    pub text: String,
+    pub tags: Vec<String>,
+    pub url: Option<String>,
+    /// unix seconds
+    pub updated: Option<i64>,
+    pub resources: Vec<Resource>,
+}
+
+/// Find where a `<tag ...>` opening tag starts (and its `>` ends) in `xml`
+fn find_open(xml: &str, tag: &str) -> Option<(usize, usize)> {
+    let open = format!("<{}", tag);
+    let mut from = 0;
+    while let Some(idx) = xml[from..].find(open.as_str()) {
+        let start = from + idx;
+        let after = start + open.len();
+        match xml[after..].chars().next() {
+            Some('>') | Some(' ') | Some('\t') | Some('\r') | Some('\n') | Some('/') => {
+                let end = match xml[after..].find('>') {
+                    Some(x) => after + x,
+                    None => return None,
+                };
+                return Some((start, end));
+            }
+            _ => from = after,
+        }
+    }
+    None
+}
+
+/// Grab the insides of every `<tag>...</tag>` in `xml`. Tags of the same name
+/// can't nest, which holds for the parts of ENEX we care about.
+pub fn elements<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let close = format!("</{}>", tag);
+    let mut found = Vec::new();
+    let mut rest = xml;
+    while let Some((_, open_end)) = find_open(rest, tag) {
+        // `<tag/>`
+        if rest[..open_end].ends_with('/') {
+            found.push(&rest[open_end..open_end]);
+            rest = &rest[open_end + 1..];
+            continue;
+        }
+        let inner_start = open_end + 1;
+        let inner_end = match rest[inner_start..].find(close.as_str()) {
+            Some(x) => inner_start + x,
+            None => break,
+        };
+        found.push(&rest[inner_start..inner_end]);
+        rest = &rest[inner_end + close.len()..];
+    }
+    found
+}
+
+/// Grab the text of the first `<tag>` in `xml`
+fn element_text(xml: &str, tag: &str) -> Option<String> {
+    elements(xml, tag).into_iter().next()
+        .map(|x| text(x))
+        .and_then(|x| if x == "" { None } else { Some(x) })
+}
+
+/// Turn an element's insides into text: unwrap CDATA, or decode entities
+pub fn text(inner: &str) -> String {
+    let trimmed = inner.trim();
+    if trimmed.starts_with("<![CDATA[") && trimmed.ends_with("]]>") {
+        return String::from(&trimmed[9..trimmed.len() - 3]);
+    }
+    unescape(trimmed)
+}
+
+/// Decode XML/HTML entities
+pub fn unescape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(idx) = rest.find('&') {
+        out.push_str(&rest[..idx]);
+        rest = &rest[idx..];
+        let end = match rest.find(';') {
+            Some(x) if x <= 10 => x,
+            _ => {
+                out.push('&');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        let entity = &rest[1..end];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ if entity.starts_with("#x") => u32::from_str_radix(&entity[2..], 16).ok().and_then(::std::char::from_u32),
+            _ if entity.starts_with("#") => entity[1..].parse::<u32>().ok().and_then(::std::char::from_u32),
+            _ => None,
+        };
+        match decoded {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Boil a note's ENML (Evernote's HTML) down to plain text. Block elements
+/// become line breaks, list items become markdown list items, and attachments
+/// (`<en-media>`) and checkboxes (`<en-todo>`) get placeholders.
+pub fn enml_to_text(enml: &str) -> String {
+    let mut out = String::with_capacity(enml.len());
+    let mut rest = enml;
+    while let Some(idx) = rest.find('<') {
+        out.push_str(&unescape(&rest[..idx]));
+        let end = match rest[idx..].find('>') {
+            Some(x) => idx + x,
+            None => {
+                rest = &rest[idx..];
+                break;
+            }
+        };
+        let tag = rest[idx + 1..end].trim().to_lowercase();
+        let name = tag.trim_left_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or("")
+            .to_string();
+        let closing = tag.starts_with('/');
+        match name.as_str() {
+            "br" => out.push('\n'),
+            "div" | "p" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "tr" | "ul" | "ol" | "blockquote" | "pre" => {
+                if closing && !out.ends_with('\n') { out.push('\n'); }
+            }
+            "li" => {
+                if !closing {
+                    if !out.ends_with('\n') && out.len() > 0 { out.push('\n'); }
+                    out.push_str("- ");
+                }
+            }
+            "en-todo" => {
+                if tag.contains("checked=\"true\"") { out.push_str("[x] "); } else { out.push_str("[ ] "); }
+            }
+            "en-media" => out.push_str("[attachment]"),
+            "hr" => out.push_str("\n---\n"),
+            _ => {}
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(&unescape(rest));
+    let lines = out.lines()
+        .map(|x| x.trim_right())
+        .collect::<Vec<_>>();
+    // squash runs of blank lines
+    let mut text = String::new();
+    let mut blank = 0;
+    for line in lines {
+        if line == "" {
+            blank += 1;
+            if blank > 1 { continue; }
+        } else {
+            blank = 0;
+        }
+        text.push_str(line);
+        text.push('\n');
+    }
+    String::from(text.trim())
+}
+
+/// Parse an ENEX date (`20180101T120000Z`) into unix seconds
+fn parse_date(date: &str) -> Option<i64> {
+    time::strptime(date.trim(), "%Y%m%dT%H%M%SZ").ok()
+        .map(|x| x.to_timespec().sec)
+}
+
+/// Pull the notes out of an ENEX file's contents
+pub fn parse(xml: &str) -> TResult<Vec<EnexNote>> {
+    if find_open(xml, "en-export").is_none() {
+        return TErr!(TError::BadValue(String::from("this doesn't look like an ENEX file")));
+    }
+    let mut notes = Vec::new();
+    for note_xml in elements(xml, "note") {
+        let mut note = EnexNote::default();
+        note.title = element_text(note_xml, "title");
+        if let Some(content) = elements(note_xml, "content").into_iter().next() {
+            note.text = enml_to_text(&text(content));
+        }
+        note.tags = elements(note_xml, "tag").into_iter()
+            .map(|x| text(x))
+            .filter(|x| x != "")
+            .collect();
+        note.url = element_text(note_xml, "source-url");
+        note.updated = element_text(note_xml, "updated")
+            .or_else(|| element_text(note_xml, "created"))
+            .and_then(|x| parse_date(&x));
+        for res_xml in elements(note_xml, "resource") {
+            let data = match elements(res_xml, "data").into_iter().next() {
+                Some(x) => x,
+                None => continue,
+            };
+            let data = data.chars().filter(|c| !c.is_whitespace()).collect::<String>();
+            note.resources.push(Resource {
+                name: element_text(res_xml, "file-name"),
+                mime: element_text(res_xml, "mime"),
+                data: crypto::from_base64(&data)?,
+            });
+        }
+        notes.push(note);
+    }
+    Ok(notes)
+}
+
+/// Build (and save) a note, with a file if given
+fn save_note(turtl: &Turtl, user_id: &String, space_id: &String, board_id: &String, enex: &EnexNote, title: Option<String>, text: &String, resource: Option<&Resource>) -> TResult<()> {
+    let mut note = Note::new();
+    note.user_id = user_id.clone();
+    note.space_id = space_id.clone();
+    note.board_id = Some(board_id.clone());
+    note.title = title;
+    note.tags = if enex.tags.len() > 0 { Some(enex.tags.clone()) } else { None };
+    note.url = enex.url.clone();
+    note.mod_ = Some(enex.updated.unwrap_or(time::get_time().sec));
+    if text != "" || resource.is_none() {
+        note.text = Some(text.clone());
+    }
+    let filedata = match resource {
+        Some(resource) => {
+            let is_image = resource.mime.as_ref().map(|x| x.starts_with("image/")).unwrap_or(false);
+            note.type_ = Some(String::from(if is_image { "image" } else { "file" }));
+            let mut file = File::new();
+            file.size = Some(resource.data.len() as u64);
+            file.name = resource.name.clone();
+            file.ty = resource.mime.clone();
+            note.file = Some(file);
+            let mut filedata = FileData::new();
+            filedata.data = Some(resource.data.clone());
+            Some(filedata)
+        }
+        None => {
+            note.type_ = Some(String::from("text"));
+            None
+        }
+    };
+    Note::save_new(turtl, &mut note, filedata)?;
+    Ok(())
+}
+
+/// Import an ENEX file
+pub fn import(turtl: &Turtl, options: &EnexOptions) -> TResult<EnexResult> {
+    let xml = {
+        let mut file = fs::File::open(&options.path)?;
+        let mut xml = String::new();
+        file.read_to_string(&mut xml)?;
+        xml
+    };
+    // parsing (and decoding attachments) can take a bit, so keep it off the
+    // main thread
+    let notes = turtl.work.run(move || parse(&xml))?;
+    let user_id = turtl.user_id()?;
+    let session = turtl.session();
+    let notebook = options.board_title.clone()
+        .or_else(|| {
+            PathBuf::from(&options.path).file_stem()
+                .and_then(|x| x.to_str())
+                .map(|x| String::from(x))
+        })
+        .unwrap_or(String::from("Evernote"));
+
+    let space_id = match options.space_id.as_ref() {
+        Some(x) => x.clone(),
+        None => {
+            let mut space: Space = Default::default();
+            space.generate_key()?;
+            space.user_id = user_id.clone();
+            space.title = Some(String::from("Evernote"));
+            let val = sync_model::save_model(SyncAction::Add, turtl, &mut space, false)?;
+            jedi::get(&["id"], &val)?
+        }
+    };
+    Space::permission_check(turtl, &space_id, &Permission::AddBoard)?;
+    Space::permission_check(turtl, &space_id, &Permission::AddNote)?;
+    let board_id: String = {
+        let mut board: Board = Default::default();
+        board.generate_key()?;
+        board.user_id = user_id.clone();
+        board.space_id = space_id.clone();
+        board.title = Some(notebook);
+        let val: Value = sync_model::save_model(SyncAction::Add, turtl, &mut board, false)?;
+        jedi::get(&["id"], &val)?
+    };
+
+    let mut result = EnexResult {
+        space_id: space_id.clone(),
+        board_id: board_id.clone(),
+        ..Default::default()
+    };
+    let mut progress = EnexProgress { total: notes.len() as u64, done: 0, failed: 0 };
+    for (index, enex) in notes.iter().enumerate() {
+        session.check()?;
+        let mut saved = save_note(turtl, &user_id, &space_id, &board_id, enex, enex.title.clone(), &enex.text, enex.resources.get(0));
+        // extra attachments each get their own note
+        for resource in enex.resources.iter().skip(1) {
+            if saved.is_err() { break; }
+            let title = match (enex.title.as_ref(), resource.name.as_ref()) {
+                (Some(title), Some(name)) => Some(format!("{} - {}", title, name)),
+                (title, name) => name.or(title).map(|x| x.clone()),
+            };
+            saved = save_note(turtl, &user_id, &space_id, &board_id, enex, title, &String::new(), Some(resource));
+        }
+        match saved {
+            Ok(_) => result.notes += 1,
+            Err(e) => {
+                warn!("enex::import() -- problem importing note {} ({:?}): {}", index, enex.title, e);
+                result.errors.push(EnexError {
+                    index: index,
+                    title: enex.title.clone(),
+                    error: format!("{}", e),
+                });
+                progress.failed += 1;
+            }
+        }
+        progress.done += 1;
+        messaging::ui_event("profile:import:enex:progress", &progress)?;
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_enex() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE en-export SYSTEM "http://xml.evernote.com/pub/evernote-export3.dtd">
+<en-export export-date="20180101T000000Z" application="Evernote" version="Evernote Mac 7.0">
+  <note>
+    <title>Groceries &amp; stuff</title>
+    <content><![CDATA[<?xml version="1.0" encoding="UTF-8" standalone="no"?>
+<!DOCTYPE en-note SYSTEM "http://xml.evernote.com/pub/enml2.dtd">
+<en-note><div>Buy:</div><ul><li>eggs</li><li>milk &amp; honey</li></ul><div><en-todo checked="true"/>call mom<br/></div><en-media hash="abc" type="image/png"/></en-note>]]></content>
+    <created>20180102T030405Z</created>
+    <tag>food</tag>
+    <tag>todo</tag>
+    <note-attributes><source-url>https://example.com/list</source-url></note-attributes>
+    <resource>
+      <data encoding="base64">aGVs
+bG8=</data>
+      <mime>image/png</mime>
+      <resource-attributes><file-name>list.png</file-name></resource-attributes>
+    </resource>
+  </note>
+  <note><title>Empty</title><content></content></note>
+</en-export>"#;
+        let notes = parse(xml).unwrap();
+        assert_eq!(notes.len(), 2);
+        let note = &notes[0];
+        assert_eq!(note.title, Some(String::from("Groceries & stuff")));
+        assert_eq!(note.text, "Buy:\n- eggs\n- milk & honey\n[x] call mom\n[attachment]");
+        assert_eq!(note.tags, vec![String::from("food"), String::from("todo")]);
+        assert_eq!(note.url, Some(String::from("https://example.com/list")));
+        assert_eq!(note.updated, Some(1514862245));
+        assert_eq!(note.resources, vec![Resource {
+            name: Some(String::from("list.png")),
+            mime: Some(String::from("image/png")),
+            data: Vec::from("hello".as_bytes()),
+        }]);
+        assert_eq!(notes[1].text, "");
+        assert!(parse("<html></html>").is_err());
+    }
+}
@@ -19,6 +19,55 @@ macro_rules! quick_error_obj {
     }
 }
 
+/// Describes why we said no to something. For permission checks against a
+/// space (the ACL), this tells the UI what was attempted, what role that takes,
+/// and what role the user has, so it can explain the refusal and what to ask
+/// the space's owner for. Other denials (bad login, etc) only have a message.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct PermissionDenial {
+    /// What went wrong
+    pub message: String,
+    /// The permission that was checked (ie "EditNote")
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub action: Option<String>,
+    /// The id of the item (generally a space) we checked against
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub item: Option<String>,
+    /// The lowest role that has the permission
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub required_role: Option<String>,
+    /// The user's role in the space (None if they aren't a member)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_role: Option<String>,
+    /// What the user can do about it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+}
+
+impl PermissionDenial {
+    /// Create a denial that's just a message
+    pub fn new<T: Into<String>>(message: T) -> PermissionDenial {
+        PermissionDenial {
+            message: message.into(),
+            ..Default::default()
+        }
+    }
+}
+
+/// One problem with one field of a model that didn't pass validation. These
+/// go out to the UI (as part of `TError::Validation`) so it can point at the
+/// field in question.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FieldError {
+    /// The field's name (as the UI knows it, so `mod` and not `mod_`)
+    pub field: String,
+    /// Which check failed: `required`, `max_len`, `url`, or `invalid` for
+    /// checks a model does by hand
+    pub rule: String,
+    /// What to tell the user
+    pub message: String,
+}
+
 quick_error! {
     #[derive(Debug)]
     /// Turtl's main error object.
@@ -55,11 +104,19 @@ quick_error! {
             description(msg)
             display("{}", quick_error_obj!("not_found", msg))
         }
-        PermissionDenied(msg: String) {
-            description(msg)
-            display("{}", quick_error_obj!("permission_denied", msg))
-        }
-        Validation(objtype: String, errors: Vec<(String, String)>) {
+        PermissionDenied(denial: PermissionDenial) {
+            description(&denial.message)
+            display("{}", json!({
+                "type": "permission_denied",
+                "message": denial.message,
+                "action": denial.action,
+                "item": denial.item,
+                "required_role": denial.required_role,
+                "current_role": denial.current_role,
+                "hint": denial.hint,
+            }))
+        }
+        Validation(objtype: String, errors: Vec<FieldError>) {
             description("validaton error")
             display("{}", json!({"type": "validation", "subtype": objtype, "errors": errors}))
         }
@@ -117,6 +174,10 @@ quick_error! {
             description("not implemented")
             display("{}", json!({"type": "not_implemented"}))
         }
+        Cancelled {
+            description("cancelled")
+            display("{}", json!({"type": "cancelled"}))
+        }
     }
 }
 
@@ -282,6 +343,7 @@ from_err!(::std::sync::mpsc::RecvError);
 from_err!(::glob::PatternError);
 from_err!(::glob::GlobError);
 from_err!(::log::SetLoggerError);
+from_err!(::tungstenite::Error);
 
 pub type BoxFuture<T, E> = Box<::futures::Future<Item = T, Error = E> + Send>;
 pub type TResult<T> = Result<T, TError>;
@@ -0,0 +1,158 @@
+//! Entry points for our fuzz targets (see `fuzz/`). Each of these takes raw,
+//! untrusted bytes and runs them through one of the parsers that sees data
+//! from the outside world (other members of a shared space, the API, the UI).
+//! The only thing that counts as failure here is a panic: every error path is
+//! fine, as long as it's an error and not a crash.
+//!
+//! Only built with the `fuzzing` feature.
+
+use ::std::str;
+
+use ::jedi::{self, Value};
+
+use ::crypto::{self, Key, CryptoOp};
+use ::dispatch::{self, Incoming};
+use ::error::TResult;
+use ::models::model::Model;
+use ::models::protected::Protected;
+use ::models::user::User;
+use ::models::keychain::KeychainEntry;
+use ::models::space::Space;
+use ::models::board::Board;
+use ::models::note::Note;
+use ::models::template::Template;
+use ::models::settings::Settings;
+use ::models::saved_search::SavedSearch;
+use ::models::file::FileData;
+use ::models::invite::Invite;
+use ::models::sync_record::{SyncRecord, SyncType};
+
+/// A fixed key so the fuzzer can reach past decryption
+fn fuzz_key() -> Key {
+    Key::new(vec![42; crypto::keylen()])
+}
+
+/// Grab a JSON string out of our raw bytes
+fn as_str(data: &[u8]) -> Option<String> {
+    match str::from_utf8(data) {
+        Ok(x) => Some(String::from(x)),
+        Err(_) => None,
+    }
+}
+
+/// Load a model from JSON and write it back out again
+fn roundtrip<T: Protected>(data: Value) -> TResult<()> {
+    let model = T::clone_from(data)?;
+    model.stringify_for_storage()?;
+    Ok(())
+}
+
+/// Fuzz the protected model pipeline: the crypto header parser, decrypting and
+/// merging an arbitrary plaintext into a model, and parsing a model (with its
+/// keys and body) straight from JSON.
+pub fn protected_body(data: &[u8]) {
+    let key = fuzz_key();
+
+    // raw crypto payloads
+    let _ = crypto::decrypt(&key, Vec::from(data));
+
+    // valid crypto wrapping an arbitrary plaintext, so we hit the JSON parsing
+    // and merging that happens after decryption
+    let op = match CryptoOp::new("chacha20poly1305") {
+        Ok(x) => x,
+        Err(_) => return,
+    };
+    if let Ok(encrypted) = crypto::encrypt(&key, Vec::from(data), op) {
+        if let Ok(body) = crypto::to_base64(&encrypted) {
+            let mut note = Note::new();
+            note.set_body(body);
+            note.set_key(Some(key.clone()));
+            let _ = note.deserialize();
+        }
+    }
+
+    // a model straight from JSON
+    let json = match as_str(data) {
+        Some(x) => x,
+        None => return,
+    };
+    let mut note: Note = match jedi::parse(&json) {
+        Ok(x) => x,
+        Err(_) => return,
+    };
+    note.set_key(Some(key));
+    let _ = note.deserialize();
+    let _ = note.stringify_for_storage();
+}
+
+/// Fuzz incoming sync record parsing, including turning the record's data into
+/// the model it describes.
+pub fn sync_record(data: &[u8]) {
+    let json = match as_str(data) {
+        Some(x) => x,
+        None => return,
+    };
+    let mut record: SyncRecord = match jedi::parse(&json) {
+        Ok(x) => x,
+        Err(_) => return,
+    };
+    let _ = jedi::stringify(&record);
+    let item = match record.data.take() {
+        Some(x) => x,
+        None => return,
+    };
+    let _ = match record.ty {
+        SyncType::User => roundtrip::<User>(item),
+        SyncType::Keychain => roundtrip::<KeychainEntry>(item),
+        SyncType::Space => roundtrip::<Space>(item),
+        SyncType::Board => roundtrip::<Board>(item),
+        SyncType::Note => roundtrip::<Note>(item),
+        SyncType::Template => roundtrip::<Template>(item),
+        SyncType::Settings => roundtrip::<Settings>(item),
+        SyncType::SavedSearch => roundtrip::<SavedSearch>(item),
+        SyncType::File | SyncType::FileIncoming | SyncType::FileOutgoing => roundtrip::<FileData>(item),
+        SyncType::Invite => roundtrip::<Invite>(item),
+    };
+}
+
+/// Fuzz the parsing of messages coming in from the UI
+pub fn dispatch_protocol(data: &[u8]) {
+    let msg = match as_str(data) {
+        Some(x) => x,
+        None => return,
+    };
+    match dispatch::parse(&msg) {
+        Ok(Incoming::Event(ev)) => { let _ = jedi::stringify(&ev); }
+        Ok(Incoming::Command(..)) => {}
+        Err(_) => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn survives_junk() {
+        let junk: Vec<&[u8]> = vec![
+            b"",
+            b":",
+            b"::e",
+            b"::ev",
+            b"::ev{\"e\":1}",
+            "\u{2620}\u{2620}".as_bytes(),
+            b"[\"1\"]",
+            b"[\"1\", \"app:api:set-endpoint\", \"a\"]",
+            b"{\"action\":\"add\",\"item_id\":\"x\",\"user_id\":\"lol\",\"type\":\"note\",\"data\":{\"id\":4}}",
+            b"{\"id\":\"1234\",\"body\":\"AAYBAAw=\",\"keys\":[{\"s\":\"1\",\"k\":\"\"}]}",
+            &[0, 6, 255],
+            &[0, 6, 1, 0, 255, 1],
+            &[0xff, 0xfe, 0x00],
+        ];
+        for data in junk {
+            protected_body(data);
+            sync_record(data);
+            dispatch_protocol(data);
+        }
+    }
+}
@@ -1,5 +1,6 @@
 #![recursion_limit="128"]
 
+extern crate argon2;
 extern crate base64;
 extern crate carrier;
 extern crate clippo;
@@ -9,12 +10,15 @@ extern crate crossbeam;
 extern crate dumpy;
 extern crate encoding_rs;
 extern crate fern;
+extern crate flate2;
 extern crate fs2;
 extern crate futures;
 extern crate futures_cpupool;
 extern crate glob;
 extern crate hex;
 extern crate hyper;
+#[cfg(feature = "thumbnails")]
+extern crate image;
 extern crate jedi;
 #[macro_use]
 extern crate lazy_static;
@@ -23,6 +27,8 @@ extern crate lib_permissions;
 extern crate log;
 extern crate migrate;
 extern crate num_cpus;
+#[cfg(feature = "extract")]
+extern crate pdf_extract;
 #[macro_use]
 extern crate protected_derive;
 #[macro_use]
@@ -36,6 +42,10 @@ extern crate serde_derive;
 extern crate serde_json;
 extern crate sodiumoxide;
 extern crate time;
+extern crate tungstenite;
+extern crate url;
+#[cfg(feature = "extract")]
+extern crate zip;
 
 #[macro_use]
 pub mod error;
@@ -49,14 +59,26 @@ mod sync;
 #[macro_use]
 mod models;
 mod profile;
+mod markdown;
+mod enex;
 mod storage;
 mod search;
+mod search_store;
+mod search_reindex;
 mod dispatch;
+mod middleware;
+mod protocol;
 mod schema;
+mod shutdown;
 mod turtl;
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
+
+/// Lets embedders hook into the sync system (see `sync::hooks`)
+pub use sync::hooks;
 
 use ::std::thread;
-use ::std::sync::Arc;
+use ::std::sync::{Arc, mpsc};
 use ::std::env;
 use ::std::fs;
 use ::jedi::Value;
@@ -152,6 +174,22 @@ pub fn start() -> thread::JoinHandle<()> {
             // create our turtl object
             let turtl = Arc::new(turtl::Turtl::new()?);
 
+            // run our shutdown coordinator once someone asks for a shutdown
+            // (or once our messaging loop exits, whichever comes first)
+            let (shutdown_tx, shutdown_rx) = mpsc::channel();
+            turtl.set_shutdown_signal(shutdown_tx.clone());
+            let turtl_shutdown = turtl.clone();
+            let shutdown_handle = thread::Builder::new().name(String::from("turtl-shutdown")).spawn(move || {
+                match shutdown_rx.recv() {
+                    Ok(_) => {}
+                    Err(_) => return,
+                }
+                match shutdown::run(turtl_shutdown) {
+                    Ok(report) => info!("main::start() -- shutdown complete: {:?}", report),
+                    Err(e) => error!("main::start() -- problem shutting down: {}", e),
+                }
+            })?;
+
             // start our messaging thread
             let msg_res = messaging::start(move |msg: String| {
                 let turtl2 = turtl.clone();
@@ -172,6 +210,14 @@ pub fn start() -> thread::JoinHandle<()> {
                 Ok(..) => {},
                 Err(e) => error!("main::start() -- messaging error: {}", e),
             }
+            // if our messaging loop died on its own, make sure we still shut
+            // down cleanly. if a shutdown already ran, nobody is listening and
+            // this send just fails.
+            let _ = shutdown_tx.send(());
+            match shutdown_handle.join() {
+                Ok(_) => {}
+                Err(e) => error!("main::start() -- problem joining shutdown thread: {:?}", e),
+            }
             drop(lockfile);
             info!("main::start() -- shutting down");
             Ok(())
@@ -649,6 +695,8 @@ mod tests {
         assert_eq!(res_msg, r#"{"e":0,"d":{}}"#);
 
         handle.join().unwrap();
+        let res_ev = recv_event().unwrap();
+        assert!(res_ev.starts_with(r#"{"e":"app:shutdown-complete""#));
 
         let handle = start(String::from(r#"{"messaging":{"reqres_append_mid":false}}"#));
 
@@ -663,6 +711,8 @@ mod tests {
         assert_eq!(res_msg, r#"{"id":"4","e":0,"d":{}}"#);
 
         handle.join().unwrap();
+        let res_ev = recv_event().unwrap();
+        assert!(res_ev.starts_with(r#"{"e":"app:shutdown-complete""#));
     }
 
     #[test]
@@ -689,6 +739,8 @@ mod tests {
         let res_msg = recv_str("2");
         assert_eq!(res_msg, r#"{"e":0,"d":{}}"#);
         handle.join().unwrap();
+        let res_ev = recv_str("");
+        assert!(res_ev.starts_with(r#"{"e":"app:shutdown-complete""#));
     }
 }
 
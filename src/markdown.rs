@@ -0,0 +1,552 @@
+//! Exports notes as a directory tree of markdown files, for people who want
+//! their notes somewhere other than Turtl (or just want a readable backup).
+//!
+//! Notes end up in `<dir>/<space>/<board>/<title>.md` (notes without a board go
+//! right in the space's folder). Each file starts with front-matter holding the
+//! note's metadata, followed by the note's text. A note's file, if it has one,
+//! goes next to it in `<title>.files/`.
+//!
+//! Importing goes the other way: point it at a folder (like one of the space
+//! folders an export makes) and each `.md` file becomes a note in the space
+//! you pick. A file's first-level folder becomes its board, any folders below
+//! that become tags, and front-matter (ours, or simple `key: value` lines from
+//! elsewhere) fills in the rest. A `<title>.files/` folder next to a file
+//! becomes that note's file.
+
+use ::std::collections::HashMap;
+use ::std::fs;
+use ::std::io::{Read, Write};
+use ::std::path::PathBuf;
+use ::jedi::{self, Value};
+use ::error::{TResult, TError};
+use ::messaging;
+use ::models::model::Model;
+use ::models::protected::Protected;
+use ::models::note::Note;
+use ::models::board::Board;
+use ::models::space::Space;
+use ::models::file::{File, FileData};
+use ::models::sync_record::SyncAction;
+use ::lib_permissions::Permission;
+use ::sync::sync_model;
+use ::time;
+use ::turtl::Turtl;
+use ::util;
+
+/// What to export, and where
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ExportOptions {
+    /// The directory to write into (created if needed)
+    pub dir: String,
+    /// Export these notes...
+    pub note_ids: Vec<String>,
+    /// ...or all the notes in this space
+    pub space_id: Option<String>,
+}
+
+/// How an export went
+#[derive(Serialize, Debug, Default)]
+pub struct ExportResult {
+    /// How many notes we wrote
+    pub notes: u64,
+    /// How many files we wrote
+    pub files: u64,
+    /// Notes whose files we don't have locally (they weren't written)
+    pub missing_files: Vec<String>,
+}
+
+/// Progress, sent to the UI as `profile:export:markdown:progress` events
+#[derive(Serialize, Debug)]
+struct ExportProgress {
+    total: u64,
+    done: u64,
+}
+
+/// Turn a title into something that's safe to use as a file/folder name
+pub fn filename(title: &str, fallback: &str) -> String {
+    let cleaned = title.chars()
+        .map(|c| if c.is_alphanumeric() || c == ' ' || c == '-' || c == '_' || c == '.' { c } else { '-' })
+        .take(80)
+        .collect::<String>();
+    let cleaned = cleaned.trim().trim_matches('.').trim();
+    if cleaned == "" { String::from(fallback) } else { String::from(cleaned) }
+}
+
+/// Build a note's front-matter. Values are written as JSON, which YAML reads
+/// just fine.
+fn front_matter(note: &Note, space: &String, board: Option<&String>) -> TResult<String> {
+    let mut lines: Vec<String> = vec![String::from("---")];
+    {
+        let mut field = |name: &str, val: Value| -> TResult<()> {
+            if val.is_null() { return Ok(()); }
+            lines.push(format!("{}: {}", name, jedi::stringify(&val)?));
+            Ok(())
+        };
+        field("id", jedi::to_val(&note.id())?)?;
+        field("title", jedi::to_val(&note.title)?)?;
+        field("type", jedi::to_val(&note.type_)?)?;
+        field("space", jedi::to_val(space)?)?;
+        field("board", jedi::to_val(&board)?)?;
+        field("tags", jedi::to_val(&note.tags)?)?;
+        field("url", jedi::to_val(&note.url)?)?;
+        field("username", jedi::to_val(&note.username)?)?;
+        field("password", jedi::to_val(&note.password)?)?;
+        field("color", jedi::to_val(&note.color)?)?;
+        field("pinned", jedi::to_val(&note.pinned)?)?;
+        field("modified", jedi::to_val(&note.mod_)?)?;
+        field("fields", jedi::to_val(&note.fields)?)?;
+        field("file", jedi::to_val(&note.file.as_ref().and_then(|x| x.name.clone()))?)?;
+    }
+    lines.push(String::from("---"));
+    Ok(lines.join("\n"))
+}
+
+/// Find a path in `dir` for `name` + `ext` that we haven't used yet
+fn unused_path(dir: &PathBuf, name: &String, ext: &str, used: &mut Vec<PathBuf>) -> PathBuf {
+    let mut num = 1;
+    loop {
+        let filename = if num == 1 { format!("{}{}", name, ext) } else { format!("{}-{}{}", name, num, ext) };
+        let mut path = dir.clone();
+        path.push(filename);
+        if !used.contains(&path) && !path.exists() {
+            used.push(path.clone());
+            return path;
+        }
+        num += 1;
+    }
+}
+
+/// Grab the notes we're exporting
+fn load_notes(turtl: &Turtl, options: &ExportOptions) -> TResult<Vec<Note>> {
+    let note_ids = match options.space_id.as_ref() {
+        Some(space_id) if options.note_ids.len() == 0 => {
+            let notes: Vec<Note> = with_db!{ db, turtl.db, db.find("notes", "space_id", &vec![space_id.clone()])? };
+            notes.iter()
+                .filter_map(|x| x.id().map(|id| id.clone()))
+                .collect::<Vec<_>>()
+        }
+        _ => options.note_ids.clone(),
+    };
+    let notes = turtl.load_notes(&note_ids)?;
+    Ok(notes.into_iter().filter(|x| x.trashed.is_none()).collect())
+}
+
+/// Run the export
+pub fn export(turtl: &Turtl, options: &ExportOptions) -> TResult<ExportResult> {
+    if options.dir == "" {
+        return TErr!(TError::MissingField(String::from("ExportOptions.dir")));
+    }
+    if options.note_ids.len() == 0 && options.space_id.is_none() {
+        return TErr!(TError::MissingField(String::from("ExportOptions.note_ids")));
+    }
+    let session = turtl.session();
+    let notes = load_notes(turtl, options)?;
+
+    // grab our folder names from the profile
+    let (spaces, boards) = {
+        let profile_guard = lockr!(turtl.profile);
+        let mut spaces: HashMap<String, String> = HashMap::new();
+        let mut boards: HashMap<String, String> = HashMap::new();
+        for space in &profile_guard.spaces {
+            if let Some(id) = space.id() {
+                spaces.insert(id.clone(), space.title.clone().unwrap_or(id.clone()));
+            }
+        }
+        for board in &profile_guard.boards {
+            if let Some(id) = board.id() {
+                boards.insert(id.clone(), board.title.clone().unwrap_or(id.clone()));
+            }
+        }
+        (spaces, boards)
+    };
+
+    let mut result = ExportResult::default();
+    let mut progress = ExportProgress { total: notes.len() as u64, done: 0 };
+    let mut used: Vec<PathBuf> = Vec::new();
+    for note in &notes {
+        session.check()?;
+        let note_id = note.id_or_else()?;
+        let space = spaces.get(&note.space_id).unwrap_or(&note.space_id);
+        let board = note.board_id.as_ref().map(|x| boards.get(x).unwrap_or(x));
+        let mut dir = PathBuf::from(&options.dir);
+        dir.push(filename(space, &note.space_id));
+        if let (Some(board), Some(board_id)) = (board, note.board_id.as_ref()) {
+            dir.push(filename(board, board_id));
+        }
+        util::create_dir(&dir)?;
+
+        let name = filename(note.title.as_ref().map(|x| x.as_str()).unwrap_or(""), &note_id);
+        let path = unused_path(&dir, &name, ".md", &mut used);
+        let mut contents = front_matter(note, space, board)?;
+        contents.push_str("\n\n");
+        if let Some(text) = note.text.as_ref() {
+            contents.push_str(text);
+            contents.push_str("\n");
+        }
+        let mut fs_file = fs::File::create(&path)?;
+        fs_file.write_all(contents.as_bytes())?;
+        result.notes += 1;
+
+        if note.has_file {
+            match FileData::load_file(turtl, note) {
+                Ok(data) => {
+                    let mut filedir = dir.clone();
+                    let stem = path.file_stem()
+                        .and_then(|x| x.to_str())
+                        .map(|x| String::from(x))
+                        .unwrap_or(name.clone());
+                    filedir.push(format!("{}.files", stem));
+                    util::create_dir(&filedir)?;
+                    let file_name = note.file.as_ref()
+                        .and_then(|x| x.name.as_ref())
+                        .map(|x| filename(x, "file"))
+                        .unwrap_or(String::from("file"));
+                    filedir.push(file_name);
+                    let mut fs_file = fs::File::create(&filedir)?;
+                    fs_file.write_all(data.as_slice())?;
+                    result.files += 1;
+                }
+                Err(e) => {
+                    warn!("markdown::export() -- couldn't load file for note {}: {}", note_id, e);
+                    result.missing_files.push(note_id.clone());
+                }
+            }
+        }
+
+        progress.done += 1;
+        messaging::ui_event("profile:export:markdown:progress", &progress)?;
+    }
+    Ok(result)
+}
+
+/// What to import, and where to put it
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ImportOptions {
+    /// The directory to read from
+    pub dir: String,
+    /// The space the notes go into
+    pub space_id: String,
+}
+
+/// A file we couldn't import
+#[derive(Serialize, Debug)]
+pub struct ImportError {
+    pub path: String,
+    pub error: String,
+}
+
+/// How an import went
+#[derive(Serialize, Debug, Default)]
+pub struct ImportResult {
+    /// How many notes we created
+    pub notes: u64,
+    /// How many of those notes got a file
+    pub files: u64,
+    /// How many boards we created
+    pub boards: u64,
+    pub errors: Vec<ImportError>,
+}
+
+/// Progress, sent to the UI as `profile:import:markdown:progress` events
+#[derive(Serialize, Debug)]
+struct ImportProgress {
+    total: u64,
+    done: u64,
+    failed: u64,
+}
+
+/// A markdown file, read and picked apart (but not yet a note)
+#[derive(Debug)]
+struct MarkdownFile {
+    path: String,
+    /// The folders between the import dir and the file
+    folders: Vec<String>,
+    /// Front-matter, as a JSON object
+    meta: Value,
+    text: String,
+    /// The file from `<title>.files/`, if any: (name, contents)
+    file: Option<(String, Vec<u8>)>,
+}
+
+/// Split a file's front-matter from its body. Values are read as JSON (which
+/// is how we export them) and if that doesn't work, as plain strings.
+pub fn parse_front_matter(contents: &str) -> (Value, String) {
+    let mut meta = json!({});
+    let mut lines = contents.lines();
+    match lines.next() {
+        Some(x) if x.trim_right() == "---" => {}
+        _ => return (meta, String::from(contents)),
+    }
+    let mut closed = false;
+    for line in lines.by_ref() {
+        if line.trim_right() == "---" {
+            closed = true;
+            break;
+        }
+        let idx = match line.find(':') {
+            Some(x) => x,
+            None => continue,
+        };
+        let key = line[..idx].trim();
+        let val = line[idx + 1..].trim();
+        if key == "" || val == "" { continue; }
+        let parsed = match jedi::parse::<Value>(&String::from(val)) {
+            Ok(x) => x,
+            Err(_) => Value::String(String::from(val.trim_matches(|c: char| c == '"' || c == '\''))),
+        };
+        meta[key] = parsed;
+    }
+    // an opening `---` with no closing one is just a horizontal rule
+    if !closed { return (json!({}), String::from(contents)); }
+    let body = lines.collect::<Vec<_>>().join("\n");
+    (meta, String::from(body.trim_matches('\n')))
+}
+
+/// Pull the tags out of front-matter. We export them as a JSON list, but take
+/// `a, b` (or `[a, b]`) too.
+fn front_matter_tags(meta: &Value) -> Vec<String> {
+    match meta.get("tags") {
+        Some(&Value::Array(ref tags)) => {
+            tags.iter()
+                .filter_map(|x| x.as_str().map(|x| String::from(x)))
+                .collect()
+        }
+        Some(&Value::String(ref tags)) => {
+            tags.trim_matches(|c: char| c == '[' || c == ']')
+                .split(',')
+                .map(|x| String::from(x.trim().trim_matches(|c: char| c == '"' || c == '\'')))
+                .filter(|x| x != "")
+                .collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Read the first file in a `<title>.files/` folder
+fn read_attachment(dir: &PathBuf) -> TResult<Option<(String, Vec<u8>)>> {
+    let mut paths = fs::read_dir(dir)?
+        .filter_map(|x| x.ok().map(|x| x.path()))
+        .filter(|x| x.is_file())
+        .collect::<Vec<_>>();
+    paths.sort();
+    let path = match paths.into_iter().next() {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let name = path.file_name()
+        .and_then(|x| x.to_str())
+        .map(|x| String::from(x))
+        .unwrap_or(String::from("file"));
+    let mut data = Vec::new();
+    fs::File::open(&path)?.read_to_end(&mut data)?;
+    Ok(Some((name, data)))
+}
+
+/// Read a markdown file (and its attachment, if it has one)
+fn read_file(path: &PathBuf, folders: &Vec<String>) -> TResult<MarkdownFile> {
+    let mut contents = String::new();
+    fs::File::open(path)?.read_to_string(&mut contents)?;
+    let (meta, text) = parse_front_matter(&contents);
+    let mut files_dir = path.clone();
+    files_dir.set_extension("files");
+    let file = if files_dir.is_dir() { read_attachment(&files_dir)? } else { None };
+    Ok(MarkdownFile {
+        path: path.to_string_lossy().into_owned(),
+        folders: folders.clone(),
+        meta: meta,
+        text: text,
+        file: file,
+    })
+}
+
+/// Walk a directory, reading every markdown file in it (and below it)
+fn scan(dir: &PathBuf, folders: &Vec<String>, found: &mut Vec<MarkdownFile>, errors: &mut Vec<ImportError>) -> TResult<()> {
+    let mut paths = fs::read_dir(dir)?
+        .filter_map(|x| x.ok().map(|x| x.path()))
+        .collect::<Vec<_>>();
+    paths.sort();
+    for path in paths {
+        let name = match path.file_name().and_then(|x| x.to_str()) {
+            Some(x) => String::from(x),
+            None => continue,
+        };
+        if name.starts_with('.') { continue; }
+        if path.is_dir() {
+            // attachments are read along with their note
+            if name.ends_with(".files") { continue; }
+            let mut sub = folders.clone();
+            sub.push(name);
+            scan(&path, &sub, found, errors)?;
+            continue;
+        }
+        match path.extension().and_then(|x| x.to_str()) {
+            Some("md") | Some("markdown") => {}
+            _ => continue,
+        }
+        match read_file(&path, folders) {
+            Ok(x) => found.push(x),
+            Err(e) => errors.push(ImportError {
+                path: path.to_string_lossy().into_owned(),
+                error: format!("{}", e),
+            }),
+        }
+    }
+    Ok(())
+}
+
+/// Find the board with the given title in a space, making it if needed.
+/// `boards` maps titles to ids for the space (and we add to it).
+fn find_board(turtl: &Turtl, space_id: &String, title: &String, boards: &mut HashMap<String, String>, result: &mut ImportResult) -> TResult<String> {
+    if let Some(id) = boards.get(title) {
+        return Ok(id.clone());
+    }
+    let mut board: Board = Default::default();
+    board.generate_key()?;
+    board.user_id = turtl.user_id()?;
+    board.space_id = space_id.clone();
+    board.title = Some(title.clone());
+    let val = sync_model::save_model(SyncAction::Add, turtl, &mut board, false)?;
+    let id: String = jedi::get(&["id"], &val)?;
+    boards.insert(title.clone(), id.clone());
+    result.boards += 1;
+    Ok(id)
+}
+
+/// Turn a markdown file into a note, and save it
+fn save_file(turtl: &Turtl, space_id: &String, md: &MarkdownFile, boards: &mut HashMap<String, String>, result: &mut ImportResult) -> TResult<()> {
+    let mut note = Note::new();
+    note.user_id = turtl.user_id()?;
+    note.space_id = space_id.clone();
+    if let Some(board) = md.folders.get(0) {
+        note.board_id = Some(find_board(turtl, space_id, board, boards, result)?);
+    }
+    let mut tags = md.folders.iter().skip(1).map(|x| x.clone()).collect::<Vec<_>>();
+    for tag in front_matter_tags(&md.meta) {
+        if !tags.contains(&tag) { tags.push(tag); }
+    }
+    if tags.len() > 0 { note.tags = Some(tags); }
+    let stem = PathBuf::from(&md.path).file_stem()
+        .and_then(|x| x.to_str())
+        .map(|x| String::from(x));
+    note.title = jedi::get_opt(&["title"], &md.meta).or(stem);
+    note.url = jedi::get_opt(&["url"], &md.meta);
+    note.username = jedi::get_opt(&["username"], &md.meta);
+    note.password = jedi::get_opt(&["password"], &md.meta);
+    note.color = jedi::get_opt(&["color"], &md.meta);
+    note.pinned = jedi::get_opt(&["pinned"], &md.meta);
+    note.fields = jedi::get_opt(&["fields"], &md.meta);
+    note.mod_ = Some(jedi::get_opt(&["modified"], &md.meta).unwrap_or(time::get_time().sec));
+    if md.text != "" { note.text = Some(md.text.clone()); }
+    let filedata = match md.file.as_ref() {
+        Some(&(ref name, ref data)) => {
+            let mut file = File::new();
+            file.size = Some(data.len() as u64);
+            file.name = Some(name.clone());
+            note.file = Some(file);
+            let mut filedata = FileData::new();
+            filedata.data = Some(data.clone());
+            Some(filedata)
+        }
+        None => None,
+    };
+    let default_type = if filedata.is_some() { "file" } else { "text" };
+    note.type_ = Some(jedi::get_opt(&["type"], &md.meta).unwrap_or(String::from(default_type)));
+    if filedata.is_some() { result.files += 1; }
+    Note::save_new(turtl, &mut note, filedata)?;
+    Ok(())
+}
+
+/// Run the import
+pub fn import(turtl: &Turtl, options: &ImportOptions) -> TResult<ImportResult> {
+    if options.dir == "" {
+        return TErr!(TError::MissingField(String::from("ImportOptions.dir")));
+    }
+    if options.space_id == "" {
+        return TErr!(TError::MissingField(String::from("ImportOptions.space_id")));
+    }
+    Space::permission_check(turtl, &options.space_id, &Permission::AddNote)?;
+    Space::permission_check(turtl, &options.space_id, &Permission::AddBoard)?;
+    let session = turtl.session();
+
+    // reading (possibly a lot of) files goes on in the work pool
+    let dir = PathBuf::from(&options.dir);
+    let (files, errors) = turtl.work.run(move || {
+        let mut found = Vec::new();
+        let mut errors = Vec::new();
+        scan(&dir, &Vec::new(), &mut found, &mut errors)?;
+        Ok((found, errors))
+    })?;
+
+    let mut boards: HashMap<String, String> = {
+        let profile_guard = lockr!(turtl.profile);
+        profile_guard.boards.iter()
+            .filter(|x| x.space_id == options.space_id)
+            .filter_map(|x| match (x.title.as_ref(), x.id()) {
+                (Some(title), Some(id)) => Some((title.clone(), id.clone())),
+                _ => None,
+            })
+            .collect()
+    };
+    let mut result = ImportResult { errors: errors, ..Default::default() };
+    let mut progress = ImportProgress {
+        total: (files.len() + result.errors.len()) as u64,
+        done: result.errors.len() as u64,
+        failed: result.errors.len() as u64,
+    };
+    for md in &files {
+        session.check()?;
+        match save_file(turtl, &options.space_id, md, &mut boards, &mut result) {
+            Ok(_) => result.notes += 1,
+            Err(e) => {
+                warn!("markdown::import() -- problem importing {}: {}", md.path, e);
+                result.errors.push(ImportError {
+                    path: md.path.clone(),
+                    error: format!("{}", e),
+                });
+                progress.failed += 1;
+            }
+        }
+        progress.done += 1;
+        messaging::ui_event("profile:import:markdown:progress", &progress)?;
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_front_matter() {
+        assert_eq!(filename("My notes: 2018/01", "x"), "My notes- 2018-01");
+        assert_eq!(filename(" ../ ", "n1"), "-");
+        assert_eq!(filename("...", "n1"), "n1");
+
+        let note: Note = jedi::parse(&String::from(r#"{"id":"n1","user_id":1,"space_id":"s1","title":"Hi \"there\"","tags":["a","b"],"text":"body"}"#)).unwrap();
+        let fm = front_matter(&note, &String::from("Personal"), None).unwrap();
+        assert_eq!(fm, r#"---
+id: "n1"
+title: "Hi \"there\""
+space: "Personal"
+tags: ["a","b"]
+---"#);
+    }
+
+    #[test]
+    fn reads_front_matter() {
+        let (meta, body) = parse_front_matter("---\nid: \"n1\"\ntitle: Shopping: weekly\ntags: [food, \"home\"]\npinned: true\n---\n\n# Eggs\n");
+        assert_eq!(meta, json!({"id": "n1", "title": "Shopping: weekly", "tags": "[food, \"home\"]", "pinned": true}));
+        assert_eq!(body, "# Eggs");
+        assert_eq!(front_matter_tags(&meta), vec![String::from("food"), String::from("home")]);
+        assert_eq!(front_matter_tags(&json!({"tags": ["a", "b"]})), vec![String::from("a"), String::from("b")]);
+
+        let (meta, body) = parse_front_matter("---\nnot front-matter");
+        assert_eq!(meta, json!({}));
+        assert_eq!(body, "---\nnot front-matter");
+        let (meta, body) = parse_front_matter("just text");
+        assert_eq!(meta, json!({}));
+        assert_eq!(body, "just text");
+    }
+}
@@ -9,6 +9,7 @@ use ::jedi::{self, Value, Serialize};
 use ::util;
 use ::config;
 use ::error::{TResult, TError};
+use ::protocol::Warning;
 
 /// Defines a container for sending responses to the client. We could use a hash
 /// table, but then the elements might serialize out of order. This allows us to
@@ -29,17 +30,26 @@ pub struct Response {
     pub e: i64,
     /// Any data we want to pass back to the UI
     pub d: Value,
+    /// Any warnings about the request (such as using a deprecated command)
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub w: Vec<Warning>,
 }
 
 impl Response {
     /// Make a new Response object with a blank id
     pub fn new(e: i64, d: Value) -> Response {
-        Response { id: None, e: e, d: d }
+        Response { id: None, e: e, d: d, w: Vec::new() }
     }
 
     /// Make a new Response object
     pub fn new_w_id(id: String, e: i64, d: Value) -> Response {
-        Response { id: Some(id), e: e, d: d }
+        Response { id: Some(id), e: e, d: d, w: Vec::new() }
+    }
+
+    /// Attach some warnings to this response
+    pub fn warnings(mut self, warnings: Vec<Warning>) -> Response {
+        self.w = warnings;
+        self
     }
 }
 
@@ -0,0 +1,451 @@
+//! Middleware wraps our dispatch handlers with the things every command needs
+//! but no command should have to implement itself: timing, deprecation
+//! warnings, making sure someone is logged in, honoring read-only mode, and
+//! making retried commands idempotent.
+//!
+//! Which of these apply to a command is declared once, in `POLICIES`, instead
+//! of in each handler. Adding a command generally means adding its handler in
+//! `dispatch` and (if it needs a login or changes anything) a line here.
+
+use ::std::mem;
+use ::std::sync::{Mutex, RwLock};
+use ::std::collections::HashMap;
+use ::std::time::{Duration, Instant};
+
+use ::jedi::Value;
+
+use ::config;
+use ::error::{TResult, TError, PermissionDenial};
+use ::protocol::{self, Warning};
+use ::turtl::Turtl;
+
+/// Describes how our middleware treats a command
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Policy {
+    /// The command needs a logged-in user
+    pub auth: bool,
+    /// The command changes data, so it's blocked in read-only mode and retries
+    /// of it are deduplicated
+    pub mutates: bool,
+}
+
+/// Anyone can run it, and it doesn't change anything
+const OPEN: Policy = Policy { auth: false, mutates: false };
+/// Doesn't need a login, but changes things
+const WRITE: Policy = Policy { auth: false, mutates: true };
+/// Needs a login, doesn't change anything
+const AUTH_READ: Policy = Policy { auth: true, mutates: false };
+/// Needs a login and changes things
+const AUTH_WRITE: Policy = Policy { auth: true, mutates: true };
+
+/// Our per-command policies. A pattern ending in `*` matches any command with
+/// that prefix, and the first matching pattern wins. Commands that don't match
+/// anything get `OPEN`.
+static POLICIES: &'static [(&'static str, Policy)] = &[
+    ("user:login*", OPEN),
+    ("user:join*", WRITE),
+    ("user:change-password", AUTH_WRITE),
+    ("user:change-email", AUTH_WRITE),
+    ("user:delete-account", AUTH_WRITE),
+    ("user:avatar:get", AUTH_READ),
+    ("user:avatar:*", AUTH_WRITE),
+    ("user:resend-confirmation", AUTH_READ),
+    ("user:get-login-token", AUTH_READ),
+    ("user:save-login", AUTH_WRITE),
+    ("app:wipe-user-data", AUTH_WRITE),
+    ("app:wipe-app-data", WRITE),
+    ("app:api:set-*", WRITE),
+    ("sync:unfreeze-item", AUTH_WRITE),
+    ("sync:unfreeze-all", AUTH_WRITE),
+    ("sync:delete-item", AUTH_WRITE),
+    ("sync:conflict:dismiss", AUTH_WRITE),
+    // the UI shuts the sync down while tearing down, logged in or not.
+    // stopping the syncers doesn't touch anyone's data, so read-only mode has
+    // no business blocking it either
+    ("sync:shutdown", OPEN),
+    // the host telling us about the device (network up/down, app in the
+    // background). this isn't a change to anything of the user's, and
+    // ignoring it in read-only mode would just leave the syncers wrong about
+    // the world
+    ("sync:set-online", OPEN),
+    ("sync:set-foreground", OPEN),
+    ("sync:start", AUTH_WRITE),
+    ("sync:pause", AUTH_WRITE),
+    ("sync:resume", AUTH_WRITE),
+    ("sync:set-*", AUTH_WRITE),
+    ("sync:*", AUTH_READ),
+    ("profile:load", AUTH_READ),
+    ("profile:get-notes", AUTH_READ),
+    ("profile:sync:changes", AUTH_READ),
+    ("profile:find-*", AUTH_READ),
+    ("profile:suggest", AUTH_READ),
+    ("profile:note:get-file", AUTH_READ),
+    ("profile:note:get-thumbnail", AUTH_READ),
+    ("profile:note:history", AUTH_READ),
+    ("profile:get-templates", AUTH_READ),
+    ("profile:saved-search:list", AUTH_READ),
+    ("profile:saved-search:run", AUTH_READ),
+    ("profile:favorites:get", AUTH_READ),
+    ("profile:invites:list", AUTH_READ),
+    ("profile:stats", AUTH_READ),
+    ("profile:export", AUTH_READ),
+    ("profile:export:*", AUTH_READ),
+    ("profile:*", AUTH_WRITE),
+    ("settings:get", AUTH_READ),
+    ("settings:*", AUTH_WRITE),
+    ("keychain:verify", AUTH_READ),
+    ("keychain:*", AUTH_WRITE),
+    ("space:export-keys", AUTH_READ),
+    ("space:*", AUTH_WRITE),
+    ("board:*", AUTH_WRITE),
+    ("trash:list", AUTH_READ),
+    ("trash:*", AUTH_WRITE),
+    ("note:reminder:list", AUTH_READ),
+    ("note:share:list", AUTH_READ),
+    ("note:*", AUTH_WRITE),
+    ("notes:*", AUTH_WRITE),
+];
+
+/// Find the policy for a command
+pub fn policy(cmd: &str) -> Policy {
+    for &(pattern, policy) in POLICIES {
+        let matches = if pattern.ends_with("*") {
+            cmd.starts_with(&pattern[0..(pattern.len() - 1)])
+        } else {
+            cmd == pattern
+        };
+        if matches { return policy; }
+    }
+    OPEN
+}
+
+/// Everything our middleware (and eventually the handler) gets to look at
+pub struct Context<'a> {
+    pub turtl: &'a Turtl,
+    /// The message id
+    pub mid: String,
+    /// The command being run
+    pub cmd: String,
+    /// The full message (`[mid, cmd, args...]`)
+    pub data: Value,
+    /// This command's policy
+    pub policy: Policy,
+    /// Warnings to send back along with the response
+    pub warnings: Vec<Warning>,
+}
+
+impl<'a> Context<'a> {
+    /// Create a context for a command
+    pub fn new(turtl: &'a Turtl, mid: String, cmd: String, data: Value) -> Context<'a> {
+        let policy = policy(cmd.as_str());
+        Context {
+            turtl: turtl,
+            mid: mid,
+            cmd: cmd,
+            data: data,
+            policy: policy,
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Take the message data out of the context (the handler gets to own it)
+    pub fn take_data(&mut self) -> Value {
+        mem::replace(&mut self.data, Value::Null)
+    }
+}
+
+/// The handler at the end of a chain
+pub type Handler<'h> = &'h Fn(&mut Context) -> TResult<Value>;
+
+/// A piece of middleware. Gets the context and the rest of the chain, and
+/// decides whether (and how) to run it.
+pub trait Middleware: Send + Sync {
+    fn handle(&self, ctx: &mut Context, next: Next) -> TResult<Value>;
+}
+
+/// The rest of a middleware chain
+pub struct Next<'c, 'h> {
+    chain: &'c [Box<Middleware>],
+    handler: Handler<'h>,
+}
+
+impl<'c, 'h> Next<'c, 'h> {
+    /// Run the rest of the chain (and then the handler)
+    pub fn run(self, ctx: &mut Context) -> TResult<Value> {
+        match self.chain.split_first() {
+            Some((middleware, rest)) => middleware.handle(ctx, Next { chain: rest, handler: self.handler }),
+            None => (self.handler)(ctx),
+        }
+    }
+}
+
+/// Run a command through a middleware chain
+pub fn run_chain(chain: &[Box<Middleware>], ctx: &mut Context, handler: Handler) -> TResult<Value> {
+    Next { chain: chain, handler: handler }.run(ctx)
+}
+
+/// Logs how long each command takes, and warns about slow ones
+pub struct Timing;
+
+impl Middleware for Timing {
+    fn handle(&self, ctx: &mut Context, next: Next) -> TResult<Value> {
+        let start = Instant::now();
+        let res = next.run(ctx);
+        let elapsed = start.elapsed();
+        let ms = elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1000000) as u64;
+        let slow_ms: u64 = config::get(&["dispatch", "slow_ms"]).unwrap_or(1000);
+        if ms >= slow_ms {
+            warn!("middleware::Timing -- {} ({}) took {}ms", ctx.cmd, ctx.mid, ms);
+        } else {
+            debug!("middleware::Timing -- {} ({}) took {}ms", ctx.cmd, ctx.mid, ms);
+        }
+        res
+    }
+}
+
+/// Attaches deprecation warnings to responses (see `protocol`)
+pub struct Deprecation;
+
+impl Middleware for Deprecation {
+    fn handle(&self, ctx: &mut Context, next: Next) -> TResult<Value> {
+        let mut warnings = protocol::check(&ctx.cmd, &ctx.data);
+        ctx.warnings.append(&mut warnings);
+        next.run(ctx)
+    }
+}
+
+/// Rejects commands that need a login if nobody is logged in
+pub struct Auth;
+
+impl Middleware for Auth {
+    fn handle(&self, ctx: &mut Context, next: Next) -> TResult<Value> {
+        if ctx.policy.auth && ctx.turtl.user_id().is_err() {
+            let mut denial = PermissionDenial::new(format!("{} requires a logged-in user", ctx.cmd));
+            denial.action = Some(ctx.cmd.clone());
+            denial.hint = Some(String::from("log in first"));
+            return TErr!(TError::PermissionDenied(denial));
+        }
+        next.run(ctx)
+    }
+}
+
+lazy_static! {
+    /// Whether we're in read-only mode
+    static ref READ_ONLY: RwLock<Option<bool>> = RwLock::new(None);
+}
+
+/// Are we in read-only mode? Defaults to `dispatch.read_only` in the config.
+pub fn read_only() -> bool {
+    let set = { lockr!((*READ_ONLY)).clone() };
+    match set {
+        Some(x) => x,
+        None => config::get(&["dispatch", "read_only"]).unwrap_or(false),
+    }
+}
+
+/// Turn read-only mode on or off
+pub fn set_read_only(yesno: bool) {
+    let mut guard = lockw!((*READ_ONLY));
+    *guard = Some(yesno);
+}
+
+/// Rejects commands that change things while we're in read-only mode
+pub struct ReadOnly;
+
+impl Middleware for ReadOnly {
+    fn handle(&self, ctx: &mut Context, next: Next) -> TResult<Value> {
+        if ctx.policy.mutates && read_only() {
+            let mut denial = PermissionDenial::new(format!("{} is not allowed in read-only mode", ctx.cmd));
+            denial.action = Some(ctx.cmd.clone());
+            denial.hint = Some(String::from("turn off read-only mode (app:read-only:set) to make changes"));
+            return TErr!(TError::PermissionDenied(denial));
+        }
+        next.run(ctx)
+    }
+}
+
+/// The most responses we hold on to for deduplication
+const IDEMPOTENCY_MAX_ENTRIES: usize = 256;
+
+/// Makes retried commands that change things safe: if the UI sends the same
+/// command with the same message id again (say, because it never saw our
+/// response), we send back the original result instead of running the command
+/// twice. Only successful results are remembered, and only for
+/// `dispatch.idempotency_ttl` ms.
+pub struct Idempotency {
+    seen: Mutex<HashMap<(String, String), (Instant, Value)>>,
+}
+
+impl Idempotency {
+    pub fn new() -> Idempotency {
+        Idempotency {
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// How long we remember results for
+    fn ttl() -> Duration {
+        let ms: u64 = config::get(&["dispatch", "idempotency_ttl"]).unwrap_or(60000);
+        Duration::from_millis(ms)
+    }
+}
+
+impl Middleware for Idempotency {
+    fn handle(&self, ctx: &mut Context, next: Next) -> TResult<Value> {
+        if !ctx.policy.mutates {
+            return next.run(ctx);
+        }
+        let ttl = Idempotency::ttl();
+        let key = (ctx.mid.clone(), ctx.cmd.clone());
+        {
+            let mut guard = lock!(self.seen);
+            guard.retain(|_, entry| entry.0.elapsed() < ttl);
+            if let Some(&(_, ref val)) = guard.get(&key) {
+                info!("middleware::Idempotency -- {} ({}) is a retry, sending the original result", ctx.cmd, ctx.mid);
+                return Ok(val.clone());
+            }
+        }
+        let val = next.run(ctx)?;
+        let mut guard = lock!(self.seen);
+        if guard.len() >= IDEMPOTENCY_MAX_ENTRIES {
+            let oldest = guard.iter()
+                .min_by_key(|&(_, entry)| entry.0)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest { guard.remove(&oldest); }
+        }
+        guard.insert(key, (Instant::now(), val.clone()));
+        Ok(val)
+    }
+}
+
+lazy_static! {
+    /// The chain every command runs through, outermost first
+    static ref CHAIN: Vec<Box<Middleware>> = vec![
+        Box::new(Timing),
+        Box::new(Deprecation),
+        Box::new(Auth),
+        Box::new(ReadOnly),
+        Box::new(Idempotency::new()),
+    ];
+}
+
+/// Run a command through our standard middleware chain
+pub fn run(ctx: &mut Context, handler: Handler) -> TResult<Value> {
+    run_chain(&CHAIN, ctx, handler)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ::std::sync::Arc;
+    use ::std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Records the order middleware runs in
+    struct Tag(&'static str, Arc<Mutex<Vec<&'static str>>>);
+
+    impl Middleware for Tag {
+        fn handle(&self, ctx: &mut Context, next: Next) -> TResult<Value> {
+            self.1.lock().unwrap().push(self.0);
+            next.run(ctx)
+        }
+    }
+
+    #[test]
+    fn finds_policies() {
+        assert_eq!(policy("user:login"), OPEN);
+        assert_eq!(policy("user:login-from-saved"), OPEN);
+        assert_eq!(policy("user:join"), WRITE);
+        assert_eq!(policy("sync:status"), AUTH_READ);
+        assert_eq!(policy("sync:delete-item"), AUTH_WRITE);
+        assert_eq!(policy("sync:unfreeze-all"), AUTH_WRITE);
+        assert_eq!(policy("sync:get-frozen"), AUTH_READ);
+        assert_eq!(policy("sync:shutdown"), OPEN);
+        assert_eq!(policy("sync:set-online"), OPEN);
+        assert_eq!(policy("sync:start"), AUTH_WRITE);
+        assert_eq!(policy("sync:pause"), AUTH_WRITE);
+        assert_eq!(policy("sync:set-spaces"), AUTH_WRITE);
+        assert_eq!(policy("sync:set-poll-policy"), AUTH_WRITE);
+        assert_eq!(policy("sync:get-poll-policy"), AUTH_READ);
+        assert_eq!(policy("profile:find-notes"), AUTH_READ);
+        assert_eq!(policy("profile:suggest"), AUTH_READ);
+        assert_eq!(policy("profile:sync:model"), AUTH_WRITE);
+        assert_eq!(policy("profile:sync:changes"), AUTH_READ);
+        assert_eq!(policy("profile:tags:rename"), AUTH_WRITE);
+        assert_eq!(policy("profile:import:enex"), AUTH_WRITE);
+        assert_eq!(policy("profile:stats"), AUTH_READ);
+        assert_eq!(policy("keychain:clean"), AUTH_WRITE);
+        assert_eq!(policy("keychain:verify"), AUTH_READ);
+        assert_eq!(policy("settings:get"), AUTH_READ);
+        assert_eq!(policy("settings:set"), AUTH_WRITE);
+        assert_eq!(policy("profile:saved-search:run"), AUTH_READ);
+        assert_eq!(policy("profile:saved-search:create"), AUTH_WRITE);
+        assert_eq!(policy("user:change-email"), AUTH_WRITE);
+        assert_eq!(policy("user:avatar:get"), AUTH_READ);
+        assert_eq!(policy("user:avatar:set"), AUTH_WRITE);
+        assert_eq!(policy("profile:note:get-thumbnail"), AUTH_READ);
+        assert_eq!(policy("notes:batch"), AUTH_WRITE);
+        assert_eq!(policy("board:reparent"), AUTH_WRITE);
+        assert_eq!(policy("space:export-keys"), AUTH_READ);
+        assert_eq!(policy("space:rotate-key"), AUTH_WRITE);
+        assert_eq!(policy("trash:list"), AUTH_READ);
+        assert_eq!(policy("trash:empty"), AUTH_WRITE);
+        assert_eq!(policy("ping"), OPEN);
+        assert_eq!(policy("i:dont:exist"), OPEN);
+    }
+
+    #[test]
+    fn runs_in_order() {
+        let turtl = ::turtl::tests::with_test(false);
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let chain: Vec<Box<Middleware>> = vec![
+            Box::new(Tag("outer", order.clone())),
+            Box::new(Tag("inner", order.clone())),
+        ];
+        let mut ctx = Context::new(&turtl, String::from("1"), String::from("ping"), json!(["1", "ping"]));
+        let order2 = order.clone();
+        let res = run_chain(&chain, &mut ctx, &|ctx: &mut Context| {
+            order2.lock().unwrap().push("handler");
+            Ok(ctx.take_data())
+        }).unwrap();
+        assert_eq!(res, json!(["1", "ping"]));
+        assert_eq!(*order.lock().unwrap(), vec!["outer", "inner", "handler"]);
+    }
+
+    #[test]
+    fn guards_auth_and_read_only() {
+        let turtl = ::turtl::tests::with_test(false);
+        let chain: Vec<Box<Middleware>> = vec![Box::new(Auth), Box::new(ReadOnly)];
+        let mut ctx = Context::new(&turtl, String::from("1"), String::from("profile:load"), json!(["1", "profile:load"]));
+        match run_chain(&chain, &mut ctx, &|_| Ok(json!({}))) {
+            Err(TError::PermissionDenied(denial)) => assert_eq!(denial.action, Some(String::from("profile:load"))),
+            _ => panic!("expected a permission denial"),
+        }
+
+        let mut ctx = Context::new(&turtl, String::from("2"), String::from("app:wipe-app-data"), json!(["2", "app:wipe-app-data"]));
+        set_read_only(true);
+        assert!(run_chain(&chain, &mut ctx, &|_| Ok(json!({}))).is_err());
+        set_read_only(false);
+        assert!(run_chain(&chain, &mut ctx, &|_| Ok(json!({}))).is_ok());
+    }
+
+    #[test]
+    fn dedupes_retries() {
+        let turtl = ::turtl::tests::with_test(false);
+        let chain: Vec<Box<Middleware>> = vec![Box::new(Idempotency::new())];
+        let runs = AtomicUsize::new(0);
+        let handler = |_: &mut Context| -> TResult<Value> {
+            Ok(json!(runs.fetch_add(1, Ordering::SeqCst)))
+        };
+        let mut ctx = Context::new(&turtl, String::from("1"), String::from("user:join"), json!(["1", "user:join"]));
+        assert_eq!(run_chain(&chain, &mut ctx, &handler).unwrap(), json!(0));
+        assert_eq!(run_chain(&chain, &mut ctx, &handler).unwrap(), json!(0));
+        // new mid, new run
+        let mut ctx = Context::new(&turtl, String::from("2"), String::from("user:join"), json!(["2", "user:join"]));
+        assert_eq!(run_chain(&chain, &mut ctx, &handler).unwrap(), json!(1));
+        // reads are never cached
+        let mut ctx = Context::new(&turtl, String::from("3"), String::from("ping"), json!(["3", "ping"]));
+        assert_eq!(run_chain(&chain, &mut ctx, &handler).unwrap(), json!(2));
+        assert_eq!(run_chain(&chain, &mut ctx, &handler).unwrap(), json!(3));
+    }
+}
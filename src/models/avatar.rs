@@ -0,0 +1,185 @@
+//! The user's avatar. The image goes up to the API as its own file (like note
+//! attachments do), encrypted with a key of its own. The key and the hash of
+//! the encrypted image live in the user object's `avatar` field, so the avatar
+//! follows the user to their other devices and survives a password change
+//! (which re-encrypts the user object, but not the image).
+//!
+//! We keep the encrypted image in the files folder (`u_<user>.avatar`) and
+//! only go back to the API for it when the hash in the user object no longer
+//! matches what we have.
+
+use ::std::fs;
+use ::std::io::{Read, Write};
+use ::std::path::PathBuf;
+use ::std::time::Duration;
+use ::hyper;
+use ::jedi::Value;
+use ::config;
+use ::crypto::{self, Key};
+use ::error::{TResult, TError};
+use ::api::{ApiReq, Headers, Method};
+use ::models::model::Model;
+use ::models::protected::Protected;
+use ::models::file::{self, FileData};
+use ::models::sync_record::SyncAction;
+use ::sync::sync_model;
+use ::turtl::Turtl;
+use ::util;
+
+/// What the user object knows about its avatar
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Avatar {
+    /// A hash (sha256, hex) of the encrypted image
+    pub hash: String,
+    /// The key (base64) the image is encrypted with
+    pub key: String,
+    /// The image's mime type
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub ty: Option<String>,
+}
+
+/// How big (in bytes) an avatar can be
+fn max_size() -> usize {
+    config::get(&["files", "avatar", "max_size"]).unwrap_or(1048576)
+}
+
+/// Where we keep a user's (encrypted) avatar
+pub fn path(user_id: &String) -> TResult<PathBuf> {
+    let mut path = PathBuf::from(file::file_folder()?);
+    path.push(format!("u_{}.avatar", user_id));
+    Ok(path)
+}
+
+/// Grab the current user's id and avatar info
+fn current(turtl: &Turtl) -> TResult<(String, Option<Avatar>)> {
+    let user_guard = lockr!(turtl.user);
+    Ok((user_guard.id_or_else()?, user_guard.avatar.clone()))
+}
+
+/// Point the user object at a new avatar (or none) and save it
+fn save_user(turtl: &Turtl, avatar: Option<Avatar>) -> TResult<()> {
+    let mut save_user = {
+        let mut user_guard = lockw!(turtl.user);
+        // set it into turtl.user as a stopgap until the sync goes through
+        user_guard.avatar = avatar.clone();
+        user_guard.clone()?
+    };
+    save_user.avatar = avatar;
+    sync_model::save_model(SyncAction::Edit, turtl, &mut save_user, false)?;
+    Ok(())
+}
+
+/// Encrypt and upload a new avatar for the current user, replacing whatever
+/// they had before. Returns the new avatar info.
+pub fn set(turtl: &Turtl, data: Vec<u8>, ty: Option<String>) -> TResult<Avatar> {
+    if data.len() == 0 {
+        return TErr!(TError::MissingData(String::from("avatar is empty")));
+    }
+    if data.len() > max_size() {
+        return TErr!(TError::BadValue(format!("avatar is too big ({} bytes, the most is {})", data.len(), max_size())));
+    }
+    turtl.assert_connected()?;
+    let (user_id, _) = current(turtl)?;
+    let key = Key::random()?;
+    let key_enc = key.clone();
+    let enc = turtl.work.run(move || {
+        crypto::encrypt(&key_enc, data, crypto::CryptoOp::new("chacha20poly1305")?)
+            .map_err(|e| From::from(e))
+    })?;
+    let hash = FileData::hash_data(enc.as_slice())?;
+
+    let url = format!("/users/{}/avatar", user_id);
+    let req = ApiReq::new().header("Content-Type", &String::from("application/octet-stream")).timeout(60);
+    let (mut stream, info) = turtl.api.call_start(Method::Put, &url[..], req)?;
+    stream.write_all(enc.as_slice())?;
+    stream.flush()?;
+    let _res: Value = turtl.api.call_end(stream.send(), info)?;
+
+    util::create_dir(file::file_folder()?)?;
+    let mut fs_file = fs::File::create(path(&user_id)?)?;
+    fs_file.write_all(enc.as_slice())?;
+
+    let avatar = Avatar {
+        hash: hash,
+        key: crypto::to_base64(key.data())?,
+        ty: ty,
+    };
+    save_user(turtl, Some(avatar.clone()))?;
+    Ok(avatar)
+}
+
+/// Remove the current user's avatar
+pub fn clear(turtl: &Turtl) -> TResult<()> {
+    turtl.assert_connected()?;
+    let (user_id, avatar) = current(turtl)?;
+    if avatar.is_none() { return Ok(()); }
+    turtl.api.delete::<Value>(format!("/users/{}/avatar", user_id).as_str(), ApiReq::new())?;
+    let cached = path(&user_id)?;
+    if cached.exists() {
+        fs::remove_file(&cached)?;
+    }
+    save_user(turtl, None)
+}
+
+/// Download a user's (encrypted) avatar into our files folder
+fn download(turtl: &Turtl, user_id: &String, to: &PathBuf) -> TResult<()> {
+    info!("avatar::download() -- grabbing avatar for {}", user_id);
+    let url = format!("/users/{}/avatar", user_id);
+    // grab the location of the image
+    let file_url: String = turtl.api.get(&url[..], ApiReq::new())?;
+    let mut headers = Headers::new();
+    let turtl_api_url: String = config::get(&["api", "endpoint"])?;
+    // only add our auth junk if we're calling back to the turtl api!
+    if file_url.contains(turtl_api_url.as_str()) {
+        turtl.api.set_auth_headers(&mut headers);
+    }
+    let mut client = hyper::Client::new();
+    client.set_read_timeout(Some(Duration::new(30, 0)));
+    let mut res = client
+        .request(Method::Get, &file_url[..])
+        .headers(headers)
+        .send()?;
+    let mut enc = Vec::new();
+    res.read_to_end(&mut enc)?;
+    if !res.status.is_success() {
+        let errstr = String::from_utf8_lossy(enc.as_slice()).into_owned();
+        return TErr!(TError::Api(res.status.clone(), Value::String(errstr)));
+    }
+    util::create_dir(file::file_folder()?)?;
+    let mut fs_file = fs::File::create(to)?;
+    fs_file.write_all(enc.as_slice())?;
+    Ok(())
+}
+
+/// Grab the current user's avatar (decrypted), downloading it first if we
+/// don't have the one the user object points to. Returns None if the user
+/// doesn't have an avatar.
+pub fn get(turtl: &Turtl) -> TResult<Option<Vec<u8>>> {
+    let (user_id, avatar) = current(turtl)?;
+    let avatar = match avatar {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let cached = path(&user_id)?;
+    let fresh = cached.exists() && FileData::verify_file(&cached, &avatar.hash).is_ok();
+    if !fresh {
+        turtl.assert_connected()?;
+        download(turtl, &user_id, &cached)?;
+        if let Err(e) = FileData::verify_file(&cached, &avatar.hash) {
+            fs::remove_file(&cached)?;
+            return Err(e);
+        }
+    }
+    let enc = {
+        let mut file = fs::File::open(&cached)?;
+        let mut enc = Vec::new();
+        file.read_to_end(&mut enc)?;
+        enc
+    };
+    let key = Key::new(crypto::from_base64(&avatar.key)?);
+    let data = turtl.work.run(move || {
+        crypto::decrypt(&key, enc)
+            .map_err(|e| From::from(e))
+    })?;
+    Ok(Some(data))
+}
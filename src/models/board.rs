@@ -1,13 +1,16 @@
 use ::jedi::Value;
 
-use ::error::TResult;
+use ::error::{TResult, TError};
 use ::crypto::Key;
 use ::models::model::Model;
-use ::models::validate::{self, Validate};
+use ::models::validate::Validate;
 use ::models::protected::{Keyfinder, Protected};
 use ::models::note::Note;
+use ::models::space::Space;
 use ::models::keychain::{Keychain, KeyRef, KeyType};
+use ::models::ordering;
 use ::models::sync_record::{SyncRecord, SyncAction};
+use ::lib_permissions::Permission;
 use ::turtl::Turtl;
 use ::sync::sync_model::{self, SyncModel, MemorySaver};
 use ::models::storable::Storable;
@@ -19,13 +22,26 @@ protected! {
         #[protected_field(public)]
         pub user_id: String,
         #[protected_field(public)]
+        #[protected_validate(required = "Please add a space id to this board")]
         pub space_id: String,
+        /// The board this board lives under, if any. A board whose parent is
+        /// gone is treated as top-level.
+        #[protected_field(public)]
+        pub parent_id: Option<String>,
         #[serde(skip_serializing_if = "Option::is_none")]
         #[protected_field(public)]
         pub meta: Option<Value>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(public)]
+        pub trashed: Option<i64>,
+        /// Where the board goes among its siblings (see `models::ordering`)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(public)]
+        pub position: Option<f64>,
 
         #[serde(skip_serializing_if = "Option::is_none")]
         #[protected_field(private)]
+        #[protected_validate(required = "Please give your board a title", max_len = 256)]
         pub title: Option<String>,
     }
 }
@@ -33,23 +49,128 @@ protected! {
 make_storable!(Board, "boards");
 impl SyncModel for Board {}
 
-impl Validate for Board {
-    fn validate(&self) -> Vec<(String, String)> {
-        let mut errors = Vec::new();
-        if self.space_id == "" {
-            errors.push(validate::entry("space_id", t!("Please add a space id to this board")));
-        }
-        if self.title.as_ref().map(|x| x == "").unwrap_or(true) {
-            errors.push(validate::entry("title", t!("Please give your board a title")));
-        }
-        errors
+impl Validate for Board {}
+
+/// Whether making `parent_id` the parent of `board_id` would loop back around
+/// to `board_id` (or run into a loop that's already there)
+pub fn creates_cycle(boards: &Vec<Board>, board_id: &String, parent_id: &String) -> bool {
+    let mut seen: Vec<String> = Vec::new();
+    let mut cur = Some(parent_id.clone());
+    while let Some(id) = cur {
+        if &id == board_id || seen.contains(&id) { return true; }
+        cur = boards.iter()
+            .find(|x| x.id() == Some(&id))
+            .and_then(|x| x.parent_id.clone());
+        seen.push(id);
+    }
+    false
+}
+
+/// Grab the ids of a board's parent, its parent's parent, etc (closest first)
+pub fn ancestors(boards: &Vec<Board>, board_id: &String) -> Vec<String> {
+    let mut ids: Vec<String> = Vec::new();
+    let mut cur = boards.iter()
+        .find(|x| x.id() == Some(board_id))
+        .and_then(|x| x.parent_id.clone());
+    while let Some(id) = cur {
+        if &id == board_id || ids.contains(&id) { break; }
+        cur = boards.iter()
+            .find(|x| x.id() == Some(&id))
+            .and_then(|x| x.parent_id.clone());
+        ids.push(id);
     }
+    ids
 }
 
 impl Board {
-    /// Move a note to a different space
+    /// Make sure this board's parent (if it has one) is a board in the same
+    /// space that doesn't have this board somewhere above it
+    pub fn check_parent(&self, turtl: &Turtl) -> TResult<()> {
+        let parent_id = match self.parent_id.as_ref() {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let profile_guard = lockr!(turtl.profile);
+        let parent = match profile_guard.boards.iter().find(|x| x.id() == Some(parent_id)) {
+            Some(x) => x,
+            None => return TErr!(TError::NotFound(format!("parent board {} wasn't found", parent_id))),
+        };
+        if parent.space_id != self.space_id {
+            return TErr!(TError::BadValue(format!("parent board {} is in a different space", parent_id)));
+        }
+        if let Some(board_id) = self.id() {
+            if creates_cycle(&profile_guard.boards, board_id, parent_id) {
+                return TErr!(TError::BadValue(format!("board {} can't go under its own child board {}", board_id, parent_id)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Move a board under another board in its space, or to the top level (if
+    /// `parent_id` is None). Returns the saved board's data.
+    pub fn reparent(turtl: &Turtl, board_id: &String, parent_id: Option<String>) -> TResult<Value> {
+        let mut board = {
+            let profile_guard = lockr!(turtl.profile);
+            match profile_guard.boards.iter().find(|x| x.id() == Some(board_id)) {
+                Some(x) => x.clone()?,
+                None => return TErr!(TError::NotFound(format!("board {} wasn't found", board_id))),
+            }
+        };
+        Space::permission_check(turtl, &board.space_id, &Permission::EditBoard)?;
+        board.parent_id = parent_id;
+        board.check_parent(turtl)?;
+        sync_model::save_model(SyncAction::Edit, turtl, &mut board, false)
+    }
+
+    /// Move a board to just after `after_id` among the boards that share its
+    /// parent (or to the top, if None). Returns the saved boards' data.
+    pub fn reorder(turtl: &Turtl, board_id: &String, after_id: Option<String>) -> TResult<Vec<Value>> {
+        let (space_id, siblings) = {
+            let profile_guard = lockr!(turtl.profile);
+            let board = match profile_guard.boards.iter().find(|x| x.id() == Some(board_id)) {
+                Some(x) => x,
+                None => return TErr!(TError::NotFound(format!("board {} wasn't found", board_id))),
+            };
+            let siblings = profile_guard.boards.iter()
+                .filter(|x| x.space_id == board.space_id && x.parent_id == board.parent_id && x.trashed.is_none())
+                .filter_map(|x| x.id().map(|id| (id.clone(), x.position)))
+                .collect::<Vec<_>>();
+            (board.space_id.clone(), siblings)
+        };
+        Space::permission_check(turtl, &space_id, &Permission::EditBoard)?;
+        let changes = ordering::place(&siblings, board_id, after_id.as_ref())?;
+        let mut saved = Vec::with_capacity(changes.len());
+        for (id, position) in changes {
+            let board = {
+                let profile_guard = lockr!(turtl.profile);
+                match profile_guard.boards.iter().find(|x| x.id() == Some(&id)) {
+                    Some(x) => Some(x.clone()?),
+                    None => None,
+                }
+            };
+            let mut board = match board {
+                Some(x) => x,
+                None => continue,
+            };
+            board.position = Some(position);
+            saved.push(sync_model::save_model(SyncAction::Edit, turtl, &mut board, false)?);
+        }
+        Ok(saved)
+    }
+
+    /// Move a board (along with its notes and child boards) to a different
+    /// space. It ends up at the top level of the new space.
     pub fn move_spaces(&mut self, turtl: &Turtl, new_space_id: String) -> TResult<()> {
+        self.parent_id = None;
+        let mut moved: Vec<String> = Vec::new();
+        self.move_tree(turtl, new_space_id, &mut moved)
+    }
+
+    /// Move this board, its notes, and its child boards to a different space.
+    /// `moved` keeps us from going in circles.
+    fn move_tree(&mut self, turtl: &Turtl, new_space_id: String, moved: &mut Vec<String>) -> TResult<()> {
         let board_id = self.id_or_else()?;
+        moved.push(board_id.clone());
         self.space_id = new_space_id.clone();
         sync_model::save_model(SyncAction::MoveSpace, turtl, self, false)?;
 
@@ -70,6 +191,20 @@ impl Board {
             note.move_spaces(turtl, new_space_id.clone())?;
         }
 
+        let children = {
+            let profile_guard = lockr!(turtl.profile);
+            let mut children = Vec::new();
+            for board in &profile_guard.boards {
+                if board.parent_id.as_ref() != Some(&board_id) { continue; }
+                if board.id().map(|x| moved.contains(x)).unwrap_or(true) { continue; }
+                children.push(board.clone()?);
+            }
+            children
+        };
+        for mut child in children {
+            child.move_tree(turtl, new_space_id.clone(), moved)?;
+        }
+
         Ok(())
     }
 
@@ -112,6 +247,18 @@ impl Keyfinder for Board {
                 keychain.upsert_key(turtl, space_id, space.key().expect("turtl::Board.get_key_search() -- space key is None"), &ty)?;
             }
         }
+
+        // a child board can also be opened with its parent's key
+        if let Some(parent_id) = self.parent_id.as_ref() {
+            let ty = String::from("board");
+            let profile_guard = lockr!(turtl.profile);
+            for board in &profile_guard.boards {
+                if board.id() != Some(parent_id) { continue; }
+                if let Some(key) = board.key() {
+                    keychain.upsert_key(turtl, parent_id, key, &ty)?;
+                }
+            }
+        }
         Ok(keychain)
     }
 
@@ -127,6 +274,17 @@ impl Keyfinder for Board {
                 });
             }
         }
+        if let Some(parent_id) = self.parent_id.as_ref() {
+            for board in &profile_guard.boards {
+                if board.id() == Some(parent_id) && board.key().is_some() {
+                    refs.push(KeyRef {
+                        id: parent_id.clone(),
+                        ty: KeyType::Board,
+                        k: board.key().expect("turtl::Board.get_keyrefs() -- parent key is None").clone(),
+                    });
+                }
+            }
+        }
         Ok(refs)
     }
 }
@@ -149,7 +307,6 @@ impl MemorySaver for Board {
                 profile_guard.boards.push(self);
             }
             SyncAction::Delete => {
-                let mut profile_guard = lockw!(turtl.profile);
                 let board_id = self.id().expect("turtl::Board.mem_update() -- delete -- self.id() is None. HOW CAN I DELETE IT IF ITS NONE?!!");
 
                 let notes: Vec<Note> = {
@@ -169,7 +326,10 @@ impl MemorySaver for Board {
                     };
                     sync_model::delete_model::<Note>(turtl, &note_id, true)?;
                 }
-                // remove the board from memory
+                // remove the board from memory. we wait until the notes are
+                // gone to grab the profile, since deleting them drops them
+                // from the profile's note cache
+                let mut profile_guard = lockw!(turtl.profile);
                 profile_guard.boards.retain(|b| b.id() != Some(&board_id));
             }
             _ => {}
@@ -178,3 +338,26 @@ impl MemorySaver for Board {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::jedi;
+
+    #[test]
+    fn finds_cycles() {
+        let boards: Vec<Board> = jedi::parse(&String::from(r#"[
+            {"id":"a","user_id":1,"space_id":"s1","parent_id":null},
+            {"id":"b","user_id":1,"space_id":"s1","parent_id":"a"},
+            {"id":"c","user_id":1,"space_id":"s1","parent_id":"b"},
+            {"id":"d","user_id":1,"space_id":"s1","parent_id":"gone"}
+        ]"#)).unwrap();
+        assert_eq!(ancestors(&boards, &String::from("c")), vec![String::from("b"), String::from("a")]);
+        assert_eq!(ancestors(&boards, &String::from("a")), Vec::<String>::new());
+        assert_eq!(ancestors(&boards, &String::from("d")), vec![String::from("gone")]);
+
+        assert!(creates_cycle(&boards, &String::from("a"), &String::from("c")));
+        assert!(creates_cycle(&boards, &String::from("a"), &String::from("a")));
+        assert!(!creates_cycle(&boards, &String::from("c"), &String::from("a")));
+        assert!(!creates_cycle(&boards, &String::from("d"), &String::from("c")));
+    }
+}
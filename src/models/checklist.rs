@@ -0,0 +1,198 @@
+//! Checklist notes. Instead of keeping a list in the note's text (where two
+//! people checking off different items would collide as edits to the same
+//! blob), a checklist note (type `checklist`) keeps its items in the note's
+//! `items` field. Each item has its own id, so the item commands here only
+//! touch the item they're about, and conflicting edits get merged item by item
+//! (see `merge()` and `sync::conflict`).
+//!
+//! Items are ordered by `position`. Positions are floats so an item can be
+//! moved between two others without renumbering the whole list.
+
+use ::std::cmp::Ordering;
+use ::jedi::Value;
+use ::time;
+use ::error::{TResult, TError};
+use ::models::model;
+use ::models::note::Note;
+use ::models::space::Space;
+use ::models::sync_record::SyncAction;
+use ::lib_permissions::Permission;
+use ::sync::sync_model;
+use ::turtl::Turtl;
+
+/// One item in a checklist
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChecklistItem {
+    pub id: String,
+    pub text: String,
+    #[serde(default)]
+    pub checked: bool,
+    #[serde(default)]
+    pub position: f64,
+}
+
+/// Put items in order
+pub fn sort(items: &mut Vec<ChecklistItem>) {
+    items.sort_by(|a, b| {
+        a.position.partial_cmp(&b.position)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| a.id.cmp(&b.id))
+    });
+}
+
+/// Three-way merge of a checklist's items, by item id. An item deleted on
+/// either side stays deleted, new items from both sides are kept, and for items
+/// both sides have, each field takes whichever side changed it (their change
+/// wins if both did).
+pub fn merge(base: &Vec<ChecklistItem>, ours: &Vec<ChecklistItem>, theirs: &Vec<ChecklistItem>) -> Vec<ChecklistItem> {
+    fn find<'a>(items: &'a Vec<ChecklistItem>, id: &String) -> Option<&'a ChecklistItem> {
+        items.iter().find(|x| &x.id == id)
+    }
+    let mut merged: Vec<ChecklistItem> = Vec::new();
+    for item in theirs {
+        let ours_item = find(ours, &item.id);
+        let base_item = match find(base, &item.id) {
+            Some(x) => x,
+            // new on their side
+            None => {
+                merged.push(item.clone());
+                continue;
+            }
+        };
+        let ours_item = match ours_item {
+            Some(x) => x,
+            // we deleted it
+            None => continue,
+        };
+        let mut item = item.clone();
+        if item.text == base_item.text { item.text = ours_item.text.clone(); }
+        if item.checked == base_item.checked { item.checked = ours_item.checked; }
+        if item.position == base_item.position { item.position = ours_item.position; }
+        merged.push(item);
+    }
+    // new on our side
+    for item in ours {
+        if find(base, &item.id).is_none() && find(theirs, &item.id).is_none() {
+            merged.push(item.clone());
+        }
+    }
+    sort(&mut merged);
+    merged
+}
+
+/// Load a checklist note for editing
+fn load(turtl: &Turtl, note_id: &String) -> TResult<Note> {
+    let mut notes = turtl.load_notes(&vec![note_id.clone()])?;
+    if notes.len() == 0 {
+        return TErr!(TError::NotFound(format!("note {} wasn't found", note_id)));
+    }
+    let note = notes.remove(0);
+    Space::permission_check(turtl, &note.space_id, &Permission::EditNote)?;
+    if note.type_.as_ref().map(|x| x.as_str()) != Some("checklist") {
+        return TErr!(TError::BadValue(format!("note {} isn't a checklist", note_id)));
+    }
+    Ok(note)
+}
+
+/// Save a checklist note after changing its items
+fn save(turtl: &Turtl, note: &mut Note, mut items: Vec<ChecklistItem>) -> TResult<Value> {
+    sort(&mut items);
+    note.items = Some(items);
+    note.mod_ = Some(time::get_time().sec as i64);
+    sync_model::save_model(SyncAction::Edit, turtl, note, false)
+}
+
+/// Add an item to a checklist, at the given position or the end of the list
+pub fn add(turtl: &Turtl, note_id: &String, text: String, position: Option<f64>) -> TResult<Value> {
+    let mut note = load(turtl, note_id)?;
+    let mut items = note.items.take().unwrap_or(Vec::new());
+    let position = match position {
+        Some(x) => x,
+        None => items.iter().map(|x| x.position).fold(0.0, f64::max) + 1.0,
+    };
+    items.push(ChecklistItem {
+        id: model::cid()?,
+        text: text,
+        checked: false,
+        position: position,
+    });
+    save(turtl, &mut note, items)
+}
+
+/// Check (or uncheck) an item. If `checked` isn't given, the item flips.
+pub fn toggle(turtl: &Turtl, note_id: &String, item_id: &String, checked: Option<bool>) -> TResult<Value> {
+    let mut note = load(turtl, note_id)?;
+    let mut items = note.items.take().unwrap_or(Vec::new());
+    {
+        let item = match items.iter_mut().find(|x| &x.id == item_id) {
+            Some(x) => x,
+            None => return TErr!(TError::NotFound(format!("item {} isn't in note {}", item_id, note_id))),
+        };
+        item.checked = checked.unwrap_or(!item.checked);
+    }
+    save(turtl, &mut note, items)
+}
+
+/// Put a checklist's items in the given order. Items not in `item_ids` keep
+/// their place after the ones that are.
+pub fn reorder(turtl: &Turtl, note_id: &String, item_ids: &Vec<String>) -> TResult<Value> {
+    let mut note = load(turtl, note_id)?;
+    let mut items = note.items.take().unwrap_or(Vec::new());
+    sort(&mut items);
+    let mut next = 1.0;
+    for item_id in item_ids {
+        if let Some(item) = items.iter_mut().find(|x| &x.id == item_id) {
+            item.position = next;
+            next += 1.0;
+        }
+    }
+    for item in items.iter_mut() {
+        if !item_ids.contains(&item.id) {
+            item.position = next;
+            next += 1.0;
+        }
+    }
+    save(turtl, &mut note, items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(id: &str, text: &str, checked: bool, position: f64) -> ChecklistItem {
+        ChecklistItem {
+            id: String::from(id),
+            text: String::from(text),
+            checked: checked,
+            position: position,
+        }
+    }
+
+    #[test]
+    fn merges_items() {
+        let base = vec![
+            item("a", "eggs", false, 1.0),
+            item("b", "milk", false, 2.0),
+            item("c", "bread", false, 3.0),
+        ];
+        // we check off eggs, delete bread, and add butter
+        let ours = vec![
+            item("a", "eggs", true, 1.0),
+            item("b", "milk", false, 2.0),
+            item("d", "butter", false, 4.0),
+        ];
+        // they rename milk, move eggs to the end, and add jam
+        let theirs = vec![
+            item("b", "oat milk", false, 2.0),
+            item("c", "bread", false, 3.0),
+            item("a", "eggs", false, 5.0),
+            item("e", "jam", false, 2.5),
+        ];
+        assert_eq!(merge(&base, &ours, &theirs), vec![
+            item("b", "oat milk", false, 2.0),
+            item("e", "jam", false, 2.5),
+            item("d", "butter", false, 4.0),
+            item("a", "eggs", true, 5.0),
+        ]);
+    }
+}
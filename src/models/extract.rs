@@ -0,0 +1,208 @@
+//! Pulls the text out of PDF and office (Word/Excel/PowerPoint and
+//! OpenDocument) attachments so searching finds what's inside them, not just
+//! their names.
+//!
+//! Extraction runs once a note's file finishes syncing (uploading or
+//! downloading). The text is kept on the note's file info (`File.text`), which
+//! is private like the rest of it, so it's encrypted, syncs to the user's other
+//! devices, and goes away when the file is replaced. The search index picks it
+//! up from there.
+//!
+//! Extracting needs the `extract` feature (which pulls in the `pdf-extract` and
+//! `zip` crates). Without it, attachments are searchable by name only.
+
+use ::config;
+use ::error::{TResult, TError};
+use ::models::model::Model;
+use ::models::note::Note;
+use ::models::file::FileData;
+use ::models::sync_record::SyncAction;
+use ::sync::sync_model;
+use ::turtl::Turtl;
+#[cfg(feature = "extract")]
+use ::std::io::{Cursor, Read};
+#[cfg(feature = "extract")]
+use ::regex::Regex;
+#[cfg(feature = "extract")]
+use ::pdf_extract;
+#[cfg(feature = "extract")]
+use ::zip;
+
+/// The kinds of documents we know how to read
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DocType {
+    Pdf,
+    /// Office Open XML (docx/xlsx/pptx)
+    Ooxml,
+    /// OpenDocument (odt/ods/odp)
+    Odf,
+}
+
+/// How much text (in characters) we keep from a document
+fn max_chars() -> usize {
+    config::get(&["files", "extract", "max_chars"]).unwrap_or(20000)
+}
+
+/// Figure out what kind of document a file is from its mime type, falling back
+/// on its extension
+pub fn doc_type(ty: Option<&String>, name: Option<&String>) -> Option<DocType> {
+    if let Some(ty) = ty {
+        match ty.as_str() {
+            "application/pdf" => return Some(DocType::Pdf),
+            x if x.starts_with("application/vnd.openxmlformats-officedocument.") => return Some(DocType::Ooxml),
+            x if x.starts_with("application/vnd.oasis.opendocument.") => return Some(DocType::Odf),
+            _ => {}
+        }
+    }
+    let ext = match name.and_then(|x| x.rsplit('.').next()) {
+        Some(x) => x.to_lowercase(),
+        None => return None,
+    };
+    match ext.as_str() {
+        "pdf" => Some(DocType::Pdf),
+        "docx" | "xlsx" | "pptx" => Some(DocType::Ooxml),
+        "odt" | "ods" | "odp" => Some(DocType::Odf),
+        _ => None,
+    }
+}
+
+/// Whether a note's file is something we'd extract text from (and hasn't had
+/// it done yet)
+pub fn wanted(note: &Note) -> bool {
+    if !cfg!(feature = "extract") || !note.has_file { return false; }
+    match note.file.as_ref() {
+        Some(file) => file.text.is_none() && doc_type(file.ty.as_ref(), file.name.as_ref()).is_some(),
+        None => false,
+    }
+}
+
+/// Trim extracted text down: collapse whitespace and cut it off at
+/// `max_chars` characters
+pub fn tidy(text: &str, max: usize) -> String {
+    let mut out = String::with_capacity(text.len().min(max * 4));
+    let mut count = 0;
+    for word in text.split_whitespace() {
+        if count > 0 {
+            if count >= max { break; }
+            out.push(' ');
+            count += 1;
+        }
+        for c in word.chars() {
+            if count >= max { break; }
+            out.push(c);
+            count += 1;
+        }
+    }
+    out
+}
+
+/// Pull the text out of an office document's XML
+#[cfg(feature = "extract")]
+fn xml_text(xml: &str) -> String {
+    lazy_static! {
+        static ref BREAKS: Regex = Regex::new(r"</(w:p|a:p|text:p|text:h|si|c)>").unwrap();
+        static ref TAGS: Regex = Regex::new(r"<[^>]*>").unwrap();
+    }
+    let text = BREAKS.replace_all(xml, " ");
+    let text = TAGS.replace_all(&text, "");
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Read the text out of the XML parts of a zipped office document
+#[cfg(feature = "extract")]
+fn zip_text(data: &[u8], doc_type: DocType) -> TResult<String> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(data))
+        .map_err(|e| TError::BadValue(format!("couldn't open document: {}", e)))?;
+    let mut parts = Vec::new();
+    for i in 0..archive.len() {
+        let name = match archive.by_index(i) {
+            Ok(x) => String::from(x.name()),
+            Err(_) => continue,
+        };
+        let wanted = match doc_type {
+            DocType::Ooxml => {
+                name == "word/document.xml" ||
+                    name == "xl/sharedStrings.xml" ||
+                    (name.starts_with("ppt/slides/slide") && name.ends_with(".xml"))
+            }
+            DocType::Odf => name == "content.xml",
+            DocType::Pdf => false,
+        };
+        if wanted { parts.push(name); }
+    }
+    // slides come out of the zip in whatever order, so put them back in order
+    parts.sort();
+    let mut text = Vec::new();
+    for name in parts {
+        let mut xml = String::new();
+        archive.by_name(&name)
+            .map_err(|e| TError::BadValue(format!("couldn't read {}: {}", name, e)))?
+            .read_to_string(&mut xml)?;
+        text.push(xml_text(&xml));
+    }
+    Ok(text.join(" "))
+}
+
+/// Pull the text out of a (decrypted) document
+#[cfg(feature = "extract")]
+pub fn text_from(doc_type: DocType, data: &[u8]) -> TResult<String> {
+    let text = match doc_type {
+        DocType::Pdf => {
+            pdf_extract::extract_text_from_mem(data)
+                .map_err(|e| TError::BadValue(format!("couldn't read PDF: {:?}", e)))?
+        }
+        DocType::Ooxml | DocType::Odf => zip_text(data, doc_type)?,
+    };
+    Ok(tidy(&text, max_chars()))
+}
+
+#[cfg(not(feature = "extract"))]
+pub fn text_from(_doc_type: DocType, _data: &[u8]) -> TResult<String> {
+    TErr!(TError::NotImplemented)
+}
+
+/// Extract the text from a note's file (if it's a document we can read and we
+/// haven't already) and save it to the note. Returns whether we did.
+pub fn run(turtl: &Turtl, note_id: &String) -> TResult<bool> {
+    let mut notes = turtl.load_notes(&vec![note_id.clone()])?;
+    if notes.len() == 0 { return Ok(false); }
+    let mut note = notes.remove(0);
+    if !wanted(&note) { return Ok(false); }
+    let doc_type = match note.file.as_ref().and_then(|x| doc_type(x.ty.as_ref(), x.name.as_ref())) {
+        Some(x) => x,
+        None => return Ok(false),
+    };
+    let data = FileData::load_file(turtl, &note)?;
+    let text = turtl.work.run(move || text_from(doc_type, data.as_slice()))?;
+    info!("extract::run() -- pulled {} characters of text from the file for note {}", text.chars().count(), note.id_or_else()?);
+    if let Some(file) = note.file.as_mut() {
+        file.text = Some(text);
+    }
+    sync_model::save_model(SyncAction::Edit, turtl, &mut note, false)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_doc_types() {
+        assert_eq!(doc_type(Some(&String::from("application/pdf")), None), Some(DocType::Pdf));
+        assert_eq!(doc_type(Some(&String::from("application/vnd.openxmlformats-officedocument.wordprocessingml.document")), None), Some(DocType::Ooxml));
+        assert_eq!(doc_type(Some(&String::from("application/octet-stream")), Some(&String::from("Budget.ODS"))), Some(DocType::Odf));
+        assert_eq!(doc_type(Some(&String::from("image/png")), Some(&String::from("cat.png"))), None);
+        assert_eq!(doc_type(None, None), None);
+    }
+
+    #[test]
+    fn tidies_text() {
+        assert_eq!(tidy("  hello\n\n there\tworld ", 100), "hello there world");
+        assert_eq!(tidy("hello there world", 8), "hello th");
+        assert_eq!(tidy("\u{2620}\u{2620} \u{2620}", 4), "\u{2620}\u{2620} \u{2620}");
+    }
+}
@@ -4,12 +4,14 @@ use ::storage::Storage;
 use ::models::model::Model;
 use ::models::protected::{Keyfinder, Protected};
 use ::models::note::Note;
+use ::models::thumbnail;
 use ::models::sync_record::{SyncAction, SyncType, SyncRecord};
 use ::models::validate::Validate;
 use ::sync::sync_model::{self, SyncModel, MemorySaver};
 use ::turtl::Turtl;
 use ::std::mem;
-use ::crypto;
+use ::crypto::{self, Key};
+use ::config;
 use ::util;
 use ::std::fs;
 use ::std::io::prelude::*;
@@ -39,6 +41,17 @@ protected! {
         #[serde(skip_serializing_if = "Option::is_none")]
         #[protected_field(private)]
         pub meta: Option<Value>,
+        /// The key (base64) the file is encrypted with, if it has its own (see
+        /// `FileData::prepare()`). Older files are encrypted with the note's
+        /// key.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(private)]
+        pub key: Option<String>,
+        /// The text inside the file, if it's a document we can read (see
+        /// `models::extract`)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(private)]
+        pub text: Option<String>,
     }
 }
 
@@ -53,6 +66,13 @@ protected! {
         #[serde(default)]
         #[protected_field(private)]
         pub data: Option<Vec<u8>>,
+
+        /// A hash (sha256, hex) of the encrypted file, so we can tell if it
+        /// got mangled on its way to/from the server
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default)]
+        #[protected_field(public)]
+        pub hash: Option<String>,
     }
 }
 
@@ -73,6 +93,10 @@ impl SyncModel for FileData {
             sync_record.generate_id()?;
             // change the type. heh heh, yes, very clever indeed...
             sync_record.ty = SyncType::FileIncoming;
+            // hang onto the file's hash so the download can be checked
+            if let Some(hash) = self.hash.as_ref() {
+                sync_record.data = Some(json!({"hash": hash}));
+            }
             // ...and queue the file for download in our incoming sync queue
             sync_record.db_save(db, None)?;
         }
@@ -96,6 +120,7 @@ impl SyncModel for FileData {
         for file in files {
             fs::remove_file(&file?)?;
         }
+        thumbnail::remove(&id)?;
         Ok(())
     }
 
@@ -158,6 +183,81 @@ impl MemorySaver for FileData {
 }
 
 impl FileData {
+    /// Whether we encrypt files so the same attachment always comes out the
+    /// same, letting the server skip uploads it already has
+    /// (`sync.files.dedupe`)
+    pub fn dedupe_enabled() -> bool {
+        config::get(&["sync", "files", "dedupe"]).unwrap_or(true)
+    }
+
+    /// Derive a file's key from its contents (and the user's key). The same
+    /// file attached to two of the user's notes gets the same key, and since
+    /// we also derive the nonce, the same encrypted blob. Mixing in the user's
+    /// key keeps anyone else from checking whether the user has some
+    /// particular file.
+    fn content_key(user_key: &Key, data: &[u8]) -> TResult<Key> {
+        let secret = crypto::hmac(user_key.data().as_slice(), b"turtl:file-dedupe")?;
+        Ok(Key::new(crypto::hmac(&secret[..crypto::HMAC_KEYLEN], data)?))
+    }
+
+    /// Encrypt file data with a key from `content_key()`
+    fn encrypt_content(key: &Key, data: Vec<u8>) -> TResult<Vec<u8>> {
+        let nonce = crypto::hmac(key.data().as_slice(), b"turtl:file-nonce")?;
+        let op = crypto::CryptoOp::new_with_nonce("chacha20poly1305", Vec::from(&nonce[..crypto::noncelen()]))?;
+        Ok(crypto::encrypt(key, data, op)?)
+    }
+
+    /// Figure out the key for this file and record it in the note's file info.
+    /// This needs to happen before the note is saved (so the key goes out with
+    /// it), and before `save()`.
+    pub fn prepare(&self, turtl: &Turtl, note: &mut Note) -> TResult<()> {
+        if !FileData::dedupe_enabled() { return Ok(()); }
+        let data = match self.data.as_ref() {
+            Some(x) => x,
+            None => return TErr!(TError::MissingField(format!("FileData.data"))),
+        };
+        let user_key = {
+            let user_guard = lockr!(turtl.user);
+            user_guard.key_or_else()?
+        };
+        let key = FileData::content_key(&user_key, data.as_slice())?;
+        let mut file = note.file.take().unwrap_or(Default::default());
+        file.key = Some(crypto::to_base64(key.data())?);
+        note.file = Some(file);
+        Ok(())
+    }
+
+    /// Grab the key a note's file is encrypted with
+    pub fn file_key(note: &Note) -> TResult<Key> {
+        match note.file.as_ref().and_then(|x| x.key.as_ref()) {
+            Some(x) => Ok(Key::new(crypto::from_base64(x)?)),
+            None => note.key_or_else(),
+        }
+    }
+
+    /// Hash some encrypted file data
+    pub fn hash_data(data: &[u8]) -> TResult<String> {
+        Ok(crypto::to_hex(&crypto::sha256(data)?)?)
+    }
+
+    /// Hash an (encrypted) file on disk
+    pub fn hash_file(path: &PathBuf) -> TResult<String> {
+        let mut file = fs::File::open(path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        FileData::hash_data(data.as_slice())
+    }
+
+    /// Make sure a file on disk matches the hash we expect, erroring with both
+    /// hashes if not.
+    pub fn verify_file(path: &PathBuf, expected: &String) -> TResult<()> {
+        let actual = FileData::hash_file(path)?;
+        if &actual != expected {
+            return TErr!(TError::BadValue(format!("file {:?} is corrupt: expected hash {}, got {}", path, expected, actual)));
+        }
+        Ok(())
+    }
+
     /// Builds a standard filename
     fn filebuilder(user_id: Option<&String>, note_id: Option<&String>) -> String {
         // wildcard, bitches. YEEEEEEEEHAWW!!!
@@ -185,6 +285,22 @@ impl FileData {
         Ok(res)
     }
 
+    /// Find every file we keep on disk for a user: attachments, partial
+    /// downloads of them, thumbnails, and their avatar
+    pub fn user_files_all(user_id: &String) -> TResult<Vec<PathBuf>> {
+        let mut filepath = PathBuf::from(file_folder()?);
+        filepath.push(format!("u_{}.*", user_id));
+        let pathstr = match filepath.to_str() {
+            Some(x) => x,
+            None => return TErr!(TError::BadValue(format!("invalid path: {:?}", filepath))),
+        };
+        let mut res = Vec::new();
+        for file in glob::glob(pathstr)? {
+            res.push(file?);
+        }
+        Ok(res)
+    }
+
     /// Find the PathBuf for a file, given the pieces that build the filename
     pub fn file_finder(user_id: Option<&String>, note_id: Option<&String>) -> TResult<PathBuf> {
         let mut files = FileData::file_finder_all(user_id, note_id)?;
@@ -205,7 +321,7 @@ impl FileData {
     /// Load a note's file, if we have one.
     pub fn load_file(turtl: &Turtl, note: &Note) -> TResult<Vec<u8>> {
         let note_id = note.id_or_else()?;
-        let note_key = note.key_or_else()?;
+        let file_key = FileData::file_key(note)?;
 
         let filename = FileData::file_finder(None, Some(&note_id))?;
         let enc = {
@@ -217,7 +333,7 @@ impl FileData {
 
         // decrypt the file using the turtl standard serialization format
         let data = turtl.work.run(move || {
-            crypto::decrypt(&note_key, enc)
+            crypto::decrypt(&file_key, enc)
                 .map_err(|e| From::from(e))
         })?;
 
@@ -227,9 +343,13 @@ impl FileData {
     /// Encrypt/save this file
     pub fn save(&mut self, turtl: &Turtl, note: &mut Note) -> TResult<()> {
         // grab some items we'll need to do our work (user_id/note_id for the
-        // filename, note_key for encrypting the file).
+        // filename, the file's key for encrypting the file).
         let user_id = turtl.user_id()?;
         let note_id = note.id_or_else()?;
+        let content_key = match note.file.as_ref().and_then(|x| x.key.as_ref()) {
+            Some(_) => Some(FileData::file_key(note)?),
+            None => None,
+        };
         let note_key = note.key_or_else()?;
 
         // the file id should ref the note
@@ -244,11 +364,20 @@ impl FileData {
             Some(x) => x,
             None => return TErr!(TError::MissingField(format!("FileData.data"))),
         };
+        // hang onto images so we can thumbnail them once the file is saved
+        let thumb_data = if thumbnail::wanted(note) { Some(data.clone()) } else { None };
 
-        // encrypt the file using the turtl standard serialization format
+        // encrypt the file using the turtl standard serialization format. files
+        // with their own key get the same treatment every time (so identical
+        // files match), older ones are encrypted with the note's key
         let enc = turtl.work.run(move || {
-            crypto::encrypt(&note_key, data, crypto::CryptoOp::new("chacha20poly1305")?)
-                .map_err(|e| From::from(e))
+            match content_key {
+                Some(key) => FileData::encrypt_content(&key, data),
+                None => {
+                    crypto::encrypt(&note_key, data, crypto::CryptoOp::new("chacha20poly1305")?)
+                        .map_err(|e| From::from(e))
+                }
+            }
         })?;
 
         // now, save the encrypted file data to disk
@@ -257,6 +386,7 @@ impl FileData {
         filepath.push(FileData::filebuilder(Some(&user_id), Some(&note_id)));
         let mut fs_file = fs::File::create(&filepath)?;
         fs_file.write_all(enc.as_slice())?;
+        self.hash = Some(FileData::hash_data(enc.as_slice())?);
 
         // phew, now that all went smoothly, create a sync record for the saved
         // file (which will let the sync system know to upload our heroic file)
@@ -286,6 +416,11 @@ impl FileData {
                 return Err(e);
             }
         }
+        if let Some(data) = thumb_data {
+            if let Err(e) = thumbnail::generate_from(turtl, note, data) {
+                warn!("FileData.save() -- couldn't make thumbnail for note {}: {}", note_id, e);
+            }
+        }
         Ok(())
     }
 }
@@ -308,6 +443,25 @@ mod tests {
         assert_eq!(file2.data.as_ref().unwrap(), &filedata);
     }
 
+    #[test]
+    fn encrypts_identical_files_identically() {
+        let user_key = Key::random().unwrap();
+        let data = Vec::from("my big pdf".as_bytes());
+        let key1 = FileData::content_key(&user_key, data.as_slice()).unwrap();
+        let key2 = FileData::content_key(&user_key, data.as_slice()).unwrap();
+        assert_eq!(key1.data(), key2.data());
+        let enc1 = FileData::encrypt_content(&key1, data.clone()).unwrap();
+        let enc2 = FileData::encrypt_content(&key2, data.clone()).unwrap();
+        assert_eq!(FileData::hash_data(enc1.as_slice()).unwrap(), FileData::hash_data(enc2.as_slice()).unwrap());
+        assert_eq!(crypto::decrypt(&key1, enc1).unwrap(), data);
+
+        // other files, and other users, get their own keys
+        let other = FileData::content_key(&user_key, "my other pdf".as_bytes()).unwrap();
+        assert!(other.data() != key1.data());
+        let other_user = FileData::content_key(&Key::random().unwrap(), data.as_slice()).unwrap();
+        assert!(other_user.data() != key1.data());
+    }
+
     #[test]
     fn can_save_and_load_files() {
         let turtl = ::turtl::tests::with_test(true);
@@ -340,6 +494,11 @@ mod tests {
         // see if the file contents match after decryption
         assert_eq!(String::from_utf8(loaded).unwrap(), r#"{"age":42,"dislikes":"slappy","likes":"slippy","lives":{"city":"santa cruz brahhhh"},"name":"flippy"}"#);
 
+        // the saved file should match the hash we recorded for it
+        let path = FileData::file_finder(Some(&user_id), note.id()).unwrap();
+        FileData::verify_file(&path, file.hash.as_ref().unwrap()).unwrap();
+        assert!(FileData::verify_file(&path, &String::from("deadbeef")).is_err());
+
         let mut db_guard = lock!(turtl.db);
         let db = db_guard.as_mut().unwrap();
         file.db_delete(db, None).unwrap();
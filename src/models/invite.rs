@@ -210,6 +210,20 @@ impl Invite {
         Ok(())
     }
 
+    /// List the user's invites: the ones sent to them (`received`) and the ones
+    /// pending for the spaces they're in (`sent`)
+    pub fn list(turtl: &Turtl) -> TResult<Value> {
+        let profile_guard = lockr!(turtl.profile);
+        let mut sent: Vec<&Invite> = Vec::new();
+        for space in &profile_guard.spaces {
+            for invite in &space.invites { sent.push(invite); }
+        }
+        Ok(json!({
+            "received": &profile_guard.invites,
+            "sent": sent,
+        }))
+    }
+
     /// Delete an invite. This is specifically for a space invitee to delete an
     /// invite that was sent to them.
     pub fn delete_user_invite(turtl: &Turtl, invite_id: &String) -> TResult<()> {
@@ -226,3 +240,83 @@ impl Invite {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::models::space::Space;
+
+    fn request(their_pubkey: Option<Key>, passphrase: Option<String>) -> InviteRequest {
+        InviteRequest {
+            space_id: String::from("1234"),
+            to_user: String::from("slappy@turtlapp.com"),
+            role: Role::Member,
+            title: String::from("come on in"),
+            their_pubkey: their_pubkey,
+            passphrase: passphrase,
+        }
+    }
+
+    fn space_key(invite: &Invite) -> Key {
+        let message = String::from_utf8(invite.message.clone().unwrap()).unwrap();
+        jedi::get(&["space_key"], &jedi::parse::<Value>(&message).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn wraps_with_pubkeys() {
+        let space_key = Key::random().unwrap();
+        let (pk, sk) = crypto::asym::keygen().unwrap();
+        let (other_pk, other_sk) = crypto::asym::keygen().unwrap();
+        let from_id = String::from("51");
+        let from = String::from("slippy@turtlapp.com");
+
+        let sealed = Invite::from_invite_request(&from_id, &from, &space_key, request(Some(pk.clone()), None)).unwrap();
+        assert!(sealed.is_pubkey_protected);
+        assert!(!sealed.is_passphrase_protected);
+        // only the invitee's keypair opens it
+        let mut invite: Invite = jedi::from_val(sealed.data_for_storage().unwrap()).unwrap();
+        assert!(invite.open(&other_pk, &other_sk, None).is_err());
+        let mut invite: Invite = jedi::from_val(sealed.data_for_storage().unwrap()).unwrap();
+        invite.open(&pk, &sk, None).unwrap();
+        assert_eq!(space_key(&invite), space_key);
+
+        // no account means the passphrase is all there is
+        let sealed = Invite::from_invite_request(&from_id, &from, &space_key, request(None, Some(String::from("tacos")))).unwrap();
+        assert!(!sealed.is_pubkey_protected);
+        assert!(sealed.is_passphrase_protected);
+        let mut invite: Invite = jedi::from_val(sealed.data_for_storage().unwrap()).unwrap();
+        assert!(invite.open(&pk, &sk, Some(String::from("burritos"))).is_err());
+        let mut invite: Invite = jedi::from_val(sealed.data_for_storage().unwrap()).unwrap();
+        invite.open(&pk, &sk, Some(String::from("tacos"))).unwrap();
+        assert_eq!(space_key(&invite), space_key);
+    }
+
+    #[test]
+    fn lists_sent_and_received() {
+        let turtl = ::turtl::tests::with_test(true);
+        let space_key = Key::random().unwrap();
+        let from_id = String::from("51");
+        let from = String::from("slippy@turtlapp.com");
+        let received = Invite::from_invite_request(&from_id, &from, &space_key, request(None, None)).unwrap();
+        let sent = Invite::from_invite_request(&from_id, &from, &space_key, request(None, None)).unwrap();
+        let received_id = received.id().unwrap().clone();
+        let sent_id = sent.id().unwrap().clone();
+        {
+            let mut profile_guard = lockw!(turtl.profile);
+            let mut space = Space::new();
+            space.set_id(String::from("1234"));
+            space.invites.push(sent);
+            profile_guard.spaces.push(space);
+            profile_guard.invites.push(received);
+        }
+
+        let list = Invite::list(&turtl).unwrap();
+        let received: Vec<Value> = jedi::get(&["received"], &list).unwrap();
+        let sent: Vec<Value> = jedi::get(&["sent"], &list).unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(jedi::get::<String>(&["id"], &received[0]).unwrap(), received_id);
+        assert_eq!(jedi::get::<String>(&["id"], &sent[0]).unwrap(), sent_id);
+        assert_eq!(jedi::get::<String>(&["to_user"], &sent[0]).unwrap(), "slappy@turtlapp.com");
+        assert_eq!(jedi::get::<String>(&["space_id"], &sent[0]).unwrap(), "1234");
+    }
+}
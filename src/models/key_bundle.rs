@@ -0,0 +1,124 @@
+//! Key bundles let a space member hand read access to a single space to
+//! someone outside of it (an auditor, say) without sharing their account or
+//! adding them as a member.
+//!
+//! A bundle is the space's key sealed to the recipient's public key, along
+//! with a capability record describing what the bundle grants (currently
+//! always read-only). The capability is sealed along with the key, and the
+//! sealed copy is the one we trust on import.
+
+use ::error::{TResult, TError};
+use ::crypto::{self, Key};
+use ::jedi;
+use ::lib_permissions::{Role, Permission};
+
+/// Describes what a key bundle grants its holder
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SpaceCapability {
+    /// The space this capability applies to
+    pub space_id: String,
+    /// The role the holder acts as in the space
+    pub role: Role,
+    /// The user who exported the keys
+    pub granted_by: String,
+    /// The email of the user who exported the keys
+    pub granted_by_username: String,
+    /// Always true (for now)
+    pub read_only: bool,
+}
+
+impl SpaceCapability {
+    /// Create a read-only capability for a space
+    pub fn read_only(space_id: &String, granted_by: &String, granted_by_username: &String) -> Self {
+        SpaceCapability {
+            space_id: space_id.clone(),
+            role: Role::Guest,
+            granted_by: granted_by.clone(),
+            granted_by_username: granted_by_username.clone(),
+            read_only: true,
+        }
+    }
+
+    /// Whether or not this capability allows the given permission
+    pub fn can(&self, permission: &Permission) -> bool {
+        self.role.can(permission)
+    }
+}
+
+/// What actually gets sealed inside a bundle
+#[derive(Serialize, Deserialize, Debug)]
+struct SealedSpaceKey {
+    space_key: Key,
+    capability: SpaceCapability,
+}
+
+/// A space key, sealed for a specific recipient, along with the capability it
+/// grants.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SpaceKeyBundle {
+    /// A readable copy of the sealed capability, so the recipient (or the UI)
+    /// can see what's being granted without opening the bundle
+    pub capability: SpaceCapability,
+    /// The space key and capability, encrypted with the recipient's pubkey
+    /// (base64)
+    pub sealed: String,
+}
+
+impl SpaceKeyBundle {
+    /// Seal a space key (and capability) for a recipient
+    pub fn seal(capability: SpaceCapability, space_key: &Key, their_pubkey: &Key) -> TResult<Self> {
+        let payload = SealedSpaceKey {
+            space_key: space_key.clone(),
+            capability: capability.clone(),
+        };
+        let message = jedi::stringify(&payload)?;
+        let sealed = crypto::asym::encrypt(their_pubkey, Vec::from(message.as_bytes()))?;
+        Ok(SpaceKeyBundle {
+            capability: capability,
+            sealed: crypto::to_base64(&sealed)?,
+        })
+    }
+
+    /// Open a bundle sealed for us, returning the space key and capability.
+    /// Errors if the bundle's readable capability doesn't match the sealed
+    /// one.
+    pub fn open(&self, our_pubkey: &Key, our_privkey: &Key) -> TResult<(Key, SpaceCapability)> {
+        let sealed = crypto::from_base64(&self.sealed)?;
+        let message = crypto::asym::decrypt(our_pubkey, our_privkey, sealed)?;
+        let payload: SealedSpaceKey = jedi::parse(&String::from_utf8(message)?)?;
+        let SealedSpaceKey { space_key, capability } = payload;
+        if capability != self.capability {
+            return TErr!(TError::BadValue(format!("key bundle for space {} has been tampered with (capability mismatch)", capability.space_id)));
+        }
+        Ok((space_key, capability))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seals_and_opens() {
+        let (pk, sk) = crypto::asym::keygen().unwrap();
+        let space_key = Key::random().unwrap();
+        let capability = SpaceCapability::read_only(&String::from("1234"), &String::from("69"), &String::from("drew@turtlapp.com"));
+        assert!(capability.can(&Permission::AddNote) == Role::Guest.can(&Permission::AddNote));
+
+        let bundle = SpaceKeyBundle::seal(capability.clone(), &space_key, &pk).unwrap();
+        let bundle: SpaceKeyBundle = jedi::parse(&jedi::stringify(&bundle).unwrap()).unwrap();
+        let (opened_key, opened_cap) = bundle.open(&pk, &sk).unwrap();
+        assert_eq!(opened_key.data(), space_key.data());
+        assert_eq!(opened_cap, capability);
+
+        // no upgrading yourself to owner
+        let mut tampered = bundle.clone();
+        tampered.capability.role = Role::Owner;
+        tampered.capability.read_only = false;
+        assert!(tampered.open(&pk, &sk).is_err());
+
+        // not ours
+        let (pk2, sk2) = crypto::asym::keygen().unwrap();
+        assert!(bundle.open(&pk2, &sk2).is_err());
+    }
+}
@@ -3,6 +3,7 @@ use ::serde::{ser, de};
 use ::error::{TResult, TError};
 use ::crypto::Key;
 use ::models::model::Model;
+use ::models::key_bundle::SpaceCapability;
 use ::models::protected::{Keyfinder, Protected};
 use ::models::sync_record::{SyncRecord, SyncAction};
 use ::models::validate::Validate;
@@ -109,6 +110,13 @@ protected! {
         #[serde(with = "::util::ser::int_converter")]
         #[protected_field(public)]
         pub user_id: String,
+        /// Set if this key was imported from a key bundle (see
+        /// `models::key_bundle`) as opposed to coming from a space we're a
+        /// member of. Spells out what we're allowed to do with it.
+        #[serde(default)]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(public)]
+        pub capability: Option<SpaceCapability>,
 
         #[serde(skip_serializing_if = "Option::is_none")]
         #[protected_field(private)]
@@ -246,6 +254,12 @@ impl Keychain {
         }
     }
 
+    /// Find the capability for a foreign (imported) key, if the given item
+    /// has one
+    pub fn find_capability<'a>(&'a self, item_id: &String) -> Option<&'a SpaceCapability> {
+        self.find_entry(item_id).and_then(|entry| entry.capability.as_ref())
+    }
+
     /// Find ALL matching keys for an object.
     pub fn find_all_entries(&self, item_id: &String) -> Vec<Key> {
         let mut found = Vec::with_capacity(2);
@@ -279,6 +293,18 @@ impl Keychain {
 // >>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
 /// Save a key to the keychain for the current logged in user
 pub fn save_key(turtl: &Turtl, item_id: &String, key: &Key, ty: &String, skip_remote_sync: bool) -> TResult<()> {
+    save_entry(turtl, item_id, key, ty, None, skip_remote_sync)
+}
+
+/// Save a key imported from a key bundle to the keychain for the current
+/// logged in user, along with the capability that limits what we can do with
+/// it.
+pub fn save_foreign_key(turtl: &Turtl, item_id: &String, key: &Key, ty: &String, capability: SpaceCapability) -> TResult<()> {
+    save_entry(turtl, item_id, key, ty, Some(capability), false)
+}
+
+/// Save a keychain entry for the current logged in user
+fn save_entry(turtl: &Turtl, item_id: &String, key: &Key, ty: &String, capability: Option<SpaceCapability>, skip_remote_sync: bool) -> TResult<()> {
     let (user_id, user_key) = {
         let user_guard = lockr!(turtl.user);
         let id = user_guard.id_or_else()?;
@@ -305,6 +331,7 @@ pub fn save_key(turtl: &Turtl, item_id: &String, key: &Key, ty: &String, skip_re
     entry.user_id = user_id.clone();
     entry.item_id = item_id.clone();
     entry.k = Some(key.clone());
+    entry.capability = capability;
 
     let action = if exists { SyncAction::Edit } else { SyncAction::Add };
     sync_model::save_model(action, turtl, entry, skip_remote_sync)?;
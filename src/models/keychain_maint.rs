@@ -0,0 +1,274 @@
+//! Keychain upkeep. Every space we create or join adds an entry to the user's
+//! keychain and not much ever takes one out, so older accounts carry piles of
+//! entries for spaces that are long gone, all of which get decrypted at login
+//! and searched through every time we look for a key.
+//!
+//! `find_dead()` picks out entries that:
+//!
+//! - point at a space or board we don't have anymore (orphaned)
+//! - cover an item another entry already covers (duplicate). we keep the one
+//!   the in-memory keychain is using
+//! - can't be decrypted with the user's key (undecryptable)
+//!
+//! and `clean()` deletes them, or on a dry run just reports what it would
+//! delete. Entries imported from key bundles (see `models::key_bundle`) are for
+//! spaces we aren't members of, and entries for spaces this device doesn't sync
+//! (see `sync::selective`) are for spaces we don't keep locally, so neither
+//! counts as orphaned.
+//!
+//! Going the other way, `verify()` makes sure every space, board, note, and
+//! template we have can still find its key, either straight from the keychain
+//! or through one of the spaces/boards its key is encrypted with. Anything that
+//! can't is unreadable until someone re-shares it, so it's better to find out
+//! while there's still someone around to do that.
+
+use ::std::collections::{HashMap, HashSet};
+use ::error::{TResult, TError};
+use ::jedi;
+use ::models::model::Model;
+use ::models::keychain::KeychainEntry;
+use ::models::space::Space;
+use ::models::board::Board;
+use ::models::note::Note;
+use ::models::template::Template;
+use ::models::protected::{self, Keyfinder, Protected};
+use ::models::storable::Storable;
+use ::sync::sync_model;
+use ::turtl::Turtl;
+
+/// Why a keychain entry is dead weight
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub enum DeadReason {
+    #[serde(rename = "orphaned")]
+    Orphaned,
+    #[serde(rename = "duplicate")]
+    Duplicate,
+    #[serde(rename = "undecryptable")]
+    Undecryptable,
+}
+
+/// A keychain entry we can do without
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DeadEntry {
+    pub id: String,
+    pub item_id: String,
+    #[serde(rename = "type")]
+    pub ty: String,
+    pub reason: DeadReason,
+}
+
+/// What a cleaning found (and did)
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct CleanReport {
+    /// How many entries we looked at
+    pub checked: u64,
+    pub dead: Vec<DeadEntry>,
+    /// How many entries we deleted (none on a dry run)
+    pub removed: u64,
+    pub dry_run: bool,
+}
+
+/// Sort the dead entries out of a keychain. `opened` holds the ids of the
+/// entries we could decrypt, `in_use` maps item ids to the id of the entry the
+/// in-memory keychain uses for them, and `is_live` says whether an entry's item
+/// is still around.
+pub fn sort_out<F>(entries: &Vec<KeychainEntry>, opened: &HashSet<String>, in_use: &HashMap<String, String>, is_live: F) -> Vec<DeadEntry>
+    where F: Fn(&KeychainEntry) -> bool
+{
+    let mut entries = entries.iter()
+        .filter(|x| x.id().is_some())
+        .collect::<Vec<_>>();
+    entries.sort_by(|a, b| a.id().cmp(&b.id()));
+    let mut seen: HashSet<&String> = HashSet::new();
+    let mut dead = Vec::new();
+    for entry in entries {
+        let id = entry.id().expect("turtl::keychain_maint::sort_out() -- entry.id() is None");
+        let reason = if !opened.contains(id) {
+            DeadReason::Undecryptable
+        } else if !is_live(entry) {
+            DeadReason::Orphaned
+        } else {
+            let duplicate = match in_use.get(&entry.item_id) {
+                Some(keep_id) => keep_id != id,
+                None => seen.contains(&entry.item_id),
+            };
+            seen.insert(&entry.item_id);
+            if !duplicate { continue; }
+            DeadReason::Duplicate
+        };
+        dead.push(DeadEntry {
+            id: id.clone(),
+            item_id: entry.item_id.clone(),
+            ty: entry.ty.clone(),
+            reason: reason,
+        });
+    }
+    dead
+}
+
+/// Find the keychain entries we can do without. Returns how many entries we
+/// checked along with the dead ones.
+pub fn find_dead(turtl: &Turtl) -> TResult<(u64, Vec<DeadEntry>)> {
+    let (entries, space_ids, board_ids) = with_db!{ db, turtl.db,
+        let entries: Vec<KeychainEntry> = db.all("keychain")?;
+        let spaces: Vec<Space> = db.all("spaces")?;
+        let boards: Vec<Board> = db.all("boards")?;
+        let space_ids = spaces.into_iter().filter_map(|x| x.id().map(|id| id.clone())).collect::<HashSet<_>>();
+        let board_ids = boards.into_iter().filter_map(|x| x.id().map(|id| id.clone())).collect::<HashSet<_>>();
+        (entries, space_ids, board_ids)
+    };
+    let mut to_open = Vec::with_capacity(entries.len());
+    for entry in &entries {
+        to_open.push(entry.clone()?);
+    }
+    turtl.find_models_keys(&mut to_open)?;
+    let opened = protected::map_deserialize(turtl, to_open)?.into_iter()
+        .filter_map(|x| x.id().map(|id| id.clone()))
+        .collect::<HashSet<_>>();
+    // if nothing opens, the problem is our key, not the keychain
+    if entries.len() > 0 && opened.len() == 0 {
+        return TErr!(TError::MissingData(String::from("couldn't decrypt any keychain entries, not cleaning")));
+    }
+    let in_use = {
+        let profile_guard = lockr!(turtl.profile);
+        profile_guard.keychain.entries.iter()
+            .filter_map(|x| x.id().map(|id| (x.item_id.clone(), id.clone())))
+            .collect::<HashMap<_, _>>()
+    };
+    let filter = turtl.sync_space_filter();
+    let dead = sort_out(&entries, &opened, &in_use, |entry| {
+        if entry.capability.is_some() { return true; }
+        match entry.ty.as_str() {
+            "space" => space_ids.contains(&entry.item_id) || !filter.allows(&entry.item_id),
+            // we can't tell which space a board we don't have was in, so if
+            // we're skipping any spaces, give it the benefit of the doubt
+            "board" => board_ids.contains(&entry.item_id) || !filter.is_all(),
+            _ => true,
+        }
+    });
+    Ok((entries.len() as u64, dead))
+}
+
+/// Delete the keychain entries we can do without (see `find_dead()`). A dry
+/// run only reports them.
+pub fn clean(turtl: &Turtl, dry_run: bool) -> TResult<CleanReport> {
+    let (checked, dead) = find_dead(turtl)?;
+    let mut report = CleanReport {
+        checked: checked,
+        dead: dead,
+        removed: 0,
+        dry_run: dry_run,
+    };
+    if dry_run || report.dead.len() == 0 { return Ok(report); }
+    sync_model::atomic(turtl, || {
+        for entry in &report.dead {
+            sync_model::delete_model::<KeychainEntry>(turtl, &entry.id, false)?;
+        }
+        Ok(())
+    })?;
+    // deleting by id leaves the in-memory keychain alone, so we do it here
+    let dead_ids = report.dead.iter().map(|x| &x.id).collect::<HashSet<_>>();
+    {
+        let mut profile_guard = lockw!(turtl.profile);
+        profile_guard.keychain.entries.retain(|x| x.id().map(|id| !dead_ids.contains(id)).unwrap_or(true));
+    }
+    report.removed = report.dead.len() as u64;
+    info!("keychain_maint::clean() -- removed {} of {} keychain entries", report.removed, report.checked);
+    Ok(report)
+}
+
+/// An item we can't find the key for
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct UnreachableItem {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub ty: String,
+    pub space_id: Option<String>,
+    /// The spaces/boards the item's key is encrypted with. None of them got us
+    /// the key.
+    pub via: Vec<String>,
+}
+
+/// What a verification found
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct VerifyReport {
+    /// How many items we checked
+    pub checked: u64,
+    pub unreachable: Vec<UnreachableItem>,
+    /// True if every item can find its key
+    pub ok: bool,
+}
+
+/// Check that every item in one of our tables can find its key
+fn verify_table<T>(turtl: &Turtl, report: &mut VerifyReport) -> TResult<()>
+    where T: Protected + Storable + Keyfinder
+{
+    let models: Vec<T> = with_db!{ db, turtl.db, db.all(T::tablename())? };
+    for mut model in models {
+        turtl.session().check()?;
+        report.checked += 1;
+        if turtl.find_model_key(&mut model).is_ok() { continue; }
+        let id = match model.id() {
+            Some(x) => x.clone(),
+            None => continue,
+        };
+        let space_id = if model.model_type() == "space" {
+            Some(id.clone())
+        } else {
+            jedi::get_opt(&["space_id"], &model.data()?)
+        };
+        let via = model.get_keys()
+            .map(|keys| keys.iter().map(|x| x.id.clone()).collect::<Vec<_>>())
+            .unwrap_or(Vec::new());
+        report.unreachable.push(UnreachableItem {
+            id: id,
+            ty: model.model_type(),
+            space_id: space_id,
+            via: via,
+        });
+    }
+    Ok(())
+}
+
+/// Find all the items we have that can't find their key
+pub fn verify(turtl: &Turtl) -> TResult<VerifyReport> {
+    let mut report = VerifyReport::default();
+    verify_table::<Space>(turtl, &mut report)?;
+    verify_table::<Board>(turtl, &mut report)?;
+    verify_table::<Note>(turtl, &mut report)?;
+    verify_table::<Template>(turtl, &mut report)?;
+    report.ok = report.unreachable.len() == 0;
+    if !report.ok {
+        warn!("keychain_maint::verify() -- {} of {} items can't find their key", report.unreachable.len(), report.checked);
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::jedi;
+
+    #[test]
+    fn sorts_out_dead_entries() {
+        let entries: Vec<KeychainEntry> = jedi::parse(&String::from(r#"[
+            {"id":"e1","type":"space","item_id":"s1","user_id":51},
+            {"id":"e2","type":"space","item_id":"s1","user_id":51},
+            {"id":"e3","type":"space","item_id":"gone","user_id":51},
+            {"id":"e4","type":"space","item_id":"s2","user_id":51},
+            {"id":"e5","type":"board","item_id":"b1","user_id":51},
+            {"id":"e6","type":"board","item_id":"b1","user_id":51}
+        ]"#)).unwrap();
+        let opened = vec!["e1", "e2", "e3", "e5", "e6"].into_iter().map(|x| String::from(x)).collect::<HashSet<_>>();
+        let mut in_use = HashMap::new();
+        in_use.insert(String::from("s1"), String::from("e2"));
+        let dead = sort_out(&entries, &opened, &in_use, |entry| entry.item_id != "gone");
+        let found = dead.iter().map(|x| (x.id.as_str(), x.reason.clone())).collect::<Vec<_>>();
+        assert_eq!(found, vec![
+            ("e1", DeadReason::Duplicate),
+            ("e3", DeadReason::Orphaned),
+            ("e4", DeadReason::Undecryptable),
+            ("e6", DeadReason::Duplicate),
+        ]);
+    }
+}
@@ -12,11 +12,29 @@ pub mod validate;
 pub mod sync_record;
 pub mod user;
 pub mod keychain;
+pub mod keychain_maint;
 pub mod space;
 pub mod space_member;
 pub mod board;
 pub mod note;
+pub mod note_history;
+pub mod note_stats;
+pub mod checklist;
+pub mod note_batch;
+pub mod ordering;
+pub mod template;
+pub mod settings;
+pub mod saved_search;
 pub mod file;
+pub mod thumbnail;
+pub mod extract;
+pub mod avatar;
 pub mod invite;
+pub mod key_bundle;
+pub mod rotate;
 pub mod feedback;
+pub mod trash;
+pub mod upgrade;
+pub mod tag;
+pub mod share;
 
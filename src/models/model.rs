@@ -95,6 +95,9 @@ pub fn cid() -> TResult<String> {
 pub fn cid_w_client_id(cid: &String, client_id: &String) -> TResult<String> {
     let mut cid_bytes = crypto::from_hex(cid)?;
     let client_id_bytes = crypto::from_hex(client_id)?;
+    if cid_bytes.len() < 38 || client_id_bytes.len() < 32 {
+        return TErr!(TError::BadValue(format!("bad cid ({}) or client id ({}) given", cid, client_id)));
+    }
     for i in 0..32 {
         cid_bytes[i + 6] = client_id_bytes[i];
     }
@@ -103,6 +106,9 @@ pub fn cid_w_client_id(cid: &String, client_id: &String) -> TResult<String> {
 
 /// Parse a unix timestamp out of a model id
 pub fn id_timestamp(id: &String) -> TResult<i64> {
+    if !id.is_ascii() {
+        return TErr!(TError::BadValue(format!("bad id given ({})", id)));
+    }
     let ts = if id.len() == 24 {
         i64::from_str_radix(&id[0..8], 16)? * 1000
     } else if id.len() == 80 {
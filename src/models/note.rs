@@ -1,14 +1,26 @@
 use ::turtl::Turtl;
-use ::error::TResult;
+use ::error::{TResult, TError, FieldError};
+use ::jedi::Value;
+use ::time;
+use ::lib_permissions::Permission;
 use ::models::model::Model;
-use ::models::validate::Validate;
+use ::models::validate::{self, Validate};
 use ::models::protected::{Keyfinder, Protected};
 use ::models::keychain::{Keychain, KeyRef, KeyType};
 use ::models::file::{File, FileData};
+use ::models::space::Space;
+use ::models::board::{self, Board};
+use ::models::note_history;
+use ::models::note_stats;
+use ::models::ordering;
+use ::models::checklist::ChecklistItem;
 use ::models::sync_record::{SyncRecord, SyncAction};
 use ::crypto::Key;
+use ::storage::Storage;
 use ::sync::sync_model::{self, SyncModel, MemorySaver};
+use ::sync::reminders;
 use ::std::fs;
+use ::std::collections::BTreeMap;
 use ::models::storable::Storable;
 
 protected! {
@@ -25,24 +37,43 @@ protected! {
         #[protected_field(public)]
         pub has_file: bool,
         #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(public)]
+        pub pinned: Option<bool>,
+        /// Where the note goes among the notes in its board (see
+        /// `models::ordering`)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(public)]
+        pub position: Option<f64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
         #[protected_field(public, submodel)]
         pub file: Option<File>,
         #[serde(skip_serializing_if = "Option::is_none")]
         #[serde(rename = "mod")]
         #[protected_field(public)]
         pub mod_: Option<i64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(public)]
+        pub trashed: Option<i64>,
+        /// The encrypted lazy fields (`embed`), kept out of `body` so a note's
+        /// metadata can be decrypted without them (see
+        /// `Protected::deserialize_meta()`)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(public)]
+        pub lazy_body: Option<String>,
 
         #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
         #[protected_field(private)]
         pub type_: Option<String>,
         #[serde(skip_serializing_if = "Option::is_none")]
         #[protected_field(private)]
+        #[protected_validate(max_len = 1024)]
         pub title: Option<String>,
         #[serde(skip_serializing_if = "Option::is_none")]
         #[protected_field(private)]
         pub tags: Option<Vec<String>>,
         #[serde(skip_serializing_if = "Option::is_none")]
         #[protected_field(private)]
+        #[protected_validate(url, max_len = 4096)]
         pub url: Option<String>,
         #[serde(skip_serializing_if = "Option::is_none")]
         #[protected_field(private)]
@@ -53,18 +84,76 @@ protected! {
         #[serde(skip_serializing_if = "Option::is_none")]
         #[protected_field(private)]
         pub text: Option<String>,
+        /// User-defined fields (due dates, priorities, ratings, etc). Values
+        /// can be anything, but only strings, numbers, and bools can be
+        /// searched on (see `search::FieldFilter`).
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(private)]
+        pub fields: Option<BTreeMap<String, Value>>,
+        /// The items of a checklist note (see `checklist`)
         #[serde(skip_serializing_if = "Option::is_none")]
         #[protected_field(private)]
+        pub items: Option<Vec<ChecklistItem>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(private, lazy)]
         pub embed: Option<String>,
         #[serde(skip_serializing_if = "Option::is_none")]
         #[protected_field(private)]
         pub color: Option<i64>,
+        /// When (unix seconds) to remind the user about this note (see
+        /// `sync::reminders`)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(private)]
+        pub reminder_at: Option<i64>,
     }
 }
 
 make_storable!(Note, "notes");
-impl SyncModel for Note {}
-impl Validate for Note {}
+impl SyncModel for Note {
+    fn keeps_base(&self) -> bool {
+        true
+    }
+
+    fn sends_deltas(&self) -> bool {
+        true
+    }
+
+    // stash the version we're saving over (see `note_history`) and keep our
+    // totals up to date (see `note_stats`)
+    fn db_save(&self, db: &mut Storage, _sync_item: Option<&SyncRecord>) -> TResult<()> {
+        note_history::record(db, self)?;
+        note_stats::record_save(db, self)?;
+        db.save(self)
+    }
+
+    fn db_delete(&self, db: &mut Storage, _sync_item: Option<&SyncRecord>) -> TResult<()> {
+        let note_id = self.id_or_else()?;
+        note_history::clear(db, &note_id)?;
+        note_stats::record_delete(db, &note_id)?;
+        db.delete(self)
+    }
+}
+impl Validate for Note {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        if let Some(fields) = self.fields.as_ref() {
+            if fields.keys().any(|x| x.trim() == "") {
+                errors.push(validate::entry("fields", t!("Custom fields need a name")));
+            }
+        }
+        errors
+    }
+}
+
+/// Where a duplicated note goes
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct DuplicateOptions {
+    /// Defaults to the original note's space
+    pub space_id: Option<String>,
+    /// Defaults to the original note's board (if it stays in the same space)
+    pub board_id: Option<String>,
+}
 
 impl Note {
     /// Remove the files attached to this note, if any.
@@ -78,6 +167,40 @@ impl Note {
         Ok(())
     }
 
+    /// Move a note to just after `after_id` among the notes in its board (or
+    /// the notes outside of any board, in its space). None moves it to the
+    /// top. Returns the saved notes' data.
+    pub fn reorder(turtl: &Turtl, note_id: &String, after_id: Option<String>) -> TResult<Vec<Value>> {
+        let note: Option<Note> = with_db!{ db, turtl.db, db.get(Note::tablename(), note_id)? };
+        let note = match note {
+            Some(x) => x,
+            None => return TErr!(TError::NotFound(format!("note {} wasn't found", note_id))),
+        };
+        Space::permission_check(turtl, &note.space_id, &Permission::EditNote)?;
+        // position is public, so we don't have to open anything to figure out
+        // the order
+        let siblings: Vec<Note> = with_db!{ db, turtl.db,
+            match note.board_id.as_ref() {
+                Some(board_id) => db.find(Note::tablename(), "board_id", &vec![board_id.clone()])?,
+                None => db.find(Note::tablename(), "space_id", &vec![note.space_id.clone()])?,
+            }
+        };
+        let siblings = siblings.into_iter()
+            .filter(|x| x.space_id == note.space_id && x.board_id == note.board_id && x.trashed.is_none())
+            .filter_map(|x| x.id().map(|id| (id.clone(), x.position)))
+            .collect::<Vec<_>>();
+        let changes = ordering::place(&siblings, note_id, after_id.as_ref())?;
+        let ids = changes.iter().map(|x| x.0.clone()).collect::<Vec<_>>();
+        let mut notes = turtl.load_notes(&ids)?;
+        let mut saved = Vec::with_capacity(notes.len());
+        for note in &mut notes {
+            let id = note.id_or_else()?;
+            note.position = changes.iter().find(|x| x.0 == id).map(|x| x.1);
+            saved.push(sync_model::save_model(SyncAction::Edit, turtl, note, false)?);
+        }
+        Ok(saved)
+    }
+
     /// Move a note to a different space
     pub fn move_spaces(&mut self, turtl: &Turtl, new_space_id: String) -> TResult<()> {
         self.space_id = new_space_id;
@@ -85,6 +208,78 @@ impl Note {
         Ok(())
     }
 
+    /// Copy a note (and its file, if it has one) into a new note with its own
+    /// key, in the same space/board or wherever `options` says. Returns the
+    /// new note's data.
+    pub fn duplicate(turtl: &Turtl, note_id: &String, options: &DuplicateOptions) -> TResult<Value> {
+        let mut notes = turtl.load_notes(&vec![note_id.clone()])?;
+        if notes.len() == 0 {
+            return TErr!(TError::NotFound(format!("note {} wasn't found", note_id)));
+        }
+        let note = notes.remove(0);
+        let mut copy = Note::new();
+        copy.user_id = turtl.user_id()?;
+        copy.space_id = options.space_id.clone().unwrap_or_else(|| note.space_id.clone());
+        copy.board_id = if options.board_id.is_some() || copy.space_id != note.space_id {
+            options.board_id.clone()
+        } else {
+            note.board_id.clone()
+        };
+        Space::permission_check(turtl, &copy.space_id, &Permission::AddNote)?;
+        if let Some(board_id) = copy.board_id.as_ref() {
+            if Board::get_space_id(turtl, board_id).as_ref() != Some(&copy.space_id) {
+                return TErr!(TError::BadValue(format!("board {} is not in space {}", board_id, copy.space_id)));
+            }
+        }
+        copy.pinned = note.pinned.clone();
+        copy.type_ = note.type_.clone();
+        copy.title = note.title.clone();
+        copy.tags = note.tags.clone();
+        copy.url = note.url.clone();
+        copy.username = note.username.clone();
+        copy.password = note.password.clone();
+        copy.text = note.text.clone();
+        copy.items = note.items.clone();
+        copy.fields = note.fields.clone();
+        copy.embed = note.embed.clone();
+        copy.color = note.color.clone();
+        copy.mod_ = Some(time::get_time().sec as i64);
+
+        // the file gets re-encrypted for the new note, so we need it on disk
+        let filedata = match note.file.as_ref() {
+            Some(file) if note.has_file => {
+                let data = match FileData::load_file(turtl, &note) {
+                    Ok(x) => x,
+                    Err(e) => return TErr!(TError::MissingData(format!("the file for note {} isn't available ({}). try again once it has downloaded.", note_id, e))),
+                };
+                let mut copy_file = File::new();
+                copy_file.size = file.size.clone();
+                copy_file.name = file.name.clone();
+                copy_file.ty = file.ty.clone();
+                copy_file.meta = file.meta.clone();
+                copy.file = Some(copy_file);
+                let mut filedata = FileData::new();
+                filedata.data = Some(data);
+                Some(filedata)
+            }
+            _ => None,
+        };
+        Note::save_new(turtl, &mut copy, filedata)
+    }
+
+    /// Save a new note, along with its file (if it has one). Returns the saved
+    /// note's data.
+    pub fn save_new(turtl: &Turtl, note: &mut Note, filedata: Option<FileData>) -> TResult<Value> {
+        if let Some(filedata) = filedata.as_ref() {
+            filedata.prepare(turtl, note)?;
+        }
+        let note_data = sync_model::save_model(SyncAction::Add, turtl, note, false)?;
+        if let Some(mut filedata) = filedata {
+            filedata.save(turtl, note)?;
+        }
+        Ok(note_data)
+    }
+
     /// Given a Turtl/note_id, grab that note's space_id (if it exists)
     pub fn get_space_id(turtl: &Turtl, note_id: &String) -> Option<String> {
         let mut db_guard = lock!(turtl.db);
@@ -134,6 +329,12 @@ impl Keyfinder for Note {
         if board_ids.len() > 0 {
             let ty = String::from("board");
             let profile_guard = lockr!(turtl.profile);
+            // boards above ours can open the note too
+            if let Some(board_id) = self.board_id.as_ref() {
+                for parent_id in board::ancestors(&profile_guard.boards, board_id) {
+                    if !board_ids.contains(&parent_id) { board_ids.push(parent_id); }
+                }
+            }
             for board in &profile_guard.boards {
                 if board.id().is_none() || board.key().is_none() { continue; }
                 let board_id = board.id().expect("turtl::Note::get_key_search() -- board.id() is none");
@@ -159,13 +360,17 @@ impl Keyfinder for Note {
 
         match self.board_id {
             Some(ref board_id) => {
-                for board in &profile_guard.boards {
-                    if board.id() == Some(board_id) && board.key().is_some() {
-                        refs.push(KeyRef {
-                            id: board_id.clone(),
-                            ty: KeyType::Board,
-                            k: board.key().expect("turtl::Note::get_keyrefs() -- board.key() is None GAHHH TIMMY").clone(),
-                        });
+                let mut board_ids = board::ancestors(&profile_guard.boards, board_id);
+                board_ids.insert(0, board_id.clone());
+                for board_id in &board_ids {
+                    for board in &profile_guard.boards {
+                        if board.id() == Some(board_id) && board.key().is_some() {
+                            refs.push(KeyRef {
+                                id: board_id.clone(),
+                                ty: KeyType::Board,
+                                k: board.key().expect("turtl::Note::get_keyrefs() -- board.key() is None GAHHH TIMMY").clone(),
+                            });
+                        }
                     }
                 }
             },
@@ -175,10 +380,19 @@ impl Keyfinder for Note {
     }
 }
 
+/// Note fields the search index doesn't look at. Changing only these doesn't
+/// need a reindex.
+const UNINDEXED_FIELDS: &'static [&'static str] = &["user_id", "username", "password", "embed", "reminder_at"];
+
 impl MemorySaver for Note {
     // reindex note on add/update (reindex is idempotent)
     fn mem_update(self, turtl: &Turtl, sync_item: &mut SyncRecord) -> TResult<()> {
         let action = sync_item.action.clone();
+        // whatever we had decrypted for this note is out of date now
+        if let Some(note_id) = self.id() {
+            let profile_guard = lockr!(turtl.profile);
+            lock!(profile_guard.notes).remove(note_id);
+        }
         match action {
             SyncAction::Add | SyncAction::Edit | SyncAction::MoveSpace => {
                 let note_id = match self.id() {
@@ -186,26 +400,39 @@ impl MemorySaver for Note {
                     // silent fail
                     None => return Ok(()),
                 };
-                let notes = turtl.load_notes(&vec![note_id])?;
+                // a note with a snapshot is one we just saved (decrypted), so
+                // we can use it as-is and skip the reindex if nothing the
+                // index cares about changed. anything else (say, an incoming
+                // sync) gets loaded and reindexed.
+                let mut reindex = true;
+                let notes = if self.get_snapshot().is_some() && !self.lazy_pending() {
+                    reindex = action != SyncAction::Edit || self.changed_fields()?.iter()
+                        .any(|x| !UNINDEXED_FIELDS.contains(&x.as_str()));
+                    let mut cached = self.clone()?;
+                    cached.mark_clean()?;
+                    let profile_guard = lockr!(turtl.profile);
+                    lock!(profile_guard.notes).put(note_id, cached);
+                    vec![self]
+                } else {
+                    turtl.load_notes(&vec![note_id])?
+                };
                 if notes.len() == 0 { return Ok(()); }
                 let note = &notes[0];
                 sync_item.data = Some(note.data()?);
-                let mut search_guard = lock!(turtl.search);
-                match search_guard.as_mut() {
-                    Some(ref mut search) => {
-                        search.reindex_note(note)?;
-                    }
-                    // i COULD throw an error here. i'm choosing not to...
-                    None => {}
+                with_db!{ db, turtl.db, reminders::update_note(db, note)? };
+                if reindex {
+                    turtl.reindex_note(note)?;
+                } else {
+                    // the index is fine as it is, but the note's saved entry
+                    // needs to know this version of the note is covered
+                    turtl.save_index_entry(note)?;
                 }
             }
             SyncAction::Delete => {
-                let mut search_guard = lock!(turtl.search);
-                match search_guard.as_mut() {
-                    Some(ref mut search) => search.unindex_note(&self)?,
-                    // i COULD throw an error here. i'm choosing not to...
-                    None => {},
-                };
+                if let Some(note_id) = self.id() {
+                    with_db!{ db, turtl.db, reminders::update(db, note_id, None)? };
+                }
+                turtl.unindex_note(&self)?;
 
                 self.clear_files()?;
             }
@@ -0,0 +1,201 @@
+//! Runs one operation (move, retag, delete) over a whole list of notes. Doing
+//! this one note at a time from the UI means a messaging round trip, a key
+//! search, and a db write per note, which gets painful with a few hundred of
+//! them. Here the notes are loaded (and their keys found) together, checked
+//! against the permissions of each space they're in, and saved as one batch
+//! (see `sync_model::save_models()`).
+
+use ::std::collections::HashSet;
+use ::error::{TResult, TError};
+use ::messaging;
+use ::models::model::Model;
+use ::models::note::Note;
+use ::models::board::Board;
+use ::models::space::Space;
+use ::models::sync_record::SyncAction;
+use ::models::tag;
+use ::models::trash;
+use ::lib_permissions::Permission;
+use ::sync::sync_model;
+use ::turtl::Turtl;
+use ::util;
+
+/// What to do to the notes
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "op")]
+pub enum BatchOp {
+    /// Move the notes to a board in their space (or out of their board)
+    #[serde(rename = "move-board")]
+    MoveBoard {
+        board_id: Option<String>,
+    },
+    /// Move the notes to another space, optionally into a board there
+    #[serde(rename = "move-space")]
+    MoveSpace {
+        space_id: String,
+        board_id: Option<String>,
+    },
+    #[serde(rename = "add-tag")]
+    AddTag {
+        tag: String,
+    },
+    #[serde(rename = "remove-tag")]
+    RemoveTag {
+        tag: String,
+    },
+    /// Delete the notes (to the trash, if it's on, unless `permanent`)
+    #[serde(rename = "delete")]
+    Delete {
+        #[serde(default)]
+        permanent: bool,
+    },
+}
+
+/// How a batch went. This is also what we send to the UI (as a
+/// `notes:batch:progress` event) once the batch is done.
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct BatchResult {
+    /// How many notes we were asked to work on
+    pub total: usize,
+    /// How many notes changed
+    pub changed: usize,
+    /// Notes we couldn't load (or that didn't need changing)
+    pub skipped: Vec<String>,
+}
+
+/// Make sure a board exists and lives in the given space
+fn check_board(turtl: &Turtl, board_id: &String, space_id: &String) -> TResult<()> {
+    match Board::get_space_id(turtl, board_id) {
+        Some(ref x) if x == space_id => Ok(()),
+        Some(_) => TErr!(TError::BadValue(format!("board {} is not in space {}", board_id, space_id))),
+        None => TErr!(TError::NotFound(format!("board {} wasn't found", board_id))),
+    }
+}
+
+/// Apply an op to a note. Returns false if the note doesn't need changing.
+fn apply(op: &BatchOp, note: &mut Note, now: i64) -> bool {
+    match *op {
+        BatchOp::MoveBoard { ref board_id } => {
+            if &note.board_id == board_id { return false; }
+            note.board_id = board_id.clone();
+        }
+        BatchOp::MoveSpace { ref space_id, ref board_id } => {
+            if &note.space_id == space_id && &note.board_id == board_id { return false; }
+            note.space_id = space_id.clone();
+            note.board_id = board_id.clone();
+        }
+        BatchOp::AddTag { ref tag } => {
+            let mut tags = note.tags.clone().unwrap_or_else(|| Vec::new());
+            if tags.contains(tag) { return false; }
+            tags.push(tag.clone());
+            note.tags = Some(tags);
+        }
+        BatchOp::RemoveTag { ref tag } => {
+            let tags = match note.tags.as_ref().and_then(|x| tag::retag(x, &vec![tag.clone()], None)) {
+                Some(x) => x,
+                None => return false,
+            };
+            note.tags = Some(tags);
+        }
+        BatchOp::Delete { .. } => {
+            if note.trashed.is_some() { return false; }
+            note.trashed = Some(now);
+        }
+    }
+    note.mod_ = Some(now);
+    true
+}
+
+/// Run an op over a set of notes
+pub fn run(turtl: &Turtl, note_ids: &Vec<String>, op: &BatchOp) -> TResult<BatchResult> {
+    let mut result = BatchResult { total: note_ids.len(), ..Default::default() };
+    let notes = turtl.load_notes(note_ids)?;
+    let loaded: HashSet<String> = notes.iter()
+        .filter_map(|x| x.id().map(|id| id.clone()))
+        .collect();
+    for id in note_ids {
+        if !loaded.contains(id) { result.skipped.push(id.clone()); }
+    }
+
+    // check permissions once per space, not once per note
+    let mut space_ids: Vec<String> = Vec::new();
+    for note in &notes {
+        if !space_ids.contains(&note.space_id) { space_ids.push(note.space_id.clone()); }
+    }
+    let permission = match *op {
+        BatchOp::MoveSpace { .. } | BatchOp::Delete { .. } => Permission::DeleteNote,
+        _ => Permission::EditNote,
+    };
+    for space_id in &space_ids {
+        Space::permission_check(turtl, space_id, &permission)?;
+        if let BatchOp::MoveBoard { board_id: Some(ref board_id) } = *op {
+            check_board(turtl, board_id, space_id)?;
+        }
+    }
+    if let BatchOp::MoveSpace { ref space_id, ref board_id } = *op {
+        Space::permission_check(turtl, space_id, &Permission::AddNote)?;
+        if let Some(ref board_id) = *board_id {
+            check_board(turtl, board_id, space_id)?;
+        }
+    }
+
+    // already-trashed notes get deleted for good, same as deleting them one
+    // at a time
+    let to_trash = match *op {
+        BatchOp::Delete { permanent } => trash::enabled() && !permanent,
+        _ => true,
+    };
+    let now = util::now_ms() / 1000;
+    let mut changed: Vec<Note> = Vec::with_capacity(notes.len());
+    for mut note in notes {
+        let note_id = note.id_or_else()?;
+        match *op {
+            BatchOp::Delete { .. } if !to_trash || note.trashed.is_some() => {
+                sync_model::delete_model::<Note>(turtl, &note_id, false)?;
+                result.changed += 1;
+                continue;
+            }
+            _ => {}
+        }
+        if apply(op, &mut note, now) {
+            changed.push(note);
+        } else {
+            result.skipped.push(note_id);
+        }
+    }
+    result.changed += changed.len();
+    let action = match *op {
+        BatchOp::MoveSpace { .. } => SyncAction::MoveSpace,
+        _ => SyncAction::Edit,
+    };
+    sync_model::save_models(action, turtl, changed, false)?;
+    messaging::ui_event("notes:batch:progress", &result)?;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::jedi;
+
+    #[test]
+    fn applies_ops() {
+        let mut note: Note = jedi::parse(&String::from(r#"{"id":"n1","user_id":1,"space_id":"s1","board_id":"b1","tags":["work"]}"#)).unwrap();
+        let op: BatchOp = jedi::parse(&String::from(r#"{"op":"add-tag","tag":"work"}"#)).unwrap();
+        assert!(!apply(&op, &mut note, 10));
+        let op: BatchOp = jedi::parse(&String::from(r#"{"op":"add-tag","tag":"todo"}"#)).unwrap();
+        assert!(apply(&op, &mut note, 10));
+        assert_eq!(note.tags, Some(vec![String::from("work"), String::from("todo")]));
+        assert_eq!(note.mod_, Some(10));
+
+        let op: BatchOp = jedi::parse(&String::from(r#"{"op":"move-space","space_id":"s2"}"#)).unwrap();
+        assert!(apply(&op, &mut note, 11));
+        assert_eq!(note.space_id, "s2");
+        assert_eq!(note.board_id, None);
+
+        let op: BatchOp = jedi::parse(&String::from(r#"{"op":"delete"}"#)).unwrap();
+        assert_eq!(op, BatchOp::Delete { permanent: false });
+        assert!(apply(&op, &mut note, 12));
+        assert_eq!(note.trashed, Some(12));
+    }
+}
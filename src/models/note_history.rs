@@ -0,0 +1,172 @@
+//! Keeps the last few versions of each note around locally, so an accidental
+//! overwrite (ours or one that came in from another device) isn't the end of
+//! the world.
+//!
+//! Whenever a note is saved to the db, the version it replaces gets stashed
+//! (still encrypted, as it sat in the db) in the kv store. We keep the last
+//! `notes.history.keep` of these per note (0 turns history off) and drop them
+//! when the note is deleted.
+//!
+//! Restoring a version saves its contents over the current note as a normal
+//! edit, so it syncs out like any other change (and the version we restored
+//! over goes into the history in turn).
+
+use ::jedi::{self, Value};
+use ::config;
+use ::error::{TResult, TError};
+use ::storage::Storage;
+use ::models::model::{self, Model};
+use ::models::protected::Protected;
+use ::models::storable::Storable;
+use ::models::note::Note;
+use ::models::sync_record::SyncAction;
+use ::sync::sync_model;
+use ::turtl::Turtl;
+use ::util;
+
+/// A stashed version of a note
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Revision {
+    pub id: String,
+    /// When (unix ms) this version was replaced
+    pub created: i64,
+    /// The note's (encrypted) data
+    pub data: Value,
+}
+
+/// What we hand the UI when listing a note's history
+#[derive(Serialize, Debug)]
+pub struct NoteRevision {
+    pub id: String,
+    pub created: i64,
+    /// The (decrypted) note as it was
+    pub note: Note,
+}
+
+/// How many versions we keep per note
+fn keep() -> usize {
+    config::get(&["notes", "history", "keep"]).unwrap_or(10)
+}
+
+/// The kv key we keep a note's history under
+fn history_key(note_id: &String) -> String {
+    format!("notes:history:{}", note_id)
+}
+
+/// Grab a note's (encrypted) history, newest first
+pub fn get(db: &Storage, note_id: &String) -> TResult<Vec<Revision>> {
+    match db.kv_get(&history_key(note_id))? {
+        Some(x) => Ok(jedi::parse(&x)?),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Add a revision to the front of a history, dropping any beyond `keep`
+fn push(history: &mut Vec<Revision>, revision: Revision, keep: usize) {
+    history.insert(0, revision);
+    history.truncate(keep);
+}
+
+/// Stash the db's current version of a note we're about to save over. Does
+/// nothing if it's a new note or the body hasn't changed.
+pub fn record(db: &Storage, note: &Note) -> TResult<()> {
+    let keep = keep();
+    if keep == 0 { return Ok(()); }
+    let note_id = note.id_or_else()?;
+    let existing: Note = match db.get(note.table(), &note_id)? {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    if existing.get_body().is_none() { return Ok(()); }
+    if existing.get_body() == note.get_body() && existing.get_lazy_body() == note.get_lazy_body() { return Ok(()); }
+    let mut history = get(db, &note_id)?;
+    let revision = Revision {
+        id: model::cid()?,
+        created: util::now_ms(),
+        data: existing.data_for_storage()?,
+    };
+    push(&mut history, revision, keep);
+    db.kv_set(&history_key(&note_id), &jedi::stringify(&history)?)
+}
+
+/// Forget a note's history
+pub fn clear(db: &Storage, note_id: &String) -> TResult<()> {
+    db.kv_delete(&history_key(note_id))
+}
+
+/// Decrypt a revision
+fn open(turtl: &Turtl, revision: &Revision) -> TResult<Note> {
+    let mut note: Note = jedi::from_val(revision.data.clone())?;
+    turtl.find_model_key(&mut note)?;
+    note.deserialize()?;
+    Ok(note)
+}
+
+/// List a note's history (decrypted), newest first. Versions we can't decrypt
+/// (say, ones from before we lost access to a board) are skipped.
+pub fn list(turtl: &Turtl, note_id: &String) -> TResult<Vec<NoteRevision>> {
+    let history = with_db!{ db, turtl.db, get(db, note_id)? };
+    let mut revisions = Vec::with_capacity(history.len());
+    for revision in history {
+        match open(turtl, &revision) {
+            Ok(note) => {
+                revisions.push(NoteRevision {
+                    id: revision.id,
+                    created: revision.created,
+                    note: note,
+                });
+            }
+            Err(e) => {
+                warn!("note_history::list() -- couldn't open revision {} of note {}: {}", revision.id, note_id, e);
+            }
+        }
+    }
+    Ok(revisions)
+}
+
+/// Save a revision's contents over the current note. The note stays where it
+/// is (space/board) and keeps its current file. Returns the saved note data.
+pub fn restore(turtl: &Turtl, note_id: &String, revision_id: &String) -> TResult<Value> {
+    let revision = {
+        let history = with_db!{ db, turtl.db, get(db, note_id)? };
+        match history.into_iter().find(|x| &x.id == revision_id) {
+            Some(x) => x,
+            None => return TErr!(TError::NotFound(format!("revision {} of note {} wasn't found", revision_id, note_id))),
+        }
+    };
+    let old = open(turtl, &revision)?;
+    let mut notes = turtl.load_notes(&vec![note_id.clone()])?;
+    if notes.len() == 0 {
+        return TErr!(TError::NotFound(format!("note {} wasn't found", note_id)));
+    }
+    let mut note = notes.remove(0);
+    note.type_ = old.type_;
+    note.title = old.title;
+    note.tags = old.tags;
+    note.url = old.url;
+    note.username = old.username;
+    note.password = old.password;
+    note.text = old.text;
+    note.embed = old.embed;
+    note.color = old.color;
+    note.mod_ = Some(util::now_ms() / 1000);
+    sync_model::save_model(SyncAction::Edit, turtl, &mut note, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rev(id: &str) -> Revision {
+        Revision { id: String::from(id), created: 0, data: json!({"id": "n1"}) }
+    }
+
+    #[test]
+    fn keeps_newest_revisions() {
+        let mut history = Vec::new();
+        push(&mut history, rev("1"), 2);
+        push(&mut history, rev("2"), 2);
+        push(&mut history, rev("3"), 2);
+        assert_eq!(history.iter().map(|x| x.id.as_str()).collect::<Vec<_>>(), vec!["3", "2"]);
+    }
+}
@@ -0,0 +1,327 @@
+//! Keeps running totals (counts, sizes, dates) for the notes in each space, so
+//! the `profile:stats` command can answer without decrypting every note.
+//!
+//! The totals live in the kv store and are updated whenever a note is saved to
+//! or deleted from the db (the same hooks `note_history` uses), from the
+//! note's public fields and encrypted body only. If they're missing (say, on a
+//! profile from before we kept them) they're built once from the db. Tag
+//! counts come from the search index, which already holds the decrypted tags.
+
+use ::std::collections::HashMap;
+use ::jedi;
+use ::error::{TResult, TError};
+use ::storage::Storage;
+use ::models::model::Model;
+use ::models::protected::Protected;
+use ::models::storable::Storable;
+use ::models::note::Note;
+use ::search::Query;
+use ::turtl::Turtl;
+
+/// The kv key we keep the totals under
+const STATS_KEY: &'static str = "notes:stats";
+
+/// Running totals for the notes in one space
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Tally {
+    pub notes: u64,
+    /// How many of the notes are in the trash
+    pub trashed: u64,
+    /// How many of the notes have a file
+    pub files: u64,
+    /// The size of the notes' (encrypted) data
+    pub bytes: u64,
+    /// The size of the notes' files
+    pub file_bytes: u64,
+    /// The oldest/newest mod time (unix seconds) of the notes
+    pub oldest: Option<i64>,
+    pub newest: Option<i64>,
+    /// Set when a note on the edge of oldest/newest goes away, meaning we need
+    /// to look at the notes again to find the new edges
+    #[serde(default)]
+    pub stale_range: bool,
+}
+
+impl Tally {
+    /// Count a note
+    fn add(&mut self, note: &Note) {
+        self.notes += 1;
+        if note.trashed.is_some() { self.trashed += 1; }
+        if note.has_file { self.files += 1; }
+        self.bytes += body_size(note);
+        self.file_bytes += file_size(note);
+        if let Some(mod_) = note.mod_ {
+            if self.oldest.map(|x| mod_ < x).unwrap_or(true) { self.oldest = Some(mod_); }
+            if self.newest.map(|x| mod_ > x).unwrap_or(true) { self.newest = Some(mod_); }
+        }
+    }
+
+    /// Stop counting a note
+    fn remove(&mut self, note: &Note) {
+        fn sub(val: u64, by: u64) -> u64 {
+            if by > val { 0 } else { val - by }
+        }
+        self.notes = sub(self.notes, 1);
+        if note.trashed.is_some() { self.trashed = sub(self.trashed, 1); }
+        if note.has_file { self.files = sub(self.files, 1); }
+        self.bytes = sub(self.bytes, body_size(note));
+        self.file_bytes = sub(self.file_bytes, file_size(note));
+        if self.notes == 0 {
+            self.oldest = None;
+            self.newest = None;
+            self.stale_range = false;
+        } else if note.mod_.is_some() && (note.mod_ == self.oldest || note.mod_ == self.newest) {
+            self.stale_range = true;
+        }
+    }
+}
+
+/// How many bytes a note's encrypted body (and lazy body) take up
+fn body_size(note: &Note) -> u64 {
+    note.get_body().map(|x| x.len() as u64).unwrap_or(0) +
+        note.get_lazy_body().map(|x| x.len() as u64).unwrap_or(0)
+}
+
+/// How big a note's file is (if it has one)
+fn file_size(note: &Note) -> u64 {
+    if !note.has_file { return 0; }
+    note.file.as_ref().and_then(|x| x.size).unwrap_or(0)
+}
+
+/// Grab the totals, if we've got them
+fn load(db: &Storage) -> TResult<Option<HashMap<String, Tally>>> {
+    match db.kv_get(STATS_KEY)? {
+        Some(x) => Ok(Some(jedi::parse(&x)?)),
+        None => Ok(None),
+    }
+}
+
+fn store(db: &Storage, tallies: &HashMap<String, Tally>) -> TResult<()> {
+    db.kv_set(STATS_KEY, &jedi::stringify(tallies)?)
+}
+
+/// Build the totals from scratch from the notes in the db
+fn rebuild(db: &Storage) -> TResult<HashMap<String, Tally>> {
+    let notes: Vec<Note> = db.all(Note::tablename())?;
+    let mut tallies: HashMap<String, Tally> = HashMap::new();
+    for note in &notes {
+        tallies.entry(note.space_id.clone()).or_insert_with(|| Default::default()).add(note);
+    }
+    store(db, &tallies)?;
+    Ok(tallies)
+}
+
+/// Find the oldest/newest notes again for any space that needs it
+fn fix_ranges(db: &Storage, tallies: &mut HashMap<String, Tally>) -> TResult<bool> {
+    let mut fixed = false;
+    for (space_id, tally) in tallies.iter_mut() {
+        if !tally.stale_range { continue; }
+        let notes: Vec<Note> = db.find(Note::tablename(), "space_id", &vec![space_id.clone()])?;
+        let mods = notes.iter().filter_map(|x| x.mod_).collect::<Vec<_>>();
+        tally.oldest = mods.iter().min().map(|x| *x);
+        tally.newest = mods.iter().max().map(|x| *x);
+        tally.stale_range = false;
+        fixed = true;
+    }
+    Ok(fixed)
+}
+
+/// Update the totals for a note we're about to save to the db
+pub fn record_save(db: &Storage, note: &Note) -> TResult<()> {
+    // no totals yet: they'll be built (with this note in them) when needed
+    let mut tallies = match load(db)? {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    let existing: Option<Note> = db.get(note.table(), &note.id_or_else()?)?;
+    if let Some(existing) = existing {
+        if let Some(tally) = tallies.get_mut(&existing.space_id) {
+            tally.remove(&existing);
+        }
+    }
+    tallies.entry(note.space_id.clone()).or_insert_with(|| Default::default()).add(note);
+    store(db, &tallies)
+}
+
+/// Update the totals for a note we're about to delete from the db
+pub fn record_delete(db: &Storage, note_id: &String) -> TResult<()> {
+    let mut tallies = match load(db)? {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    let existing: Note = match db.get(Note::tablename(), note_id)? {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    let empty = match tallies.get_mut(&existing.space_id) {
+        Some(tally) => {
+            tally.remove(&existing);
+            tally.notes == 0
+        }
+        None => false,
+    };
+    if empty { tallies.remove(&existing.space_id); }
+    store(db, &tallies)
+}
+
+/// Stats for one space
+#[derive(Serialize, Debug, Default)]
+pub struct SpaceStats {
+    pub space_id: String,
+    pub title: Option<String>,
+    pub boards: u64,
+    pub notes: u64,
+    pub trashed: u64,
+    pub files: u64,
+    pub bytes: u64,
+    pub file_bytes: u64,
+    pub oldest: Option<i64>,
+    pub newest: Option<i64>,
+}
+
+/// How often a tag is used
+#[derive(Serialize, Debug, PartialEq)]
+pub struct TagCount {
+    pub tag: String,
+    pub count: u64,
+}
+
+/// Stats for the whole profile
+#[derive(Serialize, Debug, Default)]
+pub struct ProfileStats {
+    pub spaces: u64,
+    pub boards: u64,
+    pub notes: u64,
+    pub trashed: u64,
+    pub files: u64,
+    pub bytes: u64,
+    pub file_bytes: u64,
+    pub oldest: Option<i64>,
+    pub newest: Option<i64>,
+    pub per_space: Vec<SpaceStats>,
+    /// Tags (on notes that aren't trashed), most used first
+    pub tags: Vec<TagCount>,
+}
+
+/// Count up the tags in the given spaces via the search index
+fn count_tags(turtl: &Turtl, space_ids: &Vec<String>) -> TResult<Vec<TagCount>> {
+    for space_id in space_ids {
+        turtl.index_space(space_id)?;
+    }
+    let search_guard = lock!(turtl.search);
+    let search = match search_guard.as_ref() {
+        Some(x) => x,
+        None => return TErr!(TError::MissingField(format!("turtl is missing `search` object"))),
+    };
+    let mut counts: HashMap<String, u64> = HashMap::new();
+    for space_id in space_ids {
+        let qry: Query = jedi::from_val(json!({"space_id": space_id}))?;
+        for (tag, count) in search.find_tags(&qry)? {
+            *counts.entry(tag).or_insert(0) += count as u64;
+        }
+    }
+    Ok(sort_tags(counts))
+}
+
+/// Most used first (then by name)
+fn sort_tags(counts: HashMap<String, u64>) -> Vec<TagCount> {
+    let mut tags = counts.into_iter()
+        .map(|(tag, count)| TagCount { tag: tag, count: count })
+        .collect::<Vec<_>>();
+    tags.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.tag.cmp(&b.tag)));
+    tags
+}
+
+/// Gather up the profile's stats
+pub fn stats(turtl: &Turtl) -> TResult<ProfileStats> {
+    let tallies = with_db!{ db, turtl.db,
+        let mut tallies = match load(db)? {
+            Some(x) => x,
+            None => rebuild(db)?,
+        };
+        if fix_ranges(db, &mut tallies)? { store(db, &tallies)?; }
+        tallies
+    };
+    let mut stats = ProfileStats::default();
+    {
+        let profile_guard = lockr!(turtl.profile);
+        for space in &profile_guard.spaces {
+            let space_id = match space.id() {
+                Some(x) => x.clone(),
+                None => continue,
+            };
+            let tally = tallies.get(&space_id).map(|x| x.clone()).unwrap_or_else(|| Default::default());
+            let boards = profile_guard.boards.iter()
+                .filter(|x| x.space_id == space_id)
+                .count() as u64;
+            stats.spaces += 1;
+            stats.boards += boards;
+            stats.notes += tally.notes;
+            stats.trashed += tally.trashed;
+            stats.files += tally.files;
+            stats.bytes += tally.bytes;
+            stats.file_bytes += tally.file_bytes;
+            if tally.oldest.is_some() && (stats.oldest.is_none() || tally.oldest < stats.oldest) { stats.oldest = tally.oldest; }
+            if tally.newest > stats.newest { stats.newest = tally.newest; }
+            stats.per_space.push(SpaceStats {
+                space_id: space_id,
+                title: space.title.clone(),
+                boards: boards,
+                notes: tally.notes,
+                trashed: tally.trashed,
+                files: tally.files,
+                bytes: tally.bytes,
+                file_bytes: tally.file_bytes,
+                oldest: tally.oldest,
+                newest: tally.newest,
+            });
+        }
+    }
+    let space_ids = stats.per_space.iter().map(|x| x.space_id.clone()).collect::<Vec<_>>();
+    stats.tags = count_tags(turtl, &space_ids)?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(json: &str) -> Note {
+        jedi::parse(&String::from(json)).unwrap()
+    }
+
+    #[test]
+    fn tallies_notes() {
+        let note1 = note(r#"{"id":"n1","user_id":1,"space_id":"s1","mod":100,"body":"abcd"}"#);
+        let note2 = note(r#"{"id":"n2","user_id":1,"space_id":"s1","mod":200,"body":"abcdefgh","has_file":true,"file":{"size":1000}}"#);
+        let note3 = note(r#"{"id":"n3","user_id":1,"space_id":"s1","mod":150,"body":"ab","trashed":160}"#);
+        let mut tally = Tally::default();
+        tally.add(&note1);
+        tally.add(&note2);
+        tally.add(&note3);
+        assert_eq!(tally, Tally {
+            notes: 3,
+            trashed: 1,
+            files: 1,
+            bytes: 14,
+            file_bytes: 1000,
+            oldest: Some(100),
+            newest: Some(200),
+            stale_range: false,
+        });
+
+        tally.remove(&note3);
+        assert_eq!((tally.notes, tally.trashed, tally.bytes, tally.stale_range), (2, 0, 12, false));
+        tally.remove(&note2);
+        assert_eq!((tally.notes, tally.files, tally.file_bytes, tally.stale_range), (1, 0, 0, true));
+        tally.remove(&note1);
+        assert_eq!(tally, Tally::default());
+
+        let mut counts = HashMap::new();
+        counts.insert(String::from("work"), 2);
+        counts.insert(String::from("home"), 5);
+        counts.insert(String::from("art"), 2);
+        let tags = sort_tags(counts).into_iter().map(|x| x.tag).collect::<Vec<_>>();
+        assert_eq!(tags, vec!["home", "art", "work"]);
+    }
+}
@@ -0,0 +1,126 @@
+//! Custom ordering for boards and notes. Each one can have a `position` (a
+//! number), and items are listed by position, with anything that doesn't have
+//! one yet coming after, oldest first.
+//!
+//! Moving an item gives it a position halfway between its new neighbors, so a
+//! move is usually a single edit that syncs like any other. Only when there's
+//! no room left between two neighbors (or the list has never been ordered) do
+//! we number the whole list over again.
+
+use ::std::cmp::Ordering;
+use ::error::{TResult, TError};
+
+/// How far apart we space positions when numbering a list
+const STEP: f64 = 1024.0;
+
+/// Neighbors closer than this get the whole list renumbered instead
+const MIN_GAP: f64 = 0.000001;
+
+/// Sort a list of `(id, position)` pairs into display order
+pub fn sort(items: &mut Vec<(String, Option<f64>)>) {
+    items.sort_by(|a, b| {
+        match (a.1, b.1) {
+            (Some(x), Some(y)) => x.partial_cmp(&y).unwrap_or(Ordering::Equal).then_with(|| a.0.cmp(&b.0)),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => a.0.cmp(&b.0),
+        }
+    });
+}
+
+/// Figure out the new positions for moving `item_id` to just after `after_id`
+/// (or to the top, if None) among `siblings` (`(id, position)` pairs, which
+/// may or may not include the item itself). Returns the items whose position
+/// changes, along with their new positions.
+pub fn place(siblings: &Vec<(String, Option<f64>)>, item_id: &String, after_id: Option<&String>) -> TResult<Vec<(String, f64)>> {
+    if after_id == Some(item_id) {
+        return TErr!(TError::BadValue(format!("can't put {} after itself", item_id)));
+    }
+    let mut list = siblings.iter()
+        .filter(|x| &x.0 != item_id)
+        .map(|x| x.clone())
+        .collect::<Vec<_>>();
+    sort(&mut list);
+    let idx = match after_id {
+        Some(after_id) => {
+            match list.iter().position(|x| &x.0 == after_id) {
+                Some(x) => x + 1,
+                None => return TErr!(TError::NotFound(format!("{} isn't next to {}", after_id, item_id))),
+            }
+        }
+        None => 0,
+    };
+
+    if list.iter().all(|x| x.1.is_some()) {
+        let prev = if idx > 0 { list[idx - 1].1 } else { None };
+        let next = list.get(idx).and_then(|x| x.1);
+        let pos = match (prev, next) {
+            (None, None) => Some(STEP),
+            (Some(p), None) => Some(p + STEP),
+            (None, Some(n)) => Some(n - STEP),
+            (Some(p), Some(n)) if n - p > MIN_GAP => Some(p + ((n - p) / 2.0)),
+            _ => None,
+        };
+        if let Some(pos) = pos {
+            return Ok(vec![(item_id.clone(), pos)]);
+        }
+    }
+
+    // no room (or no order to begin with), so number everything again
+    let current = siblings.iter()
+        .find(|x| &x.0 == item_id)
+        .and_then(|x| x.1);
+    list.insert(idx, (item_id.clone(), current));
+    Ok(list.into_iter()
+        .enumerate()
+        .filter_map(|(i, (id, pos))| {
+            let new_pos = STEP * ((i + 1) as f64);
+            if pos == Some(new_pos) { None } else { Some((id, new_pos)) }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn items(list: Vec<(&str, Option<f64>)>) -> Vec<(String, Option<f64>)> {
+        list.into_iter().map(|(id, pos)| (String::from(id), pos)).collect()
+    }
+
+    #[test]
+    fn sorts_by_position() {
+        let mut list = items(vec![("c", None), ("b", Some(2.0)), ("a", None), ("d", Some(1.0))]);
+        sort(&mut list);
+        let ids = list.iter().map(|x| x.0.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids, vec!["d", "b", "a", "c"]);
+    }
+
+    #[test]
+    fn places_items() {
+        let list = items(vec![("a", Some(1024.0)), ("b", Some(2048.0)), ("c", Some(3072.0))]);
+        let c = String::from("c");
+        let a = String::from("a");
+        let b = String::from("b");
+        assert_eq!(place(&list, &c, Some(&a)).unwrap(), vec![(c.clone(), 1536.0)]);
+        assert_eq!(place(&list, &c, None).unwrap(), vec![(c.clone(), 0.0)]);
+        assert_eq!(place(&list, &a, Some(&c)).unwrap(), vec![(a.clone(), 4096.0)]);
+        assert!(place(&list, &a, Some(&a)).is_err());
+        assert!(place(&list, &a, Some(&String::from("z"))).is_err());
+
+        // nothing's been ordered yet, so everything gets a position
+        let list = items(vec![("a", None), ("b", None), ("c", None)]);
+        assert_eq!(place(&list, &c, Some(&a)).unwrap(), vec![
+            (a.clone(), 1024.0),
+            (c.clone(), 2048.0),
+            (b.clone(), 3072.0),
+        ]);
+
+        // no room left between a and b
+        let list = items(vec![("a", Some(1024.0)), ("b", Some(1024.0000001)), ("c", Some(3072.0))]);
+        assert_eq!(place(&list, &c, Some(&a)).unwrap(), vec![
+            (c.clone(), 2048.0),
+            (b.clone(), 3072.0),
+        ]);
+    }
+}
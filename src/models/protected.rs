@@ -13,6 +13,9 @@
 //! a `key` set, which is used as the key for cryptographic operations.
 //! - Finding a matching key for an object either from a sibling/parent object
 //! or from the current user's keychain.
+//! - Change tracking. A model remembers what it looked like when it was last
+//! decrypted or saved (see `mark_clean()`), so it can tell which of its fields
+//! have changed since and only re-encrypt its body if it has to.
 //!
 //! This is mostly provided through the use of a `Protected` trait and a
 //! `protected! {} macro, used to wrap around struct definitions to make them
@@ -23,7 +26,7 @@
 use ::std::fmt;
 use ::futures::{future, Future};
 use ::jedi::{self, Value, Map as JsonMap};
-use ::error::{TResult, TError, TFutureResult};
+use ::error::{TResult, TError, TFutureResult, FieldError};
 use ::turtl::Turtl;
 use ::models::model::Model;
 use ::crypto::{self, Key, CryptoOp};
@@ -52,10 +55,42 @@ pub fn encrypt_key(encrypting_key: &Key, key_to_encrypt: Key) -> TResult<String>
 }
 // -----------------------------------------------------------------------------
 
+/// Encrypt some JSON data into a (base64) model body
+fn encrypt_body(key: &Key, data: &Value) -> TResult<String> {
+    let json = jedi::stringify(data)?;
+    // government surveillance agencies *HATE* him!!!!1
+    let body = crypto::encrypt(key, Vec::from(json.as_bytes()), CryptoOp::new("chacha20poly1305")?)?;
+    Ok(crypto::to_base64(&body)?)
+}
+
+/// Decrypt a (base64) model body into JSON data
+fn decrypt_body(key: &Key, body: &String) -> TResult<Value> {
+    let json_bytes = crypto::decrypt(key, crypto::from_base64(body)?)?;
+    let json_str: String = match String::from_utf8(json_bytes) {
+        Ok(x) => x,
+        Err(e) => return TErr!(TError::BadValue(format!("error decoding UTF8 string: {}", e))),
+    };
+    Ok(jedi::parse(&json_str)?)
+}
+
 /// Map over a vec of Protected models, deserialize()ing them in worker threads
 /// and returning the resulting deserialized models as a vec in a future result
 pub fn map_deserialize<T>(turtl: &Turtl, vec: Vec<T>) -> TResult<Vec<T>>
     where T: Protected + Send + Sync + 'static
+{
+    map_deserialize_with(turtl, vec, false)
+}
+
+/// Like `map_deserialize()`, but leaves the models' lazy fields encrypted (see
+/// `Protected::deserialize_meta()`)
+pub fn map_deserialize_meta<T>(turtl: &Turtl, vec: Vec<T>) -> TResult<Vec<T>>
+    where T: Protected + Send + Sync + 'static
+{
+    map_deserialize_with(turtl, vec, true)
+}
+
+fn map_deserialize_with<T>(turtl: &Turtl, vec: Vec<T>, meta_only: bool) -> TResult<Vec<T>>
+    where T: Protected + Send + Sync + 'static
 {
     // Allows our future to collect a single result type which can then be
     // filtered at the end so we only return models that successfully
@@ -67,6 +102,7 @@ pub fn map_deserialize<T>(turtl: &Turtl, vec: Vec<T>) -> TResult<Vec<T>>
 
     debug!("protected::map_deserialize() -- starting on {} items", vec.len());
     let ref work = turtl.work;
+    let session = turtl.session();
     let futures = vec.into_iter()
         .map(|mut model| -> TFutureResult<_> {
             // don't bother with models that don't have a key...
@@ -76,11 +112,20 @@ pub fn map_deserialize<T>(turtl: &Turtl, vec: Vec<T>) -> TResult<Vec<T>>
             }
             let mut model_clone = ftry!(model.clone());
             let model_type = String::from(model.model_type());
-            let model_id = model.id().expect("turtl::protected::map_deserialize() -- mode.id() is None").clone();
+            let model_id = model.id().map(|x| x.clone());
             // run the deserialize, return the result into our future chain
-            let fut = work.run_async(move || model_clone.deserialize())
+            let fut = work.run_async_cancellable(&session, move |_| {
+                    if meta_only {
+                        model_clone.deserialize_meta()
+                    } else {
+                        model_clone.deserialize()
+                    }
+                })
                 .and_then(move |item_mapped: Value| -> TFutureResult<DeserializeResult<T>> {
                     ftry!(model.merge_fields(&item_mapped));
+                    let pending = meta_only && model.get_lazy_body().is_some();
+                    model.set_lazy_pending(pending);
+                    ftry!(model.mark_clean());
                     FOk!(DeserializeResult::Model(model))
                 })
                 .or_else(move |e| -> TFutureResult<DeserializeResult<T>> {
@@ -93,6 +138,9 @@ pub fn map_deserialize<T>(turtl: &Turtl, vec: Vec<T>) -> TResult<Vec<T>>
     // wait for all our futures to finish. this will return them in order of
     // starting (NOT order of completion).
     let mapped = future::join_all(futures).wait()?;
+    // if we got logged out while working, these models belong to a session
+    // that no longer exists
+    session.check()?;
     // only return the models that succeeded deserialization, preserving
     // the order.
     // TODO: benchmark if using an iterator is faster here
@@ -107,6 +155,14 @@ pub fn map_deserialize<T>(turtl: &Turtl, vec: Vec<T>) -> TResult<Vec<T>>
     Ok(final_models)
 }
 
+/// What a model's data (and key) looked like the last time it was decrypted or
+/// saved (see `Protected::mark_clean()`). Holds private data, so it stays out
+/// of serialization and debug output.
+#[derive(Clone, Default)]
+pub struct Snapshot {
+    data: JsonMap<String, Value>,
+    key: Option<Key>,
+}
 
 /// Allows a model to expose a key search
 pub trait Keyfinder {
@@ -165,9 +221,36 @@ pub trait Protected: Model + fmt::Debug {
     /// Grab the fields names of any child models this model has
     fn submodel_fields(&self) -> Vec<&'static str>;
 
+    /// Grab the names of our lazy fields: private fields that are encrypted
+    /// into their own `lazy_body` so the rest of the model can be decrypted
+    /// without them (see `deserialize_meta()`)
+    fn lazy_fields(&self) -> Vec<&'static str>;
+
+    /// Get the model's encrypted lazy fields
+    fn get_lazy_body<'a>(&'a self) -> Option<&'a String>;
+
+    /// Set the model's encrypted lazy fields
+    fn set_lazy_body(&mut self, body: Option<String>);
+
+    /// Whether we have lazy fields that haven't been decrypted yet
+    fn lazy_pending(&self) -> bool;
+
+    /// Mark whether we have lazy fields that haven't been decrypted yet
+    fn set_lazy_pending(&mut self, pending: bool);
+
+    /// Get the snapshot of the model we last marked clean
+    fn get_snapshot<'a>(&'a self) -> Option<&'a Snapshot>;
+
+    /// Set (or clear) the model's snapshot
+    fn set_snapshot(&mut self, snapshot: Option<Snapshot>);
+
     /// Get (JSON) data from one of our submodels
     fn submodel_data(&self, field: &str, private: bool) -> TResult<Value>;
 
+    /// Run the checks declared on our fields via `#[protected_validate(...)]`
+    /// (see `models::validate`)
+    fn validate_fields(&self) -> Vec<FieldError>;
+
     /// Sets our key into all our submodels
     fn _set_key_on_submodels(&mut self);
 
@@ -258,6 +341,67 @@ pub trait Protected: Model + fmt::Debug {
         Ok(jedi::to_val(self)?)
     }
 
+    /// The fields we look at when deciding if a model has changed. The
+    /// encrypted fields are left out, since they're whatever the rest of the
+    /// fields say they are.
+    fn tracked_fields(&self) -> Vec<&'static str> {
+        let mut fields = self.public_fields();
+        fields.append(&mut self.private_fields());
+        fields.append(&mut self.submodel_fields());
+        fields.retain(|x| !["body", "keys", "lazy_body"].contains(x));
+        fields.sort();
+        fields.dedup();
+        fields
+    }
+
+    /// Remember what this model looks like now, so we can tell later what's
+    /// changed. Done for us when a model is decrypted or saved.
+    fn mark_clean(&mut self) -> TResult<()> {
+        let mut data = JsonMap::new();
+        if let Value::Object(mut all) = self.data()? {
+            for field in self.tracked_fields() {
+                if let Some(val) = all.remove(field) {
+                    data.insert(String::from(field), val);
+                }
+            }
+        }
+        let key = self.key().map(|x| x.clone());
+        self.set_snapshot(Some(Snapshot { data: data, key: key }));
+        Ok(())
+    }
+
+    /// Grab the names of the fields that have changed since the model was
+    /// last marked clean. If it never was, they've all changed.
+    fn changed_fields(&self) -> TResult<Vec<String>> {
+        let fields = self.tracked_fields();
+        let snapshot = match self.get_snapshot() {
+            Some(x) => x,
+            None => return Ok(fields.into_iter().map(|x| String::from(x)).collect()),
+        };
+        // a missing field and a null field are the same thing
+        fn val<'a>(map: Option<&'a JsonMap<String, Value>>, field: &str) -> Option<&'a Value> {
+            map.and_then(|x| x.get(field)).and_then(|x| if x.is_null() { None } else { Some(x) })
+        }
+        let data = self.data()?;
+        let mut changed = Vec::new();
+        for field in fields {
+            if val(data.as_object(), field) != val(Some(&snapshot.data), field) {
+                changed.push(String::from(field));
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Whether this model has changed (including getting a new key) since it
+    /// was last marked clean
+    fn is_dirty(&self) -> TResult<bool> {
+        let key_changed = match self.get_snapshot() {
+            Some(snapshot) => snapshot.key.as_ref() != self.key(),
+            None => return Ok(true),
+        };
+        Ok(key_changed || self.changed_fields()?.len() > 0)
+    }
+
     /// Grab all public fields for this model as a JSON Value.
     fn data_for_storage(&self) -> TResult<Value> {
         self._public_data()
@@ -285,7 +429,7 @@ pub trait Protected: Model + fmt::Debug {
     }
 
     /// "Serializes" a model...returns all public data with an *encrypted* set
-    /// of private data (in `body`).
+    /// of private data (in `body`, and lazy fields in `lazy_body`).
     ///
     /// It returns the Value of all *public* fields, but with the `body`
     /// populated with the encrypted data.
@@ -293,65 +437,122 @@ pub trait Protected: Model + fmt::Debug {
         if self.key().is_none() {
             return TErr!(TError::MissingField(format!("model {:?} missing `key`", self.id())));
         }
+        // if we never decrypted our lazy fields, serializing now would wipe
+        // them out
+        if self.lazy_pending() {
+            return TErr!(TError::MissingData(format!("model {:?} ({}) has lazy fields that haven't been decrypted", self.id(), self.model_type())));
+        }
+        // if nothing that goes into one of our bodies changed (and our key
+        // didn't either), the body we have is still good, and we don't need
+        // to encrypt it again. this has to be figured out before our submodels
+        // get re-encrypted below.
+        let (keep_body, keep_lazy) = {
+            let key_changed = self.get_snapshot()
+                .map(|x| x.key.as_ref() != self.key())
+                .unwrap_or(true);
+            let public = self.public_fields();
+            let lazy = self.lazy_fields();
+            let changed = self.changed_fields()?;
+            let body_changed = changed.iter().any(|x| !public.contains(&x.as_str()) && !lazy.contains(&x.as_str()));
+            let lazy_changed = changed.iter().any(|x| lazy.contains(&x.as_str()));
+            (
+                !key_changed && !body_changed && self.get_body().is_some(),
+                !key_changed && !lazy_changed && self.get_lazy_body().is_some(),
+            )
+        };
         self.serialize_submodels()?;
-        let body;
-        {
-            let fakeid = String::from("<no id>");
-            let id = match self.id() {
-                Some(x) => x,
-                None => &fakeid,
-            };
-            let data = self._private_data()?;
-            let json = jedi::stringify(&data)?;
-
+        let (body, lazy_body) = {
             let key: &Key = match self.key() {
                 Some(x) => x,
-                None => return TErr!(TError::MissingField(format!("model {} ({}) missing `key`", id, self.model_type()))),
+                None => return TErr!(TError::MissingField(format!("model {:?} ({}) missing `key`", self.id(), self.model_type()))),
             };
-            // government surveillance agencies *HATE* him!!!!1
-            body = crypto::encrypt(&key, Vec::from(json.as_bytes()), CryptoOp::new("chacha20poly1305")?)?;
-        }
-        let body_base64 = crypto::to_base64(&body)?;
-        self.set_body(body_base64);
+            let mut data = self._private_data()?;
+            let mut lazy = JsonMap::new();
+            if let Value::Object(ref mut map) = data {
+                for field in self.lazy_fields() {
+                    if let Some(val) = map.remove(field) {
+                        lazy.insert(String::from(field), val);
+                    }
+                }
+            }
+            let lazy_body = if lazy.len() == 0 {
+                None
+            } else if keep_lazy {
+                self.get_lazy_body().map(|x| x.clone())
+            } else {
+                Some(encrypt_body(key, &Value::Object(lazy))?)
+            };
+            let body = match self.get_body() {
+                Some(x) if keep_body => x.clone(),
+                _ => encrypt_body(key, &data)?,
+            };
+            (body, lazy_body)
+        };
+        self.set_body(body);
+        self.set_lazy_body(lazy_body);
         Ok(self.data_for_storage()?)
     }
 
-    /// "DeSerializes" a model...takes the `body` field, decrypts it, and
-    /// returns a JSON Value of the public/private fields.
+    /// "DeSerializes" a model...takes the `body` (and `lazy_body`) fields,
+    /// decrypts them, and returns a JSON Value of the public/private fields.
     fn deserialize(&mut self) -> TResult<Value> {
+        self.deserialize_meta()?;
+        self.deserialize_lazy()
+    }
+
+    /// Like `deserialize()`, but leaves the lazy fields (if the model has any
+    /// in `lazy_body`) encrypted. Use `deserialize_lazy()` to get at them.
+    /// Until then, the model can't be serialized.
+    fn deserialize_meta(&mut self) -> TResult<Value> {
         if self.key().is_none() {
             return TErr!(TError::MissingField(format!("model {:?} ({}) missing `key`", self.id(), self.model_type())));
         }
         self.deserialize_submodels()?;
-        let fakeid = String::from("<no id>");
-        let json_bytes = {
+        let parsed = {
+            let fakeid = String::from("<no id>");
             let id = match self.id() {
                 Some(x) => x,
                 None => &fakeid,
             };
-            let body: Vec<u8> = match self.get_body() {
-                Some(x) => crypto::from_base64(x)?,
+            let body = match self.get_body() {
+                Some(x) => x,
                 None => return TErr!(TError::MissingField(format!("model {} ({}) missing `body`", id, self.model_type()))),
             };
             let key: &Key = match self.key() {
                 Some(x) => x,
                 None => return TErr!(TError::MissingField(format!("model {} ({}) missing `key`", id, self.model_type()))),
             };
-            crypto::decrypt(key, body)?
-        };
-        let json_str: String = match String::from_utf8(json_bytes) {
-            Ok(x) => x,
-            Err(e) => return TErr!(TError::BadValue(format!("error decoding UTF8 string: {}", e))),
-        };
-        let parsed: Value = match jedi::parse(&json_str) {
-            Ok(x) => x,
-            Err(e) => {
-                error!("protected.deserialize() -- error parsing JSON for {} model {:?}: {}", self.model_type(), self.id(), e);
-                let err: TError = From::from(e);
-                return TErr!(err);
-            },
+            decrypt_body(key, body)
+                .map_err(|e| {
+                    error!("protected.deserialize() -- error decrypting {} model {:?}: {}", self.model_type(), self.id(), e);
+                    e
+                })?
         };
         self.merge_fields(&parsed)?;
+        let pending = self.get_lazy_body().is_some();
+        self.set_lazy_pending(pending);
+        self.mark_clean()?;
+        Ok(self._private_data()?)
+    }
+
+    /// Decrypt the lazy fields left behind by `deserialize_meta()`. Models that
+    /// don't have any (or have already decrypted them) are left as they are.
+    fn deserialize_lazy(&mut self) -> TResult<Value> {
+        let parsed = match self.get_lazy_body() {
+            Some(lazy_body) => {
+                let key: &Key = match self.key() {
+                    Some(x) => x,
+                    None => return TErr!(TError::MissingField(format!("model {:?} ({}) missing `key`", self.id(), self.model_type()))),
+                };
+                Some(decrypt_body(key, lazy_body)?)
+            }
+            None => None,
+        };
+        if let Some(parsed) = parsed {
+            self.merge_fields(&parsed)?;
+        }
+        self.set_lazy_pending(false);
+        self.mark_clean()?;
         Ok(self._private_data()?)
     }
 
@@ -394,6 +595,10 @@ macro_rules! protected {
             pub struct $name {
                 #[serde(skip)]
                 _key: Option<::crypto::Key>,
+                #[serde(skip)]
+                _lazy_pending: bool,
+                #[serde(skip)]
+                _snapshot: Option<::models::protected::Snapshot>,
 
                 #[serde(skip_serializing_if = "Option::is_none")]
                 #[protected_field(public)]
@@ -447,6 +652,43 @@ mod tests {
         }
     }
 
+    protected! {
+        #[derive(Serialize, Deserialize)]
+        pub struct Kennel {
+            #[protected_field(public)]
+            #[protected_validate(required = "Where is the kennel?")]
+            pub address: String,
+
+            #[serde(rename = "site")]
+            #[protected_field(private)]
+            #[protected_validate(url, max_len = 24)]
+            pub website: Option<String>,
+            #[protected_field(private)]
+            #[protected_validate(required, max_len = 2)]
+            pub dogs: Option<Vec<String>>,
+        }
+    }
+
+    #[test]
+    fn validates_declared_fields() {
+        let mut kennel: Kennel = jedi::parse(&String::from(r#"{"address":"12 Bark St","site":"barkbarkbark.dog","dogs":["timmy"]}"#)).unwrap();
+        assert_eq!(kennel.validate_fields(), Vec::new());
+
+        kennel.address = String::from(" ");
+        kennel.website = Some(String::from("not even close to a website"));
+        kennel.dogs = None;
+        let errors = kennel.validate_fields().into_iter()
+            .map(|x| (x.field, x.rule))
+            .collect::<Vec<_>>();
+        assert_eq!(errors, vec![
+            (String::from("address"), String::from("required")),
+            (String::from("site"), String::from("url")),
+            (String::from("site"), String::from("max_len")),
+            (String::from("dogs"), String::from("required")),
+        ]);
+        assert_eq!(kennel.validate_fields()[0].message, "Where is the kennel?");
+    }
+
     #[test]
     fn returns_correct_public_fields() {
         let dog = Dog::new();
@@ -560,6 +802,65 @@ mod tests {
         assert_eq!(note_clone.text.unwrap(), "PEOPLE TAKE U MORE SRSLY");
     }
 
+    #[test]
+    fn decrypts_lazily() {
+        let mut note: Note = jedi::parse(&String::from(r#"{"id":"n1","space_id":"s1","user_id":51,"title":"my favorite site","text":"hi","embed":"<iframe src=\"https://turtlapp.com\"></iframe>"}"#)).unwrap();
+        let key = Key::random().unwrap();
+        note.set_key(Some(key.clone()));
+        let serialized = note.serialize().unwrap();
+        assert!(jedi::get::<String>(&["lazy_body"], &serialized).is_ok());
+        assert!(jedi::get::<String>(&["embed"], &serialized).is_err());
+
+        let mut note2: Note = jedi::from_val(serialized).unwrap();
+        note2.set_key(Some(key.clone()));
+        note2.deserialize_meta().unwrap();
+        assert_eq!(note2.title, Some(String::from("my favorite site")));
+        assert_eq!(note2.text, Some(String::from("hi")));
+        assert_eq!(note2.embed, None);
+        assert!(note2.lazy_pending());
+        // can't save it until we've got all of it
+        assert!(note2.serialize().is_err());
+
+        note2.deserialize_lazy().unwrap();
+        assert_eq!(note2.embed, note.embed);
+        assert!(!note2.lazy_pending());
+        assert!(note2.serialize().is_ok());
+    }
+
+    #[test]
+    fn tracks_changes() {
+        let mut dog: Dog = jedi::parse(&String::from(r#"{"size":69,"name":"barky","type":"canadian"}"#)).unwrap();
+        let key = Key::random().unwrap();
+        dog.set_key(Some(key.clone()));
+        assert!(dog.is_dirty().unwrap());
+        assert_eq!(dog.changed_fields().unwrap().len(), dog.tracked_fields().len());
+        dog.serialize().unwrap();
+        let body = dog.body.clone();
+
+        dog.mark_clean().unwrap();
+        assert!(!dog.is_dirty().unwrap());
+        // public changes don't touch the body
+        dog.size = Some(70);
+        assert_eq!(dog.changed_fields().unwrap(), vec!["size"]);
+        dog.serialize().unwrap();
+        assert_eq!(dog.body, body);
+        // private ones do
+        dog.name = Some(String::from("barkier"));
+        dog.tags = Some(Vec::new());
+        assert_eq!(dog.changed_fields().unwrap(), vec!["name", "size", "tags"]);
+        dog.serialize().unwrap();
+        assert!(dog.body != body);
+        let body = dog.body.clone();
+
+        // so does a new key
+        dog.mark_clean().unwrap();
+        dog.set_key(Some(Key::random().unwrap()));
+        assert!(dog.is_dirty().unwrap());
+        assert_eq!(dog.changed_fields().unwrap().len(), 0);
+        dog.serialize().unwrap();
+        assert!(dog.body != body);
+    }
+
     #[test]
     fn recursive_serialization() {
         let mut junkyard: Junkyard = jedi::parse(&String::from(r#"{"name":"US political system","dog":{"size":69,"name":"Gerard","type":"chowchow","tags":["bites","stubborn","furry"]}}"#)).unwrap();
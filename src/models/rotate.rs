@@ -0,0 +1,359 @@
+//! Key rotation for spaces, boards, and notes.
+//!
+//! Rotating an item's key gives it a brand new key and re-encrypts it with it.
+//! Everything whose key is wrapped with the old one (the boards, notes, and
+//! templates in a space, the notes and child boards under a board) gets its
+//! keys wrapped again with the new one, and a space's keychain entry is
+//! updated. With `deep` set, those items get new keys of their own as well,
+//! which is what you want after removing someone from a space: they could have
+//! kept a copy of any key they once had access to.
+//!
+//! All the saves happen in one db transaction (see `sync_model::atomic()`), so
+//! the changes go out to the server together or not at all.
+//!
+//! A note's file is usually encrypted with the note's key, so rotating the
+//! note's key means re-encrypting (and re-uploading) the file, which means we
+//! need it locally. Files with their own content key keep it: that key lives
+//! in the note's body, which gets re-encrypted.
+//!
+//! A shared space can't have its key rotated. The other members' keychains
+//! hold the old key and there's no way to hand them the new one, so remove
+//! them (and any pending invites, which carry the old key), rotate, then invite
+//! them again.
+
+use ::std::collections::HashMap;
+use ::std::fs;
+use ::std::io::{Read, Write};
+use ::error::{TResult, TError};
+use ::crypto::Key;
+use ::models::model::Model;
+use ::models::protected::Protected;
+use ::models::storable::Storable;
+use ::models::space::Space;
+use ::models::board::{self, Board};
+use ::models::note::Note;
+use ::models::template::Template;
+use ::models::file::FileData;
+use ::models::keychain;
+use ::models::thumbnail;
+use ::models::sync_record::SyncAction;
+use ::lib_permissions::Permission;
+use ::sync::sync_model;
+use ::turtl::Turtl;
+
+/// Options for rotating a key
+#[derive(Deserialize, Debug, Default)]
+pub struct RotateOptions {
+    /// Give everything under the item new keys too (instead of just wrapping
+    /// their keys with the item's new key)
+    #[serde(default)]
+    pub deep: bool,
+}
+
+/// What a rotation touched
+#[derive(Serialize, Debug, Default)]
+pub struct RotateResult {
+    /// Ids of the items that got new keys
+    pub rotated: Vec<String>,
+    /// Ids of the items that kept their keys, wrapped with a new one
+    pub rewrapped: Vec<String>,
+}
+
+/// Everything a rotation is going to save, in the order it gets saved
+#[derive(Default)]
+struct Rotation {
+    space: Option<Space>,
+    /// Parents come before their children, so a child's keys get wrapped with
+    /// its parent's new key
+    boards: Vec<Board>,
+    notes: Vec<Note>,
+    templates: Vec<Template>,
+    /// The (decrypted) files of notes getting new keys, by note id
+    files: HashMap<String, Vec<u8>>,
+    result: RotateResult,
+}
+
+/// Give a model a new key if `rotate` is set, otherwise just note that its
+/// keys are getting wrapped again
+fn mark<T: Protected>(model: &mut T, rotate: bool, result: &mut RotateResult) -> TResult<()> {
+    let id = model.id_or_else()?;
+    if rotate {
+        model.set_key(Some(Key::random()?));
+        result.rotated.push(id);
+    } else {
+        result.rewrapped.push(id);
+    }
+    Ok(())
+}
+
+/// Sort boards so parents come before their children
+fn ordered(mut boards: Vec<Board>) -> Vec<Board> {
+    let mut sorted = Vec::with_capacity(boards.len());
+    while boards.len() > 0 {
+        let idx = {
+            let waiting = |parent_id: &String| boards.iter().any(|x| x.id() == Some(parent_id));
+            boards.iter()
+                .position(|x| x.parent_id.as_ref().map(|p| !waiting(p)).unwrap_or(true))
+                // can't happen (boards can't loop) but don't spin forever
+                .unwrap_or(0)
+        };
+        sorted.push(boards.remove(idx));
+    }
+    sorted
+}
+
+/// Swap the key of a space/board we hold in the profile. Mem updates only
+/// merge in a model's fields, not its key, and the items saved after it need
+/// the new key to wrap theirs with.
+fn swap_key<T: Protected>(models: &mut Vec<T>, id: &String, key: &Key) {
+    for model in models.iter_mut() {
+        if model.id() == Some(id) { model.set_key(Some(key.clone())); }
+    }
+}
+
+/// Load (decrypted) notes by a db index
+fn load_notes(turtl: &Turtl, index: &str, vals: &Vec<String>) -> TResult<Vec<Note>> {
+    let mut note_ids: Vec<String> = Vec::new();
+    with_db!{ db, turtl.db,
+        for val in vals {
+            let notes: Vec<Note> = db.find(Note::tablename(), index, &vec![val.clone()])?;
+            for note in notes {
+                if let Some(id) = note.id() { note_ids.push(id.clone()); }
+            }
+        }
+    };
+    turtl.load_notes(&note_ids)
+}
+
+impl Rotation {
+    /// Add a note to the rotation. If it's getting a new key and its file is
+    /// encrypted with that key, we grab the file now, while we can still read
+    /// it.
+    fn add_note(&mut self, turtl: &Turtl, mut note: Note, rotate: bool) -> TResult<()> {
+        let own_key = note.file.as_ref().and_then(|x| x.key.as_ref()).is_some();
+        if rotate && note.has_file && !own_key {
+            let note_id = note.id_or_else()?;
+            if FileData::file_finder(None, Some(&note_id)).is_err() {
+                return TErr!(TError::BadValue(format!("the file for note {} hasn't been downloaded, so its key can't be rotated yet", note_id)));
+            }
+            let data = FileData::load_file(turtl, &note)?;
+            self.files.insert(note_id, data);
+        }
+        mark(&mut note, rotate, &mut self.result)?;
+        self.notes.push(note);
+        Ok(())
+    }
+
+    /// Save everything, all or nothing
+    fn apply(mut self, turtl: &Turtl) -> TResult<RotateResult> {
+        // hang onto the old keys (and the encrypted files we're about to
+        // overwrite) so we can put things back if anything fails
+        let mut old_space_key: Option<(String, Key)> = None;
+        let mut old_board_keys: Vec<(String, Key)> = Vec::new();
+        {
+            let profile_guard = lockr!(turtl.profile);
+            if let Some(space) = self.space.as_ref() {
+                let space_id = space.id_or_else()?;
+                old_space_key = profile_guard.keychain.find_key(&space_id)
+                    .map(|key| (space_id, key));
+            }
+            for board in &self.boards {
+                let board_id = board.id_or_else()?;
+                if let Some(key) = profile_guard.boards.iter().find(|x| x.id() == Some(&board_id)).and_then(|x| x.key()) {
+                    old_board_keys.push((board_id, key.clone()));
+                }
+            }
+        }
+        let mut old_files: Vec<(String, Vec<u8>)> = Vec::new();
+        for note_id in self.files.keys() {
+            let mut file = fs::File::open(FileData::file_finder(None, Some(note_id))?)?;
+            let mut enc = Vec::new();
+            file.read_to_end(&mut enc)?;
+            old_files.push((note_id.clone(), enc));
+        }
+
+        let res = sync_model::atomic(turtl, || {
+            if let Some(space) = self.space.as_mut() {
+                sync_model::save_model(SyncAction::Edit, turtl, space, false)?;
+                let mut profile_guard = lockw!(turtl.profile);
+                swap_key(&mut profile_guard.spaces, &space.id_or_else()?, &space.key_or_else()?);
+            }
+            for board in &mut self.boards {
+                sync_model::save_model(SyncAction::Edit, turtl, board, false)?;
+                let mut profile_guard = lockw!(turtl.profile);
+                swap_key(&mut profile_guard.boards, &board.id_or_else()?, &board.key_or_else()?);
+            }
+            for note in &mut self.notes {
+                sync_model::save_model(SyncAction::Edit, turtl, note, false)?;
+            }
+            for template in &mut self.templates {
+                sync_model::save_model(SyncAction::Edit, turtl, template, false)?;
+            }
+            // files go last: they're written to disk as we go, so the fewer
+            // things that can fail after them, the better
+            for note in &mut self.notes {
+                if let Some(data) = self.files.remove(&note.id_or_else()?) {
+                    let mut filedata = FileData::new();
+                    filedata.data = Some(data);
+                    filedata.save(turtl, note)?;
+                }
+            }
+            Ok(())
+        });
+        match res {
+            Ok(_) => Ok(self.result),
+            Err(e) => {
+                if let Err(err) = restore(turtl, old_space_key, old_board_keys, old_files) {
+                    error!("rotate::apply() -- error restoring keys after a failed rotation: {}", err);
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Put the old keys back in memory (the db was rolled back) and the old files
+/// back on disk after a failed rotation
+fn restore(turtl: &Turtl, old_space_key: Option<(String, Key)>, old_board_keys: Vec<(String, Key)>, old_files: Vec<(String, Vec<u8>)>) -> TResult<()> {
+    {
+        let mut profile_guard = lockw!(turtl.profile);
+        if let Some((ref space_id, ref key)) = old_space_key {
+            swap_key(&mut profile_guard.spaces, space_id, key);
+        }
+        for &(ref board_id, ref key) in &old_board_keys {
+            swap_key(&mut profile_guard.boards, board_id, key);
+        }
+    }
+    if let Some((space_id, key)) = old_space_key {
+        keychain::save_key(turtl, &space_id, &key, &String::from("space"), true)?;
+    }
+    let user_id = turtl.user_id()?;
+    for (note_id, enc) in old_files {
+        let mut file = fs::File::create(FileData::new_file(&user_id, &note_id)?)?;
+        file.write_all(enc.as_slice())?;
+        // the thumbnail was made with the new key. it'll get made again.
+        thumbnail::remove(&note_id)?;
+    }
+    Ok(())
+}
+
+/// Rotate a space's key, wrapping the keys of everything in it with the new
+/// one (or, with `deep`, giving them all new keys too)
+pub fn rotate_space(turtl: &Turtl, space_id: &String, options: &RotateOptions) -> TResult<RotateResult> {
+    let user_id = turtl.user_id()?;
+    Space::permission_check(turtl, space_id, &Permission::EditSpace)?;
+    let (mut space, boards) = {
+        let profile_guard = lockr!(turtl.profile);
+        let space = match profile_guard.spaces.iter().find(|x| x.id() == Some(space_id)) {
+            Some(x) => x.clone()?,
+            None => return TErr!(TError::NotFound(format!("space {} wasn't found", space_id))),
+        };
+        let mut boards = Vec::new();
+        for board in profile_guard.boards.iter().filter(|x| &x.space_id == space_id) {
+            boards.push(board.clone()?);
+        }
+        (space, boards)
+    };
+    if space.members.iter().any(|x| x.user_id != user_id) {
+        return TErr!(TError::BadValue(format!("space {} has other members, who would lose access to it. remove them before rotating its key.", space_id)));
+    }
+    if space.invites.len() > 0 {
+        return TErr!(TError::BadValue(format!("space {} has pending invites, which hold its current key. delete them before rotating its key.", space_id)));
+    }
+
+    let mut rotation = Rotation::default();
+    mark(&mut space, true, &mut rotation.result)?;
+    rotation.space = Some(space);
+    for mut board in ordered(boards) {
+        mark(&mut board, options.deep, &mut rotation.result)?;
+        rotation.boards.push(board);
+    }
+    for note in load_notes(turtl, "space_id", &vec![space_id.clone()])? {
+        rotation.add_note(turtl, note, options.deep)?;
+    }
+    for mut template in Template::list(turtl, space_id)? {
+        mark(&mut template, options.deep, &mut rotation.result)?;
+        rotation.templates.push(template);
+    }
+    info!("rotate::rotate_space() -- rotating key for space {} (deep: {})", space_id, options.deep);
+    rotation.apply(turtl)
+}
+
+/// Rotate a board's key, wrapping the keys of its child boards and of the notes
+/// under it with the new one (or, with `deep`, giving everything under it new
+/// keys too)
+pub fn rotate_board(turtl: &Turtl, board_id: &String, options: &RotateOptions) -> TResult<RotateResult> {
+    let (space_id, tree) = {
+        let profile_guard = lockr!(turtl.profile);
+        let space_id = match profile_guard.boards.iter().find(|x| x.id() == Some(board_id)) {
+            Some(x) => x.space_id.clone(),
+            None => return TErr!(TError::NotFound(format!("board {} wasn't found", board_id))),
+        };
+        // the board and everything under it
+        let mut tree = Vec::new();
+        for board in &profile_guard.boards {
+            let id = match board.id() {
+                Some(x) => x,
+                None => continue,
+            };
+            if id == board_id || board::ancestors(&profile_guard.boards, id).contains(board_id) {
+                tree.push(board.clone()?);
+            }
+        }
+        (space_id, tree)
+    };
+    Space::permission_check(turtl, &space_id, &Permission::EditBoard)?;
+
+    let mut rotation = Rotation::default();
+    let mut tree_ids = Vec::with_capacity(tree.len());
+    for mut board in ordered(tree) {
+        let id = board.id_or_else()?;
+        tree_ids.push(id.clone());
+        // boards only wrap their key with their parent's, so unless we're going
+        // deep, grandchildren and below stay as they are
+        let is_root = &id == board_id;
+        if !is_root && !options.deep && board.parent_id.as_ref() != Some(board_id) { continue; }
+        mark(&mut board, is_root || options.deep, &mut rotation.result)?;
+        rotation.boards.push(board);
+    }
+    // notes wrap their key with every board above them
+    for note in load_notes(turtl, "board_id", &tree_ids)? {
+        rotation.add_note(turtl, note, options.deep)?;
+    }
+    info!("rotate::rotate_board() -- rotating key for board {} (deep: {})", board_id, options.deep);
+    rotation.apply(turtl)
+}
+
+/// Rotate a note's key (re-encrypting its file, if it has one)
+pub fn rotate_note(turtl: &Turtl, note_id: &String) -> TResult<RotateResult> {
+    let mut notes = turtl.load_notes(&vec![note_id.clone()])?;
+    if notes.len() == 0 {
+        return TErr!(TError::NotFound(format!("note {} wasn't found", note_id)));
+    }
+    let note = notes.remove(0);
+    Space::permission_check(turtl, &note.space_id, &Permission::EditNote)?;
+    let mut rotation = Rotation::default();
+    rotation.add_note(turtl, note, true)?;
+    info!("rotate::rotate_note() -- rotating key for note {}", note_id);
+    rotation.apply(turtl)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::jedi;
+
+    #[test]
+    fn orders_boards() {
+        let boards: Vec<Board> = jedi::parse(&String::from(r#"[
+            {"id":"c","user_id":1,"space_id":"s1","parent_id":"b"},
+            {"id":"b","user_id":1,"space_id":"s1","parent_id":"a"},
+            {"id":"d","user_id":1,"space_id":"s1","parent_id":"x"},
+            {"id":"a","user_id":1,"space_id":"s1"}
+        ]"#)).unwrap();
+        let ids = ordered(boards).iter()
+            .map(|x| x.id().unwrap().clone())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["d", "a", "b", "c"]);
+    }
+}
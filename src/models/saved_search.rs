@@ -0,0 +1,173 @@
+//! Saved searches let the user keep a search they run all the time ("receipts
+//! in Finance from this year") as a one-click filter. Each one is a name and a
+//! search query (see `search::Query`), and they sync along with the rest of
+//! the profile so they show up on all the user's devices.
+//!
+//! Like settings, saved searches are encrypted with the user's key (not a
+//! space's) since they're the user's own, even though each one searches a
+//! space.
+
+use ::jedi::{self, Value};
+use ::error::{TResult, TError, FieldError};
+use ::models::model::Model;
+use ::models::protected::{Keyfinder, Protected};
+use ::models::sync_record::{SyncAction, SyncRecord};
+use ::models::validate::{self, Validate};
+use ::search::Query;
+use ::sync::sync_model::{self, SyncModel, MemorySaver};
+use ::turtl::Turtl;
+
+protected! {
+    #[derive(Serialize, Deserialize)]
+    #[protected_modeltype(saved_search)]
+    pub struct SavedSearch {
+        #[serde(with = "::util::ser::int_converter")]
+        #[protected_field(public)]
+        pub user_id: String,
+
+        /// What the search is called
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(private)]
+        #[protected_validate(required = "Please give your search a name", max_len = 256)]
+        pub name: Option<String>,
+        /// The search itself
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(private)]
+        pub query: Option<Query>,
+    }
+}
+
+make_storable!(SavedSearch, "saved_searches");
+impl SyncModel for SavedSearch {}
+impl Keyfinder for SavedSearch {}
+
+impl Validate for SavedSearch {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        if self.query.is_none() {
+            errors.push(validate::entry("query", t!("Please give your search a query")));
+        }
+        errors
+    }
+}
+
+impl MemorySaver for SavedSearch {
+    fn mem_update(self, turtl: &Turtl, sync_item: &mut SyncRecord) -> TResult<()> {
+        let action = sync_item.action.clone();
+        match action {
+            SyncAction::Add | SyncAction::Edit => {
+                let mut profile_guard = lockw!(turtl.profile);
+                sync_item.data = Some(self.data()?);
+                let existing = profile_guard.saved_searches.iter().position(|x| x.id() == self.id());
+                match existing {
+                    Some(idx) => profile_guard.saved_searches[idx] = self,
+                    None => profile_guard.saved_searches.push(self),
+                }
+            }
+            SyncAction::Delete => {
+                let mut profile_guard = lockw!(turtl.profile);
+                profile_guard.saved_searches.retain(|x| x.id() != self.id());
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl SavedSearch {
+    /// Grab the user's saved searches
+    pub fn list(turtl: &Turtl) -> TResult<Vec<Value>> {
+        let profile_guard = lockr!(turtl.profile);
+        let mut searches = Vec::with_capacity(profile_guard.saved_searches.len());
+        for search in &profile_guard.saved_searches {
+            searches.push(search.data()?);
+        }
+        Ok(searches)
+    }
+
+    /// Save a new search. Returns the saved search's data.
+    pub fn create(turtl: &Turtl, name: String, query: Query) -> TResult<Value> {
+        {
+            let profile_guard = lockr!(turtl.profile);
+            if !profile_guard.spaces.iter().any(|x| x.id() == Some(&query.space_id)) {
+                return TErr!(TError::NotFound(format!("space {} not found", query.space_id)));
+            }
+        }
+        let mut search = SavedSearch::new();
+        search.user_id = turtl.user_id()?;
+        search.name = Some(name);
+        search.query = Some(query);
+        sync_model::save_model(SyncAction::Add, turtl, &mut search, false)
+    }
+
+    /// Remove a saved search
+    pub fn delete(turtl: &Turtl, search_id: &String) -> TResult<()> {
+        SavedSearch::query(turtl, search_id)?;
+        sync_model::delete_model::<SavedSearch>(turtl, search_id, false)
+    }
+
+    /// Grab the query for one of the user's saved searches. Paging (`page`,
+    /// `per_page`, `offset`, `limit`) in `paging` replaces the saved search's.
+    pub fn query_for(turtl: &Turtl, search_id: &String, paging: &Value) -> TResult<Query> {
+        let mut query = SavedSearch::query(turtl, search_id)?;
+        if let Some(page) = jedi::get_opt(&["page"], paging) { query.page = page; }
+        if let Some(per_page) = jedi::get_opt(&["per_page"], paging) { query.per_page = per_page; }
+        if let Some(offset) = jedi::get_opt(&["offset"], paging) { query.offset = Some(offset); }
+        if let Some(limit) = jedi::get_opt(&["limit"], paging) { query.limit = Some(limit); }
+        Ok(query)
+    }
+
+    /// Grab a saved search's query as it was saved
+    fn query(turtl: &Turtl, search_id: &String) -> TResult<Query> {
+        let profile_guard = lockr!(turtl.profile);
+        let search = profile_guard.saved_searches.iter()
+            .find(|x| x.id() == Some(search_id));
+        match search.and_then(|x| x.query.as_ref()) {
+            Some(query) => Ok(query.clone()),
+            None => TErr!(TError::NotFound(format!("saved search {} not found", search_id))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::models::space::Space;
+
+    #[test]
+    fn saves_and_runs_searches() {
+        let turtl = ::turtl::tests::with_test(true);
+        let space_id = String::from("1234");
+        let mut space = Space::new();
+        space.set_id(space_id.clone());
+        {
+            let mut profile_guard = lockw!(turtl.profile);
+            profile_guard.spaces.push(space);
+        }
+
+        let query: Query = jedi::from_val(json!({
+            "space_id": space_id,
+            "tags": ["receipt"],
+            "per_page": 50,
+        })).unwrap();
+        assert!(SavedSearch::create(&turtl, String::from(""), query.clone()).is_err());
+        let mut elsewhere = query.clone();
+        elsewhere.space_id = String::from("nope");
+        assert!(SavedSearch::create(&turtl, String::from("Receipts"), elsewhere).is_err());
+
+        let saved = SavedSearch::create(&turtl, String::from("Receipts"), query).unwrap();
+        let search_id: String = jedi::get(&["id"], &saved).unwrap();
+        let list = SavedSearch::list(&turtl).unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(jedi::get::<String>(&["name"], &list[0]).unwrap(), "Receipts");
+
+        let run = SavedSearch::query_for(&turtl, &search_id, &json!({"page": 2})).unwrap();
+        assert_eq!(run.tags, vec![String::from("receipt")]);
+        assert_eq!(run.page, 2);
+        assert_eq!(run.per_page, 50);
+        assert!(SavedSearch::query_for(&turtl, &String::from("5678"), &json!({})).is_err());
+
+        SavedSearch::delete(&turtl, &search_id).unwrap();
+        assert_eq!(SavedSearch::list(&turtl).unwrap().len(), 0);
+    }
+}
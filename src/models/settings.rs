@@ -0,0 +1,147 @@
+//! Settings hold the user's app preferences (how lists are sorted, which space
+//! the app opens to, and whatever flags the UI wants to remember) so they
+//! follow the user from device to device instead of living in one device's
+//! k/v store.
+//!
+//! There's one settings object per user, encrypted with the user's key (like
+//! keychain entries are) and kept in the profile. If two devices both create
+//! one before they sync, the newest wins when the profile loads.
+
+use ::std::collections::BTreeMap;
+use ::jedi::{self, Value};
+use ::error::{TResult, TError};
+use ::models::model::Model;
+use ::models::protected::{Keyfinder, Protected};
+use ::models::sync_record::{SyncAction, SyncRecord};
+use ::models::validate::Validate;
+use ::sync::sync_model::{self, SyncModel, MemorySaver};
+use ::turtl::Turtl;
+
+/// The fields the UI is allowed to set
+const SETTABLE: [&'static str; 3] = ["sort", "default_space", "flags"];
+
+protected! {
+    #[derive(Serialize, Deserialize)]
+    pub struct Settings {
+        #[serde(with = "::util::ser::int_converter")]
+        #[protected_field(public)]
+        pub user_id: String,
+
+        /// How each list is sorted, by list (`{"notes": "mod-desc"}`)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(private)]
+        pub sort: Option<BTreeMap<String, String>>,
+        /// The space the app opens to
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(private)]
+        pub default_space: Option<String>,
+        /// Anything else the UI wants to remember (collapsed sidebars,
+        /// dismissed tips, etc)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(private)]
+        pub flags: Option<BTreeMap<String, Value>>,
+    }
+}
+
+make_storable!(Settings, "settings");
+impl SyncModel for Settings {}
+impl Keyfinder for Settings {}
+impl Validate for Settings {}
+
+impl MemorySaver for Settings {
+    fn mem_update(self, turtl: &Turtl, sync_item: &mut SyncRecord) -> TResult<()> {
+        let action = sync_item.action.clone();
+        match action {
+            SyncAction::Add | SyncAction::Edit => {
+                let mut profile_guard = lockw!(turtl.profile);
+                let newer = match profile_guard.settings.as_ref() {
+                    Some(current) => current.id() == self.id() || current.id() < self.id(),
+                    None => true,
+                };
+                if !newer { return Ok(()); }
+                sync_item.data = Some(self.data()?);
+                profile_guard.settings = Some(self);
+            }
+            SyncAction::Delete => {
+                let mut profile_guard = lockw!(turtl.profile);
+                let ours = profile_guard.settings.as_ref()
+                    .map(|x| x.id() == self.id())
+                    .unwrap_or(false);
+                if ours { profile_guard.settings = None; }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl Settings {
+    /// Grab the user's settings (blank if they haven't set anything yet)
+    pub fn get(turtl: &Turtl) -> TResult<Value> {
+        let profile_guard = lockr!(turtl.profile);
+        match profile_guard.settings.as_ref() {
+            Some(x) => Ok(x.data()?),
+            None => Ok(json!({})),
+        }
+    }
+
+    /// Change the user's settings. The fields given replace what's there (null
+    /// clears them), and anything left out stays as it is. Returns the updated
+    /// settings.
+    pub fn set(turtl: &Turtl, changes: Value) -> TResult<Value> {
+        match changes {
+            Value::Object(ref fields) => {
+                for field in fields.keys() {
+                    if !SETTABLE.contains(&field.as_str()) {
+                        return TErr!(TError::BadValue(format!("`{}` isn't a setting", field)));
+                    }
+                }
+            }
+            _ => return TErr!(TError::BadValue(String::from("settings must be an object"))),
+        }
+        if let Some(space_id) = jedi::get_opt::<String>(&["default_space"], &changes) {
+            let profile_guard = lockr!(turtl.profile);
+            if !profile_guard.spaces.iter().any(|x| x.id() == Some(&space_id)) {
+                return TErr!(TError::NotFound(format!("space {} not found", space_id)));
+            }
+        }
+        let existing = {
+            let profile_guard = lockr!(turtl.profile);
+            match profile_guard.settings.as_ref() {
+                Some(x) => Some(x.clone()?),
+                None => None,
+            }
+        };
+        let (action, mut settings) = match existing {
+            Some(x) => (SyncAction::Edit, x),
+            None => {
+                let mut settings = Settings::new();
+                settings.user_id = turtl.user_id()?;
+                (SyncAction::Add, settings)
+            }
+        };
+        settings.merge_fields(&changes)?;
+        sync_model::save_model(action, turtl, &mut settings, false)?;
+        Ok(settings.data()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sets_and_loads_settings() {
+        let turtl = ::turtl::tests::with_test(true);
+        assert_eq!(Settings::get(&turtl).unwrap(), json!({}));
+        assert!(Settings::set(&turtl, json!({"theme": "dark"})).is_err());
+        assert!(Settings::set(&turtl, json!({"default_space": "nope"})).is_err());
+
+        Settings::set(&turtl, json!({"sort": {"notes": "mod-desc"}, "flags": {"tips": false}})).unwrap();
+        let settings = Settings::set(&turtl, json!({"flags": null})).unwrap();
+        assert_eq!(jedi::get::<String>(&["sort", "notes"], &settings).unwrap(), "mod-desc");
+        assert_eq!(jedi::get_opt::<Value>(&["flags"], &settings), None);
+        let profile_guard = lockr!(turtl.profile);
+        assert_eq!(profile_guard.settings.as_ref().unwrap().sort.as_ref().unwrap().get("notes").unwrap(), "mod-desc");
+    }
+}
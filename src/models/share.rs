@@ -0,0 +1,140 @@
+//! Read-only share links for single notes. Sharing a note takes a snapshot of
+//! it, encrypts the snapshot with a brand new key (that has nothing to do with
+//! the note's own key), and uploads it. The key only ever lives in the link's
+//! URL fragment, which browsers don't send to the server, so the server can
+//! hand the snapshot to anyone with the link without being able to read it.
+//!
+//! The snapshot doesn't follow later edits to the note (share it again for
+//! that), and it leaves out the note's password. We keep a local record of the
+//! links we've made (not their keys) so they can be listed and revoked.
+
+use ::jedi::{self, Value};
+use ::crypto::{self, Key};
+use ::error::{TResult, TError};
+use ::api::ApiReq;
+use ::storage::Storage;
+use ::models::space::Space;
+use ::lib_permissions::Permission;
+use ::turtl::Turtl;
+use ::util;
+
+/// A share link we've made
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Share {
+    /// The share's id (assigned by the server)
+    pub id: String,
+    pub note_id: String,
+    /// When the link was made (unix seconds)
+    pub created: i64,
+}
+
+/// A freshly made share link. This is the only time the key is around, so the
+/// UI needs to show (or copy) the link now.
+#[derive(Serialize, Debug)]
+pub struct ShareLink {
+    pub share: Share,
+    /// `<id>#<key>`, ready to go on the end of the share URL
+    pub fragment: String,
+}
+
+/// The kv key we keep a share under
+fn share_key(share_id: &String) -> String {
+    format!("shares:{}", share_id)
+}
+
+/// Grab all the shares we've made
+fn all(db: &Storage) -> TResult<Vec<Share>> {
+    let mut shares = Vec::new();
+    for key in db.kv_keys(&share_key(&String::new()))? {
+        match db.kv_get(&key)? {
+            Some(x) => shares.push(jedi::parse::<Share>(&x)?),
+            None => {}
+        }
+    }
+    Ok(shares)
+}
+
+/// Pull the fields we share out of a (decrypted) note's data
+fn snapshot(note_data: &Value) -> Value {
+    let mut snapshot = json!({});
+    for field in &["type", "title", "tags", "url", "username", "text", "items", "fields", "embed", "color"] {
+        if let Some(val) = note_data.get(*field) {
+            if val.is_null() { continue; }
+            snapshot[*field] = val.clone();
+        }
+    }
+    snapshot
+}
+
+/// Share a note. Returns the new link (with its key).
+pub fn create(turtl: &Turtl, note_id: &String) -> TResult<ShareLink> {
+    turtl.assert_connected()?;
+    let mut notes = turtl.load_notes(&vec![note_id.clone()])?;
+    if notes.len() == 0 {
+        return TErr!(TError::NotFound(format!("note {} wasn't found", note_id)));
+    }
+    let note = notes.remove(0);
+    Space::permission_check(turtl, &note.space_id, &Permission::EditNote)?;
+
+    let snapshot = jedi::stringify(&snapshot(&jedi::to_val(&note)?))?;
+    let key = Key::random()?;
+    let key_clone = key.clone();
+    let body = turtl.work.run(move || {
+        crypto::encrypt(&key_clone, Vec::from(snapshot.as_bytes()), crypto::CryptoOp::new("chacha20poly1305")?)
+            .map_err(|e| From::from(e))
+    })?;
+    let req = ApiReq::new().data(json!({
+        "note_id": note_id,
+        "body": crypto::to_base64(&body)?,
+    }));
+    let saved: Value = turtl.api.post("/shares", req)?;
+    let share = Share {
+        id: jedi::get(&["id"], &saved)?,
+        note_id: note_id.clone(),
+        created: util::now_ms() / 1000,
+    };
+    with_db!{ db, turtl.db, db.kv_set(&share_key(&share.id), &jedi::stringify(&share)?)? };
+    let fragment = format!("{}#{}", share.id, crypto::to_base64(key.data())?);
+    Ok(ShareLink {
+        share: share,
+        fragment: fragment,
+    })
+}
+
+/// List the links we've made, for one note or all of them (newest first)
+pub fn list(turtl: &Turtl, note_id: Option<&String>) -> TResult<Vec<Share>> {
+    let mut shares = with_db!{ db, turtl.db, all(db)? };
+    if let Some(note_id) = note_id {
+        shares.retain(|x| &x.note_id == note_id);
+    }
+    shares.sort_by(|a, b| b.created.cmp(&a.created));
+    Ok(shares)
+}
+
+/// Take a link down. Anyone holding it gets nothing from then on.
+pub fn revoke(turtl: &Turtl, share_id: &String) -> TResult<()> {
+    turtl.assert_connected()?;
+    let url = format!("/shares/{}", share_id);
+    let _: Value = turtl.api.delete(url.as_str(), ApiReq::new())?;
+    with_db!{ db, turtl.db, db.kv_delete(&share_key(share_id))? };
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshots_leave_out_secrets() {
+        let note = json!({
+            "id": "n1",
+            "space_id": "s1",
+            "title": "wifi",
+            "username": "guest",
+            "password": "hunter2",
+            "text": null,
+            "reminder_at": 12345,
+        });
+        assert_eq!(snapshot(&note), json!({"title": "wifi", "username": "guest"}));
+    }
+}
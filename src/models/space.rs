@@ -1,18 +1,23 @@
-use ::error::{TResult, TError};
+use ::error::{TResult, TError, PermissionDenial};
 use ::models::model::Model;
 use ::models::board::Board;
 use ::models::note::Note;
+use ::models::template::Template;
 use ::models::invite::{Invite, InviteRequest};
+use ::models::key_bundle::{SpaceCapability, SpaceKeyBundle};
 use ::models::protected::{Keyfinder, Protected};
 use ::models::space_member::SpaceMember;
+use ::models::user::User;
 use ::models::sync_record::{SyncRecord, SyncAction};
-use ::models::validate::{self, Validate};
+use ::models::validate::Validate;
 use ::models::keychain;
 use ::sync::sync_model::{self, SyncModel, MemorySaver};
+use ::search_store;
 use ::turtl::Turtl;
 use ::lib_permissions::{Role, Permission};
 use ::api::ApiReq;
 use ::jedi::{self, Value};
+use ::util;
 use ::crypto::Key;
 use ::messaging;
 use ::std::default::Default;
@@ -35,6 +40,7 @@ protected! {
 
         #[serde(skip_serializing_if = "Option::is_none")]
         #[protected_field(private)]
+        #[protected_validate(required = "Please give your space a title", max_len = 256)]
         pub title: Option<String>,
 
         #[serde(skip_serializing_if = "Option::is_none")]
@@ -91,15 +97,7 @@ impl Space {
 make_storable!(Space, "spaces");
 impl SyncModel for Space {}
 
-impl Validate for Space {
-    fn validate(&self) -> Vec<(String, String)> {
-        let mut errors = Vec::new();
-        if self.title.as_ref().map(|x| x == "").unwrap_or(true) {
-            errors.push(validate::entry("title", t!("Please give your space a title")));
-        }
-        errors
-    }
-}
+impl Validate for Space {}
 
 impl Keyfinder for Space {
     // We definitely want to save space keys to the keychain
@@ -152,6 +150,33 @@ impl MemorySaver for Space {
                     let note_id = note.id_or_else()?;
                     sync_model::delete_model::<Note>(turtl, &note_id, true)?;
                 }
+
+                let templates: Vec<Template> = {
+                    let db_guard = lock!(turtl.db);
+                    match *db_guard {
+                        Some(ref db) => db.find("templates", "space_id", &vec![space_id.clone()])?,
+                        None => vec![],
+                    }
+                };
+                for template in templates {
+                    let template_id = template.id_or_else()?;
+                    sync_model::delete_model::<Template>(turtl, &template_id, true)?;
+                }
+                // drop the space's search partition (and saved index) wholesale
+                {
+                    let mut search_guard = lock!(turtl.search);
+                    match search_guard.as_mut() {
+                        Some(ref mut search) => search.purge_space(&space_id),
+                        None => {}
+                    }
+                }
+                {
+                    let db_guard = lock!(turtl.db);
+                    match *db_guard {
+                        Some(ref db) => search_store::remove(db, &space_id)?,
+                        None => {}
+                    }
+                }
                 // remove the space from memory
                 let mut profile_guard = lockw!(turtl.profile);
                 profile_guard.spaces.retain(|s| s.id() != Some(&space_id));
@@ -173,15 +198,72 @@ impl Space {
             .collect::<Vec<_>>();
 
         // if no spaces in our profile match the given id, we definitely do not
-        // have access
+        // have access (unless we imported the space's keys from a bundle, in
+        // which case the bundle's capability says what we can do)
         if matched.len() == 0 {
-            return TErr!(TError::PermissionDenied(format!("user {} cannot {:?} on space {} (space is missing)", user_id, permission, space_id)));
+            if let Some(capability) = profile_guard.keychain.find_capability(space_id) {
+                if capability.can(permission) { return Ok(()); }
+                let denial = PermissionDenial {
+                    message: format!("user {} cannot {:?} on space {} (space was shared read-only)", user_id, permission, space_id),
+                    action: Some(format!("{:?}", permission)),
+                    item: Some(space_id.clone()),
+                    required_role: Space::required_role(permission),
+                    current_role: util::enum_to_string(&capability.role).ok(),
+                    hint: Some(format!("{} shared this space with you for review only. ask them for an invite if you need more access.", capability.granted_by_username)),
+                };
+                return TErr!(TError::PermissionDenied(denial));
+            }
+            let denial = PermissionDenial {
+                message: format!("user {} cannot {:?} on space {} (space is missing)", user_id, permission, space_id),
+                action: Some(format!("{:?}", permission)),
+                item: Some(space_id.clone()),
+                required_role: Space::required_role(permission),
+                current_role: None,
+                hint: Some(String::from("you are not a member of this space. ask its owner for an invite.")),
+            };
+            return TErr!(TError::PermissionDenied(denial));
         }
 
-        let space = matched[0];
-        match space.can_i(&user_id, permission)? {
-            true => Ok(()),
-            false => TErr!(TError::PermissionDenied(format!("user {} cannot {:?} on space {}", user_id, permission, space_id))),
+        matched[0].can_i_or_else(&user_id, permission)
+    }
+
+    /// Find the lowest role that has the given permission (as a string, ie
+    /// "admin").
+    pub fn required_role(permission: &Permission) -> Option<String> {
+        let roles = vec![Role::Guest, Role::Member, Role::Moderator, Role::Admin, Role::Owner];
+        roles.iter()
+            .filter(|role| role.can(permission))
+            .next()
+            .and_then(|role| util::enum_to_string(role).ok())
+    }
+
+    /// Grab a user's role in this space (as a string), or None if they aren't
+    /// a member.
+    pub fn role_of(&self, user_id: &String) -> Option<String> {
+        if user_id == &self.user_id { return util::enum_to_string(&Role::Owner).ok(); }
+        self.members.iter()
+            .filter(|member| &member.user_id == user_id)
+            .next()
+            .and_then(|member| util::enum_to_string(&member.role).ok())
+    }
+
+    /// Explain why a user doesn't have a permission in this space
+    pub fn permission_denial(&self, user_id: &String, permission: &Permission) -> PermissionDenial {
+        let space_id = self.id().map(|x| x.clone()).unwrap_or(String::from("<no id>"));
+        let required_role = Space::required_role(permission);
+        let current_role = self.role_of(user_id);
+        let hint = match (&current_role, &required_role) {
+            (&None, _) => String::from("you are not a member of this space. ask its owner for an invite."),
+            (&Some(_), &Some(ref required)) => format!("ask the space's owner (or an admin) to make you a {} or higher.", required),
+            (&Some(_), &None) => String::from("nobody in this space can do that."),
+        };
+        PermissionDenial {
+            message: format!("user {} cannot {:?} on space {}", user_id, permission, space_id),
+            action: Some(format!("{:?}", permission)),
+            item: Some(space_id),
+            required_role: required_role,
+            current_role: current_role,
+            hint: Some(hint),
         }
     }
 
@@ -205,17 +287,10 @@ impl Space {
     /// Checks if a user has the given permission on the current space, and if
     /// not, returns an error
     pub fn can_i_or_else(&self, user_id: &String, permission: &Permission) -> TResult<()> {
-        model_getter!(get_field, "Space.can_i_or_else()");
-        let space_id = get_field!(self, id);
-        match self.can_i(user_id, permission) {
-            Ok(yesno) => {
-                if yesno {
-                    Ok(())
-                } else {
-                    TErr!(TError::PermissionDenied(format!("user {} cannot {:?} on space {}", user_id, permission, space_id)))
-                }
-            },
-            Err(e) => Err(e),
+        if self.can_i(user_id, permission)? {
+            Ok(())
+        } else {
+            TErr!(TError::PermissionDenied(self.permission_denial(user_id, permission)))
         }
     }
 
@@ -311,7 +386,7 @@ impl Space {
     }
 
     /// Send an invite for this space to an unsuspecting
-    pub fn send_invite(&mut self, turtl: &Turtl, invite_request: InviteRequest) -> TResult<()> {
+    pub fn send_invite(&mut self, turtl: &Turtl, mut invite_request: InviteRequest) -> TResult<()> {
         turtl.assert_connected()?;
         let (user_id, username) = {
             let user_guard = lockr!(turtl.user);
@@ -330,6 +405,13 @@ impl Space {
             return TErr!(TError::BadValue(format!("{} is already invited to this space", invite_request.to_user)));
         }
 
+        // if the invitee already has an account, wrap the space key with their
+        // pubkey so only they can open the invite. otherwise the invite's
+        // passphrase is all that protects it.
+        if invite_request.their_pubkey.is_none() {
+            invite_request.their_pubkey = User::find_by_email(turtl, &invite_request.to_user)?
+                .and_then(|x| x.pubkey);
+        }
         let invite = Invite::from_invite_request(&user_id, &username, &space_key, invite_request)?;
         invite.send(turtl)?;
         self.invites.push(invite);
@@ -379,6 +461,56 @@ impl Space {
         Ok(space)
     }
 
+    /// Export this space's key, sealed for someone outside the space (an
+    /// auditor, for instance), along with a read-only capability. The
+    /// recipient can import the bundle via `Space::import_keys()`.
+    pub fn export_keys(&self, turtl: &Turtl, recipient_pubkey: &Key) -> TResult<SpaceKeyBundle> {
+        model_getter!(get_field, "Space.export_keys()");
+        let space_id = get_field!(self, id);
+        let (user_id, username) = {
+            let user_guard = lockr!(turtl.user);
+            let user_id = user_guard.id_or_else()?;
+            (user_id, user_guard.username.clone())
+        };
+        // handing out keys is a form of inviting
+        self.can_i_or_else(&user_id, &Permission::AddSpaceInvite)?;
+        let space_key = self.key_or_else()?;
+        let capability = SpaceCapability::read_only(&space_id, &user_id, &username);
+        info!("Space.export_keys() -- exporting read-only keys for space {}", space_id);
+        SpaceKeyBundle::seal(capability, &space_key, recipient_pubkey)
+    }
+
+    /// Import a key bundle sealed for the current user (see `export_keys()`),
+    /// saving the key to our keychain as a foreign, read-only space.
+    pub fn import_keys(turtl: &Turtl, bundle: &SpaceKeyBundle) -> TResult<SpaceCapability> {
+        let (key, capability) = {
+            let user_guard = lockr!(turtl.user);
+            let pubkey = match user_guard.pubkey.as_ref() {
+                Some(k) => k,
+                None => return TErr!(TError::MissingField(String::from("User.pubkey"))),
+            };
+            let privkey = match user_guard.privkey.as_ref() {
+                Some(k) => k,
+                None => return TErr!(TError::MissingField(String::from("User.privkey"))),
+            };
+            bundle.open(pubkey, privkey)?
+        };
+        let space_id = capability.space_id.clone();
+        {
+            // don't clobber the key of a space we're actually a member of
+            let profile_guard = lockr!(turtl.profile);
+            let is_member = profile_guard.keychain.find_entry(&space_id)
+                .map(|entry| entry.capability.is_none())
+                .unwrap_or(false);
+            if is_member {
+                return TErr!(TError::BadValue(format!("you already have access to space {}", space_id)));
+            }
+        }
+        keychain::save_foreign_key(turtl, &space_id, &key, &String::from("space"), capability.clone())?;
+        info!("Space::import_keys() -- imported read-only keys for space {} (from {})", space_id, capability.granted_by_username);
+        Ok(capability)
+    }
+
     /// Edit a space invite
     pub fn edit_invite(&mut self, turtl: &Turtl, invite: &mut Invite) -> TResult<()> {
         turtl.assert_connected()?;
@@ -1,14 +1,25 @@
 use ::jedi::{self, Value};
-use ::error::{TResult, TError};
-use ::models::model::Model;
+use ::error::{TResult, TError, PermissionDenial};
+use ::models::model::{self, Model};
 use ::models::protected::{Protected, Keyfinder};
+use ::models::space::Space;
+use ::models::storable::Storable;
 use ::storage::Storage;
 use ::turtl::Turtl;
 use ::sync::sync_model::SyncModel;
+use ::sync::seal;
+use ::std::cmp;
 use ::std::fmt::Display;
+use ::lib_permissions::Permission;
+use ::config;
+use ::util;
 
-/// How many times a sync record can fail before it's "frozen"
-static MAX_ALLOWED_FAILURES: u32 = 3;
+/// How many times a sync record can fail before it's "frozen" (unless the
+/// config says otherwise)
+static DEFAULT_MAX_ATTEMPTS: u32 = 5;
+
+/// The longest (ms) we wait between retries (unless the config says otherwise)
+static DEFAULT_MAX_DELAY: u64 = 300000;
 
 /// Makes sure we only accept certain actions for syncing
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -42,6 +53,12 @@ pub enum SyncType {
     Board,
     #[serde(rename = "note")]
     Note,
+    #[serde(rename = "template")]
+    Template,
+    #[serde(rename = "settings")]
+    Settings,
+    #[serde(rename = "saved_search")]
+    SavedSearch,
     #[serde(rename = "file")]
     File,
     #[serde(rename = "file:incoming")]
@@ -70,6 +87,89 @@ pub struct SyncError {
     #[serde(with = "::util::ser::int_converter")]
     pub code: String,
     pub msg: String,
+    /// If the API refused this record because of the space's permissions,
+    /// this explains why (see `SyncRecord::explain_denial()`)
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub permission: Option<PermissionDenial>,
+}
+
+/// How we retry a type of sync record that keeps failing. Set via `sync.retry`
+/// in the config: a `default` section, plus optional overrides per sync type
+/// (`note`, `file:outgoing`, etc).
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// How many times a record can fail before it's frozen (0 never freezes)
+    pub max_attempts: u32,
+    /// How long (ms) to wait before retrying a failed record. Doubles with
+    /// each failure. 0 retries on the next sync run.
+    pub delay: u64,
+    /// The longest (ms) we'll wait between retries
+    pub max_delay: u64,
+}
+
+/// The parts of a retry policy set in the config. Anything missing falls back
+/// to the `default` section, then to our hard-coded defaults.
+#[derive(Deserialize, Debug, Default)]
+struct RetryConfig {
+    max_attempts: Option<u32>,
+    delay: Option<u64>,
+    max_delay: Option<u64>,
+}
+
+impl RetryConfig {
+    fn load(key: &str) -> RetryConfig {
+        config::get(&["sync", "retry", key]).unwrap_or(Default::default())
+    }
+}
+
+impl RetryPolicy {
+    /// Grab the retry policy for a sync type
+    pub fn for_type(ty: &SyncType) -> RetryPolicy {
+        let defaults = RetryConfig::load("default");
+        let overrides = match util::enum_to_string(ty) {
+            Ok(x) => RetryConfig::load(x.as_str()),
+            Err(_) => Default::default(),
+        };
+        RetryPolicy {
+            max_attempts: overrides.max_attempts.or(defaults.max_attempts).unwrap_or(DEFAULT_MAX_ATTEMPTS),
+            delay: overrides.delay.or(defaults.delay).unwrap_or(0),
+            max_delay: overrides.max_delay.or(defaults.max_delay).unwrap_or(DEFAULT_MAX_DELAY),
+        }
+    }
+
+    /// Whether a record that's failed this many times should be frozen
+    pub fn should_freeze(&self, errcount: u32) -> bool {
+        self.max_attempts > 0 && errcount >= self.max_attempts
+    }
+
+    /// How long (ms) to wait before retrying a record that's failed this many
+    /// times
+    pub fn retry_delay(&self, errcount: u32) -> u64 {
+        if self.delay == 0 || errcount == 0 { return 0; }
+        let factor = 1u64 << cmp::min(errcount - 1, 32);
+        cmp::min(self.delay.saturating_mul(factor), self.max_delay)
+    }
+}
+
+/// A summary of a sync record waiting to go out to the API (see
+/// `SyncRecord::get_outgoing_queue()`)
+#[derive(Serialize, Clone)]
+pub struct QueuedSync {
+    pub id: Option<String>,
+    #[serde(rename = "type")]
+    pub ty: SyncType,
+    pub action: SyncAction,
+    pub item_id: String,
+    /// How long (in seconds) this record has been waiting, if we can tell
+    pub age: Option<i64>,
+    pub errcount: u32,
+    pub error: Option<SyncError>,
+    pub frozen: bool,
+    /// When (unix ms) this record gets retried, if it's waiting out a failure
+    pub retry_at: Option<i64>,
+    /// Whether a frozen record ahead of this one is holding it up
+    pub blocked: bool,
 }
 
 /// Define a container for our sync records
@@ -99,6 +199,12 @@ protected! {
         #[serde(skip_serializing_if = "Option::is_none")]
         #[protected_field(public)]
         pub data: Option<Value>,
+        /// Our `data`, encrypted with the sync queue key while we're in the db
+        /// (see `sync::seal`)
+        #[serde(default)]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(public)]
+        pub sealed: Option<String>,
         #[serde(skip_serializing_if = "Option::is_none")]
         #[protected_field(public)]
         pub error: Option<SyncError>,
@@ -108,13 +214,26 @@ protected! {
         #[serde(default)]
         #[protected_field(public)]
         pub frozen: bool,
+        /// If this record failed, when (unix ms) we can try it again
+        #[serde(default)]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(public)]
+        pub retry_at: Option<i64>,
         #[serde(default)]
         #[protected_field(public)]
         pub blocked: bool,
     }
 }
 make_storable!(SyncRecord, "sync");
-impl SyncModel for SyncRecord {}
+impl SyncModel for SyncRecord {
+    /// Seal our data before it hits the db
+    fn db_save(&self, db: &mut Storage, _sync_item: Option<&SyncRecord>) -> TResult<()> {
+        if self.data.is_none() { return db.save(self); }
+        let mut rec = self.clone()?;
+        seal::seal(&mut rec)?;
+        db.save(&rec)
+    }
+}
 impl Keyfinder for SyncRecord {}
 
 impl SyncRecord {
@@ -133,9 +252,88 @@ impl SyncRecord {
         self.error = Some(SyncError {
             code: String::from("7471"),
             msg: format!("{}", err),
+            permission: None,
         });
     }
 
+    /// Whether this record is waiting out a failure before it can be retried
+    pub fn waiting(&self) -> bool {
+        match self.retry_at {
+            Some(x) => x > util::now_ms(),
+            None => false,
+        }
+    }
+
+    /// Count a failure against this record, freezing it or scheduling its
+    /// next retry according to the given policy.
+    pub fn record_failure(&mut self, policy: &RetryPolicy) {
+        self.errcount += 1;
+        if policy.should_freeze(self.errcount) {
+            self.frozen = true;
+            self.retry_at = None;
+            return;
+        }
+        let delay = policy.retry_delay(self.errcount);
+        self.retry_at = if delay > 0 { Some(util::now_ms() + delay as i64) } else { None };
+    }
+
+    /// Find the space permission this record needs, along with the id of the
+    /// space it needs it in. Returns None for records that aren't governed by
+    /// a space (or that we don't have enough data to figure out).
+    pub fn required_permission(&self) -> Option<(String, Permission)> {
+        let space_id = || -> Option<String> {
+            self.data.as_ref().and_then(|x| jedi::get_opt(&["space_id"], x))
+        };
+        match (&self.ty, &self.action) {
+            (&SyncType::Space, &SyncAction::Edit) => Some((self.item_id.clone(), Permission::EditSpace)),
+            (&SyncType::Space, &SyncAction::Delete) => Some((self.item_id.clone(), Permission::DeleteSpace)),
+            (&SyncType::Board, &SyncAction::Add) |
+            (&SyncType::Board, &SyncAction::MoveSpace) => space_id().map(|x| (x, Permission::AddBoard)),
+            (&SyncType::Board, &SyncAction::Edit) => space_id().map(|x| (x, Permission::EditBoard)),
+            (&SyncType::Board, &SyncAction::Delete) => space_id().map(|x| (x, Permission::DeleteBoard)),
+            (&SyncType::Note, &SyncAction::Add) |
+            (&SyncType::Note, &SyncAction::MoveSpace) => space_id().map(|x| (x, Permission::AddNote)),
+            (&SyncType::Note, &SyncAction::Edit) => space_id().map(|x| (x, Permission::EditNote)),
+            (&SyncType::Note, &SyncAction::Delete) => space_id().map(|x| (x, Permission::DeleteNote)),
+            // templates are governed by the note permissions
+            (&SyncType::Template, &SyncAction::Add) => space_id().map(|x| (x, Permission::AddNote)),
+            (&SyncType::Template, &SyncAction::Edit) => space_id().map(|x| (x, Permission::EditNote)),
+            (&SyncType::Template, &SyncAction::Delete) => space_id().map(|x| (x, Permission::DeleteNote)),
+            _ => None,
+        }
+    }
+
+    /// If the API rejected this record with a permission error (403), look up
+    /// the space it applies to in the local db and attach an explanation of
+    /// the refusal to the record's error.
+    pub fn explain_denial(&mut self, db: &mut Storage, user_id: &String) -> TResult<()> {
+        let is_denied = match self.error.as_ref() {
+            Some(err) => err.code == "403",
+            None => false,
+        };
+        if !is_denied { return Ok(()); }
+        let (space_id, permission) = match self.required_permission() {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let space: Option<Space> = db.get(Space::tablename(), &space_id)?;
+        let denial = match space {
+            Some(space) => space.permission_denial(user_id, &permission),
+            None => PermissionDenial {
+                message: format!("user {} cannot {:?} on space {} (space is missing)", user_id, permission, space_id),
+                action: Some(format!("{:?}", permission)),
+                item: Some(space_id),
+                required_role: Space::required_role(&permission),
+                current_role: None,
+                hint: Some(String::from("you are not a member of this space. ask its owner for an invite.")),
+            },
+        };
+        if let Some(err) = self.error.as_mut() {
+            err.permission = Some(denial);
+        }
+        Ok(())
+    }
+
     /// Given a DB and some params, grab all matching sync records
     pub fn find(db: &mut Storage, ty: Option<SyncType>) -> TResult<Vec<SyncRecord>> {
         let mut args = vec![];
@@ -183,20 +381,53 @@ impl SyncRecord {
         Ok(pending)
     }
 
-    /// Increment this SyncRecord's errcount. If it's above a magic number, we
-    /// mark the sync as failed, which excludes it from further outgoing syncs
-    /// until it gets manually shaken/removed.
+    /// Static method for grabbing a summary of everything waiting to go out to
+    /// the API (including file uploads), in the order it'll go out. Lets the
+    /// UI say things like "3 notes waiting to upload."
+    pub fn get_outgoing_queue(turtl: &Turtl) -> TResult<Vec<QueuedSync>> {
+        let mut db_guard = lock!(turtl.db);
+        let db = match db_guard.as_mut() {
+            Some(x) => x,
+            None => return TErr!(TError::MissingField(String::from("Turtl.db"))),
+        };
+        let now = util::now_ms();
+        let outgoing = SyncRecord::allbut(db, &vec![SyncType::FileIncoming])?;
+        let mut queue = Vec::with_capacity(outgoing.len());
+        let mut blocked = false;
+        for sync in outgoing {
+            let age = sync.id.as_ref()
+                .and_then(|x| model::id_timestamp(x).ok())
+                .map(|x| if now > x { (now - x) / 1000 } else { 0 });
+            let frozen = sync.frozen;
+            queue.push(QueuedSync {
+                id: sync.id,
+                ty: sync.ty,
+                action: sync.action,
+                item_id: sync.item_id,
+                age: age,
+                errcount: sync.errcount,
+                error: sync.error,
+                frozen: frozen,
+                retry_at: sync.retry_at,
+                blocked: blocked,
+            });
+            if frozen { blocked = true; }
+        }
+        Ok(queue)
+    }
+
+    /// Increment this SyncRecord's errcount. If it's failed too many times (see
+    /// `RetryPolicy`), we mark the sync as frozen, which excludes it from
+    /// further outgoing syncs until it gets manually shaken/removed. Otherwise,
+    /// it waits out the policy's retry delay before it goes out again.
     pub fn handle_failed_sync(db: &mut Storage, failure: &SyncRecord) -> TResult<()> {
         debug!("SyncRecord::handle_failed_sync() -- handle failure: {:?}", failure);
         let sync_id = failure.id_or_else()?;
         let sync_record: Option<SyncRecord> = db.get("sync", &sync_id)?;
         match sync_record {
             Some(mut rec) => {
-                if rec.errcount > MAX_ALLOWED_FAILURES {
-                    rec.frozen = true;
-                } else {
-                    rec.errcount += 1;
-                }
+                let policy = RetryPolicy::for_type(&rec.ty);
+                rec.record_failure(&policy);
                 rec.error = failure.error.clone();
                 // save our heroic sync record with our mods (errcount/frozen)
                 db.save(&rec)?;
@@ -207,6 +438,52 @@ impl SyncRecord {
         Ok(())
     }
 
+    /// Grab all frozen sync records, along with the errors that froze them.
+    pub fn frozen(db: &mut Storage) -> TResult<Vec<SyncRecord>> {
+        let frozen = SyncRecord::find(db, None)?
+            .into_iter()
+            .filter(|x| x.frozen)
+            .collect::<Vec<_>>();
+        Ok(frozen)
+    }
+
+    /// Unfreeze a sync record and give it a clean slate (no errors) so it gets
+    /// a full set of retries. Returns false if the record doesn't exist.
+    pub fn unfreeze(db: &mut Storage, sync_id: &String) -> TResult<bool> {
+        let sync: Option<SyncRecord> = db.get("sync", sync_id)?;
+        match sync {
+            Some(mut rec) => {
+                rec.frozen = false;
+                rec.errcount = 0;
+                rec.error = None;
+                rec.retry_at = None;
+                db.save(&rec)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Unfreeze every frozen sync record. Returns how many we unfroze.
+    pub fn unfreeze_all(db: &mut Storage) -> TResult<u32> {
+        let mut count = 0;
+        for rec in SyncRecord::frozen(db)? {
+            if SyncRecord::unfreeze(db, &rec.id_or_else()?)? { count += 1; }
+        }
+        Ok(count)
+    }
+
+    /// Static method for grabbing all frozen sync items, so the UI can show
+    /// what's stuck and why.
+    pub fn get_all_frozen(turtl: &Turtl) -> TResult<Vec<SyncRecord>> {
+        let mut db_guard = lock!(turtl.db);
+        let db = match db_guard.as_mut() {
+            Some(x) => x,
+            None => return TErr!(TError::MissingField(String::from("Turtl.db"))),
+        };
+        SyncRecord::frozen(db)
+    }
+
     /// Static method that tells the sync system to unfreeze a sync item so it
     /// gets queued to be included in the next outgoing sync.
     pub fn kick_frozen_sync(turtl: &Turtl, sync_id: &String) -> TResult<()> {
@@ -215,17 +492,21 @@ impl SyncRecord {
             Some(x) => x,
             None => return TErr!(TError::MissingField(String::from("Turtl.db"))),
         };
-        let sync: Option<SyncRecord> = db.get("sync", sync_id)?;
-        match sync {
-            Some(mut rec) => {
-                rec.frozen = false;
-                db.save(&rec)?;
-            }
-            None => {}
-        }
+        SyncRecord::unfreeze(db, sync_id)?;
         Ok(())
     }
 
+    /// Static method that unfreezes all frozen sync items at once. Returns how
+    /// many we unfroze.
+    pub fn kick_all_frozen_syncs(turtl: &Turtl) -> TResult<u32> {
+        let mut db_guard = lock!(turtl.db);
+        let db = match db_guard.as_mut() {
+            Some(x) => x,
+            None => return TErr!(TError::MissingField(String::from("Turtl.db"))),
+        };
+        SyncRecord::unfreeze_all(db)
+    }
+
     /// Public/static method for deleting a sync record (probably initiated from
     /// the UI).
     pub fn delete_sync_item(turtl: &Turtl, sync_id: &String) -> TResult<()> {
@@ -241,3 +522,66 @@ impl SyncRecord {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ::schema;
+
+    #[test]
+    fn unfreezes_records() {
+        let mut db = Storage::new(&String::from(":memory:"), schema::get_schema()).unwrap();
+        let records: Vec<SyncRecord> = jedi::parse(&String::from(r#"[
+            {"id":"1","action":"add","item_id":"a","user_id":1,"type":"note","frozen":true,"errcount":4,"error":{"code":"500","msg":"nope"}},
+            {"id":"2","action":"add","item_id":"b","user_id":1,"type":"note"},
+            {"id":"3","action":"add","item_id":"c","user_id":1,"type":"file:outgoing","frozen":true,"errcount":4}
+        ]"#)).unwrap();
+        for rec in &records { db.save(rec).unwrap(); }
+
+        let frozen = SyncRecord::frozen(&mut db).unwrap();
+        assert_eq!(frozen.len(), 2);
+        assert_eq!(frozen[0].error.as_ref().unwrap().msg, "nope");
+
+        assert!(SyncRecord::unfreeze(&mut db, &String::from("1")).unwrap());
+        assert!(!SyncRecord::unfreeze(&mut db, &String::from("lol")).unwrap());
+        let rec: SyncRecord = db.get("sync", &String::from("1")).unwrap().unwrap();
+        assert!(!rec.frozen);
+        assert_eq!(rec.errcount, 0);
+        assert!(rec.error.is_none());
+
+        assert_eq!(SyncRecord::unfreeze_all(&mut db).unwrap(), 1);
+        assert_eq!(SyncRecord::frozen(&mut db).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn retries_per_policy() {
+        let policy = RetryPolicy { max_attempts: 3, delay: 1000, max_delay: 3000 };
+        assert_eq!(policy.retry_delay(0), 0);
+        assert_eq!(policy.retry_delay(1), 1000);
+        assert_eq!(policy.retry_delay(2), 2000);
+        assert_eq!(policy.retry_delay(3), 3000);
+        assert_eq!(policy.retry_delay(99), 3000);
+
+        let mut rec: SyncRecord = jedi::parse(&String::from(r#"{"id":"1","action":"add","item_id":"a","user_id":1,"type":"note"}"#)).unwrap();
+        assert!(!rec.waiting());
+        rec.record_failure(&policy);
+        assert_eq!(rec.errcount, 1);
+        assert!(rec.waiting());
+        assert!(!rec.frozen);
+        rec.record_failure(&policy);
+        rec.record_failure(&policy);
+        assert!(rec.frozen);
+        assert!(!rec.waiting());
+
+        // no delay means we retry right away, and no max means we never freeze
+        let policy = RetryPolicy { max_attempts: 0, delay: 0, max_delay: 0 };
+        let mut rec: SyncRecord = jedi::parse(&String::from(r#"{"id":"1","action":"add","item_id":"a","user_id":1,"type":"note"}"#)).unwrap();
+        for _ in 0..20 { rec.record_failure(&policy); }
+        assert_eq!(rec.errcount, 20);
+        assert!(!rec.frozen);
+        assert!(!rec.waiting());
+
+        let defaults = RetryPolicy::for_type(&SyncType::Note);
+        assert_eq!(defaults.max_attempts, DEFAULT_MAX_ATTEMPTS);
+    }
+}
@@ -0,0 +1,118 @@
+//! Tags aren't models of their own, they're just strings on notes. So renaming,
+//! merging, or removing a tag means editing every note in the space that has
+//! it. We find those notes via the search index (so notes in the trash, which
+//! aren't indexed, are left alone), retag them, and save them all as one batch
+//! (see `sync_model::save_models()`).
+
+use ::error::{TResult, TError};
+use ::models::note::Note;
+use ::models::space::Space;
+use ::models::sync_record::SyncAction;
+use ::lib_permissions::Permission;
+use ::search::Query;
+use ::sync::sync_model;
+use ::turtl::Turtl;
+use ::jedi;
+use ::util;
+
+/// Apply a tag change to a list of tags. Any of the `from` tags are replaced
+/// with `to` (or removed if `to` is None), keeping the order and dropping any
+/// duplicates this creates. Returns None if nothing changed.
+pub fn retag(tags: &Vec<String>, from: &Vec<String>, to: Option<&String>) -> Option<Vec<String>> {
+    if !tags.iter().any(|x| from.contains(x)) { return None; }
+    let mut new_tags: Vec<String> = Vec::with_capacity(tags.len());
+    for tag in tags {
+        let tag = if from.contains(tag) {
+            match to {
+                Some(x) => x,
+                None => continue,
+            }
+        } else {
+            tag
+        };
+        if !new_tags.contains(tag) { new_tags.push(tag.clone()); }
+    }
+    Some(new_tags)
+}
+
+/// Find the ids of the notes in a space that have any of the given tags
+fn find_notes(turtl: &Turtl, space_id: &String, tags: &Vec<String>) -> TResult<Vec<String>> {
+    turtl.index_space(space_id)?;
+    let search_guard = lock!(turtl.search);
+    let search = match search_guard.as_ref() {
+        Some(x) => x,
+        None => return TErr!(TError::MissingField(format!("turtl is missing `search` object"))),
+    };
+    let mut note_ids: Vec<String> = Vec::new();
+    // the search index matches notes with ALL the given tags, so we look for
+    // each tag on its own
+    for tag in tags {
+        let qry: Query = jedi::from_val(json!({
+            "space_id": space_id,
+            "tags": [tag],
+            "per_page": 999999,
+        }))?;
+        let (ids, _) = search.find(&qry)?;
+        for id in ids {
+            if !note_ids.contains(&id) { note_ids.push(id); }
+        }
+    }
+    Ok(note_ids)
+}
+
+/// Change tags across all the notes in a space. Returns the number of notes
+/// that changed.
+fn change(turtl: &Turtl, space_id: &String, from: &Vec<String>, to: Option<&String>) -> TResult<usize> {
+    if from.len() == 0 || from.iter().any(|x| x == "") || to.map(|x| x == "").unwrap_or(false) {
+        return TErr!(TError::BadValue(String::from("tags cannot be empty")));
+    }
+    Space::permission_check(turtl, space_id, &Permission::EditNote)?;
+    let note_ids = find_notes(turtl, space_id, from)?;
+    let now = util::now_ms() / 1000;
+    let mut changed: Vec<Note> = Vec::with_capacity(note_ids.len());
+    for mut note in turtl.load_notes(&note_ids)? {
+        let new_tags = match note.tags.as_ref().and_then(|tags| retag(tags, from, to)) {
+            Some(x) => x,
+            None => continue,
+        };
+        note.tags = Some(new_tags);
+        note.mod_ = Some(now);
+        changed.push(note);
+    }
+    let num_changed = changed.len();
+    sync_model::save_models(SyncAction::Edit, turtl, changed, false)?;
+    Ok(num_changed)
+}
+
+/// Rename a tag on all the notes in a space
+pub fn rename(turtl: &Turtl, space_id: &String, from: &String, to: &String) -> TResult<usize> {
+    change(turtl, space_id, &vec![from.clone()], Some(to))
+}
+
+/// Merge a set of tags into one tag on all the notes in a space
+pub fn merge(turtl: &Turtl, space_id: &String, from: &Vec<String>, to: &String) -> TResult<usize> {
+    change(turtl, space_id, from, Some(to))
+}
+
+/// Remove a tag from all the notes in a space
+pub fn delete(turtl: &Turtl, space_id: &String, tag: &String) -> TResult<usize> {
+    change(turtl, space_id, &vec![tag.clone()], None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(list: &[&str]) -> Vec<String> {
+        list.iter().map(|x| String::from(*x)).collect()
+    }
+
+    #[test]
+    fn retags() {
+        let note_tags = tags(&["work", "todo", "urgent"]);
+        assert_eq!(retag(&note_tags, &tags(&["home"]), None), None);
+        assert_eq!(retag(&note_tags, &tags(&["todo"]), Some(&String::from("tasks"))), Some(tags(&["work", "tasks", "urgent"])));
+        assert_eq!(retag(&note_tags, &tags(&["todo", "urgent"]), Some(&String::from("work"))), Some(tags(&["work"])));
+        assert_eq!(retag(&note_tags, &tags(&["urgent"]), None), Some(tags(&["work", "todo"])));
+    }
+}
@@ -0,0 +1,228 @@
+//! Templates are the bones of a note (title, text, tags, etc) that get copied
+//! into new notes, for the stuff people write over and over (meeting notes,
+//! daily logs...). They live in a space and sync like notes do.
+//!
+//! Template text can have `{{placeholders}}` in it, which get filled in when a
+//! note is created from the template. We know about `date`, `time`,
+//! `datetime`, and `weekday`, and the UI can pass in whatever others it wants
+//! (or override ours). Placeholders we don't know about are left as-is.
+
+use ::std::collections::HashMap;
+use ::jedi::Value;
+use ::time;
+use ::error::{TResult, TError};
+use ::crypto::Key;
+use ::models::model::Model;
+use ::models::validate::Validate;
+use ::models::protected::{Keyfinder, Protected};
+use ::models::keychain::{Keychain, KeyRef, KeyType};
+use ::models::note::Note;
+use ::models::storable::Storable;
+use ::models::sync_record::SyncAction;
+use ::sync::sync_model::{self, SyncModel, MemorySaver};
+use ::turtl::Turtl;
+
+protected! {
+    #[derive(Serialize, Deserialize)]
+    pub struct Template {
+        #[serde(with = "::util::ser::int_converter")]
+        #[protected_field(public)]
+        pub user_id: String,
+        #[protected_field(public)]
+        #[protected_validate(required = "Please add a space id to this template")]
+        pub space_id: String,
+
+        /// What the template is called
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(private)]
+        #[protected_validate(required = "Please give your template a name", max_len = 256)]
+        pub name: Option<String>,
+        /// The board new notes go in (if the UI doesn't say otherwise)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(private)]
+        pub board_id: Option<String>,
+        #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+        #[protected_field(private)]
+        pub type_: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(private)]
+        #[protected_validate(max_len = 1024)]
+        pub title: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(private)]
+        pub tags: Option<Vec<String>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(private)]
+        #[protected_validate(url, max_len = 4096)]
+        pub url: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(private)]
+        pub text: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(private)]
+        pub color: Option<i64>,
+    }
+}
+
+make_storable!(Template, "templates");
+impl SyncModel for Template {}
+impl MemorySaver for Template {}
+
+impl Validate for Template {}
+
+/// Where a note made from a template goes, and what we fill its placeholders
+/// with
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct TemplateOptions {
+    /// Defaults to the template's space
+    pub space_id: Option<String>,
+    /// Defaults to the template's board
+    pub board_id: Option<String>,
+    /// Placeholder values
+    pub vars: HashMap<String, String>,
+}
+
+/// Fill in the `{{placeholders}}` in a string
+pub fn fill(text: &str, vars: &HashMap<String, String>) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start + 2..].find("}}") {
+            Some(x) => start + 2 + x,
+            None => break,
+        };
+        out.push_str(&rest[..start]);
+        match vars.get(rest[start + 2..end].trim()) {
+            Some(val) => out.push_str(val),
+            None => out.push_str(&rest[start..end + 2]),
+        }
+        rest = &rest[end + 2..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Our built-in placeholders, with the UI's values on top
+fn placeholders(vars: &HashMap<String, String>) -> HashMap<String, String> {
+    let now = time::now();
+    let mut all = HashMap::new();
+    let formats = [
+        ("date", "%Y-%m-%d"),
+        ("time", "%H:%M"),
+        ("datetime", "%Y-%m-%d %H:%M"),
+        ("weekday", "%A"),
+    ];
+    for &(name, format) in formats.iter() {
+        if let Ok(val) = time::strftime(format, &now) {
+            all.insert(String::from(name), val);
+        }
+    }
+    for (key, val) in vars {
+        all.insert(key.clone(), val.clone());
+    }
+    all
+}
+
+impl Template {
+    /// Load and decrypt a template
+    pub fn load(turtl: &Turtl, template_id: &String) -> TResult<Template> {
+        let template: Option<Template> = with_db!{ db, turtl.db, db.get(Template::tablename(), template_id)? };
+        let mut template = match template {
+            Some(x) => x,
+            None => return TErr!(TError::NotFound(format!("template {} wasn't found", template_id))),
+        };
+        turtl.find_model_key(&mut template)?;
+        template.deserialize()?;
+        Ok(template)
+    }
+
+    /// Load and decrypt the templates in a space
+    pub fn list(turtl: &Turtl, space_id: &String) -> TResult<Vec<Template>> {
+        let mut templates: Vec<Template> = with_db!{ db, turtl.db, db.find(Template::tablename(), "space_id", &vec![space_id.clone()])? };
+        turtl.find_models_keys(&mut templates)?;
+        ::models::protected::map_deserialize(turtl, templates)
+    }
+
+    /// Build a new (unsaved) note from this template
+    pub fn to_note(&self, options: &TemplateOptions) -> Note {
+        let vars = placeholders(&options.vars);
+        let fill_opt = |x: &Option<String>| x.as_ref().map(|x| fill(x, &vars));
+        let mut note = Note::new();
+        note.space_id = options.space_id.clone().unwrap_or_else(|| self.space_id.clone());
+        note.board_id = options.board_id.clone().or_else(|| self.board_id.clone());
+        note.type_ = self.type_.clone();
+        note.title = fill_opt(&self.title);
+        note.tags = self.tags.as_ref().map(|tags| tags.iter().map(|x| fill(x, &vars)).collect());
+        note.url = fill_opt(&self.url);
+        note.text = fill_opt(&self.text);
+        note.color = self.color.clone();
+        note
+    }
+
+    /// Create (and save) a new note from this template. Returns the saved
+    /// note's data.
+    pub fn create_note(&self, turtl: &Turtl, options: &TemplateOptions) -> TResult<Value> {
+        let mut note = self.to_note(options);
+        note.user_id = turtl.user_id()?;
+        note.mod_ = Some(time::get_time().sec as i64);
+        sync_model::save_model(SyncAction::Add, turtl, &mut note, false)
+    }
+}
+
+impl Keyfinder for Template {
+    fn get_key_search(&self, turtl: &Turtl) -> TResult<Keychain> {
+        let mut keychain = Keychain::new();
+        let mut space_ids: Vec<String> = vec![self.space_id.clone()];
+        if let Some(keys) = self.keys.as_ref() {
+            for key in keys {
+                if key.ty == KeyType::Space { space_ids.push(key.id.clone()); }
+            }
+        }
+        let ty = String::from("space");
+        let profile_guard = lockr!(turtl.profile);
+        for space in &profile_guard.spaces {
+            if space.id().is_none() || space.key().is_none() { continue; }
+            let space_id = space.id().expect("turtl::Template.get_key_search() -- space id is None");
+            if !space_ids.contains(space_id) { continue; }
+            keychain.upsert_key(turtl, space_id, space.key().expect("turtl::Template.get_key_search() -- space key is None"), &ty)?;
+        }
+        Ok(keychain)
+    }
+
+    fn get_keyrefs(&self, turtl: &Turtl) -> TResult<Vec<KeyRef<Key>>> {
+        let mut refs: Vec<KeyRef<Key>> = Vec::new();
+        let profile_guard = lockr!(turtl.profile);
+        for space in &profile_guard.spaces {
+            if space.id() == Some(&self.space_id) && space.key().is_some() {
+                refs.push(KeyRef {
+                    id: self.space_id.clone(),
+                    ty: KeyType::Space,
+                    k: space.key().expect("turtl::Template.get_keyrefs() -- space key is None").clone(),
+                });
+            }
+        }
+        Ok(refs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fills_placeholders() {
+        let mut vars = HashMap::new();
+        vars.insert(String::from("who"), String::from("the team"));
+        assert_eq!(fill("meeting w/ {{who}}, {{ who }}", &vars), "meeting w/ the team, the team");
+        assert_eq!(fill("{{nope}} and {{who", &vars), "{{nope}} and {{who");
+        assert_eq!(fill("no placeholders", &vars), "no placeholders");
+
+        let template: Template = ::jedi::parse(&String::from(r#"{"id":"t1","user_id":1,"space_id":"s1","board_id":"b1","title":"{{ date }} standup","tags":["{{who}}"]}"#)).unwrap();
+        let note = template.to_note(&TemplateOptions { vars: vars, ..Default::default() });
+        assert_eq!(note.space_id, "s1");
+        assert_eq!(note.board_id, Some(String::from("b1")));
+        assert_eq!(note.tags, Some(vec![String::from("the team")]));
+        assert!(!note.title.unwrap().contains("{{"));
+    }
+}
@@ -0,0 +1,148 @@
+//! Small thumbnails for notes with image files, so the UI can draw a grid of
+//! notes without loading (and decrypting) every multi-megabyte original.
+//!
+//! A thumbnail is made when an image file is saved locally or finishes
+//! downloading, and on demand for images we have but never thumbnailed. It's
+//! encrypted with the same key as the file and sits next to it in the files
+//! folder (`u_<user>.n_<note>.thumb`), going away with it.
+//!
+//! Making thumbnails needs the `thumbnails` feature (which pulls in the `image`
+//! crate). Without it, there just aren't any thumbnails.
+
+use ::std::fs;
+use ::std::io::{Read, Write};
+use ::std::path::PathBuf;
+use ::config;
+use ::crypto;
+use ::error::{TResult, TError};
+use ::models::model::Model;
+use ::models::note::Note;
+use ::models::file::{self, FileData};
+use ::turtl::Turtl;
+use ::util;
+use ::glob;
+#[cfg(feature = "thumbnails")]
+use ::image;
+
+/// How big (in pixels, on the longest side) thumbnails are
+fn size() -> u32 {
+    config::get(&["files", "thumbnails", "size"]).unwrap_or(256)
+}
+
+/// Whether a note's file is something we can thumbnail
+pub fn wanted(note: &Note) -> bool {
+    if !cfg!(feature = "thumbnails") { return false; }
+    note.file.as_ref()
+        .and_then(|x| x.ty.as_ref())
+        .map(|x| x.starts_with("image/") && x != "image/svg+xml")
+        .unwrap_or(false)
+}
+
+/// Where a note's thumbnail lives
+pub fn path(user_id: &String, note_id: &String) -> TResult<PathBuf> {
+    let mut path = PathBuf::from(file::file_folder()?);
+    path.push(format!("u_{}.n_{}.thumb", user_id, note_id));
+    Ok(path)
+}
+
+/// Find a note's thumbnail (whoever saved it)
+fn find(note_id: &String) -> TResult<Option<PathBuf>> {
+    let mut pattern = PathBuf::from(file::file_folder()?);
+    pattern.push(format!("u_*.n_{}.thumb", note_id));
+    let pathstr = match pattern.to_str() {
+        Some(x) => String::from(x),
+        None => return TErr!(TError::BadValue(format!("invalid path: {:?}", pattern))),
+    };
+    match glob::glob(&pathstr)?.next() {
+        Some(path) => Ok(Some(path?)),
+        None => Ok(None),
+    }
+}
+
+/// Shrink an image, handing back the thumbnail as a PNG
+#[cfg(feature = "thumbnails")]
+fn resize(data: &[u8], size: u32) -> TResult<Vec<u8>> {
+    let img = image::load_from_memory(data)
+        .map_err(|e| TError::BadValue(format!("couldn't read image: {}", e)))?;
+    let mut png = Vec::new();
+    img.thumbnail(size, size)
+        .write_to(&mut png, image::ImageOutputFormat::PNG)
+        .map_err(|e| TError::BadValue(format!("couldn't write thumbnail: {}", e)))?;
+    Ok(png)
+}
+
+#[cfg(not(feature = "thumbnails"))]
+fn resize(_data: &[u8], _size: u32) -> TResult<Vec<u8>> {
+    TErr!(TError::NotImplemented)
+}
+
+/// Make (and save) a thumbnail for a note from its (decrypted) file data
+pub fn generate_from(turtl: &Turtl, note: &Note, data: Vec<u8>) -> TResult<()> {
+    if !wanted(note) { return Ok(()); }
+    let user_id = turtl.user_id()?;
+    let note_id = note.id_or_else()?;
+    let key = FileData::file_key(note)?;
+    let size = size();
+    let enc = turtl.work.run(move || {
+        let thumb = resize(data.as_slice(), size)?;
+        crypto::encrypt(&key, thumb, crypto::CryptoOp::new("chacha20poly1305")?)
+            .map_err(|e| From::from(e))
+    })?;
+    util::create_dir(file::file_folder()?)?;
+    let mut fs_file = fs::File::create(path(&user_id, &note_id)?)?;
+    fs_file.write_all(enc.as_slice())?;
+    Ok(())
+}
+
+/// Make (and save) a thumbnail for a note from the file we have on disk
+pub fn generate(turtl: &Turtl, note_id: &String) -> TResult<()> {
+    let mut notes = turtl.load_notes(&vec![note_id.clone()])?;
+    if notes.len() == 0 { return Ok(()); }
+    let note = notes.remove(0);
+    if !note.has_file || !wanted(&note) { return Ok(()); }
+    let data = FileData::load_file(turtl, &note)?;
+    generate_from(turtl, &note, data)
+}
+
+/// Grab a note's thumbnail, making it first if we have the file but no
+/// thumbnail yet. Returns None if the note can't have one (or we don't have
+/// its file).
+pub fn get(turtl: &Turtl, note_id: &String) -> TResult<Option<Vec<u8>>> {
+    let mut notes = turtl.load_notes(&vec![note_id.clone()])?;
+    if notes.len() == 0 {
+        return TErr!(TError::NotFound(format!("note {} wasn't found", note_id)));
+    }
+    let note = notes.remove(0);
+    if !note.has_file || !wanted(&note) { return Ok(None); }
+    let thumb_path = match find(note_id)? {
+        Some(x) => x,
+        None => {
+            if FileData::file_finder(None, Some(note_id)).is_err() { return Ok(None); }
+            generate(turtl, note_id)?;
+            match find(note_id)? {
+                Some(x) => x,
+                None => return Ok(None),
+            }
+        }
+    };
+    let enc = {
+        let mut file = fs::File::open(thumb_path)?;
+        let mut enc = Vec::new();
+        file.read_to_end(&mut enc)?;
+        enc
+    };
+    let key = FileData::file_key(&note)?;
+    let thumb = turtl.work.run(move || {
+        crypto::decrypt(&key, enc)
+            .map_err(|e| From::from(e))
+    })?;
+    Ok(Some(thumb))
+}
+
+/// Remove a note's thumbnail(s)
+pub fn remove(note_id: &String) -> TResult<()> {
+    while let Some(path) = find(note_id)? {
+        fs::remove_file(&path)?;
+    }
+    Ok(())
+}
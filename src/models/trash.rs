@@ -0,0 +1,154 @@
+//! Deleting a note or board moves it to the trash instead of getting rid of it
+//! outright. Trashed items are just items with their `trashed` field set (the
+//! time they were trashed), so trashing/restoring goes out to the other
+//! devices as a normal edit. They stay in the db (boards stay in the profile,
+//! since their keys open the notes inside them) but are left out of search.
+//!
+//! Items that have been in the trash longer than `trash.retention` days get
+//! deleted for real when sync starts, and `trash:empty` deletes everything in
+//! the trash right away. If `trash.enabled` is false, deletes are permanent.
+
+use ::jedi;
+use ::config;
+use ::error::{TResult, TError};
+use ::models::model::Model;
+use ::models::protected::{self, Protected, Keyfinder};
+use ::models::storable::Storable;
+use ::models::validate::Validate;
+use ::models::note::Note;
+use ::models::board::Board;
+use ::models::space::Space;
+use ::models::sync_record::SyncAction;
+use ::lib_permissions::Permission;
+use ::sync::sync_model::{self, SyncModel, MemorySaver};
+use ::turtl::Turtl;
+use ::util;
+
+/// A model that can go in the trash
+pub trait Trashable: Protected + Storable + Keyfinder + SyncModel + MemorySaver + Validate + Sync + Send {
+    /// When (unix seconds) this item was trashed, if it was
+    fn trashed(&self) -> Option<i64>;
+
+    /// Put this item in (or take it out of) the trash
+    fn set_trashed(&mut self, trashed: Option<i64>);
+
+    /// The space this item lives in
+    fn trash_space_id(&self) -> &String;
+
+    /// The permission needed to delete this item for good
+    fn delete_permission(&self) -> Permission;
+}
+
+impl Trashable for Note {
+    fn trashed(&self) -> Option<i64> { self.trashed }
+    fn set_trashed(&mut self, trashed: Option<i64>) { self.trashed = trashed; }
+    fn trash_space_id(&self) -> &String { &self.space_id }
+    fn delete_permission(&self) -> Permission { Permission::DeleteNote }
+}
+
+impl Trashable for Board {
+    fn trashed(&self) -> Option<i64> { self.trashed }
+    fn set_trashed(&mut self, trashed: Option<i64>) { self.trashed = trashed; }
+    fn trash_space_id(&self) -> &String { &self.space_id }
+    fn delete_permission(&self) -> Permission { Permission::DeleteBoard }
+}
+
+/// What's in the trash
+#[derive(Serialize, Debug, Default)]
+pub struct Trash {
+    pub notes: Vec<Note>,
+    pub boards: Vec<Board>,
+}
+
+/// Whether deletes go to the trash
+pub fn enabled() -> bool {
+    config::get(&["trash", "enabled"]).unwrap_or(true)
+}
+
+/// How many days items stay in the trash (0 keeps them until it's emptied)
+fn retention() -> i64 {
+    config::get(&["trash", "retention"]).unwrap_or(30)
+}
+
+/// Load and decrypt an item
+fn load<T: Trashable>(turtl: &Turtl, id: &String) -> TResult<T> {
+    let model: Option<T> = with_db!{ db, turtl.db, db.get(T::tablename(), id)? };
+    let mut model = match model {
+        Some(x) => x,
+        None => return TErr!(TError::NotFound(format!("that {} model wasn't found", T::tablename()))),
+    };
+    turtl.find_model_key(&mut model)?;
+    model.deserialize()?;
+    Ok(model)
+}
+
+/// Load and decrypt everything of a type that's in the trash
+fn load_trashed<T: Trashable>(turtl: &Turtl) -> TResult<Vec<T>> {
+    let all: Vec<T> = with_db!{ db, turtl.db, db.all(T::tablename())? };
+    let mut trashed = all.into_iter()
+        .filter(|x| x.trashed().is_some())
+        .collect::<Vec<_>>();
+    turtl.find_models_keys(&mut trashed)?;
+    protected::map_deserialize(turtl, trashed)
+}
+
+/// Move an item to the trash. Returns the saved item data.
+pub fn trash<T: Trashable>(turtl: &Turtl, id: &String) -> TResult<jedi::Value> {
+    let mut model: T = load(turtl, id)?;
+    if model.trashed().is_some() { return Ok(model.data()?); }
+    model.set_trashed(Some(util::now_ms() / 1000));
+    sync_model::save_model(SyncAction::Edit, turtl, &mut model, false)
+}
+
+/// Take an item back out of the trash. Returns the saved item data.
+pub fn restore<T: Trashable>(turtl: &Turtl, id: &String) -> TResult<jedi::Value> {
+    let mut model: T = load(turtl, id)?;
+    if model.trashed().is_none() { return Ok(model.data()?); }
+    model.set_trashed(None);
+    sync_model::save_model(SyncAction::Edit, turtl, &mut model, false)
+}
+
+/// Grab everything in the trash
+pub fn list(turtl: &Turtl) -> TResult<Trash> {
+    Ok(Trash {
+        notes: load_trashed(turtl)?,
+        boards: load_trashed(turtl)?,
+    })
+}
+
+/// Delete the trashed items of a type trashed before `cutoff` (unix seconds),
+/// or all of them. Items we don't have permission to delete stay put. Returns
+/// how many we deleted.
+fn purge<T: Trashable>(turtl: &Turtl, cutoff: Option<i64>) -> TResult<u64> {
+    let all: Vec<T> = with_db!{ db, turtl.db, db.all(T::tablename())? };
+    let mut purged = 0;
+    for model in all {
+        let trashed = match model.trashed() {
+            Some(x) => x,
+            None => continue,
+        };
+        if cutoff.map(|x| trashed >= x).unwrap_or(false) { continue; }
+        let id = model.id_or_else()?;
+        if let Err(e) = Space::permission_check(turtl, model.trash_space_id(), &model.delete_permission()) {
+            warn!("trash::purge() -- can't delete {} {}: {}", T::tablename(), id, e);
+            continue;
+        }
+        sync_model::delete_model::<T>(turtl, &id, false)?;
+        purged += 1;
+    }
+    Ok(purged)
+}
+
+/// Delete everything in the trash. Returns how many items we deleted.
+pub fn empty(turtl: &Turtl) -> TResult<u64> {
+    Ok(purge::<Note>(turtl, None)? + purge::<Board>(turtl, None)?)
+}
+
+/// Delete everything that's been in the trash longer than our retention
+/// period. Returns how many items we deleted.
+pub fn purge_expired(turtl: &Turtl) -> TResult<u64> {
+    let days = retention();
+    if days <= 0 { return Ok(0); }
+    let cutoff = Some((util::now_ms() / 1000) - (days * 86400));
+    Ok(purge::<Note>(turtl, cutoff)? + purge::<Board>(turtl, cutoff)?)
+}
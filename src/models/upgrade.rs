@@ -0,0 +1,271 @@
+//! Brings notes and boards saved by older versions of the app up to the
+//! current protected format. A model is out of date if its `body` (or
+//! `lazy_body`) was encrypted under an older crypto version, or if it keeps its
+//! lazy fields in `body` (from before lazy fields were split out).
+//!
+//! Reading old models works fine, so there's no rush. `scan()` runs at profile
+//! load and only notes what needs upgrading (in the user db's kv store), and
+//! `trickle()` works through that list a batch at a time with a pause between
+//! batches, re-encrypting each model and letting the save queue its sync. This
+//! keeps a login on a big, old profile from re-encrypting everything at once
+//! and then hammering the API with thousands of edits.
+
+use ::std::collections::HashSet;
+use ::std::sync::atomic::{AtomicBool, Ordering};
+use ::config;
+use ::crypto;
+use ::error::{TResult, TError};
+use ::jedi::{self, Value};
+use ::models::model::Model;
+use ::models::protected::Protected;
+use ::models::storable::Storable;
+use ::models::board::Board;
+use ::models::note::Note;
+use ::models::sync_record::SyncAction;
+use ::messaging;
+use ::storage::Storage;
+use ::sync::sync_model;
+use ::turtl::Turtl;
+use ::util;
+
+/// The kv key holding the models we still need to upgrade
+const PENDING_KEY: &'static str = "upgrade:pending";
+
+/// The kv key holding the crypto version we last scanned for
+const SCANNED_KEY: &'static str = "upgrade:scanned";
+
+lazy_static! {
+    /// Whether a `trickle()` is already working through the list
+    static ref RUNNING: AtomicBool = AtomicBool::new(false);
+}
+
+/// A model waiting to be upgraded
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Pending {
+    #[serde(rename = "type")]
+    pub ty: String,
+    pub id: String,
+}
+
+/// How many models we upgrade at a time
+fn batch_size() -> usize {
+    config::get(&["profile", "upgrade", "batch"]).unwrap_or(25)
+}
+
+/// How long (ms) we wait between batches
+fn delay() -> u64 {
+    config::get(&["profile", "upgrade", "delay"]).unwrap_or(5000)
+}
+
+/// Whether a (base64) body was encrypted under an older crypto version. We
+/// only decode enough of it to read the version.
+pub fn outdated_body(body: &String) -> bool {
+    if body.len() < 4 { return false; }
+    let head = match crypto::from_base64(&String::from(&body[0..4])) {
+        Ok(x) => x,
+        Err(_) => return false,
+    };
+    match crypto::payload_version(head.as_slice()) {
+        Ok(version) => version < crypto::current_version(),
+        Err(_) => false,
+    }
+}
+
+/// Whether a model's encrypted data is in an older format (no decrypting
+/// needed)
+pub fn is_outdated<T: Protected>(model: &T) -> bool {
+    model.get_body().map(|x| outdated_body(x)).unwrap_or(false) ||
+        model.get_lazy_body().map(|x| outdated_body(x)).unwrap_or(false)
+}
+
+/// Whether a (decrypted) model keeps its lazy fields in `body`
+pub fn lazy_misplaced<T: Protected>(model: &T) -> TResult<bool> {
+    let lazy = model.lazy_fields();
+    if lazy.len() == 0 || model.get_lazy_body().is_some() || model.lazy_pending() {
+        return Ok(false);
+    }
+    let data = model.data()?;
+    Ok(lazy.iter().any(|field| jedi::get_opt::<Value>(&[field], &data).map(|x| !x.is_null()).unwrap_or(false)))
+}
+
+/// Grab the models waiting to be upgraded
+pub fn pending(db: &Storage) -> TResult<Vec<Pending>> {
+    match db.kv_get(PENDING_KEY)? {
+        Some(x) => Ok(jedi::parse(&x)?),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Save the list of models waiting to be upgraded
+fn set_pending(db: &Storage, list: &Vec<Pending>) -> TResult<()> {
+    if list.len() == 0 {
+        db.kv_delete(PENDING_KEY)
+    } else {
+        db.kv_set(PENDING_KEY, &jedi::stringify(list)?)
+    }
+}
+
+/// Add some models to the upgrade list (skipping any already on it). Returns
+/// how many we added.
+pub fn queue(db: &Storage, items: Vec<Pending>) -> TResult<u64> {
+    if items.len() == 0 { return Ok(0); }
+    let mut list = pending(db)?;
+    let mut added = 0;
+    {
+        let mut seen = list.iter().map(|x| x.clone()).collect::<HashSet<_>>();
+        for item in items {
+            if seen.contains(&item) { continue; }
+            seen.insert(item.clone());
+            list.push(item);
+            added += 1;
+        }
+    }
+    set_pending(db, &list)?;
+    Ok(added)
+}
+
+/// Find the models in one of our tables that are in an older format
+fn scan_table<T>(db: &Storage) -> TResult<Vec<Pending>>
+    where T: Protected + Storable
+{
+    let models: Vec<T> = db.all(T::tablename())?;
+    Ok(models.into_iter()
+        .filter(|x| is_outdated(x))
+        .filter_map(|x| x.id().map(|id| Pending { ty: x.model_type(), id: id.clone() }))
+        .collect())
+}
+
+/// Look for notes/boards saved under an older crypto version and queue them
+/// for upgrading. We only scan once per crypto version, since everything we
+/// write (and anything a current client syncs to us) is in the current one.
+/// Returns how many models we queued.
+pub fn scan(turtl: &Turtl) -> TResult<u64> {
+    let current = crypto::current_version().to_string();
+    with_db!{ db, turtl.db,
+        if db.kv_get(SCANNED_KEY)? == Some(current.clone()) { return Ok(0); }
+        let mut found = scan_table::<Note>(db)?;
+        found.append(&mut scan_table::<Board>(db)?);
+        let queued = queue(db, found)?;
+        db.kv_set(SCANNED_KEY, &current)?;
+        if queued > 0 {
+            info!("upgrade::scan() -- queued {} models for upgrading", queued);
+        }
+        Ok(queued)
+    }
+}
+
+/// Re-encrypt a model under the current format and save it (which queues the
+/// sync). Clearing the snapshot makes the save re-encrypt the bodies even
+/// though none of the fields changed.
+fn upgrade_one(turtl: &Turtl, item: &Pending) -> TResult<()> {
+    match item.ty.as_str() {
+        "note" => {
+            let mut notes = turtl.load_notes(&vec![item.id.clone()])?;
+            if notes.len() == 0 { return Ok(()); }
+            let mut note = notes.remove(0);
+            note.set_snapshot(None);
+            sync_model::save_model(SyncAction::Edit, turtl, &mut note, false)?;
+        }
+        "board" => {
+            let board = {
+                let profile_guard = lockr!(turtl.profile);
+                match profile_guard.boards.iter().find(|x| x.id() == Some(&item.id)) {
+                    Some(x) => Some(x.clone()?),
+                    None => None,
+                }
+            };
+            let mut board = match board {
+                Some(x) => x,
+                None => return Ok(()),
+            };
+            board.set_snapshot(None);
+            sync_model::save_model(SyncAction::Edit, turtl, &mut board, false)?;
+        }
+        _ => return TErr!(TError::BadValue(format!("can't upgrade a {}", item.ty))),
+    }
+    Ok(())
+}
+
+/// Upgrade the next batch of models on our list. Models that won't upgrade are
+/// dropped from the list (we can still read them as they are). Returns how
+/// many are left.
+pub fn run_batch(turtl: &Turtl) -> TResult<usize> {
+    let batch = with_db!{ db, turtl.db,
+        pending(db)?.into_iter().take(batch_size()).collect::<Vec<_>>()
+    };
+    for item in &batch {
+        turtl.session().check()?;
+        if let Err(e) = upgrade_one(turtl, item) {
+            warn!("upgrade::run_batch() -- couldn't upgrade {} {}: {}", item.ty, item.id, e);
+        }
+    }
+    with_db!{ db, turtl.db,
+        let mut list = pending(db)?;
+        list.retain(|x| !batch.contains(x));
+        set_pending(db, &list)?;
+        Ok(list.len())
+    }
+}
+
+/// Kick off a `trickle()` (in its own thread, via the `profile:upgrade` app
+/// event) if we have anything to upgrade
+pub fn start(turtl: &Turtl) -> TResult<()> {
+    let waiting = with_db!{ db, turtl.db, pending(db)?.len() };
+    if waiting == 0 { return Ok(()); }
+    messaging::app_event("profile:upgrade", &json!({}))
+}
+
+/// Work through the upgrade list in batches, pausing between them, until it's
+/// empty or the user logs out. Only one of these runs at a time.
+pub fn trickle(turtl: &Turtl) -> TResult<()> {
+    if RUNNING.swap(true, Ordering::SeqCst) { return Ok(()); }
+    let res = trickle_inner(turtl);
+    RUNNING.store(false, Ordering::SeqCst);
+    res
+}
+
+fn trickle_inner(turtl: &Turtl) -> TResult<()> {
+    let session = turtl.session();
+    loop {
+        util::sleep(delay());
+        session.check()?;
+        let left = run_batch(turtl)?;
+        if left == 0 { break; }
+        debug!("upgrade::trickle() -- {} models left to upgrade", left);
+    }
+    info!("upgrade::trickle() -- all models upgraded");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::crypto::{Key, CryptoOp};
+
+    #[test]
+    fn spots_outdated_bodies() {
+        let key = Key::random().unwrap();
+        let enc = crypto::encrypt(&key, Vec::from("{}".as_bytes()), CryptoOp::new("chacha20poly1305").unwrap()).unwrap();
+        let mut old = enc.clone();
+        old[0] = 0;
+        old[1] = 5;
+        assert!(!outdated_body(&crypto::to_base64(&enc).unwrap()));
+        assert!(outdated_body(&crypto::to_base64(&old).unwrap()));
+        assert!(!outdated_body(&String::from("AA")));
+    }
+
+    #[test]
+    fn queues_without_dupes() {
+        let turtl = ::turtl::tests::with_test(true);
+        let item = |id: &str| Pending { ty: String::from("note"), id: String::from(id) };
+        {
+            let db_guard = lock!(turtl.db);
+            let db = db_guard.as_ref().unwrap();
+            assert_eq!(queue(db, vec![item("1"), item("2")]).unwrap(), 2);
+            assert_eq!(queue(db, vec![item("2"), item("3"), item("3")]).unwrap(), 1);
+            assert_eq!(pending(db).unwrap(), vec![item("1"), item("2"), item("3")]);
+        }
+        // the notes don't exist, so they just fall off the list
+        assert_eq!(run_batch(&turtl).unwrap(), 0);
+    }
+}
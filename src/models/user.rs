@@ -1,11 +1,12 @@
 use ::std::collections::HashMap;
 use ::jedi::{self, Value, Serialize};
-use ::error::{TResult, TError};
+use ::error::{TResult, TError, FieldError};
 use ::crypto::{self, Key, CryptoOp};
 use ::api::Status;
 use ::models::model::{self, Model};
 use ::models::space::Space;
 use ::models::board::Board;
+use ::models::avatar::Avatar;
 use ::models::protected::{Keyfinder, Protected};
 use ::models::sync_record::{SyncType, SyncAction, SyncRecord};
 use ::models::validate::{self, Validate};
@@ -14,13 +15,18 @@ use ::api::ApiReq;
 use ::util;
 use ::sync::sync_model::{self, SyncModel, MemorySaver};
 use ::sync::incoming::SyncIncoming;
+use ::sync::seal;
 use ::messaging;
 use ::migrate::MigrateResult;
 use ::std::path::PathBuf;
 use ::std::io::prelude::*;
 use ::std::fs;
 
-pub const CURRENT_AUTH_VERSION: u16 = 0;
+/// The auth version new logins are made with. Version 0 derives the user's
+/// key with scrypt, version 1 with argon2id (see `generate_key()`). Accounts
+/// made under an older version still log in with it, and get moved over to
+/// this one once they do (see `User::prepare_auth_upgrade()`).
+pub const CURRENT_AUTH_VERSION: u16 = 1;
 lazy_static! {
     static ref TOKEN_KEY: Key = Key::new(vec![33, 98, 95, 119, 236, 248, 150, 31, 91, 187, 94, 119, 18, 81, 190, 80, 46, 249, 173, 255, 214, 194, 176, 88, 197, 208, 38, 234, 144, 33, 144, 52]);
 }
@@ -32,8 +38,17 @@ protected! {
         pub auth: Option<String>,
         #[serde(skip)]
         pub logged_in: bool,
+        /// The auth version the user logged in with
+        #[serde(skip)]
+        pub auth_version: u16,
+        /// If the user logged in with an older auth version, their key/auth
+        /// under the current one, waiting for their profile to load so we can
+        /// switch them over (see `User::upgrade_auth()`)
+        #[serde(skip)]
+        pub auth_upgrade: Option<(Key, String)>,
 
         #[protected_field(public)]
+        #[protected_validate(max_len = 256)]
         pub username: String,
 
         #[serde(default)]
@@ -55,6 +70,12 @@ protected! {
         #[serde(skip_serializing_if = "Option::is_none")]
         #[protected_field(private)]
         pub privkey: Option<Key>,
+
+        /// Where to find the user's avatar (and how to open it), see
+        /// `models::avatar`
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[protected_field(private)]
+        pub avatar: Option<Avatar>,
     }
 }
 
@@ -64,15 +85,18 @@ struct LoginToken {
     key: Key,
     auth: String,
     username: String,
+    #[serde(default)]
+    auth_version: u16,
 }
 
 impl LoginToken {
-    fn new(id: String, key: Key, auth: String, username: String) -> LoginToken {
+    fn new(id: String, key: Key, auth: String, username: String, auth_version: u16) -> LoginToken {
         LoginToken {
             id: id,
             key: key,
             auth: auth,
             username: username,
+            auth_version: auth_version,
         }
     }
 }
@@ -88,7 +112,7 @@ impl SyncModel for User {
 impl Keyfinder for User {}
 
 impl Validate for User {
-    fn validate(&self) -> Vec<(String, String)> {
+    fn validate(&self) -> Vec<FieldError> {
         let mut errors = Vec::new();
         if self.username.len() < 3 {
             errors.push(validate::entry("username", t!("Please enter a username 3 characters or longer.")));
@@ -125,15 +149,32 @@ impl MemorySaver for User {
     }
 }
 
+/// The argon2id settings (passes over memory, memory in KiB) each auth version
+/// derives keys with. Every device has to derive the same key, so once a
+/// version is out these can't change: tuning them means adding a new version
+/// (and keeping the old one around so its accounts can still log in).
+fn argon2id_params(version: u16) -> Option<(u32, u32)> {
+    match version {
+        1 => Some((crypto::KEYGEN_ARGON2_OPS_DEFAULT, crypto::KEYGEN_ARGON2_MEM_DEFAULT)),
+        _ => None,
+    }
+}
+
 /// Generate a user's key given some variables or something
 fn generate_key(username: &String, password: &String, version: u16) -> TResult<Key> {
+    let hashme = format!("v{}/{}", version, username);
+    let salt = crypto::sha512(hashme.as_bytes())?;
+    let salt = &salt[0..crypto::KEYGEN_SALT_LEN];
     let key: Key = match version {
         0 => {
-            let hashme = format!("v{}/{}", version, username);
-            let salt = crypto::sha512(hashme.as_bytes())?;
-            crypto::gen_key(password.as_bytes(), &salt[0..crypto::KEYGEN_SALT_LEN], crypto::KEYGEN_OPS_DEFAULT, crypto::KEYGEN_MEM_DEFAULT)?
+            crypto::gen_key(password.as_bytes(), salt, crypto::KEYGEN_OPS_DEFAULT, crypto::KEYGEN_MEM_DEFAULT)?
         },
-        _ => return TErr!(TError::NotImplemented),
+        _ => {
+            match argon2id_params(version) {
+                Some((ops, mem)) => crypto::gen_key_argon2id(password.as_bytes(), salt, ops, mem)?,
+                None => return TErr!(TError::NotImplemented),
+            }
+        }
     };
     Ok(key)
 }
@@ -142,7 +183,9 @@ fn generate_key(username: &String, password: &String, version: u16) -> TResult<K
 pub fn generate_auth(username: &String, password: &String, version: u16) -> TResult<(Key, String)> {
     info!("user::generate_auth() -- generating v{} auth", version);
     let key_auth = match version {
-        0 => {
+        // the key changes between versions, but the auth we make with it
+        // doesn't
+        0 | 1 => {
             let key = generate_key(username, password, version)?;
             let nonce_len = crypto::noncelen();
             let nonce = (crypto::sha512(username.as_bytes())?)[0..nonce_len].to_vec();
@@ -161,7 +204,7 @@ pub fn generate_auth(username: &String, password: &String, version: u16) -> TRes
 /// A function that tries authenticating a username/password against various
 /// versions, starting from latest to earliest until it runs out of versions or
 /// we get a match.
-fn do_login(turtl: &Turtl, username: &String, key: Key, auth: String) -> TResult<()> {
+fn do_login(turtl: &Turtl, username: &String, key: Key, auth: String, version: u16) -> TResult<()> {
     turtl.api.set_auth(username.clone(), auth.clone())?;
     let user_id = turtl.api.post("/auth", ApiReq::new())?;
 
@@ -180,8 +223,9 @@ fn do_login(turtl: &Turtl, username: &String, key: Key, auth: String) -> TResult
     let url = format!("/users/{}", user_id);
     user_guard_w.id = Some(user_id);
     user_guard_w.do_login(key, auth);
+    user_guard_w.auth_version = version;
     drop(user_guard_w);
-    let userdata = turtl.api.get(url.as_str(), ApiReq::new())?;
+    let userdata = turtl.api.get(url.as_str(), ApiReq::new().cache())?;
     let mut user_guard = lockw!(turtl.user);
     user_guard.merge_fields(&userdata)?;
     user_guard.deserialize()?;
@@ -215,7 +259,7 @@ impl User {
     pub fn login(turtl: &Turtl, username: String, password: String, version: u16) -> TResult<()> {
         let username = username.to_lowercase();
         let (key, auth) = generate_auth(&username, &password, version)?;
-        do_login(turtl, &username, key, auth)
+        do_login(turtl, &username, key, auth, version)
             .or_else(|e| {
                 turtl.api.clear_auth();
                 let e = e.shed();
@@ -245,9 +289,9 @@ impl User {
         let token_raw = crypto::decrypt(&(*TOKEN_KEY), token_encrypted)?;
         let tokenjson = String::from_utf8(token_raw)?;
         let token: LoginToken = jedi::parse(&tokenjson)?;
-        let LoginToken {id: _id, key, auth, username} = token;
+        let LoginToken {id: _id, key, auth, username, auth_version} = token;
         let username = username.to_lowercase();
-        do_login(turtl, &username, key, auth)?;
+        do_login(turtl, &username, key, auth, auth_version)?;
         Ok(())
     }
 
@@ -280,6 +324,7 @@ impl User {
         user_guard_w.merge_fields(jedi::walk(&["data"], &joindata)?)?;
         user_guard_w.id = Some(user_id);
         user_guard_w.do_login(key, auth);
+        user_guard_w.auth_version = CURRENT_AUTH_VERSION;
         user_guard_w.deserialize()?;
         drop(user_guard_w);
 
@@ -298,31 +343,85 @@ impl User {
     /// we tried to shoehorn this through the sync system, but this tends to be
     /// a delicate procedure and you really want everything to work or nothing.
     pub fn change_password(&mut self, turtl: &Turtl, current_username: String, current_password: String, new_username: String, new_password: String) -> TResult<()> {
-        validate_user(&new_username, &new_password)?;
-        let new_username = new_username.to_lowercase();
-        let user_id = self.id_or_else()?;
-        let (_, auth) = generate_auth(&current_username, &current_password, CURRENT_AUTH_VERSION)?;
+        self.change_login(turtl, current_username, current_password, new_username, new_password)
+    }
+
+    /// Change the current user's email (their username). Since the user's key
+    /// is derived from their username and password, this is a password change
+    /// in all but name: the same key/auth regeneration, the same bulk post.
+    pub fn change_email(&mut self, turtl: &Turtl, current_username: String, current_password: String, new_email: String) -> TResult<()> {
+        if new_email.to_lowercase() == current_username.to_lowercase() {
+            return TErr!(TError::BadValue(String::from("that's already your email")));
+        }
+        self.change_login(turtl, current_username, current_password.clone(), new_email, current_password)
+    }
+
+    /// Make sure the given username/password are the ones the user logged in
+    /// with. Usernames are lowercased before generating auth, same as
+    /// `login()`.
+    fn check_login(&self, username: &String, password: &String) -> TResult<()> {
+        let (_, auth) = generate_auth(&username.to_lowercase(), password, self.auth_version)?;
         if Some(auth) != self.auth {
             return TErr!(TError::BadValue(String::from("invalid current username/password given")));
         }
+        Ok(())
+    }
+
+    /// Check the user's current login and switch them over to a new
+    /// username/password (see `switch_login()`)
+    fn change_login(&mut self, turtl: &Turtl, current_username: String, current_password: String, new_username: String, new_password: String) -> TResult<()> {
+        validate_user(&new_username, &new_password)?;
+        let new_username = new_username.to_lowercase();
+        self.check_login(&current_username, &current_password)?;
+        let (new_key, new_auth) = generate_auth(&new_username, &new_password, CURRENT_AUTH_VERSION)?;
+        self.switch_login(turtl, new_username, new_key, new_auth)?;
+        util::sleep(3000);
+        Ok(())
+    }
+
+    /// Re-encrypt everything bound to the user's login (the user object, the
+    /// keychain, the settings) for a new username and key/auth (made under
+    /// `CURRENT_AUTH_VERSION`) and send it all to the API in one go. If we
+    /// can't finish switching over locally once the API has taken the change,
+    /// we try to put the old login back on the API so the user isn't locked
+    /// out. Saved searches are re-encrypted once the switch is done, and sync
+    /// like any other edit.
+    fn switch_login(&mut self, turtl: &Turtl, new_username: String, new_key: Key, new_auth: String) -> TResult<()> {
+        let user_id = self.id_or_else()?;
+        let auth = match self.auth.as_ref() {
+            Some(x) => x.clone(),
+            None => return TErr!(TError::MissingField(String::from("User.auth"))),
+        };
+        let old_key = self.key_or_else()?;
 
         let mut new_user = self.clone()?;
         new_user.username = new_username;
-        let (new_key, new_auth) = generate_auth(&new_user.username, &new_password, CURRENT_AUTH_VERSION)?;
         new_user.set_key(Some(new_key.clone()));
         let new_userdata = Protected::serialize(&mut new_user)?;
+        let mut old_user = self.clone()?;
+        let old_userdata = Protected::serialize(&mut old_user)?;
 
-        let encrypted_keychain = {
+        /// Encrypt the keychain and settings with the given key
+        fn login_bound(turtl: &Turtl, key: &Key) -> TResult<(Vec<Value>, Option<Value>)> {
             let profile_guard = lockr!(turtl.profile);
-            let mut new_keys = Vec::with_capacity(profile_guard.keychain.entries.len());
+            let mut keychain = Vec::with_capacity(profile_guard.keychain.entries.len());
             for entry in &profile_guard.keychain.entries {
                 let mut new_entry = entry.clone()?;
-                new_entry.set_key(Some(new_key.clone()));
-                let entrydata = Protected::serialize(&mut new_entry)?;
-                new_keys.push(entrydata);
+                new_entry.set_key(Some(key.clone()));
+                keychain.push(Protected::serialize(&mut new_entry)?);
             }
-            new_keys
-        };
+            let settings = match profile_guard.settings.as_ref() {
+                Some(x) => {
+                    let mut new_settings = x.clone()?;
+                    new_settings.set_key(Some(key.clone()));
+                    Some(Protected::serialize(&mut new_settings)?)
+                }
+                None => None,
+            };
+            Ok((keychain, settings))
+        }
+        let (encrypted_keychain, encrypted_settings) = login_bound(turtl, &new_key)?;
+        let (old_keychain, old_settings) = login_bound(turtl, &old_key)?;
 
         #[derive(Deserialize, Debug)]
         struct PWChangeResponse {
@@ -330,11 +429,20 @@ impl User {
             #[serde(deserialize_with = "::util::ser::opt_vec_str_i64_converter::deserialize")]
             sync_ids: Option<Vec<i64>>,
         }
-        let auth_change = json!({
+        let mut auth_change = json!({
             "user": new_userdata,
             "auth": new_auth,
             "keychain": encrypted_keychain,
         });
+        let mut auth_rollback = json!({
+            "user": old_userdata,
+            "auth": auth,
+            "keychain": old_keychain,
+        });
+        if let (Some(new_settings), Some(old_settings)) = (encrypted_settings, old_settings) {
+            jedi::set(&["settings"], &mut auth_change, &new_settings)?;
+            jedi::set(&["settings"], &mut auth_rollback, &old_settings)?;
+        }
         let url = format!("/users/{}", user_id);
         let res: PWChangeResponse = turtl.api.put(&url[..], ApiReq::new().data(auth_change))?;
         match res.sync_ids.as_ref() {
@@ -348,34 +456,109 @@ impl User {
             None => {}
         }
 
-        turtl.api.set_auth(new_user.username.clone(), new_auth.clone())?;
-        turtl.api.post::<String>("/auth", ApiReq::new())?;
-        self.do_login(new_key.clone(), new_auth);
-        sync_model::save_model(SyncAction::Edit, turtl, self, true)?;
-
-        // save the user's new key into the keychain entries
-        {
-            let mut profile_guard = lockw!(turtl.profile);
-            let mut db_guard = lock!(turtl.db);
-            let db = match (*db_guard).as_mut() {
-                Some(x) => x,
-                None => return TErr!(TError::MissingField(format!("Turtl.db"))),
-            };
-            let user_id = turtl.user_id()?;
-            for entry in &mut profile_guard.keychain.entries {
-                entry.set_key(Some(new_key.clone()));
-                // NOTE: sync_model::save_model() will call mem_update() on our
-                // keychain entry, which is bad because that locks the profile
-                // (which, as you can see above, is already locked).
-                //
-                // we kind of side-step syncing here by just directly calling our
-                // heroic outgoing() function which saves the object in the db for
-                // us. this is pretty much all we'd need save_model() for anyway, so
-                // why give it the satisfaction of deadlocking the app?
-                entry.outgoing(SyncAction::Edit, &user_id, db, true)?;
+        let old_username = self.username.clone();
+        let switched = turtl.api.set_auth(new_user.username.clone(), new_auth.clone())
+            .and_then(|_| turtl.api.post::<String>("/auth", ApiReq::new()))
+            .and_then(|_| {
+                self.username = new_user.username.clone();
+                self.do_login(new_key.clone(), new_auth.clone());
+                sync_model::save_model(SyncAction::Edit, turtl, self, true)
+            });
+        if let Err(e) = switched {
+            error!("User.change_login() -- error switching to the new login, rolling back: {}", e);
+            self.username = old_username.clone();
+            self.do_login(old_key, auth.clone());
+            // the API only knows the new login now, so that's what we use to
+            // ask for the old one back
+            let rollback = turtl.api.set_auth(new_user.username.clone(), new_auth.clone())
+                .and_then(|_| turtl.api.put::<Value>(&url[..], ApiReq::new().data(auth_rollback)));
+            turtl.api.set_auth(old_username, auth)?;
+            if let Err(err) = rollback {
+                error!("User.change_login() -- error rolling back the login change, the new login stands: {}", err);
             }
+            return Err(e);
+        }
+
+        User::rekey_local(turtl, &new_key)?;
+        self.auth_version = CURRENT_AUTH_VERSION;
+        // a saved login has the old key/auth in it, so it's no good anymore
+        User::clear_saved_login(&user_id)?;
+        Ok(())
+    }
+
+    /// Re-encrypt everything we have locally that's bound to the user's login
+    /// (keychain, settings, saved searches, and the key for our outgoing sync
+    /// queue) with their new key.
+    fn rekey_local(turtl: &Turtl, new_key: &Key) -> TResult<()> {
+        let mut profile_guard = lockw!(turtl.profile);
+        let mut db_guard = lock!(turtl.db);
+        let db = match (*db_guard).as_mut() {
+            Some(x) => x,
+            None => return TErr!(TError::MissingField(format!("Turtl.db"))),
+        };
+        // our outgoing sync queue's key is wrapped with the user key too
+        seal::rewrap(db, new_key)?;
+        let user_id = turtl.user_id()?;
+        for entry in &mut profile_guard.keychain.entries {
+            entry.set_key(Some(new_key.clone()));
+            // the entries' bodies are still sealed with the old key, so they
+            // need re-encrypting before they're stored. the API gets the same
+            // thing in the bulk post, but our local copies are what we open
+            // at the next login.
+            Protected::serialize(entry)?;
+            // NOTE: sync_model::save_model() will call mem_update() on our
+            // keychain entry, which is bad because that locks the profile
+            // (which, as you can see above, is already locked).
+            //
+            // we kind of side-step syncing here by just directly calling our
+            // heroic outgoing() function which saves the object in the db for
+            // us. this is pretty much all we'd need save_model() for anyway, so
+            // why give it the satisfaction of deadlocking the app?
+            entry.outgoing(SyncAction::Edit, &user_id, db, true)?;
+        }
+        if let Some(settings) = profile_guard.settings.as_mut() {
+            settings.set_key(Some(new_key.clone()));
+            Protected::serialize(settings)?;
+            settings.outgoing(SyncAction::Edit, &user_id, db, true)?;
+        }
+        // the API doesn't know about saved searches as part of the login,
+        // so they go out as regular edits
+        for search in &mut profile_guard.saved_searches {
+            search.set_key(Some(new_key.clone()));
+            Protected::serialize(search)?;
+            search.outgoing(SyncAction::Edit, &user_id, db, false)?;
         }
-        util::sleep(3000);
+        Ok(())
+    }
+
+    /// If the user just logged in under an older auth version, make their
+    /// key/auth under the current one so `upgrade_auth()` can switch them over
+    /// once their profile is loaded. We do this while we still have their
+    /// password.
+    pub fn prepare_auth_upgrade(turtl: &Turtl, username: &String, password: &String) -> TResult<()> {
+        let version = lockr!(turtl.user).auth_version;
+        if version >= CURRENT_AUTH_VERSION { return Ok(()); }
+        info!("User::prepare_auth_upgrade() -- logged in with v{} auth, upgrading to v{}", version, CURRENT_AUTH_VERSION);
+        let upgrade = generate_auth(&username.to_lowercase(), password, CURRENT_AUTH_VERSION)?;
+        lockw!(turtl.user).auth_upgrade = Some(upgrade);
+        Ok(())
+    }
+
+    /// Switch a user who logged in under an older auth version over to the
+    /// current one (see `prepare_auth_upgrade()`), re-encrypting everything
+    /// bound to their login with the new key. This needs their profile loaded,
+    /// and should happen before the sync threads start.
+    /// Their old key opens everything until the switch is done, so if it
+    /// fails, we just try again at their next login.
+    pub fn upgrade_auth(turtl: &Turtl) -> TResult<()> {
+        let mut user_guard = lockw!(turtl.user);
+        let (new_key, new_auth) = match user_guard.auth_upgrade.take() {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let username = user_guard.username.clone();
+        user_guard.switch_login(turtl, username, new_key, new_auth)?;
+        info!("User::upgrade_auth() -- upgraded to v{} auth", CURRENT_AUTH_VERSION);
         Ok(())
     }
 
@@ -524,10 +707,13 @@ impl User {
         Ok(())
     }
 
-    /// Delete the current user
-    pub fn delete_account(turtl: &Turtl) -> TResult<()> {
+    /// Delete the current user. The username/password have to match the ones
+    /// the user is logged in with, so a device left unlocked can't throw the
+    /// account away on its own.
+    pub fn delete_account(turtl: &Turtl, username: String, password: String) -> TResult<()> {
         let id = {
             let user_guard = lockr!(turtl.user);
+            user_guard.check_login(&username, &password)?;
             user_guard.id_or_else()?
         };
         turtl.api.delete::<bool>(format!("/users/{}", id).as_str(), ApiReq::new())?;
@@ -568,7 +754,7 @@ impl User {
             Some(auth) => auth.clone(),
             None => return TErr!(TError::MissingField(String::from("turtl.user.auth"))),
         };
-        let token = LoginToken::new(turtl.user_id()?, user_guard.key_or_else()?, auth, user_guard.username.clone());
+        let token = LoginToken::new(turtl.user_id()?, user_guard.key_or_else()?, auth, user_guard.username.clone(), user_guard.auth_version);
         let tokenstr = jedi::stringify(&token)?;
         // add a little bit more protection. obviously, an attacker can just
         // grab this key from the source, but this might stop some less
@@ -594,6 +780,17 @@ impl User {
         Ok(key)
     }
 
+    /// Delete a login saved via User::save_login(), if there is one
+    pub fn clear_saved_login(user_id: &String) -> TResult<()> {
+        let mut filepath = PathBuf::from(util::file_folder(None)?);
+        filepath.push(user_id.clone() + ".login");
+        if filepath.exists() {
+            fs::remove_file(&filepath)?;
+            info!("User::clear_saved_login() -- removed {:?}", filepath);
+        }
+        Ok(())
+    }
+
     /// Restores a login (saved via User::save_login()) given a user_id/key.
     pub fn restore_login(user_id: String, key: Key) -> TResult<String> {
         let mut filepath = PathBuf::from(util::file_folder(None)?);
@@ -622,6 +819,8 @@ impl User {
         self.set_key(None);
         self.auth = None;
         self.logged_in = false;
+        self.auth_version = 0;
+        self.auth_upgrade = None;
     }
 
     /// Set a setting into this user's settings object
@@ -643,18 +842,63 @@ impl User {
         Ok(())
     }
 
-    /// Given an email address, find a matching user (pubkey and all)
+    /// Grab the user's favorites (kept in `settings.favorites`)
+    pub fn favorites(&self) -> Favorites {
+        self.settings.as_ref()
+            .and_then(|x| x.get("favorites"))
+            .and_then(|x| jedi::from_val(x.clone()).ok())
+            .unwrap_or(Default::default())
+    }
+
+    /// Add an item to (or remove it from) the user's favorites. Saves the user
+    /// (so the change syncs) and returns the updated favorites.
+    pub fn set_favorite(&mut self, turtl: &Turtl, ty: &SyncType, item_id: &String, favorite: bool) -> TResult<Favorites> {
+        let mut favorites = self.favorites();
+        {
+            let list = match *ty {
+                SyncType::Space => &mut favorites.spaces,
+                SyncType::Board => &mut favorites.boards,
+                SyncType::Note => &mut favorites.notes,
+                _ => return TErr!(TError::BadValue(format!("can't favorite items of type {:?}", ty))),
+            };
+            list.retain(|x| x != item_id);
+            if favorite { list.push(item_id.clone()); }
+        }
+        self.set_setting(turtl, "favorites", &favorites)?;
+        Ok(favorites)
+    }
+
+    /// Given an email address, find a matching user (pubkey and all), or None
+    /// if nobody has an account under that email. Not cached, since a stale
+    /// pubkey is worse than a slow lookup.
     pub fn find_by_email(turtl: &Turtl, email: &String) -> TResult<Option<User>> {
         let url = format!("/users/email/{}", email.to_lowercase());
         turtl.api.get(url.as_str(), ApiReq::new())
+            .or_else(|e| {
+                match e.shed() {
+                    TError::Api(Status::NotFound, _) => Ok(None),
+                    e => Err(e),
+                }
+            })
     }
 }
 
+/// The spaces/boards/notes a user has marked as favorites, in the order they
+/// were added
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Favorites {
+    pub spaces: Vec<String>,
+    pub boards: Vec<String>,
+    pub notes: Vec<String>,
+}
+
 #[cfg(test)]
 mod tests {
     //! Tests for our high-level Crypto module interface.
 
     use super::*;
+    use ::models::keychain::{self, Keychain};
 
     #[test]
     pub fn authgen() {
@@ -663,4 +907,90 @@ mod tests {
         let (_key, auth) = generate_auth(&username, &password, 0).unwrap();
         assert_eq!(auth, "000601000c9af06607bbb78b0cab4e01f29a8d06da9a65e5698768b88ac4f4c04002c96fcfcb18a1644d5ba2546901452d0ebd6c162fe494997b52660d9d190ed525076523a1a576ea7596fdaec2e0f0606f3290bd6e5815f76889a4eada71fc20dad21703453928c74db36880cf6035922e3f7093ed1eef01a630750ebd8d64baaf34e325536011de40f3a72a4d95155ca32e851257d8bc7736d2d41c92213e93");
     }
+
+    #[test]
+    pub fn authgen_argon2id() {
+        let username = String::from("andrew@lyonbros.com");
+        let password = String::from("slippy");
+        let (key, auth) = generate_auth(&username, &password, 1).unwrap();
+        assert_eq!(key.data(), &vec![8, 45, 58, 246, 202, 28, 119, 65, 132, 129, 223, 140, 90, 20, 129, 127, 92, 148, 77, 82, 24, 52, 253, 191, 204, 193, 253, 94, 239, 10, 91, 219]);
+        // same auth record as before, just under the new key
+        let dec = crypto::decrypt(&key, crypto::from_hex(&auth).unwrap()).unwrap();
+        assert_eq!(String::from_utf8(dec).unwrap(), crypto::to_hex(&crypto::sha512(password.as_bytes()).unwrap()).unwrap());
+        assert!(generate_auth(&username, &password, CURRENT_AUTH_VERSION + 1).is_err());
+    }
+
+    #[test]
+    fn checks_logins_case_insensitively() {
+        let turtl = ::turtl::tests::with_test(true);
+        let mut user_guard = lockw!(turtl.user);
+        let password = String::from("slippy");
+        let (_, auth) = generate_auth(&String::from("slippyslappy@turtlapp.com"), &password, 0).unwrap();
+        user_guard.auth = Some(auth);
+        user_guard.auth_version = 0;
+        user_guard.check_login(&String::from("slippyslappy@turtlapp.com"), &password).unwrap();
+        user_guard.check_login(&String::from("SlippySlappy@TurtlApp.com"), &password).unwrap();
+        assert!(user_guard.check_login(&String::from("slippyslappy@turtlapp.com"), &String::from("Slippy")).is_err());
+        assert!(user_guard.check_login(&String::from("slappyslippy@turtlapp.com"), &password).is_err());
+    }
+
+    #[test]
+    fn upgrades_auth_keeping_the_keychain() {
+        let turtl = ::turtl::tests::with_test(true);
+        let username = String::from("slippyslappy@turtlapp.com");
+        let password = String::from("slippy");
+        let space_id = String::from("1234");
+        let space_key = Key::random().unwrap();
+
+        // a v0 account with a key in its keychain
+        let (key0, auth0) = generate_auth(&username, &password, 0).unwrap();
+        {
+            let mut user_guard = lockw!(turtl.user);
+            user_guard.do_login(key0.clone(), auth0);
+            user_guard.auth_version = 0;
+        }
+        keychain::save_key(&turtl, &space_id, &space_key, &String::from("space"), true).unwrap();
+
+        // logging in gets the upgrade ready, and the local half of the switch
+        // re-keys what's bound to the login
+        User::prepare_auth_upgrade(&turtl, &String::from("SlippySlappy@turtlapp.com"), &password).unwrap();
+        let (new_key, new_auth) = lockw!(turtl.user).auth_upgrade.take().unwrap();
+        assert!(new_key != key0);
+        User::rekey_local(&turtl, &new_key).unwrap();
+
+        // the next login goes straight to v1, and the old keychain opens with it
+        let (key1, auth1) = generate_auth(&username, &password, CURRENT_AUTH_VERSION).unwrap();
+        assert_eq!(key1, new_key);
+        assert_eq!(auth1, new_auth);
+        {
+            let mut user_guard = lockw!(turtl.user);
+            user_guard.do_login(key1, auth1);
+            user_guard.auth_version = CURRENT_AUTH_VERSION;
+        }
+        lockw!(turtl.profile).keychain = Keychain::new();
+        turtl.load_profile().unwrap();
+        assert_eq!(lockr!(turtl.profile).keychain.find_key(&space_id), Some(space_key));
+    }
+
+    #[test]
+    fn prepares_auth_upgrades() {
+        let turtl = ::turtl::tests::with_test(true);
+        let username = String::from("Slippyslappy@turtlapp.com");
+        let password = String::from("slippy");
+        User::prepare_auth_upgrade(&turtl, &username, &password).unwrap();
+        let (key, auth) = lockr!(turtl.user).auth_upgrade.clone().unwrap();
+        let (key2, auth2) = generate_auth(&username.to_lowercase(), &password, CURRENT_AUTH_VERSION).unwrap();
+        assert_eq!(key, key2);
+        assert_eq!(auth, auth2);
+
+        // nothing to do for users already on the current version
+        {
+            let mut user_guard = lockw!(turtl.user);
+            user_guard.auth_upgrade = None;
+            user_guard.auth_version = CURRENT_AUTH_VERSION;
+        }
+        User::prepare_auth_upgrade(&turtl, &username, &password).unwrap();
+        assert!(lockr!(turtl.user).auth_upgrade.is_none());
+        User::upgrade_auth(&turtl).unwrap();
+    }
 }
@@ -1,21 +1,39 @@
 //! Defines a trait that performs model data validation.
+//!
+//! Simple checks are declared on a protected model's fields, and run by the
+//! code `#[derive(Protected)]` generates (see `Protected::validate_fields()`):
+//!
+//! ```ignore
+//! #[protected_field(private)]
+//! #[protected_validate(required = "Please give your board a title", max_len = 256)]
+//! pub title: Option<String>,
+//! ```
+//!
+//! The rules are `required` (optionally with the message to use), `max_len`
+//! (in characters for text, items for lists), and `url`. Anything fancier goes
+//! in the model's `Validate::validate()`.
 
-use ::error::{TResult, TError};
+use ::url::Url;
+use ::error::{TResult, TError, FieldError};
+use ::models::protected::Protected;
 
-pub trait Validate {
-    /// Determines if the model is fit for saving.
+pub trait Validate: Protected {
+    /// Determines if the model is fit for saving. Checks declared on fields
+    /// with `#[protected_validate(...)]` run on their own, so this only needs
+    /// to do the rest.
     ///
-    /// Returns a vec of errors pairs (field, error) if there were problems.
+    /// Returns a vec of field errors if there were problems.
     ///
     /// Override me!
-    fn validate(&self) -> Vec<(String, String)> {
+    fn validate(&self) -> Vec<FieldError> {
         Vec::new()
     }
 
     /// Called by the app, mainly, and used as a quick way to return an error
     /// if validaton fails.
     fn do_validate(&self, model_type: String) -> TResult<()> {
-        let errors = self.validate();
+        let mut errors = self.validate_fields();
+        errors.append(&mut self.validate());
         if errors.len() > 0 {
             return TErr!(TError::Validation(model_type, errors));
         }
@@ -24,9 +42,148 @@ pub trait Validate {
 }
 
 /// Create an error entry
-pub fn entry<T>(field: T, message: T) -> (String, String)
+pub fn entry<T>(field: T, message: T) -> FieldError
     where T: Into<String>
 {
-    (field.into(), message.into())
+    FieldError {
+        field: field.into(),
+        rule: String::from("invalid"),
+        message: message.into(),
+    }
+}
+
+/// Lets the declared checks work on the different types fields come in
+pub trait FieldValue {
+    /// Whether there's anything here (non-blank text, a non-empty list)
+    fn is_present(&self) -> bool;
+
+    /// How long the value is, if that means anything for it
+    fn length(&self) -> Option<usize> {
+        None
+    }
+
+    /// The value, if it's text
+    fn text(&self) -> Option<&str> {
+        None
+    }
 }
 
+impl FieldValue for String {
+    fn is_present(&self) -> bool {
+        self.trim() != ""
+    }
+
+    fn length(&self) -> Option<usize> {
+        Some(self.chars().count())
+    }
+
+    fn text(&self) -> Option<&str> {
+        Some(self.as_str())
+    }
+}
+
+impl<T: FieldValue> FieldValue for Option<T> {
+    fn is_present(&self) -> bool {
+        self.as_ref().map(|x| x.is_present()).unwrap_or(false)
+    }
+
+    fn length(&self) -> Option<usize> {
+        self.as_ref().and_then(|x| x.length())
+    }
+
+    fn text(&self) -> Option<&str> {
+        self.as_ref().and_then(|x| x.text())
+    }
+}
+
+impl<T> FieldValue for Vec<T> {
+    fn is_present(&self) -> bool {
+        self.len() > 0
+    }
+
+    fn length(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
+
+impl FieldValue for i64 {
+    fn is_present(&self) -> bool {
+        true
+    }
+}
+
+impl FieldValue for bool {
+    fn is_present(&self) -> bool {
+        true
+    }
+}
+
+/// The `required` rule
+pub fn required<T: FieldValue>(field: &str, val: &T, message: Option<&str>) -> Option<FieldError> {
+    if val.is_present() { return None; }
+    Some(FieldError {
+        field: String::from(field),
+        rule: String::from("required"),
+        message: match message {
+            Some(x) => String::from(x),
+            None => format!("{} {}", field, t!("is required")),
+        },
+    })
+}
+
+/// The `max_len` rule
+pub fn max_len<T: FieldValue>(field: &str, val: &T, max: usize) -> Option<FieldError> {
+    match val.length() {
+        Some(len) if len > max => {
+            Some(FieldError {
+                field: String::from(field),
+                rule: String::from("max_len"),
+                message: format!("{} {} {}", field, t!("can't be longer than"), max),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// The `url` rule. Blank is fine (that's what `required` is for), as is a
+/// missing scheme ("turtlapp.com").
+pub fn url<T: FieldValue>(field: &str, val: &T) -> Option<FieldError> {
+    let text = match val.text() {
+        Some(x) if x.trim() != "" => x.trim(),
+        _ => return None,
+    };
+    let valid = Url::parse(text).is_ok() ||
+        Url::parse(&format!("http://{}", text))
+            .map(|x| x.host_str().map(|h| h.contains('.') || h == "localhost").unwrap_or(false))
+            .unwrap_or(false);
+    if valid { return None; }
+    Some(FieldError {
+        field: String::from(field),
+        rule: String::from("url"),
+        message: format!("{} {}", field, t!("isn't a valid URL")),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_rules() {
+        assert!(required("title", &Some(String::from("hi")), None).is_none());
+        assert_eq!(required("title", &Some(String::from("  ")), Some("Please give it a title")).unwrap().message, "Please give it a title");
+        assert_eq!(required("title", &None::<String>, None).unwrap().rule, "required");
+        assert!(required("tags", &Vec::<String>::new(), None).is_some());
+
+        assert!(max_len("title", &String::from("\u{2620}\u{2620}\u{2620}"), 3).is_none());
+        assert_eq!(max_len("title", &String::from("abcd"), 3).unwrap().rule, "max_len");
+        assert!(max_len("tags", &Some(vec![1, 2, 3, 4]), 3).is_some());
+        assert!(max_len("title", &None::<String>, 3).is_none());
+
+        assert!(url("url", &Some(String::from("https://turtlapp.com/download"))).is_none());
+        assert!(url("url", &Some(String::from("turtlapp.com"))).is_none());
+        assert!(url("url", &Some(String::from(""))).is_none());
+        assert!(url("url", &None::<String>).is_none());
+        assert_eq!(url("url", &Some(String::from("not a url"))).unwrap().rule, "url");
+    }
+}
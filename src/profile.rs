@@ -1,12 +1,14 @@
 //! The Profile module exports a struct that is responsible for handling and
 //! storing the user's data (keychain, boards, etc) in-memory.
 //!
-//! It only stores data for the keychain, persona (soon deprecated), and boards
-//! (so no note data). The reason is that keychain/boards are useful to keep in
-//! memory to decrypt notes, but otherwise, notes can just be loaded on the fly
-//! from local storage and discarded once sent to the UI.
+//! It stores the keychain, spaces, boards, settings, and saved searches in full. The reason is
+//! that keychain/boards are useful to keep in memory to decrypt notes, but
+//! otherwise, notes are loaded on the fly from local storage. We hold on to the
+//! most recently used of those (decrypted) in a small cache so opening the same
+//! notes over and over doesn't mean decrypting them over and over.
 
 use ::std::collections::HashMap;
+use ::std::sync::Mutex;
 use ::turtl::Turtl;
 use ::error::{TResult, TError};
 use ::jedi::{self, Value};
@@ -17,6 +19,8 @@ use ::models::board::Board;
 use ::models::note::Note;
 use ::models::file::FileData;
 use ::models::invite::Invite;
+use ::models::settings::Settings;
+use ::models::saved_search::SavedSearch;
 use ::models::protected::{self, Protected};
 use ::models::sync_record::{SyncRecord, SyncAction, SyncType};
 use ::models::storable::Storable;
@@ -25,6 +29,8 @@ use ::lib_permissions::Permission;
 use ::config;
 use ::crypto;
 use ::messaging;
+use ::util::cancel::CancelToken;
+use ::util::lru::Lru;
 
 /// A structure holding a collection of objects that represent's a user's
 /// Turtl data profile.
@@ -33,6 +39,13 @@ pub struct Profile {
     pub spaces: Vec<Space>,
     pub boards: Vec<Board>,
     pub invites: Vec<Invite>,
+    /// The user's (synced) preferences, if they've set any
+    pub settings: Option<Settings>,
+    /// The user's saved searches
+    pub saved_searches: Vec<SavedSearch>,
+    /// The notes we decrypted most recently, by id. Anything that changes a
+    /// note (see `Note::mem_update()`) drops it from here.
+    pub notes: Mutex<Lru<String, Note>>,
 }
 
 /// A struct for holding a profile export
@@ -65,6 +78,11 @@ pub enum ImportMode {
     Full,
 }
 
+/// How many decrypted notes we keep around
+fn note_cache_size() -> usize {
+    config::get(&["profile", "note_cache"]).unwrap_or(200)
+}
+
 impl Profile {
     pub fn new() -> Profile {
         Profile {
@@ -72,6 +90,9 @@ impl Profile {
             spaces: Vec::new(),
             boards: Vec::new(),
             invites: Vec::new(),
+            settings: None,
+            saved_searches: Vec::new(),
+            notes: Mutex::new(Lru::new(note_cache_size())),
         }
     }
 
@@ -81,6 +102,9 @@ impl Profile {
         self.spaces = Vec::new();
         self.boards = Vec::new();
         self.invites = Vec::new();
+        self.settings = None;
+        self.saved_searches = Vec::new();
+        self.notes = Mutex::new(Lru::new(note_cache_size()));
     }
 
     /// Find a model by id in a collection of items
@@ -155,6 +179,8 @@ impl Profile {
             crypto::to_hex(&crypto::sha256(key.as_bytes())?)?
         };
         info!("Profile::import() -- running import (mode: {}, cid: {})", jedi::stringify(&mode)?, client_id);
+        // if the user logs out mid-import, stop between models
+        let session = turtl.session();
         // the import result details what changed
         let mut result = ImportResult::default();
 
@@ -187,6 +213,7 @@ impl Profile {
             };
             let user_id = turtl.user_id()?;
             for space in spaces {
+                session.check()?;
                 // it would be a bad (read: terrible) idea to remove a space
                 // that doesn't belong to us. the API won't let us, and it will
                 // end up gumming up the sync system.
@@ -216,11 +243,12 @@ impl Profile {
         // define a function that runs our sync dispatcher for the incoming
         // import models. note that this runs all of our permission checks for
         // us! yay, abstraction.
-        fn saver<T, F>(turtl: &Turtl, mode: &ImportMode, client_id: &String, models: Vec<T>, ty: SyncType, mut ser: F, id_change_map: &mut HashMap<String, String>, result: &mut ImportResult, counter: &mut Counter) -> TResult<()>
+        fn saver<T, F>(turtl: &Turtl, session: &CancelToken, mode: &ImportMode, client_id: &String, models: Vec<T>, ty: SyncType, mut ser: F, id_change_map: &mut HashMap<String, String>, result: &mut ImportResult, counter: &mut Counter) -> TResult<()>
             where T: Protected + Storable,
                   F: FnMut(&T, &mut HashMap<String, String>, &String) -> TResult<Value>
         {
             for mut model in models {
+                session.check()?;
                 let model_id = model.id_or_else()?;
                 let new_id = model::cid_w_client_id(&model_id, &client_id)?;
                 let (id, exists) = {
@@ -287,13 +315,13 @@ impl Profile {
         }
 
         let mut counter = Counter { count: 0 };
-        saver(turtl, &mode, &client_id, spaces, SyncType::Space, |x, _map, _old_id| { x.data() }, &mut id_change_map, &mut result, &mut counter)?;
-        saver(turtl, &mode, &client_id, boards, SyncType::Board, |x, id_change_map, _old_id| {
+        saver(turtl, &session, &mode, &client_id, spaces, SyncType::Space, |x, _map, _old_id| { x.data() }, &mut id_change_map, &mut result, &mut counter)?;
+        saver(turtl, &session, &mode, &client_id, boards, SyncType::Board, |x, id_change_map, _old_id| {
             let mut data = x.data()?;
             switch_id_if_needed(id_change_map, &mut data, "space_id")?;
             Ok(data)
         }, &mut id_change_map, &mut result, &mut counter)?;
-        saver(turtl, &mode, &client_id, notes, SyncType::Note, |x, id_change_map, old_id| {
+        saver(turtl, &session, &mode, &client_id, notes, SyncType::Note, |x, id_change_map, old_id| {
             let mut data = x.data()?;
             switch_id_if_needed(id_change_map, &mut data, "space_id")?;
             switch_id_if_needed(id_change_map, &mut data, "board_id")?;
@@ -0,0 +1,155 @@
+//! Versioning for the dispatch protocol (the commands a UI can send the core).
+//!
+//! A UI starts by sending `app:handshake` with the protocol version it speaks.
+//! We refuse versions we don't support, so a mismatched UI/core pair fails
+//! loudly up front instead of half-working.
+//!
+//! Commands (or argument shapes) we want to phase out are listed in
+//! `DEPRECATIONS`. They keep working, but any response to them carries a
+//! deprecation warning (in the response's `w` field) and we count how often
+//! each one is used so we know when it's safe to remove.
+
+use ::std::sync::RwLock;
+use ::std::collections::HashMap;
+
+use ::jedi::{self, Value};
+
+use ::error::{TResult, TError};
+
+/// The protocol version this core speaks
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// The oldest protocol version we still accept from a UI
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Describes a deprecated command, or a deprecated way of calling a command
+struct Deprecation {
+    /// The command this applies to
+    cmd: &'static str,
+    /// If set, only calls with this argument shape are deprecated. Gets the
+    /// full message (`[mid, cmd, args...]`).
+    shape: Option<fn(&Value) -> bool>,
+    /// The protocol version the deprecation happened in
+    since: u32,
+    /// What to tell the UI
+    message: &'static str,
+}
+
+/// True if `sync:shutdown` was called without an explicit `wait` arg
+fn implicit_wait(data: &Value) -> bool {
+    jedi::walk(&["2"], data).is_err()
+}
+
+/// Everything we've deprecated. Once an entry's usage count stays at zero for
+/// a release or two, the command (or shape) can go.
+static DEPRECATIONS: &'static [Deprecation] = &[
+    Deprecation {
+        cmd: "sync:shutdown",
+        shape: Some(implicit_wait),
+        since: 2,
+        message: "pass `wait` (bool) explicitly as the first argument",
+    },
+];
+
+lazy_static! {
+    /// How many times each deprecated command has been used
+    static ref USAGE: RwLock<HashMap<String, u64>> = RwLock::new(HashMap::new());
+}
+
+/// A warning attached to a response
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Warning {
+    /// The kind of warning (currently always "deprecated")
+    #[serde(rename = "type")]
+    pub ty: String,
+    /// The command that triggered the warning
+    pub cmd: String,
+    /// The protocol version the deprecation happened in
+    pub since: u32,
+    /// A human-readable explanation, generally including what to do instead
+    pub message: String,
+}
+
+/// What we send back from a handshake
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Handshake {
+    pub protocol_version: u32,
+    pub min_protocol_version: u32,
+    /// Every deprecated command, so a UI can check itself up front
+    pub deprecated: Vec<Warning>,
+}
+
+/// Turn a deprecation into a warning
+fn to_warning(deprecation: &Deprecation) -> Warning {
+    Warning {
+        ty: String::from("deprecated"),
+        cmd: String::from(deprecation.cmd),
+        since: deprecation.since,
+        message: String::from(deprecation.message),
+    }
+}
+
+/// Check a UI's protocol version, returning our handshake info if we can talk
+/// to it.
+pub fn handshake(version: u32) -> TResult<Handshake> {
+    if version < MIN_PROTOCOL_VERSION || version > PROTOCOL_VERSION {
+        return TErr!(TError::BadValue(format!("unsupported protocol version {} (this core supports {} - {})", version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION)));
+    }
+    info!("protocol::handshake() -- UI speaks protocol v{}", version);
+    Ok(Handshake {
+        protocol_version: PROTOCOL_VERSION,
+        min_protocol_version: MIN_PROTOCOL_VERSION,
+        deprecated: DEPRECATIONS.iter().map(to_warning).collect::<Vec<_>>(),
+    })
+}
+
+/// Find any deprecation warnings for an incoming command, counting each use.
+pub fn check(cmd: &String, data: &Value) -> Vec<Warning> {
+    let warnings = DEPRECATIONS.iter()
+        .filter(|x| x.cmd == cmd.as_str())
+        .filter(|x| match x.shape {
+            Some(shape) => shape(data),
+            None => true,
+        })
+        .map(to_warning)
+        .collect::<Vec<_>>();
+    if warnings.len() > 0 {
+        warn!("protocol::check() -- deprecated call to {}: {}", cmd, warnings.iter().map(|x| x.message.as_str()).collect::<Vec<_>>().join("; "));
+        let mut guard = lockw!((*USAGE));
+        let count = guard.entry(cmd.clone()).or_insert(0);
+        *count += 1;
+    }
+    warnings
+}
+
+/// Grab our deprecated command usage counts
+pub fn usage() -> HashMap<String, u64> {
+    let guard = lockr!((*USAGE));
+    guard.clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshakes() {
+        assert!(handshake(0).is_err());
+        assert!(handshake(PROTOCOL_VERSION + 1).is_err());
+        let shake = handshake(PROTOCOL_VERSION).unwrap();
+        assert_eq!(shake.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(shake.deprecated.len(), DEPRECATIONS.len());
+    }
+
+    #[test]
+    fn warns_on_deprecated_calls() {
+        let cmd = String::from("sync:shutdown");
+        assert_eq!(check(&cmd, &json!(["1", "sync:shutdown", false])).len(), 0);
+        let warnings = check(&cmd, &json!(["1", "sync:shutdown"]));
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].cmd, "sync:shutdown");
+        assert_eq!(warnings[0].ty, "deprecated");
+        assert!(usage().get(&cmd).unwrap() >= &1);
+        assert_eq!(check(&String::from("ping"), &json!(["3", "ping"])).len(), 0);
+    }
+}
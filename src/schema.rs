@@ -41,6 +41,13 @@ pub fn get_schema() -> Value {
                 {"name": "sync", "fields": ["type", "frozen"]}
             ]
         },
+        "saved_searches": {},
+        "settings": {},
+        "templates": {
+            "indexes": [
+                {"fields": ["space_id"]}
+            ]
+        },
         "user": {}
     })
 }
@@ -7,102 +7,1046 @@
 
 use ::rusqlite::types::ToSql;
 
-use ::clouseau::Clouseau;
+use ::clouseau::{fuzzy, Clouseau, SegmentedIndex, SegmentConfig, TokenSealer};
+use ::clouseau::prefix::PrefixIndex;
+use ::clouseau::highlight::{self, Needle};
 use ::dumpy::SearchVal;
 
+use ::std::cmp::Ordering;
+use ::std::fs;
+use ::std::path::PathBuf;
+use ::std::collections::{HashMap, HashSet};
+use ::jedi::Value;
+
+use ::time;
+
+use ::config;
+use ::util;
+use ::crypto::{self, Key};
 use ::error::{TResult, TError};
 use ::models::model;
 use ::models::note::Note;
 use ::models::file::File;
+use ::search_store;
 
 /// A query builder
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Query {
+    /// Full-text search. This can also hold field-scoped searches
+    /// (`url:github.com`, `title:"project x"`), which are pulled out into
+    /// `scoped` (see `split_scoped()`), and date filters (`before:2023-01-01`,
+    /// `last-week`), which narrow `modified` (see `split_dates()`).
     pub text: Option<String>,
+    /// Notes have to have all of these fields containing the given text
+    #[serde(default)]
+    pub scoped: Vec<ScopedText>,
+    /// If true, `text` only matches the words as typed (otherwise we also
+    /// match close misspellings of them, ranked below the exact matches)
+    #[serde(default)]
+    pub exact: bool,
     #[serde(default)]
     pub notes: Vec<String>,
     pub space_id: String,
     #[serde(default)]
     pub boards: Vec<String>,
+    /// Notes have to have all of these tags...
     #[serde(default)]
     pub tags: Vec<String>,
+    /// ...and at least one of these...
+    #[serde(default)]
+    pub any_tags: Vec<String>,
+    /// ...and none of these
     #[serde(default)]
     pub exclude_tags: Vec<String>,
+    /// Filters on notes' custom fields (all of them have to match)
+    #[serde(default)]
+    pub fields: Vec<FieldFilter>,
     #[serde(rename = "type")]
     pub type_: Option<String>,
     pub url: Option<String>,
     pub has_file: Option<bool>,
     pub color: Option<i32>,
+    pub pinned: Option<bool>,
+    /// Only notes last modified in this range. Date filters in `text` narrow
+    /// this further.
+    pub modified: Option<DateRange>,
+    /// If true, pinned notes come before the rest (each sorted by `sort`)
+    #[serde(default)]
+    pub pinned_first: bool,
+    /// What to sort by: `relevance` (the default when searching by `text`),
+    /// `id` (the default otherwise), `created`, `mod`, `title`, `position`,
+    /// `color` or `type`
     #[serde(default)]
     pub sort: String,
+    /// `asc` or `desc`. Defaults to `desc`, except for sorting by title.
     #[serde(default)]
     pub sort_direction: String,
     #[serde(default)]
     pub page: i32,
     #[serde(default)]
     pub per_page: i32,
+    /// How many results to skip. If given, this is used instead of `page`.
+    pub offset: Option<i32>,
+    /// How many results to return. If given, this is used instead of
+    /// `per_page`.
+    pub limit: Option<i32>,
 }
 
-/// Holds the state for our search
-pub struct Search {
-    /// Our main index, driven by Clouseau. Mainly for full-text search, but is
-    /// used for other indexed searches as well.
-    idx: Clouseau,
+impl Query {
+    /// Copy this query, moving any field-scoped searches in its text into
+    /// `scoped` and any date filters into `modified`
+    fn split_text(&self) -> Query {
+        let mut query = self.clone();
+        if let Some(text) = self.text.as_ref() {
+            let (rest, mut scoped) = split_scoped(text.as_str());
+            let (rest, dates) = split_dates(rest.as_str(), time::get_time().sec);
+            if scoped.len() > 0 || dates.is_some() {
+                query.text = if rest == "" { None } else { Some(rest) };
+                query.scoped.append(&mut scoped);
+            }
+            if let Some(dates) = dates {
+                query.modified = Some(match query.modified {
+                    Some(ref range) => range.intersect(&dates),
+                    None => dates,
+                });
+            }
+        }
+        query
+    }
 }
 
-unsafe impl Send for Search {}
-unsafe impl Sync for Search {}
+/// Split search text into words at whitespace, keeping quoted text
+/// (`title:"project x"`) together
+fn split_words(text: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let mut start = None;
+    let mut in_quote = false;
+    // words end at whitespace (unless it's quoted) or at the end of the text
+    for (idx, c) in text.char_indices().chain(Some((text.len(), ' '))) {
+        if c == '"' { in_quote = !in_quote; }
+        let end = idx == text.len() || (c.is_whitespace() && !in_quote);
+        match (start, end) {
+            (None, false) => start = Some(idx),
+            (Some(from), true) => {
+                words.push(&text[from..idx]);
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    words
+}
 
-impl Search {
-    /// Create a new Search object
-    pub fn new() -> TResult<Search> {
-        let idx = Clouseau::new()?;
-        idx.conn.execute("CREATE TABLE IF NOT EXISTS notes (id VARCHAR(64) PRIMARY KEY, space_id VARCHAR(96), board_id VARCHAR(96), has_file BOOL, created INTEGER, mod INTEGER, type VARCHAR(32), color INTEGER, url VARCHAR(256))", &[])?;
-        idx.conn.execute("CREATE TABLE IF NOT EXISTS notes_tags (id ROWID, note_id VARCHAR(64), tag VARCHAR(128))", &[])?;
-        Ok(Search {
-            idx: idx,
-        })
+/// The note fields a search can be scoped to. These are indexed on their own
+/// (in `notes_text`), outside of the full-text index.
+const SCOPED_FIELDS: [&'static str; 3] = ["title", "url", "username"];
+
+/// Matches notes where one of `SCOPED_FIELDS` contains some text (ignoring
+/// case)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ScopedText {
+    pub field: String,
+    pub text: String,
+}
+
+/// Pull the field-scoped searches (`url:github.com`, `title:"project x"`) out
+/// of some search text, giving back the rest of the text and the searches we
+/// found. Anything scoped to a field we don't know about is left in the text.
+pub fn split_scoped(text: &str) -> (String, Vec<ScopedText>) {
+    let mut rest: Vec<&str> = Vec::new();
+    let mut scoped = Vec::new();
+    for word in split_words(text) {
+        let field = match word.find(':') {
+            Some(x) => Some((word[..x].to_lowercase(), word[x + 1..].trim_matches('"'))),
+            None => None,
+        };
+        match field {
+            Some((ref field, val)) if SCOPED_FIELDS.contains(&field.as_str()) => {
+                if val.trim() == "" { continue; }
+                scoped.push(ScopedText {
+                    field: field.clone(),
+                    text: String::from(val),
+                });
+            }
+            _ => rest.push(word),
+        }
     }
+    (rest.join(" "), scoped)
+}
 
-    /// Index a note
-    pub fn index_note(&mut self, note: &Note) -> TResult<()> {
-        model_getter!(get_field, "Search.index_note()");
+/// Seconds in a day
+const DAY: i64 = 86400;
+
+/// A point in time given in a date filter
+enum When {
+    /// The start (midnight, UTC) of a day given as `2023-01-01`
+    Date(i64),
+    /// A time given as how long ago it was (`30d`)
+    Ago(i64),
+}
+
+/// Days from 1970-01-01 to the given date, or None if it isn't a real date
+fn days_from_civil(year: i64, month: i64, day: i64) -> Option<i64> {
+    let leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+    let month_days = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 => if leap { 29 } else { 28 },
+        _ => return None,
+    };
+    if day < 1 || day > month_days { return None; }
+    // count years from march so the leap day falls at the end of the year
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    Some(era * 146097 + day_of_era - 719468)
+}
+
+/// Parse a date (`2023-01-01`) into the unix time it starts at (UTC)
+fn parse_date(val: &str) -> Option<i64> {
+    let parts = val.split('-').collect::<Vec<_>>();
+    if parts.len() != 3 || parts[0].len() != 4 || parts[1].len() != 2 || parts[2].len() != 2 {
+        return None;
+    }
+    if !parts.iter().all(|x| x.chars().all(|c| c.is_digit(10))) { return None; }
+    let nums = parts.iter().filter_map(|x| x.parse::<i64>().ok()).collect::<Vec<_>>();
+    days_from_civil(nums[0], nums[1], nums[2]).map(|x| x * DAY)
+}
+
+/// Parse a length of time (`12h`, `30d`, `2w`, `6m`, `1y`) into seconds.
+/// Months are 30 days and years are 365.
+fn parse_age(val: &str) -> Option<i64> {
+    if val.len() < 2 { return None; }
+    let (num, unit) = val.split_at(val.len() - 1);
+    if !num.chars().all(|c| c.is_digit(10)) { return None; }
+    let num = match num.parse::<i64>() {
+        Ok(x) => x,
+        Err(_) => return None,
+    };
+    let secs = match unit {
+        "h" => 3600,
+        "d" => DAY,
+        "w" => 7 * DAY,
+        "m" => 30 * DAY,
+        "y" => 365 * DAY,
+        _ => return None,
+    };
+    num.checked_mul(secs)
+}
+
+fn parse_when(val: &str, now: i64) -> Option<When> {
+    match parse_date(val) {
+        Some(x) => Some(When::Date(x)),
+        None => parse_age(val).map(|x| When::Ago(now - x)),
+    }
+}
+
+/// Turn a single word of search text into the range of mod times it filters
+/// on, or None if it isn't a date filter
+fn date_filter(word: &str, now: i64) -> Option<DateRange> {
+    let word = word.to_lowercase();
+    match word.as_str() {
+        "last-week" => return Some(DateRange::new(Some(now - 7 * DAY), None)),
+        "last-month" => return Some(DateRange::new(Some(now - 30 * DAY), None)),
+        "last-year" => return Some(DateRange::new(Some(now - 365 * DAY), None)),
+        _ => {}
+    }
+    let (field, val) = match word.find(':') {
+        Some(x) => (&word[..x], &word[x + 1..]),
+        None => return None,
+    };
+    let (op, val) = if val.starts_with('>') {
+        (">", &val[1..])
+    } else if val.starts_with('<') {
+        ("<", &val[1..])
+    } else {
+        ("", val)
+    };
+    let when = match parse_when(val, now) {
+        Some(x) => x,
+        None => return None,
+    };
+    // with an age, "more than 30 days" (`>30d`) means further back in time
+    match (field, op, when) {
+        ("after", "", When::Date(x)) |
+            ("after", "", When::Ago(x)) |
+            ("modified", ">", When::Date(x)) |
+            ("modified", "<", When::Ago(x)) |
+            ("modified", "", When::Ago(x)) => Some(DateRange::new(Some(x), None)),
+        ("before", "", When::Date(x)) |
+            ("before", "", When::Ago(x)) |
+            ("modified", "<", When::Date(x)) |
+            ("modified", ">", When::Ago(x)) => Some(DateRange::new(None, Some(x))),
+        ("modified", "", When::Date(x)) => Some(DateRange::new(Some(x), Some(x + DAY))),
+        _ => None,
+    }
+}
+
+/// Pull the date filters out of some search text, giving back the rest of the
+/// text and the range of mod times the filters add up to (or None if there
+/// weren't any). Filters are:
+///
+/// - `after:2023-01-01` and `before:2023-01-01` (on or after the start of the
+///   day, or before it). Dates are in UTC.
+/// - `modified:2023-01-01` (during that day), `modified:>2023-01-01` and
+///   `modified:<2023-01-01` (same as `after:` and `before:`)
+/// - `modified:<30d` (in the last 30 days) and `modified:>30d` (more than 30
+///   days ago), with `h`, `d`, `w`, `m` (30 days) and `y` (365 days). These
+///   count back from `now` (a unix time), and also work with `after:` and
+///   `before:` (`before:1y`).
+/// - `last-week`, `last-month` and `last-year` (the past 7, 30 and 365 days)
+///
+/// Anything that doesn't parse is left in the text.
+pub fn split_dates(text: &str, now: i64) -> (String, Option<DateRange>) {
+    let mut rest: Vec<&str> = Vec::new();
+    let mut range: Option<DateRange> = None;
+    for word in split_words(text) {
+        match date_filter(word, now) {
+            Some(filter) => {
+                range = Some(match range {
+                    Some(ref x) => x.intersect(&filter),
+                    None => filter,
+                });
+            }
+            None => rest.push(word),
+        }
+    }
+    (rest.join(" "), range)
+}
+
+/// Grab what we order by (in SQL) for one of the `Query.sort` options, or None
+/// if it's not one we know about. `position` and `relevance` are handled
+/// separately.
+fn sort_column(sort: &str) -> Option<&'static str> {
+    match sort {
+        "id" => Some("id"),
+        "created" => Some("created"),
+        // notes that were never edited go by when they were created
+        "mod" => Some("COALESCE(mod, created / 1000)"),
+        "title" => Some("title COLLATE NOCASE"),
+        "color" => Some("color"),
+        "type" => Some("type"),
+        _ => None,
+    }
+}
+
+/// How much a term counts for when it's in a note's title (vs its body)
+const TITLE_WEIGHT: f64 = 3.0;
+
+/// How much a term counts for when it's one of a note's tags
+const TAG_WEIGHT: f64 = 2.0;
+
+/// What a misspelled match counts for, compared to an exact one
+const FUZZY_PENALTY: f64 = 0.5;
+
+/// How much newer notes get bumped up in the rankings (a note edited just now
+/// gets this much of its score added on top)
+const RECENCY_BOOST: f64 = 0.5;
+
+/// How long (in seconds) it takes for a note's recency boost to halve
+const RECENCY_HALF_LIFE: f64 = 2592000.0;
+
+/// A range of time (unix timestamps, in seconds). Either end can be left open.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct DateRange {
+    /// On or after this time
+    pub after: Option<i64>,
+    /// Before this time
+    pub before: Option<i64>,
+}
+
+impl DateRange {
+    pub fn new(after: Option<i64>, before: Option<i64>) -> DateRange {
+        DateRange {
+            after: after,
+            before: before,
+        }
+    }
+
+    /// Grab the part of this range that's also in another one
+    pub fn intersect(&self, other: &DateRange) -> DateRange {
+        fn pick(a: Option<i64>, b: Option<i64>, f: fn(i64, i64) -> i64) -> Option<i64> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(f(a, b)),
+                (a, None) => a,
+                (None, b) => b,
+            }
+        }
+        DateRange::new(pick(self.after, other.after, ::std::cmp::max), pick(self.before, other.before, ::std::cmp::min))
+    }
+}
+
+/// How a custom field gets compared in a `FieldFilter`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum FieldOp {
+    #[serde(rename = "eq")]
+    Eq,
+    #[serde(rename = "ne")]
+    Ne,
+    #[serde(rename = "lt")]
+    Lt,
+    #[serde(rename = "lte")]
+    Lte,
+    #[serde(rename = "gt")]
+    Gt,
+    #[serde(rename = "gte")]
+    Gte,
+    /// The note has the field at all (`value` is ignored)
+    #[serde(rename = "exists")]
+    Exists,
+}
+
+impl Default for FieldOp {
+    fn default() -> Self { FieldOp::Eq }
+}
+
+/// Matches notes by one of their custom fields. Numbers compare as numbers,
+/// anything else as text (so ISO dates sort the way you'd want).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FieldFilter {
+    pub name: String,
+    #[serde(default)]
+    pub op: FieldOp,
+    #[serde(default)]
+    pub value: Value,
+}
+
+/// Turn a custom field's value into what we index: its text, and its number
+/// (if it is one). Returns None for values we don't index.
+fn field_vals(val: &Value) -> Option<(String, Option<f64>)> {
+    match *val {
+        Value::Null => None,
+        Value::String(ref x) => Some((x.clone(), None)),
+        Value::Number(ref x) => Some((format!("{}", x), x.as_f64())),
+        Value::Bool(x) => Some((format!("{}", x), None)),
+        Value::Array(_) | Value::Object(_) => None,
+    }
+}
+
+/// What the index holds for a note, pulled out of the (decrypted) note. These
+/// are also what `search_store` saves, so a space can be indexed again without
+/// decrypting its notes. Fields missing from older saved entries come out as
+/// their defaults.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct IndexEntry {
+    pub id: String,
+    pub space_id: String,
+    pub board_id: Option<String>,
+    pub has_file: bool,
+    pub pinned: bool,
+    pub created: i64,
+    #[serde(rename = "mod")]
+    pub mod_: Option<i64>,
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub color: i64,
+    pub url: Option<String>,
+    pub username: Option<String>,
+    pub position: Option<f64>,
+    pub title: String,
+    pub tags: Vec<String>,
+    /// Custom fields, as (name, text, number) (see `field_vals()`)
+    pub fields: Vec<(String, String, Option<f64>)>,
+    /// Everything in the note we run full-text searches against
+    pub body: String,
+}
+
+impl IndexEntry {
+    /// Like `from_note()`, but gives back None for notes that stay out of
+    /// search (trashed ones, see `models::trash`)
+    pub fn for_note(note: &Note) -> TResult<Option<IndexEntry>> {
+        if note.trashed.is_some() { return Ok(None); }
+        Ok(Some(IndexEntry::from_note(note)?))
+    }
+
+    /// Pull what we index out of a (decrypted) note
+    pub fn from_note(note: &Note) -> TResult<IndexEntry> {
+        model_getter!(get_field, "IndexEntry::from_note()");
         let id = get_field!(note, id);
-        let id_mod = match model::id_timestamp(&id) {
+        let created = match model::id_timestamp(&id) {
             Ok(x) => x,
             Err(_) => 99999999,
         };
-        let space_id = note.space_id.clone();
-        if space_id == "" {
+        if note.space_id == "" {
             return TErr!(TError::MissingField(format!("Note {} missing `space_id`", id)));
         }
         let board_id = get_field!(note, board_id, String::from(""));
         let board_id = if board_id == "" { None } else { Some(board_id) };
-        let has_file = note.has_file;
-        let mod_ = note.mod_;
-        let type_ = get_field!(note, type_, String::from("text"));
-        let color = get_field!(note, color, 0);
-        self.idx.conn.execute(
-            "INSERT INTO notes (id, space_id, board_id, has_file, created, mod, type, color, url) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
-            &[&id, &space_id, &board_id, &has_file, &id_mod, &mod_, &type_, &color, &note.url]
-        )?;
-
-        let tags = get_field!(note, tags, Vec::new());
-        for tag in tags {
-            self.idx.conn.execute("INSERT INTO notes_tags (note_id, tag) VALUES (?, ?)", &[&id, &tag])?;
+        let mut fields = Vec::new();
+        let mut field_text: Vec<String> = Vec::new();
+        for (name, val) in &get_field!(note, fields, Default::default()) {
+            let (text, num) = match field_vals(val) {
+                Some(x) => x,
+                None => continue,
+            };
+            if let Value::String(_) = *val { field_text.push(text.clone()); }
+            fields.push((name.clone(), text, num));
         }
-        let note_body = [
-            get_field!(note, title, String::from("")),
+        let title = get_field!(note, title, String::from(""));
+        let tags = get_field!(note, tags, Vec::new());
+        let body = [
+            title.clone(),
             get_field!(note, text, String::from("")),
-            get_field!(note, tags, Vec::new()).as_slice().join(" "),
+            get_field!(note, items, Vec::new()).iter().map(|x| x.text.as_str()).collect::<Vec<_>>().join(" "),
+            tags.as_slice().join(" "),
             get_field!(note, url, String::from("")),
+            field_text.join(" "),
             {
                 let fakefile = File::new();
                 let file = get_field!(note, file, &fakefile);
                 get_field!(file, name, String::from(""))
             },
+            {
+                let fakefile = File::new();
+                let file = get_field!(note, file, &fakefile);
+                get_field!(file, text, String::from(""))
+            },
         ].join(" ");
-        self.idx.index(&id, &note_body)?;
+        Ok(IndexEntry {
+            id: id,
+            space_id: note.space_id.clone(),
+            board_id: board_id,
+            has_file: note.has_file,
+            pinned: note.pinned.unwrap_or(false),
+            created: created,
+            mod_: note.mod_,
+            type_: get_field!(note, type_, String::from("text")),
+            color: get_field!(note, color, 0),
+            url: note.url.clone(),
+            username: note.username.clone(),
+            position: note.position,
+            title: title,
+            tags: tags,
+            fields: fields,
+            body: body,
+        })
+    }
+}
+
+/// What we suggest for a prefix the user is typing (see `Search::suggest()`),
+/// each with how many notes it's in
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct Suggestions {
+    pub tags: Vec<(String, usize)>,
+    pub titles: Vec<(String, usize)>,
+    /// Words from the notes' text
+    pub terms: Vec<(String, usize)>,
+}
+
+/// Where a search's text matched in one of a note's fields, so the UI can
+/// highlight it. Fields are named for where they are in the note's data
+/// (`title`, `text`, `url`, `file.name`, `fields.<name>`), with tags and
+/// checklist items going by their position (`tags.2`, `items.0`). `start` and
+/// `end` are byte offsets into the field's text (`end` is exclusive).
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct MatchPos {
+    pub field: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Find where the given words (see `Search::highlight_words()`) show up in a
+/// (decrypted) note
+pub fn match_positions(note: &Note, needles: &[Needle]) -> Vec<MatchPos> {
+    let mut fields: Vec<(String, &String)> = Vec::new();
+    if let Some(title) = note.title.as_ref() { fields.push((String::from("title"), title)); }
+    if let Some(text) = note.text.as_ref() { fields.push((String::from("text"), text)); }
+    if let Some(url) = note.url.as_ref() { fields.push((String::from("url"), url)); }
+    if let Some(name) = note.file.as_ref().and_then(|x| x.name.as_ref()) {
+        fields.push((String::from("file.name"), name));
+    }
+    for (idx, tag) in note.tags.iter().flat_map(|x| x.iter()).enumerate() {
+        fields.push((format!("tags.{}", idx), tag));
+    }
+    for (idx, item) in note.items.iter().flat_map(|x| x.iter()).enumerate() {
+        fields.push((format!("items.{}", idx), &item.text));
+    }
+    for (name, val) in note.fields.iter().flat_map(|x| x.iter()) {
+        if let Value::String(ref text) = *val { fields.push((format!("fields.{}", name), text)); }
+    }
+    let mut found = Vec::new();
+    for (field, text) in fields {
+        for (start, end) in highlight::find(text.as_str(), needles) {
+            found.push(MatchPos {
+                field: field.clone(),
+                start: start,
+                end: end,
+            });
+        }
+    }
+    found
+}
+
+/// Mixed with the search index key (see `search_store::derive_key()`) to get
+/// the key segment tokens are sealed with
+const SEGMENT_KEY_CONTEXT: &'static [u8] = b"turtl:search-segments";
+
+/// Make the sealer our segments run every token through before it's written.
+/// Tokens are HMACed with a key derived from the user's, so like the saved
+/// index entries (see `search_store`), the segments can only be searched while
+/// the user is logged in.
+pub fn segment_sealer(user_key: &Key) -> TResult<TokenSealer> {
+    let search_key = search_store::derive_key(user_key)?;
+    let key = crypto::hmac(search_key.data().as_slice(), SEGMENT_KEY_CONTEXT)?;
+    Ok(TokenSealer::new(move |token| {
+        crypto::hmac(key.as_slice(), token)
+            // half the tag is plenty to tell tokens apart, and keeps the
+            // segments smaller
+            .map(|x| Vec::from(&x[0..16]))
+            .map_err(|e| format!("{}", e))
+    }))
+}
+
+/// Grab the segmented index config for the given user, or None if segments
+/// are disabled (or we're running in memory). Segments live in the data
+/// folder, sealed with a key derived from the user's (see `segment_sealer()`).
+pub fn segment_config(user_id: &String, user_key: &Key) -> TResult<Option<SegmentConfig>> {
+    let enabled: bool = config::get(&["search", "segments", "enabled"]).unwrap_or(false);
+    let data_folder: String = config::get(&["data_folder"])?;
+    if !enabled || data_folder == ":memory:" || cfg!(test) {
+        return Ok(None);
+    }
+    Ok(Some(SegmentConfig {
+        folder: segment_folder(user_id)?,
+        max_docs: config::get(&["search", "segments", "max_docs"]).unwrap_or(5000),
+        max_resident: config::get(&["search", "segments", "max_resident"]).unwrap_or(4),
+        mmap_size: config::get(&["search", "segments", "mmap_size"]).unwrap_or(67108864),
+        merge_interval: config::get(&["search", "segments", "merge_interval"]).unwrap_or(30000),
+        sealer: segment_sealer(user_key)?,
+    }))
+}
+
+/// Where a user's on-disk search segments live
+pub fn segment_folder(user_id: &String) -> TResult<PathBuf> {
+    let mut folder = PathBuf::from(util::file_folder(Some("search"))?);
+    folder.push(format!("u_{}", user_id));
+    Ok(folder)
+}
+
+/// Remove a user's on-disk search segments, including any left behind by a
+/// session that never got to clean up after itself (a crash, say). They're
+/// sealed, but they're only good for one session anyway.
+pub fn wipe_segments(user_id: &String) -> TResult<()> {
+    let data_folder: String = config::get(&["data_folder"])?;
+    if data_folder == ":memory:" || cfg!(test) { return Ok(()); }
+    let folder = segment_folder(user_id)?;
+    if folder.exists() {
+        fs::remove_dir_all(&folder)?;
+    }
+    Ok(())
+}
+
+/// A chunk of our search index holding the notes for a single space. Keeping
+/// each space in its own partition means purging or reindexing a space (or
+/// searching in one) only ever touches that space's data.
+struct Partition {
+    /// Our main index, driven by Clouseau. Mainly for full-text search, but is
+    /// used for other indexed searches as well.
+    idx: Clouseau,
+    /// If set, our full-text index lives here (on disk, in segments) instead
+    /// of in `idx`. Meant for large profiles where holding the entire index in
+    /// memory gets expensive.
+    segments: Option<SegmentedIndex>,
+    /// The tags, titles, and terms in the partition's notes, which is what we
+    /// suggest as the user types
+    tags: PrefixIndex,
+    titles: PrefixIndex,
+    terms: PrefixIndex,
+}
+
+impl Partition {
+    /// Create a new partition
+    fn new(segment_config: Option<SegmentConfig>) -> TResult<Partition> {
+        let idx = Clouseau::new()?;
+        idx.conn.execute("CREATE TABLE IF NOT EXISTS notes (id VARCHAR(64) PRIMARY KEY, space_id VARCHAR(96), board_id VARCHAR(96), has_file BOOL, pinned BOOL, created INTEGER, mod INTEGER, type VARCHAR(32), color INTEGER, url VARCHAR(256), position REAL, title TEXT)", &[])?;
+        idx.conn.execute("CREATE TABLE IF NOT EXISTS notes_tags (id ROWID, note_id VARCHAR(64), tag VARCHAR(128))", &[])?;
+        idx.conn.execute("CREATE TABLE IF NOT EXISTS notes_fields (id ROWID, note_id VARCHAR(64), name VARCHAR(128), value TEXT, num REAL)", &[])?;
+        // the terms in each note, which is what we rank results by and match
+        // misspellings against
+        idx.conn.execute("CREATE TABLE IF NOT EXISTS notes_terms (id ROWID, note_id VARCHAR(64), term VARCHAR(128), weight REAL)", &[])?;
+        idx.conn.execute("CREATE INDEX IF NOT EXISTS notes_terms_term ON notes_terms (term)", &[])?;
+        idx.conn.execute("CREATE INDEX IF NOT EXISTS notes_terms_note_id ON notes_terms (note_id)", &[])?;
+        // the fields searches can be scoped to (lowercased)
+        idx.conn.execute("CREATE TABLE IF NOT EXISTS notes_text (id ROWID, note_id VARCHAR(64), field VARCHAR(32), value TEXT)", &[])?;
+        idx.conn.execute("CREATE INDEX IF NOT EXISTS notes_text_note_id ON notes_text (note_id)", &[])?;
+        let segments = match segment_config {
+            Some(config) => Some(SegmentedIndex::open(config)?),
+            None => None,
+        };
+        Ok(Partition {
+            idx: idx,
+            segments: segments,
+            tags: PrefixIndex::new(),
+            titles: PrefixIndex::new(),
+            terms: PrefixIndex::new(),
+        })
+    }
+
+    /// Add an object to our full-text index
+    fn ft_index(&self, id: &String, body: &String) -> TResult<()> {
+        match self.segments {
+            Some(ref segments) => segments.index(id, body)?,
+            None => self.idx.index(id, body)?,
+        }
+        Ok(())
+    }
+
+    /// Remove an object from our full-text index
+    fn ft_unindex(&self, id: &String) -> TResult<()> {
+        match self.segments {
+            Some(ref segments) => segments.unindex(id)?,
+            None => self.idx.unindex(id)?,
+        }
+        Ok(())
+    }
+
+    /// Run a full-text search
+    fn ft_find(&self, terms: &String) -> TResult<Vec<String>> {
+        let ids = match self.segments {
+            Some(ref segments) => segments.find(terms)?,
+            None => self.idx.find(terms)?,
+        };
+        Ok(ids)
+    }
+
+    /// Remember the terms in an object, along with how much each one counts
+    /// for (how often it shows up, weighted by which of the given parts of
+    /// the object it's in). This is what `score()` ranks by and what
+    /// `fuzzy_query()` finds close words in.
+    fn index_terms(&mut self, id: &String, parts: &[(&String, f64)]) -> TResult<()> {
+        let mut terms: HashMap<String, f64> = HashMap::new();
+        for &(text, weight) in parts {
+            for term in fuzzy::tokenize(text.as_str()) {
+                *terms.entry(term).or_insert(0.0) += weight;
+            }
+        }
+        for (term, weight) in terms {
+            self.idx.conn.execute("INSERT INTO notes_terms (note_id, term, weight) VALUES (?, ?, ?)", &[id, &term, &weight])?;
+            self.terms.add(term.as_str());
+        }
+        Ok(())
+    }
+
+    /// Grab the values a query gives back for a note (one column's worth)
+    fn note_vals(&self, query: &str, id: &String) -> TResult<Vec<String>> {
+        let mut qry = self.idx.conn.prepare(query)?;
+        let rows = qry.query_map(&[id], |row| row.get(0))?;
+        let mut vals = Vec::new();
+        for val in rows { vals.push(val?); }
+        Ok(vals)
+    }
+
+    /// Take a note's tags, title, and terms back out of our suggestions. This
+    /// needs to happen before the note's rows are removed.
+    fn unindex_suggestions(&mut self, id: &String) -> TResult<()> {
+        for tag in self.note_vals("SELECT tag FROM notes_tags WHERE note_id = ?", id)? {
+            self.tags.remove(tag.as_str());
+        }
+        for title in self.note_vals("SELECT COALESCE(title, '') FROM notes WHERE id = ?", id)? {
+            self.titles.remove_words(title.as_str());
+        }
+        for term in self.note_vals("SELECT term FROM notes_terms WHERE note_id = ?", id)? {
+            self.terms.remove(term.as_str());
+        }
+        Ok(())
+    }
+
+    /// Score how well each of the given notes matches some search text: each
+    /// term in the text adds its weight in the note (dampened, so a term
+    /// showing up ten times doesn't count for ten times as much), scaled by
+    /// how rare the term is in this partition. Misspellings of a term count
+    /// for less, and newer notes get a boost.
+    fn score(&self, note_ids: &Vec<String>, text: &String, exact: bool) -> TResult<HashMap<String, f64>> {
+        let mut scores: HashMap<String, f64> = note_ids.iter()
+            .map(|x| (x.clone(), 0.0))
+            .collect();
+        if note_ids.len() == 0 { return Ok(scores); }
+        let total: i64 = self.idx.conn.query_row("SELECT COUNT(*) FROM notes", &[], |row| row.get(0))?;
+        let mut weight_qry = self.idx.conn.prepare("SELECT note_id, weight FROM notes_terms WHERE term = ?")?;
+        for term in fuzzy::query_terms(text.as_str()) {
+            let mut variants = vec![(term.clone(), 1.0)];
+            if !exact {
+                for similar in self.similar_terms(term.as_str())? {
+                    variants.push((similar, FUZZY_PENALTY));
+                }
+            }
+            for (variant, factor) in variants {
+                let df: i64 = self.idx.conn.query_row("SELECT COUNT(*) FROM notes_terms WHERE term = ?", &[&variant], |row| row.get(0))?;
+                if df == 0 { continue; }
+                let idf = (1.0 + (total as f64 / df as f64)).ln();
+                let rows = weight_qry.query_map(&[&variant], |row| (row.get(0), row.get(1)))?;
+                for row in rows {
+                    let (note_id, weight): (String, f64) = row?;
+                    if let Some(score) = scores.get_mut(&note_id) {
+                        *score += factor * idf * (1.0 + weight.ln());
+                    }
+                }
+            }
+        }
+        let now = time::get_time().sec as f64;
+        let mut ts_qry = self.idx.conn.prepare("SELECT id, COALESCE(mod, created / 1000) FROM notes")?;
+        let rows = ts_qry.query_map(&[], |row| (row.get(0), row.get(1)))?;
+        for row in rows {
+            let (note_id, ts): (String, i64) = row?;
+            if let Some(score) = scores.get_mut(&note_id) {
+                let age = (now - ts as f64).max(0.0);
+                *score *= 1.0 + (RECENCY_BOOST * (0.5 as f64).powf(age / RECENCY_HALF_LIFE));
+            }
+        }
+        Ok(scores)
+    }
+
+    /// Find the indexed terms within typo distance of a (lowercase) word,
+    /// closest first. The word itself isn't included.
+    fn similar_terms(&self, word: &str) -> TResult<Vec<String>> {
+        let len = word.chars().count();
+        let max = fuzzy::max_distance(len);
+        if max == 0 { return Ok(Vec::new()); }
+        let max_terms: usize = config::get(&["search", "fuzzy", "max_terms"]).unwrap_or(5);
+        let mut qry = self.idx.conn.prepare("SELECT DISTINCT term FROM notes_terms WHERE length(term) BETWEEN ? AND ?")?;
+        let rows = qry.query_map(&[&((len - max) as i64), &((len + max) as i64)], |row| row.get(0))?;
+        let mut found: Vec<(usize, String)> = Vec::new();
+        for term in rows {
+            let term: String = term?;
+            if term == word { continue; }
+            if let Some(dist) = fuzzy::distance(word, term.as_str(), max) {
+                found.push((dist, term));
+            }
+        }
+        found.sort();
+        Ok(found.into_iter().take(max_terms).map(|x| x.1).collect())
+    }
+
+    /// Rewrite a full-text query so it also matches misspellings of the words
+    /// in it. Returns None if there's nothing close to any of them.
+    fn fuzzy_query(&self, terms: &String) -> TResult<Option<String>> {
+        let mut err = None;
+        let expanded = fuzzy::expand_query(terms.as_str(), |word| {
+            match self.similar_terms(word) {
+                Ok(x) => x,
+                Err(e) => {
+                    err = Some(e);
+                    Vec::new()
+                }
+            }
+        });
+        match err {
+            Some(e) => Err(e),
+            None => Ok(expanded),
+        }
+    }
+
+    /// Given a set of note ids (in this partition), grab the tags for those
+    /// notes and their frequency.
+    fn tags_by_notes(&self, note_ids: &Vec<String>) -> TResult<Vec<(String, i32)>> {
+        if note_ids.len() == 0 {
+            return Ok(Vec::new());
+        }
+        let mut tag_qry: Vec<&str> = Vec::with_capacity(note_ids.len() + 4);
+        let mut qry_vals: Vec<SearchVal> = Vec::new();
+        tag_qry.push("SELECT tag, count(tag) AS tag_count FROM notes_tags WHERE note_id IN (");
+        if note_ids.len() > 0 {
+            for note_id in note_ids {
+                if note_id == &note_ids[note_ids.len() - 1] {
+                    tag_qry.push("?");
+                } else {
+                    tag_qry.push("?,");
+                }
+                qry_vals.push(SearchVal::String(note_id.clone()));
+            }
+            tag_qry.push(") ");
+        }
+        tag_qry.push("GROUP BY tag ORDER BY tag_count DESC, tag ASC");
+
+        let final_query = tag_qry.as_slice().join("");
+        let mut prepared_qry = self.idx.conn.prepare(final_query.as_str())?;
+        let mut values: Vec<&ToSql> = Vec::with_capacity(qry_vals.len());
+        for val in &qry_vals {
+            let ts: &ToSql = val;
+            values.push(ts);
+        }
+        let rows = prepared_qry.query_map(values.as_slice(), |row| (row.get("tag"), row.get("tag_count")))?;
+        let mut tags = Vec::new();
+        for entry in rows {
+            let val = entry?;
+            tags.push((val.0, val.1));
+        }
+        Ok(tags)
+    }
+}
+
+impl Drop for Partition {
+    fn drop(&mut self) {
+        match self.idx.close() {
+            Ok(_) => {},
+            Err(e) => {
+                warn!("Partition.drop() -- problem closing search index, oh well... {}", e);
+            }
+        }
+        // the segments are only good for this session, so don't leave them
+        // lying around on disk
+        match self.segments.as_mut() {
+            Some(segments) => {
+                match segments.destroy() {
+                    Ok(_) => {},
+                    Err(e) => {
+                        warn!("Partition.drop() -- problem removing search segments: {}", e);
+                    }
+                }
+            }
+            None => {}
+        }
+    }
+}
+
+/// Holds the state for our search
+pub struct Search {
+    /// Our index, partitioned by space_id
+    partitions: HashMap<String, Partition>,
+    /// Maps note_id -> space_id so we can find the partition a note was
+    /// indexed in even if the note has since moved spaces.
+    note_spaces: HashMap<String, String>,
+    /// If set, partitions keep their full-text index in on-disk segments (each
+    /// partition gets its own subfolder).
+    segment_config: Option<SegmentConfig>,
+    /// The spaces that have had all their notes indexed (see
+    /// `Turtl::index_space()`). Other spaces may still have partitions holding
+    /// the odd note that changed since login.
+    loaded: HashSet<String>,
+}
+
+unsafe impl Send for Search {}
+unsafe impl Sync for Search {}
+
+impl Search {
+    /// Create a new Search object
+    pub fn new() -> TResult<Search> {
+        Ok(Search {
+            partitions: HashMap::new(),
+            note_spaces: HashMap::new(),
+            segment_config: None,
+            loaded: HashSet::new(),
+        })
+    }
+
+    /// Create a new Search object that keeps its full-text index in on-disk
+    /// segments (see `clouseau::segment` and `segment_config()`). Note that the segment folders are
+    /// removed when the Search object is dropped.
+    pub fn new_segmented(config: SegmentConfig) -> TResult<Search> {
+        let mut search = Search::new()?;
+        search.segment_config = Some(config);
+        Ok(search)
+    }
+
+    /// Grab the partition for a space, creating it if needed
+    fn partition_mut<'a>(&'a mut self, space_id: &String) -> TResult<&'a mut Partition> {
+        if !self.partitions.contains_key(space_id) {
+            let segment_config = match self.segment_config.as_ref() {
+                Some(config) => {
+                    let mut config = config.clone();
+                    config.folder.push(format!("s_{}", space_id));
+                    Some(config)
+                }
+                None => None,
+            };
+            self.partitions.insert(space_id.clone(), Partition::new(segment_config)?);
+        }
+        Ok(self.partitions.get_mut(space_id).expect("turtl::Search.partition_mut() -- partition is None after insert"))
+    }
+
+    /// Returns the space ids we currently have partitions for
+    pub fn spaces(&self) -> Vec<String> {
+        self.partitions.keys().map(|x| x.clone()).collect::<Vec<_>>()
+    }
+
+    /// Whether all of a space's notes have been indexed
+    pub fn is_loaded(&self, space_id: &String) -> bool {
+        self.loaded.contains(space_id)
+    }
+
+    /// Remove a space (and all its notes) from the index.
+    pub fn purge_space(&mut self, space_id: &String) {
+        // dropping the partition closes/removes it
+        self.partitions.remove(space_id);
+        self.note_spaces.retain(|_, x| x != space_id);
+        self.loaded.remove(space_id);
+    }
+
+    /// Wipe out the index for a space and rebuild it from the given notes
+    /// (which should be all of the space's notes). Other spaces are left alone.
+    pub fn reindex_space(&mut self, space_id: &String, notes: &Vec<Note>) -> TResult<()> {
+        let mut entries = Vec::with_capacity(notes.len());
+        for note in notes {
+            if &note.space_id != space_id || note.trashed.is_some() { continue; }
+            match IndexEntry::from_note(note) {
+                Ok(x) => entries.push(x),
+                // keep going on error
+                Err(e) => error!("Search.reindex_space() -- problem indexing note {:?}: {}", note.id, e),
+            }
+        }
+        self.reindex_space_entries(space_id, &entries)
+    }
+
+    /// Wipe out the index for a space and rebuild it from the given entries
+    /// (see `search_store`, which saves them so we don't have to decrypt a
+    /// space's notes every time we load it).
+    pub fn reindex_space_entries(&mut self, space_id: &String, entries: &Vec<IndexEntry>) -> TResult<()> {
+        self.purge_space(space_id);
+        self.loaded.insert(space_id.clone());
+        for entry in entries {
+            if &entry.space_id != space_id { continue; }
+            match self.index_entry(entry) {
+                Ok(_) => {},
+                // keep going on error
+                Err(e) => error!("Search.reindex_space_entries() -- problem indexing note {}: {}", entry.id, e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Index a note
+    pub fn index_note(&mut self, note: &Note) -> TResult<()> {
+        match IndexEntry::for_note(note)? {
+            Some(entry) => self.index_entry(&entry),
+            None => Ok(()),
+        }
+    }
+
+    /// Index a note's entry
+    pub fn index_entry(&mut self, entry: &IndexEntry) -> TResult<()> {
+        let id = entry.id.clone();
+        let space_id = entry.space_id.clone();
+        {
+            let partition = self.partition_mut(&space_id)?;
+            partition.idx.conn.execute(
+                "INSERT INTO notes (id, space_id, board_id, has_file, pinned, created, mod, type, color, url, position, title) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                &[&id, &space_id, &entry.board_id, &entry.has_file, &entry.pinned, &entry.created, &entry.mod_, &entry.type_, &entry.color, &entry.url, &entry.position, &entry.title]
+            )?;
+            for tag in &entry.tags {
+                partition.idx.conn.execute("INSERT INTO notes_tags (note_id, tag) VALUES (?, ?)", &[&id, tag])?;
+                partition.tags.add(tag.as_str());
+            }
+            partition.titles.add_words(entry.title.as_str());
+            let scoped = [("title", Some(&entry.title)), ("url", entry.url.as_ref()), ("username", entry.username.as_ref())];
+            for &(field, val) in scoped.iter() {
+                let val = match val {
+                    Some(x) if x.trim() != "" => x.to_lowercase(),
+                    _ => continue,
+                };
+                let field = String::from(field);
+                partition.idx.conn.execute("INSERT INTO notes_text (note_id, field, value) VALUES (?, ?, ?)", &[&id, &field, &val])?;
+            }
+            for &(ref name, ref text, ref num) in &entry.fields {
+                partition.idx.conn.execute("INSERT INTO notes_fields (note_id, name, value, num) VALUES (?, ?, ?, ?)", &[&id, name, text, num])?;
+            }
+            let tag_text = entry.tags.as_slice().join(" ");
+            partition.ft_index(&id, &entry.body)?;
+            partition.index_terms(&id, &[(&entry.title, TITLE_WEIGHT), (&tag_text, TAG_WEIGHT), (&entry.body, 1.0)])?;
+        }
+        self.note_spaces.insert(id, space_id);
         Ok(())
     }
 
@@ -110,9 +1054,29 @@ impl Search {
     pub fn unindex_note(&mut self, note: &Note) -> TResult<()> {
         model_getter!(get_field, "Search.unindex_note()");
         let id = get_field!(note, id);
-        self.idx.conn.execute("DELETE FROM notes WHERE id = ?", &[&id])?;
-        self.idx.conn.execute("DELETE FROM notes_tags where note_id = ?", &[&id])?;
-        self.idx.unindex(&id)?;
+        self.unindex_id(&id, &note.space_id)
+    }
+
+    /// Unindex a note by its id. `space_id` is only used if we don't know which
+    /// space the note was indexed under.
+    pub fn unindex_id(&mut self, id: &String, space_id: &String) -> TResult<()> {
+        // the note may have moved spaces since it was indexed, so prefer the
+        // space we indexed it under
+        let space_id = match self.note_spaces.remove(id) {
+            Some(x) => x,
+            None => space_id.clone(),
+        };
+        let partition = match self.partitions.get_mut(&space_id) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        partition.unindex_suggestions(id)?;
+        partition.idx.conn.execute("DELETE FROM notes WHERE id = ?", &[id])?;
+        partition.idx.conn.execute("DELETE FROM notes_tags where note_id = ?", &[id])?;
+        partition.idx.conn.execute("DELETE FROM notes_fields where note_id = ?", &[id])?;
+        partition.idx.conn.execute("DELETE FROM notes_terms where note_id = ?", &[id])?;
+        partition.idx.conn.execute("DELETE FROM notes_text where note_id = ?", &[id])?;
+        partition.ft_unindex(id)?;
         Ok(())
     }
 
@@ -124,27 +1088,50 @@ impl Search {
 
     /// Search for notes. Returns the note IDs only. Loading them from the db
     /// and decrypting are up to you...OR YOUR MOM.
+    pub fn find(&self, query: &Query) -> TResult<(Vec<String>, i32)> {
+        let (results, total) = self.find_scored(query)?;
+        Ok((results.into_iter().map(|x| x.0).collect(), total))
+    }
+
+    /// Search for notes, returning each note's id along with how well it
+    /// matched the query's text (see `Partition::score()`, 0 if there's no
+    /// text), and the total number of matches.
     ///
     /// NOTE: This function uses a lot of vector concatenation and joining to
     /// build our queries. It's probably pretty slow and inefficient. On top of
     /// that, it makes extensive use of SQL's `intersect` to grab results from a
     /// bunch of separate queries. There may be a more efficient way to do this,
     /// however since this is all in-memory anyway, it's probably fine.
-    pub fn find(&self, query: &Query) -> TResult<(Vec<String>, i32)> {
+    pub fn find_scored(&self, query: &Query) -> TResult<(Vec<(String, f64)>, i32)> {
+        let query = &query.split_text();
         let mut queries: Vec<String> = Vec::new();
         let mut exclude_queries: Vec<String> = Vec::new();
         let mut qry_vals: Vec<SearchVal> = Vec::new();
+        // notes that only matched a misspelling of the search text, which
+        // sort below the ones that matched it exactly
+        let mut fuzzy_ids: Vec<String> = Vec::new();
 
-        let mut space_qry: Vec<&str> = Vec::with_capacity(1);
-        space_qry.push("SELECT id FROM notes WHERE space_id = ?");
-        qry_vals.push(SearchVal::String(query.space_id.clone()));
-        queries.push(space_qry.as_slice().join(""));
+        // each space has its own partition, so no need to filter on space_id
+        let partition = match self.partitions.get(&query.space_id) {
+            Some(x) => x,
+            None => return Ok((Vec::new(), 0)),
+        };
 
         // this one is kind of weird. we basically do
         //   SELECT id FROM notes WHERE id IN (id1, id2)
         // there's probably a much better way, but this is easiest for now
         if query.text.is_some() {
-            let ft_note_ids = self.idx.find(query.text.as_ref().expect("turtl::Search.find() -- query.text is None. This is so strange. I do not know how this could happen. But rest assured, I will make sure it DOES NOT HAPPEN AGAIN."))?;
+            let text = query.text.as_ref().expect("turtl::Search.find() -- query.text is None. This is so strange. I do not know how this could happen. But rest assured, I will make sure it DOES NOT HAPPEN AGAIN.");
+            let mut ft_note_ids = partition.ft_find(text)?;
+            if !query.exact {
+                if let Some(fuzzy_text) = partition.fuzzy_query(text)? {
+                    let exact_ids = ft_note_ids.iter().map(|x| x.clone()).collect::<HashSet<_>>();
+                    fuzzy_ids = partition.ft_find(&fuzzy_text)?.into_iter()
+                        .filter(|x| !exact_ids.contains(x))
+                        .collect::<Vec<_>>();
+                    ft_note_ids.append(&mut fuzzy_ids.clone());
+                }
+            }
             let mut ft_qry: Vec<&str> = Vec::with_capacity(ft_note_ids.len() + 2);
             ft_qry.push("SELECT id FROM notes WHERE id IN (");
             for id in &ft_note_ids {
@@ -205,6 +1192,21 @@ impl Search {
             queries.push(tag_qry.as_slice().join(""));
         }
 
+        if query.any_tags.len() > 0 {
+            let mut any_tag_qry: Vec<&str> = Vec::with_capacity(query.any_tags.len() + 2);
+            any_tag_qry.push("SELECT note_id FROM notes_tags WHERE tag IN (");
+            for tag in &query.any_tags {
+                if tag == &query.any_tags[query.any_tags.len() - 1] {
+                    any_tag_qry.push("?");
+                } else {
+                    any_tag_qry.push("?,");
+                }
+                qry_vals.push(SearchVal::String(tag.clone()));
+            }
+            any_tag_qry.push(")");
+            queries.push(any_tag_qry.as_slice().join(""));
+        }
+
         if query.exclude_tags.len() > 0 {
             let mut excluded_tag_qry: Vec<&str> = Vec::with_capacity(query.exclude_tags.len() + 2);
             excluded_tag_qry.push("SELECT note_id FROM notes_tags WHERE tag IN (");
@@ -220,6 +1222,45 @@ impl Search {
             exclude_queries.push(excluded_tag_qry.as_slice().join(""));
         }
 
+        for filter in &query.fields {
+            qry_vals.push(SearchVal::String(filter.name.clone()));
+            if filter.op == FieldOp::Exists {
+                queries.push(String::from("SELECT note_id FROM notes_fields WHERE name = ?"));
+                continue;
+            }
+            let op = match filter.op {
+                FieldOp::Eq | FieldOp::Exists => "=",
+                FieldOp::Ne => "!=",
+                FieldOp::Lt => "<",
+                FieldOp::Lte => "<=",
+                FieldOp::Gt => ">",
+                FieldOp::Gte => ">=",
+            };
+            let (text, num) = match field_vals(&filter.value) {
+                Some(x) => x,
+                None => return TErr!(TError::BadValue(format!("can't filter field `{}` on {}", filter.name, filter.value))),
+            };
+            match num {
+                Some(num) => {
+                    queries.push(format!("SELECT note_id FROM notes_fields WHERE name = ? AND num {} ?", op));
+                    qry_vals.push(SearchVal::Float(num));
+                }
+                None => {
+                    queries.push(format!("SELECT note_id FROM notes_fields WHERE name = ? AND value {} ?", op));
+                    qry_vals.push(SearchVal::String(text));
+                }
+            }
+        }
+
+        for scoped in &query.scoped {
+            if !SCOPED_FIELDS.contains(&scoped.field.as_str()) {
+                return TErr!(TError::BadValue(format!("can't search by field `{}`", scoped.field)));
+            }
+            queries.push(String::from("SELECT note_id FROM notes_text WHERE field = ? AND instr(value, ?) > 0"));
+            qry_vals.push(SearchVal::String(scoped.field.clone()));
+            qry_vals.push(SearchVal::String(scoped.text.to_lowercase()));
+        }
+
         if query.type_.is_some() {
             queries.push(String::from("SELECT id FROM notes WHERE type = ?"));
             qry_vals.push(SearchVal::String(query.type_.as_ref().expect("turtl::Search.find() -- query.type_ is None").clone()));
@@ -240,6 +1281,24 @@ impl Search {
             qry_vals.push(SearchVal::Int(query.color.as_ref().expect("turtl::Search.find() -- query.color is None").clone()));
         }
 
+        if let Some(pinned) = query.pinned {
+            queries.push(String::from("SELECT id FROM notes WHERE pinned = ?"));
+            qry_vals.push(SearchVal::Bool(pinned));
+        }
+
+        // notes that were never edited don't have a mod time, so they go by
+        // when they were created
+        if let Some(range) = query.modified.as_ref() {
+            if let Some(after) = range.after {
+                queries.push(String::from("SELECT id FROM notes WHERE COALESCE(mod, created / 1000) >= ?"));
+                qry_vals.push(SearchVal::Float(after as f64));
+            }
+            if let Some(before) = range.before {
+                queries.push(String::from("SELECT id FROM notes WHERE COALESCE(mod, created / 1000) < ?"));
+                qry_vals.push(SearchVal::Float(before as f64));
+            }
+        }
+
         let filter_query = if queries.len() > 0 && exclude_queries.len() > 0 {
             let include = queries.as_slice().join(" intersect ");
             let exclude = exclude_queries.as_slice().join(" union ");
@@ -254,35 +1313,174 @@ impl Search {
             String::from("SELECT id FROM notes")
         };
         let mut sort = query.sort.clone();
-        let mut sort_dir = query.sort_direction.clone();
+        let relevance = query.text.is_some() && (sort == "" || sort == "relevance");
+        if sort == "" || sort == "relevance" { sort = String::from("id"); }
+        // the sort and its direction end up in the query as-is, so only allow
+        // what we know
+        if sort != "position" && sort_column(sort.as_str()).is_none() {
+            return TErr!(TError::BadValue(format!("can't sort by `{}`", sort)));
+        }
+        let sort_dir = match query.sort_direction.as_str() {
+            "" => if sort == "title" { "asc" } else { "desc" },
+            "asc" => "asc",
+            "desc" => "desc",
+            x => return TErr!(TError::BadValue(format!("bad sort direction `{}`", x))),
+        };
         let mut page = query.page;
         let mut per_page = query.per_page;
-        if sort == "" { sort = String::from("id"); }
-        if sort_dir == "" { sort_dir = String::from("desc"); }
         if page < 1 { page = 1; }
         if per_page < 1 { per_page = 50; }
+        let limit = match query.limit {
+            Some(x) if x > 0 => x,
+            _ => per_page,
+        };
+        let offset = match query.offset {
+            Some(x) => x.max(0),
+            None => (page - 1) * limit,
+        };
 
-        let orderby = format!(" ORDER BY {} {}", sort, sort_dir);
-        let pagination = format!(" LIMIT {} OFFSET {}", per_page, (page - 1) * per_page);
-        let final_query = (filter_query.clone() + &orderby) + &pagination;
-        let total_query = format!("SELECT COUNT(search.id) AS total FROM ({}) AS search", filter_query);
-
-        let mut prepared_qry = self.idx.conn.prepare(final_query.as_str())?;
         let mut values: Vec<&ToSql> = Vec::with_capacity(qry_vals.len());
         for val in &qry_vals {
             let ts: &ToSql = val;
             values.push(ts);
         }
-        let rows = prepared_qry.query_map(values.as_slice(), |row| row.get(0))?;
+
+        // ranking needs every match scored before we can cut out a page, so
+        // grab them all and do the sorting/paging here instead of in sqlite
+        if relevance {
+            let text = query.text.as_ref().expect("turtl::Search.find() -- query.text is None");
+            let all_query = format!("SELECT id, pinned FROM notes WHERE id IN ({})", filter_query);
+            let mut prepared_qry = partition.idx.conn.prepare(all_query.as_str())?;
+            let rows = prepared_qry.query_map(values.as_slice(), |row| (row.get(0), row.get(1)))?;
+            let mut found: Vec<(String, bool)> = Vec::new();
+            for row in rows { found.push(row?); }
+            let total = found.len() as i32;
+            let scores = partition.score(&found.iter().map(|x| x.0.clone()).collect(), text, query.exact)?;
+            let mut ranked = found.into_iter()
+                .map(|(id, pinned)| {
+                    let score = scores.get(&id).map(|x| *x).unwrap_or(0.0);
+                    (id, pinned, score)
+                })
+                .collect::<Vec<_>>();
+            let asc = sort_dir == "asc";
+            ranked.sort_by(|a, b| {
+                let pinned = if query.pinned_first { b.1.cmp(&a.1) } else { Ordering::Equal };
+                pinned.then_with(|| {
+                    let (a, b) = if asc { (a, b) } else { (b, a) };
+                    a.2.partial_cmp(&b.2).unwrap_or(Ordering::Equal).then_with(|| a.0.cmp(&b.0))
+                })
+            });
+            let results = ranked.into_iter()
+                .skip(offset as usize)
+                .take(limit as usize)
+                .map(|(id, _, score)| (id, score))
+                .collect::<Vec<_>>();
+            debug!("Search.find() -- ranked {} notes ({} total)", results.len(), total);
+            return Ok((results, total));
+        }
+
+        // notes that haven't been given a position go after the ones that
+        // have (oldest first, like `models::ordering`). ties go by id so pages
+        // don't shuffle between requests.
+        let sort = match sort_column(sort.as_str()) {
+            Some("id") => String::from("id"),
+            Some(column) => format!("{} {}, id", column, sort_dir),
+            None => format!("position IS NULL, position {}, id", sort_dir),
+        };
+        // misspelled matches go after exact ones (but pinned still go first)
+        let mut order_vals: Vec<SearchVal> = Vec::new();
+        let penalty = if fuzzy_ids.len() > 0 {
+            let mut penalty_qry: Vec<&str> = Vec::with_capacity(fuzzy_ids.len() + 2);
+            penalty_qry.push("id IN (");
+            for id in &fuzzy_ids {
+                if id == &fuzzy_ids[fuzzy_ids.len() - 1] {
+                    penalty_qry.push("?");
+                } else {
+                    penalty_qry.push("?,");
+                }
+                order_vals.push(SearchVal::String(id.clone()));
+            }
+            penalty_qry.push(") ASC, ");
+            penalty_qry.as_slice().join("")
+        } else {
+            String::from("")
+        };
+        let orderby = if query.pinned_first {
+            format!(" ORDER BY pinned DESC, {}{} {}", penalty, sort, sort_dir)
+        } else {
+            format!(" ORDER BY {}{} {}", penalty, sort, sort_dir)
+        };
+        let pagination = format!(" LIMIT {} OFFSET {}", limit, offset);
+        let final_query = (filter_query.clone() + &orderby) + &pagination;
+        let total_query = format!("SELECT COUNT(search.id) AS total FROM ({}) AS search", filter_query);
+
+        let mut prepared_qry = partition.idx.conn.prepare(final_query.as_str())?;
+        let mut ordered_values = values.clone();
+        for val in &order_vals {
+            let ts: &ToSql = val;
+            ordered_values.push(ts);
+        }
+        let rows = prepared_qry.query_map(ordered_values.as_slice(), |row| row.get(0))?;
         let mut note_ids = Vec::new();
         for id in rows { note_ids.push(id?); }
 
-        let total = self.idx.conn.query_row(total_query.as_str(), values.as_slice(), |row| {
+        let total = partition.idx.conn.query_row(total_query.as_str(), values.as_slice(), |row| {
             row.get("total")
         })?;
 
-        debug!("Search.find() -- grabbed {} notes ({} total)", note_ids.len(), total);
-        Ok((note_ids, total))
+        let scores = match query.text.as_ref() {
+            Some(text) => partition.score(&note_ids, text, query.exact)?,
+            None => HashMap::new(),
+        };
+        let results = note_ids.into_iter()
+            .map(|id| {
+                let score = scores.get(&id).map(|x| *x).unwrap_or(0.0);
+                (id, score)
+            })
+            .collect::<Vec<_>>();
+        debug!("Search.find() -- grabbed {} notes ({} total)", results.len(), total);
+        Ok((results, total))
+    }
+
+    /// Suggest tags, note titles, and words from the notes in a space that
+    /// start with what the user has typed so far (at most `limit` of each, most
+    /// common first). Titles match on the start of any of their words.
+    pub fn suggest(&self, space_id: &String, prefix: &str, limit: usize) -> Suggestions {
+        let partition = match self.partitions.get(space_id) {
+            Some(x) => x,
+            None => return Default::default(),
+        };
+        Suggestions {
+            tags: partition.tags.complete(prefix, limit),
+            titles: partition.titles.complete(prefix, limit),
+            terms: partition.terms.complete(prefix, limit),
+        }
+    }
+
+    /// Grab the words to highlight in the results of a query: the words in its
+    /// text, along with (unless the query is `exact`) the misspellings of them
+    /// we'd have matched. Use with `match_positions()`.
+    pub fn highlight_words(&self, query: &Query) -> TResult<Vec<Needle>> {
+        let query = &query.split_text();
+        let mut needles = match query.text.as_ref() {
+            Some(text) => highlight::needles(text.as_str()),
+            None => return Ok(Vec::new()),
+        };
+        if query.exact { return Ok(needles); }
+        let partition = match self.partitions.get(&query.space_id) {
+            Some(x) => x,
+            None => return Ok(needles),
+        };
+        let mut similar: Vec<Needle> = Vec::new();
+        for needle in &needles {
+            if needle.prefix { continue; }
+            for word in partition.similar_terms(needle.word.as_str())? {
+                let known = needles.iter().chain(similar.iter()).any(|x| x.word == word);
+                if !known { similar.push(Needle::new(word, false)); }
+            }
+        }
+        needles.append(&mut similar);
+        Ok(needles)
     }
 
     /// Given a query object, find the tags that match it. This disregards page
@@ -301,47 +1499,28 @@ impl Search {
         if note_ids.len() == 0 {
             return Ok(Vec::new());
         }
-        let mut tag_qry: Vec<&str> = Vec::with_capacity(note_ids.len() + 4);
-        let mut qry_vals: Vec<SearchVal> = Vec::new();
-        tag_qry.push("SELECT tag, count(tag) AS tag_count FROM notes_tags WHERE note_id IN (");
-        if note_ids.len() > 0 {
-            for note_id in note_ids {
-                if note_id == &note_ids[note_ids.len() - 1] {
-                    tag_qry.push("?");
-                } else {
-                    tag_qry.push("?,");
-                }
-                qry_vals.push(SearchVal::String(note_id.clone()));
-            }
-            tag_qry.push(") ");
-        }
-        tag_qry.push("GROUP BY tag ORDER BY tag_count DESC, tag ASC");
-
-        let final_query = tag_qry.as_slice().join("");
-        let mut prepared_qry = self.idx.conn.prepare(final_query.as_str())?;
-        let mut values: Vec<&ToSql> = Vec::with_capacity(qry_vals.len());
-        for val in &qry_vals {
-            let ts: &ToSql = val;
-            values.push(ts);
-        }
-        let rows = prepared_qry.query_map(values.as_slice(), |row| (row.get("tag"), row.get("tag_count")))?;
-        let mut tags = Vec::new();
-        for entry in rows {
-            let val = entry?;
-            tags.push((val.0, val.1));
+        // group our note ids by the partition they live in
+        let mut grouped: HashMap<&String, Vec<String>> = HashMap::new();
+        for note_id in note_ids {
+            let space_id = match self.note_spaces.get(note_id) {
+                Some(x) => x,
+                None => continue,
+            };
+            grouped.entry(space_id).or_insert_with(|| Vec::new()).push(note_id.clone());
         }
-        Ok(tags)
-    }
-}
-
-impl Drop for Search {
-    fn drop(&mut self) {
-        match self.idx.close() {
-            Ok(_) => {},
-            Err(e) => {
-                warn!("Search.drop() -- problem closing search index, oh well... {}", e);
+        let mut counts: HashMap<String, i32> = HashMap::new();
+        for (space_id, ids) in grouped {
+            let partition = match self.partitions.get(space_id) {
+                Some(x) => x,
+                None => continue,
+            };
+            for (tag, count) in partition.tags_by_notes(&ids)? {
+                *counts.entry(tag).or_insert(0) += count;
             }
         }
+        let mut tags = counts.into_iter().collect::<Vec<_>>();
+        tags.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        Ok(tags)
     }
 }
 
@@ -527,10 +1706,15 @@ mod tests {
         assert_eq!(notes, vec!["3333"]);
 
         // combining boards/tags
-        let query = parserrr(r#"{"boards":["6969"],"text":"simple tricks"}"#);
+        let query = parserrr(r#"{"boards":["6969"],"text":"simple tricks","exact":true}"#);
         let (notes, _total) = search.find(&query).unwrap();
         assert_eq!(notes.len(), 0);
 
+        // ...unless we let it match misspellings
+        let query = parserrr(r#"{"boards":["6969"],"text":"simple tricks"}"#);
+        let (notes, _total) = search.find(&query).unwrap();
+        assert_eq!(notes, vec!["3333"]);
+
         // ---------------------------------------------------------------------
         // remove some notes, rerun
         // ---------------------------------------------------------------------
@@ -577,5 +1761,356 @@ mod tests {
         let (notes, _total) = search.find(&query).unwrap();
         assert_eq!(notes.len(), 0);
     }
-}
 
+    #[test]
+    fn filters_fields() {
+        let mut search = Search::new().unwrap();
+        let notes = vec![
+            json!({"id": "1111", "space_id": "4455", "user_id": 69, "title": "taxes", "fields": {"due": "2018-04-15", "priority": 1, "done": false}}),
+            json!({"id": "2222", "space_id": "4455", "user_id": 69, "title": "dentist", "fields": {"due": "2018-02-01", "priority": 3, "where": "downtown"}}),
+            json!({"id": "3333", "space_id": "4455", "user_id": 69, "title": "groceries"}),
+        ];
+        for note in notes {
+            let note: Note = jedi::from_val(note).unwrap();
+            search.index_note(&note).unwrap();
+        }
+        let find = |fields: Value| -> Vec<String> {
+            let qry: Query = jedi::from_val(json!({"space_id": "4455", "fields": fields})).unwrap();
+            search.find(&qry).unwrap().0
+        };
+        assert_eq!(find(json!([{"name": "due", "op": "exists"}])), vec!["2222", "1111"]);
+        assert_eq!(find(json!([{"name": "due", "op": "lt", "value": "2018-03-01"}])), vec!["2222"]);
+        assert_eq!(find(json!([{"name": "priority", "op": "gte", "value": 2}])), vec!["2222"]);
+        assert_eq!(find(json!([{"name": "done", "value": false}])), vec!["1111"]);
+        assert_eq!(find(json!([{"name": "priority", "op": "gt", "value": 0}, {"name": "due", "op": "gt", "value": "2018-03"}])), vec!["1111"]);
+        // string fields are searchable as text too
+        let qry: Query = jedi::from_val(json!({"space_id": "4455", "text": "downtown"})).unwrap();
+        assert_eq!(search.find(&qry).unwrap().0, vec!["2222"]);
+    }
+
+    #[test]
+    fn combines_filters() {
+        let mut search = Search::new().unwrap();
+        let notes = vec![
+            json!({"id": "1111", "space_id": "4455", "user_id": 69, "title": "rent", "tags": ["bills", "home"], "mod": 1500000000}),
+            json!({"id": "2222", "space_id": "4455", "user_id": 69, "title": "power", "tags": ["bills", "utilities"], "mod": 1510000000, "color": 2}),
+            json!({"id": "3333", "space_id": "4455", "user_id": 69, "title": "couch", "tags": ["home", "wishlist"], "mod": 1520000000}),
+            json!({"id": "4444", "space_id": "4455", "user_id": 69, "title": "tv", "tags": ["wishlist"], "mod": 1530000000, "color": 2}),
+        ];
+        for note in notes {
+            let note: Note = jedi::from_val(note).unwrap();
+            search.index_note(&note).unwrap();
+        }
+        let find = |qry: Value| -> Vec<String> {
+            let qry: Query = jedi::from_val(qry).unwrap();
+            search.find(&qry).unwrap().0
+        };
+        assert_eq!(find(json!({"space_id": "4455", "any_tags": ["home", "utilities"]})), vec!["3333", "2222", "1111"]);
+        assert_eq!(find(json!({"space_id": "4455", "tags": ["bills"], "any_tags": ["home", "wishlist"]})), vec!["1111"]);
+        assert_eq!(find(json!({"space_id": "4455", "any_tags": ["home", "wishlist"], "exclude_tags": ["bills"]})), vec!["4444", "3333"]);
+        assert_eq!(find(json!({"space_id": "4455", "modified": {"after": 1510000000, "before": 1530000000}})), vec!["3333", "2222"]);
+        assert_eq!(find(json!({"space_id": "4455", "modified": {"after": 1515000000}, "color": 2})), vec!["4444"]);
+        assert_eq!(find(json!({"space_id": "4455", "modified": {"before": 1500000000}})).len(), 0);
+    }
+
+    #[test]
+    fn ranks_results() {
+        let mut search = Search::new().unwrap();
+        let now = time::get_time().sec;
+        let notes = vec![
+            json!({"id": "1111", "space_id": "4455", "user_id": 69, "title": "tacos", "mod": 1500000000}),
+            json!({"id": "2222", "space_id": "4455", "user_id": 69, "text": "we had tacos", "mod": 1500000000}),
+            json!({"id": "3333", "space_id": "4455", "user_id": 69, "text": "tacos tacos tacos", "mod": 1500000000}),
+            json!({"id": "4444", "space_id": "4455", "user_id": 69, "text": "tacos", "mod": now}),
+        ];
+        for note in notes {
+            let note: Note = jedi::from_val(note).unwrap();
+            search.index_note(&note).unwrap();
+        }
+        let find = |qry: Value| -> Vec<(String, f64)> {
+            let qry: Query = jedi::from_val(qry).unwrap();
+            search.find_scored(&qry).unwrap().0
+        };
+        let ids = |results: &Vec<(String, f64)>| -> Vec<String> {
+            results.iter().map(|x| x.0.clone()).collect()
+        };
+        // titles beat bodies, more beats less, and new beats old
+        let results = find(json!({"space_id": "4455", "text": "tacos"}));
+        assert_eq!(ids(&results), vec!["1111", "3333", "4444", "2222"]);
+        assert!(results.iter().all(|x| x.1 > 0.0));
+        assert!(results[0].1 > results[1].1);
+        let (results, total) = search.find_scored(&jedi::from_val(json!({"space_id": "4455", "text": "tacos", "page": 2, "per_page": 2})).unwrap()).unwrap();
+        assert_eq!(ids(&results), vec!["4444", "2222"]);
+        assert_eq!(total, 4);
+        // other sorts still get scores
+        let results = find(json!({"space_id": "4455", "text": "tacos", "sort": "id"}));
+        assert_eq!(ids(&results), vec!["4444", "3333", "2222", "1111"]);
+        assert!(results.iter().all(|x| x.1 > 0.0));
+        // no text, no scores
+        let results = find(json!({"space_id": "4455", "sort": "relevance"}));
+        assert_eq!(ids(&results), vec!["4444", "3333", "2222", "1111"]);
+        assert!(results.iter().all(|x| x.1 == 0.0));
+    }
+
+    #[test]
+    fn pinned_first() {
+        let mut search = Search::new().unwrap();
+        for (id, pinned) in vec![("1111", false), ("2222", true), ("3333", false), ("4444", true)] {
+            let note: Note = jedi::from_val(json!({"id": id, "space_id": "4455", "user_id": 69, "title": "note", "pinned": pinned})).unwrap();
+            search.index_note(&note).unwrap();
+        }
+        let qry: Query = jedi::from_val(json!({"space_id": "4455", "pinned_first": true})).unwrap();
+        let (notes, _total) = search.find(&qry).unwrap();
+        assert_eq!(notes, vec!["4444", "2222", "3333", "1111"]);
+
+        let qry: Query = jedi::from_val(json!({"space_id": "4455", "pinned": true, "sort_direction": "asc"})).unwrap();
+        let (notes, _total) = search.find(&qry).unwrap();
+        assert_eq!(notes, vec!["2222", "4444"]);
+    }
+
+    #[test]
+    fn sorts_and_pages() {
+        let mut search = Search::new().unwrap();
+        let notes = vec![
+            ("1111", "banana", Some(300)),
+            ("2222", "Apple", None),
+            ("3333", "cherry", Some(100)),
+            ("4444", "apricot", Some(200)),
+        ];
+        for (id, title, mod_) in notes {
+            let note: Note = jedi::from_val(json!({"id": id, "space_id": "4455", "user_id": 69, "title": title, "mod": mod_})).unwrap();
+            search.index_note(&note).unwrap();
+        }
+        fn find(search: &Search, qry: Value) -> Vec<String> {
+            let qry: Query = jedi::from_val(qry).unwrap();
+            search.find(&qry).unwrap().0
+        }
+        assert_eq!(find(&search, json!({"space_id": "4455", "sort": "title"})), vec!["2222", "4444", "1111", "3333"]);
+        assert_eq!(find(&search, json!({"space_id": "4455", "sort": "title", "sort_direction": "desc"})), vec!["3333", "1111", "4444", "2222"]);
+        // 2222 was never edited, so it goes by its (made up) created time
+        assert_eq!(find(&search, json!({"space_id": "4455", "sort": "mod", "sort_direction": "asc"})), vec!["3333", "4444", "1111", "2222"]);
+
+        let qry: Query = jedi::from_val(json!({"space_id": "4455", "sort": "title", "offset": 1, "limit": 2})).unwrap();
+        let (notes, total) = search.find(&qry).unwrap();
+        assert_eq!(notes, vec!["4444", "1111"]);
+        assert_eq!(total, 4);
+        assert_eq!(find(&search, json!({"space_id": "4455", "sort": "title", "offset": 3, "limit": 2})), vec!["3333"]);
+        assert_eq!(find(&search, json!({"space_id": "4455", "sort": "title", "page": 2, "limit": 3})), vec!["3333"]);
+
+        let qry: Query = jedi::from_val(json!({"space_id": "4455", "sort": "title; DROP TABLE notes"})).unwrap();
+        assert!(search.find(&qry).is_err());
+        let qry: Query = jedi::from_val(json!({"space_id": "4455", "sort": "mod", "sort_direction": "sideways"})).unwrap();
+        assert!(search.find(&qry).is_err());
+    }
+
+    #[test]
+    fn matches_misspellings() {
+        let mut search = Search::new().unwrap();
+        let notes = vec![
+            json!({"id": "1111", "space_id": "4455", "user_id": 69, "title": "grandma's recipies"}),
+            json!({"id": "2222", "space_id": "4455", "user_id": 69, "title": "taco recipes", "text": "tortillas, carnitas"}),
+            json!({"id": "3333", "space_id": "4455", "user_id": 69, "title": "groceries"}),
+        ];
+        for note in notes {
+            let note: Note = jedi::from_val(note).unwrap();
+            search.index_note(&note).unwrap();
+        }
+        fn find(search: &Search, qry: Value) -> Vec<String> {
+            let qry: Query = jedi::from_val(qry).unwrap();
+            search.find(&qry).unwrap().0
+        }
+        // exact matches come first, whatever the sort
+        assert_eq!(find(&search, json!({"space_id": "4455", "text": "recipies"})), vec!["1111", "2222"]);
+        assert_eq!(find(&search, json!({"space_id": "4455", "text": "recipes"})), vec!["2222", "1111"]);
+        assert_eq!(find(&search, json!({"space_id": "4455", "text": "recipies", "exact": true})), vec!["1111"]);
+        assert_eq!(find(&search, json!({"space_id": "4455", "text": "tortilas carnitsa"})), vec!["2222"]);
+        // short words have to be spelled right
+        assert_eq!(find(&search, json!({"space_id": "4455", "text": "tac"})).len(), 0);
+
+        search.unindex_note(&jedi::from_val(json!({"id": "2222", "space_id": "4455", "user_id": 69})).unwrap()).unwrap();
+        assert_eq!(find(&search, json!({"space_id": "4455", "text": "recipes"})), vec!["1111"]);
+    }
+
+    #[test]
+    fn partitions_by_space() {
+        let mut search = Search::new().unwrap();
+        let note1: Note = jedi::parse(&String::from(r#"{"id":"1111","space_id":"4455","user_id":69,"type":"text","title":"lunch","text":"tacos","tags":["food"]}"#)).unwrap();
+        let note2: Note = jedi::parse(&String::from(r#"{"id":"2222","space_id":"4455","user_id":69,"type":"text","title":"dinner","text":"more tacos","tags":["food","night"]}"#)).unwrap();
+        let note3: Note = jedi::parse(&String::from(r#"{"id":"3333","space_id":"0000","user_id":69,"type":"text","title":"breakfast","text":"tacos, obviously","tags":["food"]}"#)).unwrap();
+        search.index_note(&note1).unwrap();
+        search.index_note(&note2).unwrap();
+        search.index_note(&note3).unwrap();
+
+        let mut spaces = search.spaces();
+        spaces.sort();
+        assert_eq!(spaces, vec!["0000", "4455"]);
+
+        let query: Query = jedi::parse(&String::from(r#"{"space_id":"4455","text":"tacos"}"#)).unwrap();
+        let (notes, total) = search.find(&query).unwrap();
+        assert_eq!(notes, vec!["2222", "1111"]);
+        assert_eq!(total, 2);
+
+        // tag counts span partitions
+        let tags = search.tags_by_notes(&vec![String::from("1111"), String::from("2222"), String::from("3333")]).unwrap();
+        assert_eq!(tags, vec![(String::from("food"), 3), (String::from("night"), 1)]);
+
+        // move a note to another space
+        let note2_moved: Note = jedi::parse(&String::from(r#"{"id":"2222","space_id":"0000","user_id":69,"type":"text","title":"dinner","text":"more tacos","tags":["food","night"]}"#)).unwrap();
+        search.reindex_note(&note2_moved).unwrap();
+        let (notes, _total) = search.find(&query).unwrap();
+        assert_eq!(notes, vec!["1111"]);
+
+        // purging a space leaves the others alone
+        search.purge_space(&String::from("0000"));
+        assert_eq!(search.spaces(), vec!["4455"]);
+        let query: Query = jedi::parse(&String::from(r#"{"space_id":"0000","text":"tacos"}"#)).unwrap();
+        let (notes, total) = search.find(&query).unwrap();
+        assert_eq!(notes.len(), 0);
+        assert_eq!(total, 0);
+        let query: Query = jedi::parse(&String::from(r#"{"space_id":"4455"}"#)).unwrap();
+        let (notes, _total) = search.find(&query).unwrap();
+        assert_eq!(notes, vec!["1111"]);
+
+        // rebuild a single space
+        assert!(!search.is_loaded(&String::from("0000")));
+        search.reindex_space(&String::from("0000"), &vec![note2_moved, note3]).unwrap();
+        let query: Query = jedi::parse(&String::from(r#"{"space_id":"0000"}"#)).unwrap();
+        let (notes, _total) = search.find(&query).unwrap();
+        assert_eq!(notes, vec!["3333", "2222"]);
+        assert!(search.is_loaded(&String::from("0000")));
+        assert!(!search.is_loaded(&String::from("4455")));
+        search.purge_space(&String::from("0000"));
+        assert!(!search.is_loaded(&String::from("0000")));
+    }
+
+    #[test]
+    fn suggests_prefixes() {
+        let mut search = Search::new().unwrap();
+        let space_id = String::from("4455");
+        let note1: Note = jedi::parse(&String::from(r#"{"id":"1111","space_id":"4455","user_id":69,"type":"text","title":"Tax receipts","text":"receipts from the hardware store","tags":["receipt","taxes"]}"#)).unwrap();
+        let note2: Note = jedi::parse(&String::from(r#"{"id":"2222","space_id":"4455","user_id":69,"type":"text","title":"Recipes","text":"tacos, then more tacos","tags":["recipe","receipt"]}"#)).unwrap();
+        search.index_note(&note1).unwrap();
+        search.index_note(&note2).unwrap();
+
+        let found = search.suggest(&space_id, "rec", 10);
+        assert_eq!(found.tags, vec![(String::from("receipt"), 2), (String::from("recipe"), 1)]);
+        assert_eq!(found.titles, vec![(String::from("Recipes"), 1), (String::from("Tax receipts"), 1)]);
+        assert_eq!(found.terms, vec![(String::from("receipt"), 2), (String::from("receipts"), 1), (String::from("recipe"), 1), (String::from("recipes"), 1)]);
+        assert_eq!(search.suggest(&space_id, "REC", 1).tags, vec![(String::from("receipt"), 2)]);
+        assert_eq!(search.suggest(&String::from("0000"), "rec", 10), Suggestions::default());
+
+        // suggestions follow the notes as they change
+        let note2_edited: Note = jedi::parse(&String::from(r#"{"id":"2222","space_id":"4455","user_id":69,"type":"text","title":"Dinner","text":"tacos","tags":["food"]}"#)).unwrap();
+        search.reindex_note(&note2_edited).unwrap();
+        let found = search.suggest(&space_id, "rec", 10);
+        assert_eq!(found.tags, vec![(String::from("receipt"), 1)]);
+        assert_eq!(found.titles, vec![(String::from("Tax receipts"), 1)]);
+        assert_eq!(search.suggest(&space_id, "ta", 10).terms, vec![(String::from("tacos"), 1), (String::from("tax"), 1), (String::from("taxes"), 1)]);
+        search.unindex_note(&note1).unwrap();
+        assert_eq!(search.suggest(&space_id, "rec", 10), Suggestions::default());
+    }
+
+    #[test]
+    fn finds_match_positions() {
+        let mut search = Search::new().unwrap();
+        let note: Note = jedi::parse(&String::from(r#"{"id":"1111","space_id":"4455","user_id":69,"type":"text","title":"Taco recipes","text":"The best recipes for tacos","tags":["food","recipes"],"items":[{"id":"1","text":"buy tortillas"}],"fields":{"source":"grandma's recipe box"}}"#)).unwrap();
+        search.index_note(&note).unwrap();
+
+        let query: Query = jedi::parse(&String::from(r#"{"space_id":"4455","text":"recipies tort*"}"#)).unwrap();
+        let needles = search.highlight_words(&query).unwrap();
+        let found = match_positions(&note, &needles);
+        let found = found.iter().map(|x| (x.field.as_str(), x.start, x.end)).collect::<Vec<_>>();
+        assert_eq!(found, vec![
+            ("title", 5, 12),
+            ("text", 9, 16),
+            ("tags.1", 0, 7),
+            ("items.0", 4, 13),
+            ("fields.source", 10, 16),
+        ]);
+
+        // exact searches only highlight what was typed
+        let query: Query = jedi::parse(&String::from(r#"{"space_id":"4455","text":"recipies","exact":true}"#)).unwrap();
+        assert_eq!(match_positions(&note, &search.highlight_words(&query).unwrap()).len(), 0);
+        let query: Query = jedi::parse(&String::from(r#"{"space_id":"4455"}"#)).unwrap();
+        assert_eq!(search.highlight_words(&query).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn scopes_to_fields() {
+        let (rest, scoped) = split_scoped(r#"tacos URL:github.com title:"Project X" foo:bar username:"#);
+        assert_eq!(rest, "tacos foo:bar");
+        assert_eq!(scoped, vec![
+            ScopedText { field: String::from("url"), text: String::from("github.com") },
+            ScopedText { field: String::from("title"), text: String::from("Project X") },
+        ]);
+
+        let mut search = Search::new().unwrap();
+        let note1: Note = jedi::parse(&String::from(r#"{"id":"1111","space_id":"4455","user_id":69,"type":"link","title":"Project X repo","url":"https://github.com/turtl/core","text":"the code"}"#)).unwrap();
+        let note2: Note = jedi::parse(&String::from(r#"{"id":"2222","space_id":"4455","user_id":69,"type":"password","title":"GitHub login","username":"Bob","text":"see github.com"}"#)).unwrap();
+        search.index_note(&note1).unwrap();
+        search.index_note(&note2).unwrap();
+        fn find(search: &Search, qry: &str) -> Vec<String> {
+            let qry: Query = jedi::parse(&qry.replacen("{", r#"{"space_id":"4455","#, 1)).unwrap();
+            search.find(&qry).unwrap().0
+        }
+        assert_eq!(find(&search, r#"{"text":"url:github.com"}"#), vec!["1111"]);
+        assert_eq!(find(&search, r#"{"text":"github"}"#), vec!["2222", "1111"]);
+        assert_eq!(find(&search, r#"{"text":"username:bob"}"#), vec!["2222"]);
+        assert_eq!(find(&search, r#"{"text":"title:\"project x\" code"}"#), vec!["1111"]);
+        assert_eq!(find(&search, r#"{"text":"title:\"project x\" login"}"#).len(), 0);
+        assert_eq!(find(&search, r#"{"scoped":[{"field":"title","text":"login"}]}"#), vec!["2222"]);
+        let qry: Query = jedi::parse(&String::from(r#"{"space_id":"4455","scoped":[{"field":"text","text":"code"}]}"#)).unwrap();
+        assert!(search.find(&qry).is_err());
+
+        search.unindex_note(&note2).unwrap();
+        assert_eq!(find(&search, r#"{"text":"username:bob"}"#).len(), 0);
+    }
+
+    #[test]
+    fn filters_by_date() {
+        // 2018-06-26 08:00:00 UTC
+        let now = 1530000000;
+        assert_eq!(parse_date("2017-07-14"), Some(1499990400));
+        assert_eq!(parse_date("2016-02-29"), Some(1456704000));
+        assert_eq!(parse_date("1969-12-31"), Some(-86400));
+        assert_eq!(parse_date("2017-02-29"), None);
+        assert_eq!(parse_date("2017-7-14"), None);
+        assert_eq!(parse_date("2017-07-+4"), None);
+        assert_eq!(parse_age("36h"), Some(129600));
+        assert_eq!(parse_age("d"), None);
+        assert_eq!(parse_age("3x"), None);
+
+        let (rest, range) = split_dates("tacos before:2017-07-14 Last-Week", now);
+        assert_eq!(rest, "tacos");
+        assert_eq!(range, Some(DateRange::new(Some(now - 7 * DAY), Some(1499990400))));
+        assert_eq!(split_dates("modified:>30d", now).1, Some(DateRange::new(None, Some(now - 30 * DAY))));
+        assert_eq!(split_dates("modified:<2w", now).1, Some(DateRange::new(Some(now - 14 * DAY), None)));
+        assert_eq!(split_dates("modified:2017-07-14", now).1, Some(DateRange::new(Some(1499990400), Some(1499990400 + DAY))));
+        assert_eq!(split_dates("after:1y modified:>2018-01-01", now).1, Some(DateRange::new(Some(1514764800), None)));
+        let (rest, range) = split_dates(r#"before:2017-02-30 after:soon modified:> "last-week""#, now);
+        assert_eq!(rest, r#"before:2017-02-30 after:soon modified:> "last-week""#);
+        assert_eq!(range, None);
+
+        let mut search = Search::new().unwrap();
+        let now = time::get_time().sec;
+        let notes = vec![
+            json!({"id": "1111", "space_id": "4455", "user_id": 69, "title": "rent", "mod": 1500000000}),
+            json!({"id": "2222", "space_id": "4455", "user_id": 69, "title": "power", "mod": 1510000000}),
+            json!({"id": "3333", "space_id": "4455", "user_id": 69, "title": "rent", "mod": now - 60 * DAY}),
+            json!({"id": "4444", "space_id": "4455", "user_id": 69, "title": "rent", "mod": now - 2 * DAY}),
+        ];
+        for note in notes {
+            let note: Note = jedi::from_val(note).unwrap();
+            search.index_note(&note).unwrap();
+        }
+        let find = |qry: Value| -> Vec<String> {
+            let qry: Query = jedi::from_val(qry).unwrap();
+            search.find(&qry).unwrap().0
+        };
+        assert_eq!(find(json!({"space_id": "4455", "text": "last-week"})), vec!["4444"]);
+        assert_eq!(find(json!({"space_id": "4455", "text": "rent modified:>30d"})), vec!["3333", "1111"]);
+        assert_eq!(find(json!({"space_id": "4455", "text": "after:2017-07-15 modified:>30d"})), vec!["3333", "2222"]);
+        assert_eq!(find(json!({"space_id": "4455", "text": "modified:2017-11-06"})), vec!["2222"]);
+        assert_eq!(find(json!({"space_id": "4455", "text": "before:2018-01-01", "modified": {"after": 1505000000}})), vec!["2222"]);
+    }
+}
@@ -0,0 +1,284 @@
+//! Catches the search index up in the background.
+//!
+//! When a space is loaded for searching (`Turtl::index_space()`), every note
+//! with a saved entry (see `search_store`) goes straight into the index, and
+//! the space is queued here if any of its notes were added or changed since
+//! they were last indexed (or have entries from an older version of the
+//! index). We work through those a batch at a time: the notes are decrypted
+//! and made into index entries on the work pool, then indexed and saved. Until
+//! then a changed note is searched by its old entry, and a new one just
+//! doesn't show up yet, but the space is searchable right away instead of
+//! waiting on every note to decrypt.
+//!
+//! Each batch's entries are saved as soon as it's done, so a job that gets cut
+//! off (the user logs out, the app closes) picks up where it left off on the
+//! next login. The spaces waiting on us are kept in the user db's kv store. The
+//! UI gets a `search:reindex:progress` event after each batch and a
+//! `search:reindex:done` once everything is caught up.
+
+use ::std::collections::HashMap;
+use ::std::mem;
+use ::std::sync::atomic::{AtomicBool, Ordering};
+use ::config;
+use ::crypto::Key;
+use ::error::TResult;
+use ::jedi;
+use ::messaging;
+use ::models::model::Model;
+use ::models::protected::{self, Protected};
+use ::models::note::Note;
+use ::models::upgrade;
+use ::search::IndexEntry;
+use ::search_store::{self, Stored};
+use ::storage::Storage;
+use ::sync::reminders;
+use ::turtl::Turtl;
+
+/// The kv key holding the spaces waiting to be caught up
+const PENDING_KEY: &'static str = "search:reindex:pending";
+
+lazy_static! {
+    /// Whether a `run()` is already working through the list
+    static ref RUNNING: AtomicBool = AtomicBool::new(false);
+}
+
+/// How many notes we decrypt and index at a time
+fn batch_size() -> usize {
+    config::get::<usize>(&["search", "reindex", "batch"]).unwrap_or(100).max(1)
+}
+
+/// The key we save entries under (see `search_store::derive_key()`), or None
+/// if we're not saving them
+pub fn store_key(turtl: &Turtl) -> TResult<Option<Key>> {
+    if !search_store::enabled() { return Ok(None); }
+    let user_key = lockr!(turtl.user).key_or_else()?;
+    Ok(Some(search_store::derive_key(&user_key)?))
+}
+
+/// What we found comparing a space's notes to their saved entries
+pub struct Scan {
+    /// The saved entries for the space's notes. Some of these may be out of
+    /// date, but they're better than nothing until we get to them.
+    pub entries: Vec<IndexEntry>,
+    /// The notes (as they are in the db, encrypted) that need (re)indexing
+    pub stale: Vec<Note>,
+}
+
+/// Compare a space's notes to their saved entries, finding which notes need
+/// (re)indexing. Entries for notes no longer in the space are removed.
+pub fn scan(turtl: &Turtl, space_id: &String) -> TResult<Scan> {
+    let notes: Vec<Note> = with_db!{ db, turtl.db, db.find("notes", "space_id", &vec![space_id.clone()])? };
+    let mut saved = match store_key(turtl)? {
+        Some(key) => with_db!{ db, turtl.db, search_store::load(db, &key, space_id)? },
+        None => HashMap::new(),
+    };
+    let mut scan = Scan { entries: Vec::new(), stale: Vec::new() };
+    for note in notes {
+        let fresh = match saved.remove(&note.id_or_else()?) {
+            Some(stored) => {
+                let fresh = !stored.is_outdated() && stored.stamp == search_store::stamp(&note)?;
+                if let Some(entry) = stored.entry { scan.entries.push(entry); }
+                fresh
+            }
+            None => false,
+        };
+        if !fresh { scan.stale.push(note); }
+    }
+    // anything left was deleted (or moved to another space) since
+    if saved.len() > 0 {
+        with_db!{ db, turtl.db,
+            for note_id in saved.keys() {
+                search_store::remove_note(db, space_id, note_id)?;
+            }
+        }
+    }
+    Ok(scan)
+}
+
+/// Grab the spaces waiting to be caught up
+pub fn pending(db: &Storage) -> TResult<Vec<String>> {
+    match db.kv_get(PENDING_KEY)? {
+        Some(x) => Ok(jedi::parse(&x)?),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Save the list of spaces waiting to be caught up
+fn set_pending(db: &Storage, list: &Vec<String>) -> TResult<()> {
+    if list.len() == 0 {
+        db.kv_delete(PENDING_KEY)
+    } else {
+        db.kv_set(PENDING_KEY, &jedi::stringify(list)?)
+    }
+}
+
+/// Add a space to the list (if it isn't on it already)
+pub fn queue(db: &Storage, space_id: &String) -> TResult<()> {
+    let mut list = pending(db)?;
+    if list.contains(space_id) { return Ok(()); }
+    list.push(space_id.clone());
+    set_pending(db, &list)
+}
+
+/// Decrypt a batch of a space's notes (as they are in the db), index them, and
+/// save their entries
+fn index_batch(turtl: &Turtl, space_id: &String, mut notes: Vec<Note>) -> TResult<()> {
+    let mut stamps = HashMap::with_capacity(notes.len());
+    for note in &notes {
+        stamps.insert(note.id_or_else()?, search_store::stamp(note)?);
+    }
+    turtl.find_models_keys(&mut notes)?;
+    // the index doesn't need the lazy fields, so don't pay to open them
+    let notes: Vec<Note> = protected::map_deserialize_meta(turtl, notes)?;
+    turtl.session().check()?;
+    // we've got these notes open, so make sure their reminders are scheduled
+    with_db!{ db, turtl.db, reminders::refresh(db, &notes)? };
+    // notes from before lazy fields were split out of `body` get upgraded
+    // in the background
+    let mut misplaced = Vec::new();
+    for note in &notes {
+        if upgrade::lazy_misplaced(note)? {
+            misplaced.push(upgrade::Pending { ty: note.model_type(), id: note.id_or_else()? });
+        }
+    }
+    if misplaced.len() > 0 {
+        with_db!{ db, turtl.db, upgrade::queue(db, misplaced)? };
+        upgrade::start(turtl)?;
+    }
+    let entries = turtl.work.run(move || -> TResult<Vec<(String, Option<IndexEntry>)>> {
+        let mut entries = Vec::with_capacity(notes.len());
+        for note in &notes {
+            match IndexEntry::for_note(note) {
+                Ok(x) => entries.push((note.id_or_else()?, x)),
+                // keep going on error
+                Err(e) => error!("search_reindex::index_batch() -- problem indexing note {:?}: {}", note.id(), e),
+            }
+        }
+        Ok(entries)
+    })?;
+    {
+        let mut search_guard = lock!(turtl.search);
+        // spaces that aren't loaded get their entries saved for later, but
+        // don't need to take up memory now
+        if let Some(search) = search_guard.as_mut() {
+            if search.is_loaded(space_id) {
+                for &(ref note_id, ref entry) in &entries {
+                    search.unindex_id(note_id, space_id)?;
+                    if let Some(entry) = entry.as_ref() {
+                        if let Err(e) = search.index_entry(entry) {
+                            error!("search_reindex::index_batch() -- problem indexing note {}: {}", note_id, e);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    if let Some(key) = store_key(turtl)? {
+        with_db!{ db, turtl.db,
+            for (note_id, entry) in entries {
+                let stamp = match stamps.remove(&note_id) {
+                    Some(x) => x,
+                    None => continue,
+                };
+                search_store::save(db, &key, space_id, &note_id, &Stored::new(stamp, entry))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Decrypt and index all the notes in a space that need it, a batch at a time
+fn reindex_space(turtl: &Turtl, space_id: &String) -> TResult<()> {
+    // without saved entries, there's only a point in this if the space is
+    // being searched right now
+    if !search_store::enabled() {
+        let search_guard = lock!(turtl.search);
+        let loaded = search_guard.as_ref().map(|x| x.is_loaded(space_id)).unwrap_or(false);
+        if !loaded { return Ok(()); }
+    }
+    let mut stale = scan(turtl, space_id)?.stale;
+    let total = stale.len();
+    let mut done = 0;
+    while stale.len() > 0 {
+        turtl.session().check()?;
+        let rest = stale.split_off(batch_size().min(stale.len()));
+        let batch = mem::replace(&mut stale, rest);
+        done += batch.len();
+        index_batch(turtl, space_id, batch)?;
+        messaging::ui_event("search:reindex:progress", &json!({
+            "space_id": space_id,
+            "done": done,
+            "total": total,
+        }))?;
+    }
+    if total > 0 {
+        info!("search_reindex::reindex_space() -- indexed {} notes in space {}", total, space_id);
+    }
+    Ok(())
+}
+
+/// Work through the waiting spaces until they're all caught up or the user
+/// logs out. A space that errors out is dropped from the list (it'll be
+/// queued again the next time it's loaded).
+pub fn catch_up(turtl: &Turtl) -> TResult<()> {
+    let session = turtl.session();
+    loop {
+        session.check()?;
+        let waiting = with_db!{ db, turtl.db, pending(db)? };
+        let space_id = match waiting.into_iter().next() {
+            Some(x) => x,
+            None => break,
+        };
+        if let Err(e) = reindex_space(turtl, &space_id) {
+            session.check()?;
+            warn!("search_reindex::catch_up() -- problem indexing space {}: {}", space_id, e);
+        }
+        with_db!{ db, turtl.db,
+            let mut list = pending(db)?;
+            list.retain(|x| x != &space_id);
+            set_pending(db, &list)?;
+        }
+    }
+    messaging::ui_event("search:reindex:done", &json!({}))?;
+    Ok(())
+}
+
+/// Kick off a `run()` (in its own thread, via the `search:reindex` app event)
+/// if any spaces are waiting
+pub fn start(turtl: &Turtl) -> TResult<()> {
+    let waiting = with_db!{ db, turtl.db, pending(db)?.len() };
+    if waiting == 0 { return Ok(()); }
+    messaging::app_event("search:reindex", &json!({}))
+}
+
+/// Catch up the waiting spaces. Only one of these runs at a time.
+pub fn run(turtl: &Turtl) -> TResult<()> {
+    if RUNNING.swap(true, Ordering::SeqCst) { return Ok(()); }
+    let res = catch_up(turtl);
+    RUNNING.store(false, Ordering::SeqCst);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queues_spaces_once() {
+        let turtl = ::turtl::tests::with_test(true);
+        let space1 = String::from("1111");
+        let space2 = String::from("2222");
+        {
+            let db_guard = lock!(turtl.db);
+            let db = db_guard.as_ref().unwrap();
+            queue(db, &space1).unwrap();
+            queue(db, &space2).unwrap();
+            queue(db, &space1).unwrap();
+            assert_eq!(pending(db).unwrap(), vec![space1.clone(), space2.clone()]);
+        }
+        // neither space has any notes, so they just fall off the list
+        catch_up(&turtl).unwrap();
+        let db_guard = lock!(turtl.db);
+        assert_eq!(pending(db_guard.as_ref().unwrap()).unwrap().len(), 0);
+    }
+}
@@ -0,0 +1,236 @@
+//! Saves the search index's entry for each note in the user's db so logging in
+//! doesn't mean decrypting every note again just to be able to search them.
+//!
+//! What we save is the index's entry for each note (see `search::IndexEntry`),
+//! encrypted, one kv row per note. Next to each entry goes
+//! a stamp of the note as it sat (encrypted) in the db when we indexed it. When
+//! a space is loaded, notes whose stamp still matches are indexed straight from
+//! their saved entry, and only notes that were added or changed since get
+//! decrypted (see `search_reindex`). Entries are saved as notes are indexed
+//! (`Turtl::reindex_note()`), so a note edited here never needs decrypting
+//! again at the next login.
+//!
+//! Each entry also records the version of the index it was made for. When the
+//! entry format changes, older entries are still used as they are (`IndexEntry`
+//! fills in anything they're missing with defaults) while their notes are
+//! indexed over again in the background, so nothing has to be rebuilt all at
+//! once. Entries that can't be read at all (the password changed, say) are
+//! treated like they were never saved.
+//!
+//! Entries hold tokens pulled straight out of the user's notes, so they're
+//! never saved in the clear. They're encrypted with a key of their own, derived
+//! from the user's key (see `derive_key()`) so it only exists while the user
+//! is logged in and is gone again when they log out.
+
+use ::std::collections::HashMap;
+use ::config;
+use ::crypto::{self, Key, CryptoOp};
+use ::error::TResult;
+use ::jedi;
+use ::models::note::Note;
+use ::search::IndexEntry;
+use ::storage::Storage;
+
+/// Bump this when `IndexEntry` changes. Entries saved under an older version
+/// get made over again from their notes.
+pub const ENTRY_VERSION: u32 = 2;
+
+/// The prefix of the kv keys our saved entries live under. Keys look like
+/// `search:note:<space_id>:<note_id>`.
+const KV_PREFIX: &'static str = "search:note:";
+
+/// Mixed with the user's key to get the key we save entries under
+const KEY_CONTEXT: &'static [u8] = b"turtl:search-index";
+
+/// A note's saved entry
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Stored {
+    /// The `ENTRY_VERSION` this entry was made under
+    #[serde(default)]
+    pub version: u32,
+    /// A stamp of the (encrypted) note this entry was made from (see
+    /// `stamp()`)
+    pub stamp: String,
+    /// The note's entry, or None if the note isn't indexed (it's in the
+    /// trash, say) so we don't bother decrypting it again
+    pub entry: Option<IndexEntry>,
+}
+
+impl Stored {
+    pub fn new(stamp: String, entry: Option<IndexEntry>) -> Stored {
+        Stored {
+            version: ENTRY_VERSION,
+            stamp: stamp,
+            entry: entry,
+        }
+    }
+
+    /// Whether this entry was made under an older version of the index
+    pub fn is_outdated(&self) -> bool {
+        self.version < ENTRY_VERSION
+    }
+}
+
+/// Whether we save search indexes at all
+pub fn enabled() -> bool {
+    config::get(&["search", "persist"]).unwrap_or(true)
+}
+
+fn space_prefix(space_id: &String) -> String {
+    format!("{}{}:", KV_PREFIX, space_id)
+}
+
+fn kv_key(space_id: &String, note_id: &String) -> String {
+    format!("{}{}", space_prefix(space_id), note_id)
+}
+
+/// Derive the key we save entries under from the user's key. Keeping it
+/// separate means an entry can't be passed off as some other data the user's
+/// key protects (or the other way around).
+pub fn derive_key(user_key: &Key) -> TResult<Key> {
+    Ok(Key::new(crypto::hmac(user_key.data().as_slice(), KEY_CONTEXT)?))
+}
+
+/// Stamp a note as it sits in the db (encrypted). Any change to the note
+/// (including re-encrypting it) changes its stamp.
+pub fn stamp(note: &Note) -> TResult<String> {
+    let serialized = jedi::stringify(note)?;
+    Ok(crypto::to_hex(&crypto::sha256(serialized.as_bytes())?)?)
+}
+
+/// Open up a saved (encrypted) entry
+fn open(key: &Key, enc: &String) -> TResult<Stored> {
+    let dec = crypto::decrypt(key, crypto::from_base64(enc)?)?;
+    Ok(jedi::parse(&String::from_utf8(dec)?)?)
+}
+
+/// Load the saved entries for a space, by note id. Entries we can't read are
+/// left out.
+pub fn load(db: &Storage, key: &Key, space_id: &String) -> TResult<HashMap<String, Stored>> {
+    let prefix = space_prefix(space_id);
+    let mut entries = HashMap::new();
+    let mut unreadable = 0;
+    for kv_key in db.kv_keys(&prefix)? {
+        let enc = match db.kv_get(&kv_key)? {
+            Some(x) => x,
+            None => continue,
+        };
+        match open(key, &enc) {
+            Ok(x) => { entries.insert(String::from(&kv_key[prefix.len()..]), x); }
+            Err(_) => unreadable += 1,
+        }
+    }
+    if unreadable > 0 {
+        warn!("search_store::load() -- couldn't open {} saved entries for space {}, they'll be rebuilt", unreadable, space_id);
+    }
+    Ok(entries)
+}
+
+/// Save a note's entry
+pub fn save(db: &Storage, key: &Key, space_id: &String, note_id: &String, stored: &Stored) -> TResult<()> {
+    let serialized = jedi::stringify(stored)?;
+    let enc = crypto::encrypt(key, Vec::from(serialized.as_bytes()), CryptoOp::new("chacha20poly1305")?)?;
+    db.kv_set(&kv_key(space_id, note_id), &crypto::to_base64(&enc)?)
+}
+
+/// Remove a note's saved entry
+pub fn remove_note(db: &Storage, space_id: &String, note_id: &String) -> TResult<()> {
+    db.kv_delete(&kv_key(space_id, note_id))
+}
+
+/// Remove all the saved entries for a space
+pub fn remove(db: &Storage, space_id: &String) -> TResult<()> {
+    for key in db.kv_keys(&space_prefix(space_id))? {
+        db.kv_delete(&key)?;
+    }
+    Ok(())
+}
+
+/// Remove the saved entries for any spaces not in the given list (ones we've
+/// left or that were deleted)
+pub fn prune(db: &Storage, space_ids: &Vec<String>) -> TResult<()> {
+    for key in db.kv_keys(KV_PREFIX)? {
+        let keep = match key[KV_PREFIX.len()..].split(':').next() {
+            Some(space_id) => space_ids.iter().any(|x| x == space_id),
+            None => false,
+        };
+        if !keep { db.kv_delete(&key)?; }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saves_and_loads_entries() {
+        let turtl = ::turtl::tests::with_test(true);
+        let db_guard = lock!(turtl.db);
+        let db = db_guard.as_ref().unwrap();
+        let key = Key::random().unwrap();
+        let space_id = String::from("4455");
+        let note1 = String::from("1111");
+        let note2 = String::from("2222");
+        assert_eq!(load(db, &key, &space_id).unwrap().len(), 0);
+
+        let mut entry = IndexEntry::default();
+        entry.id = note1.clone();
+        entry.space_id = space_id.clone();
+        entry.body = String::from("tacos");
+        let stored1 = Stored::new(String::from("abc"), Some(entry.clone()));
+        let stored2 = Stored::new(String::from("def"), None);
+        save(db, &key, &space_id, &note1, &stored1).unwrap();
+        save(db, &key, &space_id, &note2, &stored2).unwrap();
+        let loaded = load(db, &key, &space_id).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.get(&note1), Some(&stored1));
+        assert_eq!(loaded.get(&note2), Some(&stored2));
+        assert!(!stored1.is_outdated());
+
+        // the wrong key just means we rebuild
+        assert_eq!(load(db, &Key::random().unwrap(), &space_id).unwrap().len(), 0);
+
+        // entries from an older version still load, but are marked as such
+        let mut old = stored1.clone();
+        old.version = ENTRY_VERSION - 1;
+        save(db, &key, &space_id, &note1, &old).unwrap();
+        assert!(load(db, &key, &space_id).unwrap().get(&note1).unwrap().is_outdated());
+        remove_note(db, &space_id, &note1).unwrap();
+        assert_eq!(load(db, &key, &space_id).unwrap().len(), 1);
+
+        // spaces we're no longer in get cleaned up
+        let other = String::from("0000");
+        save(db, &key, &other, &note1, &stored1).unwrap();
+        prune(db, &vec![space_id.clone()]).unwrap();
+        assert_eq!(load(db, &key, &other).unwrap().len(), 0);
+        assert_eq!(load(db, &key, &space_id).unwrap().len(), 1);
+        remove(db, &space_id).unwrap();
+        assert_eq!(load(db, &key, &space_id).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn derives_keys() {
+        let turtl = ::turtl::tests::with_test(true);
+        let db_guard = lock!(turtl.db);
+        let db = db_guard.as_ref().unwrap();
+        let user_key = Key::random().unwrap();
+        let key = derive_key(&user_key).unwrap();
+        assert_eq!(key.data().len(), crypto::keylen());
+        assert!(key.data() != user_key.data());
+        assert_eq!(derive_key(&user_key).unwrap().data(), key.data());
+        assert!(derive_key(&Key::random().unwrap()).unwrap().data() != key.data());
+
+        // entries saved under the derived key don't open with the user's key
+        let space_id = String::from("4455");
+        let note_id = String::from("1111");
+        let stored = Stored::new(String::from("abc"), None);
+        save(db, &key, &space_id, &note_id, &stored).unwrap();
+        assert_eq!(load(db, &user_key, &space_id).unwrap().len(), 0);
+        // ...but do with the derived one, keyed by their note's id
+        let loaded = load(db, &derive_key(&user_key).unwrap(), &space_id).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded.get(&note_id), Some(&stored));
+        remove(db, &space_id).unwrap();
+    }
+}
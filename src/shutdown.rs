@@ -0,0 +1,267 @@
+//! The shutdown coordinator tears the app down in a well-defined order.
+//!
+//! Each component registers a shutdown function along with the components that
+//! must finish shutting down before it runs (sync before the db closes, for
+//! instance, and messaging after everything else). Components with no ordering
+//! constraints between them run in parallel as part of the same phase, and each
+//! gets a timeout: a component that hangs is logged, reported, and left behind
+//! so the rest of the app can still go down.
+
+use ::std::sync::{Arc, mpsc};
+use ::std::collections::HashMap;
+use ::std::time::{Duration, Instant};
+use ::std::thread;
+
+use ::config;
+use ::error::{TResult, TError};
+use ::messaging;
+use ::turtl::Turtl;
+
+/// A function that shuts down one piece of the app
+type ShutdownFn = Arc<Fn() -> TResult<()> + Send + Sync>;
+
+/// Describes a component we shut down
+struct Component {
+    /// This component's name
+    name: String,
+    /// The components that must be shut down before this one
+    after: Vec<String>,
+    /// How long we wait for this component before giving up on it
+    timeout: Duration,
+    /// Does the actual shutting down
+    run: ShutdownFn,
+}
+
+/// Tells the host how shutdown went. Sent along with the
+/// `app:shutdown-complete` event.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct ShutdownReport {
+    /// Components that shut down cleanly
+    pub completed: Vec<String>,
+    /// Components whose shutdown returned an error (name, error)
+    pub failed: Vec<(String, String)>,
+    /// Components that didn't finish within their timeout
+    pub timed_out: Vec<String>,
+}
+
+/// Runs our shutdown, in order.
+pub struct Coordinator {
+    components: Vec<Component>,
+}
+
+impl Coordinator {
+    /// Create an empty coordinator
+    pub fn new() -> Coordinator {
+        Coordinator {
+            components: Vec::new(),
+        }
+    }
+
+    /// Register a component. `after` lists the components that must be shut
+    /// down before this one runs.
+    pub fn add<F>(&mut self, name: &str, after: &[&str], timeout: Duration, run: F) -> &mut Self
+        where F: Fn() -> TResult<()> + Send + Sync + 'static
+    {
+        self.components.push(Component {
+            name: String::from(name),
+            after: after.iter().map(|x| String::from(*x)).collect::<Vec<_>>(),
+            timeout: timeout,
+            run: Arc::new(run),
+        });
+        self
+    }
+
+    /// Sort our components into phases. Everything in a phase only depends on
+    /// things in earlier phases, so a phase's components can run in parallel.
+    /// Errors if a dependency doesn't exist or if the dependencies have a
+    /// cycle.
+    fn phases(&self) -> TResult<Vec<Vec<usize>>> {
+        let mut index: HashMap<&String, usize> = HashMap::new();
+        for (i, component) in self.components.iter().enumerate() {
+            index.insert(&component.name, i);
+        }
+        let mut waiting_on: Vec<usize> = Vec::with_capacity(self.components.len());
+        let mut dependents: Vec<Vec<usize>> = vec![Vec::new(); self.components.len()];
+        for (i, component) in self.components.iter().enumerate() {
+            for dep in &component.after {
+                match index.get(dep) {
+                    Some(x) => dependents[*x].push(i),
+                    None => return TErr!(TError::BadValue(format!("shutdown component `{}` depends on unknown component `{}`", component.name, dep))),
+                }
+            }
+            waiting_on.push(component.after.len());
+        }
+
+        let mut phases = Vec::new();
+        let mut current = (0..self.components.len())
+            .filter(|i| waiting_on[*i] == 0)
+            .collect::<Vec<_>>();
+        let mut placed = 0;
+        while current.len() > 0 {
+            placed += current.len();
+            let mut next = Vec::new();
+            for i in &current {
+                for dependent in &dependents[*i] {
+                    waiting_on[*dependent] -= 1;
+                    if waiting_on[*dependent] == 0 { next.push(*dependent); }
+                }
+            }
+            phases.push(current);
+            current = next;
+        }
+        if placed < self.components.len() {
+            let stuck = self.components.iter().enumerate()
+                .filter(|&(i, _)| waiting_on[i] > 0)
+                .map(|(_, x)| x.name.clone())
+                .collect::<Vec<_>>();
+            return TErr!(TError::BadValue(format!("shutdown components have a dependency cycle: {}", stuck.join(", "))));
+        }
+        Ok(phases)
+    }
+
+    /// Run our shutdown, phase by phase. Components in a phase run in their own
+    /// threads. If a component times out we move on without it.
+    pub fn run(&self) -> TResult<ShutdownReport> {
+        let phases = self.phases()?;
+        let mut report = ShutdownReport::default();
+        for phase in phases {
+            let start = Instant::now();
+            let mut running = Vec::with_capacity(phase.len());
+            for i in phase {
+                let component = &self.components[i];
+                let (tx, rx) = mpsc::channel();
+                let run = component.run.clone();
+                let spawned = thread::Builder::new()
+                    .name(format!("shutdown:{}", component.name))
+                    .spawn(move || {
+                        // the receiver may have given up on us, which is fine
+                        let _ = tx.send(run());
+                    });
+                match spawned {
+                    Ok(_) => running.push((component, rx)),
+                    Err(e) => report.failed.push((component.name.clone(), format!("{}", e))),
+                }
+            }
+            for (component, rx) in running {
+                let elapsed = start.elapsed();
+                let remaining = if elapsed < component.timeout { component.timeout - elapsed } else { Duration::from_millis(0) };
+                match rx.recv_timeout(remaining) {
+                    Ok(Ok(_)) => {
+                        info!("shutdown::run() -- {} shut down", component.name);
+                        report.completed.push(component.name.clone());
+                    }
+                    Ok(Err(e)) => {
+                        error!("shutdown::run() -- {} failed to shut down: {}", component.name, e);
+                        report.failed.push((component.name.clone(), format!("{}", e)));
+                    }
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        warn!("shutdown::run() -- {} timed out after {:?}, moving on", component.name, component.timeout);
+                        report.timed_out.push(component.name.clone());
+                    }
+                    Err(mpsc::RecvTimeoutError::Disconnected) => {
+                        error!("shutdown::run() -- {} panicked while shutting down", component.name);
+                        report.failed.push((component.name.clone(), String::from("panicked")));
+                    }
+                }
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// Grab the timeout for a component from config (shutdown.timeouts.<name>, in
+/// ms), falling back to a default.
+fn timeout(name: &str, default: u64) -> Duration {
+    let ms: u64 = config::get(&["shutdown", "timeouts", name]).unwrap_or(default);
+    Duration::from_millis(ms)
+}
+
+/// Build the coordinator for a running Turtl app
+pub fn coordinator(turtl: Arc<Turtl>) -> Coordinator {
+    let mut coordinator = Coordinator::new();
+    let turtl1 = turtl.clone();
+    let turtl2 = turtl.clone();
+    let turtl3 = turtl.clone();
+    coordinator
+        .add("sync", &[], timeout("sync", 10000), move || turtl1.sync_shutdown(true))
+        .add("search", &["sync"], timeout("search", 5000), move || {
+            turtl2.close_search();
+            Ok(())
+        })
+        .add("db", &["sync", "search"], timeout("db", 5000), move || turtl3.close_user_db())
+        .add("messaging", &["sync", "search", "db"], timeout("messaging", 5000), move || {
+            messaging::stop();
+            Ok(())
+        });
+    coordinator
+}
+
+/// Shut the app down in order and let the host know we're done via the
+/// `app:shutdown-complete` event.
+pub fn run(turtl: Arc<Turtl>) -> TResult<ShutdownReport> {
+    info!("shutdown::run() -- starting shutdown");
+    let report = coordinator(turtl).run()?;
+    messaging::ui_event("app:shutdown-complete", &report)?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ::std::sync::RwLock;
+
+    #[test]
+    fn orders_phases() {
+        let mut coordinator = Coordinator::new();
+        let timeout = Duration::from_millis(1000);
+        coordinator
+            .add("messaging", &["sync", "db"], timeout, || Ok(()))
+            .add("db", &["sync"], timeout, || Ok(()))
+            .add("sync", &[], timeout, || Ok(()))
+            .add("search", &[], timeout, || Ok(()));
+        let phases = coordinator.phases().unwrap();
+        assert_eq!(phases, vec![vec![2, 3], vec![1], vec![0]]);
+
+        let mut coordinator = Coordinator::new();
+        coordinator
+            .add("a", &["b"], timeout, || Ok(()))
+            .add("b", &["a"], timeout, || Ok(()));
+        assert!(coordinator.phases().is_err());
+
+        let mut coordinator = Coordinator::new();
+        coordinator.add("a", &["lol"], timeout, || Ok(()));
+        assert!(coordinator.phases().is_err());
+    }
+
+    #[test]
+    fn runs_in_order_with_timeouts() {
+        let order: Arc<RwLock<Vec<&'static str>>> = Arc::new(RwLock::new(Vec::new()));
+        let order1 = order.clone();
+        let order2 = order.clone();
+        let order3 = order.clone();
+        let mut coordinator = Coordinator::new();
+        coordinator
+            .add("last", &["slow", "broken"], Duration::from_millis(1000), move || {
+                order1.write().unwrap().push("last");
+                Ok(())
+            })
+            .add("slow", &[], Duration::from_millis(10), move || {
+                thread::sleep(Duration::from_millis(2000));
+                order2.write().unwrap().push("slow");
+                Ok(())
+            })
+            .add("broken", &[], Duration::from_millis(1000), move || {
+                order3.write().unwrap().push("broken");
+                TErr!(TError::Msg(String::from("oh no")))
+            });
+        let report = coordinator.run().unwrap();
+        assert_eq!(report.completed, vec!["last"]);
+        assert_eq!(report.timed_out, vec!["slow"]);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, "broken");
+        // we didn't wait on the slow component
+        assert_eq!(*order.read().unwrap(), vec!["broken", "last"]);
+    }
+}
+
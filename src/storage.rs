@@ -160,6 +160,11 @@ impl Storage {
         Ok(self.dumpy.kv_delete(&self.conn, key)?)
     }
 
+    /// Grab all the keys in our k/v store starting with the given prefix
+    pub fn kv_keys(&self, prefix: &str) -> TResult<Vec<String>> {
+        Ok(self.dumpy.kv_keys(&self.conn, prefix)?)
+    }
+
     /// Close the db connection
     pub fn close(&mut self) -> TResult<()> {
         let mut conn = Connection::open_in_memory()?;
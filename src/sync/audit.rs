@@ -0,0 +1,232 @@
+//! Checks that what we have locally lines up with what the server has. Every
+//! so often someone finds notes missing on one of their devices, and since the
+//! sync system thinks everything is fine, there's nothing to go on. This asks
+//! the server for a manifest (ids and mod times, no content) of the user's
+//! spaces, boards, and notes, and reports, per space, what we're missing, what
+//! we have that the server doesn't, and what we have an older version of.
+//!
+//! Items with local changes waiting to go out are left out of the report,
+//! since of course those don't match yet. So are items in spaces this device
+//! doesn't sync (see `sync::selective`).
+
+use ::std::collections::{HashMap, HashSet, BTreeMap};
+use ::jedi::{self, Value};
+use ::error::{TResult, TError};
+use ::api::ApiReq;
+use ::models::sync_record::SyncRecord;
+use ::sync::selective::SpaceFilter;
+use ::turtl::Turtl;
+
+/// The tables we audit, and the type the server calls their items
+const TABLES: [(&'static str, &'static str); 3] = [("spaces", "space"), ("boards", "board"), ("notes", "note")];
+
+/// One item, as the server (or our db) sees it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ManifestItem {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub ty: String,
+    #[serde(default)]
+    pub space_id: Option<String>,
+    #[serde(rename = "mod")]
+    #[serde(default)]
+    pub mod_: Option<i64>,
+}
+
+impl ManifestItem {
+    /// Which space this item lives in (spaces live in themselves)
+    fn space(&self) -> String {
+        if self.ty == "space" { return self.id.clone(); }
+        self.space_id.clone().unwrap_or(String::new())
+    }
+}
+
+/// What the server gives us back
+#[derive(Deserialize, Debug)]
+struct Manifest {
+    #[serde(default)]
+    items: Vec<ManifestItem>,
+}
+
+/// An item that doesn't line up
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct AuditItem {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub ty: String,
+    pub local_mod: Option<i64>,
+    pub server_mod: Option<i64>,
+}
+
+/// Everything that doesn't line up in one space
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct SpaceAudit {
+    pub space_id: String,
+    /// On the server, but not here
+    pub missing: Vec<AuditItem>,
+    /// Here, but not on the server
+    pub extra: Vec<AuditItem>,
+    /// Older here than on the server
+    pub stale: Vec<AuditItem>,
+}
+
+/// The results of an audit
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct AuditReport {
+    /// Only spaces with problems show up here
+    pub spaces: Vec<SpaceAudit>,
+    /// How many items we compared
+    pub checked: u64,
+    /// How many items we skipped because they have local changes pending
+    pub pending: u64,
+    /// True if everything lines up
+    pub clean: bool,
+}
+
+/// Grab the audit for a space, starting one if needed
+fn space_audit<'a>(spaces: &'a mut BTreeMap<String, SpaceAudit>, space_id: String) -> &'a mut SpaceAudit {
+    spaces.entry(space_id.clone()).or_insert_with(|| SpaceAudit { space_id: space_id, ..Default::default() })
+}
+
+/// Compare our items against the server's
+pub fn compare(local: Vec<ManifestItem>, remote: Vec<ManifestItem>, pending: &HashSet<String>, filter: &SpaceFilter) -> AuditReport {
+    let mut report = AuditReport::default();
+    let mut spaces: BTreeMap<String, SpaceAudit> = BTreeMap::new();
+    let mut local = local.into_iter().map(|x| (x.id.clone(), x)).collect::<HashMap<_, _>>();
+    let mut skipped = HashSet::new();
+    for theirs in remote {
+        if pending.contains(&theirs.id) {
+            skipped.insert(theirs.id.clone());
+            local.remove(&theirs.id);
+            continue;
+        }
+        let space_id = theirs.space();
+        if !filter.allows(&space_id) {
+            local.remove(&theirs.id);
+            continue;
+        }
+        report.checked += 1;
+        match local.remove(&theirs.id) {
+            Some(ours) => {
+                let stale = match (ours.mod_, theirs.mod_) {
+                    (Some(x), Some(y)) => x < y,
+                    _ => false,
+                };
+                if stale {
+                    space_audit(&mut spaces, space_id).stale.push(AuditItem { id: theirs.id, ty: theirs.ty, local_mod: ours.mod_, server_mod: theirs.mod_ });
+                }
+            }
+            None => {
+                space_audit(&mut spaces, space_id).missing.push(AuditItem { id: theirs.id, ty: theirs.ty, local_mod: None, server_mod: theirs.mod_ });
+            }
+        }
+    }
+    let mut extra = local.into_iter().map(|(_, x)| x).collect::<Vec<_>>();
+    extra.sort_by(|a, b| a.id.cmp(&b.id));
+    for ours in extra {
+        if pending.contains(&ours.id) {
+            skipped.insert(ours.id.clone());
+            continue;
+        }
+        report.checked += 1;
+        space_audit(&mut spaces, ours.space()).extra.push(AuditItem { id: ours.id, ty: ours.ty, local_mod: ours.mod_, server_mod: None });
+    }
+    report.pending = skipped.len() as u64;
+    report.spaces = spaces.into_iter().map(|(_, x)| x).collect();
+    report.clean = report.spaces.len() == 0;
+    report
+}
+
+/// Run an audit: grab the server's manifest and compare it to our db
+pub fn run(turtl: &Turtl) -> TResult<AuditReport> {
+    let manifest: Manifest = turtl.api.get("/sync/manifest", ApiReq::new().timeout(120))?;
+    let filter = turtl.sync_space_filter();
+    let (local, pending) = {
+        let mut db_guard = lock!(turtl.db);
+        let db = match db_guard.as_mut() {
+            Some(x) => x,
+            None => return TErr!(TError::MissingField(String::from("Turtl.db"))),
+        };
+        let mut local = Vec::new();
+        for &(table, ty) in TABLES.iter() {
+            for item in db.dumpy.all(&db.conn, &String::from(table))? {
+                local.push(ManifestItem {
+                    id: jedi::get(&["id"], &item)?,
+                    ty: String::from(ty),
+                    space_id: jedi::get_opt(&["space_id"], &item),
+                    mod_: jedi::get_opt::<Value>(&["mod"], &item).and_then(|x| x.as_i64()),
+                });
+            }
+        }
+        let pending = SyncRecord::find(db, None)?
+            .into_iter()
+            .map(|x| x.item_id)
+            .collect::<HashSet<_>>();
+        (local, pending)
+    };
+    let types = TABLES.iter().map(|x| x.1).collect::<Vec<_>>();
+    let remote = manifest.items.into_iter()
+        .filter(|x| types.contains(&x.ty.as_str()))
+        .collect::<Vec<_>>();
+    let report = compare(local, remote, &pending, &filter);
+    info!("audit::run() -- checked {} items ({} pending), {} space(s) with problems", report.checked, report.pending, report.spaces.len());
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(id: &str, ty: &str, space_id: &str, mod_: Option<i64>) -> ManifestItem {
+        ManifestItem {
+            id: String::from(id),
+            ty: String::from(ty),
+            space_id: if ty == "space" { None } else { Some(String::from(space_id)) },
+            mod_: mod_,
+        }
+    }
+
+    #[test]
+    fn compares_manifests() {
+        let local = vec![
+            item("s1", "space", "", None),
+            item("n1", "note", "s1", Some(100)),
+            item("n2", "note", "s1", Some(100)),
+            item("n3", "note", "s1", Some(100)),
+            item("n4", "note", "s1", Some(100)),
+        ];
+        let remote = vec![
+            item("s1", "space", "", None),
+            // same
+            item("n1", "note", "s1", Some(100)),
+            // newer on the server
+            item("n2", "note", "s1", Some(200)),
+            // newer on the server, but we have an edit on the way
+            item("n3", "note", "s1", Some(200)),
+            // we don't have it
+            item("n5", "note", "s1", Some(100)),
+            item("b1", "board", "s2", None),
+            // we don't sync this space
+            item("n6", "note", "s3", Some(100)),
+        ];
+        let mut pending = HashSet::new();
+        pending.insert(String::from("n3"));
+        let filter: SpaceFilter = jedi::parse(&String::from(r#"{"mode":"except","spaces":["s3"]}"#)).unwrap();
+        let report = compare(local, remote, &pending, &filter);
+        assert!(!report.clean);
+        assert_eq!(report.pending, 1);
+        assert_eq!(report.checked, 6);
+        assert_eq!(report.spaces.len(), 2);
+        let s1 = &report.spaces[0];
+        assert_eq!(s1.space_id, "s1");
+        assert_eq!(s1.stale.iter().map(|x| x.id.as_str()).collect::<Vec<_>>(), vec!["n2"]);
+        assert_eq!(s1.missing.iter().map(|x| x.id.as_str()).collect::<Vec<_>>(), vec!["n5"]);
+        assert_eq!(s1.extra.iter().map(|x| x.id.as_str()).collect::<Vec<_>>(), vec!["n4"]);
+        assert_eq!(report.spaces[1].missing[0].id, "b1");
+
+        let report = compare(vec![item("n1", "note", "s1", Some(1))], vec![item("n1", "note", "s1", Some(1))], &HashSet::new(), &Default::default());
+        assert!(report.clean);
+        assert_eq!(report.checked, 1);
+    }
+}
+
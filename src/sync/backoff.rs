@@ -0,0 +1,135 @@
+//! Keeps a failing syncer from hammering the API. Each consecutive failure
+//! doubles the time a syncer waits before trying again (up to a cap), with some
+//! jitter thrown in so a bunch of clients coming back from the same outage
+//! don't all hit the server in lockstep.
+
+use ::time;
+use ::config;
+
+/// What we tell the UI about a syncer's backoff (via `sync:status`)
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BackoffStatus {
+    /// The syncer this applies to (incoming, outgoing, etc)
+    pub syncer: String,
+    /// How many times in a row this syncer has failed
+    pub failures: u32,
+    /// Whether or not the syncer is currently backing off
+    pub backing_off: bool,
+    /// How long (in ms) until the syncer tries again
+    pub delay: u64,
+}
+
+/// Tracks consecutive failures for a syncer and decides how long to wait
+/// before the next run.
+pub struct Backoff {
+    /// The syncer we're tracking
+    name: &'static str,
+    /// Consecutive failures
+    failures: u32,
+    /// The most we'll ever wait (ms)
+    max: u64,
+}
+
+impl Backoff {
+    /// Create a new backoff, with the cap pulled from `sync.backoff.max`
+    pub fn new(name: &'static str) -> Self {
+        Backoff::with_max(name, config::get(&["sync", "backoff", "max"]).unwrap_or(300000))
+    }
+
+    /// Create a new backoff with a specific cap
+    pub fn with_max(name: &'static str, max: u64) -> Self {
+        Backoff {
+            name: name,
+            failures: 0,
+            max: max,
+        }
+    }
+
+    /// Record a failed run
+    pub fn failure(&mut self) {
+        self.failures = self.failures.saturating_add(1);
+    }
+
+    /// Record a successful run. Returns true if we were backing off (so the
+    /// caller can let everyone know we've recovered).
+    pub fn success(&mut self) -> bool {
+        let was_failing = self.failures > 0;
+        self.failures = 0;
+        was_failing
+    }
+
+    /// How many failures in a row we've seen
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Given a syncer's normal delay, get how long we should actually wait.
+    /// With no failures, this is just the normal delay. Otherwise it's the
+    /// normal delay doubled for each failure, capped, and then randomized
+    /// between half and all of that.
+    pub fn delay(&self, base: u64) -> u64 {
+        if self.failures == 0 { return base; }
+        let exp = if self.failures > 31 { 31 } else { self.failures };
+        let ceiling = base.saturating_mul(1 << exp);
+        let ceiling = if ceiling > self.max { self.max } else { ceiling };
+        let ceiling = if ceiling < base { base } else { ceiling };
+        let half = ceiling / 2;
+        half + jitter(ceiling - half)
+    }
+
+    /// Grab our current state for the UI
+    pub fn status(&self, delay: u64) -> BackoffStatus {
+        BackoffStatus {
+            syncer: String::from(self.name),
+            failures: self.failures,
+            backing_off: self.failures > 0,
+            delay: delay,
+        }
+    }
+}
+
+/// A random-ish number between 0 and `max` (inclusive). This doesn't need to be
+/// unpredictable, just spread out between clients.
+fn jitter(max: u64) -> u64 {
+    if max == 0 { return 0; }
+    // mix the clock's nanos up a bit so back-to-back calls don't correlate
+    let mut x = time::precise_time_ns() ^ 0x9e3779b97f4a7c15;
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xff51afd7ed558ccd);
+    x ^= x >> 33;
+    x % (max + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_off_and_recovers() {
+        let mut backoff = Backoff::with_max("incoming", 10000);
+        assert_eq!(backoff.delay(1000), 1000);
+        assert!(!backoff.success());
+
+        backoff.failure();
+        let delay = backoff.delay(1000);
+        assert!(delay >= 1000 && delay <= 2000);
+        backoff.failure();
+        let delay = backoff.delay(1000);
+        assert!(delay >= 2000 && delay <= 4000);
+        for _ in 0..100 { backoff.failure(); }
+        assert_eq!(backoff.failures(), 102);
+        for _ in 0..20 {
+            let delay = backoff.delay(1000);
+            assert!(delay >= 5000 && delay <= 10000);
+        }
+        let status = backoff.status(backoff.delay(1000));
+        assert_eq!(status.syncer, "incoming");
+        assert!(status.backing_off);
+
+        assert!(backoff.success());
+        assert_eq!(backoff.failures(), 0);
+        assert_eq!(backoff.delay(1000), 1000);
+        assert!(!backoff.status(1000).backing_off);
+    }
+}
+
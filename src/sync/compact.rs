@@ -0,0 +1,149 @@
+//! Keeps the sync bookkeeping in the user db from piling up forever. Run every
+//! so often (`sync.compact.interval`) by the outgoing syncer, compaction:
+//!
+//! - merges runs of pending edits to the same item into one (each edit carries
+//!   the item's full data, so only the last one matters)
+//! - drops ignored incoming sync ids we've already synced past
+//! - forgets stashed base versions (see `sync::conflict`) for items with no
+//!   pending edits
+//! - throws out partial file downloads that are orphaned or older than
+//!   `sync.compact.max_age` seconds
+//! - deletes attachment files whose notes are gone (see `sync::files::gc`)
+
+use ::std::collections::{HashMap, HashSet};
+use ::config;
+use ::error::TResult;
+use ::storage::Storage;
+use ::models::sync_record::{SyncRecord, SyncType, SyncAction};
+use ::sync::conflict;
+use ::sync::delta;
+use ::sync::incoming::SyncIncoming;
+use ::sync::files::incoming::FileSyncIncoming;
+use ::sync::files::gc;
+use ::sync::sync_model::SyncModel;
+use ::util;
+
+/// What a compaction pass cleaned up
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct CompactResult {
+    /// Pending edits folded into a later edit of the same item
+    pub merged: u64,
+    /// Ignored sync ids we no longer need
+    pub ignored: u64,
+    /// Stashed base versions we no longer need
+    pub bases: u64,
+    /// Partial downloads we threw out
+    pub partials: u64,
+    /// Orphaned attachment files we deleted
+    pub files: u64,
+}
+
+/// How often (ms) we compact
+pub fn interval() -> u64 {
+    config::get(&["sync", "compact", "interval"]).unwrap_or(3600000)
+}
+
+/// Merge runs of pending edits to the same item, keeping the last one. A run
+/// is broken by any other record for that item (an add, a move, a delete) and
+/// by records that have already failed, so we never reorder anything or touch
+/// records the user might be looking at. Returns how many records we removed.
+///
+/// The edit we keep loses its delta (if any), since it was made against a
+/// version of the item the server will now never see.
+pub fn merge_edits(db: &mut Storage) -> TResult<u64> {
+    let records = SyncRecord::allbut(db, &vec![SyncType::FileIncoming, SyncType::FileOutgoing])?;
+    let mut last_edit: HashMap<(String, String), SyncRecord> = HashMap::new();
+    let mut redundant = Vec::new();
+    let mut merged_into = HashSet::new();
+    for rec in records {
+        let key = (util::enum_to_string(&rec.ty)?, rec.item_id.clone());
+        let mergeable = rec.action == SyncAction::Edit && !rec.frozen && rec.errcount == 0;
+        if mergeable {
+            let id = rec.id.clone();
+            if let Some(prev) = last_edit.insert(key, rec) {
+                merged_into.remove(&prev.id);
+                merged_into.insert(id);
+                redundant.push(prev);
+            }
+        } else {
+            last_edit.remove(&key);
+        }
+    }
+    for rec in &redundant {
+        debug!("compact::merge_edits() -- dropping {:?} edit {:?} for {} (superseded)", rec.ty, rec.id, rec.item_id);
+        rec.db_delete(db, None)?;
+    }
+    for id in merged_into {
+        if let Some(id) = id { delta::strip(db, &id)?; }
+    }
+    Ok(redundant.len() as u64)
+}
+
+/// Run a compaction pass over the user db
+pub fn compact(db: &mut Storage, user_id: Option<&String>) -> TResult<CompactResult> {
+    let max_age: u64 = config::get(&["sync", "compact", "max_age"]).unwrap_or(604800);
+    let mut res = CompactResult::default();
+    res.merged = merge_edits(db)?;
+    let sync_id = db.kv_get("sync_id")?.and_then(|x| x.parse::<i64>().ok());
+    if let Some(sync_id) = sync_id {
+        res.ignored = SyncIncoming::prune_ignored(db, sync_id)?;
+    }
+    res.bases = conflict::prune_bases(db)?;
+    if let Some(user_id) = user_id {
+        res.partials = FileSyncIncoming::prune_partials(db, user_id, max_age)?;
+        res.files = gc::run(db, user_id)?.deleted;
+    }
+    info!("compact::compact() -- {:?}", res);
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ::jedi;
+    use ::schema;
+
+    #[test]
+    fn compacts() {
+        let mut db = Storage::new(&String::from(":memory:"), schema::get_schema()).unwrap();
+        let records: Vec<SyncRecord> = jedi::parse(&String::from(r#"[
+            {"id":"01","action":"add","item_id":"a","user_id":1,"type":"note","data":{"id":"a"}},
+            {"id":"02","action":"edit","item_id":"a","user_id":1,"type":"note","data":{"id":"a"}},
+            {"id":"03","action":"edit","item_id":"b","user_id":1,"type":"note","data":{"id":"b"}},
+            {"id":"04","action":"edit","item_id":"a","user_id":1,"type":"note","data":{"id":"a"}},
+            {"id":"05","action":"edit","item_id":"a","user_id":1,"type":"note","data":{"id":"a","body_delta":{"base":"x","delta":"y"}}},
+            {"id":"06","action":"move-space","item_id":"a","user_id":1,"type":"note","data":{"id":"a"}},
+            {"id":"07","action":"edit","item_id":"a","user_id":1,"type":"note","data":{"id":"a","body_delta":{"base":"x","delta":"y"}}},
+            {"id":"08","action":"edit","item_id":"b","user_id":1,"type":"note","data":{"id":"b"},"errcount":1},
+            {"id":"09","action":"edit","item_id":"b","user_id":1,"type":"note","data":{"id":"b"}},
+            {"id":"10","action":"edit","item_id":"a","user_id":1,"type":"board","data":{"id":"a"}}
+        ]"#)).unwrap();
+        for rec in &records { db.save(rec).unwrap(); }
+
+        assert_eq!(merge_edits(&mut db).unwrap(), 2);
+        let ids = SyncRecord::find(&mut db, None).unwrap()
+            .into_iter()
+            .map(|x| x.id.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["01", "03", "05", "06", "07", "08", "09", "10"]);
+        // merged edits lose their delta, lone edits keep theirs
+        let rec: SyncRecord = db.get("sync", &String::from("05")).unwrap().unwrap();
+        assert!(!delta::is_delta(rec.data.as_ref()));
+        let rec: SyncRecord = db.get("sync", &String::from("07")).unwrap().unwrap();
+        assert!(delta::is_delta(rec.data.as_ref()));
+        assert_eq!(merge_edits(&mut db).unwrap(), 0);
+
+        db.kv_set("sync_id", &String::from("100")).unwrap();
+        db.kv_set("sync:incoming:ignore", &String::from(r#"["99","100","101"]"#)).unwrap();
+        db.kv_set("sync:base:a", &String::from("{}")).unwrap();
+        db.kv_set("sync:base:zzz", &String::from("{}")).unwrap();
+        let res = compact(&mut db, None).unwrap();
+        assert_eq!(res.ignored, 2);
+        assert_eq!(res.bases, 1);
+        assert_eq!(db.kv_get("sync:incoming:ignore").unwrap(), Some(String::from(r#"["101"]"#)));
+        assert!(db.kv_get("sync:base:a").unwrap().is_some());
+        assert!(db.kv_get("sync:base:zzz").unwrap().is_none());
+    }
+}
+
@@ -0,0 +1,614 @@
+//! Handles incoming edits that collide with local edits we haven't sent yet.
+//!
+//! Conflicts are caught in the incoming sync thread, which can only see
+//! encrypted data. Server-wins and client-wins get resolved right there (by
+//! dropping either our pending records or the incoming one). Duplicating and
+//! merging need the note's plaintext, so for those the incoming edit is saved
+//! as usual and the conflict gets handed off to the main thread (along with our
+//! local version), which decrypts everything and finishes the job.
+//!
+//! Either way, the UI gets a `sync:conflict` event describing what happened.
+//! We also keep a record of each conflict (with a rundown of both versions) in
+//! the kv store and send it along as `sync:conflict:item`, so the UI can offer
+//! a review screen later on. These stick around until the UI dismisses them
+//! (`sync:conflict:dismiss`).
+//!
+//! To do a real three-way merge we need the version of the note our local
+//! edits started from. Models that opt in via `SyncModel::keeps_base()` stash
+//! their pre-edit data in the kv store on their first unsynced edit.
+
+use ::std::cmp;
+use ::std::collections::{HashSet, BTreeMap};
+use ::jedi::{self, Value};
+use ::config;
+use ::error::{TResult, TError};
+use ::messaging;
+use ::storage::Storage;
+use ::models::model::Model;
+use ::models::protected::{Protected, Keyfinder};
+use ::models::storable::Storable;
+use ::models::note::Note;
+use ::models::checklist;
+use ::models::board::Board;
+use ::models::space::Space;
+use ::models::sync_record::{SyncRecord, SyncType, SyncAction};
+use ::sync::sync_model::{self, SyncModel};
+use ::sync::seal;
+use ::turtl::Turtl;
+use ::util;
+
+/// How we resolve a conflict
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ConflictStrategy {
+    /// The incoming edit replaces ours
+    #[serde(rename = "server-wins")]
+    ServerWins,
+    /// Our edit replaces the incoming one
+    #[serde(rename = "client-wins")]
+    ClientWins,
+    /// The incoming edit wins, and our version is saved as a new note
+    #[serde(rename = "duplicate")]
+    Duplicate,
+    /// The text fields (and checklist items) of both versions get merged
+    /// (notes only)
+    #[serde(rename = "merge")]
+    Merge,
+}
+
+/// Grab our configured conflict strategy (`sync.conflicts.strategy`)
+pub fn strategy() -> ConflictStrategy {
+    config::get(&["sync", "conflicts", "strategy"]).unwrap_or(ConflictStrategy::ClientWins)
+}
+
+/// A conflict the main thread needs to finish up
+#[derive(Debug, Clone)]
+pub struct Conflict {
+    pub item_id: String,
+    pub ty: SyncType,
+    pub strategy: ConflictStrategy,
+    /// Our local (encrypted) version of the item
+    pub local: Option<Value>,
+    /// The incoming (encrypted) version of the item
+    pub remote: Option<Value>,
+    /// The (encrypted) version our local edits started from, if we have it
+    pub base: Option<Value>,
+}
+
+/// What we tell the UI about a conflict
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ConflictEvent {
+    pub item_id: String,
+    #[serde(rename = "type")]
+    pub ty: SyncType,
+    pub strategy: ConflictStrategy,
+    /// The note we saved our local version to (duplicate)
+    pub duplicate_id: Option<String>,
+    /// Whether a merge had overlapping changes that we marked up in the text
+    pub merge_conflicts: bool,
+}
+
+/// A rundown of one side of a conflict
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct VersionInfo {
+    #[serde(rename = "mod")]
+    pub mod_: Option<i64>,
+    pub space_id: Option<String>,
+    pub board_id: Option<String>,
+    /// The (decrypted) title, if we could get at it
+    pub title: Option<String>,
+}
+
+/// A conflict we hang onto until the UI has had a look at it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConflictItem {
+    pub item_id: String,
+    #[serde(rename = "type")]
+    pub ty: SyncType,
+    pub strategy: ConflictStrategy,
+    /// Our version of the item (the one with the unsent edits)
+    pub local: Option<VersionInfo>,
+    /// The version that came in from the server
+    pub remote: Option<VersionInfo>,
+    /// The note we saved our local version to (duplicate)
+    pub duplicate_id: Option<String>,
+    /// Whether a merge had overlapping changes that we marked up in the text
+    pub merge_conflicts: bool,
+    /// When (unix ms) we ran into this
+    pub created: i64,
+}
+
+/// The kv key we keep an item's last conflict under
+fn item_key(item_id: &String) -> String {
+    format!("sync:conflict:{}", item_id)
+}
+
+/// The kv key we keep an item's pre-edit version under
+fn base_key(item_id: &String) -> String {
+    format!("sync:base:{}", item_id)
+}
+
+/// Stash a model's current (pre-edit) db version, unless we already have one
+/// from an earlier unsynced edit.
+pub fn stash_base<T>(db: &Storage, model: &T) -> TResult<()>
+    where T: Protected + Storable
+{
+    let id = model.id_or_else()?;
+    if db.kv_get(&base_key(&id))?.is_some() { return Ok(()); }
+    let existing: Option<T> = db.get(model.table(), &id)?;
+    if let Some(existing) = existing {
+        db.kv_set(&base_key(&id), &jedi::stringify(&existing.data_for_storage()?)?)?;
+    }
+    Ok(())
+}
+
+/// Forget an item's stashed base version
+pub fn clear_base(db: &Storage, item_id: &String) -> TResult<()> {
+    db.kv_delete(&base_key(item_id))
+}
+
+/// Forget the stashed base versions of any items that no longer have local
+/// edits waiting to go out. Returns how many we forgot.
+pub fn prune_bases(db: &mut Storage) -> TResult<u64> {
+    let pending = SyncRecord::find(db, None)?
+        .into_iter()
+        .map(|x| x.item_id)
+        .collect::<HashSet<_>>();
+    let prefix = base_key(&String::new());
+    let mut pruned = 0;
+    for key in db.kv_keys(&prefix)? {
+        if pending.contains(&key[prefix.len()..]) { continue; }
+        db.kv_delete(&key)?;
+        pruned += 1;
+    }
+    Ok(pruned)
+}
+
+/// Check an incoming record against our pending outgoing records. If they
+/// collide, resolve what we can here and return the conflict.
+///
+/// If the returned conflict is client-wins, the incoming record should be
+/// skipped.
+pub fn detect(db: &mut Storage, rec: &SyncRecord, strategy: &ConflictStrategy) -> TResult<Option<Conflict>> {
+    match rec.action {
+        SyncAction::Edit | SyncAction::MoveSpace => {}
+        _ => return Ok(None),
+    }
+    match rec.ty {
+        SyncType::Note | SyncType::Board | SyncType::Space => {}
+        _ => return Ok(None),
+    }
+    let pending = SyncRecord::find(db, Some(rec.ty.clone()))?
+        .into_iter()
+        .filter(|x| x.item_id == rec.item_id && x.action != SyncAction::Delete)
+        .collect::<Vec<_>>();
+    if pending.len() == 0 { return Ok(None); }
+
+    // duplicating/merging only makes sense for notes. for anything else, we
+    // keep the edit the user just made.
+    let strategy = match (strategy, &rec.ty) {
+        (&ConflictStrategy::Duplicate, &SyncType::Note) | (&ConflictStrategy::Merge, &SyncType::Note) => strategy.clone(),
+        (&ConflictStrategy::Duplicate, _) | (&ConflictStrategy::Merge, _) => ConflictStrategy::ClientWins,
+        _ => strategy.clone(),
+    };
+    info!("conflict::detect() -- {:?} {} has {} pending local change(s), resolving via {:?}", rec.ty, rec.item_id, pending.len(), strategy);
+
+    let remote = rec.data.clone();
+    let local = match pending.last() {
+        Some(x) => {
+            let mut last = x.clone()?;
+            seal::unseal(&mut last)?;
+            last.data
+        }
+        None => None,
+    };
+    let base = match db.kv_get(&base_key(&rec.item_id))? {
+        Some(x) => Some(jedi::parse(&x)?),
+        None => None,
+    };
+    if strategy != ConflictStrategy::ClientWins {
+        // the incoming edit gets applied, so our pending edits are done for.
+        // (merge/duplicate will queue up new ones as needed)
+        for sync in &pending {
+            sync.db_delete(db, None)?;
+        }
+        clear_base(db, &rec.item_id)?;
+    }
+    Ok(Some(Conflict {
+        item_id: rec.item_id.clone(),
+        ty: rec.ty.clone(),
+        strategy: strategy,
+        local: local,
+        remote: remote,
+        base: base,
+    }))
+}
+
+/// Decrypt a note from its stored data
+fn open_note(turtl: &Turtl, data: Value) -> TResult<Note> {
+    let mut note: Note = jedi::from_val(data)?;
+    turtl.find_model_key(&mut note)?;
+    note.deserialize()?;
+    Ok(note)
+}
+
+/// Sum up one version of a conflicted item. We try to decrypt the title so the
+/// user can tell what we're talking about, but don't sweat it if we can't.
+fn version_info(turtl: &Turtl, ty: &SyncType, data: &Value) -> VersionInfo {
+    fn title<T>(turtl: &Turtl, data: &Value, get: fn(&T) -> Option<String>) -> Option<String>
+        where T: Protected + Keyfinder
+    {
+        let open = || -> TResult<Option<String>> {
+            let mut model: T = jedi::from_val(data.clone())?;
+            turtl.find_model_key(&mut model)?;
+            model.deserialize()?;
+            Ok(get(&model))
+        };
+        match open() {
+            Ok(x) => x,
+            Err(e) => {
+                debug!("conflict::version_info() -- couldn't open item: {}", e);
+                None
+            }
+        }
+    }
+    let title = match ty {
+        &SyncType::Note => title::<Note>(turtl, data, |x| x.title.clone()),
+        &SyncType::Board => title::<Board>(turtl, data, |x| x.title.clone()),
+        &SyncType::Space => title::<Space>(turtl, data, |x| x.title.clone()),
+        _ => None,
+    };
+    VersionInfo {
+        mod_: jedi::get_opt(&["mod"], data),
+        space_id: jedi::get_opt(&["space_id"], data),
+        board_id: jedi::get_opt(&["board_id"], data),
+        title: title,
+    }
+}
+
+/// Remember a conflict and let the UI know about it
+fn record(turtl: &Turtl, item: &ConflictItem) -> TResult<()> {
+    with_db!{ db, turtl.db, db.kv_set(&item_key(&item.item_id), &jedi::stringify(item)?)? };
+    messaging::ui_event("sync:conflict:item", item)
+}
+
+/// Grab all the conflicts the UI hasn't dismissed yet, oldest first
+pub fn get_all(turtl: &Turtl) -> TResult<Vec<ConflictItem>> {
+    let db_guard = lock!(turtl.db);
+    let db = match db_guard.as_ref() {
+        Some(x) => x,
+        None => return TErr!(TError::MissingField(String::from("Turtl.db"))),
+    };
+    let mut items = Vec::new();
+    for key in db.kv_keys(&item_key(&String::new()))? {
+        match db.kv_get(&key)? {
+            Some(x) => items.push(jedi::parse::<ConflictItem>(&x)?),
+            None => {}
+        }
+    }
+    items.sort_by_key(|x| x.created);
+    Ok(items)
+}
+
+/// Forget a conflict (the user has seen it). Returns false if we didn't have
+/// one for that item.
+pub fn dismiss(turtl: &Turtl, item_id: &String) -> TResult<bool> {
+    let db_guard = lock!(turtl.db);
+    let db = match db_guard.as_ref() {
+        Some(x) => x,
+        None => return TErr!(TError::MissingField(String::from("Turtl.db"))),
+    };
+    let key = item_key(item_id);
+    if db.kv_get(&key)?.is_none() { return Ok(false); }
+    db.kv_delete(&key)?;
+    Ok(true)
+}
+
+/// Finish resolving a conflict (in the main thread) and let the UI know about
+/// it.
+pub fn resolve(turtl: &Turtl, conflict: Conflict) -> TResult<()> {
+    let Conflict { item_id, ty, strategy, local, remote, base } = conflict;
+    let mut item = ConflictItem {
+        item_id: item_id.clone(),
+        ty: ty.clone(),
+        strategy: strategy.clone(),
+        local: local.as_ref().map(|x| version_info(turtl, &ty, x)),
+        remote: remote.as_ref().map(|x| version_info(turtl, &ty, x)),
+        duplicate_id: None,
+        merge_conflicts: false,
+        created: util::now_ms(),
+    };
+    let mut event = ConflictEvent {
+        item_id: item_id.clone(),
+        ty: ty,
+        strategy: strategy.clone(),
+        duplicate_id: None,
+        merge_conflicts: false,
+    };
+    match (&strategy, local) {
+        (&ConflictStrategy::Duplicate, Some(local)) => {
+            let mut dupe = open_note(turtl, local)?;
+            dupe.id = None;
+            dupe.set_key(None);
+            dupe.set_keys(Vec::new());
+            dupe.clear_body();
+            // the file (if any) stays with the original
+            dupe.has_file = false;
+            dupe.file = None;
+            dupe.title = Some(format!("{} (conflicted copy)", dupe.title.clone().unwrap_or(String::new())));
+            sync_model::save_model(SyncAction::Add, turtl, &mut dupe, false)?;
+            event.duplicate_id = dupe.id().map(|x| x.clone());
+        }
+        (&ConflictStrategy::Merge, Some(local)) => {
+            let ours = open_note(turtl, local)?;
+            let base = match base {
+                Some(x) => Some(open_note(turtl, x)?),
+                None => None,
+            };
+            let mut notes = turtl.load_notes(&vec![item_id.clone()])?;
+            if notes.len() == 0 { return Ok(()); }
+            let mut theirs = notes.swap_remove(0);
+            let mut conflicts = false;
+            {
+                let mut merge_field = |base: Option<&String>, ours: Option<&String>, theirs: &mut Option<String>| {
+                    let empty = String::new();
+                    let (merged, had_conflicts) = merge_text(
+                        base.unwrap_or(&empty),
+                        ours.unwrap_or(&empty),
+                        theirs.as_ref().unwrap_or(&empty),
+                    );
+                    conflicts = conflicts || had_conflicts;
+                    *theirs = if merged.len() > 0 { Some(merged) } else { None };
+                };
+                merge_field(base.as_ref().and_then(|x| x.title.as_ref()), ours.title.as_ref(), &mut theirs.title);
+                merge_field(base.as_ref().and_then(|x| x.text.as_ref()), ours.text.as_ref(), &mut theirs.text);
+            }
+            // custom fields merge key by key
+            if ours.fields.is_some() || theirs.fields.is_some() {
+                let empty = BTreeMap::new();
+                let merged = merge_fields(
+                    base.as_ref().and_then(|x| x.fields.as_ref()).unwrap_or(&empty),
+                    ours.fields.as_ref().unwrap_or(&empty),
+                    theirs.fields.as_ref().unwrap_or(&empty),
+                );
+                theirs.fields = if merged.len() > 0 { Some(merged) } else { None };
+            }
+            // checklists merge item by item
+            if ours.items.is_some() || theirs.items.is_some() {
+                let empty = Vec::new();
+                let merged = checklist::merge(
+                    base.as_ref().and_then(|x| x.items.as_ref()).unwrap_or(&empty),
+                    ours.items.as_ref().unwrap_or(&empty),
+                    theirs.items.as_ref().unwrap_or(&empty),
+                );
+                theirs.items = Some(merged);
+            }
+            event.merge_conflicts = conflicts;
+            sync_model::save_model(SyncAction::Edit, turtl, &mut theirs, false)?;
+        }
+        _ => {}
+    }
+    info!("conflict::resolve() -- {:?} {} resolved via {:?}", event.ty, event.item_id, event.strategy);
+    item.duplicate_id = event.duplicate_id.clone();
+    item.merge_conflicts = event.merge_conflicts;
+    record(turtl, &item)?;
+    messaging::ui_event("sync:conflict", &event)
+}
+
+/// Three-way merge of a note's custom fields. Each field takes whichever side
+/// changed (or removed) it, their change winning if both did.
+pub fn merge_fields(base: &BTreeMap<String, Value>, ours: &BTreeMap<String, Value>, theirs: &BTreeMap<String, Value>) -> BTreeMap<String, Value> {
+    let mut merged = BTreeMap::new();
+    let names = base.keys().chain(ours.keys()).chain(theirs.keys()).collect::<HashSet<_>>();
+    for name in names {
+        let val = if theirs.get(name) == base.get(name) { ours.get(name) } else { theirs.get(name) };
+        if let Some(val) = val {
+            merged.insert(name.clone(), val.clone());
+        }
+    }
+    merged
+}
+
+/// A run of base lines (`start..end`) replaced by `lines`
+#[derive(Debug, PartialEq)]
+struct Hunk<'a> {
+    start: usize,
+    end: usize,
+    lines: Vec<&'a str>,
+}
+
+/// Past this many (base lines * other lines), we don't bother diffing
+const MAX_DIFF_CELLS: usize = 4_000_000;
+
+/// Find the hunks that turn `base` into `other` (line-based LCS diff). Returns
+/// None if the texts are too big to diff.
+fn diff<'a>(base: &[&'a str], other: &[&'a str]) -> Option<Vec<Hunk<'a>>> {
+    let (n, m) = (base.len(), other.len());
+    if (n + 1) * (m + 1) > MAX_DIFF_CELLS { return None; }
+    // lcs[i][j] is the LCS length of base[i..] and other[j..]
+    let mut lcs = vec![vec![0u32; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if base[i] == other[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                cmp::max(lcs[i + 1][j], lcs[i][j + 1])
+            };
+        }
+    }
+    let mut hunks = Vec::new();
+    let mut current: Option<Hunk> = None;
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && base[i] == other[j] {
+            if let Some(hunk) = current.take() { hunks.push(hunk); }
+            i += 1;
+            j += 1;
+            continue;
+        }
+        let hunk = current.get_or_insert(Hunk { start: i, end: i, lines: Vec::new() });
+        if j >= m || (i < n && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            i += 1;
+            hunk.end = i;
+        } else {
+            hunk.lines.push(other[j]);
+            j += 1;
+        }
+    }
+    if let Some(hunk) = current.take() { hunks.push(hunk); }
+    Some(hunks)
+}
+
+/// Apply a set of hunks to `base[start..end]`
+fn apply<'a>(base: &[&'a str], start: usize, end: usize, hunks: &[&Hunk<'a>]) -> Vec<&'a str> {
+    let mut out = Vec::new();
+    let mut pos = start;
+    for hunk in hunks {
+        out.extend_from_slice(&base[pos..hunk.start]);
+        out.extend_from_slice(&hunk.lines[..]);
+        pos = hunk.end;
+    }
+    out.extend_from_slice(&base[pos..end]);
+    out
+}
+
+/// Three-way merge two edited versions of a text (by line). Changes to
+/// different parts of the text both make it in. Overlapping changes that
+/// disagree get wrapped in conflict markers. Returns the merged text and
+/// whether or not there were any conflicts.
+pub fn merge_text(base: &str, ours: &str, theirs: &str) -> (String, bool) {
+    if ours == theirs || theirs == base { return (String::from(ours), false); }
+    if ours == base { return (String::from(theirs), false); }
+
+    let base_lines = base.split('\n').collect::<Vec<_>>();
+    let ours_lines = ours.split('\n').collect::<Vec<_>>();
+    let theirs_lines = theirs.split('\n').collect::<Vec<_>>();
+    let conflict = |ours: &[&str], theirs: &[&str]| -> Vec<String> {
+        let mut out = vec![String::from("<<<<<<< local")];
+        out.extend(ours.iter().map(|x| String::from(*x)));
+        out.push(String::from("======="));
+        out.extend(theirs.iter().map(|x| String::from(*x)));
+        out.push(String::from(">>>>>>> server"));
+        out
+    };
+    let (ours_hunks, theirs_hunks) = match (diff(&base_lines, &ours_lines), diff(&base_lines, &theirs_lines)) {
+        (Some(x), Some(y)) => (x, y),
+        _ => return (conflict(&ours_lines, &theirs_lines).join("\n"), true),
+    };
+
+    let mut out: Vec<String> = Vec::new();
+    let mut had_conflicts = false;
+    let mut pos = 0;
+    let (mut a, mut b) = (0, 0);
+    loop {
+        // start a region at whichever hunk comes first
+        let (start, mut end) = match (ours_hunks.get(a), theirs_hunks.get(b)) {
+            (Some(x), Some(y)) => if x.start <= y.start { (x.start, x.end) } else { (y.start, y.end) },
+            (Some(x), None) => (x.start, x.end),
+            (None, Some(y)) => (y.start, y.end),
+            (None, None) => break,
+        };
+        // pull in every hunk (from either side) that overlaps the region
+        let mut ours_region = Vec::new();
+        let mut theirs_region = Vec::new();
+        loop {
+            if let Some(h) = ours_hunks.get(a) {
+                if h.start < end || h.start == start {
+                    end = cmp::max(end, h.end);
+                    ours_region.push(h);
+                    a += 1;
+                    continue;
+                }
+            }
+            if let Some(h) = theirs_hunks.get(b) {
+                if h.start < end || h.start == start {
+                    end = cmp::max(end, h.end);
+                    theirs_region.push(h);
+                    b += 1;
+                    continue;
+                }
+            }
+            break;
+        }
+        out.extend(base_lines[pos..start].iter().map(|x| String::from(*x)));
+        let ours_text = apply(&base_lines, start, end, &ours_region);
+        let theirs_text = apply(&base_lines, start, end, &theirs_region);
+        if theirs_region.len() == 0 || ours_text == theirs_text {
+            out.extend(ours_text.iter().map(|x| String::from(*x)));
+        } else if ours_region.len() == 0 {
+            out.extend(theirs_text.iter().map(|x| String::from(*x)));
+        } else {
+            had_conflicts = true;
+            out.extend(conflict(&ours_text, &theirs_text));
+        }
+        pos = end;
+    }
+    out.extend(base_lines[pos..].iter().map(|x| String::from(*x)));
+    (out.join("\n"), had_conflicts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_text() {
+        let base = "one\ntwo\nthree\nfour";
+        // trivial cases
+        assert_eq!(merge_text(base, base, base), (String::from(base), false));
+        assert_eq!(merge_text(base, "lol", base), (String::from("lol"), false));
+        assert_eq!(merge_text(base, base, "lol"), (String::from("lol"), false));
+
+        // changes in different places both make it in
+        let (merged, conflicts) = merge_text(base, "ONE\ntwo\nthree\nfour", "one\ntwo\nthree\nFOUR\nfive");
+        assert_eq!(merged, "ONE\ntwo\nthree\nFOUR\nfive");
+        assert!(!conflicts);
+
+        // same change on both sides is fine
+        let (merged, conflicts) = merge_text(base, "one\n2\nthree\nfour\nx", "one\n2\nthree\nfour");
+        assert_eq!(merged, "one\n2\nthree\nfour\nx");
+        assert!(!conflicts);
+
+        // overlapping changes get marked up
+        let (merged, conflicts) = merge_text(base, "one\ndos\nthree\nfour", "one\nzwei\nthree\nfour");
+        assert_eq!(merged, "one\n<<<<<<< local\ndos\n=======\nzwei\n>>>>>>> server\nthree\nfour");
+        assert!(conflicts);
+
+        // no base to work from
+        let (merged, conflicts) = merge_text("", "ours", "theirs");
+        assert_eq!(merged, "<<<<<<< local\nours\n=======\ntheirs\n>>>>>>> server");
+        assert!(conflicts);
+    }
+
+    #[test]
+    fn merges_fields() {
+        let fields = |val: Value| -> BTreeMap<String, Value> { jedi::from_val(val).unwrap() };
+        let base = fields(json!({"due": "2018-01-01", "priority": 1, "rating": 3}));
+        let ours = fields(json!({"due": "2018-02-01", "priority": 1, "rating": 3, "owner": "andrew"}));
+        let theirs = fields(json!({"due": "2018-03-01", "priority": 2}));
+        assert_eq!(merge_fields(&base, &ours, &theirs), fields(json!({"due": "2018-03-01", "priority": 2, "owner": "andrew"})));
+    }
+
+    #[test]
+    fn detects_conflicts() {
+        let mut db = Storage::new(&String::from(":memory:"), ::schema::get_schema()).unwrap();
+        let mut local: SyncRecord = jedi::parse(&String::from(r#"{"id":"1","action":"edit","item_id":"n1","user_id":1,"type":"note","data":{"id":"n1","body":"local"}}"#)).unwrap();
+        local.db_save(&mut db, None).unwrap();
+        let incoming: SyncRecord = jedi::parse(&String::from(r#"{"id":"2","action":"edit","item_id":"n1","user_id":1,"type":"note","data":{"id":"n1","body":"remote"}}"#)).unwrap();
+        let other: SyncRecord = jedi::parse(&String::from(r#"{"id":"3","action":"edit","item_id":"n2","user_id":1,"type":"note","data":{"id":"n2"}}"#)).unwrap();
+
+        assert!(detect(&mut db, &other, &ConflictStrategy::ServerWins).unwrap().is_none());
+
+        let conflict = detect(&mut db, &incoming, &ConflictStrategy::ClientWins).unwrap().unwrap();
+        assert_eq!(conflict.strategy, ConflictStrategy::ClientWins);
+        assert_eq!(jedi::get::<String>(&["body"], conflict.local.as_ref().unwrap()).unwrap(), "local");
+        assert_eq!(jedi::get::<String>(&["body"], conflict.remote.as_ref().unwrap()).unwrap(), "remote");
+        // client-wins leaves our edit queued
+        assert_eq!(SyncRecord::find(&mut db, None).unwrap().len(), 1);
+
+        let conflict = detect(&mut db, &incoming, &ConflictStrategy::Merge).unwrap().unwrap();
+        assert_eq!(conflict.strategy, ConflictStrategy::Merge);
+        assert_eq!(SyncRecord::find(&mut db, None).unwrap().len(), 0);
+        assert!(detect(&mut db, &incoming, &ConflictStrategy::Merge).unwrap().is_none());
+    }
+}
+
@@ -0,0 +1,197 @@
+//! Keeps track of whether or not we can reach the API. While we're offline,
+//! the other syncers sit tight instead of hammering away at calls that can't
+//! succeed, and once we're back they pick up right where they left off.
+//!
+//! We find out we're offline/online a few ways:
+//!
+//! - the connectivity syncer pings the API every so often (more often while
+//!   we're offline, see `sync.connectivity`)
+//! - a syncer hitting a network error asks for a check right away
+//! - the host app can tell us when the device's network goes up or down (via
+//!   `sync:set-online`), since it usually knows before we do
+//!
+//! The UI hears about changes via `sync:online`/`sync:offline` events.
+
+use ::std::sync::{Arc, RwLock, Mutex};
+use ::std::time::{Duration, Instant};
+use ::hyper;
+use ::config;
+use ::error::{TResult, TError};
+use ::messaging;
+use ::sync::{SyncConfig, Syncer};
+use ::storage::Storage;
+use ::api::{Api, ApiReq, Method};
+
+/// Whether the sync system thinks we're online
+pub fn is_online(config: &Arc<RwLock<SyncConfig>>) -> bool {
+    let guard = lockr!(config);
+    guard.online
+}
+
+/// Mark us as online/offline, letting the UI know if that's a change. Coming
+/// back online wakes the incoming syncer so it grabs whatever we missed.
+pub fn set_online(config: &Arc<RwLock<SyncConfig>>, online: bool) {
+    let signal = {
+        let mut guard = lockw!(config);
+        if guard.online == online { return; }
+        guard.online = online;
+        guard.push_signal.clone()
+    };
+    info!("connectivity::set_online() -- we are now {}", if online { "online" } else { "offline" });
+    let event = if online { "sync:online" } else { "sync:offline" };
+    messaging::ui_event(event, &())
+        .unwrap_or_else(|e| error!("connectivity::set_online() -- error sending {} event: {}", event, e));
+    if online { signal.notify(); }
+}
+
+/// Ask the connectivity syncer to check the connection on its next run
+pub fn request_check(config: &Arc<RwLock<SyncConfig>>) {
+    let mut guard = lockw!(config);
+    guard.connectivity_check = true;
+}
+
+/// Whether an error means we couldn't reach the server at all (as opposed to
+/// the server telling us no).
+pub fn is_network_error(err: &TError) -> bool {
+    match err {
+        &TError::Wrapped(_, _, _, ref inner) => is_network_error(inner),
+        &TError::Io(_) => true,
+        &TError::Boxed(ref boxed) => {
+            match boxed.downcast_ref::<hyper::Error>() {
+                Some(&hyper::Error::Io(_)) => true,
+                _ => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+/// Pings the API to see if we can reach it
+pub struct SyncConnectivity {
+    /// Holds our sync config. Note that this is shared between the sync system
+    /// and the `Turtl` object in the main thread.
+    config: Arc<RwLock<SyncConfig>>,
+
+    /// Holds our Api object. Lets us chit chat with the Turtl server.
+    api: Arc<Api>,
+
+    /// Stores our syn run version
+    run_version: i64,
+
+    /// When we last pinged the API
+    last_check: Option<Instant>,
+}
+
+impl SyncConnectivity {
+    /// Create a new connectivity syncer
+    pub fn new(config: Arc<RwLock<SyncConfig>>, api: Arc<Api>, _db: Arc<Mutex<Option<Storage>>>) -> Self {
+        SyncConnectivity {
+            config: config,
+            api: api,
+            run_version: 0,
+            last_check: None,
+        }
+    }
+
+    /// Whether it's time to ping the API. Checks that were asked for go out
+    /// right away, otherwise we go by `sync.connectivity.online_interval` or
+    /// `sync.connectivity.offline_interval` (ms).
+    fn check_due(&self) -> bool {
+        let (requested, online) = {
+            let mut guard = lockw!(self.config);
+            let requested = guard.connectivity_check;
+            guard.connectivity_check = false;
+            (requested, guard.online)
+        };
+        let interval: u64 = if online {
+            config::get(&["sync", "connectivity", "online_interval"]).unwrap_or(60000)
+        } else {
+            config::get(&["sync", "connectivity", "offline_interval"]).unwrap_or(10000)
+        };
+        match self.last_check {
+            Some(x) => requested || x.elapsed() >= Duration::from_millis(interval),
+            None => true,
+        }
+    }
+}
+
+impl Syncer for SyncConnectivity {
+    fn get_name(&self) -> &'static str {
+        "connectivity"
+    }
+
+    fn get_config(&self) -> Arc<RwLock<SyncConfig>> {
+        self.config.clone()
+    }
+
+    fn get_delay(&self) -> u64 {
+        1000
+    }
+
+    fn set_run_version(&mut self, run_version: i64) {
+        self.run_version = run_version;
+    }
+
+    fn get_run_version(&self) -> i64 {
+        self.run_version
+    }
+
+    /// We're the ones who figure out if we're online, so we run either way
+    fn needs_network(&self) -> bool {
+        false
+    }
+
+    fn run_sync(&mut self) -> TResult<()> {
+        let skip = {
+            let guard = lockr!(self.config);
+            guard.skip_api_init || guard.dry_run
+        };
+        if skip || !self.check_due() { return Ok(()); }
+        self.last_check = Some(Instant::now());
+
+        let resource: String = config::get(&["sync", "connectivity", "resource"]).unwrap_or(String::from("/"));
+        let online = match self.api.call_raw(Method::Get, &resource[..], ApiReq::new().timeout(10)) {
+            Ok(_) => true,
+            // if the server answered at all (even with an error), we can
+            // reach it
+            Err(e) => {
+                let network = is_network_error(&e);
+                if network { debug!("SyncConnectivity.run_sync() -- heartbeat failed: {}", e); }
+                !network
+            }
+        };
+        set_online(&self.config, online);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::std::io;
+
+    #[test]
+    fn finds_network_errors() {
+        let ioerr = TError::Io(io::Error::new(io::ErrorKind::ConnectionRefused, "nope"));
+        assert!(is_network_error(&ioerr));
+        let wrapped = TError::Wrapped("lol", "file.rs", 69, Arc::new(ioerr));
+        assert!(is_network_error(&wrapped));
+        let hyper_err: TError = From::from(hyper::Error::Io(io::Error::new(io::ErrorKind::TimedOut, "slow")));
+        assert!(is_network_error(&hyper_err));
+        assert!(!is_network_error(&TError::Api(hyper::status::StatusCode::InternalServerError, json!({}))));
+        assert!(!is_network_error(&TError::Msg(String::from("bad"))));
+    }
+
+    #[test]
+    fn goes_offline_and_back() {
+        let config = Arc::new(RwLock::new(SyncConfig::new()));
+        assert!(is_online(&config));
+        set_online(&config, false);
+        assert!(!is_online(&config));
+        request_check(&config);
+        assert!(lockr!(config).connectivity_check);
+        set_online(&config, true);
+        assert!(is_online(&config));
+    }
+}
+
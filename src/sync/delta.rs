@@ -0,0 +1,282 @@
+//! Delta sync for big note bodies. Re-uploading a multi-megabyte note every
+//! time the user fixes a typo is a waste, so for notes over a certain size
+//! (`sync.delta.min_size`) we send the change instead of the whole body.
+//!
+//! The server can't read note bodies, so it can't apply a change itself. What
+//! we send is a splice (replace these bytes with those) of the note's
+//! *plaintext*, encrypted with the note's key. The server checks the delta
+//! against the body it has (via the hash in `body_delta.base`) and passes it
+//! along to other clients, which decrypt their copy, apply the splice, and
+//! re-encrypt it.
+//!
+//! Both sides have to opt in: we advertise that we understand deltas with an
+//! `X-Turtl-Delta` request header, and only send them once the server answers
+//! with the same header. Outgoing records carry both the full body and the
+//! delta, and we pick one right before sending, so if the server turns down a
+//! delta (say, it has a different base) we just retry with the full body.
+
+use ::std::cmp;
+use ::jedi::{self, Value};
+use ::config;
+use ::crypto::{self, Key, CryptoOp};
+use ::error::{TResult, TError};
+use ::storage::Storage;
+use ::models::model::Model;
+use ::models::protected::Protected;
+use ::models::storable::Storable;
+use ::models::note::Note;
+use ::models::sync_record::SyncRecord;
+use ::sync::sync_model::SyncModel;
+use ::sync::seal;
+use ::turtl::Turtl;
+
+/// Replace `remove` bytes at `start` with `insert`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Splice {
+    pub start: usize,
+    pub remove: usize,
+    pub insert: String,
+}
+
+/// What we encrypt and send in place of a note's body
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BodyDelta {
+    /// The hash of the plaintext this applies to
+    pub base: String,
+    /// The hash of the plaintext we get after applying it
+    pub hash: String,
+    pub splice: Splice,
+}
+
+/// Whether or not we do delta sync at all (`sync.delta.enabled`)
+pub fn enabled() -> bool {
+    config::get(&["sync", "delta", "enabled"]).unwrap_or(true)
+}
+
+/// How big (bytes) an encrypted body has to be before we bother with deltas
+fn min_size() -> usize {
+    config::get(&["sync", "delta", "min_size"]).unwrap_or(65536)
+}
+
+/// Find the splice that turns `old` into `new` (everything between their
+/// common prefix and common suffix).
+pub fn splice(old: &str, new: &str) -> Splice {
+    let (a, b) = (old.as_bytes(), new.as_bytes());
+    let max = cmp::min(a.len(), b.len());
+    let mut prefix = 0;
+    while prefix < max && a[prefix] == b[prefix] { prefix += 1; }
+    while prefix > 0 && !(old.is_char_boundary(prefix) && new.is_char_boundary(prefix)) { prefix -= 1; }
+    let mut suffix = 0;
+    while suffix < max - prefix && a[a.len() - 1 - suffix] == b[b.len() - 1 - suffix] { suffix += 1; }
+    while suffix > 0 && !(old.is_char_boundary(a.len() - suffix) && new.is_char_boundary(b.len() - suffix)) { suffix -= 1; }
+    Splice {
+        start: prefix,
+        remove: a.len() - prefix - suffix,
+        insert: String::from(&new[prefix..(b.len() - suffix)]),
+    }
+}
+
+/// Apply a splice to a string
+pub fn apply(old: &str, splice: &Splice) -> TResult<String> {
+    let end = splice.start + splice.remove;
+    if end > old.len() || !old.is_char_boundary(splice.start) || !old.is_char_boundary(end) {
+        return TErr!(TError::BadValue(format!("splice ({}, {}) doesn't fit a string of {} bytes", splice.start, splice.remove, old.len())));
+    }
+    let mut out = String::with_capacity(old.len() - splice.remove + splice.insert.len());
+    out.push_str(&old[..splice.start]);
+    out.push_str(&splice.insert);
+    out.push_str(&old[end..]);
+    Ok(out)
+}
+
+/// Hash some data (hex sha256)
+fn hash(data: &[u8]) -> TResult<String> {
+    Ok(crypto::to_hex(&crypto::sha256(data)?)?)
+}
+
+/// Decrypt a base64 encrypted body (or delta) into a string
+fn open(key: &Key, body: &String) -> TResult<String> {
+    let plain = crypto::decrypt(key, crypto::from_base64(body)?)?;
+    Ok(String::from_utf8(plain)?)
+}
+
+/// Encrypt a string into a base64 body (or delta)
+fn seal(key: &Key, plaintext: &str) -> TResult<String> {
+    let enc = crypto::encrypt(key, Vec::from(plaintext.as_bytes()), CryptoOp::new("chacha20poly1305")?)?;
+    Ok(crypto::to_base64(&enc)?)
+}
+
+/// Build the `body_delta` for a (just serialized) model we're about to save
+/// over its db version. Returns None if the model is too small to bother, if
+/// we can't make sense of the db version, or if the delta wouldn't save us
+/// much.
+pub fn build<T>(db: &Storage, model: &T) -> TResult<Option<Value>>
+    where T: Protected + Storable
+{
+    if !enabled() { return Ok(None); }
+    let (new_body, key) = match (model.get_body(), model.key()) {
+        (Some(x), Some(y)) => (x, y),
+        _ => return Ok(None),
+    };
+    if new_body.len() < min_size() { return Ok(None); }
+    let id = model.id_or_else()?;
+    let existing: Option<T> = db.get(model.table(), &id)?;
+    let old_body = match existing.as_ref().and_then(|x| x.get_body()) {
+        Some(x) => x.clone(),
+        None => return Ok(None),
+    };
+    // if the key changed out from under us, we can't make a delta anyone
+    // else could use
+    let old = match open(key, &old_body) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("delta::build() -- can't open the current body of {} {}, sending full body: {}", model.model_type(), id, e);
+            return Ok(None);
+        }
+    };
+    // saves that don't touch the body keep the one they had (see
+    // `Protected::serialize()`), in which case we send an empty splice
+    let new = if &old_body == new_body { old.clone() } else { open(key, new_body)? };
+    let delta = BodyDelta {
+        base: hash(old.as_bytes())?,
+        hash: hash(new.as_bytes())?,
+        splice: splice(&old, &new),
+    };
+    let sealed = seal(key, &jedi::stringify(&delta)?)?;
+    if sealed.len() * 2 > new_body.len() { return Ok(None); }
+    debug!("delta::build() -- {} {}: sending {} byte delta instead of {} byte body", model.model_type(), id, sealed.len(), new_body.len());
+    Ok(Some(json!({
+        "base": hash(old_body.as_bytes())?,
+        "delta": sealed,
+    })))
+}
+
+/// Whether a record's data carries a delta
+pub fn is_delta(data: Option<&Value>) -> bool {
+    match data {
+        Some(x) => jedi::get_opt::<Value>(&["body_delta"], x).is_some(),
+        None => false,
+    }
+}
+
+/// Get an outgoing record ready to send: if the server takes deltas, send
+/// the delta instead of the body, otherwise drop the delta.
+pub fn prepare(rec: &mut SyncRecord, supported: bool) {
+    if !is_delta(rec.data.as_ref()) { return; }
+    let field = if supported { "body" } else { "body_delta" };
+    if let Some(data) = rec.data.as_mut() {
+        let _ = jedi::remove(&[field], data);
+    }
+}
+
+/// Drop the delta from a stored outgoing record, so next time around it goes
+/// out with its full body.
+pub fn strip(db: &mut Storage, sync_id: &String) -> TResult<()> {
+    let rec: Option<SyncRecord> = db.get("sync", sync_id)?;
+    let mut rec = match rec {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    seal::unseal(&mut rec)?;
+    if !is_delta(rec.data.as_ref()) { return Ok(()); }
+    info!("delta::strip() -- {:?} {}: falling back to full body", rec.ty, rec.item_id);
+    if let Some(data) = rec.data.as_mut() {
+        jedi::remove(&["body_delta"], data)?;
+    }
+    rec.db_save(db, None)
+}
+
+/// Apply an incoming note delta to our local copy of the note (in the main
+/// thread, since we need the note's key). Hands back the sync record with
+/// its data swapped out for the updated note. Records without a delta pass
+/// through untouched.
+pub fn apply_incoming(turtl: &Turtl, mut sync_item: SyncRecord) -> TResult<SyncRecord> {
+    let envelope: Value = match sync_item.data.as_ref().and_then(|x| jedi::get_opt(&["body_delta"], x)) {
+        Some(x) => x,
+        None => return Ok(sync_item),
+    };
+    let sealed: String = jedi::get(&["delta"], &envelope)?;
+    let mut note: Note = {
+        let db_guard = lock!(turtl.db);
+        let db = match db_guard.as_ref() {
+            Some(x) => x,
+            None => return TErr!(TError::MissingField(String::from("Turtl.db"))),
+        };
+        match db.get(Note::tablename(), &sync_item.item_id)? {
+            Some(x) => x,
+            None => return TErr!(TError::MissingData(format!("can't apply delta to note {}: we don't have it", sync_item.item_id))),
+        }
+    };
+    turtl.find_model_key(&mut note)?;
+    let key = note.key_or_else()?;
+    let old = match note.get_body() {
+        Some(x) => open(&key, x)?,
+        None => return TErr!(TError::MissingData(format!("can't apply delta to note {}: it has no body", sync_item.item_id))),
+    };
+    let delta: BodyDelta = jedi::parse(&open(&key, &sealed)?)?;
+    // the server only sends deltas against the version it last sent us, so
+    // this means our copy got out of step somehow
+    if hash(old.as_bytes())? != delta.base {
+        return TErr!(TError::BadValue(format!("delta for note {} doesn't apply to our version of it", sync_item.item_id)));
+    }
+    let new = apply(&old, &delta.splice)?;
+    if hash(new.as_bytes())? != delta.hash {
+        return TErr!(TError::BadValue(format!("delta for note {} produced the wrong body", sync_item.item_id)));
+    }
+
+    // lay the incoming public fields over ours and swap in the new body
+    let mut data = sync_item.data.take().unwrap_or(Value::Null);
+    jedi::remove(&["body_delta"], &mut data)?;
+    note.merge_fields(&data)?;
+    note.set_body(seal(&key, &new)?);
+    with_db!{ db, turtl.db, db.save(&note)? };
+    sync_item.data = Some(note.data_for_storage()?);
+    Ok(sync_item)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splices() {
+        let tests = vec![
+            ("hello there", "hello there"),
+            ("hello there", "hello, there"),
+            ("hello there", "hi there"),
+            ("hello there", "hello"),
+            ("", "get a job"),
+            ("get a job", ""),
+            ("aaaa", "aaaaaa"),
+            ("{\"text\":\"caf\u{e9}\"}", "{\"text\":\"caf\u{e8}\"}"),
+            ("\u{1f422}\u{1f422}", "\u{1f422}\u{1f40d}\u{1f422}"),
+        ];
+        for (old, new) in tests {
+            let splice = splice(old, new);
+            assert_eq!(apply(old, &splice).unwrap(), new);
+        }
+
+        let splice = splice("one two three", "one 2 three");
+        assert_eq!(splice, Splice { start: 4, remove: 3, insert: String::from("2") });
+        // splices that don't line up are rejected
+        assert!(apply("one", &splice).is_err());
+        assert!(apply("\u{1f422}", &Splice { start: 1, remove: 0, insert: String::new() }).is_err());
+    }
+
+    #[test]
+    fn prepares_records() {
+        let mut rec: SyncRecord = jedi::parse(&String::from(r#"{"id":"1","action":"edit","item_id":"n1","user_id":1,"type":"note","data":{"id":"n1","body":"abc","body_delta":{"base":"x","delta":"y"}}}"#)).unwrap();
+        assert!(is_delta(rec.data.as_ref()));
+        let mut rec2 = rec.clone_shallow();
+        rec2.data = rec.data.clone();
+
+        prepare(&mut rec, true);
+        assert!(jedi::get_opt::<String>(&["body"], rec.data.as_ref().unwrap()).is_none());
+        assert!(is_delta(rec.data.as_ref()));
+
+        prepare(&mut rec2, false);
+        assert_eq!(jedi::get::<String>(&["body"], rec2.data.as_ref().unwrap()).unwrap(), "abc");
+        assert!(!is_delta(rec2.data.as_ref()));
+    }
+}
+
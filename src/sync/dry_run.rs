@@ -0,0 +1,151 @@
+//! Dry-run mode. While it's on, the syncers don't talk to the API or write to
+//! the db. Instead, each one works out what it *would* do on its next run and
+//! reports it to the UI via `sync:dry-run` events. Handy for debugging sync
+//! loops, and for showing the user what changes are waiting to go out.
+//!
+//! Note that the incoming syncers can't know what the server has for us
+//! without asking it, so in dry-run mode they just sit idle.
+
+use ::std::collections::HashMap;
+use ::jedi;
+use ::error::TResult;
+use ::messaging;
+use ::models::sync_record::{SyncRecord, SyncType, SyncAction};
+use ::util;
+
+/// Counts for one type (or action) of sync record
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct DryRunCount {
+    pub records: u64,
+    pub bytes: u64,
+}
+
+/// One record a syncer would run
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DryRunItem {
+    pub id: Option<String>,
+    pub item_id: String,
+    #[serde(rename = "type")]
+    pub ty: SyncType,
+    pub action: SyncAction,
+    pub bytes: u64,
+}
+
+/// What a syncer would do on its next run
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DryRunReport {
+    /// The syncer this report is for (outgoing, files:outgoing, etc)
+    pub syncer: String,
+    /// What the syncer would do with these records (send, upload, download)
+    pub operation: String,
+    /// How many records we'd run
+    pub records: u64,
+    /// How many bytes we'd send/receive (as best as we can tell)
+    pub bytes: u64,
+    /// Counts broken down by record type
+    pub types: HashMap<String, DryRunCount>,
+    /// Counts broken down by record action
+    pub actions: HashMap<String, DryRunCount>,
+    /// The records themselves, in the order we'd run them
+    pub items: Vec<DryRunItem>,
+}
+
+impl DryRunReport {
+    /// Start a new (empty) report
+    pub fn new(syncer: &str, operation: &str) -> Self {
+        DryRunReport {
+            syncer: String::from(syncer),
+            operation: String::from(operation),
+            records: 0,
+            bytes: 0,
+            types: HashMap::new(),
+            actions: HashMap::new(),
+            items: Vec::new(),
+        }
+    }
+
+    /// Add a record (and the number of bytes it would move) to the report
+    pub fn add(&mut self, rec: &SyncRecord, bytes: u64) -> TResult<()> {
+        self.records += 1;
+        self.bytes += bytes;
+        {
+            let ty = self.types.entry(util::enum_to_string(&rec.ty)?).or_insert_with(Default::default);
+            ty.records += 1;
+            ty.bytes += bytes;
+        }
+        {
+            let action = self.actions.entry(util::enum_to_string(&rec.action)?).or_insert_with(Default::default);
+            action.records += 1;
+            action.bytes += bytes;
+        }
+        self.items.push(DryRunItem {
+            id: rec.id.clone(),
+            item_id: rec.item_id.clone(),
+            ty: rec.ty.clone(),
+            action: rec.action.clone(),
+            bytes: bytes,
+        });
+        Ok(())
+    }
+
+    /// How many bytes a record takes up when we send it to the API
+    pub fn record_size(rec: &SyncRecord) -> TResult<u64> {
+        Ok(jedi::stringify(rec)?.len() as u64)
+    }
+}
+
+/// Sends a syncer's dry-run reports to the UI, skipping any that are the same
+/// as the last one (syncers run every second or so, and the UI doesn't need to
+/// hear the same thing over and over).
+pub struct DryRunReporter {
+    last: Option<DryRunReport>,
+}
+
+impl DryRunReporter {
+    pub fn new() -> Self {
+        DryRunReporter { last: None }
+    }
+
+    /// Send a report to the UI (if it changed since the last one)
+    pub fn report(&mut self, report: DryRunReport) -> TResult<()> {
+        if self.last.as_ref() == Some(&report) { return Ok(()); }
+        messaging::ui_event("sync:dry-run", &report)?;
+        self.last = Some(report);
+        Ok(())
+    }
+
+    /// Forget our last report, so the next one always gets sent
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_reports() {
+        let records: Vec<SyncRecord> = jedi::parse(&String::from(r#"[
+            {"id":"1","action":"add","item_id":"a","user_id":1,"type":"note","data":{"id":"a","body":"abc"}},
+            {"id":"2","action":"edit","item_id":"a","user_id":1,"type":"note","data":{"id":"a","body":"abcd"}},
+            {"id":"3","action":"delete","item_id":"b","user_id":1,"type":"board","data":{"id":"b"}}
+        ]"#)).unwrap();
+        let mut report = DryRunReport::new("outgoing", "send");
+        let mut total = 0;
+        for rec in &records {
+            let size = DryRunReport::record_size(rec).unwrap();
+            assert!(size > 0);
+            total += size;
+            report.add(rec, size).unwrap();
+        }
+        assert_eq!(report.records, 3);
+        assert_eq!(report.bytes, total);
+        assert_eq!(report.types.get("note").unwrap().records, 2);
+        assert_eq!(report.types.get("board").unwrap().records, 1);
+        assert_eq!(report.actions.get("delete").unwrap().records, 1);
+        assert_eq!(report.items[1].id, Some(String::from("2")));
+        assert_eq!(report.items[2].action, SyncAction::Delete);
+    }
+}
+
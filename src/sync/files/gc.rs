@@ -0,0 +1,112 @@
+//! Cleans up attachment files nobody is ever going to ask for again. If a note
+//! gets deleted while its file is still waiting to upload (or download) the
+//! file stays behind in the files folder forever, so every so often (as part of
+//! compaction, see `sync::compact`) we go through the folder and delete:
+//!
+//! - our files whose note is gone
+//! - files for our notes that were saved under some other user
+//!
+//! Files belonging to other users whose notes we don't have are left alone,
+//! since they're most likely from another account on this device. We also skip
+//! anything that's been touched recently (`sync.files.gc_grace` seconds) or
+//! that has sync records pending, so we never pull a file out from under a
+//! save in progress.
+//!
+//! The UI gets a `sync:files:gc` event with what we cleaned up.
+
+use ::std::collections::HashSet;
+use ::std::fs;
+use ::std::path::PathBuf;
+use ::config;
+use ::error::TResult;
+use ::messaging;
+use ::storage::Storage;
+use ::models::file::FileData;
+use ::models::note::Note;
+use ::models::storable::Storable;
+use ::models::sync_record::SyncRecord;
+
+/// What a garbage collection run did
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct GcResult {
+    /// How many files we looked at
+    pub scanned: u64,
+    /// How many files we deleted
+    pub deleted: u64,
+    /// How many bytes that freed up
+    pub bytes: u64,
+}
+
+/// Pull the user id and note id out of a file's name (`u_<user>.n_<note>.enc`)
+fn parse_filename(name: &str) -> Option<(String, String)> {
+    if !name.starts_with("u_") || !name.ends_with(".enc") { return None; }
+    let inner = &name[2..(name.len() - 4)];
+    let split = match inner.find(".n_") {
+        Some(x) => x,
+        None => return None,
+    };
+    let (user_id, note_id) = (&inner[..split], &inner[(split + 3)..]);
+    if user_id.len() == 0 || note_id.len() == 0 { return None; }
+    Some((String::from(user_id), String::from(note_id)))
+}
+
+/// Whether a file was modified in the last `grace` seconds (or we can't tell)
+fn recent(path: &PathBuf, grace: u64) -> bool {
+    match fs::metadata(path).and_then(|x| x.modified()) {
+        Ok(modified) => modified.elapsed().map(|x| x.as_secs() < grace).unwrap_or(true),
+        Err(_) => true,
+    }
+}
+
+/// Find and delete orphaned attachment files for the given user
+pub fn collect(db: &mut Storage, user_id: &String) -> TResult<GcResult> {
+    let grace: u64 = config::get(&["sync", "files", "gc_grace"]).unwrap_or(3600);
+    let pending = SyncRecord::find(db, None)?
+        .into_iter()
+        .map(|x| x.item_id)
+        .collect::<HashSet<_>>();
+    let mut res = GcResult::default();
+    for path in FileData::file_finder_all(None, None)? {
+        let (file_user, note_id) = match path.file_name().and_then(|x| x.to_str()).and_then(parse_filename) {
+            Some(x) => x,
+            None => continue,
+        };
+        res.scanned += 1;
+        if pending.contains(&note_id) || recent(&path, grace) { continue; }
+        let note: Option<Note> = db.get(Note::tablename(), &note_id)?;
+        let orphaned = match note {
+            Some(_) => &file_user != user_id,
+            None => &file_user == user_id,
+        };
+        if !orphaned { continue; }
+        let size = fs::metadata(&path).map(|x| x.len()).unwrap_or(0);
+        info!("gc::collect() -- removing orphaned file {:?}", path);
+        fs::remove_file(&path)?;
+        res.deleted += 1;
+        res.bytes += size;
+    }
+    Ok(res)
+}
+
+/// Run a garbage collection pass and let the UI know how it went
+pub fn run(db: &mut Storage, user_id: &String) -> TResult<GcResult> {
+    let res = collect(db, user_id)?;
+    info!("gc::run() -- {:?}", res);
+    messaging::ui_event("sync:files:gc", &res)?;
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_filenames() {
+        assert_eq!(parse_filename("u_51.n_015caf78be50.enc"), Some((String::from("51"), String::from("015caf78be50"))));
+        assert_eq!(parse_filename("u_51.n_015caf78be50.enc.part"), None);
+        assert_eq!(parse_filename("u_.n_015caf78be50.enc"), None);
+        assert_eq!(parse_filename("u_51.n_.enc"), None);
+        assert_eq!(parse_filename("notes.sqlite"), None);
+    }
+}
+
@@ -1,20 +1,60 @@
 use ::std::sync::{Arc, RwLock, Mutex};
 use ::sync::{SyncConfig, Syncer};
+use ::sync::files;
+use ::sync::dry_run::{DryRunReport, DryRunReporter};
+use ::sync::throttle::Throttle;
+use ::sync::seal;
 use ::sync::sync_model::SyncModel;
 use ::storage::Storage;
-use ::api::{Api, ApiReq, Method, Headers};
+use ::api::{self, Api, ApiReq, Method, Headers};
 use ::messaging;
 use ::error::{TResult, TError};
 use ::models::sync_record::{SyncType, SyncRecord};
 use ::models::file::FileData;
+use ::models::note::Note;
+use ::models::storable::Storable;
 use ::hyper;
 use ::std::time::Duration;
 use ::std::fs;
 use ::std::io::{Read, Write};
+use ::std::path::PathBuf;
 use ::jedi::{self, Value};
 use ::util;
 use ::config;
 
+/// What we remember about a partially-downloaded file so we can pick up where
+/// we left off
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct PartialDownload {
+    /// The ETag the server gave us for the file, so we only resume if the file
+    /// hasn't changed out from under us
+    etag: Option<String>,
+    /// The full size of the file (if the server told us)
+    size: Option<u64>,
+}
+
+/// Where we store a file while it's downloading
+fn partial_path(file: &PathBuf) -> PathBuf {
+    let mut name = file.clone().into_os_string();
+    name.push(".part");
+    PathBuf::from(name)
+}
+
+/// Parse a `Content-Range: bytes <start>-<end>/<total>` header into its start
+/// and total (if known)
+fn parse_content_range(val: &str) -> Option<(u64, Option<u64>)> {
+    let val = val.trim();
+    if !val.starts_with("bytes ") { return None; }
+    let mut parts = val[6..].splitn(2, '/');
+    let range = parts.next()?;
+    let total = match parts.next()?.trim() {
+        "*" => None,
+        x => Some(x.parse::<u64>().ok()?),
+    };
+    let start = range.splitn(2, '-').next()?.trim().parse::<u64>().ok()?;
+    Some((start, total))
+}
+
 /// Holds the state for incoming files (download)
 pub struct FileSyncIncoming {
     /// Holds our sync config. Note that this is shared between the sync system
@@ -30,6 +70,9 @@ pub struct FileSyncIncoming {
 
     /// Stores our syn run version
     run_version: i64,
+
+    /// Sends our dry-run reports (if we're in dry-run mode)
+    dry_runs: DryRunReporter,
 }
 
 impl FileSyncIncoming {
@@ -40,29 +83,103 @@ impl FileSyncIncoming {
             api: api,
             db: db,
             run_version: 0,
+            dry_runs: DryRunReporter::new(),
         }
     }
 
+    /// The k/v key we store partial download info under
+    fn partial_key(note_id: &String) -> String {
+        format!("sync:files:partial:{}", note_id)
+    }
+
+    /// Grab what we know about a partial download for a note
+    fn get_partial(&self, note_id: &String) -> TResult<PartialDownload> {
+        let val = with_db!{ db, self.db, db.kv_get(&FileSyncIncoming::partial_key(note_id)) }?;
+        match val {
+            Some(x) => Ok(jedi::parse(&x)?),
+            None => Ok(Default::default()),
+        }
+    }
+
+    /// Save what we know about a partial download
+    fn set_partial(&self, note_id: &String, partial: &PartialDownload) -> TResult<()> {
+        let val = jedi::stringify(partial)?;
+        with_db!{ db, self.db, db.kv_set(&FileSyncIncoming::partial_key(note_id), &val) }
+    }
+
+    /// Throw out a partial download
+    fn clear_partial(&self, note_id: &String, part: &PathBuf) -> TResult<()> {
+        if part.exists() { fs::remove_file(part)?; }
+        with_db!{ db, self.db, db.kv_delete(&FileSyncIncoming::partial_key(note_id)) }
+    }
+
+    /// Throw out partial downloads we're never going to finish: ones whose
+    /// download record is gone, or that nobody has touched in `max_age`
+    /// seconds. Returns how many we threw out.
+    pub fn prune_partials(db: &mut Storage, user_id: &String, max_age: u64) -> TResult<u64> {
+        let pending = SyncRecord::find(db, Some(SyncType::FileIncoming))?
+            .into_iter()
+            .map(|x| x.item_id)
+            .collect::<Vec<_>>();
+        let prefix = FileSyncIncoming::partial_key(&String::new());
+        let mut pruned = 0;
+        for key in db.kv_keys(&prefix)? {
+            let note_id = String::from(&key[prefix.len()..]);
+            let part = partial_path(&FileData::new_file(user_id, &note_id)?);
+            let stale = match fs::metadata(&part).and_then(|x| x.modified()) {
+                Ok(modified) => modified.elapsed().map(|x| x.as_secs() > max_age).unwrap_or(false),
+                // no file to resume, so the record is useless
+                Err(_) => true,
+            };
+            if pending.contains(&note_id) && !stale { continue; }
+            info!("FileSyncIncoming::prune_partials() -- removing partial download for {}", note_id);
+            if part.exists() { fs::remove_file(&part)?; }
+            db.kv_delete(&key)?;
+            pruned += 1;
+        }
+        Ok(pruned)
+    }
+
     /// Returns a list of note_ids for notes that have pending file downloads.
     /// This uses the `sync` table.
     fn get_incoming_file_syncs(&self) -> TResult<Vec<SyncRecord>> {
         let syncs = with_db!{ db, self.db,
             SyncRecord::find(db, Some(SyncType::FileIncoming))
         }?;
+        let space_filter = {
+            let guard = lockr!(self.config);
+            guard.space_filter.clone()
+        };
         let mut final_syncs = Vec::with_capacity(syncs.len());
         for sync in syncs {
             // NOTE: in the normal sync process, we break on frozen. here, we
             // continue. the reason being that file syncs don't necessarily
             // benefit from being run in order like normal outgoing syncs do.
-            if sync.frozen { continue; }
+            if sync.frozen || sync.waiting() { continue; }
+            // don't download files for notes in spaces we don't keep (if we
+            // don't have the note, it was either filtered out or deleted)
+            if !space_filter.is_all() {
+                let keep = with_db!{ db, self.db,
+                    let note: Option<Note> = db.get(Note::tablename(), &sync.item_id)?;
+                    match note {
+                        Some(x) => space_filter.allows(&x.space_id),
+                        None => false,
+                    }
+                };
+                if !keep {
+                    info!("FileSyncIncoming.get_incoming_file_syncs() -- skipping file for filtered note {}", sync.item_id);
+                    with_db!{ db, self.db, sync.db_delete(db, None)? };
+                    continue;
+                }
+            }
             final_syncs.push(sync);
         }
-        Ok(final_syncs)
+        seal::unseal_all(final_syncs)
     }
 
     /// Given a sync record for an outgoing file, find the corresponding file
     /// in our storage folder and stream it to our heroic API.
-    fn download_file(&mut self, sync: &SyncRecord) -> TResult<()> {
+    fn download_file(&self, sync: &SyncRecord) -> TResult<()> {
         let note_id = &sync.item_id;
         let user_id = {
             let local_config = self.get_config();
@@ -74,19 +191,30 @@ impl FileSyncIncoming {
         };
         info!("FileSyncIncoming.download_file() -- syncing file for {}", note_id);
 
+        // the hash of the file as it was uploaded (older records won't have
+        // one)
+        let expected_hash: Option<String> = sync.data.as_ref()
+            .and_then(|x| jedi::get_opt(&["hash"], x));
+
         // define a container function that grabs our file and runs the download.
-        // if anything in here fails, we mark 
+        // if anything in here fails, we mark the sync as failed, but leave any
+        // partial download sitting around so the next attempt can resume it.
         let download = |note_id, user_id| -> TResult<()> {
-            // generate the filename we'll save to, and open the file (we should
-            // test if the file can be created before we run off blasting API
-            // calls in every direction)
+            // generate the filename we'll save to, and make sure its folder
+            // exists (we should test if the file can be created before we run
+            // off blasting API calls in every direction)
             let file = FileData::new_file(user_id, note_id)?;
             let parent = match file.parent() {
                 Some(path) => path.clone(),
                 None => return TErr!(TError::BadValue(format!("bad file path: {:?}", file))),
             };
             util::create_dir(parent)?;
-            let mut file = fs::File::create(&file)?;
+            let part = partial_path(&file);
+            let partial = self.get_partial(note_id)?;
+            let have = match fs::metadata(&part) {
+                Ok(meta) => meta.len(),
+                Err(_) => 0,
+            };
 
             // start our API call to the note file attachment endpoint
             let url = format!("/notes/{}/attachment", note_id);
@@ -99,6 +227,16 @@ impl FileSyncIncoming {
             if file_url.contains(turtl_api_url.as_str()) {
                 self.api.set_auth_headers(&mut headers);
             }
+            // if we have part of the file already, only ask for the rest. if
+            // we know the file's etag, the server will send the whole file
+            // back if it's changed since we started.
+            if have > 0 {
+                info!("FileSyncIncoming.download_file() -- resuming download for {} at {} bytes", note_id, have);
+                headers.set_raw("Range", vec![Vec::from(format!("bytes={}-", have).as_bytes())]);
+                if let Some(etag) = partial.etag.as_ref() {
+                    headers.set_raw("If-Range", vec![Vec::from(etag.as_bytes())]);
+                }
+            }
             let mut client = hyper::Client::new();
             client.set_read_timeout(Some(Duration::new(30, 0)));
             let mut res = client
@@ -106,6 +244,12 @@ impl FileSyncIncoming {
                 .headers(headers)
                 .send()?;
             let status = res.status_raw().0;
+            if status == 416 {
+                // we asked for a range the server doesn't have. our partial
+                // file is junk, so start over next time.
+                self.clear_partial(note_id, &part)?;
+                return TErr!(TError::BadValue(format!("server rejected our resume range ({} bytes), restarting download", have)));
+            }
             if status >= 400 {
                 let mut errstr = String::new();
                 res.read_to_string(&mut errstr)?;
@@ -115,18 +259,75 @@ impl FileSyncIncoming {
                 };
                 return TErr!(TError::Api(res.status.clone(), val));
             }
+
+            // figure out if we're appending to what we have or starting over,
+            // and how big the finished file should be
+            let etag = api::raw_header(&res.headers, "ETag");
+            let (resuming, size) = if status == 206 {
+                let range = api::raw_header(&res.headers, "Content-Range")
+                    .and_then(|x| parse_content_range(x.as_str()));
+                match range {
+                    Some((start, total)) if start == have => (true, total),
+                    _ => {
+                        self.clear_partial(note_id, &part)?;
+                        return TErr!(TError::BadValue(format!("server sent a range that doesn't match our partial download ({} bytes), restarting download", have)));
+                    }
+                }
+            } else {
+                let len = res.headers.get::<hyper::header::ContentLength>().map(|x| x.0);
+                (false, len)
+            };
+            self.set_partial(note_id, &PartialDownload { etag: etag, size: size })?;
+            let mut file_out = if resuming {
+                fs::OpenOptions::new().append(true).open(&part)?
+            } else {
+                fs::File::create(&part)?
+            };
+
+            let limit = {
+                let local_config = self.get_config();
+                let guard = lockr!(local_config);
+                guard.download_limit
+            };
+            let mut throttle = Throttle::new(limit);
             // start streaming our API call into the file 4K at a time
             let mut buf = [0; 4096];
+            let mut received: u64 = 0;
             loop {
                 let read = res.read(&mut buf[..])?;
                 // all done! (EOF)
                 if read <= 0 { break; }
                 let (read_bytes, _) = buf.split_at(read);
-                let written = file.write(read_bytes)?;
+                let written = file_out.write(read_bytes)?;
                 if read != written {
                     return TErr!(TError::Msg(format!("problem downloading file: downloaded {} bytes, only saved {} wtf wtf lol", read, written)));
                 }
+                received += written as u64;
+                let wait = throttle.consume(written as u64);
+                if wait > 0 { self.sleep(wait); }
+            }
+            file_out.flush()?;
+            self.stats(|x| x.down(0, received));
+            drop(file_out);
+
+            // make sure we got the whole thing before moving it into place
+            let got = fs::metadata(&part)?.len();
+            if let Some(size) = size {
+                if got != size {
+                    return TErr!(TError::Msg(format!("incomplete download: got {} of {} bytes (will resume)", got, size)));
+                }
+            }
+            // and that what we got is what was uploaded. if not, there's no
+            // point in resuming, so start over next time.
+            if let Some(hash) = expected_hash.as_ref() {
+                if let Err(e) = FileData::verify_file(&part, hash) {
+                    error!("FileSyncIncoming.download_file() -- {}", e);
+                    self.clear_partial(note_id, &part)?;
+                    return Err(e);
+                }
             }
+            fs::rename(&part, &file)?;
+            with_db!{ db, self.db, db.kv_delete(&FileSyncIncoming::partial_key(note_id)) }?;
             Ok(())
         };
 
@@ -144,10 +345,14 @@ impl FileSyncIncoming {
         // if we're still here, the download succeeded. remove the sync record so
         // we know to stop trying to download this file.
         with_db!{ db, self.db, sync.db_delete(db, None)? };
+        self.stats(|x| x.down(1, 0));
 
         // let the UI know how great we are. you will love this app. tremendous
         // app. everyone says so.
         messaging::ui_event("sync:file:downloaded", &json!({"note_id": note_id}))?;
+        // and let the dispatch thread know, so it can make a thumbnail (and
+        // pull the text out of documents)
+        messaging::app_event("sync:file:downloaded", &json!({"note_id": note_id}))?;
         Ok(())
     }
 }
@@ -174,14 +379,46 @@ impl Syncer for FileSyncIncoming {
     }
 
     fn run_sync(&mut self) -> TResult<()> {
-        let syncs = self.get_incoming_file_syncs()?;
-        for sync in &syncs {
-            self.download_file(sync)?;
-            // if we've been disabled, return
-            if !self.is_enabled() { return Ok(()); }
+        if self.dry_run() {
+            // skip get_incoming_file_syncs() here, since it cleans up records
+            // for filtered notes
+            let syncs = with_db!{ db, self.db,
+                SyncRecord::find(db, Some(SyncType::FileIncoming))
+            }?;
+            let mut report = DryRunReport::new(self.get_name(), "download");
+            for sync in syncs.iter().filter(|x| !x.frozen) {
+                // go by the size the note says its file is
+                let bytes = with_db!{ db, self.db,
+                    let note: Option<Note> = db.get(Note::tablename(), &sync.item_id)?;
+                    note.and_then(|x| x.file).and_then(|x| x.size).unwrap_or(0)
+                };
+                report.add(sync, bytes)?;
+            }
+            return self.dry_runs.report(report);
         }
-        Ok(())
+        self.dry_runs.reset();
+        let syncs = self.get_incoming_file_syncs()?;
+        if syncs.len() == 0 { return Ok(()); }
+        let slots = {
+            let guard = lockr!(self.config);
+            guard.file_transfers.clone()
+        };
+        // if we've been disabled, stop picking up new downloads
+        files::run_parallel(syncs, files::workers(), &slots, || self.is_enabled(), |sync| self.download_file(sync))
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_content_range() {
+        assert_eq!(parse_content_range("bytes 100-199/200"), Some((100, Some(200))));
+        assert_eq!(parse_content_range("bytes 0-49/*"), Some((0, None)));
+        assert_eq!(parse_content_range("bytes */200"), None);
+        assert_eq!(parse_content_range("items 0-5/6"), None);
+        assert_eq!(partial_path(&PathBuf::from("/tmp/u_1.n_2.enc")), PathBuf::from("/tmp/u_1.n_2.enc.part"));
+    }
+}
 
@@ -1,2 +1,201 @@
+//! File syncing. Uploads and downloads run on a small pool of workers so a few
+//! attachments can move at once, capped globally (across both directions) by
+//! the `TransferSlots` in our `SyncConfig`.
+
 pub mod outgoing;
 pub mod incoming;
+pub mod gc;
+
+use ::std::sync::{Arc, Mutex, Condvar};
+use ::std::collections::VecDeque;
+use ::crossbeam;
+use ::error::TResult;
+use ::models::sync_record::SyncRecord;
+use ::config;
+
+/// Limits how many file transfers can run at once, globally.
+pub struct TransferSlots {
+    /// The most transfers we allow at once
+    max: usize,
+    /// How many transfers are running right now
+    used: Mutex<usize>,
+    /// Lets waiting workers know a slot opened up
+    cond: Condvar,
+}
+
+/// A held transfer slot. Gives the slot back when dropped.
+pub struct TransferSlot {
+    slots: Arc<TransferSlots>,
+}
+
+impl TransferSlots {
+    /// Create a new set of slots
+    pub fn new(max: usize) -> Self {
+        TransferSlots {
+            max: if max < 1 { 1 } else { max },
+            used: Mutex::new(0),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// Create a new set of slots, sized via `sync.files.max_concurrent`
+    pub fn from_config() -> Self {
+        TransferSlots::new(config::get(&["sync", "files", "max_concurrent"]).unwrap_or(3))
+    }
+
+    /// Wait for a slot to open up and grab it
+    pub fn acquire(slots: &Arc<TransferSlots>) -> TransferSlot {
+        let mut guard = lock!(slots.used);
+        while *guard >= slots.max {
+            guard = match slots.cond.wait(guard) {
+                Ok(x) => x,
+                Err(e) => e.into_inner(),
+            };
+        }
+        *guard += 1;
+        TransferSlot { slots: slots.clone() }
+    }
+
+    /// How many transfers are running right now
+    pub fn active(&self) -> usize {
+        *lock!(self.used)
+    }
+}
+
+impl Drop for TransferSlot {
+    fn drop(&mut self) {
+        let mut guard = lock!(self.slots.used);
+        *guard -= 1;
+        self.slots.cond.notify_one();
+    }
+}
+
+/// How many workers each file syncer runs (`sync.files.workers`)
+pub fn workers() -> usize {
+    let workers: usize = config::get(&["sync", "files", "workers"]).unwrap_or(2);
+    if workers < 1 { 1 } else { workers }
+}
+
+/// Group sync records by note, keeping both the order of the notes and the
+/// order of the records within each note.
+fn lanes(records: Vec<SyncRecord>) -> VecDeque<Vec<SyncRecord>> {
+    let mut lanes: VecDeque<Vec<SyncRecord>> = VecDeque::new();
+    for rec in records {
+        let idx = lanes.iter().position(|x| x[0].item_id == rec.item_id);
+        match idx {
+            Some(i) => lanes[i].push(rec),
+            None => lanes.push_back(vec![rec]),
+        }
+    }
+    lanes
+}
+
+/// Run `work` for each of the given records on up to `workers` threads. All the
+/// records for a given note run on the same worker, in order, and if one of
+/// them fails the rest of that note's records wait for the next run. Each call
+/// to `work` holds a transfer slot.
+///
+/// `keep_going` is checked before each record, so we can stop early if sync
+/// gets disabled. Returns the first error we hit, after everything finishes.
+pub fn run_parallel<F, K>(records: Vec<SyncRecord>, workers: usize, slots: &Arc<TransferSlots>, keep_going: K, work: F) -> TResult<()>
+    where F: Fn(&mut SyncRecord) -> TResult<()> + Sync,
+          K: Fn() -> bool + Sync
+{
+    let queue = Mutex::new(lanes(records));
+    let first_err: Mutex<Option<::error::TError>> = Mutex::new(None);
+    let workers = {
+        let num_lanes = lock!(queue).len();
+        if workers > num_lanes { num_lanes } else { workers }
+    };
+    crossbeam::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                loop {
+                    let lane = match lock!(queue).pop_front() {
+                        Some(x) => x,
+                        None => break,
+                    };
+                    for mut rec in lane {
+                        if !keep_going() { return; }
+                        let res = {
+                            let _slot = TransferSlots::acquire(slots);
+                            work(&mut rec)
+                        };
+                        if let Err(e) = res {
+                            let mut guard = lock!(first_err);
+                            if guard.is_none() { *guard = Some(e); }
+                            break;
+                        }
+                    }
+                }
+            });
+        }
+    });
+    let err = match first_err.into_inner() {
+        Ok(x) => x,
+        Err(e) => e.into_inner(),
+    };
+    match err {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ::std::sync::RwLock;
+    use ::jedi;
+    use ::util;
+    use ::error::TError;
+
+    fn record(id: &str, note_id: &str) -> SyncRecord {
+        jedi::parse(&format!(r#"{{"id":"{}","action":"add","item_id":"{}","user_id":1,"type":"file:outgoing"}}"#, id, note_id)).unwrap()
+    }
+
+    #[test]
+    fn runs_in_parallel_per_note() {
+        let records = vec![
+            record("1", "a"),
+            record("2", "b"),
+            record("3", "a"),
+            record("4", "c"),
+            record("5", "b"),
+        ];
+        let slots = Arc::new(TransferSlots::new(2));
+        let seen: RwLock<Vec<String>> = RwLock::new(Vec::new());
+        let peak: Mutex<usize> = Mutex::new(0);
+        run_parallel(records, 3, &slots, || true, |rec| {
+            {
+                let mut guard = lock!(peak);
+                let active = slots.active();
+                if active > *guard { *guard = active; }
+            }
+            util::sleep(20);
+            lockw!(seen).push(rec.id.clone().unwrap());
+            Ok(())
+        }).unwrap();
+        let seen = seen.into_inner().unwrap();
+        assert_eq!(seen.len(), 5);
+        let pos = |id: &str| seen.iter().position(|x| x == id).unwrap();
+        assert!(pos("1") < pos("3"));
+        assert!(pos("2") < pos("5"));
+        assert!(*lock!(peak) <= 2);
+        assert_eq!(slots.active(), 0);
+
+        // a failure stops that note, but not the others
+        let records = vec![record("1", "a"), record("2", "a"), record("3", "b")];
+        let seen: RwLock<Vec<String>> = RwLock::new(Vec::new());
+        let res = run_parallel(records, 2, &slots, || true, |rec| {
+            let id = rec.id.clone().unwrap();
+            lockw!(seen).push(id.clone());
+            if id == "1" { TErr!(TError::Msg(String::from("nope"))) } else { Ok(()) }
+        });
+        assert!(res.is_err());
+        let mut seen = seen.into_inner().unwrap();
+        seen.sort();
+        assert_eq!(seen, vec!["1", "3"]);
+    }
+}
+
@@ -1,5 +1,10 @@
 use ::std::sync::{Arc, RwLock, Mutex};
 use ::sync::{SyncConfig, Syncer};
+use ::sync::files;
+use ::sync::dry_run::{DryRunReport, DryRunReporter};
+use ::sync::throttle::Throttle;
+use ::sync::seal;
+use ::sync::hooks;
 use ::sync::sync_model::SyncModel;
 use ::sync::incoming::SyncIncoming;
 use ::storage::Storage;
@@ -10,6 +15,21 @@ use ::models::file::FileData;
 use ::models::sync_record::{SyncType, SyncRecord};
 use ::std::fs;
 use ::std::io::{Read, Write};
+use ::jedi;
+
+/// What the API tells us after an upload
+#[derive(Deserialize, Debug)]
+struct UploadRes {
+    #[serde(default)]
+    #[serde(deserialize_with = "::util::ser::opt_vec_str_i64_converter::deserialize")]
+    sync_ids: Option<Vec<i64>>,
+    /// The hash of the file as the server received it, if it tells us
+    #[serde(default)]
+    hash: Option<String>,
+    /// Whether we linked a file the server already had instead of uploading
+    #[serde(skip_deserializing)]
+    deduped: bool,
+}
 
 /// Holds the state for outgoing files (uploads)
 pub struct FileSyncOutgoing {
@@ -26,6 +46,9 @@ pub struct FileSyncOutgoing {
 
     /// Stores our syn run version
     run_version: i64,
+
+    /// Sends our dry-run reports (if we're in dry-run mode)
+    dry_runs: DryRunReporter,
 }
 
 impl FileSyncOutgoing {
@@ -36,38 +59,53 @@ impl FileSyncOutgoing {
             api: api,
             db: db,
             run_version: 0,
+            dry_runs: DryRunReporter::new(),
         }
     }
 
-    /// Looks at the first entry in the sync table for an outgoing file sync
-    /// record. We could scan the whole table, but since syncs are in order and
-    /// we really don't want to start uploading a file for a note that hasn't
-    /// finished syncing, it only makes sense to check the front of the table
-    /// for the sync record.
-    fn get_next_outgoing_file_sync(&self) -> TResult<Option<SyncRecord>> {
-        let next = with_db!{ db, self.db,
-            SyncRecord::next(db)
+    /// Grabs the outgoing file sync records at the front of the sync table.
+    /// We could scan the whole table, but since syncs are in order and we
+    /// really don't want to start uploading a file for a note that hasn't
+    /// finished syncing, it only makes sense to take the file records up until
+    /// the first non-file (or frozen) record.
+    fn get_outgoing_file_syncs(&self) -> TResult<Vec<SyncRecord>> {
+        let syncs = with_db!{ db, self.db,
+            SyncRecord::find(db, None)
         }?;
-        match next {
-            Some(x) => {
-                match x.ty {
-                    SyncType::FileOutgoing => {
-                        if x.frozen {
-                            Ok(None)
-                        } else {
-                            Ok(Some(x))
-                        }
-                    }
-                    _ => Ok(None),
+        let syncs = syncs.into_iter()
+            .take_while(|x| x.ty == SyncType::FileOutgoing && !x.frozen && !x.waiting())
+            .collect::<Vec<_>>();
+        seal::unseal_all(syncs)
+    }
+
+    /// Ask the server if it already has a file with the given hash (for our
+    /// user), and if so, attach that file to the note instead of uploading the
+    /// same thing again. Files are encrypted so the same attachment always
+    /// comes out the same (see `FileData::prepare()`), so this catches the
+    /// same PDF attached to a handful of notes. Returns None if we need to
+    /// upload after all.
+    fn link_existing(&self, note_id: &String, hash: &String) -> TResult<Option<UploadRes>> {
+        if !FileData::dedupe_enabled() { return Ok(None); }
+        let url = format!("/files/{}", hash);
+        match self.api.call_raw(api::Method::Head, &url[..], ApiReq::new().timeout(10)) {
+            Ok(_) => {}
+            Err(e) => {
+                match e.shed() {
+                    // the server doesn't have it (or doesn't dedupe)
+                    TError::Api(..) => return Ok(None),
+                    e => return Err(e),
                 }
             }
-            None => { Ok(None) }
         }
+        let url = format!("/notes/{}/attachment/link", note_id);
+        let mut res: UploadRes = self.api.put(&url[..], ApiReq::new().timeout(30).data(json!({"hash": hash})))?;
+        res.deduped = true;
+        Ok(Some(res))
     }
 
     /// Given a sync record for an outgoing file, find the corresponding file
     /// in our storage folder and stream it to our heroic API.
-    fn upload_file(&mut self, sync: &mut SyncRecord) -> TResult<()> {
+    fn upload_file(&self, sync: &mut SyncRecord) -> TResult<()> {
         let note_id = sync.item_id.clone();
         let user_id = {
             let local_config = self.get_config();
@@ -78,18 +116,25 @@ impl FileSyncOutgoing {
             }
         };
 
-        #[derive(Deserialize, Debug)]
-        struct UploadRes {
-            #[serde(default)]
-            #[serde(deserialize_with = "::util::ser::opt_vec_str_i64_converter::deserialize")]
-            sync_ids: Option<Vec<i64>>,
-        }
+        // the hash we recorded when the file was saved (older records won't
+        // have one)
+        let expected_hash: Option<String> = sync.data.as_ref()
+            .and_then(|x| jedi::get_opt(&["hash"], x));
 
         // define a container function that grabs our file and runs the upload.
         // if anything in here fails, we mark 
         let upload = |note_id| -> TResult<UploadRes> {
             let file = FileData::file_finder(Some(&user_id), Some(note_id))?;
             info!("FileSyncOutgoing.upload_file() -- syncing file {:?}", file);
+            // don't send a file that's been corrupted on disk
+            if let Some(hash) = expected_hash.as_ref() {
+                FileData::verify_file(&file, hash)?;
+                // no need to send a file the server already has
+                if let Some(res) = self.link_existing(note_id, hash)? {
+                    info!("FileSyncOutgoing.upload_file() -- server already has file {}, linked it to note {}", hash, note_id);
+                    return Ok(res);
+                }
+            }
             // open our local file. we should test if it's readable/exists
             // before making API calls
             let mut file = fs::File::open(&file)?;
@@ -98,8 +143,15 @@ impl FileSyncOutgoing {
             let req = ApiReq::new().header("Content-Type", &String::from("application/octet-stream")).timeout(60);
             // get an API stream we can start piping file data into
             let (mut stream, info) = self.api.call_start(api::Method::Put, &url[..], req)?;
+            let limit = {
+                let local_config = self.get_config();
+                let guard = lockr!(local_config);
+                guard.upload_limit
+            };
+            let mut throttle = Throttle::new(limit);
             // start streaming our file into the API call 4K at a time
             let mut buf = [0; 4096];
+            let mut sent: u64 = 0;
             loop {
                 let read = file.read(&mut buf[..])?;
                 // all done! (EOF)
@@ -109,13 +161,26 @@ impl FileSyncOutgoing {
                 if read != written {
                     return TErr!(TError::Msg(format!("problem uploading file: grabbed {} bytes, only sent {} wtf wtf lol", read, written)));
                 }
+                sent += written as u64;
+                let wait = throttle.consume(written as u64);
+                if wait > 0 { self.sleep(wait); }
             }
             // write all our output and finalize the API call
             stream.flush()?;
-            self.api.call_end(stream.send(), info)
+            self.stats(|x| x.up(0, sent));
+            let res: UploadRes = self.api.call_end(stream.send(), info)?;
+            // if the server tells us what it got, make sure it's what we sent
+            // before we call this upload done
+            match (expected_hash.as_ref(), res.hash.as_ref()) {
+                (Some(ours), Some(theirs)) if ours != theirs => {
+                    return TErr!(TError::BadValue(format!("file for note {} was corrupted in transit: sent hash {}, server got {}", note_id, ours, theirs)));
+                }
+                _ => {}
+            }
+            Ok(res)
         };
 
-        match upload(&note_id) {
+        let deduped = match upload(&note_id) {
             Ok(res) => {
                 match res.sync_ids.as_ref() {
                     Some(ids) => {
@@ -131,6 +196,7 @@ impl FileSyncOutgoing {
                     }
                     None => {}
                 }
+                res.deduped
             }
             Err(e) => {
                 warn!("FileSyncOutgoing.run_sync() -- failed to upload file: {}", e);
@@ -143,15 +209,19 @@ impl FileSyncOutgoing {
                 // re-log the error which isn't but but kind of annoying
                 return Ok(());
             }
-        }
+        };
 
         // if we're still here, the upload succeeded. remove the sync record so
         // we know to stop trying to upload this file.
         with_db!{ db, self.db, sync.db_delete(db, None)? };
+        self.stats(|x| x.up(1, 0));
 
         // let the UI know how great we are. you will love this app. tremendous
         // app. everyone says so.
-        messaging::ui_event("sync:file:uploaded", &json!({"note_id": note_id}))?;
+        messaging::ui_event("sync:file:uploaded", &json!({"note_id": note_id, "deduped": deduped}))?;
+        // and let the app know, so it can pull the text out of documents
+        messaging::app_event("sync:file:uploaded", &json!({"note_id": note_id}))?;
+        hooks::file_uploaded(&note_id, deduped);
         Ok(())
     }
 }
@@ -178,11 +248,30 @@ impl Syncer for FileSyncOutgoing {
     }
 
     fn run_sync(&mut self) -> TResult<()> {
-        let sync_maybe = self.get_next_outgoing_file_sync()?;
-        if let Some(mut sync) = sync_maybe {
-            self.upload_file(&mut sync)?;
+        let syncs = self.get_outgoing_file_syncs()?;
+        if self.dry_run() {
+            let user_id = {
+                let guard = lockr!(self.config);
+                guard.user_id.clone()
+            };
+            let mut report = DryRunReport::new(self.get_name(), "upload");
+            for sync in &syncs {
+                // the size of the file we'd upload (if we can find it)
+                let bytes = FileData::file_finder(user_id.as_ref(), Some(&sync.item_id)).ok()
+                    .and_then(|x| fs::metadata(x).ok())
+                    .map(|x| x.len())
+                    .unwrap_or(0);
+                report.add(sync, bytes)?;
+            }
+            return self.dry_runs.report(report);
         }
-        Ok(())
+        self.dry_runs.reset();
+        if syncs.len() == 0 { return Ok(()); }
+        let slots = {
+            let guard = lockr!(self.config);
+            guard.file_transfers.clone()
+        };
+        files::run_parallel(syncs, files::workers(), &slots, || self.is_enabled(), |sync| self.upload_file(sync))
     }
 }
 
@@ -0,0 +1,141 @@
+//! Lets whoever is embedding the core hook into the sync system without
+//! patching the syncers: keep an audit log of everything that goes out, index
+//! incoming changes locally, kick off something after a file finishes
+//! uploading, etc.
+//!
+//! Hooks are registered for a `SyncHook` and get called (from the sync
+//! threads, so keep them quick) with the relevant data as JSON:
+//!
+//! - `BeforeOutgoingSend`: the records we're about to send, as they'll go
+//!   out. Returning an error holds the send (the records stay queued and the
+//!   outgoing syncer backs off like any other failure).
+//! - `AfterIncomingApply`: the incoming records we just applied to (and
+//!   committed in) the local db.
+//! - `FileUploaded`: `{"note_id": ..., "deduped": ...}` once a note's file is
+//!   up on the server.
+//!
+//! Errors from the "after" hooks are logged and otherwise ignored, since by
+//! then there's nothing left to stop.
+
+use ::std::sync::{Arc, RwLock};
+use ::jedi::{self, Value, Serialize};
+use ::error::TResult;
+
+/// The points in the sync lifecycle we can hook into
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SyncHook {
+    BeforeOutgoingSend,
+    AfterIncomingApply,
+    FileUploaded,
+}
+
+/// Identifies a registered hook (so it can be removed later)
+pub type HookId = u64;
+
+/// A hook function
+type HookFn = Arc<Fn(&Value) -> TResult<()> + Send + Sync>;
+
+/// Holds our registered hooks
+struct Registry {
+    next_id: HookId,
+    hooks: Vec<(HookId, SyncHook, HookFn)>,
+}
+
+lazy_static! {
+    static ref HOOKS: RwLock<Registry> = RwLock::new(Registry { next_id: 1, hooks: Vec::new() });
+}
+
+/// Register a hook. Returns an id that can be passed to `unregister()`.
+pub fn register<F>(hook: SyncHook, fun: F) -> HookId
+    where F: Fn(&Value) -> TResult<()> + Send + Sync + 'static
+{
+    let mut guard = lockw!((*HOOKS));
+    let id = guard.next_id;
+    guard.next_id += 1;
+    guard.hooks.push((id, hook, Arc::new(fun)));
+    id
+}
+
+/// Remove a hook. Returns false if there was no such hook.
+pub fn unregister(id: HookId) -> bool {
+    let mut guard = lockw!((*HOOKS));
+    let before = guard.hooks.len();
+    guard.hooks.retain(|x| x.0 != id);
+    guard.hooks.len() != before
+}
+
+/// Grab the hooks registered for the given point. We hand back copies so the
+/// hooks run without the registry locked (and can register/unregister hooks
+/// themselves).
+fn hooks_for(hook: SyncHook) -> Vec<HookFn> {
+    let guard = lockr!((*HOOKS));
+    guard.hooks.iter()
+        .filter(|x| x.1 == hook)
+        .map(|x| x.2.clone())
+        .collect()
+}
+
+/// Run the hooks for the given point, stopping at the first error. We only
+/// serialize the data if someone is listening.
+fn run<T: Serialize>(hook: SyncHook, data: &T) -> TResult<()> {
+    let hooks = hooks_for(hook);
+    if hooks.len() == 0 { return Ok(()); }
+    let val = jedi::to_val(data)?;
+    for fun in hooks {
+        fun(&val)?;
+    }
+    Ok(())
+}
+
+/// Run the hooks for the given point, logging (and eating) any error
+fn run_after<T: Serialize>(hook: SyncHook, data: &T) {
+    if let Err(e) = run(hook, data) {
+        error!("hooks::run_after() -- {:?} hook failed: {}", hook, e);
+    }
+}
+
+/// Call our before-outgoing-send hooks
+pub fn before_outgoing_send<T: Serialize>(records: &T) -> TResult<()> {
+    run(SyncHook::BeforeOutgoingSend, records)
+}
+
+/// Call our after-incoming-apply hooks
+pub fn after_incoming_apply<T: Serialize>(records: &T) {
+    run_after(SyncHook::AfterIncomingApply, records)
+}
+
+/// Call our file-uploaded hooks
+pub fn file_uploaded(note_id: &String, deduped: bool) {
+    run_after(SyncHook::FileUploaded, &json!({"note_id": note_id, "deduped": deduped}))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::std::sync::Mutex;
+    use ::error::TError;
+
+    #[test]
+    fn runs_hooks() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen2 = seen.clone();
+        let id1 = register(SyncHook::FileUploaded, move |val| {
+            lock!(seen2).push(jedi::get::<String>(&["note_id"], val)?);
+            Ok(())
+        });
+        file_uploaded(&String::from("n1"), false);
+        assert_eq!(*lock!(seen), vec![String::from("n1")]);
+
+        // a failing hook holds the send
+        let id2 = register(SyncHook::BeforeOutgoingSend, |_| TErr!(TError::Msg(String::from("not today"))));
+        assert!(before_outgoing_send(&vec![1, 2, 3]).is_err());
+        assert!(unregister(id2));
+        assert!(!unregister(id2));
+        assert!(before_outgoing_send(&vec![1, 2, 3]).is_ok());
+
+        assert!(unregister(id1));
+        file_uploaded(&String::from("n2"), false);
+        assert_eq!(lock!(seen).len(), 1);
+    }
+}
+
@@ -4,8 +4,13 @@ use ::jedi::{self, Value};
 use ::error::{TResult, TError};
 use ::sync::{SyncConfig, Syncer};
 use ::sync::sync_model::{SyncModel, MemorySaver};
+use ::sync::progress::SyncProgress;
+use ::sync::conflict::{self, Conflict, ConflictStrategy};
+use ::sync::delta;
+use ::sync::hooks;
+use ::sync::initial::{self, Checkpoint};
 use ::storage::Storage;
-use ::api::{Api, ApiReq};
+use ::api::{Api, ApiReq, Method};
 use ::messaging;
 use ::models;
 use ::models::protected::{Protected, Keyfinder};
@@ -16,12 +21,16 @@ use ::models::space::Space;
 use ::models::invite::Invite;
 use ::models::board::Board;
 use ::models::note::Note;
+use ::models::template::Template;
+use ::models::settings::Settings;
+use ::models::saved_search::SavedSearch;
 use ::models::file::FileData;
 use ::models::sync_record::{SyncType, SyncRecord, SyncAction};
 use ::turtl::Turtl;
 use ::std::mem;
 use ::config;
 use ::util;
+use ::url::form_urlencoded;
 
 const SYNC_IGNORE_KEY: &'static str = "sync:incoming:ignore";
 
@@ -33,6 +42,14 @@ struct SyncResponse {
     #[serde(default)]
     #[serde(deserialize_with = "::util::ser::str_i64_converter::deserialize")]
     sync_id: i64,
+    /// When loading the full profile a page at a time, how many records the
+    /// collection we asked for has
+    #[serde(default)]
+    total: Option<u64>,
+    /// If the server split a big batch of changes into pages, the cursor for
+    /// the next page (see `SyncIncoming.sync_from_api()`)
+    #[serde(default)]
+    next: Option<String>,
 }
 
 struct Handlers {
@@ -41,6 +58,9 @@ struct Handlers {
     space: models::space::Space,
     board: models::board::Board,
     note: models::note::Note,
+    template: models::template::Template,
+    settings: models::settings::Settings,
+    saved_search: models::saved_search::SavedSearch,
     file: models::file::FileData,
     invite: models::invite::Invite,
 }
@@ -54,6 +74,8 @@ enum SyncReason {
     Reconnect,
     #[serde(rename = "initial")]
     Initial,
+    #[serde(rename = "push")]
+    Push,
 }
 
 /// Given a Value object with sync_ids, try to ignore the sync ids. Kids' stuff.
@@ -109,6 +131,9 @@ impl SyncIncoming {
             space: models::space::Space::new(),
             board: models::board::Board::new(),
             note: models::note::Note::new(),
+            template: models::template::Template::new(),
+            settings: models::settings::Settings::new(),
+            saved_search: models::saved_search::SavedSearch::new(),
             file: models::file::FileData::new(),
             invite: models::invite::Invite::new(),
         };
@@ -151,6 +176,28 @@ impl SyncIncoming {
         db.kv_set(SYNC_IGNORE_KEY, &jedi::stringify(&ignored)?)
     }
 
+    /// Drop any ignored sync ids at or below the given sync id. We've already
+    /// synced past those, so they'll never show up again. Returns how many we
+    /// dropped.
+    pub fn prune_ignored(db: &mut Storage, sync_id: i64) -> TResult<u64> {
+        let ignored = SyncIncoming::get_ignored_impl(db)?;
+        let before = ignored.len();
+        let ignored = ignored.into_iter()
+            .filter(|x| match x.parse::<i64>() {
+                Ok(id) => id > sync_id,
+                Err(_) => false,
+            })
+            .collect::<Vec<_>>();
+        let pruned = (before - ignored.len()) as u64;
+        if pruned == 0 { return Ok(0); }
+        if ignored.len() == 0 {
+            db.kv_delete(SYNC_IGNORE_KEY)?;
+        } else {
+            db.kv_set(SYNC_IGNORE_KEY, &jedi::stringify(&ignored)?)?;
+        }
+        Ok(pruned)
+    }
+
     /// Get all sync ids that should be ignored on the next sync run
     fn get_ignored(&self) -> TResult<Vec<String>> {
         with_db!{ db, self.db, SyncIncoming::get_ignored_impl(db) }
@@ -161,18 +208,37 @@ impl SyncIncoming {
         with_db!{ db, self.db, db.kv_delete(SYNC_IGNORE_KEY) }
     }
 
+    /// Grab sync records from the API, keeping track of how much we pulled
+    /// down.
+    fn fetch(&self, resource: &str, req: ApiReq) -> TResult<SyncResponse> {
+        let req = if delta::enabled() { req.header("X-Turtl-Delta", &String::from("1")) } else { req };
+        let out = self.api.call_raw(Method::Get, resource, req.gzip())?;
+        let syncdata: SyncResponse = jedi::parse(&out)?;
+        self.stats(|x| x.down(syncdata.records.len() as u64, out.len() as u64));
+        Ok(syncdata)
+    }
+
     /// Grab the latest changes from the API (anything after the given sync ID).
     /// Also, if `poll` is true, we long-poll.
+    ///
+    /// If there's a lot waiting for us, the server can split it into pages of
+    /// (at most) `sync.incoming.page_size` records, handing back a `next`
+    /// cursor with each page but the last. Each page is applied (and committed)
+    /// on its own, along with the sync id the server gives it (the one its last
+    /// record leaves off at), so a huge batch of changes never has to fit in
+    /// memory all at once, and if we get cut off partway through we only redo
+    /// the page we were on.
     fn sync_from_api(&mut self, sync_id: &String, reason: SyncReason) -> TResult<()> {
         let reason_s = util::enum_to_string(&reason)?;
-        let url = format!("/sync?sync_id={}&type={}", sync_id, reason_s);
+        let page_size: u64 = config::get(&["sync", "incoming", "page_size"]).unwrap_or(1000);
+        let url = format!("/sync?sync_id={}&type={}&per_page={}", sync_id, reason_s, page_size);
         let timeout = match &reason {
             SyncReason::Poll => {
                 config::get(&["sync", "poll_timeout"]).unwrap_or(60)
             }
             _ => 10
         };
-        let syncres: TResult<SyncResponse> = self.api.get(url.as_str(), ApiReq::new().timeout(timeout));
+        let syncres: TResult<SyncResponse> = self.fetch(url.as_str(), ApiReq::new().timeout(timeout));
 
         // ^ this call can take a while. if sync got disabled while it was
         // taking its sweet time, then bail on the result.
@@ -180,7 +246,7 @@ impl SyncIncoming {
 
         // if we have a timeout just return Ok(()) (the sync system is built to
         // timeout if no response is received)
-        let syncdata = match syncres {
+        let mut syncdata = match syncres {
             Ok(x) => x,
             Err(e) => {
                 let e = e.shed();
@@ -209,29 +275,80 @@ impl SyncIncoming {
         };
 
         self.set_connected(true);
-        self.update_local_db_from_api_sync(syncdata, reason != SyncReason::Poll)
+        let force = reason != SyncReason::Poll && reason != SyncReason::Push;
+        let mut pages = 1;
+        loop {
+            let next = syncdata.next.clone();
+            self.update_local_db_from_api_sync(syncdata, force, None)?;
+            let cursor = match next {
+                Some(x) => x,
+                None => break,
+            };
+            if self.should_quit() || !self.is_enabled() { break; }
+            let cursor = form_urlencoded::byte_serialize(cursor.as_bytes()).collect::<String>();
+            let url = format!("/sync?sync_id={}&type={}&per_page={}&cursor={}", sync_id, reason_s, page_size, cursor);
+            syncdata = self.fetch(url.as_str(), ApiReq::new().timeout(30))?;
+            pages += 1;
+        }
+        if pages > 1 {
+            info!("SyncIncoming.sync_from_api() -- applied {} pages of changes", pages);
+        }
+        Ok(())
     }
 
     /// Load the user's entire profile. The API gives us back a set of sync
     /// objects, which is super handy because we can just treat them like any
-    /// other sync
+    /// other sync.
+    ///
+    /// We load it a page at a time, checkpointing as we go, so if we get cut
+    /// off we resume where we left off (see `sync::initial`). Servers that
+    /// don't page hand us the whole thing on the first call.
     fn load_full_profile(&mut self) -> TResult<()> {
-        let syncdata = self.api.get("/sync/full", ApiReq::new().timeout(120))?;
-        self.set_connected(true);
-        self.update_local_db_from_api_sync(syncdata, true)
+        let mut checkpoint = with_db!{ db, self.db, Checkpoint::load(db) }?.unwrap_or(Default::default());
+        if checkpoint != Default::default() {
+            info!("SyncIncoming.load_full_profile() -- resuming at {} (page {})", checkpoint.current(), checkpoint.page);
+        }
+        let per_page = initial::page_size();
+        while !checkpoint.done {
+            let url = format!("/sync/full?type={}&page={}&per_page={}", checkpoint.current(), checkpoint.page, per_page);
+            let mut syncdata = self.fetch(&url[..], ApiReq::new().timeout(120))?;
+            self.set_connected(true);
+            // we pick up from wherever the server was when we started
+            let sync_id = *checkpoint.sync_id.get_or_insert(syncdata.sync_id);
+            syncdata.sync_id = sync_id;
+            match syncdata.total {
+                Some(total) => {
+                    let progress = checkpoint.advance(syncdata.records.len() as u64, total, per_page);
+                    self.update_local_db_from_api_sync(syncdata, true, Some(&checkpoint))?;
+                    initial::emit(&progress)?;
+                }
+                None => {
+                    checkpoint.done = true;
+                    self.update_local_db_from_api_sync(syncdata, true, Some(&checkpoint))?;
+                }
+            }
+            if self.should_quit() { break; }
+        }
+        Ok(())
     }
 
     /// Take sync data we got from the API and update our local database with
     /// it. Kewl.
-    fn update_local_db_from_api_sync(&self, syncdata: SyncResponse, force: bool) -> TResult<()> {
+    ///
+    /// While loading the full profile, we pass in our checkpoint, which gets
+    /// saved along with the records (in place of the sync id, until we're
+    /// done).
+    fn update_local_db_from_api_sync(&self, syncdata: SyncResponse, force: bool, checkpoint: Option<&Checkpoint>) -> TResult<()> {
         // sometimes the sync call takes a while, and it's possible we've quit
         // mid-call. if this is the case, throw out our sync result.
         if self.should_quit() && !force { return Ok(()); }
         // same, but with enabled
         if !self.is_enabled() && !force { return Ok(()); }
 
-        // destructure our response
-        let SyncResponse { sync_id, records } = syncdata;
+        // destructure our response. if there are more pages coming, hang onto
+        // our ignore list (the records it's waiting for might be on them)
+        let last_page = syncdata.next.is_none();
+        let SyncResponse { sync_id, records, .. } = syncdata;
 
         // grab sync ids we're ignoring
         let ignored = self.get_ignored()?;
@@ -256,34 +373,101 @@ impl SyncIncoming {
             .collect::<Vec<_>>();
 
         info!("SyncIncoming.update_local_db_from_api_sync() -- ignored {} incoming syncs", ignore_count);
+
+        // anything from a space we don't keep on this device gets cleaned out
+        // instead of saved
+        let space_filter = {
+            let guard = lockr!(self.config);
+            guard.space_filter.clone()
+        };
+        let filtered = records.iter_mut()
+            .map(|rec| space_filter.apply(rec))
+            .filter(|x| *x)
+            .count();
+        if filtered > 0 {
+            info!("SyncIncoming.update_local_db_from_api_sync() -- dropped {} syncs for filtered spaces", filtered);
+        }
+        // let the UI know how we're doing (mainly useful for big syncs, like
+        // loading the full profile)
+        let mut progress = if records.len() > 0 {
+            let mut progress = SyncProgress::new(self.get_name(), "apply", &records)?;
+            progress.emit(true)?;
+            Some(progress)
+        } else {
+            None
+        };
+        // incoming edits to things we've changed locally but haven't sent out
+        // yet get run through our conflict strategy. we hold onto them until
+        // after the transaction commits.
+        let strategy = conflict::strategy();
+        let mut conflicts: Vec<Conflict> = Vec::new();
+        let mut applied: Vec<bool> = Vec::with_capacity(records.len());
         with_db!{ db, self.db,
             // start a transaction. running incoming sync is all or nothing.
             db.conn.execute("BEGIN TRANSACTION", &[])?;
             for rec in &mut records {
-                self.run_sync_item(db, rec)?;
+                let skip = match conflict::detect(db, rec, &strategy)? {
+                    Some(conflict) => {
+                        let skip = conflict.strategy == ConflictStrategy::ClientWins;
+                        conflicts.push(conflict);
+                        skip
+                    }
+                    None => false,
+                };
+                if !skip { self.run_sync_item(db, rec)?; }
+                applied.push(!skip);
+                if let Some(progress) = progress.as_mut() {
+                    progress.tick(&rec.ty)?;
+                    progress.emit(false)?;
+                }
+            }
+            // save our sync id (or, partway through loading the full profile,
+            // where we are)
+            match checkpoint {
+                Some(x) if !x.done => x.save(db)?,
+                _ => {
+                    db.kv_set("sync_id", &sync_id.to_string())?;
+                    Checkpoint::clear(db)?;
+                }
             }
-            // save our sync id
-            db.kv_set("sync_id", &sync_id.to_string())?;
             // ok, commit
             db.conn.execute("COMMIT TRANSACTION", &[])?;
         }
+        if let Some(progress) = progress.as_mut() {
+            progress.phase("done");
+            progress.complete();
+            progress.emit(true)?;
+        }
 
         // send our incoming syncs into a queue that the Turtl/dispatch thread
         // can read and process. The purpose is to run MemorySaver for the syncs
         // which can only happen if we have access to Turtl, which we DO NOT
         // at this particular juncture.
-        let sync_incoming_queue = {
+        let (sync_incoming_queue, conflict_queue) = {
             let conf = self.get_config();
             let sync_config_guard = lockr!(conf);
-            sync_config_guard.incoming_sync.clone()
+            (sync_config_guard.incoming_sync.clone(), sync_config_guard.conflicts.clone())
         };
-        // queue em
-        for rec in records { sync_incoming_queue.push(rec); }
+        // let any hooks know what we applied
+        {
+            let applied_records = records.iter()
+                .zip(applied.iter())
+                .filter(|x| *x.1)
+                .map(|x| x.0)
+                .collect::<Vec<_>>();
+            if applied_records.len() > 0 { hooks::after_incoming_apply(&applied_records); }
+        }
+        // queue em (minus any we skipped because our local version won)
+        for (rec, applied) in records.into_iter().zip(applied) {
+            if applied { sync_incoming_queue.push(rec); }
+        }
+        for conflict in conflicts { conflict_queue.push(conflict); }
         // this is what tells our dispatch thread to load the queued incoming
         // syncs and process them
         messaging::app_event("sync:incoming", &())?;
 
         // clear out the sync ignore list
+        if !last_page { return Ok(()); }
         match self.clear_ignored() {
             Ok(_) => {},
             Err(e) => error!("SyncIncoming.update_local_db_from_api_sync() -- error clearing out ignored syncs (but continue because it's not really a big deal): {}", e),
@@ -316,6 +500,9 @@ impl SyncIncoming {
             SyncType::Space => self.handlers.space.incoming(db, sync_item),
             SyncType::Board => self.handlers.board.incoming(db, sync_item),
             SyncType::Note => self.handlers.note.incoming(db, sync_item),
+            SyncType::Template => self.handlers.template.incoming(db, sync_item),
+            SyncType::Settings => self.handlers.settings.incoming(db, sync_item),
+            SyncType::SavedSearch => self.handlers.saved_search.incoming(db, sync_item),
             SyncType::File | SyncType::FileIncoming => self.handlers.file.incoming(db, sync_item),
             SyncType::Invite => self.handlers.invite.incoming(db, sync_item),
             SyncType::FileOutgoing => Ok(()),
@@ -324,6 +511,33 @@ impl SyncIncoming {
         Ok(())
     }
 
+    /// Whether or not the push syncer has a live connection to the server
+    fn push_connected(&self) -> bool {
+        let guard = lockr!(self.config);
+        guard.push_connected
+    }
+
+    /// Wait for the push syncer to tell us there's something new. We wait in
+    /// small slices so we can bail if we're quitting/disabled or if push drops
+    /// out from under us, and give up after `sync.push.fallback_poll` seconds
+    /// so we still catch anything a push might have missed.
+    ///
+    /// Returns the reason we should sync with.
+    fn wait_for_push(&self) -> SyncReason {
+        let signal = {
+            let guard = lockr!(self.config);
+            guard.push_signal.clone()
+        };
+        let fallback: u64 = config::get(&["sync", "push", "fallback_poll"]).unwrap_or(300);
+        let mut waited = 0;
+        while waited < fallback * 1000 {
+            if signal.wait(1000) { break; }
+            if self.should_quit() || !self.is_enabled() || !self.push_connected() { break; }
+            waited += 1000;
+        }
+        if self.push_connected() { SyncReason::Push } else { SyncReason::Poll }
+    }
+
     fn set_connected(&mut self, yesno: bool) {
         self.connected = yesno;
         self.connected(yesno);
@@ -353,7 +567,8 @@ impl Syncer for SyncIncoming {
             let config_guard = lockr!(self.config);
             config_guard.skip_api_init
         };
-        let res = if !skip_init {
+        // in dry-run mode we don't talk to the API at all
+        let res = if !skip_init && !self.dry_run() {
             match sync_id {
                 // we have a sync id! grab the latest changes from the API
                 Some(ref x) => self.sync_from_api(x, SyncReason::Initial),
@@ -367,15 +582,30 @@ impl Syncer for SyncIncoming {
     }
 
     fn run_sync(&mut self) -> TResult<()> {
+        // we can't know what the server has for us without asking, so in
+        // dry-run mode there's nothing for us to do
+        if self.dry_run() { return Ok(()); }
         let sync_id = with_db!{ db, self.db, db.kv_get("sync_id") }?;
         // note that when syncing changes from the server, we only poll if we
         // are currently connected. this way, if we DO get a connection back
         // after being previously disconnected, we can update our state
-        // immediately instead of waiting 60s or w/e until the sync goes through
-        let reason = if self.connected { SyncReason::Poll } else { SyncReason::Reconnect };
+        // immediately instead of waiting 60s or w/e until the sync goes through.
+        //
+        // if the push syncer is connected, we don't poll at all and instead
+        // wait for it to tell us there are changes.
+        let reason = if !self.connected {
+            SyncReason::Reconnect
+        } else if self.push_connected() {
+            self.wait_for_push()
+        } else {
+            SyncReason::Poll
+        };
+        if self.should_quit() || !self.is_enabled() { return Ok(()); }
         let res = match sync_id {
             Some(ref x) => self.sync_from_api(x, reason),
-            None => return TErr!(TError::MissingData(String::from("no sync_id present"))),
+            // our sync id got wiped (like when the space filter lets in spaces
+            // we skipped before), so grab everything again
+            None => self.load_full_profile(),
         };
         res
     }
@@ -416,6 +646,9 @@ pub fn process_incoming_sync(turtl: &Turtl) -> TResult<()> {
                 if model.should_deserialize_on_mem_update() {
                     turtl.find_model_key(&mut model)?;
                     model.deserialize()?;
+                    // we have no idea what changed in this version, so don't
+                    // let the mem update think it knows
+                    model.set_snapshot(None);
                 }
                 model
             };
@@ -427,13 +660,34 @@ pub fn process_incoming_sync(turtl: &Turtl) -> TResult<()> {
             SyncType::Keychain => mem_save::<KeychainEntry>(turtl, sync_item)?,
             SyncType::Space => mem_save::<Space>(turtl, sync_item)?,
             SyncType::Board => mem_save::<Board>(turtl, sync_item)?,
-            SyncType::Note => mem_save::<Note>(turtl, sync_item)?,
+            SyncType::Note => mem_save::<Note>(turtl, delta::apply_incoming(turtl, sync_item)?)?,
+            SyncType::Template => mem_save::<Template>(turtl, sync_item)?,
+            SyncType::Settings => mem_save::<Settings>(turtl, sync_item)?,
+            SyncType::SavedSearch => mem_save::<SavedSearch>(turtl, sync_item)?,
             SyncType::File => mem_save::<FileData>(turtl, sync_item)?,
             SyncType::Invite => mem_save::<Invite>(turtl, sync_item)?,
             _ => (),
         }
         drop(sync_incoming_lock);
     }
+
+    // now that the incoming edits are in, finish resolving any conflicts they
+    // caused. one bad conflict shouldn't hold up the rest.
+    let conflict_queue = {
+        let sync_config_guard = lockr!(turtl.sync_config);
+        sync_config_guard.conflicts.clone()
+    };
+    loop {
+        let conflict = match conflict_queue.try_pop() {
+            Some(x) => x,
+            None => break,
+        };
+        let item_id = conflict.item_id.clone();
+        match conflict::resolve(turtl, conflict) {
+            Ok(_) => {}
+            Err(e) => error!("sync::incoming::process_incoming_sync() -- error resolving conflict for {}: {}", item_id, e),
+        }
+    }
     Ok(())
 }
 
@@ -0,0 +1,147 @@
+//! Tracks where we are in loading the user's full profile, so a first sync
+//! that gets interrupted (app killed, network drops) picks up where it left off
+//! instead of pulling down 10,000 notes all over again.
+//!
+//! We grab the profile one collection at a time, a page at a time, and after
+//! each page we save a checkpoint in the kv store (in the same transaction as
+//! the page's records). The sync id we'll continue from once we're done is the
+//! one the server gave us on the very first page, so anything that changed
+//! while we were paging comes in on the next incoming sync.
+//!
+//! The UI hears about how far along we are via `sync:initial:progress` events.
+
+use ::jedi;
+use ::config;
+use ::error::TResult;
+use ::messaging;
+use ::storage::Storage;
+
+/// The kv key our checkpoint lives under
+const CHECKPOINT_KEY: &'static str = "sync:initial:checkpoint";
+
+/// The collections that make up a profile, in the order we load them (things
+/// that hold keys come before the things they unlock)
+pub const COLLECTIONS: [&'static str; 7] = ["user", "keychain", "space", "board", "note", "file", "invite"];
+
+/// How many records we ask for per page (`sync.initial.page_size`)
+pub fn page_size() -> u64 {
+    config::get(&["sync", "initial", "page_size"]).unwrap_or(500)
+}
+
+/// Where we are in loading the full profile
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Checkpoint {
+    /// The sync id to continue from once we're done
+    pub sync_id: Option<i64>,
+    /// Which of `COLLECTIONS` we're on
+    pub collection: usize,
+    /// The next page of that collection to grab
+    pub page: u64,
+    /// How many records of that collection we've loaded so far
+    pub loaded: u64,
+    /// Whether we've loaded everything
+    #[serde(default)]
+    pub done: bool,
+}
+
+/// What we tell the UI after each page
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct InitialProgress {
+    pub collection: String,
+    pub loaded: u64,
+    pub total: u64,
+}
+
+impl Checkpoint {
+    /// Load our checkpoint, if we have one
+    pub fn load(db: &Storage) -> TResult<Option<Checkpoint>> {
+        match db.kv_get(CHECKPOINT_KEY)? {
+            Some(x) => Ok(Some(jedi::parse(&x)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Save our checkpoint
+    pub fn save(&self, db: &Storage) -> TResult<()> {
+        db.kv_set(CHECKPOINT_KEY, &jedi::stringify(self)?)
+    }
+
+    /// Forget our checkpoint
+    pub fn clear(db: &Storage) -> TResult<()> {
+        db.kv_delete(CHECKPOINT_KEY)
+    }
+
+    /// The collection we're currently loading
+    pub fn current(&self) -> &'static str {
+        COLLECTIONS[if self.done { COLLECTIONS.len() - 1 } else { self.collection }]
+    }
+
+    /// Count a page of `count` records (out of `total` for the collection)
+    /// against our checkpoint, moving on to the next collection if that was the
+    /// last page. Returns the progress to report.
+    pub fn advance(&mut self, count: u64, total: u64, per_page: u64) -> InitialProgress {
+        self.loaded += count;
+        self.page += 1;
+        let progress = InitialProgress {
+            collection: String::from(self.current()),
+            loaded: self.loaded,
+            total: total,
+        };
+        if count < per_page || self.loaded >= total {
+            self.collection += 1;
+            self.page = 0;
+            self.loaded = 0;
+            self.done = self.collection >= COLLECTIONS.len();
+        }
+        progress
+    }
+}
+
+/// Let the UI know how far along we are
+pub fn emit(progress: &InitialProgress) -> TResult<()> {
+    messaging::ui_event("sync:initial:progress", progress)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::schema;
+
+    #[test]
+    fn advances() {
+        let mut checkpoint = Checkpoint::default();
+        assert_eq!(checkpoint.current(), "user");
+        // one user, done
+        let progress = checkpoint.advance(1, 1, 100);
+        assert_eq!(progress, InitialProgress { collection: String::from("user"), loaded: 1, total: 1 });
+        assert_eq!(checkpoint.current(), "keychain");
+        // two full pages of keys and a partial one
+        checkpoint.advance(100, 250, 100);
+        let progress = checkpoint.advance(100, 250, 100);
+        assert_eq!(progress.loaded, 200);
+        assert_eq!((checkpoint.collection, checkpoint.page), (1, 2));
+        checkpoint.advance(50, 250, 100);
+        assert_eq!((checkpoint.current(), checkpoint.page, checkpoint.loaded), ("space", 0, 0));
+        // a full last page moves on too, if we know it's the last
+        checkpoint.advance(100, 100, 100);
+        assert_eq!(checkpoint.current(), "board");
+        for _ in 0..3 { checkpoint.advance(0, 0, 100); }
+        assert!(!checkpoint.done);
+        checkpoint.advance(0, 0, 100);
+        assert!(checkpoint.done);
+    }
+
+    #[test]
+    fn saves_checkpoints() {
+        let db = Storage::new(&String::from(":memory:"), schema::get_schema()).unwrap();
+        assert_eq!(Checkpoint::load(&db).unwrap(), None);
+        let mut checkpoint = Checkpoint::default();
+        checkpoint.sync_id = Some(69);
+        checkpoint.advance(1, 1, 100);
+        checkpoint.save(&db).unwrap();
+        assert_eq!(Checkpoint::load(&db).unwrap(), Some(checkpoint));
+        Checkpoint::clear(&db).unwrap();
+        assert_eq!(Checkpoint::load(&db).unwrap(), None);
+    }
+}
+
@@ -0,0 +1,252 @@
+//! Splits the outgoing sync queue into priority lanes so the small stuff
+//! (keychain entries, space changes, quick note edits) doesn't sit behind a
+//! pile of huge records or items stuck waiting on their file uploads.
+//!
+//! Every outgoing record lands in a lane:
+//!
+//! - `high`: users, keychain entries, spaces, and invites (these unlock or
+//!   gate everything else)
+//! - `normal`: boards, notes, and everything else
+//! - `low`: any record whose data is bigger than `large_record` bytes
+//!
+//! Each run we fill a batch (of at most `batch` records) by taking up to
+//! `high` records from the high lane, then `normal` from the normal lane, then
+//! `low` from the low lane, and around again until the batch is full or we run
+//! out. A record never jumps ahead of an earlier record it's related to (the
+//! same item, or an item it lives in/that lives in it), so the server still
+//! sees things in an order that makes sense. If the record holding it back is
+//! stuck, it waits, but unrelated records behind it keep moving.
+//!
+//! Settings live under `sync.lanes` in the config.
+
+use ::std::collections::{HashMap, HashSet, BTreeSet, VecDeque};
+use ::jedi;
+use ::config;
+use ::error::TResult;
+use ::models::sync_record::{SyncRecord, SyncType};
+
+/// How we weigh our lanes against each other
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct LanePolicy {
+    /// How many high-priority records we take per round
+    pub high: usize,
+    /// How many normal records we take per round
+    pub normal: usize,
+    /// How many low-priority records we take per round
+    pub low: usize,
+    /// The most records we send in one run
+    pub batch: usize,
+    /// Records with more data than this (bytes) go in the low lane
+    pub large_record: u64,
+}
+
+impl Default for LanePolicy {
+    fn default() -> Self {
+        LanePolicy {
+            high: 4,
+            normal: 2,
+            low: 1,
+            batch: 250,
+            large_record: 65536,
+        }
+    }
+}
+
+impl LanePolicy {
+    /// Load our policy from the config (`sync.lanes`)
+    pub fn from_config() -> Self {
+        config::get(&["sync", "lanes"]).unwrap_or(Default::default())
+    }
+
+    /// How many records we take from the given lane per round (always at least
+    /// one, so no lane gets starved outright)
+    fn weight(&self, lane: &Lane) -> usize {
+        let weight = match *lane {
+            Lane::High => self.high,
+            Lane::Normal => self.normal,
+            Lane::Low => self.low,
+        };
+        if weight == 0 { 1 } else { weight }
+    }
+}
+
+/// The lanes, in the order we serve them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Lane {
+    High,
+    Normal,
+    Low,
+}
+
+const LANES: [Lane; 3] = [Lane::High, Lane::Normal, Lane::Low];
+
+impl Lane {
+    /// Figure out which lane a sync record goes in
+    pub fn of(rec: &SyncRecord, policy: &LanePolicy) -> TResult<Lane> {
+        let size = match rec.data.as_ref() {
+            Some(x) => jedi::stringify(x)?.len() as u64,
+            None => 0,
+        };
+        if size > policy.large_record { return Ok(Lane::Low); }
+        Ok(match rec.ty {
+            SyncType::User | SyncType::Keychain | SyncType::Space | SyncType::Invite | SyncType::Settings | SyncType::SavedSearch => Lane::High,
+            _ => Lane::Normal,
+        })
+    }
+}
+
+/// The items a record lives in (its space and board)
+fn parents(rec: &SyncRecord) -> Vec<String> {
+    let data = match rec.data.as_ref() {
+        Some(x) => x,
+        None => return Vec::new(),
+    };
+    let mut parents = Vec::new();
+    for field in &["space_id", "board_id"] {
+        if let Some(id) = jedi::get_opt::<String>(&[*field], data) {
+            if id != rec.item_id { parents.push(id); }
+        }
+    }
+    parents
+}
+
+/// Keeps track of the records we haven't picked yet (by position in the queue)
+/// so we can tell if a record has an earlier relative still waiting
+struct Waiting {
+    by_item: HashMap<String, BTreeSet<usize>>,
+    by_parent: HashMap<String, BTreeSet<usize>>,
+}
+
+impl Waiting {
+    fn new() -> Self {
+        Waiting { by_item: HashMap::new(), by_parent: HashMap::new() }
+    }
+
+    fn add(&mut self, pos: usize, item_id: &String, parents: &Vec<String>) {
+        self.by_item.entry(item_id.clone()).or_insert_with(BTreeSet::new).insert(pos);
+        for parent in parents {
+            self.by_parent.entry(parent.clone()).or_insert_with(BTreeSet::new).insert(pos);
+        }
+    }
+
+    fn remove(&mut self, pos: usize, item_id: &String, parents: &Vec<String>) {
+        if let Some(x) = self.by_item.get_mut(item_id) { x.remove(&pos); }
+        for parent in parents {
+            if let Some(x) = self.by_parent.get_mut(parent) { x.remove(&pos); }
+        }
+    }
+
+    /// Whether anything before `pos` in one of the given sets is still waiting
+    fn earlier(set: Option<&BTreeSet<usize>>, pos: usize) -> bool {
+        set.map(|x| x.range(..pos).next().is_some()).unwrap_or(false)
+    }
+
+    /// Whether the record at `pos` has to wait on an earlier relative
+    fn blocks(&self, pos: usize, item_id: &String, parents: &Vec<String>) -> bool {
+        if Waiting::earlier(self.by_item.get(item_id), pos) { return true; }
+        if Waiting::earlier(self.by_parent.get(item_id), pos) { return true; }
+        parents.iter().any(|x| Waiting::earlier(self.by_item.get(x), pos))
+    }
+}
+
+/// Pick the next batch of records to send, in the order we should send them.
+/// `records` is the queue in order. Records for items in `hold` stay put (and
+/// hold back their relatives) this time around.
+pub fn schedule(records: Vec<SyncRecord>, hold: &HashSet<String>, policy: &LanePolicy) -> TResult<Vec<SyncRecord>> {
+    let mut waiting = Waiting::new();
+    let mut lanes: HashMap<Lane, VecDeque<(usize, Vec<String>, SyncRecord)>> = HashMap::new();
+    for (pos, rec) in records.into_iter().enumerate() {
+        let parents = parents(&rec);
+        waiting.add(pos, &rec.item_id, &parents);
+        if hold.contains(&rec.item_id) { continue; }
+        let lane = Lane::of(&rec, policy)?;
+        lanes.entry(lane).or_insert_with(VecDeque::new).push_back((pos, parents, rec));
+    }
+
+    let batch = if policy.batch == 0 { 1 } else { policy.batch };
+    let mut picked = Vec::new();
+    loop {
+        let mut progressed = false;
+        for lane in LANES.iter() {
+            let queue = match lanes.get_mut(lane) {
+                Some(x) => x,
+                None => continue,
+            };
+            for _ in 0..policy.weight(lane) {
+                if picked.len() >= batch { break; }
+                // take the first record in the lane that isn't waiting on a
+                // relative
+                let ready = queue.iter().position(|&(pos, ref parents, ref rec)| !waiting.blocks(pos, &rec.item_id, parents));
+                let idx = match ready {
+                    Some(x) => x,
+                    None => break,
+                };
+                let (pos, parents, rec) = queue.remove(idx).expect("lanes::schedule() -- lane index out of bounds");
+                waiting.remove(pos, &rec.item_id, &parents);
+                picked.push(rec);
+                progressed = true;
+            }
+        }
+        if !progressed || picked.len() >= batch { break; }
+    }
+    Ok(picked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rec(id: &str, ty: &str, item_id: &str, data: &str) -> SyncRecord {
+        let json = format!(r#"{{"id":"{}","action":"edit","item_id":"{}","user_id":1,"type":"{}","data":{}}}"#, id, item_id, ty, data);
+        jedi::parse(&json).unwrap()
+    }
+
+    fn ids(records: &Vec<SyncRecord>) -> Vec<&str> {
+        records.iter().map(|x| x.id.as_ref().unwrap().as_str()).collect()
+    }
+
+    #[test]
+    fn weighs_lanes() {
+        let big = format!(r#"{{"id":"n1","space_id":"s1","body":"{}"}}"#, "x".repeat(200));
+        let records = vec![
+            rec("1", "note", "n1", &big),
+            rec("2", "note", "n2", r#"{"id":"n2","space_id":"s1"}"#),
+            rec("3", "note", "n3", r#"{"id":"n3","space_id":"s1"}"#),
+            rec("4", "note", "n4", r#"{"id":"n4","space_id":"s1"}"#),
+            rec("5", "keychain", "k1", r#"{"id":"k1","item_id":"s2"}"#),
+            rec("6", "note", "n1", r#"{"id":"n1","space_id":"s1"}"#),
+        ];
+        let mut policy = LanePolicy::default();
+        policy.large_record = 100;
+        let picked = schedule(records.clone(), &HashSet::new(), &policy).unwrap();
+        // the keychain entry goes first, the big note gets through on its
+        // weight, and the later edit of the big note never jumps it
+        assert_eq!(ids(&picked), vec!["5", "2", "3", "1", "4", "6"]);
+
+        policy.batch = 3;
+        let picked = schedule(records.clone(), &HashSet::new(), &policy).unwrap();
+        assert_eq!(ids(&picked), vec!["5", "2", "3"]);
+    }
+
+    #[test]
+    fn respects_relatives() {
+        let records = vec![
+            rec("1", "note", "n1", r#"{"id":"n1","space_id":"s1","board_id":"b1"}"#),
+            rec("2", "note", "n2", r#"{"id":"n2","space_id":"s1"}"#),
+            rec("3", "space", "s1", r#"{"id":"s1"}"#),
+            rec("4", "board", "b1", r#"{"id":"b1","space_id":"s1"}"#),
+            rec("5", "space", "s2", r#"{"id":"s2"}"#),
+        ];
+        let picked = schedule(records.clone(), &HashSet::new(), &LanePolicy::default()).unwrap();
+        // the space waits for the notes in it, the board for the note and space
+        assert_eq!(ids(&picked), vec!["5", "1", "2", "3", "4"]);
+
+        // a held note holds its later edits and the space it lives in
+        let mut hold = HashSet::new();
+        hold.insert(String::from("n1"));
+        let picked = schedule(records, &hold, &LanePolicy::default()).unwrap();
+        assert_eq!(ids(&picked), vec!["5", "2"]);
+    }
+}
+
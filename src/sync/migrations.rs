@@ -0,0 +1,177 @@
+//! Upgrades the sync bookkeeping left behind by older versions of the app so
+//! the current sync code can read it. This runs before the syncers start (see
+//! `Turtl::sync_start()`), and we record the version we've upgraded to in the
+//! kv store so each step only ever runs once.
+//!
+//! To add a migration, write a function that takes the db from the previous
+//! version to the next and tack it onto the end of `MIGRATIONS`. Each one runs
+//! in its own transaction (along with bumping the version), so a failed step
+//! leaves the db as it was and gets retried on the next start.
+
+use ::rusqlite::Connection;
+use ::jedi::{self, Value};
+use ::dumpy::Dumpy;
+use ::error::{TResult, TError};
+use ::storage::Storage;
+use ::models::sync_record::SyncRecord;
+
+/// The kv key we keep our sync schema version under
+const VERSION_KEY: &'static str = "sync:schema_version";
+
+/// The table sync records used to live in
+const LEGACY_TABLE: &'static str = "sync_outgoing";
+
+/// An upgrade step. Returns how many records it touched.
+type Migration = fn(&Dumpy, &Connection) -> TResult<u64>;
+
+/// Our migrations, in order. The db is at version N once the first N of these
+/// have run.
+const MIGRATIONS: [(&'static str, Migration); 2] = [
+    ("move sync_outgoing records into sync", move_legacy_table),
+    ("normalize legacy sync record fields", normalize_records),
+];
+
+/// The sync schema version this code expects
+pub fn current_version() -> u32 {
+    MIGRATIONS.len() as u32
+}
+
+/// Grab the sync schema version of the db (0 if it's never been migrated)
+pub fn version(db: &Storage) -> TResult<u32> {
+    match db.kv_get(VERSION_KEY)? {
+        Some(x) => Ok(x.parse::<u32>()?),
+        None => Ok(0),
+    }
+}
+
+/// Bring the db's sync records up to date. Returns the number of migrations
+/// we ran.
+pub fn run(db: &mut Storage) -> TResult<u32> {
+    let from = version(db)?;
+    let to = current_version();
+    if from > to {
+        return TErr!(TError::BadValue(format!("sync schema version {} is newer than this app knows about ({}), refusing to sync", from, to)));
+    }
+    for (idx, &(name, migration)) in MIGRATIONS.iter().enumerate().skip(from as usize) {
+        let version = (idx + 1) as u32;
+        let tx = db.conn.transaction()?;
+        let touched = migration(&db.dumpy, &tx)?;
+        db.dumpy.kv_set(&tx, VERSION_KEY, &version.to_string())?;
+        tx.commit()?;
+        info!("migrations::run() -- sync schema v{}: {} ({} record(s))", version, name, touched);
+    }
+    Ok(to - from)
+}
+
+/// v1: sync records used to live in their own `sync_outgoing` table. Move them
+/// into `sync` (ids are unique across tables, so order is preserved).
+fn move_legacy_table(dumpy: &Dumpy, conn: &Connection) -> TResult<u64> {
+    let legacy_table = String::from(LEGACY_TABLE);
+    let sync_table = String::from("sync");
+    let records = dumpy.all(conn, &legacy_table)?;
+    for rec in &records {
+        let id: String = jedi::get(&["id"], rec)?;
+        dumpy.delete(conn, &legacy_table, &id)?;
+        dumpy.store(conn, &sync_table, rec)?;
+    }
+    Ok(records.len() as u64)
+}
+
+/// Upgrade the fields of a legacy sync record in place. Returns true if we
+/// changed anything.
+///
+/// Older records:
+///
+/// - used `failed` where we now use `frozen`
+/// - sometimes stored `errcount` as a string
+/// - stored `data` as a JSON string instead of an object
+fn normalize_record(rec: &mut Value) -> TResult<bool> {
+    let mut changed = false;
+    if let Some(failed) = jedi::get_opt::<bool>(&["failed"], rec) {
+        jedi::remove(&["failed"], rec)?;
+        if jedi::get_opt::<bool>(&["frozen"], rec).is_none() {
+            jedi::set(&["frozen"], rec, &failed)?;
+        }
+        changed = true;
+    }
+    if let Some(errcount) = jedi::get_opt::<String>(&["errcount"], rec) {
+        jedi::set(&["errcount"], rec, &errcount.parse::<u32>().unwrap_or(0))?;
+        changed = true;
+    }
+    if let Some(data) = jedi::get_opt::<String>(&["data"], rec) {
+        let parsed: Value = jedi::parse(&data)?;
+        jedi::set(&["data"], rec, &parsed)?;
+        changed = true;
+    }
+    Ok(changed)
+}
+
+/// v2: bring old-style sync records in line with `SyncRecord`. Anything we
+/// still can't read gets moved out of the sync table (into the kv store, under
+/// `sync:unreadable:<id>`) so it doesn't jam up the syncers, but isn't lost.
+fn normalize_records(dumpy: &Dumpy, conn: &Connection) -> TResult<u64> {
+    let sync_table = String::from("sync");
+    let mut touched = 0;
+    for mut rec in dumpy.all(conn, &sync_table)? {
+        let id: String = jedi::get(&["id"], &rec)?;
+        let changed = match normalize_record(&mut rec) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("migrations::normalize_records() -- couldn't normalize sync record {}: {}", id, e);
+                false
+            }
+        };
+        if jedi::from_val::<SyncRecord>(rec.clone()).is_err() {
+            warn!("migrations::normalize_records() -- sync record {} is unreadable, setting it aside", id);
+            dumpy.kv_set(conn, &format!("sync:unreadable:{}", id), &jedi::stringify(&rec)?)?;
+            dumpy.delete(conn, &sync_table, &id)?;
+            touched += 1;
+        } else if changed {
+            dumpy.store(conn, &sync_table, &rec)?;
+            touched += 1;
+        }
+    }
+    Ok(touched)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ::schema;
+    use ::models::sync_record::SyncType;
+
+    #[test]
+    fn migrates_legacy_records() {
+        let mut db = Storage::new(&String::from(":memory:"), schema::get_schema()).unwrap();
+        let legacy: Vec<Value> = jedi::parse(&String::from(r#"[
+            {"id":"1","action":"add","item_id":"a","user_id":1,"type":"note","data":"{\"id\":\"a\"}","errcount":"2"},
+            {"id":"2","action":"edit","item_id":"b","user_id":1,"type":"board","data":{"id":"b"},"failed":true},
+            {"id":"3","action":"lol","item_id":"c","user_id":1,"type":"note","data":{"id":"c"}}
+        ]"#)).unwrap();
+        for rec in &legacy {
+            db.dumpy.store(&db.conn, &String::from(LEGACY_TABLE), rec).unwrap();
+        }
+        assert_eq!(version(&db).unwrap(), 0);
+
+        assert_eq!(run(&mut db).unwrap(), current_version());
+        assert_eq!(version(&db).unwrap(), current_version());
+        assert_eq!(db.dumpy.all(&db.conn, &String::from(LEGACY_TABLE)).unwrap().len(), 0);
+
+        let records = SyncRecord::find(&mut db, None).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].errcount, 2);
+        assert_eq!(jedi::get::<String>(&["id"], records[0].data.as_ref().unwrap()).unwrap(), "a");
+        assert_eq!(records[1].ty, SyncType::Board);
+        assert!(records[1].frozen);
+        assert!(db.kv_get("sync:unreadable:3").unwrap().is_some());
+
+        // nothing left to do
+        assert_eq!(run(&mut db).unwrap(), 0);
+
+        // a db from the future is left alone
+        db.kv_set(VERSION_KEY, &String::from("999")).unwrap();
+        assert!(run(&mut db).is_err());
+    }
+}
+
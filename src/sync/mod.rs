@@ -18,18 +18,52 @@ mod macros;
 pub mod incoming;
 pub mod outgoing;
 pub mod files;
+pub mod push;
+pub mod backoff;
+pub mod progress;
+pub mod selective;
+pub mod conflict;
+pub mod dry_run;
+pub mod stats;
+pub mod compact;
+pub mod throttle;
+pub mod delta;
+pub mod connectivity;
+pub mod migrations;
+pub mod seal;
+pub mod schedule;
+pub mod initial;
+pub mod audit;
+pub mod lanes;
+pub mod watchdog;
+pub mod hooks;
+pub mod reminders;
 #[macro_use]
 pub mod sync_model;
 
 use ::std::thread;
-use ::std::sync::{Arc, RwLock, Mutex, mpsc};
+use ::std::panic;
+use ::std::sync::{Arc, RwLock, Mutex, Condvar, mpsc};
+use ::std::collections::HashSet;
+use ::std::time::{Duration, Instant};
 use ::config;
 use ::sync::outgoing::SyncOutgoing;
 use ::sync::incoming::SyncIncoming;
 use ::sync::files::outgoing::FileSyncOutgoing;
 use ::sync::files::incoming::FileSyncIncoming;
+use ::sync::connectivity::SyncConnectivity;
+use ::sync::reminders::SyncReminders;
+use ::sync::files::TransferSlots;
+use ::sync::push::SyncPush;
+use ::sync::backoff::Backoff;
+use ::sync::selective::SpaceFilter;
+use ::sync::conflict::Conflict;
+use ::sync::stats::{SyncStats, SyncerStats};
+use ::sync::schedule::PollPolicy;
+use ::sync::watchdog::{Heartbeats, SyncWatchdog};
 use ::models::sync_record::SyncRecord;
 use ::util;
+use ::util::cancel::CancelToken;
 use ::error::{TResult, TError};
 use ::storage::Storage;
 use ::api::Api;
@@ -78,6 +112,55 @@ pub struct SyncConfig {
     /// SyncIncoming thread (since the sync threads are all generalized). Deal
     /// with it.
     pub incoming_sync: Arc<MsQueue<SyncRecord>>,
+    /// The session this sync run belongs to. Cancelled on logout, at which
+    /// point our sync threads stop.
+    pub session: CancelToken,
+    /// Poked by the push syncer whenever the server tells us there's something
+    /// new, at which point the incoming syncer runs immediately.
+    pub push_signal: Arc<SyncSignal>,
+    /// Whether or not the push syncer currently has a live connection. If not,
+    /// the incoming syncer falls back to long-polling.
+    pub push_connected: bool,
+    /// Caps how many file transfers (uploads and downloads combined) can run
+    /// at once.
+    pub file_transfers: Arc<TransferSlots>,
+    /// Syncers (by name) that have been paused individually. A paused syncer
+    /// sits idle even when sync as a whole is enabled.
+    pub paused: HashSet<String>,
+    /// Which spaces this device keeps locally
+    pub space_filter: SpaceFilter,
+    /// Conflicts the incoming syncer found that the main thread needs to
+    /// finish resolving (and tell the UI about). Same deal as `incoming_sync`.
+    pub conflicts: Arc<MsQueue<Conflict>>,
+    /// If true, syncers report what they would do instead of doing it (no API
+    /// calls, no db writes). See `sync::dry_run`.
+    pub dry_run: bool,
+    /// Running counters for each syncer (see `sync::stats`)
+    pub stats: Arc<Mutex<SyncStats>>,
+    /// The most bytes/sec we'll send when uploading a file (0 is no limit).
+    /// See `sync::throttle`.
+    pub upload_limit: u64,
+    /// The most bytes/sec we'll pull down when downloading a file (0 is no
+    /// limit)
+    pub download_limit: u64,
+    /// Whether or not we can reach the API. While we can't, syncers that need
+    /// the network sit idle. See `sync::connectivity`.
+    pub online: bool,
+    /// Set when someone wants the connectivity syncer to check the connection
+    /// right away
+    pub connectivity_check: bool,
+    /// How syncers slow down when things are quiet (see `sync::schedule`)
+    pub poll_policy: PollPolicy,
+    /// When the user last did something that might need syncing
+    pub last_activity: Instant,
+    /// Whether the app is in the foreground
+    pub foreground: bool,
+    /// Where our syncers check in so the watchdog knows they're alive (see
+    /// `sync::watchdog`)
+    pub heartbeats: Arc<Heartbeats>,
+    /// Where the user db lives, so restarted syncers can open their own
+    /// connection to it
+    pub db_location: Option<String>,
 }
 
 impl SyncConfig {
@@ -90,6 +173,81 @@ impl SyncConfig {
             skip_api_init: false,
             run_version: 0,
             incoming_sync: Arc::new(MsQueue::new()),
+            session: CancelToken::new(),
+            push_signal: Arc::new(SyncSignal::new()),
+            push_connected: false,
+            file_transfers: Arc::new(TransferSlots::from_config()),
+            paused: HashSet::new(),
+            space_filter: Default::default(),
+            conflicts: Arc::new(MsQueue::new()),
+            dry_run: config::get(&["sync", "dry_run"]).unwrap_or(false),
+            stats: Arc::new(Mutex::new(SyncStats::new())),
+            upload_limit: config::get(&["sync", "files", "upload_limit"]).unwrap_or(0),
+            download_limit: config::get(&["sync", "files", "download_limit"]).unwrap_or(0),
+            online: true,
+            connectivity_check: false,
+            poll_policy: PollPolicy::from_config(),
+            last_activity: Instant::now(),
+            foreground: true,
+            heartbeats: Arc::new(Heartbeats::new()),
+            db_location: None,
+        }
+    }
+}
+
+/// A simple wakeup signal one sync thread can use to nudge another.
+pub struct SyncSignal {
+    /// Whether or not a signal is waiting to be picked up
+    pending: Mutex<bool>,
+    /// Wakes up anyone waiting on the signal
+    cond: Condvar,
+}
+
+impl SyncSignal {
+    /// Create a new signal
+    pub fn new() -> Self {
+        SyncSignal {
+            pending: Mutex::new(false),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// Fire the signal
+    pub fn notify(&self) {
+        let mut guard = lock!(self.pending);
+        *guard = true;
+        self.cond.notify_all();
+    }
+
+    /// Wait (up to `timeout` ms) for the signal to fire. Returns true if it
+    /// did, and resets it either way.
+    pub fn wait(&self, timeout: u64) -> bool {
+        let mut guard = lock!(self.pending);
+        if !*guard {
+            guard = match self.cond.wait_timeout(guard, Duration::from_millis(timeout)) {
+                Ok((x, _)) => x,
+                Err(e) => e.into_inner().0,
+            };
+        }
+        let fired = *guard;
+        *guard = false;
+        fired
+    }
+}
+
+/// Every syncer we run, by name
+pub const SYNCERS: [&'static str; 7] = ["outgoing", "incoming", "files:outgoing", "files:incoming", "push", "connectivity", "reminders"];
+
+/// Turn something the UI wants to pause/resume into the syncers it covers. This
+/// is either a syncer's name or `files` (both file syncers).
+pub fn syncers_for(target: &str) -> TResult<Vec<&'static str>> {
+    match target {
+        "files" => Ok(vec!["files:outgoing", "files:incoming"]),
+        _ => {
+            match SYNCERS.iter().find(|x| **x == target) {
+                Some(x) => Ok(vec![*x]),
+                None => TErr!(TError::BadValue(format!("unknown syncer: {}", target))),
+            }
         }
     }
 }
@@ -101,6 +259,10 @@ pub struct SyncState {
     pub pause: Box<Fn() + 'static + Sync + Send>,
     pub resume: Box<Fn() + 'static + Sync + Send>,
     pub enabled: Box<Fn() -> bool + 'static + Sync + Send>,
+    pub pause_syncers: Box<Fn(&Vec<&'static str>) + 'static + Sync + Send>,
+    pub resume_syncers: Box<Fn(&Vec<&'static str>) + 'static + Sync + Send>,
+    pub paused: Box<Fn() -> Vec<String> + 'static + Sync + Send>,
+    pub stats: Box<Fn() -> SyncStats + 'static + Sync + Send>,
 }
 
 /// Defines some common functions for our incoming/outgoing sync objects
@@ -129,18 +291,48 @@ pub trait Syncer {
     }
 
     /// Get the delay (in ms) between called to run_sync() for this Syncer
+    /// when things are busy. The actual delay stretches out as things quiet
+    /// down (see `next_delay()`).
     fn get_delay(&self) -> u64 {
         1000
     }
 
-    /// Check to see if we should quit the thread
+    /// Whether our delay should follow the poll policy
+    fn adaptive(&self) -> bool {
+        true
+    }
+
+    /// Get the delay (in ms) before our next call to run_sync(), going by how
+    /// long it's been since the user did anything
+    fn next_delay(&self) -> u64 {
+        if !self.adaptive() { return self.get_delay(); }
+        schedule::next_delay(&self.get_config(), self.get_delay())
+    }
+
+    /// Sleep between runs for (up to) the given number of ms, waking up early
+    /// if the user does something or if we should quit.
+    fn idle(&self, millis: u64) {
+        let since = Instant::now();
+        let mut remaining = millis;
+        while remaining > 0 && !self.should_quit() {
+            let slice = if remaining > 250 { 250 } else { remaining };
+            util::sleep(slice);
+            remaining -= slice;
+            if self.adaptive() && schedule::active_since(&self.get_config(), &since) { break; }
+        }
+    }
+
+    /// Check to see if we should quit the thread. Since every sync loop calls
+    /// this constantly, this is also where we check in with the watchdog (and
+    /// find out if we've been replaced).
     fn should_quit(&self) -> bool {
         let local_config = self.get_config();
         let guard = lockr!(local_config);
         let quit = guard.quit.clone();
         let run_version = self.get_run_version();
         let run_mismatch = guard.run_version != run_version;
-        run_mismatch || quit
+        let replaced = !guard.heartbeats.beat(self.get_name());
+        run_mismatch || quit || replaced || guard.session.is_cancelled()
     }
 
     /// Check to see if we're enabled
@@ -150,6 +342,10 @@ pub trait Syncer {
             "incoming" => "enable_incoming",
             "files:outgoing" => "enable_files_outgoing",
             "files:incoming" => "enable_files_incoming",
+            "push" => "enable_push",
+            "connectivity" => "enable_connectivity",
+            "reminders" => "enable_reminders",
+            "watchdog" => "enable_watchdog",
             _ => "<unknown>",
         };
         let config_enabled: bool = match config::get(&["sync", config_enabled_key]) {
@@ -160,7 +356,36 @@ pub trait Syncer {
         let guard = lockr!(local_config);
         let run_version = self.get_run_version();
         let run_mismatch = guard.run_version != run_version;
-        guard.enabled.clone() && config_enabled && !run_mismatch
+        let paused = guard.paused.contains(self.get_name());
+        guard.enabled.clone() && config_enabled && !run_mismatch && !paused
+    }
+
+    /// Whether or not this syncer talks to the API (and should sit idle while
+    /// we're offline)
+    fn needs_network(&self) -> bool {
+        true
+    }
+
+    /// Whether or not we can reach the API
+    fn is_online(&self) -> bool {
+        connectivity::is_online(&self.get_config())
+    }
+
+    /// Wait (up to `millis` ms) for us to come back online, checking often
+    /// enough that we get going right away when we do.
+    fn wait_online(&self, millis: u64) {
+        let mut waited = 0;
+        while waited < millis && !self.is_online() && !self.should_quit() {
+            util::sleep(250);
+            waited += 250;
+        }
+    }
+
+    /// Check to see if we're in dry-run mode
+    fn dry_run(&self) -> bool {
+        let local_config = self.get_config();
+        let guard = lockr!(local_config);
+        guard.dry_run
     }
 
     /// Get our sync_id key (for our k/v store)
@@ -182,6 +407,7 @@ pub trait Syncer {
             let local_config = self.get_config();
             let guard = lockr!(local_config);
             self.set_run_version(guard.run_version);
+            guard.heartbeats.register(self.get_name());
         }
 
         info!("sync::runner() -- {} init (run {})", self.get_name(), self.get_run_version());
@@ -211,20 +437,73 @@ pub trait Syncer {
         }
 
         info!("sync::runner() -- {} main loop", self.get_name());
+        let mut backoff = Backoff::new(self.get_name());
         while !self.should_quit() {
-            let delay = self.get_delay();
-            if self.is_enabled() {
+            let delay = self.next_delay();
+            if self.is_enabled() && self.needs_network() && !self.is_online() {
+                // no point in trying while we can't reach the server
+                self.wait_online(delay);
+            } else if self.is_enabled() {
                 match self.run_sync() {
-                    Err(e) => error!("sync::runner() -- {}: main loop: {}", self.get_name(), e),
-                    _ => (),
+                    Err(e) => {
+                        error!("sync::runner() -- {}: main loop: {}", self.get_name(), e);
+                        // if we couldn't reach the server, find out if we're
+                        // offline before trying again
+                        if connectivity::is_network_error(&e) {
+                            connectivity::request_check(&self.get_config());
+                        }
+                        self.stats(|x| x.failure(&e));
+                        backoff.failure();
+                        let wait = backoff.delay(delay);
+                        warn!("sync::runner() -- {}: {} failure(s) in a row, backing off for {}ms", self.get_name(), backoff.failures(), wait);
+                        self.backoff_status(&backoff, wait);
+                        self.sleep(wait);
+                    }
+                    Ok(_) => {
+                        self.stats(|x| x.success());
+                        if backoff.success() {
+                            info!("sync::runner() -- {}: recovered", self.get_name());
+                            self.backoff_status(&backoff, delay);
+                        }
+                        self.idle(delay);
+                    }
                 }
-                util::sleep(delay);
             } else {
-                util::sleep(delay);
+                self.idle(delay);
             }
         }
     }
 
+    /// Update this syncer's counters
+    fn stats<F>(&self, update: F)
+        where F: FnOnce(&mut SyncerStats)
+    {
+        let stats = {
+            let local_config = self.get_config();
+            let guard = lockr!(local_config);
+            guard.stats.clone()
+        };
+        let mut stats_guard = lock!(stats);
+        update(stats_guard.syncer(self.get_name()));
+    }
+
+    /// Sleep for the given number of ms, waking up every so often to see if we
+    /// should quit (backoff delays can get long).
+    fn sleep(&self, millis: u64) {
+        let mut remaining = millis;
+        while remaining > 0 && !self.should_quit() {
+            let slice = if remaining > 1000 { 1000 } else { remaining };
+            util::sleep(slice);
+            remaining -= slice;
+        }
+    }
+
+    /// Let the UI know our backoff state changed
+    fn backoff_status(&self, backoff: &Backoff, delay: u64) {
+        messaging::ui_event("sync:status", &backoff.status(delay))
+            .unwrap_or_else(|e| error!("Syncer::backoff_status() -- error sending status event: {}", e));
+    }
+
     /// Let the main thread know that we've (dis)connected to the API. Useful
     /// for updating the UI on our connection state
     fn connected(&mut self, yesno: bool) {
@@ -233,24 +512,12 @@ pub trait Syncer {
     }
 }
 
-/// Start our syncing system!
+/// Start a syncer (by name, see `SYNCERS`, or `watchdog`) in its own thread.
+/// Returns the thread's handle, along with a channel that hears back from the
+/// syncer once it inits (or fails to).
 ///
-/// Note that we have separate db objects for in/out. This is because each
-/// thread needs its own connection. We don't have the ability to create the
-/// connections in this scope (no access to Turtl by design) so we need to
-/// just have them passed in.
-pub fn start(config: Arc<RwLock<SyncConfig>>, api: Arc<Api>, db: Arc<Mutex<Option<Storage>>>) -> TResult<SyncState> {
-    // enable syncing (set phasers to stun)
-    {
-        let mut config_guard = lockw!(config);
-        (*config_guard).enabled = true;
-        (*config_guard).quit = false;
-    }
-
-    // some holders for our thread handles and init receivers
-    let mut join_handles = Vec::with_capacity(4);
-    let mut rx_vec = Vec::with_capacity(4);
-
+/// If the syncer panics, we let the watchdog know so it can start it back up.
+pub fn spawn(name: &str, config: Arc<RwLock<SyncConfig>>, api: Arc<Api>, db: Arc<Mutex<Option<Storage>>>) -> TResult<(thread::JoinHandle<()>, mpsc::Receiver<TResult<()>>)> {
     /// Starts a sync class.
     macro_rules! sync_starter {
         ($synctype:expr) => {
@@ -259,27 +526,66 @@ pub fn start(config: Arc<RwLock<SyncConfig>>, api: Arc<Api>, db: Arc<Mutex<Optio
                 // thread back to here (mainly, a "yes init succeeded" or "no,
                 // init failed")
                 let (tx, rx) = mpsc::channel::<TResult<()>>();
-                rx_vec.push(rx);
-                let config_c = config.clone();
-                let api_c = api.clone();
-                let db_c = db.clone();
-                let mut sync = $synctype(config_c, api_c, db_c);
-                let handle = thread::Builder::new().name(format!("sync:{}", sync.get_name())).spawn(move || {
-                    sync.runner(tx);
-                    info!("sync::start() -- {} shut down (run {})", sync.get_name(), sync.get_run_version());
+                let heartbeats = {
+                    let guard = lockr!(config);
+                    guard.heartbeats.clone()
+                };
+                let mut sync = $synctype(config, api, db);
+                let name = sync.get_name();
+                let handle = thread::Builder::new().name(format!("sync:{}", name)).spawn(move || {
+                    let res = panic::catch_unwind(panic::AssertUnwindSafe(|| sync.runner(tx)));
+                    match res {
+                        Ok(_) => info!("sync::spawn() -- {} shut down (run {})", name, sync.get_run_version()),
+                        Err(_) => {
+                            error!("sync::spawn() -- {} panicked (run {})", name, sync.get_run_version());
+                            heartbeats.died(name, "panic");
+                        }
+                    }
                 })?;
-                // push our handle/rx onto their respective holder vecs
-                join_handles.push(handle);
+                Ok((handle, rx))
             }
         }
     }
 
     // i try to use the type without the ::new but drew *destroy the value* of
     // the macro!
-    sync_starter!(SyncOutgoing::new);
-    sync_starter!(SyncIncoming::new);
-    sync_starter!(FileSyncOutgoing::new);
-    sync_starter!(FileSyncIncoming::new);
+    match name {
+        "outgoing" => sync_starter!(SyncOutgoing::new),
+        "incoming" => sync_starter!(SyncIncoming::new),
+        "files:outgoing" => sync_starter!(FileSyncOutgoing::new),
+        "files:incoming" => sync_starter!(FileSyncIncoming::new),
+        "push" => sync_starter!(SyncPush::new),
+        "connectivity" => sync_starter!(SyncConnectivity::new),
+        "reminders" => sync_starter!(SyncReminders::new),
+        "watchdog" => sync_starter!(SyncWatchdog::new),
+        _ => TErr!(TError::BadValue(format!("unknown syncer: {}", name))),
+    }
+}
+
+/// Start our syncing system!
+///
+/// Note that we have separate db objects for in/out. This is because each
+/// thread needs its own connection. We don't have the ability to create the
+/// connections in this scope (no access to Turtl by design) so we need to
+/// just have them passed in.
+pub fn start(config: Arc<RwLock<SyncConfig>>, api: Arc<Api>, db: Arc<Mutex<Option<Storage>>>) -> TResult<SyncState> {
+    // enable syncing (set phasers to stun)
+    {
+        let mut config_guard = lockw!(config);
+        (*config_guard).enabled = true;
+        (*config_guard).quit = false;
+        // forget about any syncers from the last run
+        (*config_guard).heartbeats.clear();
+    }
+
+    // some holders for our thread handles and init receivers
+    let mut join_handles = Vec::with_capacity(SYNCERS.len() + 1);
+    let mut rx_vec = Vec::with_capacity(SYNCERS.len() + 1);
+    for name in SYNCERS.iter().chain(["watchdog"].iter()) {
+        let (handle, rx) = spawn(name, config.clone(), api.clone(), db.clone())?;
+        join_handles.push(handle);
+        rx_vec.push(rx);
+    }
 
     // seems to make the sync "ready!!" channels not bitch as much. if we don't
     // have this here, we get a lot of:
@@ -302,6 +608,8 @@ pub fn start(config: Arc<RwLock<SyncConfig>>, api: Arc<Api>, db: Arc<Mutex<Optio
         let mut guard = lockw!(config1);
         guard.enabled = false;
         guard.quit = true;
+        // don't leave the incoming syncer waiting on a push that'll never come
+        guard.push_signal.notify();
     };
     let config2 = config.clone();
     let pause = move || {
@@ -318,6 +626,32 @@ pub fn start(config: Arc<RwLock<SyncConfig>>, api: Arc<Api>, db: Arc<Mutex<Optio
         let guard = lockr!(config4);
         guard.enabled
     };
+    let config5 = config.clone();
+    let pause_syncers = move |names: &Vec<&'static str>| {
+        let mut guard = lockw!(config5);
+        for name in names { guard.paused.insert(String::from(*name)); }
+    };
+    let config6 = config.clone();
+    let resume_syncers = move |names: &Vec<&'static str>| {
+        let mut guard = lockw!(config6);
+        for name in names { guard.paused.remove(*name); }
+    };
+    let config7 = config.clone();
+    let paused = move || -> Vec<String> {
+        let guard = lockr!(config7);
+        let mut paused = guard.paused.iter().map(|x| x.clone()).collect::<Vec<_>>();
+        paused.sort();
+        paused
+    };
+    let config8 = config.clone();
+    let stats = move || -> SyncStats {
+        let stats = {
+            let guard = lockr!(config8);
+            guard.stats.clone()
+        };
+        let stats_guard = lock!(stats);
+        stats_guard.clone()
+    };
 
     // Wait on an "OK! A++++" Ok(()) signal from the sync thread (sent after it
     // inits successfully) or a "SHITFUCK!" Err() if there was a problem.
@@ -350,6 +684,10 @@ pub fn start(config: Arc<RwLock<SyncConfig>>, api: Arc<Api>, db: Arc<Mutex<Optio
         pause: Box::new(pause),
         resume: Box::new(resume),
         enabled: Box::new(enabled),
+        pause_syncers: Box::new(pause_syncers),
+        resume_syncers: Box::new(resume_syncers),
+        paused: Box::new(paused),
+        stats: Box::new(stats),
     })
 }
 
@@ -378,6 +716,21 @@ mod tests {
         assert_eq!(syncstr, String::from(r#"{"id":"1234","body":null,"action":"add","item_id":"6969","user_id":1,"type":"note","data":{"id":"6969"},"errcount":0,"frozen":false,"blocked":false}"#));
     }
 
+    #[test]
+    fn signals() {
+        let signal = Arc::new(SyncSignal::new());
+        assert!(!signal.wait(10));
+        signal.notify();
+        assert!(signal.wait(10));
+        assert!(!signal.wait(10));
+
+        let signal2 = signal.clone();
+        let handle = thread::spawn(move || signal2.wait(5000));
+        util::sleep(50);
+        signal.notify();
+        assert!(handle.join().unwrap());
+    }
+
     #[test]
     fn starts_and_quits() {
         let mut sync_config = SyncConfig::new();
@@ -386,6 +739,11 @@ mod tests {
         let api = Arc::new(Api::new());
         let db = Arc::new(Mutex::new(Some(Storage::new(&String::from(":memory:"), json!({})).unwrap())));
         let mut state = start(sync_config, api, db).unwrap();
+        (state.pause_syncers)(&syncers_for("files").unwrap());
+        assert_eq!((state.paused)(), vec!["files:incoming", "files:outgoing"]);
+        (state.resume_syncers)(&syncers_for("files:incoming").unwrap());
+        assert_eq!((state.paused)(), vec!["files:outgoing"]);
+        assert!(syncers_for("lol").is_err());
         (state.shutdown)();
         loop {
             let hn = state.join_handles.pop();
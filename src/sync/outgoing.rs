@@ -1,10 +1,20 @@
 use ::std::sync::{Arc, RwLock, Mutex};
+use ::std::collections::HashSet;
+use ::std::time::{Duration, Instant};
 
 use ::jedi;
 
 use ::error::TResult;
 use ::sync::{SyncConfig, Syncer};
 use ::sync::incoming::SyncIncoming;
+use ::sync::progress::SyncProgress;
+use ::sync::conflict;
+use ::sync::compact;
+use ::sync::delta;
+use ::sync::seal;
+use ::sync::hooks;
+use ::sync::lanes::{self, LanePolicy};
+use ::sync::dry_run::{DryRunReport, DryRunReporter};
 use ::storage::Storage;
 use ::api::{Api, ApiReq};
 use ::messaging;
@@ -39,6 +49,12 @@ pub struct SyncOutgoing {
 
     /// Stores our syn run version
     run_version: i64,
+
+    /// Sends our dry-run reports (if we're in dry-run mode)
+    dry_runs: DryRunReporter,
+
+    /// When we last compacted the sync bookkeeping (see `sync::compact`)
+    last_compact: Option<Instant>,
 }
 
 impl SyncOutgoing {
@@ -49,23 +65,62 @@ impl SyncOutgoing {
             api: api,
             db: db,
             run_version: 0,
+            dry_runs: DryRunReporter::new(),
+            last_compact: None,
         }
     }
 
-    /// Grab all non-file outgoing sync items, in order
+    /// Grab the next batch of non-file outgoing sync items. These all go out
+    /// together in one bulk call to the API, in the order the priority lanes
+    /// pick them (see `sync::lanes`).
+    ///
+    /// File uploads run separately (see `sync::files::outgoing`), but we don't
+    /// let a record jump ahead of an upload for the same item. Otherwise we
+    /// could, say, delete a note on the server while its file is still waiting
+    /// to go up, which would fail the upload and freeze the file queue. Records
+    /// for items with uploads pending are held (along with anything related to
+    /// them) until the upload finishes, and everything else keeps moving.
     fn get_outgoing_syncs(&self) -> TResult<Vec<SyncRecord>> {
         let syncs = with_db!{ db, self.db,
-            SyncRecord::allbut(db, &vec![SyncType::FileOutgoing, SyncType::FileIncoming])
+            SyncRecord::allbut(db, &vec![SyncType::FileIncoming])
         }?;
 
-        // stop at our first frozen record! this creates a "block" that must be
-        // cleared before syncing can continue.
-        let mut final_syncs = Vec::with_capacity(syncs.len());
+        let mut uploading: HashSet<String> = HashSet::new();
+        let mut queued = Vec::with_capacity(syncs.len());
         for sync in syncs {
+            if sync.ty == SyncType::FileOutgoing {
+                if !sync.frozen { uploading.insert(sync.item_id.clone()); }
+                continue;
+            }
+            // stop at our first frozen record! this creates a "block" that
+            // must be cleared before syncing can continue.
             if sync.frozen { break; }
-            final_syncs.push(sync);
+            // a record waiting out a retry delay holds up everything after it
+            // (but only until the delay passes)
+            if sync.waiting() { break; }
+            if uploading.contains(&sync.item_id) {
+                debug!("SyncOutgoing.get_outgoing_syncs() -- holding {:?} for {} until its file uploads", sync.action, sync.item_id);
+            }
+            queued.push(sync);
         }
-        Ok(final_syncs)
+        let queued = seal::unseal_all(queued)?;
+        lanes::schedule(queued, &uploading, &LanePolicy::from_config())
+    }
+
+    /// Compact our sync bookkeeping if it's been a while
+    fn maybe_compact(&mut self) -> TResult<()> {
+        let due = match self.last_compact {
+            Some(x) => x.elapsed() >= Duration::from_millis(compact::interval()),
+            None => true,
+        };
+        if !due { return Ok(()); }
+        self.last_compact = Some(Instant::now());
+        let user_id = {
+            let guard = lockr!(self.config);
+            guard.user_id.clone()
+        };
+        with_db!{ db, self.db, compact::compact(db, user_id.as_ref()) }?;
+        Ok(())
     }
 
     /// Delete a sync record from sync (like, when we send it to the API and it
@@ -73,20 +128,40 @@ impl SyncOutgoing {
     fn delete_sync_record(&self, sync: &SyncRecord) -> TResult<()> {
         let noid = String::from("<no id>");
         debug!("SyncOutgoing.delete_sync_record() -- delete {} ({:?} / {:?})", sync.id.as_ref().unwrap_or(&noid), sync.action, sync.ty);
-        with_db!{ db, self.db, db.delete(sync)?; }
+        with_db!{ db, self.db,
+            db.delete(sync)?;
+            // the server has our edit now, so we no longer need the version
+            // it was based on
+            if sync.ty == SyncType::Note { conflict::clear_base(db, &sync.item_id)?; }
+        }
         Ok(())
     }
 
     /// Handle each failed sync record, and notify the UI that we have failed
     /// sync items that might need inspection/alerting.
-    fn handle_sync_failures(&self, fail: &Vec<SyncRecord>) -> TResult<()> {
-        for failure in fail {
+    ///
+    /// Records the API refused on permission grounds get an explanation of the
+    /// refusal attached to their error before they're saved/sent to the UI.
+    fn handle_sync_failures(&self, fail: &mut Vec<SyncRecord>) -> TResult<()> {
+        let user_id = {
+            let config_guard = lockr!(self.config);
+            config_guard.user_id.clone()
+        };
+        for failure in fail.iter_mut() {
             let errmsg = match failure.error.as_ref() {
                 Some(err) => err.msg.clone(),
                 None => String::from("<blank error>"),
             };
             warn!("SyncOutgoing.handle_sync_failures() -- failwhale: {:?}/{:?}: {}", failure.ty, failure.action, errmsg);
             with_db!{ db, self.db,
+                if let Some(user_id) = user_id.as_ref() {
+                    failure.explain_denial(db, user_id)?;
+                }
+                // if the server didn't like our delta, send the whole thing
+                // next time
+                if let Some(sync_id) = failure.id.as_ref() {
+                    delta::strip(db, sync_id)?;
+                }
                 SyncRecord::handle_failed_sync(db, failure)?;
             }
         }
@@ -116,16 +191,45 @@ impl Syncer for SyncOutgoing {
     }
 
     fn run_sync(&mut self) -> TResult<()> {
+        // compaction is just housekeeping, so don't let it hold up syncing.
+        // (and no db writes in dry-run mode)
+        if !self.dry_run() {
+            if let Err(e) = self.maybe_compact() {
+                error!("SyncOutgoing.run_sync() -- error compacting sync records: {}", e);
+            }
+        }
         // get all our sync records queued to be sent out
-        let syncs = self.get_outgoing_syncs()?;
+        let mut syncs = self.get_outgoing_syncs()?;
+        if self.dry_run() {
+            let mut report = DryRunReport::new(self.get_name(), "send");
+            for sync in &syncs {
+                report.add(sync, DryRunReport::record_size(sync)?)?;
+            }
+            return self.dry_runs.report(report);
+        }
+        self.dry_runs.reset();
         if syncs.len() == 0 { return Ok(()); }
 
         // send our syncs out to the api, and remove and successful records from
         // our local db
         info!("SyncOutgoing.run_sync() -- sending {} sync items", syncs.len());
+        let mut progress = SyncProgress::new(self.get_name(), "upload", &syncs)?;
+        progress.emit(true)?;
+        let deltas = self.api.deltas_supported();
+        for sync in &mut syncs {
+            delta::prepare(sync, deltas);
+        }
+        hooks::before_outgoing_send(&syncs)?;
         let syncs_json = jedi::to_val(&syncs)?;
-        let sync_result: SyncResponse = self.api.post("/sync", ApiReq::new().timeout(120).data(syncs_json))?;
+        let bytes_up = jedi::stringify(&syncs_json)?.len() as u64;
+        let mut req = ApiReq::new().timeout(120).gzip().data(syncs_json);
+        if delta::enabled() {
+            req = req.header("X-Turtl-Delta", &String::from("1"));
+        }
+        let mut sync_result: SyncResponse = self.api.post("/sync", req)?;
+        self.stats(|x| x.up(sync_result.success.len() as u64, bytes_up));
         info!("SyncOutgoing.run_sync() -- got {} successes, {} failed, {} blocked syncs", sync_result.success.len(), sync_result.failures.len(), sync_result.blocked.len());
+        progress.phase("apply");
 
         // clear out the successful syncs
         let mut err: TResult<()> = Ok(());
@@ -146,11 +250,16 @@ impl Syncer for SyncOutgoing {
             // just because one of them failed to delete.
             if res.is_err() && err.is_ok() { err = res; }
             if res2.is_err() && err.is_ok() { err = res2; }
+            progress.tick(&sync.ty)?;
+            progress.emit(false)?;
         }
 
         if sync_result.failures.len() > 0 {
-            self.handle_sync_failures(&sync_result.failures)?;
+            self.handle_sync_failures(&mut sync_result.failures)?;
         }
+        progress.phase("done");
+        progress.complete();
+        progress.emit(true)?;
 
         // let the ui know we had an outgoing sync. there are cases where it
         // will want to know this happened.
@@ -170,6 +279,7 @@ mod tests {
     use ::models::sync_record::SyncRecord;
     use ::jedi;
     use ::schema;
+    use ::models::space::Space;
 
     #[test]
     fn ignores_frozen_syncs() {
@@ -558,5 +668,31 @@ mod tests {
         assert_eq!(res.failures.len(), 1);
         assert_eq!(res.blocked.len(), 8);
     }
+
+    #[test]
+    fn explains_permission_rejections() {
+        let mut db = Storage::new(&String::from(":memory:"), schema::get_schema()).unwrap();
+        let space: Space = jedi::from_val(json!({
+            "id": "1234",
+            "user_id": 99,
+            "members": [{"id": 5, "user_id": "12", "space_id": "1234", "username": "slappy@turtlapp.com", "role": "guest", "created": "", "updated": ""}],
+        })).unwrap();
+        db.save(&space).unwrap();
+
+        let mut sync: SyncRecord = jedi::from_val(json!({"id": "4", "action": "add", "item_id": "69", "user_id": 12, "type": "note", "data": {"id": "69", "space_id": "1234"}, "error": {"code": 403, "msg": "nope"}})).unwrap();
+        sync.explain_denial(&mut db, &String::from("12")).unwrap();
+        {
+            let denial = sync.error.as_ref().unwrap().permission.as_ref().unwrap();
+            assert_eq!(denial.action, Some(String::from("AddNote")));
+            assert_eq!(denial.item, Some(String::from("1234")));
+            assert_eq!(denial.current_role, Some(String::from("guest")));
+            assert!(denial.required_role.is_some());
+        }
+
+        // not a permission error, nothing to explain
+        let mut sync: SyncRecord = jedi::from_val(json!({"id": "5", "action": "add", "item_id": "70", "user_id": 12, "type": "note", "data": {"id": "70", "space_id": "1234"}, "error": {"code": 500, "msg": "lol"}})).unwrap();
+        sync.explain_denial(&mut db, &String::from("12")).unwrap();
+        assert!(sync.error.as_ref().unwrap().permission.is_none());
+    }
 }
 
@@ -0,0 +1,140 @@
+//! Tracks how far along a sync run is so the UI can show a progress bar. Sent
+//! to the UI as `sync:progress` events.
+
+use ::std::collections::HashMap;
+use ::error::TResult;
+use ::messaging;
+use ::models::sync_record::{SyncRecord, SyncType};
+use ::util;
+
+/// Progress for one type of sync record
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct TypeProgress {
+    pub total: u64,
+    pub processed: u64,
+}
+
+/// Progress for a sync run
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SyncProgress {
+    /// The syncer doing the work (incoming, outgoing)
+    pub syncer: String,
+    /// What the syncer is currently doing (download, apply, upload, done)
+    pub phase: String,
+    /// How many records this run covers
+    pub total: u64,
+    /// How many records we've gotten through
+    pub processed: u64,
+    /// 0-100
+    pub percent: u8,
+    /// Counts broken down by record type
+    pub types: HashMap<String, TypeProgress>,
+    /// The last percent we told the UI about, so we don't flood it
+    #[serde(skip)]
+    last_sent: Option<u8>,
+}
+
+impl SyncProgress {
+    /// Start tracking progress for a set of records
+    pub fn new(syncer: &str, phase: &str, records: &Vec<SyncRecord>) -> TResult<Self> {
+        let mut types: HashMap<String, TypeProgress> = HashMap::new();
+        for rec in records {
+            types.entry(util::enum_to_string(&rec.ty)?).or_insert_with(Default::default).total += 1;
+        }
+        let mut progress = SyncProgress {
+            syncer: String::from(syncer),
+            phase: String::from(phase),
+            total: records.len() as u64,
+            processed: 0,
+            percent: 0,
+            types: types,
+            last_sent: None,
+        };
+        progress.update_percent();
+        Ok(progress)
+    }
+
+    /// Move on to a new phase. Resets our processed counts.
+    pub fn phase(&mut self, phase: &str) {
+        self.phase = String::from(phase);
+        self.processed = 0;
+        for (_, ty) in self.types.iter_mut() { ty.processed = 0; }
+        self.last_sent = None;
+        self.update_percent();
+    }
+
+    /// Mark a record of the given type as processed
+    pub fn tick(&mut self, ty: &SyncType) -> TResult<()> {
+        self.processed += 1;
+        if let Some(x) = self.types.get_mut(&util::enum_to_string(ty)?) {
+            x.processed += 1;
+        }
+        self.update_percent();
+        Ok(())
+    }
+
+    /// Mark everything in the current phase as processed
+    pub fn complete(&mut self) {
+        self.processed = self.total;
+        for (_, ty) in self.types.iter_mut() { ty.processed = ty.total; }
+        self.update_percent();
+    }
+
+    fn update_percent(&mut self) {
+        self.percent = if self.total == 0 {
+            100
+        } else {
+            ((self.processed * 100) / self.total) as u8
+        };
+    }
+
+    /// Send our progress to the UI, but only if the percent has moved since
+    /// the last time we did (or if `force` is set).
+    pub fn emit(&mut self, force: bool) -> TResult<()> {
+        if !force && self.last_sent == Some(self.percent) { return Ok(()); }
+        self.last_sent = Some(self.percent);
+        messaging::ui_event("sync:progress", self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ::jedi;
+
+    #[test]
+    fn tracks_progress() {
+        let records: Vec<SyncRecord> = jedi::parse(&String::from(r#"[
+            {"id":"1","action":"add","item_id":"a","user_id":1,"type":"note"},
+            {"id":"2","action":"add","item_id":"b","user_id":1,"type":"note"},
+            {"id":"3","action":"edit","item_id":"c","user_id":1,"type":"board"},
+            {"id":"4","action":"delete","item_id":"d","user_id":1,"type":"space"}
+        ]"#)).unwrap();
+        let mut progress = SyncProgress::new("incoming", "apply", &records).unwrap();
+        assert_eq!(progress.total, 4);
+        assert_eq!(progress.percent, 0);
+        assert_eq!(progress.types.get("note").unwrap().total, 2);
+
+        progress.tick(&SyncType::Note).unwrap();
+        progress.tick(&SyncType::Board).unwrap();
+        assert_eq!(progress.percent, 50);
+        assert_eq!(progress.types.get("note").unwrap().processed, 1);
+        assert_eq!(progress.types.get("board").unwrap().processed, 1);
+        // types we aren't tracking don't count against anyone
+        progress.tick(&SyncType::Invite).unwrap();
+        assert_eq!(progress.types.get("invite"), None);
+
+        progress.complete();
+        assert_eq!(progress.percent, 100);
+        assert_eq!(progress.types.get("space").unwrap().processed, 1);
+
+        progress.phase("done");
+        assert_eq!(progress.processed, 0);
+        assert_eq!(progress.types.get("note").unwrap().processed, 0);
+
+        let empty = SyncProgress::new("outgoing", "upload", &Vec::new()).unwrap();
+        assert_eq!(empty.percent, 100);
+    }
+}
+
@@ -0,0 +1,186 @@
+//! The push syncer holds open a websocket to the Turtl server, which tells us
+//! whenever there's new data waiting for us. When we get a push, we poke the
+//! incoming syncer so it grabs the changes right away instead of waiting on its
+//! long-poll.
+//!
+//! If the socket drops (or we can't connect in the first place), we mark push
+//! as disconnected and the incoming syncer goes back to polling until we get
+//! our connection back.
+
+use ::std::sync::{Arc, RwLock, Mutex};
+use ::std::io::ErrorKind;
+use ::std::net::TcpStream;
+use ::std::time::Duration;
+use ::std::borrow::Cow;
+use ::tungstenite::{self, WebSocket, Message};
+use ::tungstenite::client::AutoStream;
+use ::tungstenite::handshake::client::Request;
+use ::tungstenite::stream::Stream;
+use ::url::Url;
+use ::error::{TResult, TError};
+use ::sync::{SyncConfig, SyncSignal, Syncer};
+use ::storage::Storage;
+use ::api::Api;
+use ::config;
+
+/// Holds the state for our push connection
+pub struct SyncPush {
+    /// Holds our sync config. Note that this is shared between the sync system
+    /// and the `Turtl` object in the main thread.
+    config: Arc<RwLock<SyncConfig>>,
+
+    /// Holds our Api object. We don't make API calls, but we do need its auth.
+    api: Arc<Api>,
+
+    /// Stores our syn run version
+    run_version: i64,
+}
+
+impl SyncPush {
+    /// Create a new push syncer
+    pub fn new(config: Arc<RwLock<SyncConfig>>, api: Arc<Api>, _db: Arc<Mutex<Option<Storage>>>) -> Self {
+        SyncPush {
+            config: config,
+            api: api,
+            run_version: 0,
+        }
+    }
+
+    /// Figure out where our push socket lives. Uses `sync.push.endpoint` if
+    /// set, otherwise we build it off of the api endpoint.
+    fn endpoint(&self) -> TResult<Url> {
+        let endpoint = match config::get::<String>(&["sync", "push", "endpoint"]) {
+            Ok(x) => x,
+            Err(_) => {
+                let api_endpoint = config::get::<String>(&["api", "endpoint"])?;
+                let ws_endpoint = if api_endpoint.starts_with("https://") {
+                    api_endpoint.replacen("https://", "wss://", 1)
+                } else {
+                    api_endpoint.replacen("http://", "ws://", 1)
+                };
+                format!("{}/sync/push", ws_endpoint.trim_right_matches('/'))
+            }
+        };
+        Ok(Url::parse(endpoint.as_str())?)
+    }
+
+    /// Grab the signal we use to wake up the incoming syncer
+    fn signal(&self) -> Arc<SyncSignal> {
+        let guard = lockr!(self.config);
+        guard.push_signal.clone()
+    }
+
+    /// Mark our push connection as up/down. Either way, we wake the incoming
+    /// syncer: if we just connected, there may be changes from while we were
+    /// down, and if we disconnected it needs to go back to polling.
+    fn set_push_connected(&self, yesno: bool) {
+        let signal = {
+            let mut guard = lockw!(self.config);
+            guard.push_connected = yesno;
+            guard.push_signal.clone()
+        };
+        signal.notify();
+    }
+
+    /// Connect to the server's push socket
+    fn connect(&self) -> TResult<WebSocket<AutoStream>> {
+        let auth = match self.api.auth_header() {
+            Some(x) => x,
+            None => return TErr!(TError::MissingData(String::from("no auth set on the api, can't connect to push"))),
+        };
+        let mut req = Request::from(self.endpoint()?);
+        req.add_header(Cow::from("Authorization"), Cow::from(auth));
+        let (socket, _) = tungstenite::connect(req)?;
+        // we want our reads to time out every so often so we can check if we
+        // should be quitting
+        let read_timeout = Some(Duration::from_millis(1000));
+        match socket.get_ref() {
+            &Stream::Plain(ref tcp) => tcp.set_read_timeout(read_timeout)?,
+            &Stream::Tls(ref tls) => {
+                let tcp: &TcpStream = tls.get_ref();
+                tcp.set_read_timeout(read_timeout)?
+            }
+        }
+        Ok(socket)
+    }
+
+    /// Read from our socket until it drops or we're told to stop, waking the
+    /// incoming syncer on any push we get.
+    fn listen(&self, socket: &mut WebSocket<AutoStream>) -> TResult<()> {
+        let signal = self.signal();
+        while !self.should_quit() && self.is_enabled() {
+            match socket.read_message() {
+                Ok(Message::Text(_)) | Ok(Message::Binary(_)) => {
+                    debug!("SyncPush.listen() -- got push, triggering incoming sync");
+                    signal.notify();
+                }
+                // tungstenite answers pings for us
+                Ok(_) => {}
+                Err(tungstenite::Error::Io(ref e)) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    // flush any pongs tungstenite has queued up
+                    match socket.write_pending() {
+                        Ok(_) => {}
+                        Err(tungstenite::Error::Io(ref e)) if e.kind() == ErrorKind::WouldBlock => {}
+                        Err(e) => return Err(toterr!(e)),
+                    }
+                }
+                Err(tungstenite::Error::ConnectionClosed(_)) => {
+                    info!("SyncPush.listen() -- server closed push connection");
+                    return Ok(());
+                }
+                Err(e) => return Err(toterr!(e)),
+            }
+        }
+        match socket.close(None) {
+            Ok(_) => {}
+            Err(e) => debug!("SyncPush.listen() -- problem closing push socket: {}", e),
+        }
+        Ok(())
+    }
+}
+
+impl Syncer for SyncPush {
+    fn get_name(&self) -> &'static str {
+        "push"
+    }
+
+    fn get_config(&self) -> Arc<RwLock<SyncConfig>> {
+        self.config.clone()
+    }
+
+    fn set_run_version(&mut self, run_version: i64) {
+        self.run_version = run_version;
+    }
+
+    fn get_run_version(&self) -> i64 {
+        self.run_version
+    }
+
+    /// How long we wait between reconnect attempts
+    fn get_delay(&self) -> u64 {
+        config::get(&["sync", "push", "reconnect_delay"]).unwrap_or(30000)
+    }
+
+    /// A live push connection is what lets everyone else slow down, so we
+    /// stick to our reconnect delay
+    fn adaptive(&self) -> bool {
+        false
+    }
+
+    fn run_sync(&mut self) -> TResult<()> {
+        let skip = {
+            let guard = lockr!(self.config);
+            guard.skip_api_init || guard.dry_run
+        };
+        if skip { return Ok(()); }
+
+        let mut socket = self.connect()?;
+        info!("SyncPush.run_sync() -- push connected");
+        self.set_push_connected(true);
+        let res = self.listen(&mut socket);
+        info!("SyncPush.run_sync() -- push disconnected, incoming sync falling back to polling");
+        self.set_push_connected(false);
+        res
+    }
+}
+
@@ -0,0 +1,281 @@
+//! Fires reminders for notes. A note with its (private) `reminder_at` set gets
+//! a `note:reminder` UI event once that time comes around.
+//!
+//! Since `reminder_at` is encrypted with the note, the schedule is kept
+//! separately (by note id, in the kv store) and updated in the main thread
+//! whenever a note is loaded into memory or changes. That way the reminders
+//! syncer can check it every `sync.reminders.interval` ms without decrypting
+//! anything, and a reminder that comes due while the app is closed fires the
+//! next time it starts.
+//!
+//! A reminder that has fired sticks around until it's dismissed (or snoozed,
+//! which sets it to fire again later).
+
+use ::std::collections::HashSet;
+use ::std::sync::{Arc, RwLock, Mutex};
+use ::jedi;
+use ::config;
+use ::error::{TResult, TError};
+use ::messaging;
+use ::storage::Storage;
+use ::api::Api;
+use ::models::model::Model;
+use ::models::note::Note;
+use ::sync::{SyncConfig, Syncer};
+use ::turtl::Turtl;
+use ::time;
+
+/// A scheduled reminder
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Reminder {
+    pub note_id: String,
+    /// The note's `reminder_at` (unix seconds)
+    pub reminder_at: i64,
+    /// When we fire (unix seconds). Later than `reminder_at` if snoozed.
+    pub fire_at: i64,
+    /// Whether we've let the UI know
+    pub fired: bool,
+    /// Whether the user is done with it
+    pub dismissed: bool,
+}
+
+impl Reminder {
+    fn new(note_id: &String, reminder_at: i64) -> Self {
+        Reminder {
+            note_id: note_id.clone(),
+            reminder_at: reminder_at,
+            fire_at: reminder_at,
+            fired: false,
+            dismissed: false,
+        }
+    }
+
+    /// Whether this reminder should fire at the given time
+    fn is_due(&self, now: i64) -> bool {
+        !self.fired && !self.dismissed && self.fire_at <= now
+    }
+}
+
+/// The current time, in unix seconds
+fn now() -> i64 {
+    time::get_time().sec as i64
+}
+
+/// The kv key we keep a note's reminder under
+fn reminder_key(note_id: &String) -> String {
+    format!("reminders:{}", note_id)
+}
+
+/// Grab a note's reminder
+fn get(db: &Storage, note_id: &String) -> TResult<Option<Reminder>> {
+    match db.kv_get(&reminder_key(note_id))? {
+        Some(x) => Ok(Some(jedi::parse(&x)?)),
+        None => Ok(None),
+    }
+}
+
+/// Save a reminder
+fn save(db: &Storage, reminder: &Reminder) -> TResult<()> {
+    db.kv_set(&reminder_key(&reminder.note_id), &jedi::stringify(reminder)?)
+}
+
+/// Grab all our reminders
+fn all(db: &Storage) -> TResult<Vec<Reminder>> {
+    let mut reminders = Vec::new();
+    for key in db.kv_keys(&reminder_key(&String::new()))? {
+        match db.kv_get(&key)? {
+            Some(x) => reminders.push(jedi::parse::<Reminder>(&x)?),
+            None => {}
+        }
+    }
+    Ok(reminders)
+}
+
+/// The time a (decrypted) note wants to be reminded at, if any. Trashed notes
+/// don't get reminders.
+fn reminder_at(note: &Note) -> Option<i64> {
+    if note.trashed.is_some() { return None; }
+    note.reminder_at
+}
+
+/// Update the schedule for a note. Setting a new time (or clearing it) resets
+/// the reminder. Same time, same reminder (snoozes and all).
+pub fn update(db: &Storage, note_id: &String, reminder_at: Option<i64>) -> TResult<()> {
+    let reminder_at = match reminder_at {
+        Some(x) => x,
+        None => return db.kv_delete(&reminder_key(note_id)),
+    };
+    match get(db, note_id)? {
+        Some(ref x) if x.reminder_at == reminder_at => Ok(()),
+        _ => save(db, &Reminder::new(note_id, reminder_at)),
+    }
+}
+
+/// Update the schedule for a (decrypted) note
+pub fn update_note(db: &Storage, note: &Note) -> TResult<()> {
+    update(db, &note.id_or_else()?, reminder_at(note))
+}
+
+/// Bring the schedule in line with a set of (decrypted) notes, dropping
+/// reminders for notes we don't have anymore. The notes don't have to be all
+/// of them (we open one space at a time), so anything else is checked against
+/// the db.
+pub fn refresh(db: &Storage, notes: &Vec<Note>) -> TResult<()> {
+    let mut note_ids = HashSet::new();
+    for note in notes {
+        update_note(db, note)?;
+        note_ids.insert(note.id_or_else()?);
+    }
+    let others = all(db)?.into_iter()
+        .map(|x| x.note_id)
+        .filter(|x| !note_ids.contains(x))
+        .collect::<Vec<_>>();
+    if others.len() == 0 { return Ok(()); }
+    let existing = db.by_id::<Note>("notes", &others)?.into_iter()
+        .filter_map(|x| x.id().map(|id| id.clone()))
+        .collect::<HashSet<_>>();
+    for note_id in others {
+        if existing.contains(&note_id) { continue; }
+        db.kv_delete(&reminder_key(&note_id))?;
+    }
+    Ok(())
+}
+
+/// List the reminders that haven't been dismissed, soonest first
+pub fn list(turtl: &Turtl) -> TResult<Vec<Reminder>> {
+    let mut reminders = with_db!{ db, turtl.db, all(db)? };
+    reminders.retain(|x| !x.dismissed);
+    reminders.sort_by_key(|x| x.fire_at);
+    Ok(reminders)
+}
+
+/// Load a reminder or error
+fn get_or_else(db: &Storage, note_id: &String) -> TResult<Reminder> {
+    match get(db, note_id)? {
+        Some(x) => Ok(x),
+        None => TErr!(TError::NotFound(format!("note {} has no reminder", note_id))),
+    }
+}
+
+/// Have a note's reminder fire again at the given time (unix seconds)
+pub fn snooze(turtl: &Turtl, note_id: &String, until: i64) -> TResult<Reminder> {
+    with_db!{ db, turtl.db,
+        let mut reminder = get_or_else(db, note_id)?;
+        reminder.fire_at = until;
+        reminder.fired = false;
+        reminder.dismissed = false;
+        save(db, &reminder)?;
+        Ok(reminder)
+    }
+}
+
+/// Be done with a note's reminder
+pub fn dismiss(turtl: &Turtl, note_id: &String) -> TResult<Reminder> {
+    with_db!{ db, turtl.db,
+        let mut reminder = get_or_else(db, note_id)?;
+        reminder.dismissed = true;
+        save(db, &reminder)?;
+        Ok(reminder)
+    }
+}
+
+/// Fires our reminders
+pub struct SyncReminders {
+    /// Holds our sync config. Note that this is shared between the sync system
+    /// and the `Turtl` object in the main thread.
+    config: Arc<RwLock<SyncConfig>>,
+
+    /// Holds our user-specific db. This is mainly for persisting k/v data (our
+    /// reminder schedule).
+    db: Arc<Mutex<Option<Storage>>>,
+
+    /// Stores our syn run version
+    run_version: i64,
+}
+
+impl SyncReminders {
+    /// Create a new reminders syncer
+    pub fn new(config: Arc<RwLock<SyncConfig>>, _api: Arc<Api>, db: Arc<Mutex<Option<Storage>>>) -> Self {
+        SyncReminders {
+            config: config,
+            db: db,
+            run_version: 0,
+        }
+    }
+}
+
+impl Syncer for SyncReminders {
+    fn get_name(&self) -> &'static str {
+        "reminders"
+    }
+
+    fn get_config(&self) -> Arc<RwLock<SyncConfig>> {
+        self.config.clone()
+    }
+
+    fn get_delay(&self) -> u64 {
+        config::get(&["sync", "reminders", "interval"]).unwrap_or(15000)
+    }
+
+    fn adaptive(&self) -> bool {
+        false
+    }
+
+    fn set_run_version(&mut self, run_version: i64) {
+        self.run_version = run_version;
+    }
+
+    fn get_run_version(&self) -> i64 {
+        self.run_version
+    }
+
+    fn needs_network(&self) -> bool {
+        false
+    }
+
+    fn run_sync(&mut self) -> TResult<()> {
+        let now = now();
+        let due = with_db!{ db, self.db,
+            let mut due = Vec::new();
+            for mut reminder in all(db)? {
+                if !reminder.is_due(now) { continue; }
+                reminder.fired = true;
+                save(db, &reminder)?;
+                due.push(reminder);
+            }
+            due
+        };
+        for reminder in due {
+            messaging::ui_event("note:reminder", &reminder)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::schema;
+
+    #[test]
+    fn schedules_reminders() {
+        let db = Storage::new(&String::from(":memory:"), schema::get_schema()).unwrap();
+        let note_id = String::from("n1");
+        update(&db, &note_id, Some(100)).unwrap();
+        let mut reminder = get(&db, &note_id).unwrap().unwrap();
+        assert!(!reminder.is_due(99));
+        assert!(reminder.is_due(100));
+
+        // same time keeps the snooze, a new time resets it
+        reminder.fire_at = 500;
+        save(&db, &reminder).unwrap();
+        update(&db, &note_id, Some(100)).unwrap();
+        assert_eq!(get(&db, &note_id).unwrap().unwrap().fire_at, 500);
+        update(&db, &note_id, Some(200)).unwrap();
+        assert_eq!(get(&db, &note_id).unwrap().unwrap().fire_at, 200);
+
+        // notes we no longer have lose their reminders
+        refresh(&db, &Vec::new()).unwrap();
+        assert_eq!(get(&db, &note_id).unwrap(), None);
+    }
+}
@@ -0,0 +1,126 @@
+//! Decides how long syncers wait between runs. Right after the user does
+//! something (or brings the app to the foreground) we run at full speed, since
+//! that's when changes are flying around. The longer things stay quiet, the
+//! more we slow down, and while the app is in the background we barely poll at
+//! all. Polling every second on a phone sitting in someone's pocket is a great
+//! way to eat their battery.
+//!
+//! Each syncer's `get_delay()` is its full-speed delay, and the policy (in
+//! `SyncConfig.poll_policy`, from `sync.poll` in the config) stretches it.
+//! Local activity is reported via `touch()` (saving/deleting models does this)
+//! and the host app tells us about foreground/background changes via
+//! `sync:set-foreground`. Either one wakes idle syncers right away.
+
+use ::std::cmp;
+use ::std::sync::{Arc, RwLock};
+use ::std::time::Instant;
+use ::config;
+use ::sync::SyncConfig;
+
+/// How syncers pace themselves when things are quiet
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct PollPolicy {
+    /// How long (ms) after local activity we keep running at full speed
+    pub active_for: u64,
+    /// The slowest (ms) we get while the app is in the foreground
+    pub idle_delay: u64,
+    /// How long (ms) we wait between runs while the app is in the background
+    pub background_delay: u64,
+}
+
+impl Default for PollPolicy {
+    fn default() -> Self {
+        PollPolicy {
+            active_for: 60000,
+            idle_delay: 30000,
+            background_delay: 120000,
+        }
+    }
+}
+
+impl PollPolicy {
+    /// Load our policy from the config (`sync.poll`)
+    pub fn from_config() -> Self {
+        config::get(&["sync", "poll"]).unwrap_or(Default::default())
+    }
+
+    /// Given a syncer's full-speed delay and how long (ms) it's been since the
+    /// last local activity, figure out how long (ms) the syncer should wait.
+    /// Once we've been idle for `active_for`, the delay doubles every
+    /// `active_for` until it hits `idle_delay`.
+    pub fn delay(&self, base: u64, idle_ms: u64, foreground: bool) -> u64 {
+        if !foreground { return cmp::max(base, self.background_delay); }
+        if idle_ms < self.active_for { return base; }
+        let periods = idle_ms / cmp::max(self.active_for, 1);
+        let factor = 1u64 << cmp::min(periods, 32);
+        cmp::min(base.saturating_mul(factor), cmp::max(base, self.idle_delay))
+    }
+}
+
+/// Record some local activity, which puts the syncers back on full speed
+pub fn touch(config: &Arc<RwLock<SyncConfig>>) {
+    let mut guard = lockw!(config);
+    guard.last_activity = Instant::now();
+}
+
+/// Let the syncers know if the app is in the foreground. Coming back to the
+/// foreground counts as activity.
+pub fn set_foreground(config: &Arc<RwLock<SyncConfig>>, foreground: bool) {
+    let mut guard = lockw!(config);
+    if foreground && !guard.foreground { guard.last_activity = Instant::now(); }
+    guard.foreground = foreground;
+}
+
+/// How long (ms) a syncer with the given full-speed delay should wait before
+/// its next run
+pub fn next_delay(config: &Arc<RwLock<SyncConfig>>, base: u64) -> u64 {
+    let guard = lockr!(config);
+    let idle = guard.last_activity.elapsed();
+    let idle_ms = (idle.as_secs() * 1000) + (idle.subsec_nanos() as u64 / 1000000);
+    guard.poll_policy.delay(base, idle_ms, guard.foreground)
+}
+
+/// Whether there's been any activity since the given time
+pub fn active_since(config: &Arc<RwLock<SyncConfig>>, since: &Instant) -> bool {
+    let guard = lockr!(config);
+    guard.last_activity > *since
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::std::time::Duration;
+
+    #[test]
+    fn stretches_delays() {
+        let policy = PollPolicy::default();
+        // busy
+        assert_eq!(policy.delay(1000, 0, true), 1000);
+        assert_eq!(policy.delay(1000, 59999, true), 1000);
+        // quieting down
+        assert_eq!(policy.delay(1000, 60000, true), 2000);
+        assert_eq!(policy.delay(1000, 120000, true), 4000);
+        // asleep
+        assert_eq!(policy.delay(1000, 3600000, true), 30000);
+        assert_eq!(policy.delay(1000, 0, false), 120000);
+        // we never speed a syncer up
+        assert_eq!(policy.delay(60000, 3600000, true), 60000);
+        assert_eq!(policy.delay(300000, 0, false), 300000);
+    }
+
+    #[test]
+    fn wakes_on_activity() {
+        let config = Arc::new(RwLock::new(SyncConfig::new()));
+        assert_eq!(next_delay(&config, 1000), 1000);
+        lockw!(config).last_activity = Instant::now() - Duration::from_secs(600);
+        assert_eq!(next_delay(&config, 1000), 30000);
+        let since = Instant::now();
+        set_foreground(&config, false);
+        assert_eq!(next_delay(&config, 1000), 120000);
+        assert!(!active_since(&config, &since));
+        set_foreground(&config, true);
+        assert!(active_since(&config, &since));
+    }
+}
+
@@ -0,0 +1,271 @@
+//! Encrypts the outgoing sync queue at rest. Sync records carry a copy of the
+//! item they're syncing, and while the private bits of that are already
+//! encrypted, the public bits (space/board ids, tags-to-be, file hashes, etc)
+//! are not, so anyone holding the user's db could read off queued edits.
+//!
+//! When a sync record is saved, its `data` is encrypted into `sealed` using the
+//! queue key. The queue key is random, and lives in the kv store wrapped with
+//! the user's keypair. The keypair stays the same when the password changes,
+//! even if it's changed on another device while we have edits queued, so the
+//! queue stays readable across password changes. Accounts without a keypair
+//! get the queue key wrapped with the user's key instead, which means
+//! re-wrapping it when the password changes here (see `rewrap()`). Only the
+//! sync threads open records back up; the UI listings get the sealed version.
+//!
+//! The key is held globally (like the client id) since records get sealed all
+//! over the place. It's set on `Turtl::sync_start()` and dropped on logout.
+//! Records saved while it isn't set stay in plaintext, and get sealed the next
+//! time we unlock.
+
+use ::std::sync::RwLock;
+use ::jedi::{self, Value};
+use ::crypto::{self, Key, CryptoOp};
+use ::error::{TResult, TError};
+use ::storage::Storage;
+use ::models::model::Model;
+use ::models::sync_record::SyncRecord;
+
+/// The kv key our (wrapped) queue key lives under
+const KEY_KV: &'static str = "sync:queue_key";
+
+/// Marks a queue key wrapped with the user's keypair (as opposed to their key)
+const KEYPAIR_PREFIX: &'static str = "pk:";
+
+lazy_static! {
+    /// Holds the key for the current user's sync queue
+    static ref QUEUE_KEY: RwLock<Option<Key>> = RwLock::new(None);
+}
+
+/// Encrypt some data into a base64 string
+fn encrypt(key: &Key, data: Vec<u8>) -> TResult<String> {
+    let enc = crypto::encrypt(key, data, CryptoOp::new("chacha20poly1305")?)?;
+    Ok(crypto::to_base64(&enc)?)
+}
+
+/// Decrypt a base64 string
+fn decrypt(key: &Key, data: &String) -> TResult<Vec<u8>> {
+    Ok(crypto::decrypt(key, crypto::from_base64(data)?)?)
+}
+
+/// Wrap the queue key (with the user's keypair if they have one, otherwise
+/// their key) and store it
+fn save_key(db: &Storage, user_key: &Key, keypair: Option<(&Key, &Key)>, queue_key: &Key) -> TResult<()> {
+    let wrapped = match keypair {
+        Some((pubkey, _)) => {
+            let enc = crypto::asym::encrypt(pubkey, queue_key.data().clone())?;
+            format!("{}{}", KEYPAIR_PREFIX, crypto::to_base64(&enc)?)
+        }
+        None => encrypt(user_key, queue_key.data().clone())?,
+    };
+    db.kv_set(KEY_KV, &wrapped)
+}
+
+/// Unwrap a stored queue key
+fn unwrap_key(wrapped: &String, user_key: &Key, keypair: Option<(&Key, &Key)>) -> TResult<Key> {
+    if !wrapped.starts_with(KEYPAIR_PREFIX) {
+        return Ok(Key::new(decrypt(user_key, wrapped)?));
+    }
+    match keypair {
+        Some((pubkey, privkey)) => {
+            let enc = crypto::from_base64(&String::from(&wrapped[KEYPAIR_PREFIX.len()..]))?;
+            Ok(Key::new(crypto::asym::decrypt(pubkey, privkey, enc)?))
+        }
+        None => TErr!(TError::MissingData(String::from("the sync queue key is wrapped with a keypair we don't have"))),
+    }
+}
+
+/// Grab this db's queue key, making one if we don't have one yet. A key still
+/// wrapped with the user's key gets moved over to their keypair once they have
+/// one. If we can't unwrap the key we have (the keypair is gone, or the
+/// password was changed elsewhere on an account without one) the records
+/// sealed with it are lost to us, so we set them aside and start over with a
+/// new key.
+fn load_key(db: &mut Storage, user_key: &Key, keypair: Option<(&Key, &Key)>) -> TResult<Key> {
+    if let Some(wrapped) = db.kv_get(KEY_KV)? {
+        match unwrap_key(&wrapped, user_key, keypair) {
+            Ok(queue_key) => {
+                if keypair.is_some() && !wrapped.starts_with(KEYPAIR_PREFIX) {
+                    save_key(db, user_key, keypair, &queue_key)?;
+                }
+                return Ok(queue_key);
+            }
+            Err(e) => {
+                warn!("seal::load_key() -- can't unwrap the sync queue key, setting sealed records aside: {}", e);
+                set_aside(db)?;
+            }
+        }
+    }
+    let queue_key = Key::random()?;
+    save_key(db, user_key, keypair, &queue_key)?;
+    Ok(queue_key)
+}
+
+/// Move any sealed sync records out of the sync table (into the kv store, under
+/// `sync:unreadable:<id>`, same as the migrations do)
+fn set_aside(db: &mut Storage) -> TResult<()> {
+    for rec in SyncRecord::find(db, None)? {
+        if rec.sealed.is_none() { continue; }
+        let id = rec.id_or_else()?;
+        db.kv_set(&format!("sync:unreadable:{}", id), &jedi::stringify(&rec)?)?;
+        db.delete(&rec)?;
+    }
+    Ok(())
+}
+
+/// Encrypt a sync record's data with the given key
+fn seal_with(key: &Key, rec: &mut SyncRecord) -> TResult<()> {
+    let data = match rec.data.take() {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    rec.sealed = Some(encrypt(key, Vec::from(jedi::stringify(&data)?.as_bytes()))?);
+    Ok(())
+}
+
+/// Decrypt a sync record's data with the given key
+fn unseal_with(key: &Key, rec: &mut SyncRecord) -> TResult<()> {
+    let sealed = match rec.sealed.take() {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    let data: Value = jedi::parse(&String::from_utf8(decrypt(key, &sealed)?)?)?;
+    rec.data = Some(data);
+    Ok(())
+}
+
+/// Load (or create) the current user's queue key and seal any records that
+/// were saved in plaintext (queued by an older version of the app, or before
+/// we had a key). `keypair` is the user's (pubkey, privkey), if they have one.
+pub fn unlock(db: &mut Storage, user_key: &Key, keypair: Option<(&Key, &Key)>) -> TResult<()> {
+    let queue_key = load_key(db, user_key, keypair)?;
+    let mut sealed = 0;
+    for mut rec in SyncRecord::find(db, None)? {
+        if rec.data.is_none() { continue; }
+        seal_with(&queue_key, &mut rec)?;
+        db.save(&rec)?;
+        sealed += 1;
+    }
+    if sealed > 0 { info!("seal::unlock() -- sealed {} plaintext sync record(s)", sealed); }
+    let mut guard = lockw!((*QUEUE_KEY));
+    *guard = Some(queue_key);
+    Ok(())
+}
+
+/// Forget the queue key (on logout)
+pub fn lock() {
+    let mut guard = lockw!((*QUEUE_KEY));
+    *guard = None;
+}
+
+/// Re-wrap the queue key with the user's new key (after a password change). A
+/// queue key wrapped with the user's keypair doesn't care about the password,
+/// and is left alone.
+pub fn rewrap(db: &Storage, user_key: &Key) -> TResult<()> {
+    match db.kv_get(KEY_KV)? {
+        Some(ref wrapped) if wrapped.starts_with(KEYPAIR_PREFIX) => return Ok(()),
+        _ => {}
+    }
+    let guard = lockr!((*QUEUE_KEY));
+    match guard.as_ref() {
+        Some(queue_key) => save_key(db, user_key, None, queue_key),
+        None => Ok(()),
+    }
+}
+
+/// Seal a sync record's data before it goes into the db. If we don't have a
+/// queue key, the record is left alone.
+pub fn seal(rec: &mut SyncRecord) -> TResult<()> {
+    let guard = lockr!((*QUEUE_KEY));
+    match guard.as_ref() {
+        Some(key) => seal_with(key, rec),
+        None => Ok(()),
+    }
+}
+
+/// Open a sealed sync record back up
+pub fn unseal(rec: &mut SyncRecord) -> TResult<()> {
+    if rec.sealed.is_none() { return Ok(()); }
+    let guard = lockr!((*QUEUE_KEY));
+    match guard.as_ref() {
+        Some(key) => unseal_with(key, rec),
+        None => TErr!(TError::MissingData(format!("can't open sync record {:?}: the sync queue is locked", rec.id))),
+    }
+}
+
+/// Open a list of sealed sync records
+pub fn unseal_all(mut records: Vec<SyncRecord>) -> TResult<Vec<SyncRecord>> {
+    for rec in &mut records { unseal(rec)?; }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::schema;
+
+    #[test]
+    fn seals_and_unseals() {
+        let key = Key::random().unwrap();
+        let mut rec: SyncRecord = jedi::parse(&String::from(r#"{"id":"1","action":"edit","item_id":"n1","user_id":1,"type":"note","data":{"id":"n1","space_id":"s1"}}"#)).unwrap();
+        seal_with(&key, &mut rec).unwrap();
+        assert!(rec.data.is_none());
+        assert!(!rec.sealed.as_ref().unwrap().contains("s1"));
+        // only the right key opens it
+        let mut rec2: SyncRecord = jedi::parse(&jedi::stringify(&rec).unwrap()).unwrap();
+        assert!(unseal_with(&Key::random().unwrap(), &mut rec2).is_err());
+        unseal_with(&key, &mut rec).unwrap();
+        assert!(rec.sealed.is_none());
+        assert_eq!(jedi::get::<String>(&["space_id"], rec.data.as_ref().unwrap()).unwrap(), "s1");
+    }
+
+    #[test]
+    fn wraps_queue_key() {
+        let mut db = Storage::new(&String::from(":memory:"), schema::get_schema()).unwrap();
+        let user_key = Key::random().unwrap();
+        let queue_key = load_key(&mut db, &user_key, None).unwrap();
+        assert_eq!(load_key(&mut db, &user_key, None).unwrap().data(), queue_key.data());
+
+        // a key we can't unwrap takes its records down with it
+        let mut rec: SyncRecord = jedi::parse(&String::from(r#"{"id":"1","action":"edit","item_id":"n1","user_id":1,"type":"note","data":{"id":"n1"}}"#)).unwrap();
+        seal_with(&queue_key, &mut rec).unwrap();
+        db.save(&rec).unwrap();
+        let new_key = load_key(&mut db, &Key::random().unwrap(), None).unwrap();
+        assert!(new_key.data() != queue_key.data());
+        assert_eq!(SyncRecord::find(&mut db, None).unwrap().len(), 0);
+        assert!(db.kv_get("sync:unreadable:1").unwrap().is_some());
+    }
+
+    #[test]
+    fn survives_password_changes_elsewhere() {
+        let mut db = Storage::new(&String::from(":memory:"), schema::get_schema()).unwrap();
+        let (pk, sk) = crypto::asym::keygen().unwrap();
+        let old_user_key = Key::random().unwrap();
+        let queue_key = load_key(&mut db, &old_user_key, Some((&pk, &sk))).unwrap();
+        let mut rec: SyncRecord = jedi::parse(&String::from(r#"{"id":"1","action":"edit","item_id":"n1","user_id":1,"type":"note","data":{"id":"n1","space_id":"s1"}}"#)).unwrap();
+        seal_with(&queue_key, &mut rec).unwrap();
+        db.save(&rec).unwrap();
+
+        // the password was changed on another device, so all we have at our
+        // next login is the new key (and the same keypair)
+        let new_user_key = Key::random().unwrap();
+        rewrap(&db, &new_user_key).unwrap();
+        let reloaded = load_key(&mut db, &new_user_key, Some((&pk, &sk))).unwrap();
+        assert_eq!(reloaded.data(), queue_key.data());
+        let mut records = SyncRecord::find(&mut db, None).unwrap();
+        assert_eq!(records.len(), 1);
+        unseal_with(&reloaded, &mut records[0]).unwrap();
+        assert_eq!(jedi::get::<String>(&["space_id"], records[0].data.as_ref().unwrap()).unwrap(), "s1");
+        assert!(db.kv_get("sync:unreadable:1").unwrap().is_none());
+    }
+
+    #[test]
+    fn moves_queue_key_to_keypair() {
+        let mut db = Storage::new(&String::from(":memory:"), schema::get_schema()).unwrap();
+        let user_key = Key::random().unwrap();
+        let queue_key = load_key(&mut db, &user_key, None).unwrap();
+        let (pk, sk) = crypto::asym::keygen().unwrap();
+        assert_eq!(load_key(&mut db, &user_key, Some((&pk, &sk))).unwrap().data(), queue_key.data());
+        assert!(db.kv_get(KEY_KV).unwrap().unwrap().starts_with(KEYPAIR_PREFIX));
+        assert_eq!(load_key(&mut db, &Key::random().unwrap(), Some((&pk, &sk))).unwrap().data(), queue_key.data());
+    }
+}
@@ -0,0 +1,172 @@
+//! Selective sync lets a device keep only some of the user's spaces locally
+//! (say, just their personal space on a phone, instead of a giant shared
+//! space).
+//!
+//! Incoming records for boards/notes/spaces outside the filter are turned into
+//! local deletes, so anything we already had from those spaces gets cleaned out
+//! and nothing new comes in. Deletes only ever touch the local db (incoming
+//! syncs never generate outgoing ones), so the data is untouched on the server.
+//! File downloads are skipped for notes we aren't keeping.
+
+use ::std::collections::HashSet;
+use ::jedi;
+use ::error::TResult;
+use ::models::sync_record::{SyncRecord, SyncType, SyncAction};
+use ::storage::Storage;
+
+/// The k/v key (in the user db) we save the filter under
+pub const FILTER_KEY: &'static str = "sync:space_filter";
+
+/// How we apply a filter's list of spaces
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum FilterMode {
+    /// Sync everything (the list is ignored)
+    #[serde(rename = "all")]
+    All,
+    /// Only sync the listed spaces
+    #[serde(rename = "only")]
+    Only,
+    /// Sync everything but the listed spaces
+    #[serde(rename = "except")]
+    Except,
+}
+
+/// Which spaces we keep locally
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SpaceFilter {
+    pub mode: FilterMode,
+    #[serde(default)]
+    pub spaces: Vec<String>,
+}
+
+impl Default for SpaceFilter {
+    fn default() -> Self {
+        SpaceFilter {
+            mode: FilterMode::All,
+            spaces: Vec::new(),
+        }
+    }
+}
+
+impl SpaceFilter {
+    /// Whether or not this filter lets everything through
+    pub fn is_all(&self) -> bool {
+        self.mode == FilterMode::All
+    }
+
+    /// Whether or not we keep data for the given space
+    pub fn allows(&self, space_id: &String) -> bool {
+        match self.mode {
+            FilterMode::All => true,
+            FilterMode::Only => self.spaces.contains(space_id),
+            FilterMode::Except => !self.spaces.contains(space_id),
+        }
+    }
+
+    /// Whether switching from `old` to this filter could let in spaces `old`
+    /// kept out. If so, we need to re-grab the profile to fill in what we
+    /// skipped.
+    pub fn widens(&self, old: &SpaceFilter) -> bool {
+        let set = |x: &SpaceFilter| x.spaces.iter().map(|x| x.clone()).collect::<HashSet<_>>();
+        match (&old.mode, &self.mode) {
+            (&FilterMode::All, _) => false,
+            (_, &FilterMode::All) => true,
+            (&FilterMode::Only, &FilterMode::Only) => !set(self).is_subset(&set(old)),
+            (&FilterMode::Except, &FilterMode::Except) => !set(old).is_subset(&set(self)),
+            (&FilterMode::Only, &FilterMode::Except) => true,
+            // an allow-list can only let in spaces an exclude-list excluded if
+            // it lists them
+            (&FilterMode::Except, &FilterMode::Only) => self.spaces.iter().any(|x| old.spaces.contains(x)),
+        }
+    }
+
+    /// Grab the space an incoming record belongs to, if it belongs to one we
+    /// filter on.
+    fn record_space(rec: &SyncRecord) -> Option<String> {
+        match rec.ty {
+            SyncType::Space => Some(rec.item_id.clone()),
+            SyncType::Board | SyncType::Note | SyncType::Template => {
+                rec.data.as_ref().and_then(|x| jedi::get_opt(&["space_id"], x))
+            }
+            _ => None,
+        }
+    }
+
+    /// Run an incoming record through the filter. Records for spaces we don't
+    /// keep become (local) deletes. Returns true if the record was changed.
+    pub fn apply(&self, rec: &mut SyncRecord) -> bool {
+        if self.is_all() || rec.action == SyncAction::Delete { return false; }
+        let space_id = match SpaceFilter::record_space(rec) {
+            Some(x) => x,
+            None => return false,
+        };
+        if self.allows(&space_id) { return false; }
+        rec.action = SyncAction::Delete;
+        rec.data = Some(json!({"id": rec.item_id.clone()}));
+        true
+    }
+
+    /// Load the saved filter from the user db
+    pub fn load(db: &Storage) -> TResult<SpaceFilter> {
+        match db.kv_get(FILTER_KEY)? {
+            Some(x) => Ok(jedi::parse(&x)?),
+            None => Ok(Default::default()),
+        }
+    }
+
+    /// Save this filter to the user db
+    pub fn save(&self, db: &Storage) -> TResult<()> {
+        db.kv_set(FILTER_KEY, &jedi::stringify(self)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(mode: FilterMode, spaces: Vec<&str>) -> SpaceFilter {
+        SpaceFilter {
+            mode: mode,
+            spaces: spaces.into_iter().map(|x| String::from(x)).collect::<Vec<_>>(),
+        }
+    }
+
+    #[test]
+    fn filters_records() {
+        let only = filter(FilterMode::Only, vec!["personal"]);
+        assert!(only.allows(&String::from("personal")));
+        assert!(!only.allows(&String::from("work")));
+
+        let mut rec: SyncRecord = jedi::parse(&String::from(r#"{"id":"1","action":"add","item_id":"n1","user_id":1,"type":"note","data":{"id":"n1","space_id":"work"}}"#)).unwrap();
+        assert!(!SpaceFilter::default().apply(&mut rec));
+        assert!(only.apply(&mut rec));
+        assert_eq!(rec.action, SyncAction::Delete);
+        assert_eq!(jedi::get::<String>(&["id"], rec.data.as_ref().unwrap()).unwrap(), "n1");
+
+        let mut rec: SyncRecord = jedi::parse(&String::from(r#"{"id":"2","action":"edit","item_id":"personal","user_id":1,"type":"space","data":{"id":"personal"}}"#)).unwrap();
+        assert!(!only.apply(&mut rec));
+        assert_eq!(rec.action, SyncAction::Edit);
+
+        let mut rec: SyncRecord = jedi::parse(&String::from(r#"{"id":"3","action":"edit","item_id":"u1","user_id":1,"type":"user","data":{"id":"u1"}}"#)).unwrap();
+        assert!(!only.apply(&mut rec));
+    }
+
+    #[test]
+    fn knows_when_widening() {
+        let all = SpaceFilter::default();
+        let only_a = filter(FilterMode::Only, vec!["a"]);
+        let only_ab = filter(FilterMode::Only, vec!["a", "b"]);
+        let except_a = filter(FilterMode::Except, vec!["a"]);
+        let except_ab = filter(FilterMode::Except, vec!["a", "b"]);
+        assert!(!only_a.widens(&all));
+        assert!(all.widens(&only_a));
+        assert!(only_ab.widens(&only_a));
+        assert!(!only_a.widens(&only_ab));
+        assert!(except_a.widens(&except_ab));
+        assert!(!except_ab.widens(&except_a));
+        assert!(except_a.widens(&only_a));
+        assert!(only_a.widens(&except_a));
+        assert!(!filter(FilterMode::Only, vec!["c"]).widens(&except_ab));
+    }
+}
+
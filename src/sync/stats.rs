@@ -0,0 +1,108 @@
+//! Keeps running counters for each syncer (runs, failures, records and bytes
+//! moved, etc) so the UI can tell whether or not sync is healthy. Exposed via
+//! the `sync:status` command.
+
+use ::std::collections::HashMap;
+use ::std::fmt::Display;
+use ::time;
+
+/// Counters for one syncer
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct SyncerStats {
+    /// How many times the syncer has run without error
+    pub runs: u64,
+    /// How many times the syncer has run and failed
+    pub failures: u64,
+    /// How many records (or files) we've sent to the server
+    pub records_up: u64,
+    /// How many records (or files) we've gotten from the server
+    pub records_down: u64,
+    /// How many bytes we've sent to the server
+    pub bytes_up: u64,
+    /// How many bytes we've gotten from the server
+    pub bytes_down: u64,
+    /// When (unix timestamp) the syncer last ran without error
+    pub last_success: Option<i64>,
+    /// When (unix timestamp) the syncer last failed
+    pub last_failure: Option<i64>,
+    /// The error from the syncer's last failure
+    pub last_error: Option<String>,
+}
+
+impl SyncerStats {
+    /// Record a successful run
+    pub fn success(&mut self) {
+        self.runs += 1;
+        self.last_success = Some(now());
+    }
+
+    /// Record a failed run
+    pub fn failure<T: Display>(&mut self, err: &T) {
+        self.failures += 1;
+        self.last_failure = Some(now());
+        self.last_error = Some(format!("{}", err));
+    }
+
+    /// Record records/bytes sent to the server
+    pub fn up(&mut self, records: u64, bytes: u64) {
+        self.records_up += records;
+        self.bytes_up += bytes;
+    }
+
+    /// Record records/bytes gotten from the server
+    pub fn down(&mut self, records: u64, bytes: u64) {
+        self.records_down += records;
+        self.bytes_down += bytes;
+    }
+}
+
+/// Counters for all our syncers, by name
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct SyncStats {
+    pub syncers: HashMap<String, SyncerStats>,
+}
+
+impl SyncStats {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Grab the counters for a syncer (creating them if needed)
+    pub fn syncer(&mut self, name: &str) -> &mut SyncerStats {
+        self.syncers.entry(String::from(name)).or_insert_with(Default::default)
+    }
+}
+
+/// The current unix timestamp
+fn now() -> i64 {
+    time::get_time().sec
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_things() {
+        let mut stats = SyncStats::new();
+        stats.syncer("outgoing").success();
+        stats.syncer("outgoing").up(3, 1024);
+        stats.syncer("outgoing").up(1, 10);
+        stats.syncer("incoming").failure(&"server went away");
+        stats.syncer("incoming").down(2, 50);
+
+        let outgoing = stats.syncers.get("outgoing").unwrap();
+        assert_eq!(outgoing.runs, 1);
+        assert_eq!(outgoing.records_up, 4);
+        assert_eq!(outgoing.bytes_up, 1034);
+        assert!(outgoing.last_success.is_some());
+        assert_eq!(outgoing.last_failure, None);
+
+        let incoming = stats.syncers.get("incoming").unwrap();
+        assert_eq!(incoming.failures, 1);
+        assert_eq!(incoming.runs, 0);
+        assert_eq!(incoming.records_down, 2);
+        assert_eq!(incoming.last_error, Some(String::from("server went away")));
+    }
+}
+
@@ -5,7 +5,7 @@
 //! data from the API and it's a note, we pass it through the NoteSync object
 //! which handles saving to the local disk.
 
-use ::error::{TError, TResult};
+use ::error::{TError, TResult, TFutureResult};
 use ::storage::Storage;
 use ::models::model::Model;
 use ::models::protected::{Protected, Keyfinder};
@@ -16,13 +16,19 @@ use ::models::validate::Validate;
 use ::models::space::Space;
 use ::models::board::Board;
 use ::models::note::Note;
+use ::models::template::Template;
 use ::models::file::FileData;
+use ::models::trash;
 use ::lib_permissions::Permission;
 use ::jedi::{self, Value};
+use ::futures::{future, Future};
 use ::turtl::Turtl;
 use ::std::mem;
 use ::time;
 use ::messaging;
+use ::sync::conflict;
+use ::sync::delta;
+use ::sync::schedule;
 
 pub trait SyncModel: Protected + Storable + Keyfinder + Sync + Send + 'static {
     /// Allows a model to handle an incoming sync item for its type.
@@ -49,6 +55,11 @@ pub trait SyncModel: Protected + Storable + Keyfinder + Sync + Send + 'static {
                 if has_missing.is_some() {
                     return Ok(());
                 }
+                // deltas can only be applied with the model's key, so they
+                // get saved in the main thread (see `sync::delta`)
+                if self.sends_deltas() && delta::is_delta(sync_item.data.as_ref()) {
+                    return Ok(());
+                }
 
                 self.transform(sync_item)?;
                 let mut data = Value::Null;
@@ -69,11 +80,20 @@ pub trait SyncModel: Protected + Storable + Keyfinder + Sync + Send + 'static {
     /// Allows a model to save itself to the outgoing sync database (or perform
     /// any custom needed actual in addition/instead).
     fn outgoing(&self, action: SyncAction, user_id: &String, db: &mut Storage, skip_remote_sync: bool) -> TResult<()> {
+        let mut body_delta = None;
         match action {
             SyncAction::Delete => {
                 self.db_delete(db, None)?;
             }
             _ => {
+                // hang onto the version our local edits started from so we
+                // can merge if someone else edits it before we sync
+                if action == SyncAction::Edit && !skip_remote_sync && self.keeps_base() {
+                    conflict::stash_base(db, self)?;
+                }
+                if action == SyncAction::Edit && !skip_remote_sync && self.sends_deltas() {
+                    body_delta = delta::build(db, self)?;
+                }
                 self.db_save(db, None)?;
             }
         }
@@ -92,7 +112,11 @@ pub trait SyncModel: Protected + Storable + Keyfinder + Sync + Send + 'static {
                 }));
             }
             _ => {
-                sync_record.data = Some(self.data_for_storage()?);
+                let mut data = self.data_for_storage()?;
+                if let Some(body_delta) = body_delta {
+                    jedi::set(&["body_delta"], &mut data, &body_delta)?;
+                }
+                sync_record.data = Some(data);
             }
         }
         sync_record.db_save(db, None)
@@ -119,6 +143,18 @@ pub trait SyncModel: Protected + Storable + Keyfinder + Sync + Send + 'static {
     fn transform(&self, _sync_item: &mut SyncRecord) -> TResult<()> {
         Ok(())
     }
+
+    /// Whether or not we keep the pre-edit version of this model around while
+    /// it has unsynced edits (for three-way merging conflicts).
+    fn keeps_base(&self) -> bool {
+        false
+    }
+
+    /// Whether or not edits to this model can go out as a delta instead of a
+    /// full body (see `sync::delta`)
+    fn sends_deltas(&self) -> bool {
+        false
+    }
 }
 
 pub trait MemorySaver: Protected {
@@ -154,8 +190,153 @@ pub trait MemorySaver: Protected {
 /// Serialize this model and save it to the local db
 pub fn save_model<T>(action: SyncAction, turtl: &Turtl, model: &mut T, skip_remote_sync: bool) -> TResult<Value>
     where T: Protected + Storable + Keyfinder + SyncModel + MemorySaver + Validate + Sync + Send
+{
+    prepare_model(&action, turtl, model, skip_remote_sync)?;
+    if !skip_remote_sync {
+        check_permission(turtl, model.model_type(), &action, &model.id_or_else()?, model.data()?)?;
+    }
+
+    // TODO: is there a way around all the horrible cloning?
+    let mut model2: T = model.clone()?;
+    let serialized: Value = turtl.work.run(move || Protected::serialize(&mut model2))?;
+    model.merge_fields(&serialized)?;
+
+    {
+        let user_id = turtl.user_id()?;
+        let mut db_guard = lock!(turtl.db);
+        let db = match (*db_guard).as_mut() {
+            Some(x) => x,
+            None => return TErr!(TError::MissingField(format!("Turtl.db ({})", model.model_type()))),
+        };
+        model.outgoing(action.clone(), &user_id, db, skip_remote_sync)?;
+    }
+    // we've got something to send, so get the syncers moving
+    if !skip_remote_sync { schedule::touch(&turtl.sync_config); }
+
+    let model_data = model.data()?;
+    // TODO: is there a way around all the horrible cloning?
+    // NOTE: the clone keeps our snapshot, so the mem update can see what
+    // changed in this save
+    model.clone()?.run_mem_update(turtl, action.clone())?;
+    model.mark_clean()?;
+    Ok(model_data)
+}
+
+/// Save a batch of models in one go. This works like `save_model()`, except
+/// the models are all serialized in parallel in the work pool and their sync
+/// records are written in a single transaction, so the whole group goes out
+/// to the API together (or not at all). Returns the saved models' data, in the
+/// order given.
+pub fn save_models<T>(action: SyncAction, turtl: &Turtl, mut models: Vec<T>, skip_remote_sync: bool) -> TResult<Vec<Value>>
+    where T: Protected + Storable + Keyfinder + SyncModel + MemorySaver + Validate + Sync + Send + 'static
+{
+    if models.len() == 0 { return Ok(Vec::new()); }
+    for model in &mut models {
+        prepare_model(&action, turtl, model, skip_remote_sync)?;
+        if !skip_remote_sync {
+            check_permission(turtl, model.model_type(), &action, &model.id_or_else()?, model.data()?)?;
+        }
+    }
+
+    let ref work = turtl.work;
+    let session = turtl.session();
+    let futures = models.into_iter()
+        .map(|mut model| -> TFutureResult<T> {
+            let mut model_clone = ftry!(model.clone());
+            let fut = work.run_async_cancellable(&session, move |_| Protected::serialize(&mut model_clone))
+                .and_then(move |serialized: Value| -> TFutureResult<T> {
+                    ftry!(model.merge_fields(&serialized));
+                    FOk!(model)
+                });
+            Box::new(fut)
+        })
+        .collect::<Vec<_>>();
+    // join_all() keeps the order we started in
+    let mut models = future::join_all(futures).wait()?;
+    session.check()?;
+
+    {
+        let user_id = turtl.user_id()?;
+        with_db!{ db, turtl.db,
+            db.conn.execute("BEGIN TRANSACTION", &[])?;
+            for model in &mut models {
+                model.outgoing(action.clone(), &user_id, db, skip_remote_sync)?;
+            }
+            db.conn.execute("COMMIT TRANSACTION", &[])?;
+        }
+    }
+    if !skip_remote_sync { schedule::touch(&turtl.sync_config); }
+
+    let mut model_data = Vec::with_capacity(models.len());
+    for model in models {
+        model_data.push(model.data()?);
+        model.run_mem_update(turtl, action.clone())?;
+    }
+    Ok(model_data)
+}
+
+/// Run a group of saves (via `save_model()`, not `save_models()`, which starts
+/// its own transaction) inside one db transaction, so either all of their
+/// local writes and outgoing sync records go through or none do. Note that
+/// in-memory updates made along the way are NOT undone if we roll back.
+pub fn atomic<F, T>(turtl: &Turtl, run: F) -> TResult<T>
+    where F: FnOnce() -> TResult<T>
+{
+    with_db!{ db, turtl.db, db.conn.execute("BEGIN TRANSACTION", &[])? };
+    match run() {
+        Ok(x) => {
+            with_db!{ db, turtl.db, db.conn.execute("COMMIT TRANSACTION", &[])? };
+            // the saves poked the sync system before their records were
+            // committed, so poke it again now that they are
+            schedule::touch(&turtl.sync_config);
+            Ok(x)
+        }
+        Err(e) => {
+            with_db!{ db, turtl.db, db.conn.execute("ROLLBACK TRANSACTION", &[])? };
+            Err(e)
+        }
+    }
+}
+
+/// Make sure the current user's role in the space an item lives in lets them
+/// make the given change. `dispatch()` checks this up front for changes coming
+/// from the UI, but models get saved from plenty of other places, and it's
+/// better to find out here (with a permission error saying what role is
+/// needed) than to have the API reject the change once it syncs.
+///
+/// Only the things that live inside a space (boards, notes, templates) are
+/// checked here. Space changes go through the API (members, invites) or are
+/// checked where they happen.
+///
+/// Saves/deletes with `skip_remote_sync` set are not checked. Those are changes
+/// the server already made (incoming syncs, or the results of API calls like
+/// accepting an invite or moving a note between spaces) being applied to our
+/// local copy, or local-only bookkeeping that never leaves this device. Either
+/// way there's nothing for the API to reject, and refusing them would just
+/// leave our local data out of step with the server's.
+fn check_permission(turtl: &Turtl, model_type: String, action: &SyncAction, id: &String, data: Value) -> TResult<()> {
+    let mut record = SyncRecord::default();
+    record.ty = SyncType::from_string(model_type)?;
+    match record.ty {
+        SyncType::Board | SyncType::Note | SyncType::Template => {}
+        _ => return Ok(()),
+    }
+    record.action = action.clone();
+    record.item_id = id.clone();
+    record.data = Some(data);
+    match record.required_permission() {
+        Some((space_id, permission)) => Space::permission_check(turtl, &space_id, &permission),
+        None => Ok(()),
+    }
+}
+
+/// Validate a model and get it ready to be serialized: merge it with what we
+/// have stored (on edit), find its key, and set up its subkeys.
+fn prepare_model<T>(action: &SyncAction, turtl: &Turtl, model: &mut T, skip_remote_sync: bool) -> TResult<()>
+    where T: Protected + Storable + Keyfinder + SyncModel + Validate
 {
     model.do_validate(model.model_type())?;
+    let mut existing: Option<T> = None;
     {
         let db_guard = lock!(turtl.db);
         let db = match (*db_guard).as_ref() {
@@ -163,7 +344,7 @@ pub fn save_model<T>(action: SyncAction, turtl: &Turtl, model: &mut T, skip_remo
             None => return TErr!(TError::MissingField(format!("Turtl.db ({})", model.model_type()))),
         };
 
-        if action == SyncAction::Add {
+        if action == &SyncAction::Add {
             model.generate_id()?;
             model.generate_key()?;
         } else {
@@ -181,6 +362,7 @@ pub fn save_model<T>(action: SyncAction, turtl: &Turtl, model: &mut T, skip_remo
                         }
                         None => {}
                     }
+                    existing = Some(db_model);
                 },
                 None => (),
             }
@@ -188,6 +370,29 @@ pub fn save_model<T>(action: SyncAction, turtl: &Turtl, model: &mut T, skip_remo
     }
 
     turtl.find_model_key(model)?;
+    // if we don't know what the model looked like before this edit (say, it
+    // came from the UI), compare against the db version so serializing only
+    // re-encrypts what changed
+    match existing {
+        Some(mut db_model) => {
+            if model.get_snapshot().is_none() && db_model.get_body().is_some() {
+                db_model.set_key(model.key().map(|x| x.clone()));
+                match db_model.deserialize() {
+                    Ok(_) => {
+                        model.set_snapshot(db_model.get_snapshot().map(|x| x.clone()));
+                        if let Some(body) = db_model.get_body() {
+                            model.set_body(body.clone());
+                        }
+                        model.set_lazy_body(db_model.get_lazy_body().map(|x| x.clone()));
+                    }
+                    Err(e) => {
+                        debug!("sync_model::prepare_model() -- can't open db version of {} {:?}, re-encrypting it all: {}", model.model_type(), model.id(), e);
+                    }
+                }
+            }
+        }
+        None => {}
+    }
     let keyrefs = model.get_keyrefs(&turtl)?;
     model.generate_subkeys(&keyrefs)?;
 
@@ -200,26 +405,7 @@ pub fn save_model<T>(action: SyncAction, turtl: &Turtl, model: &mut T, skip_remo
             skip_remote_sync
         )?;
     }
-
-    // TODO: is there a way around all the horrible cloning?
-    let mut model2: T = model.clone()?;
-    let serialized: Value = turtl.work.run(move || Protected::serialize(&mut model2))?;
-    model.merge_fields(&serialized)?;
-
-    {
-        let user_id = turtl.user_id()?;
-        let mut db_guard = lock!(turtl.db);
-        let db = match (*db_guard).as_mut() {
-            Some(x) => x,
-            None => return TErr!(TError::MissingField(format!("Turtl.db ({})", model.model_type()))),
-        };
-        model.outgoing(action.clone(), &user_id, db, skip_remote_sync)?;
-    }
-
-    let model_data = model.data()?;
-    // TODO: is there a way around all the horrible cloning?
-    model.clone()?.run_mem_update(turtl, action.clone())?;
-    Ok(model_data)
+    Ok(())
 }
 
 /// Remove a model from memory/storage
@@ -229,6 +415,13 @@ pub fn delete_model<T>(turtl: &Turtl, id: &String, skip_remote_sync: bool) -> TR
     let mut model: T = Default::default();
     model.set_id(id.clone());
 
+    if !skip_remote_sync {
+        let existing: Option<T> = with_db!{ db, turtl.db, db.get(model.table(), id)? };
+        if let Some(existing) = existing {
+            check_permission(turtl, model.model_type(), &SyncAction::Delete, id, existing.data_for_storage()?)?;
+        }
+    }
+
     // if this model adds itself to the keychain on create, then it should be
     // removed from the keychain on delete.
     if model.add_to_keychain() {
@@ -244,10 +437,41 @@ pub fn delete_model<T>(turtl: &Turtl, id: &String, skip_remote_sync: bool) -> TR
         };
         model.outgoing(SyncAction::Delete, &user_id, db, skip_remote_sync)?;
     }
+    if !skip_remote_sync { schedule::touch(&turtl.sync_config); }
     model.run_mem_update(turtl, SyncAction::Delete)?;
     Ok(())
 }
 
+/// Find which fields of a model (as the UI has it) differ from the version
+/// we've got saved. A model we haven't saved yet has changed all over.
+/// Fields left out of `modeldata` count as unchanged.
+pub fn changed_fields(turtl: &Turtl, ty: &SyncType, modeldata: Value) -> TResult<Vec<String>> {
+    fn changes<T>(turtl: &Turtl, modeldata: Value) -> TResult<Vec<String>>
+        where T: Protected + Storable + Keyfinder
+    {
+        let model: T = jedi::from_val(modeldata.clone())?;
+        let existing: Option<T> = match model.id() {
+            Some(id) => with_db!{ db, turtl.db, db.get(model.table(), id)? },
+            None => None,
+        };
+        let mut existing = match existing {
+            Some(x) => x,
+            None => return model.changed_fields(),
+        };
+        turtl.find_model_key(&mut existing)?;
+        existing.deserialize()?;
+        existing.merge_fields(&modeldata)?;
+        existing.changed_fields()
+    }
+    match ty {
+        &SyncType::Space => changes::<Space>(turtl, modeldata),
+        &SyncType::Board => changes::<Board>(turtl, modeldata),
+        &SyncType::Note => changes::<Note>(turtl, modeldata),
+        &SyncType::Template => changes::<Template>(turtl, modeldata),
+        _ => TErr!(TError::BadValue(format!("can't track changes for an item of type {:?}", ty))),
+    }
+}
+
 /// Given a sync record, dispatch it into the sync system, calling the
 /// appropriate functions and running any permissions checks.
 pub fn dispatch(turtl: &Turtl, sync_record: SyncRecord) -> TResult<Value> {
@@ -314,6 +538,7 @@ pub fn dispatch(turtl: &Turtl, sync_record: SyncRecord) -> TResult<Value> {
                     if action == SyncAction::Add {
                         model.user_id = turtl.user_id()?;
                     }
+                    model.check_parent(turtl)?;
                     save_model(action, turtl, &mut model, false)?
                 }
                 SyncType::Note => {
@@ -322,6 +547,9 @@ pub fn dispatch(turtl: &Turtl, sync_record: SyncRecord) -> TResult<Value> {
                         Ok(_) => {}
                         Err(_) => {}
                     }
+                    let sent_fields: Vec<String> = modeldata.as_object()
+                        .map(|x| x.keys().cloned().collect())
+                        .unwrap_or(Vec::new());
                     let mut note: Note = jedi::from_val(modeldata)?;
                     let permission = match &action {
                         &SyncAction::Add => Permission::AddNote,
@@ -337,6 +565,35 @@ pub fn dispatch(turtl: &Turtl, sync_record: SyncRecord) -> TResult<Value> {
                     note.has_file = false;
                     let now = time::get_time();
                     note.mod_ = Some(now.sec as i64);
+                    // the file's key goes out with the note. if we're not
+                    // getting a new file, make sure we hang onto the key of
+                    // the one we have
+                    match filemebbe.as_ref() {
+                        Some(file) => file.prepare(turtl, &mut note)?,
+                        None => {
+                            let missing_key = note.file.as_ref().map(|x| x.key.is_none()).unwrap_or(false);
+                            if action == SyncAction::Edit && missing_key {
+                                let existing = turtl.load_notes(&vec![note.id_or_else()?])?;
+                                let key = existing.get(0)
+                                    .and_then(|x| x.file.as_ref())
+                                    .and_then(|x| x.key.clone());
+                                if let Some(file) = note.file.as_mut() { file.key = key; }
+                            }
+                        }
+                    }
+                    // notes loaded with `{"meta": true}` come back without
+                    // their lazy fields. leaving one out entirely (as opposed
+                    // to sending null) means we keep the one we have.
+                    let missing_lazy: Vec<&str> = note.lazy_fields().into_iter()
+                        .filter(|x| !sent_fields.iter().any(|y| y == x))
+                        .collect();
+                    if action == SyncAction::Edit && missing_lazy.len() > 0 {
+                        let existing = turtl.load_notes(&vec![note.id_or_else()?])?;
+                        if let Some(existing) = existing.get(0) {
+                            let lazy_data = existing.get_fields(&missing_lazy)?;
+                            note.merge_fields(&Value::Object(lazy_data))?;
+                        }
+                    }
                     let note_data = save_model(action, turtl, &mut note, false)?;
                     match filemebbe {
                         Some(mut file) => {
@@ -346,6 +603,19 @@ pub fn dispatch(turtl: &Turtl, sync_record: SyncRecord) -> TResult<Value> {
                     }
                     note_data
                 }
+                SyncType::Template => {
+                    let mut model: Template = jedi::from_val(modeldata)?;
+                    let permission = match &action {
+                        &SyncAction::Add => Permission::AddNote,
+                        &SyncAction::Edit => Permission::EditNote,
+                        _ => return TErr!(TError::BadValue(format!("couldn't find permission for {:?}/{:?}", ty, action))),
+                    };
+                    Space::permission_check(turtl, &model.space_id, &permission)?;
+                    if action == SyncAction::Add {
+                        model.user_id = turtl.user_id()?;
+                    }
+                    save_model(action, turtl, &mut model, false)?
+                }
                 _ => {
                     return TErr!(TError::BadValue(format!("cannot direct sync an item of type {:?}", ty)));
                 }
@@ -354,6 +624,8 @@ pub fn dispatch(turtl: &Turtl, sync_record: SyncRecord) -> TResult<Value> {
         }
         SyncAction::Delete => {
             let id: String = jedi::get(&["id"], &modeldata)?;
+            // notes and boards go to the trash unless asked otherwise
+            let to_trash = trash::enabled() && !jedi::get_opt(&["permanent"], &modeldata).unwrap_or(false);
             fn get_model<T>(turtl: &Turtl, id: &String) -> TResult<T>
                 where T: Protected + Storable
             {
@@ -375,13 +647,24 @@ pub fn dispatch(turtl: &Turtl, sync_record: SyncRecord) -> TResult<Value> {
                 SyncType::Board => {
                     let model = get_model::<Board>(turtl, &id)?;
                     Space::permission_check(turtl, &model.space_id, &Permission::DeleteBoard)?;
+                    if to_trash && model.trashed.is_none() {
+                        return trash::trash::<Board>(turtl, &id);
+                    }
                     delete_model::<Board>(turtl, &id, false)?;
                 }
                 SyncType::Note => {
                     let model = get_model::<Note>(turtl, &id)?;
                     Space::permission_check(turtl, &model.space_id, &Permission::DeleteNote)?;
+                    if to_trash && model.trashed.is_none() {
+                        return trash::trash::<Note>(turtl, &id);
+                    }
                     delete_model::<Note>(turtl, &id, false)?;
                 }
+                SyncType::Template => {
+                    let model = get_model::<Template>(turtl, &id)?;
+                    Space::permission_check(turtl, &model.space_id, &Permission::DeleteNote)?;
+                    delete_model::<Template>(turtl, &id, false)?;
+                }
                 SyncType::File => {
                     let model = get_model::<Note>(turtl, &id)?;
                     Space::permission_check(turtl, &model.space_id, &Permission::EditNote)?;
@@ -446,3 +729,118 @@ pub fn dispatch(turtl: &Turtl, sync_record: SyncRecord) -> TResult<Value> {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::error::PermissionDenial;
+    use ::models::space_member::SpaceMember;
+    use ::lib_permissions::Role;
+
+    /// Give the test user a space owned by someone else, optionally as a
+    /// member with the given role.
+    fn their_space(turtl: &Turtl, space_id: &str, role: Option<Role>) {
+        let mut space = Space::new();
+        space.set_id(String::from(space_id));
+        space.user_id = String::from("52");
+        if let Some(role) = role {
+            let mut member = SpaceMember::default();
+            member.user_id = turtl.user_id().unwrap();
+            member.space_id = String::from(space_id);
+            member.role = role;
+            space.members.push(member);
+        }
+        let mut profile_guard = lockw!(turtl.profile);
+        profile_guard.spaces.push(space);
+    }
+
+    fn denial<T>(res: TResult<T>) -> PermissionDenial {
+        match res {
+            Err(e) => match e.shed() {
+                TError::PermissionDenied(denial) => denial,
+                e => panic!("expected a permission error, got {}", e),
+            },
+            Ok(_) => panic!("expected a permission error, but it went through"),
+        }
+    }
+
+    fn board(space_id: &str) -> Board {
+        let mut board = Board::new();
+        board.space_id = String::from(space_id);
+        board.title = Some(String::from("get a job"));
+        board
+    }
+
+    fn note(space_id: &str) -> Note {
+        let mut note = Note::new();
+        note.space_id = String::from(space_id);
+        note.title = Some(String::from("what happened to my turtl"));
+        note
+    }
+
+    #[test]
+    fn denies_saves_in_other_spaces() {
+        let turtl = ::turtl::tests::with_test(true);
+        their_space(&turtl, "1234", Some(Role::Guest));
+        their_space(&turtl, "5678", None);
+
+        let denied = denial(save_model(SyncAction::Add, &turtl, &mut board("1234"), false));
+        assert_eq!(denied.action, Some(String::from("AddBoard")));
+        assert_eq!(denied.item, Some(String::from("1234")));
+        assert!(denied.current_role.is_some());
+        assert!(denied.required_role.is_some());
+        let denied = denial(save_model(SyncAction::Add, &turtl, &mut note("1234"), false));
+        assert_eq!(denied.action, Some(String::from("AddNote")));
+        assert!(denied.current_role.is_some());
+
+        // not a member at all
+        let denied = denial(save_model(SyncAction::Add, &turtl, &mut board("5678"), false));
+        assert_eq!(denied.item, Some(String::from("5678")));
+        assert_eq!(denied.current_role, None);
+        let denied = denial(save_model(SyncAction::Add, &turtl, &mut note("5678"), false));
+        assert_eq!(denied.current_role, None);
+
+        // nothing was written for the failed saves
+        assert_eq!(lockr!(turtl.profile).boards.len(), 0);
+        let boards: Vec<Board> = lock!(turtl.db).as_ref().unwrap().all("boards").unwrap();
+        assert_eq!(boards.len(), 0);
+
+        // changes the server already made (incoming syncs) aren't ours to
+        // refuse
+        save_model(SyncAction::Add, &turtl, &mut board("1234"), true).unwrap();
+        assert_eq!(lockr!(turtl.profile).boards.len(), 1);
+    }
+
+    #[test]
+    fn denies_deletes_in_other_spaces() {
+        let turtl = ::turtl::tests::with_test(true);
+        their_space(&turtl, "1234", Some(Role::Guest));
+        their_space(&turtl, "5678", None);
+
+        let mut guest_board = board("1234");
+        guest_board.set_id(String::from("1111"));
+        let mut guest_note = note("1234");
+        guest_note.set_id(String::from("2222"));
+        let mut outside_note = note("5678");
+        outside_note.set_id(String::from("3333"));
+        {
+            let db_guard = lock!(turtl.db);
+            let db = db_guard.as_ref().unwrap();
+            db.save(&guest_board).unwrap();
+            db.save(&guest_note).unwrap();
+            db.save(&outside_note).unwrap();
+        }
+
+        let denied = denial(delete_model::<Board>(&turtl, &String::from("1111"), false));
+        assert_eq!(denied.action, Some(String::from("DeleteBoard")));
+        assert!(denied.current_role.is_some());
+        let denied = denial(delete_model::<Note>(&turtl, &String::from("2222"), false));
+        assert_eq!(denied.action, Some(String::from("DeleteNote")));
+        assert!(denied.current_role.is_some());
+        let denied = denial(delete_model::<Note>(&turtl, &String::from("3333"), false));
+        assert_eq!(denied.item, Some(String::from("5678")));
+        assert_eq!(denied.current_role, None);
+
+        let notes: Vec<Note> = lock!(turtl.db).as_ref().unwrap().all("notes").unwrap();
+        assert_eq!(notes.len(), 2);
+    }
+}
@@ -0,0 +1,70 @@
+//! Keeps file transfers under a rate limit (bytes/sec) so syncing a big
+//! attachment doesn't eat the user's whole connection. The limits live in the
+//! SyncConfig (`upload_limit`/`download_limit`, from `sync.files` in the config)
+//! and can be changed at runtime via `sync:set-rate-limits`.
+
+use ::std::time::Instant;
+
+/// If we're less than this many ms ahead of schedule, don't bother sleeping
+/// (lets small chunks pile up instead of sleeping after every 4K)
+const MIN_DELAY: u64 = 50;
+
+/// Tracks how much a transfer has moved, and how long it's been at it
+pub struct Throttle {
+    /// Our limit, in bytes/sec (0 means no limit)
+    limit: u64,
+    /// Bytes moved so far
+    bytes: u64,
+    started: Instant,
+}
+
+impl Throttle {
+    /// Start throttling a transfer at `limit` bytes/sec (0 is unlimited)
+    pub fn new(limit: u64) -> Self {
+        Throttle {
+            limit: limit,
+            bytes: 0,
+            started: Instant::now(),
+        }
+    }
+
+    /// Record some bytes moved, returning how long (ms) the transfer should
+    /// wait before moving more.
+    pub fn consume(&mut self, bytes: u64) -> u64 {
+        self.bytes += bytes;
+        let elapsed = self.started.elapsed();
+        let elapsed_ms = (elapsed.as_secs() * 1000) + (elapsed.subsec_nanos() as u64 / 1000000);
+        delay(self.limit, self.bytes, elapsed_ms)
+    }
+}
+
+/// Given a limit (bytes/sec), how much we've moved, and how long we've been at
+/// it, figure out how long (ms) we need to wait to get back under the limit.
+fn delay(limit: u64, bytes: u64, elapsed_ms: u64) -> u64 {
+    if limit == 0 { return 0; }
+    let expected_ms = bytes.saturating_mul(1000) / limit;
+    if expected_ms < elapsed_ms + MIN_DELAY { return 0; }
+    expected_ms - elapsed_ms
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays() {
+        // no limit, no waiting
+        assert_eq!(delay(0, 99999999, 0), 0);
+        assert_eq!(Throttle::new(0).consume(99999999), 0);
+        // 10K at 1K/s should take 10s
+        assert_eq!(delay(1024, 10240, 0), 10000);
+        assert_eq!(delay(1024, 10240, 4000), 6000);
+        // on (or behind) schedule
+        assert_eq!(delay(1024, 10240, 10000), 0);
+        assert_eq!(delay(1024, 10240, 20000), 0);
+        // barely ahead of schedule isn't worth sleeping over
+        assert_eq!(delay(1024, 10240, 9990), 0);
+        assert!(Throttle::new(1024).consume(10240) > 9000);
+    }
+}
+
@@ -0,0 +1,294 @@
+//! Keeps an eye on the other sync threads. If a syncer panics, its thread just
+//! goes away, and if one gets stuck (a call that never returns, a deadlock)
+//! it just sits there, and either way sync is dead until the app restarts
+//! without anyone being the wiser.
+//!
+//! Every syncer checks in (see `Syncer::should_quit()`, which every sync loop
+//! calls constantly) and the watchdog looks over the check-ins every so often.
+//! A syncer that panicked, or hasn't checked in for `sync.watchdog.timeout` ms
+//! (overridable per syncer under `sync.watchdog.timeouts`) is logged, reported
+//! to the UI via `sync:error:dead-thread`, and started up again on its own db
+//! connection (in case the old one is what it choked on). A stuck thread we
+//! replace quits the next time it checks in.
+//!
+//! We give up on a syncer after `sync.watchdog.max_restarts` restarts, so a
+//! syncer that dies every time it starts doesn't take the app down with it.
+
+use ::std::collections::HashMap;
+use ::std::sync::{Arc, RwLock, Mutex, mpsc};
+use ::std::thread::{self, ThreadId};
+use ::std::time::Instant;
+use ::config;
+use ::error::TResult;
+use ::messaging;
+use ::schema;
+use ::storage::Storage;
+use ::api::Api;
+use ::sync::{self, SyncConfig, Syncer};
+
+/// The last we heard from a syncer
+struct Beat {
+    /// The thread running the syncer (None while a replacement starts up)
+    thread: Option<ThreadId>,
+    /// When it last checked in
+    last: Instant,
+    /// Set if we know the thread is dead (and why)
+    dead: Option<String>,
+}
+
+/// Tracks check-ins from our syncers
+pub struct Heartbeats {
+    beats: Mutex<HashMap<String, Beat>>,
+}
+
+impl Heartbeats {
+    /// Create a new heartbeat tracker
+    pub fn new() -> Self {
+        Heartbeats { beats: Mutex::new(HashMap::new()) }
+    }
+
+    /// Mark the current thread as the one running the given syncer
+    pub fn register(&self, name: &str) {
+        let mut guard = lock!(self.beats);
+        guard.insert(String::from(name), Beat { thread: Some(thread::current().id()), last: Instant::now(), dead: None });
+    }
+
+    /// Check in for the given syncer. Returns false if the current thread has
+    /// been replaced (and should quit).
+    pub fn beat(&self, name: &str) -> bool {
+        let mut guard = lock!(self.beats);
+        let beat = match guard.get_mut(name) {
+            Some(x) => x,
+            // not being watched
+            None => return true,
+        };
+        if beat.thread != Some(thread::current().id()) { return false; }
+        beat.last = Instant::now();
+        true
+    }
+
+    /// Note that a syncer's thread died
+    pub fn died(&self, name: &str, reason: &str) {
+        let mut guard = lock!(self.beats);
+        if let Some(beat) = guard.get_mut(name) {
+            if beat.thread == Some(thread::current().id()) { beat.dead = Some(String::from(reason)); }
+        }
+    }
+
+    /// Hand a syncer's slot over to a replacement that's on its way
+    pub fn replace(&self, name: &str) {
+        let mut guard = lock!(self.beats);
+        guard.insert(String::from(name), Beat { thread: None, last: Instant::now(), dead: None });
+    }
+
+    /// Stop watching everyone (a new sync run is starting)
+    pub fn clear(&self) {
+        let mut guard = lock!(self.beats);
+        guard.clear();
+    }
+
+    /// Stop watching a syncer
+    pub fn forget(&self, name: &str) {
+        let mut guard = lock!(self.beats);
+        guard.remove(name);
+    }
+
+    /// Find the syncers that died or haven't checked in for longer than their
+    /// timeout (in ms). Returns (name, reason) pairs.
+    pub fn check<F>(&self, timeout: F) -> Vec<(String, String)>
+        where F: Fn(&str) -> u64
+    {
+        let guard = lock!(self.beats);
+        let mut problems = Vec::new();
+        for (name, beat) in guard.iter() {
+            if let Some(reason) = beat.dead.as_ref() {
+                problems.push((name.clone(), reason.clone()));
+                continue;
+            }
+            let silent = beat.last.elapsed();
+            let silent_ms = (silent.as_secs() * 1000) + (silent.subsec_nanos() as u64 / 1000000);
+            if silent_ms > timeout(name) {
+                problems.push((name.clone(), String::from("hung")));
+            }
+        }
+        problems.sort();
+        problems
+    }
+}
+
+/// How long (ms) a syncer can go without checking in before we call it hung
+pub fn timeout(name: &str) -> u64 {
+    match config::get(&["sync", "watchdog", "timeouts", name]) {
+        Ok(x) => x,
+        Err(_) => config::get(&["sync", "watchdog", "timeout"]).unwrap_or(600000),
+    }
+}
+
+/// What we tell the UI about a dead syncer
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DeadThread {
+    pub syncer: String,
+    /// `panic` or `hung`
+    pub reason: String,
+    /// How many times we've restarted this syncer (this time included)
+    pub restarts: u32,
+    /// Whether we started it back up
+    pub restarted: bool,
+}
+
+/// Watches over the other syncers
+pub struct SyncWatchdog {
+    /// Holds our sync config. Note that this is shared between the sync system
+    /// and the `Turtl` object in the main thread.
+    config: Arc<RwLock<SyncConfig>>,
+
+    /// Holds our Api object, which we pass along to syncers we restart
+    api: Arc<Api>,
+
+    /// Holds our user-specific db, for restarted syncers if we can't open a
+    /// new connection
+    db: Arc<Mutex<Option<Storage>>>,
+
+    /// Stores our syn run version
+    run_version: i64,
+
+    /// How many times we've restarted each syncer
+    restarts: HashMap<String, u32>,
+
+    /// The syncers we've restarted, along with their init channels (so they
+    /// have someone to talk to). We don't wait on these when shutting down:
+    /// they quit along with the rest of the session, and they have their own db
+    /// connections, so they won't trip over the user db closing.
+    children: Vec<(thread::JoinHandle<()>, mpsc::Receiver<TResult<()>>)>,
+}
+
+impl SyncWatchdog {
+    /// Create a new watchdog
+    pub fn new(config: Arc<RwLock<SyncConfig>>, api: Arc<Api>, db: Arc<Mutex<Option<Storage>>>) -> SyncWatchdog {
+        SyncWatchdog {
+            config: config,
+            api: api,
+            db: db,
+            run_version: 0,
+            restarts: HashMap::new(),
+            children: Vec::new(),
+        }
+    }
+
+    /// Open a new connection to the user db for a restarted syncer. If we don't
+    /// know where the db lives (in-memory dbs, say) we share the one we have.
+    fn fresh_db(&self) -> TResult<Arc<Mutex<Option<Storage>>>> {
+        let location = {
+            let guard = lockr!(self.config);
+            guard.db_location.clone()
+        };
+        match location {
+            Some(x) => Ok(Arc::new(Mutex::new(Some(Storage::new(&x, schema::get_schema())?)))),
+            None => Ok(self.db.clone()),
+        }
+    }
+
+    /// Start a syncer back up
+    fn restart(&mut self, name: &str) -> TResult<()> {
+        let db = self.fresh_db()?;
+        let child = sync::spawn(name, self.config.clone(), self.api.clone(), db)?;
+        self.children.push(child);
+        Ok(())
+    }
+}
+
+impl Syncer for SyncWatchdog {
+    fn get_name(&self) -> &'static str {
+        "watchdog"
+    }
+
+    fn get_config(&self) -> Arc<RwLock<SyncConfig>> {
+        self.config.clone()
+    }
+
+    fn get_delay(&self) -> u64 {
+        config::get(&["sync", "watchdog", "interval"]).unwrap_or(10000)
+    }
+
+    fn adaptive(&self) -> bool {
+        false
+    }
+
+    fn set_run_version(&mut self, run_version: i64) {
+        self.run_version = run_version;
+    }
+
+    fn get_run_version(&self) -> i64 {
+        self.run_version
+    }
+
+    fn needs_network(&self) -> bool {
+        false
+    }
+
+    fn run_sync(&mut self) -> TResult<()> {
+        let heartbeats = {
+            let guard = lockr!(self.config);
+            guard.heartbeats.clone()
+        };
+        let max_restarts: u32 = config::get(&["sync", "watchdog", "max_restarts"]).unwrap_or(5);
+        for (name, reason) in heartbeats.check(timeout) {
+            if name == self.get_name() { continue; }
+            let restarts = {
+                let count = self.restarts.entry(name.clone()).or_insert(0);
+                *count += 1;
+                *count
+            };
+            let restart = restarts <= max_restarts;
+            error!("SyncWatchdog.run_sync() -- syncer {} is dead ({}), {}", name, reason, if restart { "restarting" } else { "giving up" });
+            if restart {
+                heartbeats.replace(&name);
+            } else {
+                heartbeats.forget(&name);
+            }
+            let dead = DeadThread {
+                syncer: name.clone(),
+                reason: reason,
+                restarts: restarts,
+                restarted: restart,
+            };
+            messaging::ui_event("sync:error:dead-thread", &dead)?;
+            if restart { self.restart(&name)?; }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::std::time::Duration;
+
+    #[test]
+    fn tracks_heartbeats() {
+        let heartbeats = Arc::new(Heartbeats::new());
+        // unwatched syncers are always fine
+        assert!(heartbeats.beat("outgoing"));
+        heartbeats.register("outgoing");
+        heartbeats.register("incoming");
+        assert!(heartbeats.beat("outgoing"));
+        assert_eq!(heartbeats.check(|_| 60000).len(), 0);
+        thread::sleep(Duration::from_millis(5));
+        assert_eq!(heartbeats.check(|x| if x == "incoming" { 0 } else { 60000 }), vec![(String::from("incoming"), String::from("hung"))]);
+
+        // a thread that dies on us
+        let heartbeats2 = heartbeats.clone();
+        thread::spawn(move || {
+            heartbeats2.register("files:outgoing");
+            heartbeats2.died("files:outgoing", "panic");
+        }).join().unwrap();
+        assert_eq!(heartbeats.check(|_| 60000), vec![(String::from("files:outgoing"), String::from("panic"))]);
+
+        // once replaced, the old thread gets told to quit
+        heartbeats.replace("outgoing");
+        assert!(!heartbeats.beat("outgoing"));
+        heartbeats.forget("files:outgoing");
+        assert_eq!(heartbeats.check(|_| 60000).len(), 0);
+    }
+}
+
@@ -2,17 +2,19 @@
 //! functions/interfaces for updating or retrieving stateful info, and is passed
 //! around to various pieces of the app running in the main thread.
 
-use ::std::sync::{Arc, RwLock, Mutex};
+use ::std::sync::{Arc, RwLock, Mutex, mpsc};
 use ::std::ops::Drop;
 use ::std::fs;
+use ::std::time::Instant;
 use ::regex::Regex;
 use ::num_cpus;
 use ::jedi::{self, Value};
 use ::config;
-use ::error::{TResult, TError};
+use ::error::{TResult, TError, PermissionDenial};
 use ::crypto::Key;
 use ::util;
 use ::util::thredder::Thredder;
+use ::util::cancel::CancelToken;
 use ::storage::{self, Storage};
 use ::api::Api;
 use ::profile::Profile;
@@ -22,14 +24,25 @@ use ::models::user::{self, User};
 use ::models::space::Space;
 use ::models::board::Board;
 use ::models::invite::Invite;
+use ::models::settings::Settings;
+use ::models::saved_search::SavedSearch;
 use ::models::keychain::KeychainEntry;
 use ::models::note::Note;
 use ::models::file::FileData;
+use ::models::trash;
+use ::models::upgrade;
 use ::models::sync_record::{SyncRecord, SyncAction};
 use ::messaging::{self, Messenger, Response};
+use ::protocol::Warning;
 use ::sync::{self, SyncConfig, SyncState};
 use ::sync::sync_model::MemorySaver;
-use ::search::Search;
+use ::sync::selective::SpaceFilter;
+use ::sync::stats::SyncStats;
+use ::sync::connectivity;
+use ::sync::schedule::{self, PollPolicy};
+use ::search::{self, Search, IndexEntry};
+use ::search_store::{self, Stored};
+use ::search_reindex;
 use ::schema;
 use ::migrate::{self, MigrateResult};
 use ::std::collections::HashMap;
@@ -95,6 +108,13 @@ pub struct Turtl {
     pub incoming_sync_lock: Mutex<()>,
     /// Whether or not we're connected to the API
     pub connected: RwLock<bool>,
+    /// The current session's cancellation token. Long-running work grabs a
+    /// copy of this (via `turtl.session()`) and checks it at safe points. It's
+    /// cancelled (and replaced) on logout.
+    session: RwLock<CancelToken>,
+    /// If set, `request_shutdown()` signals this instead of shutting things
+    /// down itself (see the `shutdown` module).
+    shutdown_signal: Mutex<Option<mpsc::Sender<()>>>,
 }
 
 impl Turtl {
@@ -122,6 +142,8 @@ impl Turtl {
             sync_state: Arc::new(RwLock::new(None)),
             connected: RwLock::new(false),
             incoming_sync_lock: Mutex::new(()),
+            session: RwLock::new(CancelToken::new()),
+            shutdown_signal: Mutex::new(None),
         };
         Ok(turtl)
     }
@@ -141,21 +163,21 @@ impl Turtl {
     }
 
     /// Send a success response to a remote request
-    pub fn msg_success(&self, mid: &String, data: Value) -> TResult<()> {
+    pub fn msg_success(&self, mid: &String, data: Value, warnings: Vec<Warning>) -> TResult<()> {
         let reqres_append_mid: bool = config::get(&["messaging", "reqres_append_mid"])?;
         if reqres_append_mid {
-            let res = Response::new(0, data);
+            let res = Response::new(0, data).warnings(warnings);
             let msg = jedi::stringify(&res)?;
             self.remote_send(Some(mid.clone()), msg)
         } else {
-            let res = Response::new_w_id(mid.clone(), 0, data);
+            let res = Response::new_w_id(mid.clone(), 0, data).warnings(warnings);
             let msg = jedi::stringify(&res)?;
             self.remote_send(None, msg)
         }
     }
 
     /// Send an error response to a remote request
-    pub fn msg_error(&self, mid: &String, err: &TError) -> TResult<()> {
+    pub fn msg_error(&self, mid: &String, err: &TError, warnings: Vec<Warning>) -> TResult<()> {
         let reqres_append_mid: bool = config::get(&["messaging", "reqres_append_mid"])?;
         let mut errval = util::json_or_string(format!("{}", err));
         let wrapped = match jedi::get_opt::<bool>(&["wrapped"], &errval) {
@@ -170,11 +192,11 @@ impl Turtl {
             errval = jedi::get(&["err"], &errval)?;
         }
         if reqres_append_mid {
-            let res = Response::new(1, errval);
+            let res = Response::new(1, errval).warnings(warnings);
             let msg = jedi::stringify(&res)?;
             self.remote_send(Some(mid.clone()), msg)
         } else {
-            let res = Response::new_w_id(mid.clone(), 1, errval);
+            let res = Response::new_w_id(mid.clone(), 1, errval).warnings(warnings);
             let msg = jedi::stringify(&res)?;
             self.remote_send(None, msg)
         }
@@ -229,7 +251,8 @@ impl Turtl {
 
     /// Log a user in
     pub fn login(&self, username: String, password: String) -> TResult<()> {
-        User::login(self, username, password, user::CURRENT_AUTH_VERSION)?;
+        User::login(self, username.clone(), password.clone(), user::CURRENT_AUTH_VERSION)?;
+        User::prepare_auth_upgrade(self, &username, &password)?;
         self.post_login()
     }
 
@@ -261,7 +284,7 @@ impl Turtl {
     pub fn join_migrate(&self, old_username: String, old_password: String, new_username: String, new_password: String) -> TResult<()> {
         let login = migrate::check_login(&old_username, &old_password)?;
         if login.is_none() {
-            return TErr!(TError::PermissionDenied(String::from("login on old server failed")));
+            return TErr!(TError::PermissionDenied(PermissionDenial::new("login on old server failed")));
         }
         let migrate_data = migrate::migrate(login.expect("turtl.join_migrate() -- login is None"), |ev, args| {
             debug!("turtl.join_migrate() -- migration event: {}", ev);
@@ -275,14 +298,31 @@ impl Turtl {
         self.do_join(new_username, new_password, Some(migrate_data))
     }
 
+    /// Grab a copy of the current session's cancellation token
+    pub fn session(&self) -> CancelToken {
+        lockr!(self.session).clone()
+    }
+
+    /// Cancel the current session, telling any in-flight work tied to it to
+    /// stop, and start a fresh one.
+    fn end_session(&self) {
+        let mut session_guard = lockw!(self.session);
+        session_guard.cancel();
+        *session_guard = CancelToken::new();
+    }
+
     /// Log a user out
     pub fn logout(&self) -> TResult<()> {
+        // do this first so any running jobs let go of the state we're about to
+        // tear down
+        self.end_session();
         {
             let mut profile_guard = lockw!(self.profile);
             profile_guard.wipe();
             *profile_guard = Profile::new();
         }
         self.sync_shutdown(false)?;
+        sync::seal::lock();
         self.close_user_db()?;
         self.close_search();
         self.clear_user_id();
@@ -312,11 +352,29 @@ impl Turtl {
         Ok(())
     }
 
-    /// Delete the current user's account (if they are logged in derr)
-    pub fn delete_account(&self) -> TResult<()> {
+    /// Change the current user's email (username)
+    pub fn change_user_email(&self, current_username: String, current_password: String, new_email: String) -> TResult<()> {
+        self.assert_connected()?;
+        {
+            let mut user_guard = lockw!(self.user);
+            user_guard.change_email(self, current_username, current_password, new_email)?;
+        }
+        // same as a password change: the local data is encrypted for the old
+        // login, so clear it out
+        self.sync_shutdown(true)?;
+        self.wipe_user_data()?;
+        Ok(())
+    }
+
+    /// Delete the current user's account (if they are logged in derr), then
+    /// get rid of everything we kept for them locally. Lets the UI know with a
+    /// `user:delete-account:complete` event once it's all gone.
+    pub fn delete_account(&self, username: String, password: String) -> TResult<()> {
         self.assert_connected()?;
-        User::delete_account(self)?;
+        let user_id = self.user_id()?;
+        User::delete_account(self, username, password)?;
         self.wipe_user_data()?;
+        messaging::ui_event("user:delete-account:complete", &json!({"user_id": user_id}))?;
         Ok(())
     }
 
@@ -364,26 +422,94 @@ impl Turtl {
         // our heroic db, error out ='[
         self.check_db_exists()?;
 
+        // grab which spaces this device syncs, make sure any sync records left
+        // behind by older versions of the app are readable, and unlock our
+        // (encrypted) outgoing queue
+        let (user_key, keypair) = {
+            let user_guard = lockr!(self.user);
+            let keypair = match (user_guard.pubkey.as_ref(), user_guard.privkey.as_ref()) {
+                (Some(pk), Some(sk)) => Some((pk.clone(), sk.clone())),
+                _ => None,
+            };
+            (user_guard.key_or_else()?, keypair)
+        };
+        let space_filter = {
+            let mut db_guard = lock!(self.db);
+            match db_guard.as_mut() {
+                Some(db) => {
+                    sync::migrations::run(db)?;
+                    sync::seal::unlock(db, &user_key, keypair.as_ref().map(|&(ref pk, ref sk)| (pk, sk)))?;
+                    SpaceFilter::load(db)?
+                }
+                None => Default::default(),
+            }
+        };
+
+        // in-memory dbs can't be shared between connections, so restarted
+        // syncers just use ours
+        let db_location = self.get_user_db_location(&self.user_id()?)?;
+        let db_location = if db_location == ":memory:" { None } else { Some(db_location) };
+
         // increment our run version to catch rogue sync threads
         {
             let mut sync_config_guard = lockw!(self.sync_config);
             sync_config_guard.run_version += 1;
+            sync_config_guard.session = self.session();
+            sync_config_guard.space_filter = space_filter;
+            sync_config_guard.db_location = db_location;
         }
 
         // lock down incoming syncs so we have a chance to load our profile
         // before dealing with a bunch of sync records
         let sync_lock = self.incoming_sync_lock.lock();
+        self.load_profile()?;
+        // users still on an older auth version get moved to the current one
+        // now that we have everything bound to their login. this re-keys the
+        // keychain/settings and switches our API auth, so it happens before
+        // the sync threads start: they should only ever see the new login.
+        match User::upgrade_auth(self) {
+            Ok(_) => {}
+            Err(e) => warn!("turtl.sync_start() -- problem upgrading auth, will try again next login: {}", e),
+        }
+
         // start the sync, and save the resulting state into Turtl
         let sync_state = sync::start(self.sync_config.clone(), self.api.clone(), self.db.clone())?;
         {
             let mut state_guard = lockw!(self.sync_state);
             *state_guard = Some(sync_state);
         }
-
-        self.load_profile()?;
         messaging::ui_event("profile:loaded", &())?;
-        self.index_notes()?;
+        // saved search indexes for spaces we're no longer in can go
+        let space_ids = {
+            let profile_guard = lockr!(self.profile);
+            profile_guard.spaces.iter()
+                .filter_map(|x| x.id().map(|id| id.clone()))
+                .collect::<Vec<_>>()
+        };
+        with_db!{ db, self.db, search_store::prune(db, &space_ids)? };
+        // spaces get indexed as they're searched, unless we're told to do them
+        // all up front
+        if config::get(&["profile", "preload"]).unwrap_or(false) {
+            self.index_notes()?;
+        } else {
+            self.init_search()?;
+        }
         messaging::ui_event("profile:indexed", &())?;
+        // pick up any indexing that got cut off last time
+        match search_reindex::start(self) {
+            Ok(_) => {}
+            Err(e) => warn!("turtl.sync_start() -- problem resuming search indexing: {}", e),
+        }
+        // anything saved in an older format gets upgraded a bit at a time
+        match upgrade::scan(self).and_then(|_| upgrade::start(self)) {
+            Ok(_) => {}
+            Err(e) => warn!("turtl.sync_start() -- problem queuing model upgrades: {}", e),
+        }
+        // clear out anything that's been in the trash too long
+        match trash::purge_expired(self) {
+            Ok(x) => if x > 0 { info!("turtl.sync_start() -- purged {} expired items from the trash", x); },
+            Err(e) => warn!("turtl.sync_start() -- problem purging the trash: {}", e),
+        }
 
         // wipe our incoming sync queue. we're about to synchronize all our
         // in-mem state with what's in the DB, so we don't really need to run
@@ -443,6 +569,125 @@ impl Turtl {
         if guard.is_some() { (guard.as_ref().expect("turtl::Turtl.sync_resume() -- sync_state is None").resume)(); }
     }
 
+    /// Pause a single syncer (or group of syncers, see `sync::syncers_for()`)
+    /// while leaving the rest of sync running
+    pub fn sync_pause_syncer(&self, target: &str) -> TResult<()> {
+        let names = sync::syncers_for(target)?;
+        let guard = lockr!(self.sync_state);
+        if guard.is_some() { (guard.as_ref().expect("turtl::Turtl.sync_pause_syncer() -- sync_state is None").pause_syncers)(&names); }
+        Ok(())
+    }
+
+    /// Resume a syncer (or group of syncers) paused via `sync_pause_syncer()`
+    pub fn sync_resume_syncer(&self, target: &str) -> TResult<()> {
+        let names = sync::syncers_for(target)?;
+        let guard = lockr!(self.sync_state);
+        if guard.is_some() { (guard.as_ref().expect("turtl::Turtl.sync_resume_syncer() -- sync_state is None").resume_syncers)(&names); }
+        Ok(())
+    }
+
+    /// Returns the names of any individually-paused syncers
+    pub fn sync_paused(&self) -> Vec<String> {
+        let guard = lockr!(self.sync_state);
+        if guard.is_some() {
+            (guard.as_ref().expect("turtl::Turtl.sync_paused() -- sync_state is None").paused)()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Grab the counters for each of our syncers (empty if sync isn't running)
+    pub fn sync_stats(&self) -> SyncStats {
+        let guard = lockr!(self.sync_state);
+        if guard.is_some() {
+            (guard.as_ref().expect("turtl::Turtl.sync_stats() -- sync_state is None").stats)()
+        } else {
+            SyncStats::new()
+        }
+    }
+
+    /// Whether or not sync is in dry-run mode
+    pub fn sync_dry_run(&self) -> bool {
+        let guard = lockr!(self.sync_config);
+        guard.dry_run
+    }
+
+    /// Turn dry-run mode on/off. While on, the syncers report what they would
+    /// do (via `sync:dry-run` events) instead of doing it.
+    pub fn sync_set_dry_run(&self, yesno: bool) {
+        let mut guard = lockw!(self.sync_config);
+        guard.dry_run = yesno;
+    }
+
+    /// Set our file transfer rate limits (bytes/sec, 0 for no limit). Takes
+    /// effect on the next upload/download.
+    pub fn sync_set_rate_limits(&self, upload: u64, download: u64) {
+        let mut guard = lockw!(self.sync_config);
+        guard.upload_limit = upload;
+        guard.download_limit = download;
+    }
+
+    /// Let the sync system know whether the device has a network connection
+    /// (the host app usually hears about this before we would). Going offline
+    /// takes effect right away. Coming back online wakes the syncers, and we
+    /// check that we can actually reach the API.
+    pub fn sync_set_online(&self, online: bool) {
+        connectivity::set_online(&self.sync_config, online);
+        if online { connectivity::request_check(&self.sync_config); }
+    }
+
+    /// Whether the sync system thinks we can reach the API
+    pub fn sync_online(&self) -> bool {
+        connectivity::is_online(&self.sync_config)
+    }
+
+    /// Let the sync system know whether the app is in the foreground, so it
+    /// can poll less while nobody's looking (see `sync::schedule`)
+    pub fn sync_set_foreground(&self, foreground: bool) {
+        schedule::set_foreground(&self.sync_config, foreground);
+    }
+
+    /// Swap out the policy the syncers use to pace themselves
+    pub fn sync_set_poll_policy(&self, policy: PollPolicy) {
+        let mut guard = lockw!(self.sync_config);
+        guard.poll_policy = policy;
+        guard.last_activity = Instant::now();
+    }
+
+    /// Grab the policy the syncers use to pace themselves
+    pub fn sync_poll_policy(&self) -> PollPolicy {
+        let guard = lockr!(self.sync_config);
+        guard.poll_policy.clone()
+    }
+
+    /// Grab the filter deciding which spaces this device syncs
+    pub fn sync_space_filter(&self) -> SpaceFilter {
+        let guard = lockr!(self.sync_config);
+        guard.space_filter.clone()
+    }
+
+    /// Change which spaces this device syncs. Data for spaces we're dropping
+    /// gets cleaned out as it comes in, and if we're picking up spaces we used
+    /// to skip, we re-grab the full profile on the next incoming sync.
+    pub fn sync_set_space_filter(&self, filter: SpaceFilter) -> TResult<()> {
+        let old = self.sync_space_filter();
+        {
+            let db_guard = lock!(self.db);
+            let db = match db_guard.as_ref() {
+                Some(x) => x,
+                None => return TErr!(TError::MissingField(String::from("Turtl.db"))),
+            };
+            filter.save(db)?;
+            if filter.widens(&old) {
+                info!("Turtl.sync_set_space_filter() -- space filter widened, reloading profile on next sync");
+                db.kv_delete("sync_id")?;
+            }
+        }
+        let mut guard = lockw!(self.sync_config);
+        guard.space_filter = filter;
+        Ok(())
+    }
+
     /// Returns whether or not the sync system is running
     pub fn sync_running(&self) -> bool {
         let guard = lockr!(self.sync_state);
@@ -478,10 +723,17 @@ impl Turtl {
         Ok(())
     }
 
-    /// Shut down the search system
+    /// Shut down the search system, wiping whatever it left on disk. The index
+    /// only ever lives (decrypted) in memory while someone's logged in.
     pub fn close_search(&self) {
         let mut search_guard = lock!(self.search);
         *search_guard = None;
+        drop(search_guard);
+        if let Ok(user_id) = self.user_id() {
+            if let Err(e) = search::wipe_segments(&user_id) {
+                warn!("Turtl.close_search() -- problem wiping search segments: {}", e);
+            }
+        }
     }
 
     /// Get the physical location of the per-user database file we will use for
@@ -518,9 +770,11 @@ impl Turtl {
 
         // the user object is encrypted with the master key.
         //
-        // keychain entries are always encrypted using the user's key, so we
-        // skip the song and dance of searching and just set it in here.
-        if (model.model_type() == "user" && model.id_or_else()? == self.user_id()?) || model.model_type() == "keychain" {
+        // keychain entries (and the user's settings and saved searches) are
+        // always encrypted using the user's key, so we skip the song and dance
+        // of searching and just set it in here.
+        let user_bound = ["keychain", "settings", "saved_search"];
+        if (model.model_type() == "user" && model.id_or_else()? == self.user_id()?) || user_bound.contains(&model.model_type().as_str()) {
             let user_key = {
                 let user_guard = lockr!(self.user);
                 user_guard.key_or_else()?
@@ -655,6 +909,8 @@ impl Turtl {
         let mut spaces: Vec<Space> = db.all("spaces")?;
         let mut boards: Vec<Board> = db.all("boards")?;
         let invites: Vec<Invite> = db.all("invites")?;
+        let mut settings: Vec<Settings> = db.all("settings")?;
+        let mut saved_searches: Vec<SavedSearch> = db.all("saved_searches")?;
 
         // decrypt the keychain
         self.find_models_keys(&mut keychain)?;
@@ -679,6 +935,21 @@ impl Turtl {
             board.mem_update(self, &mut sync_item)?;
         }
 
+        // settings are encrypted with the user's key, like the keychain. if
+        // there's more than one, the mem update keeps the newest
+        self.find_models_keys(&mut settings)?;
+        let settings: Vec<Settings> = protected::map_deserialize(self, settings)?;
+        for entry in settings {
+            entry.mem_update(self, &mut sync_item)?;
+        }
+
+        // same goes for saved searches
+        self.find_models_keys(&mut saved_searches)?;
+        let saved_searches: Vec<SavedSearch> = protected::map_deserialize(self, saved_searches)?;
+        for search in saved_searches {
+            search.mem_update(self, &mut sync_item)?;
+        }
+
         // invites are NOT decrypted. they are stored as-is.
         // set the invites into the profile
         for invite in invites {
@@ -692,6 +963,58 @@ impl Turtl {
 
     /// Load/deserialize a set of notes by id.
     pub fn load_notes(&self, note_ids: &Vec<String>) -> TResult<Vec<Note>> {
+        self.load_notes_cached(note_ids, false)
+    }
+
+    /// Like `load_notes()`, but only decrypts the notes' metadata, leaving
+    /// their lazy fields (`embed`) encrypted. Good for listing notes, not so
+    /// good for editing them.
+    pub fn load_notes_meta(&self, note_ids: &Vec<String>) -> TResult<Vec<Note>> {
+        self.load_notes_cached(note_ids, true)
+    }
+
+    /// Load notes by id, taking what we can from the profile's cache of
+    /// decrypted notes and decrypting (and caching) the rest.
+    fn load_notes_cached(&self, note_ids: &Vec<String>, meta_only: bool) -> TResult<Vec<Note>> {
+        let mut found: HashMap<String, Note> = HashMap::with_capacity(note_ids.len());
+        {
+            let profile_guard = lockr!(self.profile);
+            let mut cache_guard = lock!(profile_guard.notes);
+            for note_id in note_ids {
+                let note = match cache_guard.get(note_id) {
+                    Some(x) => x,
+                    None => continue,
+                };
+                // a note we only opened the metadata of won't do for a full
+                // load
+                if !meta_only && note.lazy_pending() { continue; }
+                found.insert(note_id.clone(), note.clone()?);
+            }
+        }
+        let missing = note_ids.iter()
+            .filter(|x| !found.contains_key(*x))
+            .map(|x| x.clone())
+            .collect::<Vec<_>>();
+        if missing.len() > 0 {
+            let notes = self.load_notes_encrypted(&missing)?;
+            let notes = if meta_only {
+                protected::map_deserialize_meta(self, notes)?
+            } else {
+                protected::map_deserialize(self, notes)?
+            };
+            let profile_guard = lockr!(self.profile);
+            let mut cache_guard = lock!(profile_guard.notes);
+            for note in notes {
+                let note_id = note.id_or_else()?;
+                cache_guard.put(note_id.clone(), note.clone()?);
+                found.insert(note_id, note);
+            }
+        }
+        Ok(note_ids.iter().filter_map(|x| found.remove(x)).collect::<Vec<_>>())
+    }
+
+    /// Grab notes (in the order of the ids given) along with their keys
+    fn load_notes_encrypted(&self, note_ids: &Vec<String>) -> TResult<Vec<Note>> {
         let db_guard = lock!(self.db);
         let db = match (*db_guard).as_ref() {
             Some(x) => x,
@@ -714,35 +1037,134 @@ impl Turtl {
             tmp
         };
         self.find_models_keys(&mut notes)?;
-        protected::map_deserialize(self, notes)
+        Ok(notes)
     }
 
-    /// Take all the (encrypted) notes in our profile data then decrypt, index,
-    /// and free them. The idea is we can get a set of note IDs from a search,
-    /// but we're not holding all our notes decrypted in memory at all times.
+    /// Set up an empty search index. Notes aren't decrypted and indexed until
+    /// their space is first searched (see `index_space()`), so large profiles
+    /// don't pay for all of them at login.
+    pub fn init_search(&self) -> TResult<()> {
+        let user_id = self.user_id()?;
+        let user_key = lockr!(self.user).key_or_else()?;
+        search::wipe_segments(&user_id)?;
+        let search = match search::segment_config(&user_id, &user_key)? {
+            Some(config) => Search::new_segmented(config)?,
+            None => Search::new()?,
+        };
+        let mut search_guard = lock!(self.search);
+        *search_guard = Some(search);
+        Ok(())
+    }
+
+    /// Load a space's notes into the search index, if it isn't already. The
+    /// idea is we can get a set of note IDs from a search, but we're not
+    /// holding all our notes decrypted in memory at all times.
+    ///
+    /// Nothing is decrypted here: notes are indexed from their saved entries
+    /// (see `search_store`), and any that were added or changed since they
+    /// were saved are left to a background job (see `search_reindex`).
+    pub fn index_space(&self, space_id: &String) -> TResult<()> {
+        if self.load_space_index(space_id)? {
+            search_reindex::start(self)?;
+        }
+        Ok(())
+    }
+
+    /// Index a space's saved entries, queuing the space for `search_reindex`
+    /// if any of its notes need decrypting. Returns whether we queued it.
+    fn load_space_index(&self, space_id: &String) -> TResult<bool> {
+        {
+            let search_guard = lock!(self.search);
+            match search_guard.as_ref() {
+                Some(search) => if search.is_loaded(space_id) { return Ok(false); },
+                None => return TErr!(TError::MissingField(format!("turtl is missing `search` object"))),
+            }
+        }
+        let scan = search_reindex::scan(self, space_id)?;
+        {
+            let mut search_guard = lock!(self.search);
+            match search_guard.as_mut() {
+                Some(search) => search.reindex_space_entries(space_id, &scan.entries)?,
+                None => {}
+            }
+        }
+        if scan.stale.len() == 0 { return Ok(false); }
+        debug!("turtl.index_space() -- {} notes in space {} need indexing", scan.stale.len(), space_id);
+        with_db!{ db, self.db, search_reindex::queue(db, space_id)? };
+        Ok(true)
+    }
+
+    /// Index all our spaces now (decrypting whatever needs it) instead of
+    /// waiting until they're searched
     pub fn index_notes(&self) -> TResult<()> {
-        let db_guard = lock!(self.db);
-        if db_guard.is_none() {
-            return TErr!(TError::MissingData(String::from("Turtl.db")));
+        self.init_search()?;
+        let space_ids = {
+            let profile_guard = lockr!(self.profile);
+            profile_guard.spaces.iter()
+                .filter_map(|x| x.id().map(|id| id.clone()))
+                .collect::<Vec<_>>()
+        };
+        for space_id in space_ids {
+            self.session().check()?;
+            self.load_space_index(&space_id)?;
         }
-        let db = db_guard.as_ref().expect("turtl::Turtl::index_notes() -- db is None");
-        let mut notes: Vec<Note> = db.all("notes")?;
-        self.find_models_keys(&mut notes)?;
-        let notes: Vec<Note> = protected::map_deserialize(self, notes)
-            .or_else(|e| -> TResult<Vec<Note>> {
-                error!("turtl.index_notes() -- there was a problem indexing notes: {}", e);
-                Err(e)
-            })?;
-        let mut search = Search::new()?;
-        for note in &notes {
-            match search.index_note(note) {
-                Ok(_) => {},
-                // keep going on error
-                Err(e) => error!("turtl.index_notes() -- problem indexing note {:?}: {}", note.id(), e),
+        search_reindex::catch_up(self)
+    }
+
+    /// Reindex a (decrypted) note that just changed, saving its new entry so
+    /// its space doesn't have to decrypt it again the next time it's loaded
+    pub fn reindex_note(&self, note: &Note) -> TResult<()> {
+        let entry = IndexEntry::for_note(note)?;
+        {
+            let mut search_guard = lock!(self.search);
+            if let Some(search) = search_guard.as_mut() {
+                search.unindex_note(note)?;
+                if let Some(entry) = entry.as_ref() {
+                    search.index_entry(entry)?;
+                }
             }
         }
-        let mut search_guard = lock!(self.search);
-        *search_guard = Some(search);
+        self.store_index_entry(note, entry)
+    }
+
+    /// Save a (decrypted) note's index entry without touching the index
+    /// itself. This is for notes that changed in ways search doesn't care
+    /// about, which still need their saved entry stamped as current.
+    pub fn save_index_entry(&self, note: &Note) -> TResult<()> {
+        self.store_index_entry(note, IndexEntry::for_note(note)?)
+    }
+
+    fn store_index_entry(&self, note: &Note, entry: Option<IndexEntry>) -> TResult<()> {
+        let key = match search_reindex::store_key(self)? {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let note_id = note.id_or_else()?;
+        with_db!{ db, self.db,
+            // the stamp comes from the note as it was saved, not as we have it
+            let saved: Option<Note> = db.get("notes", &note_id)?;
+            match saved {
+                Some(saved) => {
+                    let stored = Stored::new(search_store::stamp(&saved)?, entry);
+                    search_store::save(db, &key, &note.space_id, &note_id, &stored)?;
+                }
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove a note from the index, along with its saved entry
+    pub fn unindex_note(&self, note: &Note) -> TResult<()> {
+        {
+            let mut search_guard = lock!(self.search);
+            if let Some(search) = search_guard.as_mut() {
+                search.unindex_note(note)?;
+            }
+        }
+        if let Some(note_id) = note.id() {
+            with_db!{ db, self.db, search_store::remove_note(db, &note.space_id, note_id)? };
+        }
         Ok(())
     }
 
@@ -768,7 +1190,7 @@ impl Turtl {
                 Some(x) => x,
                 None => return TErr!(TError::Msg(format!("error converting OsString into &str"))),
             };
-            if &filename_str[0..6] != "turtl-" { continue; }
+            if !filename_str.starts_with("turtl-") { continue; }
             fs::remove_file(&path)?;
             info!("turtl.wipe_app_data() -- removing {}", path.display());
         }
@@ -797,12 +1219,48 @@ impl Turtl {
             fs::remove_file(&db_loc)?;
         }
 
-        let files = FileData::file_finder_all(Some(&user_id), None)?;
+        let files = FileData::user_files_all(&user_id)?;
         for file in files {
             fs::remove_file(&file)?;
             info!("turtl.wipe_user_data() -- removing {}", file.display());
         }
 
+        let segments = search::segment_folder(&user_id)?;
+        if segments.exists() {
+            info!("turtl.wipe_user_data() -- removing {}", segments.display());
+            fs::remove_dir_all(&segments)?;
+        }
+
+        User::clear_saved_login(&user_id)?;
+
+        Ok(())
+    }
+
+    /// Set the channel `request_shutdown()` signals when the app is asked to
+    /// shut down. Whoever holds the receiving end runs the shutdown
+    /// coordinator.
+    pub fn set_shutdown_signal(&self, signal: mpsc::Sender<()>) {
+        let mut guard = lock!(self.shutdown_signal);
+        *guard = Some(signal);
+    }
+
+    /// Ask the app to shut down. If a shutdown coordinator is listening, it
+    /// takes it from here (and will send `app:shutdown-complete` when done).
+    /// Otherwise we stop sync and messaging ourselves.
+    pub fn request_shutdown(&self) -> TResult<()> {
+        let signal = lock!(self.shutdown_signal).take();
+        match signal {
+            Some(tx) => {
+                match tx.send(()) {
+                    Ok(_) => {}
+                    Err(_) => warn!("turtl.request_shutdown() -- shutdown coordinator is gone"),
+                }
+            }
+            None => {
+                self.sync_shutdown(false)?;
+                messaging::stop();
+            }
+        }
         Ok(())
     }
 
@@ -1032,6 +1490,8 @@ pub mod tests {
         let notes = turtl.load_notes(&note_ids).unwrap();
         let grabbed_ids = notes.into_iter().map(|x| x.id().unwrap().clone()).collect::<Vec<_>>();
         assert_eq!(grabbed_ids, note_ids);
+        // and that they're cached for next time, which should keep order too
+        assert_eq!(lock!(profile_guard.notes).len(), 5);
 
         let note_ids = vec![
             String::from("015d0aee51102af6297cf0cc29180f9cc45f4c80e5b30238581f845367f9c404ef3fb8fb0a5a0249"),
@@ -1043,6 +1503,23 @@ pub mod tests {
         let notes = turtl.load_notes(&note_ids).unwrap();
         let grabbed_ids = notes.into_iter().map(|x| x.id().unwrap().clone()).collect::<Vec<_>>();
         assert_eq!(grabbed_ids, note_ids);
+        drop(search_guard);
+        drop(profile_guard);
+
+        // the index was saved, so starting over (like after a restart) should
+        // load it from the db and find the same things
+        let space_id = String::from("015bac2244d44944baee41b88207731eaeb7e2cc5c955fb8a05b028c1409aaf55024f5d26fa3002e");
+        {
+            let key = search_reindex::store_key(&turtl).unwrap().unwrap();
+            let db_guard = lock!(turtl.db);
+            let saved = search_store::load(db_guard.as_ref().unwrap(), &key, &space_id).unwrap();
+            assert_eq!(saved.len(), 1);
+        }
+        turtl.index_notes().unwrap();
+        let search_guard = lock!(turtl.search);
+        let search = search_guard.as_ref().unwrap();
+        let qry = parserrr(r#"{"space_id":"015bac2244d44944baee41b88207731eaeb7e2cc5c955fb8a05b028c1409aaf55024f5d26fa3002e","text":"grandpa happy"}"#);
+        assert_eq!(search.find(&qry).unwrap().0, vec![String::from("015d0b84f5562af6297cf0cc29180f9cc45f4c80e5b30238581f845367f9c404ef3fb8fb0a5a00f5")]);
     }
 
     #[test]
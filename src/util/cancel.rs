@@ -0,0 +1,46 @@
+//! Cancellation tokens let long-running work (decrypting a profile, indexing,
+//! importing, syncing) find out that the state it's operating on has gone away
+//! (generally because the user logged out) so it can stop at a safe point
+//! instead of plowing ahead on stale data.
+
+use ::std::sync::Arc;
+use ::std::sync::atomic::{AtomicBool, Ordering};
+
+use ::error::{TResult, TError};
+
+/// A cheaply-cloneable cancellation flag. All clones share the same state, so
+/// cancelling one cancels them all. Once cancelled, a token stays cancelled.
+#[derive(Debug, Clone)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    /// Create a new, live token
+    pub fn new() -> CancelToken {
+        CancelToken {
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Cancel this token (and all its clones)
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Has this token been cancelled?
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Returns a `TError::Cancelled` if this token has been cancelled. Meant to
+    /// be sprinkled at safe points in long-running jobs via `?`.
+    pub fn check(&self) -> TResult<()> {
+        if self.is_cancelled() {
+            TErr!(TError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
@@ -0,0 +1,118 @@
+//! A small least-recently-used cache. Lookups and inserts are O(n) in the
+//! number of entries, which is fine for the few hundred items we hold in one.
+
+use ::std::collections::{HashMap, VecDeque};
+use ::std::hash::Hash;
+
+/// Holds up to `capacity` values, dropping the least recently used one when
+/// it fills up. A capacity of 0 holds nothing.
+pub struct Lru<K, V> {
+    capacity: usize,
+    entries: HashMap<K, V>,
+    /// Our keys, most recently used at the back
+    order: VecDeque<K>,
+}
+
+impl<K: Hash + Eq + Clone, V> Lru<K, V> {
+    /// Create a new cache
+    pub fn new(capacity: usize) -> Lru<K, V> {
+        Lru {
+            capacity: capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Move a key to the front of the line
+    fn touch(&mut self, key: &K) {
+        if let Some(idx) = self.order.iter().position(|x| x == key) {
+            if let Some(key) = self.order.remove(idx) {
+                self.order.push_back(key);
+            }
+        }
+    }
+
+    /// Grab a value (marking it as used)
+    pub fn get<'a>(&'a mut self, key: &K) -> Option<&'a V> {
+        if !self.entries.contains_key(key) { return None; }
+        self.touch(key);
+        self.entries.get(key)
+    }
+
+    /// Put a value in the cache, bumping out the least recently used value if
+    /// we're full
+    pub fn put(&mut self, key: K, val: V) {
+        if self.capacity == 0 { return; }
+        if self.entries.insert(key.clone(), val).is_some() {
+            self.touch(&key);
+            return;
+        }
+        self.order.push_back(key);
+        while self.order.len() > self.capacity {
+            if let Some(old) = self.order.pop_front() {
+                self.entries.remove(&old);
+            }
+        }
+    }
+
+    /// Drop a value from the cache
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let val = self.entries.remove(key);
+        if val.is_some() {
+            self.order.retain(|x| x != key);
+        }
+        val
+    }
+
+    /// Drop any values that match the given test
+    pub fn retain<F>(&mut self, mut test: F)
+        where F: FnMut(&K, &V) -> bool
+    {
+        let entries = &mut self.entries;
+        self.order.retain(|key| {
+            let keep = entries.get(key).map(|val| test(key, val)).unwrap_or(false);
+            if !keep { entries.remove(key); }
+            keep
+        });
+    }
+
+    /// Empty the cache
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    /// How many values we're holding
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_least_recently_used() {
+        let mut lru: Lru<&str, i32> = Lru::new(2);
+        lru.put("a", 1);
+        lru.put("b", 2);
+        assert_eq!(lru.get(&"a"), Some(&1));
+        lru.put("c", 3);
+        assert_eq!(lru.get(&"b"), None);
+        assert_eq!(lru.get(&"a"), Some(&1));
+        assert_eq!(lru.get(&"c"), Some(&3));
+        lru.put("a", 4);
+        lru.put("d", 5);
+        assert_eq!(lru.get(&"c"), None);
+        assert_eq!(lru.get(&"a"), Some(&4));
+        lru.retain(|_, val| *val != 4);
+        assert_eq!(lru.len(), 1);
+        assert_eq!(lru.remove(&"d"), Some(5));
+        assert_eq!(lru.len(), 0);
+
+        let mut lru: Lru<&str, i32> = Lru::new(0);
+        lru.put("a", 1);
+        assert_eq!(lru.get(&"a"), None);
+    }
+}
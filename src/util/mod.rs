@@ -8,6 +8,7 @@ use ::std::fmt::Debug;
 use ::jedi::{self, Value, Serialize};
 use ::config;
 use ::encoding_rs;
+use ::time;
 
 macro_rules! do_lock {
     ($lock:expr) => {{
@@ -38,6 +39,8 @@ macro_rules! lockw {
 
 pub mod logger;
 pub mod thredder;
+pub mod cancel;
+pub mod lru;
 #[macro_use]
 pub mod ser;
 #[macro_use]
@@ -48,6 +51,12 @@ pub fn sleep(millis: u64) {
     thread::sleep(Duration::from_millis(millis));
 }
 
+/// The current time, in ms
+pub fn now_ms() -> i64 {
+    let now = time::get_time();
+    (now.sec * 1000) + (now.nsec as i64 / 1000000)
+}
+
 /// Get the app's file folder. This can be different depending on whether we're
 /// running tests or not, so tries to be mindful of that.
 pub fn file_folder(suffix: Option<&str>) -> TResult<String> {
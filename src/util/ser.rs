@@ -1,6 +1,6 @@
 pub mod int_converter {
     use ::error::{TResult, TError};
-    use ::serde::ser::Serializer;
+    use ::serde::ser::{self, Serializer};
     use ::serde::de::{self, Deserializer, Visitor};
     use ::jedi::Value;
 
@@ -10,7 +10,12 @@ pub mod int_converter {
         if val == "" {
             ser.serialize_i64(0)
         } else {
-            ser.serialize_i64(val.parse().expect("turtl::util::ser::int_converter::serialize() -- failed to parse string to i64"))
+            // the deserializer takes any string, so this can come from the
+            // outside world. error instead of exploding.
+            match val.parse() {
+                Ok(x) => ser.serialize_i64(x),
+                Err(_) => Err(ser::Error::custom(format!("turtl::util::ser::int_converter::serialize() -- failed to parse string to i64: {}", val))),
+            }
         }
     }
 
@@ -93,7 +98,7 @@ pub mod int_converter {
 }
 
 pub mod int_opt_converter {
-    use ::serde::ser::Serializer;
+    use ::serde::ser::{self, Serializer};
     use ::serde::de::{Deserialize, Deserializer};
     use ::jedi::Value;
 
@@ -102,7 +107,12 @@ pub mod int_opt_converter {
         where S: Serializer
     {
         match val {
-            &Some(ref x) => ser.serialize_i64(x.parse().expect("turtl::util::ser::int_opt_converter::serialize() -- failed to parse string to i64")),
+            &Some(ref x) => {
+                match x.parse() {
+                    Ok(x) => ser.serialize_i64(x),
+                    Err(_) => Err(ser::Error::custom(format!("turtl::util::ser::int_opt_converter::serialize() -- failed to parse string to i64: {}", x))),
+                }
+            }
             &None => ser.serialize_none(),
         }
     }
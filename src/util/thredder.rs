@@ -7,6 +7,7 @@ use ::futures::Future;
 use ::futures_cpupool::CpuPool;
 
 use ::error::{TResult, TFutureResult};
+use ::util::cancel::CancelToken;
 
 /// Stores state information for a thread we've spawned.
 pub struct Thredder {
@@ -37,6 +38,20 @@ impl Thredder {
         Box::new(self.pool.spawn_fn(run))
     }
 
+    /// Run an operation on this pool, handing it a cancellation token it is
+    /// expected to check at safe points. If the token is cancelled before the
+    /// job gets a chance to run, the job is skipped entirely.
+    pub fn run_async_cancellable<F, T>(&self, token: &CancelToken, run: F) -> TFutureResult<T>
+        where T: Sync + Send + 'static,
+              F: FnOnce(&CancelToken) -> TResult<T> + Send + 'static
+    {
+        let token = token.clone();
+        Box::new(self.pool.spawn_fn(move || {
+            token.check()?;
+            run(&token)
+        }))
+    }
+
     /// Run an operation on this pool
     pub fn run<F, T>(&self, run: F) -> TResult<T>
         where T: Sync + Send + 'static,